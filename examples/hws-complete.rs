@@ -37,8 +37,9 @@ use structopt::StructOpt;
 // could also roll our own.
 //
 // The spirit will add some more options on top of that ‒ it'll be able to accept
-// `--config-override` to override one or more config option on the command line and it'll accept
-// an optional list of config files and config directories.
+// `--config-override` to override one or more config option on the command line, `--check-config`
+// to only validate the configuration and it'll accept an optional list of config files and config
+// directories.
 //
 // Note that this doc comment gets printed as part of the `--help` message:
 /// A Hello World Service.
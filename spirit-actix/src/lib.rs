@@ -0,0 +1,504 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-actix/0.1.0/spirit_actix/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Running [actix-web] servers under spirit.
+//!
+//! The [`ActixServer`] fragment describes where the server listens (a TCP or a unix domain
+//! socket, using the same socket configuration as [`spirit_tokio`]) and how the server behaves ‒
+//! the number of workers, connection limits and timeouts. It is installed by an
+//! [`ActixInstaller`], which gets the `App` factory (the same closure as passed to
+//! [`HttpServer::new`]).
+//!
+//! Actix's own signal handling is turned off, spirit takes care of that. Each server runs in its
+//! own actix system, in a background thread. When the configuration changes, the server is
+//! started anew (on the same socket, unless the socket configuration changed too) and the old one
+//! is stopped gracefully, letting it finish the requests in progress for up to
+//! `shutdown-timeout`. The same happens when the application terminates.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use actix_web::{web, App, HttpResponse};
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_actix::{ActixInstaller, ActixServer};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! workers = 2
+//! shutdown-timeout = "5s"
+//!
+//! [server.tcp]
+//! port = 8080
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     server: ActixServer,
+//! }
+//!
+//! fn main() {
+//!     let app = || {
+//!         App::new().route("/", web::get().to(|| async { HttpResponse::Ok().body("Hello") }))
+//!     };
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("server")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.server.clone())
+//!                 .install(ActixInstaller::new(app))
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [actix-web]: https://crates.io/crates/actix-web
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::io::Error as IoError;
+use std::net::TcpListener as StdTcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use actix_http::body::MessageBody;
+use actix_http::{Error as ActixError, Request, Response};
+use actix_service::{IntoServiceFactory, Service, ServiceFactory};
+use actix_web::dev::{AppConfig, Server};
+use actix_web::http::KeepAlive;
+use actix_web::HttpServer;
+use err_context::prelude::*;
+use log::{debug, error, info};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
+use spirit::fragment::{Fragment, Installer, Stackable};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+fn default_shutdown_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Configuration of an actix-web server.
+///
+/// Exactly one of `tcp` and `unix` needs to be set.
+///
+/// # Fields
+///
+/// * `tcp`: The TCP socket to listen on. See [`spirit_tokio::net::Listen`] for the options.
+/// * `unix`: The unix domain socket to listen on (unix only). See
+///   [`spirit_tokio::net::unix::Listen`] for the options.
+/// * `workers`: Number of worker threads. Defaults to the number of CPUs.
+/// * `max-connections`: Maximum number of connections per worker. Left on the actix default if
+///   not set.
+/// * `keep-alive`: How long to keep idle connections open. Left on the actix default if not set,
+///   `0s` turns keep-alive off.
+/// * `client-timeout`: Time limit for the client to send the request head. Left on the actix
+///   default if not set.
+/// * `shutdown-timeout`: How long to wait for the requests in progress when the server is
+///   stopped. Defaults to `30s`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct ActixServer {
+    /// The TCP socket to listen on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<spirit_tokio::net::Listen>,
+
+    /// The unix domain socket to listen on.
+    #[cfg(unix)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix: Option<spirit_tokio::net::unix::Listen>,
+
+    /// Number of worker threads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub workers: Option<usize>,
+
+    /// Maximum number of connections per worker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_connections: Option<usize>,
+
+    /// How long to keep idle connections open.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub keep_alive: Option<Duration>,
+
+    /// Time limit for the client to send the request head.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub client_timeout: Option<Duration>,
+
+    /// How long to wait for the requests in progress when stopping.
+    #[serde(
+        default = "default_shutdown_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub shutdown_timeout: Duration,
+}
+
+impl ActixServer {
+    #[cfg(unix)]
+    fn has_unix(&self) -> bool {
+        self.unix.is_some()
+    }
+
+    #[cfg(not(unix))]
+    fn has_unix(&self) -> bool {
+        false
+    }
+
+    fn same_transport(&self, other: &Self) -> bool {
+        #[cfg(unix)]
+        let same_unix = self.unix == other.unix;
+        #[cfg(not(unix))]
+        let same_unix = true;
+        self.tcp == other.tcp && same_unix
+    }
+
+    /// Creates the listening socket.
+    pub fn create_listener(&self) -> Result<StdListener, AnyError> {
+        match (&self.tcp, self.has_unix()) {
+            (Some(_), true) => Err("Both tcp and unix set for an actix server".into()),
+            (None, false) => Err("No socket (tcp or unix) set for an actix server".into()),
+            (Some(tcp), false) => Ok(StdListener::Tcp(tcp.create_tcp()?)),
+            #[cfg(unix)]
+            (None, true) => {
+                let unix = self.unix.as_ref().expect("Checked above");
+                Ok(StdListener::Unix(unix.create_listener()?))
+            }
+            #[cfg(not(unix))]
+            (None, true) => unreachable!(),
+        }
+    }
+
+    fn configure<F, I, S, B>(&self, server: HttpServer<F, I, S, B>) -> HttpServer<F, I, S, B>
+    where
+        F: Fn() -> I + Send + Clone + 'static,
+        I: IntoServiceFactory<S>,
+        S: ServiceFactory<Config = AppConfig, Request = Request>,
+        S::Error: Into<ActixError> + 'static,
+        S::InitError: Debug,
+        S::Response: Into<Response<B>> + 'static,
+        <S::Service as Service>::Future: 'static,
+        B: MessageBody + 'static,
+    {
+        let mut server = server
+            .disable_signals()
+            .shutdown_timeout(self.shutdown_timeout.as_secs());
+        if let Some(workers) = self.workers {
+            server = server.workers(workers);
+        }
+        if let Some(max) = self.max_connections {
+            server = server.max_connections(max);
+        }
+        if let Some(keep_alive) = self.keep_alive {
+            server = server.keep_alive(if keep_alive == Duration::from_secs(0) {
+                KeepAlive::Disabled
+            } else {
+                KeepAlive::Timeout(keep_alive.as_secs() as usize)
+            });
+        }
+        if let Some(timeout) = self.client_timeout {
+            server = server.client_timeout(timeout.as_millis() as u64);
+        }
+        server
+    }
+}
+
+impl Stackable for ActixServer {}
+
+impl Comparable for ActixServer {
+    fn compare(&self, other: &Self) -> Comparison {
+        if !self.same_transport(other) {
+            Comparison::Dissimilar
+        } else if self != other {
+            Comparison::Similar
+        } else {
+            Comparison::Same
+        }
+    }
+}
+
+impl Fragment for ActixServer {
+    type Driver = CacheSimilar<Self>;
+    type Installer = ();
+    type Seed = StdListener;
+    type Resource = ActixResource;
+    fn make_seed(&self, name: &'static str) -> Result<StdListener, AnyError> {
+        self.create_listener()
+            .with_context(|_| format!("Failed to create socket {}/{:?}", name, self))
+            .map_err(AnyError::from)
+    }
+    fn make_resource(
+        &self,
+        seed: &mut StdListener,
+        name: &'static str,
+    ) -> Result<ActixResource, AnyError> {
+        let listener = seed
+            .try_clone() // Another copy of the listener
+            .with_context(|_| format!("Failed to copy socket {}/{:?}", name, self))?;
+        Ok(ActixResource {
+            listener,
+            config: self.clone(),
+        })
+    }
+}
+
+/// A bound (blocking) listening socket.
+///
+/// This is the seed of the [`ActixServer`] fragment. The user should not need to interact with
+/// this directly.
+#[derive(Debug)]
+pub enum StdListener {
+    /// A TCP socket.
+    Tcp(StdTcpListener),
+    /// A unix domain socket.
+    #[cfg(unix)]
+    Unix(StdUnixListener),
+}
+
+impl StdListener {
+    /// Creates another handle to the same socket.
+    pub fn try_clone(&self) -> Result<Self, IoError> {
+        match self {
+            StdListener::Tcp(listener) => listener.try_clone().map(StdListener::Tcp),
+            #[cfg(unix)]
+            StdListener::Unix(listener) => listener.try_clone().map(StdListener::Unix),
+        }
+    }
+}
+
+/// An actix server ready to be started.
+///
+/// This is what the [`ActixServer`] fragment creates. The user should not need to interact with
+/// this directly.
+#[derive(Debug)]
+pub struct ActixResource {
+    listener: StdListener,
+    config: ActixServer,
+}
+
+struct Running {
+    server: Mutex<Option<Server>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Running {
+    fn shutdown(&self) {
+        if let Some(server) = self.server.lock().unwrap().take() {
+            // Waits for the requests in flight, up to the shutdown timeout
+            futures::executor::block_on(server.stop(true));
+        }
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if thread.join().is_err() {
+                error!("Actix server thread panicked");
+            }
+        }
+    }
+}
+
+/// A running actix server.
+///
+/// This is the uninstall handle of [`ActixInstaller`]. Dropping it stops the server gracefully
+/// (and waits for it to stop). The user should not need to interact with this directly.
+pub struct RunningServer(Arc<Running>);
+
+impl Debug for RunningServer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("RunningServer").finish()
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// An [`Installer`] of [`ActixServer`]s.
+///
+/// It holds the `App` factory, in the same form as passed to [`HttpServer::new`]. All the
+/// servers are stopped when the application terminates.
+pub struct ActixInstaller<F> {
+    factory: F,
+    running: Arc<Mutex<Vec<Weak<Running>>>>,
+}
+
+impl<F: Clone> Clone for ActixInstaller<F> {
+    fn clone(&self) -> Self {
+        ActixInstaller {
+            factory: self.factory.clone(),
+            running: Arc::clone(&self.running),
+        }
+    }
+}
+
+impl<F> Debug for ActixInstaller<F> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ActixInstaller").finish()
+    }
+}
+
+impl<F> ActixInstaller<F> {
+    /// Creates the installer from the `App` factory.
+    pub fn new(factory: F) -> Self {
+        ActixInstaller {
+            factory,
+            running: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Stops all the servers installed through this installer.
+    pub fn shutdown(&self) {
+        let running = self.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for server in running.iter().filter_map(Weak::upgrade) {
+            server.shutdown();
+        }
+    }
+}
+
+fn start<F, I, S, B>(
+    factory: F,
+    resource: ActixResource,
+    name: &'static str,
+) -> Result<(Server, JoinHandle<()>), AnyError>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<ActixError> + 'static,
+    S::InitError: Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    let (sender, receiver) = mpsc::channel();
+    let thread = thread::Builder::new()
+        .name(format!("actix-{}", name))
+        .spawn(move || {
+            let ActixResource { listener, config } = resource;
+            let mut system = actix_rt::System::new(name);
+            let server = config.configure(HttpServer::new(factory));
+            let server = match listener {
+                StdListener::Tcp(listener) => server.listen(listener),
+                #[cfg(unix)]
+                StdListener::Unix(listener) => server.listen_uds(listener),
+            };
+            let server = match server {
+                Ok(server) => server.run(),
+                Err(e) => {
+                    let _ = sender.send(Err(e));
+                    return;
+                }
+            };
+            let _ = sender.send(Ok(server.clone()));
+            match system.block_on(server) {
+                Ok(()) => info!("Actix server {} terminated", name),
+                Err(e) => error!("Actix server {} failed: {}", name, e),
+            }
+        })?;
+    let server = receiver
+        .recv()
+        .map_err(|_| AnyError::from("Actix server thread died during startup"))?
+        .with_context(|_| format!("Failed to start actix server {}", name))?;
+    Ok((server, thread))
+}
+
+impl<F, I, S, B, O, C> Installer<ActixResource, O, C> for ActixInstaller<F>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S>,
+    S: ServiceFactory<Config = AppConfig, Request = Request>,
+    S::Error: Into<ActixError> + 'static,
+    S::InitError: Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service>::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type UninstallHandle = RunningServer;
+    fn install(&mut self, resource: ActixResource, name: &'static str) -> RunningServer {
+        debug!("Starting actix server {}", name);
+        let (server, thread) = match start(self.factory.clone(), resource, name) {
+            Ok((server, thread)) => (Some(server), Some(thread)),
+            Err(e) => {
+                error!("{}", e.display("; "));
+                (None, None)
+            }
+        };
+        let running = Arc::new(Running {
+            server: Mutex::new(server),
+            thread: Mutex::new(thread),
+        });
+        let mut all = self.running.lock().unwrap();
+        all.retain(|running| running.strong_count() > 0);
+        all.push(Arc::downgrade(&running));
+        RunningServer(running)
+    }
+    fn init<Bld: Extensible<Opts = O, Config = C, Ok = Bld>>(
+        &mut self,
+        builder: Bld,
+        _name: &'static str,
+    ) -> Result<Bld, AnyError>
+    where
+        Bld::Config: DeserializeOwned + Send + Sync + 'static,
+        Bld::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_server() -> ActixServer {
+        ActixServer {
+            tcp: Some(spirit_tokio::net::Listen::default()),
+            ..ActixServer::default()
+        }
+    }
+
+    #[test]
+    fn no_socket() {
+        assert!(ActixServer::default().create_listener().is_err());
+    }
+
+    #[test]
+    fn compare_transport() {
+        let server = tcp_server();
+        let more_workers = ActixServer {
+            workers: Some(8),
+            ..tcp_server()
+        };
+        assert_eq!(Comparison::Same, server.compare(&tcp_server()));
+        assert_eq!(Comparison::Similar, server.compare(&more_workers));
+        assert_eq!(
+            Comparison::Dissimilar,
+            server.compare(&ActixServer::default())
+        );
+    }
+}
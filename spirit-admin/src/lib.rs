@@ -0,0 +1,403 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-admin/0.1.0/spirit_admin/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! A control socket for a running application.
+//!
+//! Signals are a bit limited way to talk to a running service ‒ there's only a handful of them and
+//! they can't return anything. This crate adds an optional unix domain socket (the path is taken
+//! from the configuration) that accepts simple commands and answers with a line of JSON.
+//!
+//! Each command is a single line. It can be either plain text (the command, optionally followed
+//! by an argument) or a JSON object with the `command` and optional `arg` fields. These commands
+//! are available:
+//!
+//! * `reload`: Reloads the configuration, the same as `SIGHUP` would.
+//! * `status`: Lists the [resources][spirit::status] managed by the application.
+//! * `health`: Reports if the application is healthy. It is considered unhealthy if it is
+//!   terminating, if any of the resources failed to be created last time or if any of the
+//!   [health probes][spirit::health] reports a problem.
+//! * `log-level <level>`: Sets the maximum log level (`off`, `error`, `warn`, `info`, `debug`,
+//!   `trace`). Note that this can only lower the verbosity configured by the logging itself and
+//!   that the next reload of configuration may reset it.
+//! * `dump-config`: Returns the current configuration.
+//! * `maintenance [on|off]`: Turns the [maintenance mode][spirit::maintenance] on or off (servers
+//!   supporting it answer that they are unavailable, while keeping the sockets bound). Returns
+//!   whether the mode is on, so without the argument it only asks.
+//! * `refresh-ca`: Re-reads the certificate authorities of all the TLS configurations in use (see
+//!   the `trust` module of [`spirit-tls`](https://crates.io/crates/spirit-tls)). Returns the
+//!   number of refreshed stores. Only the rustls-based servers and clients are refreshed, the
+//!   clients of `spirit-reqwest`, `spirit-sqlx` and `spirit-nats` keep their authorities until
+//!   re-created and don't count. Available with the `tls` feature.
+//! * `terminate`: Terminates the application.
+//!
+//! The answer is a JSON object with `ok` set to `true` or `false` and either `result` or `error`
+//! field.
+//!
+//! The commands are processed one at a time, so a misbehaving client can block the socket only
+//! for a short while (there's a timeout on reading the commands).
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::{Deserialize, Serialize};
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_admin::ControlSocket;
+//!
+//! #[derive(Default, Deserialize, Serialize)]
+//! struct Cfg {
+//!     control: Option<ControlSocket>,
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(spirit_admin::extension(|cfg: &Cfg| cfg.control.clone()))
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! And then, with `path = "/run/my-app.sock"` in the `[control]` section:
+//!
+//! ```sh
+//! $ echo status | socat - UNIX-CONNECT:/run/my-app.sock
+//! ```
+
+use std::fs;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use err_context::prelude::*;
+use log::{debug, error, info, trace, warn, LevelFilter};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use spirit::extension::{Extensible, Extension};
+use spirit::{health, maintenance, AnyError, Spirit};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+/// How often the listening thread checks for configuration changes and termination.
+const POLL: Duration = Duration::from_millis(100);
+
+/// How long to wait for a client to send a command.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Configuration of the control socket.
+///
+/// # Fields
+///
+/// * `path`: Where to create the unix domain socket. If there's a stale socket from previous run,
+///   it is removed.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct ControlSocket {
+    /// Where to create the unix domain socket.
+    pub path: PathBuf,
+}
+
+/// A parsed command.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
+struct Request {
+    command: String,
+    #[serde(default)]
+    arg: Option<String>,
+}
+
+impl FromStr for Request {
+    type Err = AnyError;
+    fn from_str(line: &str) -> Result<Self, AnyError> {
+        let line = line.trim();
+        if line.starts_with('{') {
+            return serde_json::from_str(line).map_err(AnyError::from);
+        }
+        let mut parts = line.splitn(2, char::is_whitespace);
+        let command = parts
+            .next()
+            .filter(|c| !c.is_empty())
+            .ok_or("Empty command")?;
+        Ok(Request {
+            command: command.to_owned(),
+            arg: parts.next().map(str::trim).map(str::to_owned),
+        })
+    }
+}
+
+fn status<O, C>(spirit: &Spirit<O, C>) -> Value
+where
+    C: DeserializeOwned + Send + Sync,
+    O: StructOpt,
+{
+    let resources = spirit
+        .resources()
+        .into_iter()
+        .map(|status| {
+            json!({
+                "name": status.name,
+                "fragment": status.fragment,
+                "config": status.config,
+                "instances": status.instances,
+                "installed": status
+                    .installed
+                    .and_then(|i| i.duration_since(UNIX_EPOCH).ok())
+                    .map(|i| i.as_secs()),
+                "last-error": status.last_error,
+            })
+        })
+        .collect();
+    Value::Array(resources)
+}
+
+fn health<O, C>(spirit: &Spirit<O, C>) -> Value
+where
+    C: DeserializeOwned + Send + Sync,
+    O: StructOpt,
+{
+    let failing = spirit
+        .resources()
+        .into_iter()
+        .filter(|status| status.last_error.is_some())
+        .map(|status| status.name)
+        .collect::<Vec<_>>();
+    let problems = health::check()
+        .into_iter()
+        .map(|problem| json!({ "probe": problem.probe, "reason": problem.reason }))
+        .collect::<Vec<_>>();
+    let terminating = spirit.is_terminated();
+    json!({
+        "healthy": failing.is_empty() && problems.is_empty() && !terminating,
+        "terminating": terminating,
+        "maintenance": maintenance::is_active(),
+        "failing": failing,
+        "problems": problems,
+    })
+}
+
+fn execute<O, C>(spirit: &Spirit<O, C>, request: &Request) -> Result<Value, AnyError>
+where
+    C: DeserializeOwned + Serialize + Send + Sync,
+    O: StructOpt,
+{
+    match request.command.as_str() {
+        "reload" => {
+            spirit.config_reload()?;
+            Ok(Value::Null)
+        }
+        "status" => Ok(status(spirit)),
+        "health" => Ok(health(spirit)),
+        "log-level" => {
+            let level = request.arg.as_ref().ok_or("Missing the log level")?;
+            let level =
+                LevelFilter::from_str(level).map_err(|_| format!("Invalid log level {}", level))?;
+            info!("Setting log level to {} through the control socket", level);
+            log::set_max_level(level);
+            Ok(Value::Null)
+        }
+        "dump-config" => Ok(serde_json::to_value(&*spirit.config())?),
+        "maintenance" => {
+            match request.arg.as_deref() {
+                None => (),
+                Some("on") => maintenance::set(true),
+                Some("off") => maintenance::set(false),
+                Some(arg) => return Err(format!("Expected on or off, not {}", arg).into()),
+            }
+            Ok(Value::Bool(maintenance::is_active()))
+        }
+        #[cfg(feature = "tls")]
+        "refresh-ca" => {
+            info!("Refreshing certificate authorities on request from the control socket");
+            Ok(Value::from(spirit_tls::trust::refresh_all()?))
+        }
+        // Handled by the caller, as the answer needs to be sent before terminating
+        "terminate" => Ok(Value::Null),
+        cmd => Err(format!("Unknown command {}", cmd).into()),
+    }
+}
+
+fn answer(result: Result<Value, AnyError>) -> Value {
+    match result {
+        Ok(result) => json!({ "ok": true, "result": result }),
+        Err(e) => json!({ "ok": false, "error": e.display("; ").to_string() }),
+    }
+}
+
+fn serve_conn<O, C>(spirit: &Spirit<O, C>, conn: UnixStream) -> Result<(), AnyError>
+where
+    C: DeserializeOwned + Serialize + Send + Sync,
+    O: StructOpt,
+{
+    conn.set_nonblocking(false)?;
+    conn.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut writer = conn.try_clone()?;
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let request = line.parse::<Request>();
+        debug!("Control socket command {:?}", request);
+        let terminate = match &request {
+            Ok(request) => request.command == "terminate",
+            Err(_) => false,
+        };
+        let result = request.and_then(|request| execute(spirit, &request));
+        writeln!(writer, "{}", answer(result))?;
+        if terminate {
+            info!("Terminating on request from the control socket");
+            spirit.terminate();
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn bind(cfg: &ControlSocket) -> Result<UnixListener, AnyError> {
+    // A stale socket from previous run would prevent binding
+    match fs::remove_file(&cfg.path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        other => {
+            other.with_context(|_| format!("Failed to remove old socket {}", cfg.path.display()))?
+        }
+    }
+    let listener = UnixListener::bind(&cfg.path)
+        .with_context(|_| format!("Failed to bind control socket {}", cfg.path.display()))?;
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+fn run<O, C, E>(spirit: Weak<Spirit<O, C>>, extract: E)
+where
+    C: DeserializeOwned + Serialize + Send + Sync,
+    O: StructOpt,
+    E: Fn(&C) -> Option<ControlSocket>,
+{
+    let mut current: Option<(ControlSocket, UnixListener)> = None;
+    // Not to spam the logs with the same error over and over
+    let mut failed: Option<ControlSocket> = None;
+    loop {
+        let spirit = match spirit.upgrade() {
+            Some(spirit) if !spirit.is_terminated() => spirit,
+            _ => break,
+        };
+        let desired = extract(&spirit.config());
+        if desired.as_ref() != current.as_ref().map(|(cfg, _)| cfg) {
+            if let Some((old, _)) = current.take() {
+                debug!("Closing control socket {}", old.path.display());
+                let _ = fs::remove_file(&old.path);
+            }
+            if let Some(desired) = desired.filter(|d| failed.as_ref() != Some(d)) {
+                match bind(&desired) {
+                    Ok(listener) => {
+                        info!("Listening on control socket {}", desired.path.display());
+                        failed = None;
+                        current = Some((desired, listener));
+                    }
+                    Err(e) => {
+                        error!("{}", e.display("; "));
+                        failed = Some(desired);
+                    }
+                }
+            }
+        }
+        match current.as_ref().map(|(_, listener)| listener.accept()) {
+            Some(Ok((conn, _))) => {
+                trace!("New control socket connection");
+                if let Err(e) = serve_conn(&spirit, conn) {
+                    warn!("Control socket connection failed: {}", e.display("; "));
+                }
+            }
+            Some(Err(ref e)) if e.kind() == ErrorKind::WouldBlock => thread::sleep(POLL),
+            Some(Err(e)) => {
+                warn!("Failed to accept on control socket: {}", e);
+                thread::sleep(POLL);
+            }
+            None => thread::sleep(POLL),
+        }
+    }
+    if let Some((cfg, _)) = current {
+        debug!("Removing control socket {}", cfg.path.display());
+        let _ = fs::remove_file(&cfg.path);
+    }
+}
+
+/// An extension adding the control socket.
+///
+/// The parameter is an extractor of the socket configuration. If it returns `None`, there's no
+/// socket. The socket is rebound when its configuration changes.
+///
+/// The socket is served by its own thread. The thread is started once the application starts
+/// running and ends when it terminates. If binding the socket fails, the error is logged and it is
+/// tried again only after the configuration of the socket changes.
+///
+/// For the `dump-config` command, the configuration needs to implement [`Serialize`].
+pub fn extension<B, E>(extract: E) -> impl Extension<B>
+where
+    B: Extensible<Ok = B>,
+    B::Config: DeserializeOwned + Serialize + Send + Sync + 'static,
+    B::Opts: StructOpt + Send + Sync + 'static,
+    E: Fn(&B::Config) -> Option<ControlSocket> + Send + 'static,
+{
+    |builder: B| {
+        builder.run_before(|spirit| {
+            let spirit = Arc::downgrade(spirit);
+            thread::Builder::new()
+                .name("spirit-admin".to_owned())
+                .spawn(move || run(spirit, extract))
+                .context("Failed to start the control socket thread")?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn req(command: &str, arg: Option<&str>) -> Request {
+        Request {
+            command: command.to_owned(),
+            arg: arg.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn parse_plain() {
+        assert_eq!(req("status", None), "status\n".parse().unwrap());
+        assert_eq!(
+            req("log-level", Some("debug")),
+            "log-level  debug ".parse().unwrap()
+        );
+        assert!("  ".parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn parse_json() {
+        assert_eq!(
+            req("log-level", Some("warn")),
+            r#"{"command": "log-level", "arg": "warn"}"#.parse().unwrap()
+        );
+        assert_eq!(
+            req("reload", None),
+            r#"{"command": "reload"}"#.parse().unwrap()
+        );
+        assert!(r#"{"arg": "x"}"#.parse::<Request>().is_err());
+    }
+
+    #[test]
+    fn answers() {
+        assert_eq!(json!({"ok": true, "result": 42}), answer(Ok(json!(42))));
+        let err = answer(Err("Broken".into()));
+        assert_eq!(Some(false), err["ok"].as_bool());
+        assert_eq!(Some("Broken"), err["error"].as_str());
+    }
+}
@@ -15,15 +15,16 @@
 //! These extensions are meant to integrate into the [`spirit`] configuration framework. They aim at
 //! making the user experience around application's configuration more smooth.
 //! Specifically, they allow dumping the configuration collected through all the config files and
-//! environment variables and printing help about all the configuration options the application
-//! accepts.
+//! environment variables, printing help about all the configuration options the application
+//! accepts and printing the configuration schema.
 //!
 //! # Features
 //!
 //! By default, all features are turned on. However, it is possible to opt out of some to cut down
 //! on dependencies. Specifically:
 //!
-//! * `toml` and `json` features enable dumping in the respective formats.
+//! * `toml` and `json` features enable dumping in the respective formats. The `json` feature also
+//!   enables printing of the configuration schema.
 //! * `cfg-help` enables the printing of configuration help.
 
 use std::borrow::Borrow;
@@ -38,6 +39,8 @@ use log::{log, Level};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use spirit::extension::{Extensible, Extension};
+#[cfg(feature = "json")]
+use spirit::schema::Schematic;
 use spirit::validation::Action;
 use spirit::Builder;
 use structopt::StructOpt;
@@ -266,6 +269,101 @@ impl CfgDump {
     }
 }
 
+/// A command line fragment adding the `--config-schema` option.
+///
+/// When specified, the [JSON Schema](https://json-schema.org) of the configuration is printed and
+/// the application exits. The schema can be fed to an editor for autocompletion and validation of
+/// the configuration files or used by external tools to check them.
+///
+/// The schema is extracted through the [`Schematic`] trait, which the configuration needs to
+/// implement. See the [`spirit::schema`] module.
+///
+/// # Examples
+///
+/// ```rust
+/// use serde_derive::Deserialize;
+/// use spirit::Spirit;
+/// use spirit::prelude::*;
+/// use spirit::schema::{Schema, Schematic};
+/// use spirit_cfg_helpers::CfgSchema;
+/// use structopt::StructOpt;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Cfg {
+///     option: Option<String>,
+/// }
+///
+/// impl Schematic for Cfg {
+///     fn schema() -> Schema {
+///         Schema::object().field::<Option<String>>("option", "A useless option")
+///     }
+/// }
+///
+/// #[derive(Debug, StructOpt)]
+/// struct Opts {
+///     #[structopt(flatten)]
+///     schema: CfgSchema,
+/// }
+///
+/// impl Opts {
+///     fn schema(&self) -> &CfgSchema {
+///         &self.schema
+///     }
+/// }
+///
+/// fn main() {
+///     Spirit::<Opts, Cfg>::new()
+///         .with(CfgSchema::extension(Opts::schema))
+///         .run(|_| Ok(()));
+/// }
+/// ```
+#[cfg(feature = "json")]
+#[derive(Clone, Debug, Default, StructOpt)]
+pub struct CfgSchema {
+    /// Print the JSON Schema of the configuration and exit.
+    #[structopt(long = "--config-schema")]
+    config_schema: bool,
+}
+
+#[cfg(feature = "json")]
+impl CfgSchema {
+    /// Print the schema and exit if it was asked for in the options.
+    ///
+    /// Note that the `C` type is passed as type parameter, therefore this needs to be invoked
+    /// with the turbofish syntax.
+    pub fn schema<C: Schematic>(&self) {
+        if self.config_schema {
+            let schema = spirit::schema::to_json_schema::<C>();
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&schema).expect("Schema is always serializable")
+            );
+            process::exit(0);
+        }
+    }
+
+    /// A helper to be registered within an [`Extensible`][Extensible::with].
+    ///
+    /// The extractor should take the whole command line options structure and provide
+    /// reference to just the [`CfgSchema`] instance.
+    ///
+    /// The schema is printed before the configuration is loaded, so it works even if the
+    /// configuration is broken.
+    pub fn extension<O, C, F>(extract: F) -> impl Extension<Builder<O, C>>
+    where
+        F: FnOnce(&O) -> &Self + Send + 'static,
+        O: Debug + StructOpt + Send + Sync + 'static,
+        C: DeserializeOwned + Schematic + Send + Sync + 'static,
+    {
+        |builder: Builder<O, C>| {
+            builder.before_config(|_: &C, opts: &O| {
+                extract(opts).schema::<C>();
+                Ok(())
+            })
+        }
+    }
+}
+
 #[cfg(feature = "cfg-help")]
 mod cfg_help {
     use super::*;
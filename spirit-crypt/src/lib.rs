@@ -0,0 +1,300 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-crypt/0.1.0/spirit_crypt/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Decryption of encrypted configuration.
+//!
+//! Secrets (passwords, API tokens) are often the only reason the configuration can't live in a
+//! repository together with the rest of the deployment. With a
+//! [decryptor][spirit::ConfigBuilder::config_decryptor] set, spirit decrypts whole files with the
+//! `.enc` extension (eg. `secrets.toml.enc`) and string values prefixed by `enc:` (followed by
+//! base64 of the encrypted data) while loading the configuration. This crate provides the
+//! decryptors:
+//!
+//! * [`Age`] (behind the `age` feature, on by default) decrypts files encrypted by
+//!   [age](https://age-encryption.org) to X25519 recipients.
+//! * [`Gpg`] runs the `gpg` binary, using the keys in its keyring (or agent).
+//!
+//! The decryption [`Key`] can be read from an environment variable, a file or the output of a
+//! command. The last one is the way to use a KMS or a secret store (eg. `aws kms decrypt` or
+//! `vault kv get`).
+//!
+//! # Examples
+//!
+//! Encrypting a value for the configuration:
+//!
+//! ```sh
+//! echo -n "hunter2" | age -r age1... | base64 -w0
+//! ```
+//!
+//! ```toml
+//! [database]
+//! password = "enc:YWdlLWVuY3J5cHRpb24..."
+//! ```
+//!
+//! ```rust,no_run
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_crypt::{Age, Key};
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Database {
+//!     password: String,
+//! }
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     database: Database,
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_decryptor(Age::new(Key::Env("CONFIG_KEY".to_owned())))
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use err_context::prelude::*;
+use log::debug;
+use spirit::cfg_loader::Decryptor;
+use spirit::AnyError;
+
+/// Where to get a decryption key from.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Key {
+    /// The content of an environment variable.
+    Env(String),
+
+    /// The content of a file.
+    File(PathBuf),
+
+    /// The standard output of a command (the first element is the program, the rest are the
+    /// arguments).
+    ///
+    /// The command is run only once and the key is cached for the whole lifetime of the
+    /// application.
+    Command(Vec<String>),
+}
+
+impl Key {
+    fn read(&self) -> Result<String, AnyError> {
+        match self {
+            Key::Env(var) => {
+                Ok(env::var(var).with_context(|_| format!("Failed to read key ${}", var))?)
+            }
+            Key::File(path) => Ok(fs::read_to_string(path)
+                .with_context(|_| format!("Failed to read key file {}", path.display()))?),
+            Key::Command(cmd) => {
+                let (program, args) = cmd.split_first().ok_or("Empty key command")?;
+                debug!("Running {} to get the decryption key", program);
+                let output = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::null())
+                    .stderr(Stdio::inherit())
+                    .output()
+                    .with_context(|_| format!("Failed to run key command {}", program))?;
+                if !output.status.success() {
+                    return Err(
+                        format!("Key command {} failed with {}", program, output.status).into(),
+                    );
+                }
+                Ok(String::from_utf8(output.stdout)?)
+            }
+        }
+    }
+}
+
+/// Feeds the input to the command and collects its output.
+fn filter(mut cmd: Command, input: &[u8]) -> Result<Vec<u8>, AnyError> {
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("Stdin is piped");
+    let input = input.to_owned();
+    // Writing from another thread, so the pipes can't fill up and block both sides
+    let writer = thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output()?;
+    let written = writer.join().expect("Writer thread panicked");
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} ({})", output.status, stderr.trim()).into());
+    }
+    written?;
+    Ok(output.stdout)
+}
+
+/// A decryptor of data encrypted by [age](https://age-encryption.org).
+///
+/// The [`Key`] contains the identities (`AGE-SECRET-KEY-1...`), one per line, in the format of
+/// the age identity files (empty lines and comments starting with `#` are skipped). Both binary
+/// and ASCII-armored data are accepted. Passphrase-encrypted data isn't supported.
+#[cfg(feature = "age")]
+pub struct Age {
+    key: Key,
+    cached: Option<String>,
+}
+
+#[cfg(feature = "age")]
+impl Age {
+    /// Creates the decryptor with the identities from the given key.
+    pub fn new(key: Key) -> Self {
+        Age { key, cached: None }
+    }
+
+    fn identities(&mut self) -> Result<Vec<age::x25519::Identity>, AnyError> {
+        let content = match &self.cached {
+            Some(cached) => cached.clone(),
+            None => {
+                let content = self.key.read()?;
+                if let Key::Command(_) = self.key {
+                    self.cached = Some(content.clone());
+                }
+                content
+            }
+        };
+        let identities = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.parse().map_err(AnyError::from))
+            .collect::<Result<Vec<age::x25519::Identity>, _>>()
+            .context("Invalid age identity")?;
+        if identities.is_empty() {
+            return Err("No age identities in the key".into());
+        }
+        Ok(identities)
+    }
+}
+
+#[cfg(feature = "age")]
+impl Decryptor for Age {
+    fn decrypt(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, AnyError> {
+        use std::io::Read;
+
+        let identities = self.identities()?;
+        let armored = age::armor::ArmoredReader::new(encrypted);
+        let decryptor = match age::Decryptor::new(armored)? {
+            age::Decryptor::Recipients(decryptor) => decryptor,
+            age::Decryptor::Passphrase(_) => {
+                return Err("Passphrase-encrypted configuration isn't supported".into());
+            }
+        };
+        let identities = identities
+            .into_iter()
+            .map(|identity| Box::new(identity) as Box<dyn age::Identity>);
+        let mut reader = decryptor.decrypt(identities)?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+}
+
+/// A decryptor running the `gpg` binary.
+///
+/// The private keys are taken from the keyring of the user (or the one set by
+/// [`homedir`][Gpg::homedir]). If the key is protected by a passphrase, it's either provided by
+/// the running agent or set by [`passphrase_file`][Gpg::passphrase_file].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Gpg {
+    program: Option<OsString>,
+    homedir: Option<PathBuf>,
+    passphrase_file: Option<PathBuf>,
+}
+
+impl Gpg {
+    /// Creates the decryptor with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the binary to run.
+    ///
+    /// Defaults to `gpg`.
+    pub fn program<P: Into<OsString>>(self, program: P) -> Self {
+        Gpg {
+            program: Some(program.into()),
+            ..self
+        }
+    }
+
+    /// Uses the keyring in the given directory instead of the default one.
+    pub fn homedir<P: Into<PathBuf>>(self, homedir: P) -> Self {
+        Gpg {
+            homedir: Some(homedir.into()),
+            ..self
+        }
+    }
+
+    /// Reads the passphrase of the private key from a file.
+    pub fn passphrase_file<P: Into<PathBuf>>(self, path: P) -> Self {
+        Gpg {
+            passphrase_file: Some(path.into()),
+            ..self
+        }
+    }
+
+    fn command(&self) -> Command {
+        let program = self.program.clone().unwrap_or_else(|| "gpg".into());
+        let mut cmd = Command::new(program);
+        cmd.args(&["--batch", "--quiet", "--no-tty"]);
+        if let Some(homedir) = &self.homedir {
+            cmd.arg("--homedir").arg(homedir);
+        }
+        if let Some(passphrase) = &self.passphrase_file {
+            cmd.args(&["--pinentry-mode", "loopback", "--passphrase-file"])
+                .arg(passphrase);
+        }
+        cmd.arg("--decrypt");
+        cmd
+    }
+}
+
+impl Decryptor for Gpg {
+    fn decrypt(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, AnyError> {
+        Ok(filter(self.command(), encrypted).context("Failed to decrypt with gpg")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_sources() {
+        env::set_var("SPIRIT_CRYPT_TEST_KEY", "secret");
+        assert_eq!(
+            "secret",
+            Key::Env("SPIRIT_CRYPT_TEST_KEY".to_owned()).read().unwrap()
+        );
+        assert!(Key::Env("SPIRIT_CRYPT_NO_SUCH_KEY".to_owned())
+            .read()
+            .is_err());
+        let cmd = Key::Command(vec!["echo".to_owned(), "hello".to_owned()]);
+        assert_eq!("hello\n", cmd.read().unwrap());
+        assert!(Key::Command(Vec::new()).read().is_err());
+        assert!(Key::Command(vec!["false".to_owned()]).read().is_err());
+    }
+
+    #[test]
+    fn filter_cmd() {
+        assert_eq!(
+            b"hello".to_vec(),
+            filter(Command::new("cat"), b"hello").unwrap()
+        );
+        assert!(filter(Command::new("false"), b"hello").is_err());
+    }
+}
@@ -93,6 +93,8 @@ use structdoc::StructDoc;
 #[cfg(feature = "cfg-help")]
 use structopt::StructOpt;
 
+pub mod limits;
+
 /// Configuration of either user or a group.
 ///
 /// This is used to load the configuration into which user and group to drop privileges.
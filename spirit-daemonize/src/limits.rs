@@ -0,0 +1,334 @@
+//! Resource limits (rlimits) of the process.
+//!
+//! The defaults of the OS are often too low for servers (eg. the 1024 open files, which is easy to
+//! exceed by the number of connections). The [`Limits`] configuration fragment sets them from the
+//! configuration instead of relying on the init scripts to do it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_daemonize::limits::Limits;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     limits: Limits,
+//! }
+//!
+//! impl Cfg {
+//!     fn limits(&self) -> Limits {
+//!         self.limits.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(Limits::extension(Cfg::limits))
+//!         .run(|_spirit| {
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! # Added options
+//!
+//! * `nofile`: Maximum number of open file descriptors (including sockets).
+//! * `core`: Maximum size of a core dump, in bytes.
+//! * `nproc`: Maximum number of processes (threads) of the user.
+//! * `memlock`: Maximum amount of memory locked in RAM, in bytes.
+//! * `stack`: Maximum size of the stack of the main thread, in bytes.
+//! * `address-space`: Maximum size of the virtual memory, in bytes.
+//!
+//! Each of them can be either a number or `unlimited`. That sets the soft limit and raises the
+//! hard limit if it is lower. Alternatively, the soft and hard limits can be set separately with a
+//! table with the `soft` and `hard` fields (each optional). Limits not mentioned are left as they
+//! are. Note that raising a hard limit usually needs privileges and lowering it can't be undone.
+//!
+//! ```toml
+//! [limits]
+//! nofile = 65536
+//! core = "unlimited"
+//! stack = { soft = 16777216 }
+//! ```
+
+use std::fmt::{Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+use err_context::prelude::*;
+use log::{debug, info};
+use rlimit::{Resource, INFINITY};
+use serde::de::{Deserializer, Error as DeError, Unexpected, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use spirit::extension::{Extensible, Extension};
+use spirit::validation::Action;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+/// A value of one (soft or hard) limit.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum LimitValue {
+    /// A concrete value.
+    Value(u64),
+    /// No limit.
+    Unlimited,
+}
+
+impl LimitValue {
+    fn raw(self) -> u64 {
+        match self {
+            LimitValue::Value(value) => value,
+            LimitValue::Unlimited => INFINITY,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for LimitValue {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct LimitVisitor;
+
+        impl<'de> Visitor<'de> for LimitVisitor {
+            type Value = LimitValue;
+
+            fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+                fmt.write_str("a non-negative number or \"unlimited\"")
+            }
+
+            fn visit_u64<E: DeError>(self, v: u64) -> Result<LimitValue, E> {
+                Ok(LimitValue::Value(v))
+            }
+
+            fn visit_i64<E: DeError>(self, v: i64) -> Result<LimitValue, E> {
+                if v < 0 {
+                    Err(E::invalid_value(Unexpected::Signed(v), &self))
+                } else {
+                    Ok(LimitValue::Value(v as u64))
+                }
+            }
+
+            fn visit_str<E: DeError>(self, v: &str) -> Result<LimitValue, E> {
+                if v == "unlimited" {
+                    Ok(LimitValue::Unlimited)
+                } else {
+                    v.parse()
+                        .map(LimitValue::Value)
+                        .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+                }
+            }
+        }
+
+        d.deserialize_any(LimitVisitor)
+    }
+}
+
+impl Serialize for LimitValue {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        match self {
+            LimitValue::Value(value) => s.serialize_u64(*value),
+            LimitValue::Unlimited => s.serialize_str("unlimited"),
+        }
+    }
+}
+
+/// Configuration of a single resource limit.
+///
+/// See the [module documentation](index.html) for the format.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[serde(untagged)]
+pub enum Limit {
+    /// Sets the soft limit (raising the hard one if needed).
+    Soft(LimitValue),
+
+    /// Sets the soft and hard limits separately.
+    Separate {
+        /// The soft limit.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        soft: Option<LimitValue>,
+
+        /// The hard limit.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        hard: Option<LimitValue>,
+    },
+}
+
+#[cfg(feature = "cfg-help")]
+impl StructDoc for Limit {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("number, \"unlimited\" or a table with soft and hard")
+    }
+}
+
+impl Limit {
+    /// Computes the new (soft, hard) limits from the current ones.
+    fn compute(self, soft: u64, hard: u64) -> (u64, u64) {
+        match self {
+            // Note that INFINITY is the largest value, so max/min work as expected
+            Limit::Soft(value) => (value.raw(), hard.max(value.raw())),
+            Limit::Separate {
+                soft: new_soft,
+                hard: new_hard,
+            } => {
+                let hard = new_hard.map(LimitValue::raw).unwrap_or(hard);
+                // The soft one can't be above the hard one
+                let soft = new_soft.map(LimitValue::raw).unwrap_or(soft).min(hard);
+                (soft, hard)
+            }
+        }
+    }
+
+    fn apply(self, resource: Resource, name: &str) -> Result<(), AnyError> {
+        let (soft, hard) = rlimit::getrlimit(resource)
+            .with_context(|_| format!("Failed to read the {} limit", name))?;
+        let (new_soft, new_hard) = self.compute(soft, hard);
+        if (soft, hard) == (new_soft, new_hard) {
+            debug!("The {} limit is already {}/{}", name, soft, hard);
+            return Ok(());
+        }
+        rlimit::setrlimit(resource, new_soft, new_hard).with_context(|_| {
+            format!(
+                "Failed to set the {} limit to {}/{}",
+                name, new_soft, new_hard
+            )
+        })?;
+        info!(
+            "Changed the {} limit from {}/{} to {}/{}",
+            name, soft, hard, new_soft, new_hard
+        );
+        Ok(())
+    }
+}
+
+/// A configuration fragment with resource limits of the process.
+///
+/// See the [module documentation](index.html).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Limits {
+    /// Maximum number of open file descriptors (including sockets).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nofile: Option<Limit>,
+
+    /// Maximum size of a core dump, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub core: Option<Limit>,
+
+    /// Maximum number of processes (threads) of the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nproc: Option<Limit>,
+
+    /// Maximum amount of memory locked in RAM, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memlock: Option<Limit>,
+
+    /// Maximum size of the main thread's stack, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stack: Option<Limit>,
+
+    /// Maximum size of the virtual memory, in bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_space: Option<Limit>,
+
+    // Prevent the user from creating this directly.
+    #[serde(default, skip)]
+    sentinel: (),
+}
+
+impl Limits {
+    /// Sets the configured limits.
+    ///
+    /// This does the actual work and can be used manually.
+    pub fn apply(&self) -> Result<(), AnyError> {
+        let limits = [
+            (self.nofile, Resource::NOFILE, "nofile"),
+            (self.core, Resource::CORE, "core"),
+            (self.nproc, Resource::NPROC, "nproc"),
+            (self.memlock, Resource::MEMLOCK, "memlock"),
+            (self.stack, Resource::STACK, "stack"),
+            (self.address_space, Resource::AS, "address-space"),
+        ];
+        for (limit, resource, name) in &limits {
+            if let Some(limit) = limit {
+                limit.apply(*resource, name)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// An extension that can be plugged into the [`Spirit`][spirit::Spirit].
+    ///
+    /// The limits are set during the validation of the configuration, whenever they change. To
+    /// let the other fragments (eg. listening sockets) already see the new limits, register this
+    /// before them.
+    pub fn extension<E, F>(extractor: F) -> impl Extension<E>
+    where
+        E: Extensible<Ok = E>,
+        F: Fn(&E::Config) -> Self + Send + 'static,
+    {
+        let mut previous = None;
+        let validator_hook =
+            move |_: &_, cfg: &Arc<E::Config>, _: &_| -> Result<Action, AnyError> {
+                let limits = extractor(cfg);
+                if previous.as_ref() != Some(&limits) {
+                    limits.apply().context("Failed to set resource limits")?;
+                    previous = Some(limits);
+                }
+                Ok(Action::new())
+            };
+        move |e: E| e.config_validator(validator_hook)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let limits: Limits = toml::from_str(
+            r#"
+            nofile = 65536
+            core = "unlimited"
+            stack = { soft = 1024 }
+            address-space = { hard = "unlimited", soft = "2048" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Some(Limit::Soft(LimitValue::Value(65536))), limits.nofile);
+        assert_eq!(Some(Limit::Soft(LimitValue::Unlimited)), limits.core);
+        assert_eq!(
+            Some(Limit::Separate {
+                soft: Some(LimitValue::Value(1024)),
+                hard: None,
+            }),
+            limits.stack
+        );
+        assert_eq!(
+            Some(Limit::Separate {
+                soft: Some(LimitValue::Value(2048)),
+                hard: Some(LimitValue::Unlimited),
+            }),
+            limits.address_space
+        );
+        assert!(limits.nproc.is_none());
+        assert!(toml::from_str::<Limits>("nofile = -1").is_err());
+    }
+
+    #[test]
+    fn compute() {
+        let soft = Limit::Soft(LimitValue::Value(4096));
+        assert_eq!((4096, 8192), soft.compute(1024, 8192));
+        assert_eq!((4096, 4096), soft.compute(1024, 2048));
+        let hard = Limit::Separate {
+            soft: None,
+            hard: Some(LimitValue::Value(512)),
+        };
+        assert_eq!((512, 512), hard.compute(1024, 2048));
+        let unlimited = Limit::Soft(LimitValue::Unlimited);
+        assert_eq!((INFINITY, INFINITY), unlimited.compute(0, 0));
+    }
+}
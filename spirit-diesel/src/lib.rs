@@ -0,0 +1,372 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-diesel/0.1.0/spirit_diesel/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [diesel] connection pools.
+//!
+//! This provides the [`DbPool`] configuration fragment describing an [r2d2] pool of database
+//! connections and the [`AtomicPool`] handle that always holds the pool corresponding to the
+//! current configuration.
+//!
+//! On reconfiguration, the new pool is built first and a health check (a trivial query) is run
+//! on it. Only if that succeeds, the pool is swapped in. The old pool is not closed forcefully ‒
+//! connections currently checked out of it are returned to it and it goes away once the last of
+//! them is done.
+//!
+//! The database backends are enabled by the `postgres`, `mysql` and `sqlite` features (passed
+//! through to diesel).
+//!
+//! # Examples
+//!
+//! ```rust
+//! use diesel::sqlite::SqliteConnection;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_diesel::{AtomicPool, DbPool};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [db]
+//! url = ":memory:"
+//! max-size = 4
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     db: DbPool,
+//! }
+//!
+//! fn main() {
+//!     let pool = AtomicPool::<SqliteConnection>::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("database")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.db.typed::<SqliteConnection>())
+//!                 .install(pool.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _conn = pool.get()?;
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [r2d2]: https://crates.io/crates/r2d2
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use diesel::connection::{Connection, SimpleConnection};
+use diesel::r2d2::{
+    ConnectionManager, CustomizeConnection, Error as PoolError, Pool, PooledConnection,
+};
+use err_context::prelude::*;
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::{Fragment, Installer};
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_max_size() -> u32 {
+    10
+}
+
+fn default_connection_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_health_check() -> bool {
+    true
+}
+
+/// The pool of connections of type `C`.
+pub type ConnectionPool<C> = Pool<ConnectionManager<C>>;
+
+/// A configuration fragment of a database connection pool.
+///
+/// # Fields
+///
+/// * `url`: The database URL (in the format of the diesel backend). It is hidden when dumping
+///   the configuration, as it often contains a password.
+/// * `max-size`: Maximum number of connections. Defaults to 10.
+/// * `min-idle`: Minimum number of idle connections kept around. Defaults to `max-size`.
+/// * `connection-timeout`: How long to wait when checking out a connection. Defaults to `30s`.
+/// * `idle-timeout`: Close connections idle for longer than this. Defaults to `nil` (never).
+/// * `max-lifetime`: Close connections older than this. Defaults to `nil` (never).
+/// * `statement-timeout`: Set the `statement_timeout` on each new connection. This is a
+///   PostgreSQL setting and can't be used with other databases. Defaults to `nil` (not set).
+/// * `health-check`: Check the database is usable before replacing the pool on
+///   reconfiguration. Defaults to `true`.
+///
+/// To get a [`Fragment`], choose the connection type by [`typed`][DbPool::typed].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct DbPool {
+    /// The database URL.
+    pub url: Hidden<String>,
+
+    /// Maximum number of connections in the pool.
+    #[serde(default = "default_max_size")]
+    pub max_size: u32,
+
+    /// Minimum number of idle connections.
+    ///
+    /// If not set, this is the same as `max-size`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_idle: Option<u32>,
+
+    /// How long to wait for a connection when checking it out.
+    #[serde(
+        default = "default_connection_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub connection_timeout: Duration,
+
+    /// Close connections idle for longer than this.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close connections older than this.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_lifetime: Option<Duration>,
+
+    /// Statement timeout set on each connection (PostgreSQL only).
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub statement_timeout: Option<Duration>,
+
+    /// Check the new pool works before using it.
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+}
+
+impl Default for DbPool {
+    fn default() -> Self {
+        DbPool {
+            url: Hidden(String::new()),
+            max_size: default_max_size(),
+            min_idle: None,
+            connection_timeout: default_connection_timeout(),
+            idle_timeout: None,
+            max_lifetime: None,
+            statement_timeout: None,
+            health_check: default_health_check(),
+        }
+    }
+}
+
+/// Sets up the statement timeout on each new connection.
+#[derive(Debug)]
+struct StatementTimeout(Duration);
+
+impl<C: Connection> CustomizeConnection<C, PoolError> for StatementTimeout {
+    fn on_acquire(&self, conn: &mut C) -> Result<(), PoolError> {
+        let ms = self.0.as_secs() * 1000 + u64::from(self.0.subsec_millis());
+        conn.batch_execute(&format!("SET statement_timeout = {}", ms))
+            .map_err(PoolError::QueryError)
+    }
+}
+
+impl DbPool {
+    /// Chooses the type of connections, making it into a [`Fragment`].
+    pub fn typed<C>(&self) -> TypedDbPool<C> {
+        TypedDbPool {
+            cfg: self.clone(),
+            _conn: PhantomData,
+        }
+    }
+
+    /// Creates the connection pool according to the configuration.
+    ///
+    /// This also runs the health check if configured.
+    pub fn create_pool<C: Connection + Send + 'static>(
+        &self,
+    ) -> Result<ConnectionPool<C>, AnyError> {
+        debug!(
+            "Creating database pool of up to {} connections",
+            self.max_size
+        );
+        let mut builder = Pool::builder()
+            .max_size(self.max_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(self.connection_timeout)
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime);
+        if let Some(timeout) = self.statement_timeout {
+            builder = builder.connection_customizer(Box::new(StatementTimeout(timeout)));
+        }
+        let manager = ConnectionManager::<C>::new(self.url.as_str());
+        let pool = builder
+            .build(manager)
+            .context("Failed to create database pool")?;
+        if self.health_check {
+            trace!("Checking health of the new database pool");
+            pool.get()
+                .context("Can't get a connection from the new pool")?
+                .batch_execute("SELECT 1")
+                .context("Health check of the new pool failed")?;
+        }
+        Ok(pool)
+    }
+}
+
+/// The [`DbPool`] with a chosen connection type.
+///
+/// This is the actual [`Fragment`], created by [`DbPool::typed`].
+pub struct TypedDbPool<C> {
+    cfg: DbPool,
+    _conn: PhantomData<fn() -> C>,
+}
+
+impl<C> Clone for TypedDbPool<C> {
+    fn clone(&self) -> Self {
+        self.cfg.typed()
+    }
+}
+
+impl<C> Debug for TypedDbPool<C> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        self.cfg.fmt(fmt)
+    }
+}
+
+impl<C> PartialEq for TypedDbPool<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cfg == other.cfg
+    }
+}
+
+impl<C> Eq for TypedDbPool<C> {}
+
+impl<C: Connection + Send + 'static> Fragment for TypedDbPool<C> {
+    type Driver = CacheEq<Self>;
+    type Installer = ();
+    type Seed = ();
+    type Resource = ConnectionPool<C>;
+    fn make_seed(&self, _: &'static str) -> Result<(), AnyError> {
+        Ok(())
+    }
+    fn make_resource(&self, _: &mut (), name: &'static str) -> Result<Self::Resource, AnyError> {
+        self.cfg
+            .create_pool()
+            .with_context(|_| format!("Failed to create database pool {}", name))
+            .map_err(AnyError::from)
+    }
+}
+
+/// A handle to the currently configured connection pool.
+///
+/// This is cheap to clone, all the clones share the same pool. It can be used as an
+/// [`Installer`] for the [`TypedDbPool`] fragment.
+///
+/// # Panics
+///
+/// Using the pool before one is set (by [`replace`][AtomicPool::replace] or by the
+/// [`Pipeline`][spirit::Pipeline]) panics.
+pub struct AtomicPool<C: Connection + Send + 'static>(Arc<ArcSwapOption<ConnectionPool<C>>>);
+
+impl<C: Connection + Send + 'static> Clone for AtomicPool<C> {
+    fn clone(&self) -> Self {
+        AtomicPool(Arc::clone(&self.0))
+    }
+}
+
+impl<C: Connection + Send + 'static> Debug for AtomicPool<C> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicPool").finish()
+    }
+}
+
+impl<C: Connection + Send + 'static> Default for AtomicPool<C> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<C: Connection + Send + 'static> AtomicPool<C> {
+    /// Creates an [`AtomicPool`] without any pool inside.
+    pub fn empty() -> Self {
+        AtomicPool(Arc::new(ArcSwapOption::empty()))
+    }
+
+    /// Replaces the pool inside.
+    ///
+    /// The old pool stays alive until all the connections checked out from it are returned.
+    pub fn replace(&self, pool: ConnectionPool<C>) {
+        self.0.store(Some(Arc::new(pool)));
+    }
+
+    /// Returns the current pool.
+    pub fn pool(&self) -> Arc<ConnectionPool<C>> {
+        self.0
+            .load_full()
+            .expect("Accessing database pool before setting it up")
+    }
+
+    /// Checks out a connection from the current pool.
+    pub fn get(&self) -> Result<PooledConnection<ConnectionManager<C>>, AnyError> {
+        self.pool()
+            .get()
+            .context("Failed to get a database connection")
+            .map_err(AnyError::from)
+    }
+}
+
+impl<C: Connection + Send + 'static, O, Cfg> Installer<ConnectionPool<C>, O, Cfg>
+    for AtomicPool<C>
+{
+    type UninstallHandle = ();
+    fn install(&mut self, pool: ConnectionPool<C>, name: &'static str) {
+        debug!("Installing database pool {}", name);
+        self.replace(pool);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::sqlite::SqliteConnection;
+
+    use super::*;
+
+    #[test]
+    fn create_and_swap() {
+        let cfg = DbPool {
+            url: Hidden(":memory:".to_owned()),
+            max_size: 2,
+            ..DbPool::default()
+        };
+        let atomic = AtomicPool::<SqliteConnection>::empty();
+        atomic.replace(cfg.create_pool().unwrap());
+        let conn = atomic.get().unwrap();
+        // The old pool keeps working for connections checked out of it
+        atomic.replace(cfg.create_pool().unwrap());
+        conn.batch_execute("SELECT 1").unwrap();
+        atomic.get().unwrap().batch_execute("SELECT 1").unwrap();
+    }
+}
@@ -0,0 +1,519 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-geoip/0.1.0/spirit_geoip/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! GeoIP lookups in a configured MaxMind database.
+//!
+//! The [`GeoIp`] fragment describes where the database file (in the [MaxMind DB] format, like the
+//! GeoLite2 databases) lives. The created [`Database`] keeps it up to date in a background thread
+//! ‒ it reloads the file when it changes on disk and, if the `download` section is configured,
+//! periodically downloads a new version from MaxMind with the license key (this needs the
+//! `download` feature).
+//!
+//! The database is installed into an [`AtomicGeoIp`] handle the application keeps around and uses
+//! for the lookups. The handle can also produce the hook for
+//! [`EnrichConnections`][spirit_tokio::handlers::EnrichConnections] of `spirit-tokio`, to tag each
+//! accepted connection with the country and the autonomous system of the peer. Requests coming
+//! through a proxy can be looked up by the address in their headers.
+//!
+//! Country (or City) and ASN databases are supported. The [`GeoInfo`] of a lookup has the parts
+//! the database provides filled in.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_geoip::{AtomicGeoIp, GeoInfo, GeoIp};
+//! use spirit_tokio::handlers::{EnrichConnections, Enriched, HandleListener};
+//! use spirit_tokio::TcpListen;
+//! use tokio::net::TcpStream;
+//! use tokio::prelude::*;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [geoip]
+//! path = "/var/lib/geoip/GeoLite2-Country.mmdb"
+//!
+//! [listen]
+//! port = 1234
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     geoip: GeoIp,
+//!     listen: TcpListen,
+//! }
+//!
+//! fn main() {
+//!     let geoip = AtomicGeoIp::new();
+//!     let enrich = geoip.enricher(|conn: &TcpStream| conn.peer_addr());
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("geoip")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.geoip.clone())
+//!                 .install(geoip),
+//!         )
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.listen.clone())
+//!                 .transform(EnrichConnections(enrich))
+//!                 .transform(HandleListener(
+//!                     |conn: Enriched<TcpStream, GeoInfo>, _: &TcpListen| {
+//!                         let country = conn.context().country.clone();
+//!                         let greeting = format!("Hello {}\n", country.unwrap_or_default());
+//!                         tokio::io::write_all(conn, greeting).map(|_| ())
+//!                     },
+//!                 )),
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [MaxMind DB]: https://maxmind.github.io/MaxMind-DB/
+
+use std::cmp;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::Error as IoError;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::{ArcSwap, ArcSwapOption};
+use err_context::prelude::*;
+use log::{debug, info, warn};
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde::{Deserialize, Serialize};
+use spirit::cfg::Duration as CfgDuration;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::{Installer, Stackable};
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+spirit::cfg_defaults! {
+    fn default_reload_interval() -> CfgDuration = CfgDuration(Duration::from_secs(60));
+    fn default_edition() -> String = "GeoLite2-Country".to_owned();
+    fn default_download_interval() -> CfgDuration = CfgDuration(Duration::from_secs(24 * 3600));
+    fn default_url() -> String = "https://download.maxmind.com/app/geoip_download".to_owned();
+}
+
+/// How long to wait before trying again after a failed download.
+const DOWNLOAD_RETRY: Duration = Duration::from_secs(3600);
+
+/// Configuration of periodic downloads of the database from MaxMind.
+///
+/// # Fields
+///
+/// * `license-key`: The MaxMind license key. Hidden when dumping the configuration.
+/// * `edition`: The edition of the database, defaults to `GeoLite2-Country`. Eg.
+///   `GeoLite2-ASN` or `GeoIP2-City` are other options.
+/// * `interval`: How often to download a new version. Defaults to `1day`.
+/// * `url`: Where to download from. Defaults to the MaxMind download service; other services with
+///   the same interface can be used.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Download {
+    /// The MaxMind license key.
+    pub license_key: Hidden<String>,
+
+    /// The edition of the database.
+    #[serde(default = "default_edition")]
+    pub edition: String,
+
+    /// How often to download a new version.
+    #[serde(default = "default_download_interval")]
+    pub interval: CfgDuration,
+
+    /// The download service.
+    #[serde(default = "default_url")]
+    pub url: String,
+}
+
+/// Configuration of a GeoIP database.
+///
+/// # Fields
+///
+/// * `path`: The database file (eg. `GeoLite2-Country.mmdb`).
+/// * `reload-interval`: How often to check if the file changed. Defaults to `1m`.
+/// * `download`: Optional periodic download of new versions into the `path`, described by
+///   [`Download`]. If the file doesn't exist yet, it is downloaded before the database is created.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct GeoIp {
+    /// The database file.
+    pub path: PathBuf,
+
+    /// How often to check if the file changed.
+    #[serde(default = "default_reload_interval")]
+    pub reload_interval: CfgDuration,
+
+    /// Periodic downloads of new versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub download: Option<Download>,
+}
+
+impl Default for GeoIp {
+    fn default() -> Self {
+        GeoIp {
+            path: PathBuf::new(),
+            reload_interval: default_reload_interval(),
+            download: None,
+        }
+    }
+}
+
+impl GeoIp {
+    /// Opens the database and starts the background thread keeping it up to date.
+    pub fn open(&self, name: &'static str) -> Result<Database, AnyError> {
+        if let Some(download) = &self.download {
+            if !cfg!(feature = "download") {
+                return Err("Downloading GeoIP databases needs the download feature".into());
+            }
+            if !self.path.exists() {
+                info!("Downloading the {} database for {}", download.edition, name);
+                fetch(download, &self.path)?;
+            }
+        }
+        let (reader, modified) = load(&self.path)?;
+        debug!(
+            "Opened {} database {} for {}",
+            reader.metadata.database_type,
+            self.path.display(),
+            name
+        );
+        let reader = Arc::new(ArcSwap::from_pointee(reader));
+        let (stop, stopped) = mpsc::channel();
+        let updater = Updater {
+            cfg: self.clone(),
+            name,
+            reader: Arc::clone(&reader),
+            modified,
+        };
+        thread::Builder::new()
+            .name(format!("geoip-{}", name))
+            .spawn(move || updater.run(stopped))?;
+        Ok(Database {
+            path: self.path.clone(),
+            reader,
+            _stop: Mutex::new(stop),
+        })
+    }
+}
+
+impl Stackable for GeoIp {}
+
+spirit::simple_fragment! {
+    impl Fragment for GeoIp {
+        type Driver = CacheEq<GeoIp>;
+        type Resource = Database;
+        type Installer = ();
+        fn create(&self, name: &'static str) -> Result<Database, AnyError> {
+            self.open(name)
+        }
+    }
+}
+
+fn load(path: &Path) -> Result<(Reader<Vec<u8>>, Option<SystemTime>), AnyError> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+    let data = fs::read(path).with_context(|_| format!("Failed to read {}", path.display()))?;
+    let reader = Reader::from_source(data)
+        .with_context(|_| format!("Invalid GeoIP database {}", path.display()))?;
+    Ok((reader, modified))
+}
+
+/// Downloads the database and atomically replaces the file with it.
+#[cfg(feature = "download")]
+fn fetch(cfg: &Download, path: &Path) -> Result<(), AnyError> {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+    use tar::Archive;
+
+    let response = reqwest::Client::new()
+        .get(&cfg.url)
+        .query(&[
+            ("edition_id", cfg.edition.as_str()),
+            ("license_key", cfg.license_key.as_str()),
+            ("suffix", "tar.gz"),
+        ])
+        .send()
+        .and_then(|response| response.error_for_status())
+        .with_context(|_| format!("Failed to download {}", cfg.edition))?;
+    let mut archive = Archive::new(GzDecoder::new(response));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.extension() != Some("mmdb".as_ref()) {
+            continue;
+        }
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        // Don't replace a working database with a broken one
+        Reader::from_source(&data[..])
+            .with_context(|_| format!("Downloaded invalid database {}", cfg.edition))?;
+        let tmp = path.with_extension("mmdb.tmp");
+        fs::write(&tmp, &data).with_context(|_| format!("Failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|_| format!("Failed to replace {}", path.display()))?;
+        return Ok(());
+    }
+    Err(format!("No database in the downloaded {} archive", cfg.edition).into())
+}
+
+#[cfg(not(feature = "download"))]
+fn fetch(_: &Download, _: &Path) -> Result<(), AnyError> {
+    Err("Downloading GeoIP databases needs the download feature".into())
+}
+
+/// The background thread keeping the database up to date.
+struct Updater {
+    cfg: GeoIp,
+    name: &'static str,
+    reader: Arc<ArcSwap<Reader<Vec<u8>>>>,
+    modified: Option<SystemTime>,
+}
+
+impl Updater {
+    fn run(mut self, stopped: Receiver<()>) {
+        let now = SystemTime::now();
+        let mut next_download = self.cfg.download.as_ref().map(|download| {
+            // A fresh enough file (eg. left from the previous run) doesn't need downloading
+            self.modified.unwrap_or(now) + *download.interval
+        });
+        loop {
+            let mut wait = *self.cfg.reload_interval;
+            if let Some(next) = next_download {
+                let until = next.duration_since(SystemTime::now()).unwrap_or_default();
+                wait = cmp::min(wait, until);
+            }
+            match stopped.recv_timeout(wait) {
+                Err(RecvTimeoutError::Timeout) => (),
+                // Sender dropped ‒ the database is no longer used
+                _ => break,
+            }
+            if let (Some(download), Some(next)) = (&self.cfg.download, next_download) {
+                let now = SystemTime::now();
+                if now >= next {
+                    debug!("Downloading {} for {}", download.edition, self.name);
+                    next_download = match fetch(download, &self.cfg.path) {
+                        Ok(()) => Some(now + *download.interval),
+                        Err(e) => {
+                            spirit::log_error!(multi Warn, format!("GeoIP {}", self.name) => e);
+                            Some(now + cmp::min(DOWNLOAD_RETRY, *download.interval))
+                        }
+                    };
+                }
+            }
+            self.check();
+        }
+        debug!("Stopped updating GeoIP database {}", self.name);
+    }
+
+    /// Reloads the file if it changed.
+    fn check(&mut self) {
+        let path = &self.cfg.path;
+        let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => Some(modified),
+            Err(e) => {
+                warn!("Can't check GeoIP database {}: {}", path.display(), e);
+                return;
+            }
+        };
+        if modified == self.modified {
+            return;
+        }
+        match load(path) {
+            Ok((reader, modified)) => {
+                info!(
+                    "Reloaded GeoIP database {} for {}",
+                    path.display(),
+                    self.name
+                );
+                self.reader.store(Arc::new(reader));
+                self.modified = modified;
+            }
+            Err(e) => {
+                spirit::log_error!(multi Warn, format!("GeoIP {}", self.name) => e);
+                // Don't retry until it changes again
+                self.modified = modified;
+            }
+        }
+    }
+}
+
+/// What the database knows about an IP address.
+///
+/// Only the parts provided by the type of the database are filled in.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct GeoInfo {
+    /// The ISO code of the country (eg. `CZ`).
+    ///
+    /// Provided by Country and City databases.
+    pub country: Option<String>,
+
+    /// The number of the autonomous system.
+    ///
+    /// Provided by ASN databases.
+    pub asn: Option<u32>,
+
+    /// The organization owning the autonomous system.
+    ///
+    /// Provided by ASN databases.
+    pub as_org: Option<String>,
+}
+
+fn lookup(reader: &Reader<Vec<u8>>, ip: IpAddr) -> Result<GeoInfo, MaxMindDBError> {
+    let mut info = GeoInfo::default();
+    if reader.metadata.database_type.contains("ASN") {
+        let asn: geoip2::Asn = reader.lookup(ip)?;
+        info.asn = asn.autonomous_system_number;
+        info.as_org = asn.autonomous_system_organization;
+    } else {
+        // City databases are a superset of Country ones
+        let country: geoip2::Country = reader.lookup(ip)?;
+        info.country = country
+            .country
+            .or(country.registered_country)
+            .and_then(|country| country.iso_code);
+    }
+    Ok(info)
+}
+
+/// An open GeoIP database.
+///
+/// Created by the [`GeoIp`] fragment. It is kept up to date by a background thread for as long as
+/// it exists.
+pub struct Database {
+    path: PathBuf,
+    reader: Arc<ArcSwap<Reader<Vec<u8>>>>,
+    // Dropping it stops the background thread
+    _stop: Mutex<Sender<()>>,
+}
+
+impl Database {
+    /// Looks up an IP address.
+    ///
+    /// Addresses not in the database produce an empty [`GeoInfo`].
+    pub fn lookup(&self, ip: IpAddr) -> Result<GeoInfo, AnyError> {
+        match lookup(&self.reader.load(), ip) {
+            Ok(info) => Ok(info),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(GeoInfo::default()),
+            Err(e) => Err(e
+                .context(format!(
+                    "Failed to look up {} in {}",
+                    ip,
+                    self.path.display()
+                ))
+                .into()),
+        }
+    }
+}
+
+impl Debug for Database {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Database")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// A handle to the current GeoIP database.
+///
+/// This is the [`Installer`] for the [`Database`]. The application keeps a clone and looks up
+/// addresses through it; the database inside is replaced whenever the configuration changes.
+/// Removing the configuration keeps the last database in place.
+#[derive(Clone, Debug, Default)]
+pub struct AtomicGeoIp(Arc<ArcSwapOption<Database>>);
+
+impl AtomicGeoIp {
+    /// Creates a handle without any database.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the database inside.
+    pub fn replace<D: Into<Arc<Database>>>(&self, database: D) {
+        self.0.store(Some(database.into()));
+    }
+
+    /// Is there a database inside?
+    pub fn is_configured(&self) -> bool {
+        self.0.load().is_some()
+    }
+
+    /// Looks up an IP address.
+    ///
+    /// If there's no database yet or the lookup fails, the result is empty (failures are
+    /// logged). Use [`Database::lookup`] to get the errors.
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        match self.0.load().as_ref().map(|db| db.lookup(ip)) {
+            Some(Ok(info)) => info,
+            Some(Err(e)) => {
+                spirit::log_error!(multi Warn, e);
+                GeoInfo::default()
+            }
+            None => GeoInfo::default(),
+        }
+    }
+
+    /// Creates a hook for tagging connections with their [`GeoInfo`].
+    ///
+    /// The result is meant for the
+    /// [`EnrichConnections`][spirit_tokio::handlers::EnrichConnections] transformation of
+    /// `spirit-tokio`. The `addr` closure extracts the address of the peer from the connection
+    /// (usually by its `peer_addr` method).
+    pub fn enricher<Conn, Cfg, A>(
+        &self,
+        addr: A,
+    ) -> impl Fn(&Conn, &Cfg) -> Result<GeoInfo, AnyError> + Clone + Send + Sync + 'static
+    where
+        A: Fn(&Conn) -> Result<SocketAddr, IoError> + Clone + Send + Sync + 'static,
+        Conn: 'static,
+        Cfg: 'static,
+    {
+        let geoip = self.clone();
+        move |conn: &Conn, _: &Cfg| Ok(geoip.lookup(addr(conn)?.ip()))
+    }
+}
+
+impl<O, C> Installer<Database, O, C> for AtomicGeoIp {
+    type UninstallHandle = ();
+    fn install(&mut self, database: Database, name: &'static str) {
+        debug!("Installing GeoIP database {}", name);
+        self.replace(database);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unconfigured() {
+        let geoip = AtomicGeoIp::new();
+        assert!(!geoip.is_configured());
+        assert_eq!(
+            GeoInfo::default(),
+            geoip.lookup("192.0.2.1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn missing_file() {
+        let cfg = GeoIp {
+            path: PathBuf::from("/does/not/exist.mmdb"),
+            ..GeoIp::default()
+        };
+        assert!(cfg.open("test").is_err());
+    }
+}
@@ -0,0 +1,687 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-h3/0.1.0/spirit_h3/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Experimental HTTP/3 servers.
+//!
+//! The [`Http3Server`] fragment describes an HTTP/3 (QUIC) server ‒ the UDP socket it listens on
+//! (the same options as [`spirit_tokio::net::Listen`]) and its certificate (the `tls-cert`,
+//! `tls-key` and `tls-ca` options of the [`TlsConfig`] shared with the TLS listeners of
+//! `spirit-tokio`). It is installed by an [`H3Installer`], which runs the servers on the given
+//! runtime and passes the requests to an async handler. The runtime is a tokio 1 one, as [quinn]
+//! and [h3] don't run on the tokio the rest of spirit is built on.
+//!
+//! This is experimental. The handler gets the whole request body at once and produces the whole
+//! response, there's no streaming.
+//!
+//! If the socket configuration doesn't change, the QUIC endpoint is kept across reloads. The new
+//! certificate and limits apply to new connections, the established ones are left to finish. When
+//! the application terminates, the servers stop accepting new connections.
+//!
+//! # Advertisement
+//!
+//! The clients find out about an HTTP/3 server through the `Alt-Svc` header of a TCP-based server
+//! serving the same content. [`Http3Server::alt_svc`] describes the advertisement and the
+//! [`HyperServer::with_alt_svc`][spirit_hyper::HyperServer::with_alt_svc] adds it to the
+//! configuration of a `spirit-hyper` server, so both stay in sync through reloads.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use bytes::Bytes;
+//! use http::{Request, Response};
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_h3::{H3Installer, Http3Server};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [h3]
+//! port = 4433
+//! tls-cert = "/etc/ssl/certs/server.pem"
+//! tls-key = "/etc/ssl/private/server.key"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     h3: Http3Server,
+//! }
+//!
+//! async fn hello(_req: Request<Bytes>) -> Response<Bytes> {
+//!     Response::new(Bytes::from_static(b"Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     let runtime = tokio::runtime::Runtime::new().unwrap();
+//!     let installer = H3Installer::new(runtime.handle().clone(), hello);
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("h3")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.h3.clone())
+//!                 .install(installer)
+//!         )
+//!         .run(|spirit| {
+//!             while !spirit.is_terminated() {
+//!                 std::thread::sleep(std::time::Duration::from_millis(100));
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [quinn]: https://crates.io/crates/quinn
+//! [h3]: https://crates.io/crates/h3
+
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs::File;
+use std::future::Future;
+use std::io::BufReader;
+use std::net::UdpSocket as StdUdpSocket;
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use err_context::prelude::*;
+use futures::future::{self, Either};
+use h3::server::RequestStream;
+use http::{Request, Response, StatusCode};
+use log::{debug, error, info, trace};
+use quinn::{Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, TransportConfig};
+use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::{Certificate, PrivateKey, RootCertStore};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::cfg::ByteSize;
+use spirit::extension::Extensible;
+use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
+use spirit::fragment::{Fragment, Installer, Stackable};
+use spirit::AnyError;
+use spirit_hyper::alt_svc::AltSvc;
+use spirit_tls::TlsConfig;
+use spirit_tokio::net::Listen;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot::{self, Sender};
+
+/// The ALPN protocol ID of HTTP/3.
+const ALPN: &[u8] = b"h3";
+
+fn default_max_body_size() -> ByteSize {
+    ByteSize(1024 * 1024)
+}
+
+fn default_max_age() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, AnyError> {
+    let file = File::open(path)?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))?;
+    if certs.is_empty() {
+        return Err("No certificates found".into());
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey, AnyError> {
+    let file = File::open(path)?;
+    rustls_pemfile::read_all(&mut BufReader::new(file))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| "No private key found".into())
+}
+
+/// Configuration of an HTTP/3 server.
+///
+/// # Fields
+///
+/// * The UDP socket to listen on (`port`, `host`, `address`, ...). See
+///   [`spirit_tokio::net::Listen`] for the options.
+/// * `tls-cert`, `tls-key`: The certificate chain and the private key of the server, in PEM.
+///   Mandatory.
+/// * `tls-ca`: If set, the clients need to present a certificate signed by one of these
+///   authorities.
+/// * `max-body-size`: The largest accepted request body. Larger ones are answered with `413`.
+///   Defaults to `1MiB`.
+/// * `max-concurrent-streams`: How many requests a client may have in flight on one connection.
+///   Left on the quinn default if not set.
+/// * `idle-timeout`: Connections without any activity for this long are closed. Left on the
+///   quinn default if not set.
+/// * `alt-svc-max-age`: How long the clients may remember the advertisement of this server (see
+///   [`alt_svc`][Http3Server::alt_svc]). Defaults to `24h`.
+///
+/// The other options of the [`TlsConfig`] (versions, cipher suites, ALPN, ...) don't apply to
+/// QUIC and are refused.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Http3Server {
+    /// The UDP socket to listen on.
+    #[serde(flatten)]
+    pub listen: Listen,
+
+    /// The certificate of the server.
+    #[serde(flatten)]
+    pub tls: TlsConfig,
+
+    /// The largest accepted request body.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: ByteSize,
+
+    /// How many requests a client may have in flight on one connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_streams: Option<u32>,
+
+    /// Close connections without any activity for this long.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<Duration>,
+
+    /// How long the clients may remember the advertisement of this server.
+    #[serde(
+        default = "default_max_age",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub alt_svc_max_age: Duration,
+}
+
+impl Default for Http3Server {
+    fn default() -> Self {
+        Http3Server {
+            listen: Listen::default(),
+            tls: TlsConfig::default(),
+            max_body_size: default_max_body_size(),
+            max_concurrent_streams: None,
+            idle_timeout: None,
+            alt_svc_max_age: default_max_age(),
+        }
+    }
+}
+
+impl Http3Server {
+    /// The advertisement of this server.
+    ///
+    /// Meant to be added to the TCP-based server serving the same content, by
+    /// [`HyperServer::with_alt_svc`][spirit_hyper::HyperServer::with_alt_svc]. Returns `None`
+    /// if the port is not known from the configuration (including port `0`, picked by the OS).
+    pub fn alt_svc(&self) -> Option<AltSvc> {
+        let port = self.listen.port().filter(|&port| port != 0);
+        port.map(|port| AltSvc {
+            protocol: String::from_utf8_lossy(ALPN).into_owned(),
+            host: None,
+            port,
+            max_age: self.alt_svc_max_age,
+        })
+    }
+
+    /// Loads the certificates and creates the quinn configuration of the server.
+    pub fn server_config(&self) -> Result<ServerConfig, AnyError> {
+        if !self.tls.files_only() {
+            return Err("Only the tls-cert, tls-key and tls-ca TLS options apply to HTTP/3".into());
+        }
+        let (cert, key) = match (&self.tls.tls_cert, &self.tls.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => return Err("HTTP/3 server needs both tls-cert and tls-key".into()),
+        };
+        let certs = load_certs(cert)
+            .with_context(|_| format!("Failed to load certificate {}", cert.display()))?;
+        spirit_tls::expiry::track_file(cert);
+        let key =
+            load_key(key).with_context(|_| format!("Failed to load key {}", key.display()))?;
+        let crypto = rustls::ServerConfig::builder().with_safe_defaults();
+        let crypto = match &self.tls.tls_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                let certs = load_certs(ca)
+                    .with_context(|_| format!("Failed to load CA {}", ca.display()))?;
+                for cert in &certs {
+                    roots
+                        .add(cert)
+                        .with_context(|_| format!("Invalid CA in {}", ca.display()))?;
+                }
+                crypto.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => crypto.with_no_client_auth(),
+        };
+        let mut crypto = crypto
+            .with_single_cert(certs, key)
+            .context("Invalid certificate of HTTP/3 server")?;
+        crypto.alpn_protocols = vec![ALPN.to_vec()];
+
+        let mut transport = TransportConfig::default();
+        if let Some(streams) = self.max_concurrent_streams {
+            transport.max_concurrent_bidi_streams(streams.into());
+        }
+        if let Some(timeout) = self.idle_timeout {
+            let timeout = IdleTimeout::try_from(timeout)
+                .map_err(|_| format!("Idle timeout {:?} too large", timeout))?;
+            transport.max_idle_timeout(Some(timeout));
+        }
+        let mut config = ServerConfig::with_crypto(Arc::new(crypto));
+        config.transport_config(Arc::new(transport));
+        Ok(config)
+    }
+}
+
+impl Stackable for Http3Server {}
+
+impl Comparable for Http3Server {
+    fn compare(&self, other: &Self) -> Comparison {
+        if self.listen != other.listen {
+            Comparison::Dissimilar
+        } else if self != other {
+            Comparison::Similar
+        } else {
+            Comparison::Same
+        }
+    }
+}
+
+impl Fragment for Http3Server {
+    type Driver = CacheSimilar<Self>;
+    type Installer = ();
+    type Seed = QuicSocket;
+    type Resource = H3Resource;
+    fn make_seed(&self, name: &'static str) -> Result<QuicSocket, AnyError> {
+        let socket = self
+            .listen
+            .create_udp()
+            .with_context(|_| format!("Failed to create socket {}/{:?}", name, self))?;
+        Ok(QuicSocket(Arc::new(SocketInner {
+            socket,
+            endpoint: Mutex::new(None),
+        })))
+    }
+    fn make_resource(
+        &self,
+        seed: &mut QuicSocket,
+        _name: &'static str,
+    ) -> Result<H3Resource, AnyError> {
+        Ok(H3Resource {
+            socket: Arc::clone(&seed.0),
+            config: self.server_config()?,
+            max_body_size: self.max_body_size.bytes(),
+        })
+    }
+}
+
+struct SocketInner {
+    socket: StdUdpSocket,
+    // Created lazily, inside the runtime
+    endpoint: Mutex<Option<Endpoint>>,
+}
+
+impl SocketInner {
+    /// Gets the endpoint on this socket and switches it to the new configuration.
+    ///
+    /// Needs to be called inside the runtime.
+    fn endpoint(&self, config: ServerConfig) -> Result<Endpoint, AnyError> {
+        let mut endpoint = self.endpoint.lock().unwrap();
+        if let Some(endpoint) = endpoint.as_ref() {
+            endpoint.set_server_config(Some(config));
+            return Ok(endpoint.clone());
+        }
+        let socket = self.socket.try_clone()?;
+        let created = Endpoint::new(
+            EndpointConfig::default(),
+            Some(config),
+            socket,
+            Arc::new(TokioRuntime),
+        )
+        .context("Failed to create QUIC endpoint")?;
+        *endpoint = Some(created.clone());
+        Ok(created)
+    }
+
+    /// Stops accepting new connections.
+    ///
+    /// The established ones are left alone.
+    fn close(&self) {
+        if let Some(endpoint) = self.endpoint.lock().unwrap().as_ref() {
+            endpoint.set_server_config(None);
+        }
+    }
+}
+
+impl Drop for SocketInner {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// A bound UDP socket, with the QUIC endpoint on top of it.
+///
+/// This is the seed of the [`Http3Server`] fragment. The user should not need to interact with
+/// this directly.
+pub struct QuicSocket(Arc<SocketInner>);
+
+impl Debug for QuicSocket {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple("QuicSocket").field(&self.0.socket).finish()
+    }
+}
+
+/// An HTTP/3 server ready to be started.
+///
+/// This is what the [`Http3Server`] fragment creates. The user should not need to interact with
+/// this directly.
+pub struct H3Resource {
+    socket: Arc<SocketInner>,
+    config: ServerConfig,
+    max_body_size: u64,
+}
+
+impl Debug for H3Resource {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("H3Resource")
+            .field("socket", &self.socket.socket)
+            .field("max_body_size", &self.max_body_size)
+            .finish()
+    }
+}
+
+fn status(status: StatusCode) -> Response<Bytes> {
+    let mut response = Response::new(Bytes::new());
+    *response.status_mut() = status;
+    response
+}
+
+async fn request<H, F>(
+    req: Request<()>,
+    mut stream: RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>,
+    handler: Arc<H>,
+    max_body_size: u64,
+) -> Result<(), AnyError>
+where
+    H: Fn(Request<Bytes>) -> F,
+    F: Future<Output = Response<Bytes>>,
+{
+    let mut body = BytesMut::new();
+    let mut too_large = false;
+    while let Some(chunk) = stream.recv_data().await? {
+        body.put(chunk);
+        if body.len() as u64 > max_body_size {
+            too_large = true;
+            break;
+        }
+    }
+    let response = if too_large {
+        status(StatusCode::PAYLOAD_TOO_LARGE)
+    } else {
+        let (parts, ()) = req.into_parts();
+        handler(Request::from_parts(parts, body.freeze())).await
+    };
+    let (parts, body) = response.into_parts();
+    stream
+        .send_response(Response::from_parts(parts, ()))
+        .await?;
+    if !body.is_empty() {
+        stream.send_data(body).await?;
+    }
+    stream.finish().await?;
+    Ok(())
+}
+
+async fn connection<H, F>(
+    connecting: quinn::Connecting,
+    handler: Arc<H>,
+    max_body_size: u64,
+) -> Result<(), AnyError>
+where
+    H: Fn(Request<Bytes>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Bytes>> + Send + 'static,
+{
+    let conn = connecting.await?;
+    let peer = conn.remote_address();
+    trace!("New HTTP/3 connection from {}", peer);
+    let mut conn = h3::server::Connection::<_, Bytes>::new(h3_quinn::Connection::new(conn)).await?;
+    while let Some((req, stream)) = conn.accept().await? {
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = request(req, stream, handler, max_body_size).await {
+                debug!("HTTP/3 request from {} failed: {}", peer, e.display("; "));
+            }
+        });
+    }
+    trace!("HTTP/3 connection from {} closed", peer);
+    Ok(())
+}
+
+async fn serve<H, F>(
+    endpoint: Endpoint,
+    handler: Arc<H>,
+    max_body_size: u64,
+    mut shutdown: oneshot::Receiver<()>,
+) where
+    H: Fn(Request<Bytes>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Bytes>> + Send + 'static,
+{
+    loop {
+        let accept = endpoint.accept();
+        futures::pin_mut!(accept);
+        let connecting = match future::select(accept, &mut shutdown).await {
+            Either::Left((Some(connecting), _)) => connecting,
+            // Either the endpoint is closed or this server is shut down (or replaced)
+            _ => break,
+        };
+        let handler = Arc::clone(&handler);
+        tokio::spawn(async move {
+            if let Err(e) = connection(connecting, handler, max_body_size).await {
+                debug!("HTTP/3 connection failed: {}", e.display("; "));
+            }
+        });
+    }
+}
+
+struct Running {
+    shutdown: Mutex<Option<Sender<()>>>,
+    socket: Arc<SocketInner>,
+}
+
+impl Running {
+    fn shutdown(&self) {
+        if let Some(sender) = self.shutdown.lock().unwrap().take() {
+            // The server might be gone already, due to an error
+            let _ = sender.send(());
+        }
+    }
+}
+
+/// A running HTTP/3 server.
+///
+/// This is the uninstall handle of [`H3Installer`]. Dropping it stops the server from accepting
+/// new connections (a server replacing it on the same socket takes over), the established ones
+/// are left to finish. The user should not need to interact with this directly.
+pub struct RunningServer(Arc<Running>);
+
+impl Debug for RunningServer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("RunningServer").finish()
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// An [`Installer`] of [`Http3Server`]s.
+///
+/// The handler passed to [`new`][H3Installer::new] is an async function answering the requests.
+/// The servers run on the runtime of the given handle and are shut down when the application
+/// terminates.
+pub struct H3Installer<H> {
+    handle: Handle,
+    handler: Arc<H>,
+    running: Arc<Mutex<Vec<Weak<Running>>>>,
+}
+
+impl<H> Clone for H3Installer<H> {
+    fn clone(&self) -> Self {
+        H3Installer {
+            handle: self.handle.clone(),
+            handler: Arc::clone(&self.handler),
+            running: Arc::clone(&self.running),
+        }
+    }
+}
+
+impl<H> Debug for H3Installer<H> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("H3Installer").finish()
+    }
+}
+
+impl<H, F> H3Installer<H>
+where
+    H: Fn(Request<Bytes>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Bytes>> + Send + 'static,
+{
+    /// Creates the installer.
+    ///
+    /// The servers are spawned onto the runtime of the `handle`.
+    pub fn new(handle: Handle, handler: H) -> Self {
+        H3Installer {
+            handle,
+            handler: Arc::new(handler),
+            running: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<H> H3Installer<H> {
+    /// Shuts down all the servers installed through this installer.
+    ///
+    /// They stop accepting new connections, the established ones are left to finish.
+    pub fn shutdown(&self) {
+        let running = self.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for server in running.iter().filter_map(Weak::upgrade) {
+            server.shutdown();
+            server.socket.close();
+        }
+    }
+}
+
+impl<H, F, O, C> Installer<H3Resource, O, C> for H3Installer<H>
+where
+    H: Fn(Request<Bytes>) -> F + Send + Sync + 'static,
+    F: Future<Output = Response<Bytes>> + Send + 'static,
+{
+    type UninstallHandle = RunningServer;
+    fn install(&mut self, resource: H3Resource, name: &'static str) -> RunningServer {
+        let H3Resource {
+            socket,
+            config,
+            max_body_size,
+        } = resource;
+        let (sender, receiver) = oneshot::channel();
+        let handler = Arc::clone(&self.handler);
+        let endpoint_socket = Arc::clone(&socket);
+        debug!("Starting HTTP/3 server {}", name);
+        self.handle.spawn(async move {
+            // The endpoint needs to be created inside the runtime
+            match endpoint_socket.endpoint(config) {
+                Ok(endpoint) => {
+                    serve(endpoint, handler, max_body_size, receiver).await;
+                    info!("HTTP/3 server {} terminated", name);
+                }
+                Err(e) => error!("HTTP/3 server {} failed: {}", name, e.display("; ")),
+            }
+        });
+        let running = Arc::new(Running {
+            shutdown: Mutex::new(Some(sender)),
+            socket,
+        });
+        let mut all = self.running.lock().unwrap();
+        all.retain(|server| server.strong_count() > 0);
+        all.push(Arc::downgrade(&running));
+        trace!("{} HTTP/3 servers running", all.len());
+        RunningServer(running)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn alt_svc() {
+        let server: Http3Server = serde_json::from_str(r#"{"port": 4433}"#).unwrap();
+        let alt_svc = server.alt_svc().unwrap();
+        assert_eq!("h3=\":4433\"; ma=86400", alt_svc.entry());
+        assert!(Http3Server::default().alt_svc().is_none());
+    }
+
+    #[test]
+    fn compare_socket() {
+        let server: Http3Server = serde_json::from_str(r#"{"port": 4433}"#).unwrap();
+        let limited = Http3Server {
+            max_concurrent_streams: Some(10),
+            ..server.clone()
+        };
+        assert_eq!(Comparison::Same, server.compare(&server.clone()));
+        assert_eq!(Comparison::Similar, server.compare(&limited));
+        assert_eq!(
+            Comparison::Dissimilar,
+            server.compare(&Http3Server::default())
+        );
+    }
+
+    #[test]
+    fn tls_options() {
+        assert!(Http3Server::default().server_config().is_err());
+        let missing = Http3Server {
+            tls: TlsConfig {
+                tls_cert: Some(PathBuf::from("/does/not/exist.pem")),
+                tls_key: Some(PathBuf::from("/does/not/exist.key")),
+                ..TlsConfig::default()
+            },
+            ..Http3Server::default()
+        };
+        assert!(missing.server_config().is_err());
+        let alpn = Http3Server {
+            tls: TlsConfig {
+                tls_alpn: vec!["h2".to_owned()],
+                ..missing.tls.clone()
+            },
+            ..Http3Server::default()
+        };
+        let err = alpn.server_config().unwrap_err();
+        assert!(err.to_string().contains("Only the tls-cert"));
+    }
+}
@@ -0,0 +1,76 @@
+//! The access log shared by the request wrappers.
+//!
+//! Several wrappers (like [`Traced`][crate::trace::Traced] and
+//! [`RequestIds`][crate::request_id::RequestIds]) know something worth logging about a request.
+//! Only the outermost of them logs the line, the inner ones just add their fields to it (through
+//! the extensions of the request).
+
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use hyper::Request;
+use log::{debug, warn};
+
+const TARGET: &str = "spirit_hyper::access";
+
+#[derive(Debug, Default)]
+pub(crate) struct Fields {
+    pub(crate) request_id: Option<String>,
+    pub(crate) trace_id: Option<String>,
+    pub(crate) user: Option<String>,
+}
+
+/// Marks the request as already logged by an outer wrapper.
+#[derive(Clone)]
+struct Shared(Arc<Mutex<Fields>>);
+
+/// The access log line of one request.
+pub(crate) struct Entry {
+    request: String,
+    fields: Arc<Mutex<Fields>>,
+}
+
+/// Starts the log line, unless an outer wrapper already did.
+pub(crate) fn start<B>(req: &mut Request<B>) -> Option<Entry> {
+    if req.extensions().get::<Shared>().is_some() {
+        return None;
+    }
+    let fields = Arc::new(Mutex::new(Fields::default()));
+    req.extensions_mut().insert(Shared(Arc::clone(&fields)));
+    Some(Entry {
+        request: format!("{} {}", req.method(), req.uri()),
+        fields,
+    })
+}
+
+/// Adds fields to the log line of the request.
+pub(crate) fn record<B, F: FnOnce(&mut Fields)>(req: &Request<B>, update: F) {
+    if let Some(Shared(fields)) = req.extensions().get::<Shared>() {
+        update(&mut fields.lock().unwrap_or_else(PoisonError::into_inner));
+    }
+}
+
+impl Entry {
+    fn fields(&self) -> String {
+        let fields = self.fields.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut result = String::new();
+        if let Some(id) = &fields.request_id {
+            let _ = write!(result, " request_id={}", id);
+        }
+        if let Some(id) = &fields.trace_id {
+            let _ = write!(result, " trace={}", id);
+        }
+        if let Some(user) = &fields.user {
+            let _ = write!(result, " user={}", user);
+        }
+        result
+    }
+
+    pub(crate) fn finished(&self, status: u16) {
+        debug!(target: TARGET, "{} {}{}", self.request, status, self.fields());
+    }
+
+    pub(crate) fn failed(&self) {
+        warn!(target: TARGET, "{} failed{}", self.request, self.fields());
+    }
+}
@@ -0,0 +1,311 @@
+//! Advertising alternative services.
+//!
+//! A server may tell its clients the same content is available through another protocol or port
+//! by the `Alt-Svc` response header. This is how clients discover an HTTP/3 (QUIC) endpoint
+//! serving the same site as a TCP-based server.
+//!
+//! This crate doesn't provide the HTTP/3 server itself (the QUIC implementations need a newer
+//! tokio than the one this crate is built on), that one lives in the `spirit-h3` crate. The
+//! advertisement can be configured here or taken from the HTTP/3 server configuration by
+//! [`HyperServer::with_alt_svc`][crate::HyperServer::with_alt_svc].
+//!
+//! The alternatives are configured by the `alt-svc` option of the [`HyperServer`] (an array of
+//! [`AltSvc`]), the [`Advertised`] wrapper adds the header to all the responses of a service.
+//! The [`Serve`][crate::Serve] transformation puts the wrapper in place by itself.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::alt_svc::Advertised;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//!
+//! [[server.alt-svc]]
+//! protocol = "h3"
+//! port = 1234
+//! max-age = "1h"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     builder.serve(Advertised::new(cfg.alt_svc(), || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::time::Duration;
+
+use futures::{try_ready, Async, Future, Poll};
+use hyper::header::{HeaderValue, ALT_SVC};
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response};
+use log::warn;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_protocol() -> String {
+    "h3".to_owned()
+}
+
+fn default_max_age() -> Duration {
+    Duration::from_secs(24 * 60 * 60)
+}
+
+/// One alternative service to advertise.
+///
+/// # Fields
+///
+/// * `protocol`: The ALPN protocol ID of the alternative service. Defaults to `h3`.
+/// * `host`: The host of the alternative service. Defaults to the same host.
+/// * `port`: The port of the alternative service.
+/// * `max-age`: How long the client may remember the advertisement. Defaults to `24h`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct AltSvc {
+    /// The ALPN protocol ID of the alternative service.
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+
+    /// The host of the alternative service.
+    ///
+    /// If not present, the same host as the one of the request is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+
+    /// The port of the alternative service.
+    pub port: u16,
+
+    /// How long the client may remember the advertisement.
+    #[serde(
+        default = "default_max_age",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub max_age: Duration,
+}
+
+impl AltSvc {
+    /// Formats this one alternative as it appears inside the `Alt-Svc` header.
+    pub fn entry(&self) -> String {
+        format!(
+            "{}=\"{}:{}\"; ma={}",
+            self.protocol,
+            self.host.as_ref().map(String::as_str).unwrap_or(""),
+            self.port,
+            self.max_age.as_secs(),
+        )
+    }
+
+    /// Creates the whole value of the `Alt-Svc` header for the given alternatives.
+    ///
+    /// Returns `None` if there's nothing to advertise or the configuration contains something
+    /// that can't be put into a header.
+    pub fn header(alternatives: &[AltSvc]) -> Option<HeaderValue> {
+        if alternatives.is_empty() {
+            return None;
+        }
+        let value = alternatives
+            .iter()
+            .map(AltSvc::entry)
+            .collect::<Vec<_>>()
+            .join(", ");
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                warn!("Can't advertise alternative services {}: {}", value, e);
+                None
+            }
+        }
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] that adds the `Alt-Svc` header to the responses.
+///
+/// The header is not added if the response already has one.
+pub struct Advertised<MS> {
+    header: Option<HeaderValue>,
+    inner: MS,
+}
+
+impl<MS> Advertised<MS> {
+    /// Wraps the [`MakeService`], advertising the given alternatives.
+    pub fn new(alternatives: &[AltSvc], inner: MS) -> Self {
+        Advertised {
+            header: AltSvc::header(alternatives),
+            inner,
+        }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Advertised<MS>
+where
+    MS: MakeService<&'a Ctx>,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = AdvertisedService<MS::Service>;
+    type Future = AdvertisedMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        AdvertisedMake {
+            header: self.header.clone(),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`AdvertisedService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct AdvertisedMake<F> {
+    header: Option<HeaderValue>,
+    inner: F,
+}
+
+impl<F: Future> Future for AdvertisedMake<F> {
+    type Item = AdvertisedService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(AdvertisedService {
+            header: self.header.take(),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that adds the header.
+///
+/// The user should not need to interact with this directly.
+pub struct AdvertisedService<S> {
+    header: Option<HeaderValue>,
+    inner: S,
+}
+
+impl<S: Service> Service for AdvertisedService<S> {
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = AdvertisedResponse<S::Future>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        AdvertisedResponse {
+            header: self.header.clone(),
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+/// A plumbing future adding the header to one response.
+///
+/// The user should not need to interact with this directly.
+pub struct AdvertisedResponse<F> {
+    header: Option<HeaderValue>,
+    inner: F,
+}
+
+impl<F, B> Future for AdvertisedResponse<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut response = try_ready!(self.inner.poll());
+        if let Some(header) = self.header.take() {
+            response
+                .headers_mut()
+                .entry(ALT_SVC)
+                .unwrap()
+                .or_insert(header);
+        }
+        Ok(Async::Ready(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_value() {
+        let alternatives = vec![
+            AltSvc {
+                protocol: default_protocol(),
+                host: None,
+                port: 443,
+                max_age: default_max_age(),
+            },
+            AltSvc {
+                protocol: "h2".to_owned(),
+                host: Some("alt.example.com".to_owned()),
+                port: 8443,
+                max_age: Duration::from_secs(60),
+            },
+        ];
+        let header = AltSvc::header(&alternatives).unwrap();
+        assert_eq!(
+            r#"h3=":443"; ma=86400, h2="alt.example.com:8443"; ma=60"#,
+            header
+        );
+        assert!(AltSvc::header(&[]).is_none());
+    }
+    #[test]
+    fn added_once() {
+        let alt_svc = AltSvc {
+            protocol: default_protocol(),
+            host: None,
+            port: 4433,
+            max_age: default_max_age(),
+        };
+        let server = crate::HttpServer::default()
+            .with_alt_svc(alt_svc.clone())
+            .with_alt_svc(alt_svc.clone());
+        assert_eq!(&[alt_svc], server.alt_svc());
+    }
+}
@@ -0,0 +1,874 @@
+//! Simple authentication of requests.
+//!
+//! Endpoints like admin interfaces or metrics often need some protection, but not a full-blown
+//! identity management. The `auth` option of the [`HyperServer`] ([`Auth`]) lists who is allowed
+//! in and the [`Authenticated`] wrapper enforces it, before the wrapped service sees the request:
+//!
+//! * Static bearer tokens, sent as `Authorization: Bearer <token>`.
+//! * Users with passwords (HTTP basic authentication), in the format of `htpasswd` files. The
+//!   passwords may be hashed by bcrypt (`htpasswd -B`) or SHA-1 (`htpasswd -s`).
+//! * Network ranges that are let in without any credentials (for example the local monitoring).
+//!
+//! Both the tokens and the users can be placed directly in the configuration (where they are
+//! hidden when dumping it and can be encrypted, if the application supports encrypted values) or
+//! read from files (`tokens-file` and `users-file`, one per line), which plays well with secrets
+//! mounted as files. The files are read whenever the server is (re)created.
+//!
+//! The [`Serve`][crate::Serve] transformation installs the wrapper on its own. A
+//! [`BuildServer`][crate::BuildServer] closure that doesn't take the `auth` out of the
+//! configuration gets the configuration refused, instead of serving unauthenticated requests.
+//!
+//! Requests that fail the check are answered with `401 Unauthorized` and the
+//! `WWW-Authenticate` challenge. The passed ones carry the [`Identity`] in their
+//! [extensions][hyper::Request::extensions]. If the credentials can't be loaded, the server
+//! refuses the new configuration and, should the wrapper get a broken one anyway, it denies all
+//! requests rather than letting them through.
+//!
+//! Bcrypt is slow on purpose. The [`Authenticated`] wrapper checks the bcrypt hashes in the
+//! blocking section of the tokio threadpool, so they don't stall the other connections, and
+//! remembers the passwords that passed. The failed checks are limited to a few per second, the
+//! requests over the limit are denied without checking the password.
+//!
+//! Note that the credentials travel in plain text, so this should be used over TLS or on trusted
+//! networks only.
+//!
+//! This needs the `auth` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::auth::{Authenticated, Identity};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [admin]
+//! port = 1234
+//!
+//! [admin.auth]
+//! tokens = ["very-secret"]
+//! # The password is "password", hashed by bcrypt
+//! users = ["admin:$2b$05$J6EOpvdcKTfAY93MB9N5u.gaZIZzkIYBVMcDRrQVFPbm.fR06jTPW"]
+//! exempt = ["127.0.0.0/8", "::1"]
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     admin: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn admin(&self) -> HttpServer {
+//!         self.admin.clone()
+//!     }
+//! }
+//!
+//! fn request(req: Request<Body>) -> Response<Body> {
+//!     let who = req.extensions().get::<Identity>().cloned();
+//!     Response::new(Body::from(format!("Hello {:?}\n", who)))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("admin")
+//!                 .extract_cfg(Config::admin)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     builder.serve(Authenticated::new(cfg.auth(), || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use err_context::prelude::*;
+use futures::{try_ready, Async, Future, Poll};
+use hyper::header::{HeaderMap, HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response, StatusCode};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use spirit::cfg::Cidr;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+use spirit_tokio::net::PeerAddr;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+use crate::access;
+
+fn default_realm() -> String {
+    "restricted".to_owned()
+}
+
+/// Configuration of the authentication.
+///
+/// # Fields
+///
+/// * `realm`: The realm announced to the clients. Defaults to `restricted`.
+/// * `tokens`: Bearer tokens that are let in.
+/// * `tokens-file`: A file with more tokens, one per line.
+/// * `users`: Users and their password hashes, as lines of a `htpasswd` file (`user:hash`). Only
+///   bcrypt and `{SHA}` hashes are supported.
+/// * `users-file`: A `htpasswd` file with more users.
+/// * `exempt`: Network ranges (in the CIDR notation) let in without credentials.
+///
+/// Empty lines and lines starting with `#` are skipped in the files. At least one way in needs to
+/// be configured.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Auth {
+    /// The realm announced to the clients.
+    #[serde(default = "default_realm")]
+    pub realm: String,
+
+    /// Bearer tokens that are let in.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tokens: Vec<Hidden<String>>,
+
+    /// A file with more tokens, one per line.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tokens_file: Option<PathBuf>,
+
+    /// Users with password hashes, in the `user:hash` format.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<Hidden<String>>,
+
+    /// A htpasswd file with more users.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub users_file: Option<PathBuf>,
+
+    /// Network ranges let in without credentials.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exempt: Vec<Cidr>,
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Auth {
+            realm: default_realm(),
+            tokens: Vec::new(),
+            tokens_file: None,
+            users: Vec::new(),
+            users_file: None,
+            exempt: Vec::new(),
+        }
+    }
+}
+
+fn read_lines(file: &Path, what: &str) -> Result<Vec<String>, AnyError> {
+    let content = fs::read_to_string(file)
+        .with_context(|_| format!("Failed to read {} from {}", what, file.display()))?;
+    let lines = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    Ok(lines)
+}
+
+impl Auth {
+    /// Loads the credentials and prepares the [`Guard`] checking them.
+    ///
+    /// This reads the files and validates the content.
+    pub fn guard(&self) -> Result<Guard, AnyError> {
+        let mut tokens = self
+            .tokens
+            .iter()
+            .map(|token| token.0.clone())
+            .collect::<Vec<_>>();
+        if let Some(file) = &self.tokens_file {
+            tokens.extend(read_lines(file, "auth tokens")?);
+        }
+        if tokens.iter().any(String::is_empty) {
+            return Err("Empty auth token".into());
+        }
+
+        let mut lines = self
+            .users
+            .iter()
+            .map(|line| line.0.clone())
+            .collect::<Vec<_>>();
+        if let Some(file) = &self.users_file {
+            lines.extend(read_lines(file, "auth users")?);
+        }
+        let users = lines
+            .iter()
+            .map(|line| parse_user(line))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        if tokens.is_empty() && users.is_empty() && self.exempt.is_empty() {
+            return Err("The auth configuration lets nobody in".into());
+        }
+
+        let challenge = |scheme: &str| {
+            HeaderValue::from_str(&format!("{} realm=\"{}\"", scheme, self.realm))
+                .ok()
+                .filter(|_| !self.realm.contains('"'))
+                .ok_or_else(|| format!("Invalid auth realm {}", self.realm))
+        };
+        let mut challenges = Vec::new();
+        if !users.is_empty() || tokens.is_empty() {
+            challenges.push(challenge("Basic")?);
+        }
+        if !tokens.is_empty() {
+            challenges.push(challenge("Bearer")?);
+        }
+
+        Ok(Guard {
+            inner: Arc::new(GuardInner {
+                challenges,
+                tokens: tokens.into_iter().map(String::into_bytes).collect(),
+                users,
+                exempt: self.exempt.clone(),
+                // The thread RNG is cryptographically secure, seeded from the OS
+                salt: rand::random(),
+                verified: Mutex::new(HashMap::new()),
+                failures: Mutex::new(Failures::default()),
+            }),
+        })
+    }
+}
+
+/// A stored password hash.
+enum PasswordHash {
+    Bcrypt(String),
+    Sha1([u8; 20]),
+}
+
+/// Parses one `user:hash` line of a htpasswd file.
+fn parse_user(line: &str) -> Result<(String, PasswordHash), AnyError> {
+    let mut parts = line.trim().splitn(2, ':');
+    let user = parts.next().unwrap_or_default();
+    let hash = parts.next().unwrap_or_default();
+    if user.is_empty() || hash.is_empty() {
+        // Not including the line, it contains the hash
+        return Err("Auth user not in the user:hash format".into());
+    }
+    let hash = if let Some(digest) = hash.strip_prefix("{SHA}") {
+        let digest = base64::decode(digest)
+            .ok()
+            .filter(|digest| digest.len() == 20)
+            .ok_or_else(|| format!("Broken SHA password hash of auth user {}", user))?;
+        let mut result = [0; 20];
+        result.copy_from_slice(&digest);
+        PasswordHash::Sha1(result)
+    } else if ["$2a$", "$2b$", "$2y$"]
+        .iter()
+        .any(|prefix| hash.starts_with(prefix))
+    {
+        PasswordHash::Bcrypt(hash.to_owned())
+    } else {
+        let msg = format!(
+            "Unsupported password hash of auth user {}, only bcrypt and SHA are supported",
+            user
+        );
+        return Err(msg.into());
+    };
+    Ok((user.to_owned(), hash))
+}
+
+/// Compares two byte strings without leaking the position of the first difference by timing.
+fn constant_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    sha1::Sha1::from(data).digest().bytes()
+}
+
+/// Who got through the [`Guard`].
+///
+/// Inserted into the [extensions][hyper::Request::extensions] of the authenticated requests.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Identity {
+    /// The peer is in one of the exempt network ranges.
+    Exempt,
+
+    /// The request carried one of the bearer tokens.
+    Token,
+
+    /// The request carried the password of this user.
+    User(String),
+}
+
+impl Identity {
+    /// The name of the user, if the request was authenticated by a password.
+    pub fn user(&self) -> Option<&str> {
+        match self {
+            Identity::User(user) => Some(user),
+            _ => None,
+        }
+    }
+}
+
+/// How many failed bcrypt checks are allowed in one [`FAILURES_WINDOW`].
+const MAX_FAILURES: u32 = 10;
+
+const FAILURES_WINDOW: Duration = Duration::from_secs(1);
+
+/// The failed bcrypt checks in the current window.
+#[derive(Default)]
+struct Failures {
+    since: Option<Instant>,
+    count: u32,
+}
+
+impl Failures {
+    fn in_window(&self) -> u32 {
+        match self.since {
+            Some(since) if since.elapsed() < FAILURES_WINDOW => self.count,
+            _ => 0,
+        }
+    }
+
+    fn record(&mut self) {
+        if self.in_window() == 0 {
+            self.since = Some(Instant::now());
+            self.count = 0;
+        }
+        self.count += 1;
+        if self.count == MAX_FAILURES {
+            warn!("Too many failed password checks, denying the unknown passwords for a while");
+        }
+    }
+}
+
+/// The outcome of checking a request without the slow hashing.
+enum Checked {
+    Passed(Identity),
+    /// The password needs to be checked against the bcrypt hash.
+    Bcrypt {
+        user: String,
+        password: String,
+    },
+}
+
+struct GuardInner {
+    challenges: Vec<HeaderValue>,
+    tokens: Vec<Vec<u8>>,
+    users: HashMap<String, PasswordHash>,
+    exempt: Vec<Cidr>,
+    // Remember the (salted) digests of passwords that already passed, so they don't need the
+    // slow bcrypt check on every request.
+    salt: [u8; 16],
+    verified: Mutex<HashMap<String, [u8; 20]>>,
+    failures: Mutex<Failures>,
+}
+
+/// The loaded credentials, checking the requests.
+///
+/// Created by [`Auth::guard`]. Usually used through the [`Authenticated`] wrapper, but can be
+/// used directly too (for example, when only some paths need the protection).
+#[derive(Clone)]
+pub struct Guard {
+    inner: Arc<GuardInner>,
+}
+
+impl Guard {
+    /// A guard letting nobody in.
+    fn deny_all() -> Self {
+        Guard {
+            inner: Arc::new(GuardInner {
+                challenges: Vec::new(),
+                tokens: Vec::new(),
+                users: HashMap::new(),
+                exempt: Vec::new(),
+                salt: [0; 16],
+                verified: Mutex::new(HashMap::new()),
+                failures: Mutex::new(Failures::default()),
+            }),
+        }
+    }
+
+    /// Checks a request by its headers and the address of the peer.
+    ///
+    /// Returns who the request belongs to, or `None` if it shall be denied.
+    ///
+    /// This may check the password against a bcrypt hash, which takes a while. The
+    /// [`Authenticated`] wrapper does that outside of the reactor, a direct caller might want to
+    /// do the same.
+    pub fn check(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<Identity> {
+        match self.pre_check(headers, peer)? {
+            Checked::Passed(identity) => Some(identity),
+            Checked::Bcrypt { user, password } => self.bcrypt(user, &password),
+        }
+    }
+
+    /// Checks everything that's fast, leaving the bcrypt hashes for later.
+    ///
+    /// Returns `None` if the request shall be denied.
+    fn pre_check(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<Checked> {
+        if let Some(ip) = peer {
+            if self.inner.exempt.iter().any(|net| net.contains(ip)) {
+                return Some(Checked::Passed(Identity::Exempt));
+            }
+        }
+        let value = headers.get(AUTHORIZATION)?.to_str().ok()?;
+        let mut parts = value.trim().splitn(2, ' ');
+        let scheme = parts.next()?;
+        let param = parts.next()?.trim();
+        if scheme.eq_ignore_ascii_case("Bearer") {
+            // Go through all of them, not to tell which one was close by timing
+            let found = self.inner.tokens.iter().fold(false, |found, token| {
+                found | constant_eq(token, param.as_bytes())
+            });
+            if found {
+                Some(Checked::Passed(Identity::Token))
+            } else {
+                None
+            }
+        } else if scheme.eq_ignore_ascii_case("Basic") {
+            let decoded = String::from_utf8(base64::decode(param).ok()?).ok()?;
+            let mut parts = decoded.splitn(2, ':');
+            let user = parts.next()?;
+            let password = parts.next()?;
+            self.pre_verify(user, password)
+        } else {
+            None
+        }
+    }
+
+    fn pre_verify(&self, user: &str, password: &str) -> Option<Checked> {
+        let inner = &self.inner;
+        let passed = || Some(Checked::Passed(Identity::User(user.to_owned())));
+        match inner.users.get(user)? {
+            PasswordHash::Sha1(digest) => {
+                if constant_eq(&sha1(password.as_bytes()), digest) {
+                    passed()
+                } else {
+                    None
+                }
+            }
+            PasswordHash::Bcrypt(_) => {
+                let digest = self.salted(password);
+                let verified = inner
+                    .verified
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                if let Some(known) = verified.get(user) {
+                    if constant_eq(known, &digest) {
+                        return passed();
+                    }
+                }
+                drop(verified);
+                let failures = inner
+                    .failures
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .in_window();
+                if failures >= MAX_FAILURES {
+                    debug!("Not checking password of {}, too many failures", user);
+                    return None;
+                }
+                Some(Checked::Bcrypt {
+                    user: user.to_owned(),
+                    password: password.to_owned(),
+                })
+            }
+        }
+    }
+
+    fn salted(&self, password: &str) -> [u8; 20] {
+        let mut salted = self.inner.salt.to_vec();
+        salted.extend_from_slice(password.as_bytes());
+        sha1(&salted)
+    }
+
+    /// The slow part of the check, running bcrypt.
+    fn bcrypt(&self, user: String, password: &str) -> Option<Identity> {
+        let inner = &self.inner;
+        let hash = match inner.users.get(&user) {
+            Some(PasswordHash::Bcrypt(hash)) => hash,
+            _ => unreachable!("Bcrypt check of user without bcrypt hash"),
+        };
+        match bcrypt::verify(password, hash) {
+            Ok(true) => {
+                inner
+                    .verified
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(user.clone(), self.salted(password));
+                Some(Identity::User(user))
+            }
+            Ok(false) => {
+                inner
+                    .failures
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .record();
+                None
+            }
+            Err(e) => {
+                debug!("Failed to check password of {}: {}", user, e);
+                None
+            }
+        }
+    }
+
+    /// The response denying a request.
+    fn challenge<B: Default>(&self) -> Response<B> {
+        let mut response = Response::new(B::default());
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        for challenge in &self.inner.challenges {
+            response
+                .headers_mut()
+                .append(WWW_AUTHENTICATE, challenge.clone());
+        }
+        response
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] that lets in only authenticated requests.
+///
+/// See the [module documentation][crate::auth].
+///
+/// The connections need to provide the address of the peer (by the [`PeerAddr`] trait) for the
+/// exempt network ranges. The body of the responses needs to be [`Default`] to create the denying
+/// ones.
+pub struct Authenticated<MS> {
+    guard: Option<Guard>,
+    inner: MS,
+}
+
+impl<MS> Authenticated<MS> {
+    /// Wraps the [`MakeService`].
+    ///
+    /// If the configuration is `None`, all requests are let through. If the credentials can't be
+    /// loaded, all requests are denied.
+    pub fn new(cfg: Option<&Auth>, inner: MS) -> Self {
+        let guard = cfg.map(|cfg| {
+            cfg.guard().unwrap_or_else(|e| {
+                spirit::log_error!(multi Error, "Denying all requests, broken auth" => e);
+                Guard::deny_all()
+            })
+        });
+        Authenticated { guard, inner }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Authenticated<MS>
+where
+    Ctx: PeerAddr,
+    MS: MakeService<&'a Ctx>,
+    MS::ResBody: Default,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = AuthService<MS::Service>;
+    type Future = AuthMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        AuthMake {
+            guard: self.guard.clone(),
+            peer: ctx.remote_addr().map(|addr| addr.ip()),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`AuthService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct AuthMake<F> {
+    guard: Option<Guard>,
+    peer: Option<IpAddr>,
+    inner: F,
+}
+
+impl<F: Future> Future for AuthMake<F> {
+    type Item = AuthService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(AuthService {
+            guard: self.guard.take(),
+            peer: self.peer,
+            inner: Arc::new(Mutex::new(inner)),
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that checks the requests.
+///
+/// The user should not need to interact with this directly.
+pub struct AuthService<S> {
+    guard: Option<Guard>,
+    peer: Option<IpAddr>,
+    // Shared with the responses waiting for a bcrypt check, they call it once it passes
+    inner: Arc<Mutex<S>>,
+}
+
+fn allow<S: Service>(
+    service: &Mutex<S>,
+    mut req: Request<S::ReqBody>,
+    identity: Option<Identity>,
+) -> AuthResponse<S> {
+    if let Some(identity) = identity {
+        if let Some(user) = identity.user() {
+            access::record(&req, |fields| fields.user = Some(user.to_owned()));
+        }
+        req.extensions_mut().insert(identity);
+    }
+    let response = service
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .call(req);
+    AuthResponse(State::Allowed(response))
+}
+
+fn deny<S: Service>(
+    guard: &Guard,
+    req: &Request<S::ReqBody>,
+    peer: Option<IpAddr>,
+) -> AuthResponse<S>
+where
+    S::ResBody: Default,
+{
+    debug!(
+        "Denying unauthenticated request {} {} from {:?}",
+        req.method(),
+        req.uri(),
+        peer
+    );
+    AuthResponse(State::Denied(Some(guard.challenge())))
+}
+
+impl<S> Service for AuthService<S>
+where
+    S: Service,
+    S::ResBody: Default,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = AuthResponse<S>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .poll_ready()
+    }
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        let guard = match &self.guard {
+            Some(guard) => guard,
+            None => return allow(&self.inner, req, None),
+        };
+        match guard.pre_check(req.headers(), self.peer) {
+            Some(Checked::Passed(identity)) => allow(&self.inner, req, Some(identity)),
+            Some(Checked::Bcrypt { user, password }) => AuthResponse(State::Hashing {
+                guard: guard.clone(),
+                peer: self.peer,
+                user: Some(user),
+                password,
+                req: Some(req),
+                service: Arc::clone(&self.inner),
+            }),
+            None => deny(guard, &req, self.peer),
+        }
+    }
+}
+
+enum State<S: Service> {
+    Allowed(S::Future),
+    Denied(Option<Response<S::ResBody>>),
+    Hashing {
+        guard: Guard,
+        peer: Option<IpAddr>,
+        user: Option<String>,
+        password: String,
+        req: Option<Request<S::ReqBody>>,
+        service: Arc<Mutex<S>>,
+    },
+}
+
+/// A plumbing future of the response of an [`AuthService`].
+///
+/// The user should not need to interact with this directly.
+pub struct AuthResponse<S: Service>(State<S>);
+
+impl<S> Future for AuthResponse<S>
+where
+    S: Service,
+    S::ResBody: Default,
+{
+    type Item = Response<S::ResBody>;
+    type Error = S::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            let next = match &mut self.0 {
+                State::Allowed(inner) => return inner.poll(),
+                State::Denied(response) => {
+                    let response = response
+                        .take()
+                        .expect("Auth response polled after completion");
+                    return Ok(Async::Ready(response));
+                }
+                State::Hashing {
+                    guard,
+                    peer,
+                    user,
+                    password,
+                    req,
+                    service,
+                } => {
+                    let mut check = || {
+                        let user = user.take().expect("Auth response polled after completion");
+                        guard.bcrypt(user, password)
+                    };
+                    let identity = match tokio_threadpool::blocking(&mut check) {
+                        Ok(Async::Ready(identity)) => identity,
+                        Ok(Async::NotReady) => return Ok(Async::NotReady),
+                        // Not running on the threadpool, there's nowhere else to do it
+                        Err(_) => check(),
+                    };
+                    let req = req.take().expect("Auth response polled after completion");
+                    match identity {
+                        Some(identity) => allow(service, req, Some(identity)),
+                        None => deny(guard, &req, *peer),
+                    }
+                }
+            };
+            *self = next;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both have the password "password"
+    const BCRYPT: &str = "user:$2b$05$PnTVvFNC4fAWdzaYyihgFeps6Cv6riuDymFpKp6oqRg.a5Viyw6sq";
+    const SHA: &str = "other:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=";
+
+    fn headers(auth: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_str(auth).unwrap());
+        headers
+    }
+
+    fn basic(user: &str, password: &str) -> HeaderMap {
+        headers(&format!(
+            "Basic {}",
+            base64::encode(&format!("{}:{}", user, password))
+        ))
+    }
+
+    #[test]
+    fn users() {
+        assert!(parse_user(BCRYPT).is_ok());
+        assert!(parse_user(SHA).is_ok());
+        assert!(parse_user("user:$apr1$xxxxxxxx$yyyyyyyyyyyyyyyyyyyyyy").is_err());
+        assert!(parse_user("user:{SHA}short").is_err());
+        assert!(parse_user("no-hash").is_err());
+        assert!(parse_user(":{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=").is_err());
+    }
+
+    #[test]
+    fn check() {
+        let auth = Auth {
+            tokens: vec![Hidden("secret-token".to_owned())],
+            users: vec![Hidden(BCRYPT.to_owned()), Hidden(SHA.to_owned())],
+            exempt: vec!["127.0.0.0/8".parse().unwrap()],
+            ..Auth::default()
+        };
+        let guard = auth.guard().unwrap();
+        let local = Some("127.0.0.1".parse().unwrap());
+        let remote = Some("192.0.2.1".parse().unwrap());
+
+        assert_eq!(
+            Some(Identity::Exempt),
+            guard.check(&HeaderMap::new(), local)
+        );
+        assert_eq!(None, guard.check(&HeaderMap::new(), remote));
+        assert_eq!(None, guard.check(&HeaderMap::new(), None));
+
+        let token = headers("Bearer secret-token");
+        assert_eq!(Some(Identity::Token), guard.check(&token, remote));
+        assert_eq!(None, guard.check(&headers("Bearer secret"), remote));
+        assert_eq!(None, guard.check(&headers("Basic secret-token"), remote));
+
+        let user = Some(Identity::User("user".to_owned()));
+        assert_eq!(user, guard.check(&basic("user", "password"), remote));
+        // From the cache this time
+        assert_eq!(user, guard.check(&basic("user", "password"), remote));
+        assert_eq!(None, guard.check(&basic("user", "wrong"), remote));
+        let other = Some(Identity::User("other".to_owned()));
+        assert_eq!(other, guard.check(&basic("other", "password"), remote));
+        assert_eq!(None, guard.check(&basic("nobody", "password"), remote));
+
+        let response = guard.challenge::<hyper::Body>();
+        assert_eq!(StatusCode::UNAUTHORIZED, response.status());
+        let challenges = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["Basic realm=\"restricted\"", "Bearer realm=\"restricted\""],
+            challenges
+        );
+    }
+
+    #[test]
+    fn failures_limited() {
+        let auth = Auth {
+            users: vec![Hidden(BCRYPT.to_owned())],
+            ..Auth::default()
+        };
+        let guard = auth.guard().unwrap();
+        let user = Some(Identity::User("user".to_owned()));
+        assert_eq!(user, guard.check(&basic("user", "password"), None));
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(None, guard.check(&basic("user", "wrong"), None));
+        }
+        assert!(guard.pre_check(&basic("user", "wrong"), None).is_none());
+        // The known password still passes, from the cache
+        assert_eq!(user, guard.check(&basic("user", "password"), None));
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Auth::default().guard().is_err());
+        let auth = Auth {
+            users_file: Some(PathBuf::from("/does/not/exist")),
+            exempt: vec!["::1".parse().unwrap()],
+            ..Auth::default()
+        };
+        assert!(auth.guard().is_err());
+        let auth = Auth {
+            realm: "bad\"realm".to_owned(),
+            tokens: vec![Hidden("token".to_owned())],
+            ..Auth::default()
+        };
+        assert!(auth.guard().is_err());
+        let denied = Guard::deny_all();
+        assert_eq!(None, denied.check(&headers("Bearer token"), None));
+    }
+}
@@ -0,0 +1,414 @@
+//! A configurable hyper HTTP client.
+//!
+//! This is the client-side counterpart of the servers in this crate. The [`HyperClient`]
+//! configuration fragment describes how to build a hyper [`Client`] (the connection pool, the
+//! connector and its TLS settings) and the [`AtomicHyperClient`] holds the currently configured
+//! one. Pairing them in a [`Pipeline`] makes sure the application always uses a client
+//! corresponding to the current configuration.
+//!
+//! This is similar to what the [`spirit-reqwest`] crate provides for reqwest and is available
+//! under the `client` feature flag.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::rt::{Future, Stream};
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::client::{AtomicHyperClient, HyperClient};
+//!
+//! #[derive(Debug, Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     client: HyperClient,
+//! }
+//!
+//! impl Cfg {
+//!     fn client(&self) -> HyperClient {
+//!         self.client.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let client = AtomicHyperClient::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(
+//!             Pipeline::new("http client")
+//!                 .extract_cfg(Cfg::client)
+//!                 .install(client.clone())
+//!         )
+//!         .run(move |_| {
+//!             let uri = "http://www.rust-lang.org".parse()?;
+//!             // Not actually running it in the example
+//!             let _request = client
+//!                 .get(uri)
+//!                 .and_then(|response| response.into_body().concat2());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`Pipeline`]: spirit::Pipeline
+//! [`spirit-reqwest`]: https://crates.io/crates/spirit-reqwest
+
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use hyper::client::connect::HttpConnector;
+use hyper::client::{Client, ResponseFuture};
+use hyper::{Body, Request, Uri};
+use hyper_tls::HttpsConnector;
+use log::{debug, trace};
+use native_tls::{Certificate, TlsConnector};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+/// The type of the client built from [`HyperClient`].
+///
+/// It can speak both plain HTTP and HTTPS (unless configured to be HTTPS only).
+pub type HttpsClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+fn default_dns_threads() -> usize {
+    4
+}
+
+fn default_pool_idle_timeout() -> Option<Duration> {
+    Some(Duration::from_secs(90))
+}
+
+fn default_on() -> bool {
+    true
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+fn load_cert(path: &Path) -> Result<Certificate, AnyError> {
+    let mut input = File::open(path)?;
+    let mut cert = Vec::new();
+    input.read_to_end(&mut cert)?;
+    const BEGIN_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----";
+    let contains_begin_cert = cert.windows(BEGIN_CERT.len()).any(|w| w == BEGIN_CERT);
+    let result = if contains_begin_cert {
+        trace!("Loading as PEM");
+        Certificate::from_pem(&cert)?
+    } else {
+        trace!("Loading as DER");
+        Certificate::from_der(&cert)?
+    };
+    Ok(result)
+}
+
+/// A configuration fragment for the hyper [`Client`].
+///
+/// An empty configuration corresponds more or less to the defaults of hyper, but with support for
+/// HTTPS.
+///
+/// # Fields
+///
+/// * `dns-threads`: Number of threads used for resolving host names. Defaults to 4.
+/// * `pool-keepalive`: Keep idle connections in the pool for reuse. Defaults to `true`.
+/// * `pool-idle-timeout`: How long an idle connection is kept in the pool. Defaults to `90s`,
+///   `nil` means forever.
+/// * `max-idle-per-host`: Maximum number of idle connections kept per one host. Defaults to `nil`
+///   (no limit).
+/// * `http2-only`: Speak only HTTP/2. Defaults to `false`.
+/// * `connect-timeout`: Timeout for establishing a TCP connection. Defaults to `nil` (no timeout).
+/// * `tcp-keepalive`: The TCP keepalive interval for the connections. Defaults to `nil`.
+/// * `tcp-nodelay`: Set the `SO_NODELAY` on the connections. Defaults to `false`.
+/// * `local-address`: Make the connections from this local address.
+/// * `https-only`: Refuse to make plain-text HTTP requests. Defaults to `false`.
+/// * `tls-extra-root-certs`: Paths to additional certificates to trust (PEM or DER).
+/// * `tls-accept-invalid-hostnames`: Accept certificates for other hosts. **Dangerous**.
+/// * `tls-accept-invalid-certs`: Accept any certificates at all. **Dangerous**.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct HyperClient {
+    /// Number of threads used for resolving host names.
+    #[serde(default = "default_dns_threads")]
+    pub dns_threads: usize,
+
+    /// Keep idle connections in a pool for reuse.
+    #[serde(default = "default_on")]
+    pub pool_keepalive: bool,
+
+    /// How long an idle connection may stay in the pool.
+    ///
+    /// If set to `nil`, idle connections are kept forever.
+    #[serde(
+        default = "default_pool_idle_timeout",
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration"
+    )]
+    pub pool_idle_timeout: Option<Duration>,
+
+    /// Maximum number of idle connections per one host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_idle_per_host: Option<usize>,
+
+    /// Use only HTTP/2.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub http2_only: bool,
+
+    /// Timeout for establishing the TCP connection.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub connect_timeout: Option<Duration>,
+
+    /// The TCP keepalive interval of the connections.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Set the `SO_NODELAY` on the connections.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tcp_nodelay: bool,
+
+    /// Connect from this local address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub local_address: Option<IpAddr>,
+
+    /// Refuse plain-text HTTP and use only HTTPS.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub https_only: bool,
+
+    /// Additional certificates to add into the TLS trust store.
+    ///
+    /// Accepts PEM and DER formats (autodetected).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tls_extra_root_certs: Vec<PathBuf>,
+
+    /// Accept certificates not matching the host name.
+    ///
+    /// **Dangerous**, avoid if possible.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls_accept_invalid_hostnames: bool,
+
+    /// Accept any server certificates, including invalid and expired ones.
+    ///
+    /// **Dangerous**, avoid if possible.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls_accept_invalid_certs: bool,
+}
+
+impl Default for HyperClient {
+    fn default() -> Self {
+        HyperClient {
+            dns_threads: default_dns_threads(),
+            pool_keepalive: true,
+            pool_idle_timeout: default_pool_idle_timeout(),
+            max_idle_per_host: None,
+            http2_only: false,
+            connect_timeout: None,
+            tcp_keepalive: None,
+            tcp_nodelay: false,
+            local_address: None,
+            https_only: false,
+            tls_extra_root_certs: Vec::new(),
+            tls_accept_invalid_hostnames: false,
+            tls_accept_invalid_certs: false,
+        }
+    }
+}
+
+impl HyperClient {
+    /// Creates the TLS connector according to the configuration.
+    pub fn tls_connector(&self) -> Result<TlsConnector, AnyError> {
+        let mut builder = TlsConnector::builder();
+        for path in &self.tls_extra_root_certs {
+            trace!("Adding root certificate {:?}", path);
+            let cert = load_cert(path)
+                .with_context(|_| format!("Failed to load certificate {:?}", path))?;
+            builder.add_root_certificate(cert);
+        }
+        builder
+            .danger_accept_invalid_hostnames(self.tls_accept_invalid_hostnames)
+            .danger_accept_invalid_certs(self.tls_accept_invalid_certs);
+        Ok(builder.build()?)
+    }
+
+    /// Creates the connector (both the TCP and the TLS layer) according to the configuration.
+    pub fn connector(&self) -> Result<HttpsConnector<HttpConnector>, AnyError> {
+        let mut http = HttpConnector::new(self.dns_threads);
+        http.enforce_http(false);
+        http.set_connect_timeout(self.connect_timeout);
+        http.set_keepalive(self.tcp_keepalive);
+        http.set_nodelay(self.tcp_nodelay);
+        http.set_local_address(self.local_address);
+        let tls = self
+            .tls_connector()
+            .context("Failed to create TLS connector")?;
+        let mut https = HttpsConnector::from((http, tls));
+        https.https_only(self.https_only);
+        Ok(https)
+    }
+
+    /// Creates a [`Client`] according to the configuration.
+    ///
+    /// Note that this is done by the [`Fragment`][spirit::Fragment] implementation and it is
+    /// usually more convenient to pair this with [`AtomicHyperClient`].
+    pub fn create_client(&self) -> Result<HttpsClient, AnyError> {
+        debug!("Creating hyper client");
+        let connector = self.connector()?;
+        let mut builder = Client::builder();
+        builder
+            .keep_alive(self.pool_keepalive)
+            .keep_alive_timeout(self.pool_idle_timeout)
+            .http2_only(self.http2_only);
+        if let Some(max_idle) = self.max_idle_per_host {
+            builder.max_idle_per_host(max_idle);
+        }
+        Ok(builder.build(connector))
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for HyperClient {
+        type Driver = CacheEq<HyperClient>;
+        type Resource = HttpsClient;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<HttpsClient, AnyError> {
+            self.create_client()
+        }
+    }
+}
+
+/// A storage for one [`Client`] that can be atomically exchanged under the hood.
+///
+/// This acts as a proxy for a [`Client`]. This is cheap to clone all cloned handles refer to the
+/// same client. It has most of the [`Client`]'s methods directly on itself, the others can be
+/// accessed through the [`client`] method.
+///
+/// It also supports the [`Installer`] trait, therefore it is possible to use it to store the
+/// client configured from a [`HyperClient`] fragment inside [`Spirit`].
+///
+/// # Warning
+///
+/// The client may be replaced at any time by another thread, therefore successive calls to eg.
+/// [`get`] may happen on different clients. If this is a problem, get a specific client by the
+/// [`client`] method and keep it around for as long as needed.
+///
+/// # Panics
+///
+/// Trying to access the client if the [`AtomicHyperClient`] was created with [`empty`] and
+/// wasn't set yet (either by [`Spirit`] or by explicit [`replace`]) results in panic.
+///
+/// [`Spirit`]: spirit::Spirit
+/// [`replace`]: AtomicHyperClient::replace
+/// [`empty`]: AtomicHyperClient::empty
+/// [`client`]: AtomicHyperClient::client
+/// [`get`]: AtomicHyperClient::get
+#[derive(Clone, Debug)]
+pub struct AtomicHyperClient(Arc<ArcSwapOption<HttpsClient>>);
+
+impl Default for AtomicHyperClient {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<C: Into<Arc<HttpsClient>>> From<C> for AtomicHyperClient {
+    fn from(c: C) -> Self {
+        AtomicHyperClient(Arc::new(ArcSwapOption::from(Some(c.into()))))
+    }
+}
+
+impl AtomicHyperClient {
+    /// Creates an empty [`AtomicHyperClient`].
+    ///
+    /// This is effectively a `NULL`. It'll panic until a value is set, either by [`replace`]
+    /// or by [`Spirit`] behind the scenes.
+    ///
+    /// [`replace`]: AtomicHyperClient::replace
+    /// [`Spirit`]: spirit::Spirit
+    pub fn empty() -> Self {
+        AtomicHyperClient(Arc::new(ArcSwapOption::empty()))
+    }
+
+    /// Creates an [`AtomicHyperClient`] with a client built from the default configuration.
+    pub fn unconfigured() -> Result<Self, AnyError> {
+        Ok(HyperClient::default().create_client()?.into())
+    }
+
+    /// Replaces the content of this [`AtomicHyperClient`] with a new [`Client`].
+    ///
+    /// This replaces it for *all* connected handles (eg. created by cloning from the same
+    /// original [`AtomicHyperClient`]).
+    pub fn replace<C: Into<Arc<HttpsClient>>>(&self, by: C) {
+        self.0.store(Some(by.into()));
+    }
+
+    /// Returns a handle to the [`Client`] currently held inside.
+    ///
+    /// The returned client doesn't change even if the one inside the [`AtomicHyperClient`] is
+    /// replaced.
+    pub fn client(&self) -> Arc<HttpsClient> {
+        self.0
+            .load_full()
+            .expect("Accessing hyper HTTP client before setting it up")
+    }
+
+    /// Sends a request using the current client.
+    ///
+    /// This is forwarded to [`Client::request`].
+    pub fn request(&self, req: Request<Body>) -> ResponseFuture {
+        self.client().request(req)
+    }
+
+    /// Sends a GET request using the current client.
+    ///
+    /// This is forwarded to [`Client::get`].
+    pub fn get(&self, uri: Uri) -> ResponseFuture {
+        self.client().get(uri)
+    }
+}
+
+impl<O, C> Installer<HttpsClient, O, C> for AtomicHyperClient {
+    type UninstallHandle = ();
+    fn install(&mut self, client: HttpsClient, name: &'static str) {
+        debug!("Installing hyper http client '{}'", name);
+        self.replace(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_config() {
+        let cfg: HyperClient = serde_json::from_str("{}").unwrap();
+        assert_eq!(HyperClient::default(), cfg);
+    }
+
+    #[test]
+    fn durations() {
+        let cfg: HyperClient =
+            serde_json::from_str(r#"{"pool-idle-timeout": null, "connect-timeout": "5s"}"#)
+                .unwrap();
+        assert_eq!(None, cfg.pool_idle_timeout);
+        assert_eq!(Some(Duration::from_secs(5)), cfg.connect_timeout);
+    }
+}
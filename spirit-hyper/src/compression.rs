@@ -0,0 +1,513 @@
+//! Compression of responses.
+//!
+//! A [`HyperServer`] can be configured to compress the responses it sends, if the client asks for
+//! it by the `Accept-Encoding` header. This is meant for simple APIs that don't want to pull in a
+//! whole framework just for this.
+//!
+//! The [`Serve`][crate::Serve] transformation compresses the responses as configured. With
+//! [`BuildServer`][crate::BuildServer], the service needs to be wrapped in the [`Compressed`]
+//! wrapper, as in the example below.
+//!
+//! # Configuration options
+//!
+//! These are added to the [`HyperServer`]:
+//!
+//! * `compression`: List of enabled encodings, in the order of preference. Each one is either
+//!   `"gzip"` or `"br"`. Defaults to empty list (no compression).
+//! * `compression-min-size`: Responses with known length shorter than this are sent as they are.
+//!   Either a number of bytes or a size with units (eg. `4KiB`). Defaults to 1024 bytes.
+//! * `compression-content-types`: Content types that are compressed. An item can end with `/*`
+//!   to match a whole class of types (eg. `text/*`). Defaults to common textual types.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::compression::Compressed;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! compression = ["br", "gzip"]
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     let compression = cfg.compression().clone();
+//!                     builder.serve(Compressed::new(compression, || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::io::{Error as IoError, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use flate2::write::GzEncoder;
+use futures::{try_ready, Async, Future, Poll, Stream};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY,
+};
+use hyper::service::{MakeService, Service};
+use hyper::{Body, Chunk, Request, Response};
+use serde::{Deserialize, Serialize};
+use spirit::cfg::ByteSize;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+/// One of the supported compression encodings.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Encoding {
+    /// The gzip encoding.
+    Gzip,
+
+    /// The brotli encoding.
+    #[serde(rename = "br")]
+    Brotli,
+}
+
+impl Encoding {
+    fn token(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+}
+
+fn default_min_size() -> ByteSize {
+    ByteSize(1024)
+}
+
+fn default_content_types() -> Vec<String> {
+    [
+        "text/*",
+        "application/json",
+        "application/javascript",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .iter()
+    .map(|t| (*t).to_owned())
+    .collect()
+}
+
+/// The compression configuration of a [`HyperServer`][crate::HyperServer].
+///
+/// See the [module documentation](index.html) for the options.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Compression {
+    /// The enabled encodings, in the order of preference.
+    ///
+    /// Available are `gzip` and `br`. By default, no compression is done.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    compression: Vec<Encoding>,
+
+    /// Minimal size of a response to get compressed (in bytes or with units, like `4KiB`).
+    ///
+    /// Only responses with known length can be left out, streamed responses are always
+    /// compressed. Defaults to 1024 bytes.
+    #[serde(default = "default_min_size")]
+    compression_min_size: ByteSize,
+
+    /// Content types that get compressed.
+    ///
+    /// An item ending with `/*` matches whole class of types (eg. `text/*`). Defaults to common
+    /// textual types.
+    #[serde(default = "default_content_types")]
+    compression_content_types: Vec<String>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            compression: Vec::new(),
+            compression_min_size: default_min_size(),
+            compression_content_types: default_content_types(),
+        }
+    }
+}
+
+impl Compression {
+    /// Is any compression turned on?
+    pub fn enabled(&self) -> bool {
+        !self.compression.is_empty()
+    }
+
+    /// Picks the encoding to use for a request with these headers.
+    ///
+    /// This is the first configured encoding the client accepts. The `*` wildcard stands only for
+    /// the encodings not named in the header, so an explicitly refused one stays refused.
+    fn negotiate(&self, headers: &HeaderMap) -> Option<Encoding> {
+        // The tokens with the information if they are refused (`q=0`)
+        let listed = headers
+            .get_all(ACCEPT_ENCODING)
+            .iter()
+            .filter_map(|h| h.to_str().ok())
+            .flat_map(|h| h.split(','))
+            .filter_map(|item| {
+                let mut parts = item.split(';').map(str::trim);
+                let token = parts.next()?;
+                let refused = parts.any(|p| {
+                    p.starts_with("q=") && p[2..].parse::<f32>().map(|q| q <= 0.0) == Ok(true)
+                });
+                if token.is_empty() {
+                    None
+                } else {
+                    Some((token.to_ascii_lowercase(), refused))
+                }
+            })
+            .collect::<Vec<_>>();
+        let accepts = |name: &str| {
+            listed
+                .iter()
+                .find(|(token, _)| token == name)
+                .map(|(_, refused)| !refused)
+        };
+        self.compression.iter().cloned().find(|enc| {
+            accepts(enc.token())
+                .or_else(|| accepts("*"))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Decides if the response should be compressed.
+    fn applies(&self, headers: &HeaderMap) -> bool {
+        if headers.contains_key(CONTENT_ENCODING) {
+            return false;
+        }
+        let len = headers
+            .get(CONTENT_LENGTH)
+            .and_then(|l| l.to_str().ok())
+            .and_then(|l| l.parse::<u64>().ok());
+        if len.map(|l| l < self.compression_min_size.bytes()) == Some(true) {
+            return false;
+        }
+        let ctype = headers
+            .get(CONTENT_TYPE)
+            .and_then(|t| t.to_str().ok())
+            .and_then(|t| t.split(';').next())
+            .map(|t| t.trim().to_ascii_lowercase());
+        match ctype {
+            Some(ctype) => self.compression_content_types.iter().any(|allowed| {
+                if allowed.ends_with("/*") {
+                    ctype.starts_with(&allowed[..allowed.len() - 1])
+                } else {
+                    *allowed == ctype
+                }
+            }),
+            None => false,
+        }
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] that compresses the responses.
+///
+/// It compresses according to the passed [`Compression`] configuration. If it is turned off,
+/// everything is passed through as it is.
+pub struct Compressed<MS> {
+    cfg: Arc<Compression>,
+    inner: MS,
+}
+
+impl<MS> Compressed<MS> {
+    /// Wraps the given [`MakeService`].
+    pub fn new(cfg: Compression, inner: MS) -> Self {
+        Compressed {
+            cfg: Arc::new(cfg),
+            inner,
+        }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Compressed<MS>
+where
+    MS: MakeService<&'a Ctx, ResBody = Body>,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = Body;
+    type Error = MS::Error;
+    type Service = CompressedService<MS::Service>;
+    type Future = CompressedMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        CompressedMake {
+            cfg: Arc::clone(&self.cfg),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`CompressedService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct CompressedMake<F> {
+    cfg: Arc<Compression>,
+    inner: F,
+}
+
+impl<F: Future> Future for CompressedMake<F> {
+    type Item = CompressedService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(CompressedService {
+            cfg: Arc::clone(&self.cfg),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper around a per-connection [`Service`] compressing its responses.
+///
+/// The user should not need to interact with this directly.
+pub struct CompressedService<S> {
+    cfg: Arc<Compression>,
+    inner: S,
+}
+
+impl<S> Service for CompressedService<S>
+where
+    S: Service<ResBody = Body>,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = Body;
+    type Error = S::Error;
+    type Future = CompressedResponse<S::Future>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        let encoding = if self.cfg.enabled() {
+            self.cfg.negotiate(req.headers())
+        } else {
+            None
+        };
+        CompressedResponse {
+            cfg: Arc::clone(&self.cfg),
+            encoding,
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+/// A plumbing future that compresses one response, if appropriate.
+///
+/// The user should not need to interact with this directly.
+pub struct CompressedResponse<F> {
+    cfg: Arc<Compression>,
+    encoding: Option<Encoding>,
+    inner: F,
+}
+
+impl<F> Future for CompressedResponse<F>
+where
+    F: Future<Item = Response<Body>>,
+{
+    type Item = Response<Body>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Response<Body>, F::Error> {
+        let response = try_ready!(self.inner.poll());
+        let encoding = match self.encoding {
+            Some(encoding) if self.cfg.applies(response.headers()) => encoding,
+            _ => return Ok(Async::Ready(response)),
+        };
+        let (mut parts, body) = response.into_parts();
+        parts.headers.remove(CONTENT_LENGTH);
+        parts
+            .headers
+            .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.token()));
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("Accept-Encoding"));
+        let body = Body::wrap_stream(CompressStream::new(body, encoding));
+        Ok(Async::Ready(Response::from_parts(parts, body)))
+    }
+}
+
+/// A writer that collects the compressed output so it can be sent out in chunks.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<u8>>>);
+
+impl Collector {
+    fn take(&self) -> Vec<u8> {
+        let mut buf = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        mem::replace(&mut *buf, Vec::new())
+    }
+}
+
+impl Write for Collector {
+    fn write(&mut self, data: &[u8]) -> Result<usize, IoError> {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .extend_from_slice(data);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        Ok(())
+    }
+}
+
+enum Encoder {
+    Gzip(GzEncoder<Collector>),
+    Brotli(Box<brotli::CompressorWriter<Collector>>),
+}
+
+impl Encoder {
+    fn write(&mut self, data: &[u8]) -> Result<(), IoError> {
+        match self {
+            Encoder::Gzip(enc) => enc.write_all(data),
+            Encoder::Brotli(enc) => enc.write_all(data),
+        }
+    }
+    fn finish(self) -> Result<(), IoError> {
+        match self {
+            Encoder::Gzip(enc) => enc.finish().map(|_| ()),
+            Encoder::Brotli(mut enc) => enc.flush().map(|_| drop(enc)),
+        }
+    }
+}
+
+struct CompressStream {
+    body: Body,
+    output: Collector,
+    encoder: Option<Encoder>,
+}
+
+impl CompressStream {
+    fn new(body: Body, encoding: Encoding) -> Self {
+        let output = Collector::default();
+        let encoder = match encoding {
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(
+                output.clone(),
+                flate2::Compression::default(),
+            )),
+            Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                output.clone(),
+                4096,
+                5,
+                22,
+            ))),
+        };
+        CompressStream {
+            body,
+            output,
+            encoder: Some(encoder),
+        }
+    }
+}
+
+impl Stream for CompressStream {
+    type Item = Chunk;
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+    fn poll(&mut self) -> Poll<Option<Chunk>, Self::Error> {
+        loop {
+            let encoder = match self.encoder.as_mut() {
+                Some(encoder) => encoder,
+                None => return Ok(Async::Ready(None)),
+            };
+            match try_ready!(self.body.poll()) {
+                Some(chunk) => encoder.write(&chunk)?,
+                None => self.encoder.take().unwrap().finish()?,
+            }
+            let out = self.output.take();
+            if !out.is_empty() {
+                return Ok(Async::Ready(Some(Chunk::from(out))));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(accept: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_str(accept).unwrap());
+        headers
+    }
+
+    fn cfg() -> Compression {
+        Compression {
+            compression: vec![Encoding::Brotli, Encoding::Gzip],
+            ..Compression::default()
+        }
+    }
+
+    #[test]
+    fn negotiate_preference() {
+        let cfg = cfg();
+        assert_eq!(Some(Encoding::Brotli), cfg.negotiate(&headers("gzip, br")));
+        assert_eq!(Some(Encoding::Gzip), cfg.negotiate(&headers("gzip;q=0.5")));
+        assert_eq!(
+            Some(Encoding::Gzip),
+            cfg.negotiate(&headers("br;q=0, gzip"))
+        );
+        assert_eq!(None, cfg.negotiate(&headers("identity")));
+        assert_eq!(Some(Encoding::Brotli), cfg.negotiate(&headers("*")));
+        assert_eq!(Some(Encoding::Gzip), cfg.negotiate(&headers("br;q=0, *")));
+        assert_eq!(None, cfg.negotiate(&headers("gzip;q=0, br;q=0, *")));
+        assert_eq!(None, cfg.negotiate(&headers("*;q=0")));
+        assert_eq!(None, Compression::default().negotiate(&headers("gzip")));
+    }
+
+    #[test]
+    fn applies_content_type() {
+        let cfg = cfg();
+        let mut headers = HeaderMap::new();
+        assert!(!cfg.applies(&headers));
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("text/html; charset=utf-8"),
+        );
+        assert!(cfg.applies(&headers));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("10"));
+        assert!(!cfg.applies(&headers));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        headers.remove(CONTENT_LENGTH);
+        assert!(!cfg.applies(&headers));
+    }
+}
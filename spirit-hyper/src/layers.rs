@@ -0,0 +1,131 @@
+//! Applying all the wrappers configured in a [`HyperServer`] at once.
+//!
+//! The [`Serve`][crate::Serve] and [`WarpServer`][crate::warp::WarpServer] transformations wrap
+//! the service into all of them. The [`BuildServer`][crate::BuildServer] leaves it to its closure,
+//! so it at least checks the closure has taken the options it should have applied, by tracking
+//! which accessors of the [`HyperServer`] got called.
+
+use std::cell::RefCell;
+
+use log::warn;
+
+use crate::alt_svc::Advertised;
+use crate::maintenance::MaintenanceMode;
+use crate::request_id::RequestIds;
+use crate::request_limits::Limited;
+use crate::trace::Traced;
+use crate::HyperServer;
+
+#[cfg(feature = "metrics")]
+type MetricsLayer<MS> = crate::metrics::Measured<MS>;
+#[cfg(not(feature = "metrics"))]
+type MetricsLayer<MS> = MS;
+
+#[cfg(feature = "compression")]
+type CompressionLayer<MS> = crate::compression::Compressed<MS>;
+#[cfg(not(feature = "compression"))]
+type CompressionLayer<MS> = MS;
+
+#[cfg(feature = "auth")]
+type AuthLayer<MS> = crate::auth::Authenticated<MS>;
+#[cfg(not(feature = "auth"))]
+type AuthLayer<MS> = MS;
+
+/// A service wrapped in all the wrappers a [`HyperServer`] can configure.
+///
+/// From the outside in, these are the metrics, request IDs, trace propagation, alternative
+/// services, compression, maintenance mode, authentication and request limits. The ones turned
+/// off in the configuration (or by the crate features) pass the requests through.
+pub type Layered<MS> = MetricsLayer<
+    RequestIds<Traced<Advertised<CompressionLayer<MaintenanceMode<AuthLayer<Limited<MS>>>>>>>,
+>;
+
+/// Wraps the service into all the layers configured in the server.
+///
+/// The metrics, if turned on, need to be created by the caller, as they need a monitor to report
+/// into.
+pub(crate) fn wrap<Transport, MS>(
+    cfg: &HyperServer<Transport>,
+    #[cfg(feature = "metrics")] metrics: Option<crate::metrics::ServerMetrics>,
+    name: &str,
+    inner: MS,
+) -> Layered<MS> {
+    let service = Limited::new(cfg.request_limits(), inner);
+    #[cfg(feature = "auth")]
+    let service = crate::auth::Authenticated::new(cfg.auth(), service);
+    let service = MaintenanceMode::new(cfg.maintenance(), service);
+    #[cfg(feature = "compression")]
+    let service = crate::compression::Compressed::new(cfg.compression().clone(), service);
+    let service = Advertised::new(cfg.alt_svc(), service);
+    let service = Traced::new(cfg.trace_propagation(), service);
+    let service = RequestIds::new(cfg.request_id(), service);
+    #[cfg(feature = "metrics")]
+    {
+        if metrics.is_none() && cfg.metrics_enabled() {
+            warn!(
+                "Metrics of HTTP server {} are turned on, but there's no monitor for them",
+                name
+            );
+        }
+        crate::metrics::Measured::new(metrics, service)
+    }
+    #[cfg(not(feature = "metrics"))]
+    {
+        if cfg.metrics_enabled() {
+            warn!(
+                "Metrics of HTTP server {} are turned on, but the metrics feature is not",
+                name
+            );
+        }
+        service
+    }
+}
+
+thread_local! {
+    /// The options taken from the configuration since the start of [`tracked`].
+    static CONSUMED: RefCell<Option<Vec<&'static str>>> = RefCell::new(None);
+}
+
+/// Notes that the option was taken out of the configuration.
+pub(crate) fn consume(option: &'static str) {
+    CONSUMED.with(|consumed| {
+        if let Some(consumed) = consumed.borrow_mut().as_mut() {
+            consumed.push(option);
+        }
+    });
+}
+
+/// Runs the closure, collecting the options it took out of the configuration.
+pub(crate) fn tracked<R, F: FnOnce() -> R>(f: F) -> (R, Vec<&'static str>) {
+    let previous = CONSUMED.with(|consumed| consumed.replace(Some(Vec::new())));
+    let result = f();
+    let consumed = CONSUMED.with(|consumed| consumed.replace(previous));
+    (result, consumed.unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maintenance::Maintenance;
+    use crate::HttpServer;
+
+    #[test]
+    fn unconsumed() {
+        let mut cfg = HttpServer::default();
+        assert!(cfg.unconsumed(&[]).is_empty());
+        cfg.inner.maintenance = Some(Maintenance::default());
+        #[cfg(feature = "auth")]
+        {
+            cfg.inner.auth = Some(Default::default());
+        }
+        // Outside of the tracking, nothing is collected
+        cfg.maintenance();
+        let (taken, consumed) = tracked(|| cfg.maintenance().is_some());
+        assert!(taken);
+        assert_eq!(vec!["maintenance"], consumed);
+        #[cfg(feature = "auth")]
+        assert_eq!(vec!["auth"], cfg.unconsumed(&consumed));
+        #[cfg(not(feature = "auth"))]
+        assert!(cfg.unconsumed(&consumed).is_empty());
+    }
+}
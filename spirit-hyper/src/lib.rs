@@ -67,11 +67,80 @@
 //! }
 //! ```
 //!
+//! The [`BuildServer`] leaves the wrappers configured by the options below (compression,
+//! authentication, ...) to its closure. The [`Serve`] transformation applies all of them on its
+//! own.
+//!
 //! Further examples are in the
 //! [git repository](https://github.com/vorner/spirit/tree/master/spirit-hyper/examples).
 //!
+//! # Client
+//!
+//! With the `client` feature, a hyper [`Client`][hyper::Client] can be configured too and kept up
+//! to date inside an [`AtomicHyperClient`][crate::client::AtomicHyperClient]. See the
+//! [`client`][crate::client] module.
+//!
+//! # Metrics
+//!
+//! With the `metrics` feature, each server can report basic statistics into a
+//! [`spirit-dipstick`] monitor. The collection is turned on by the `metrics` configuration
+//! option, see the [`metrics`][crate::metrics] module.
+//!
+//! # Compression
+//!
+//! With the `compression` feature, responses can be compressed by gzip or brotli, according to
+//! the configuration and the `Accept-Encoding` header of the request. See the
+//! [`compression`][crate::compression] module.
+//!
+//! # Alternative services
+//!
+//! A server can advertise alternative endpoints (for example an HTTP/3 one) by the `Alt-Svc`
+//! header, see the [`alt_svc`][crate::alt_svc] module.
+//!
+//! # Trace propagation
+//!
+//! The trace context of distributed tracing (W3C `traceparent` or B3 headers) can be taken from
+//! the requests and made available to the handlers, see the [`trace`][crate::trace] module.
+//!
+//! # Request IDs
+//!
+//! Requests can get an ID (or keep the one from the `X-Request-Id` header), included in the logs
+//! and in the response. See the [`request_id`][crate::request_id] module.
+//!
+//! # Authentication
+//!
+//! With the `auth` feature, a server can let in only requests with a bearer token, a password of
+//! a configured user or from exempt networks. See the [`auth`][crate::auth] module.
+//!
+//! # Maintenance mode
+//!
+//! Servers can answer `503` to drain the traffic away while keeping their sockets bound, switched
+//! by the configuration or at runtime. See the [`maintenance`][crate::maintenance] module.
+//!
+//! # Request limits
+//!
+//! Timeouts of the handlers and sizes of the request bodies can be limited for the whole server
+//! and overridden for path prefixes (eg. more for `/upload`, less for `/healthz`). See the
+//! [`request_limits`][crate::request_limits] module.
+//!
+//! # Routing
+//!
+//! The [`routing`][crate::routing] module allows mapping hosts and path prefixes to named
+//! handlers in the configuration.
+//!
+//! # Static files
+//!
+//! With the `static-files` feature, a server can serve a configured directory. See the
+//! [`static_files`][crate::static_files] module.
+//!
+//! # Warp
+//!
+//! With the `warp` feature, a warp filter can be served directly, see the
+//! [`warp`][crate::warp] module.
+//!
 //! [Spirit]: https://crates.io/crates/spirit.
 //! [`spirit-tokio`]: spirit_tokio
+//! [`spirit-dipstick`]: https://crates.io/crates/spirit-dipstick
 
 use std::error::Error;
 use std::fmt::Debug;
@@ -84,7 +153,7 @@ use hyper::body::Payload;
 use hyper::server::{Builder, Server};
 use hyper::service::{MakeServiceRef, Service};
 use hyper::Body;
-use log::debug;
+use log::{debug, warn};
 use serde::{Deserialize, Serialize};
 use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
 use spirit::fragment::{Fragment, Stackable, Transformation};
@@ -92,12 +161,37 @@ use spirit::AnyError;
 use spirit::Empty;
 use spirit_tokio::installer::FutureInstaller;
 use spirit_tokio::net::limits::WithLimits;
-use spirit_tokio::net::IntoIncoming;
+use spirit_tokio::net::{Endpoint, IntoIncoming};
 use spirit_tokio::TcpListen;
 #[cfg(feature = "cfg-help")]
 use structdoc::StructDoc;
 use tokio::io::{AsyncRead, AsyncWrite};
 
+mod access;
+pub mod alt_svc;
+#[cfg(feature = "auth")]
+pub mod auth;
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod layers;
+pub mod maintenance;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod request_id;
+pub mod request_limits;
+pub mod routing;
+#[cfg(feature = "static-files")]
+pub mod static_files;
+pub mod trace;
+#[cfg(feature = "warp")]
+pub mod warp;
+
+pub use crate::layers::Layered;
+#[cfg(feature = "static-files")]
+pub use crate::static_files::static_files;
+
 fn default_on() -> bool {
     true
 }
@@ -153,6 +247,57 @@ struct HyperCfg {
 
     #[serde(default)]
     http_mode: HttpMode,
+
+    /// Collect per-server metrics.
+    ///
+    /// If turned on, request counts, response status classes, latencies and active connections
+    /// are reported into the metrics sink (see the `metrics` module). Requires the `metrics`
+    /// feature to do anything.
+    ///
+    /// Default is off.
+    #[serde(default)]
+    metrics: bool,
+
+    /// Alternative services to advertise in the `Alt-Svc` header.
+    ///
+    /// Used by the [`Advertised`][alt_svc::Advertised] wrapper, usually to point clients to an
+    /// HTTP/3 endpoint serving the same content.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    alt_svc: Vec<alt_svc::AltSvc>,
+
+    /// Header formats of distributed tracing context to take from the requests.
+    ///
+    /// Used by the [`Traced`][trace::Traced] wrapper. Either `w3c` or `b3`. Empty by default, which
+    /// turns the propagation off.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    trace_propagation: Vec<trace::TraceFormat>,
+
+    /// Assigning IDs to the requests.
+    ///
+    /// Used by the [`RequestIds`][request_id::RequestIds] wrapper. Off if not present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id: Option<request_id::RequestIdCfg>,
+
+    /// Authentication of the requests.
+    ///
+    /// Used by the [`Authenticated`][auth::Authenticated] wrapper. Everyone is let in if not
+    /// present.
+    #[cfg(feature = "auth")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth: Option<auth::Auth>,
+
+    /// Taking part in the maintenance mode.
+    ///
+    /// Used by the [`MaintenanceMode`][maintenance::MaintenanceMode] wrapper. Not affected by the
+    /// maintenance mode if not present.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    maintenance: Option<maintenance::Maintenance>,
+
+    /// Time and size limits of the requests.
+    ///
+    /// Used by the [`Limited`][request_limits::Limited] wrapper. Unlimited by default.
+    #[serde(flatten)]
+    request_limits: request_limits::RequestLimits,
 }
 
 /// A [`Fragment`] for hyper servers.
@@ -160,8 +305,8 @@ struct HyperCfg {
 /// This is a wrapper around a `Transport` [`Fragment`]. It takes something that accepts
 /// connections ‒ like [`TcpListen`] and adds configuration specific for a HTTP server.
 ///
-/// The [`Fragment`] produces [hyper] [Builder]. The [`BuildServer`] transformation can be used to
-/// make it into a [`Server`] and install it into a tokio runtime.
+/// The [`Fragment`] produces [hyper] [Builder]. The [`Serve`] or [`BuildServer`] transformations
+/// can be used to make it into a [`Server`] and install it into a tokio runtime.
 ///
 /// See also the [`HttpServer`] type alias.
 ///
@@ -172,6 +317,27 @@ struct HyperCfg {
 /// * `http1-keepalive`: boolean, default true.
 /// * `http1-writev`: boolean, default true.
 /// * `http-mode`: One of `"both"`, `"http1-only"` or `"http2-only"`. Defaults to `"both"`.
+/// * `metrics`: boolean, default false. See the [`metrics`] module (needs the `metrics` feature).
+/// * `alt-svc`: Array of alternative services to advertise. See the [`alt_svc`] module.
+/// * `trace-propagation`: Array of trace header formats (`"w3c"`, `"b3"`). See the [`trace`]
+///   module.
+/// * `request-id`: Table configuring request IDs. See the [`request_id`] module.
+/// * `maintenance`: Table configuring the maintenance mode. See the [`maintenance`] module.
+/// * `request-timeout`, `max-body-size`, `route-limits`: Limits of the requests, possibly per
+///   path prefix. See the [`request_limits`] module.
+/// * `auth`: Table with the credentials to let in. See the [`auth`][crate#authentication] module
+///   (needs the `auth` feature).
+/// * `compression`, `compression-min-size`, `compression-content-types`: See the
+///   [`compression`][crate#compression] module (needs the `compression` feature).
+///
+/// The [`Serve`] transformation applies the metrics, request IDs, trace propagation, alternative
+/// services, compression, maintenance mode, authentication and request limits on its own. With
+/// [`BuildServer`], the closure needs to wrap the service itself and it should take the options
+/// through the methods of this type. A server with options the closure didn't take is warned
+/// about. If that option is `auth`, the configuration is refused, as serving the requests
+/// unauthenticated is not a good fallback.
+///
+/// [`metrics`]: crate#metrics
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
 #[cfg_attr(feature = "cfg-help", derive(StructDoc))]
 #[serde(rename_all = "kebab-case")]
@@ -183,6 +349,11 @@ pub struct HyperServer<Transport> {
     #[serde(flatten)]
     pub transport: Transport,
 
+    /// Compression of the responses.
+    #[cfg(feature = "compression")]
+    #[serde(flatten)]
+    compression: compression::Compression,
+
     #[serde(flatten)]
     inner: HyperCfg,
 }
@@ -191,11 +362,21 @@ impl<Transport: Default> Default for HyperServer<Transport> {
     fn default() -> Self {
         HyperServer {
             transport: Transport::default(),
+            #[cfg(feature = "compression")]
+            compression: compression::Compression::default(),
             inner: HyperCfg {
                 http1_keepalive: true,
                 http1_writev: true,
                 http1_half_close: true,
                 http_mode: HttpMode::default(),
+                metrics: false,
+                alt_svc: Vec::new(),
+                trace_propagation: Vec::new(),
+                request_id: None,
+                #[cfg(feature = "auth")]
+                auth: None,
+                maintenance: None,
+                request_limits: request_limits::RequestLimits::default(),
             },
         }
     }
@@ -204,7 +385,11 @@ impl<Transport: Default> Default for HyperServer<Transport> {
 impl<Transport: Comparable> Comparable for HyperServer<Transport> {
     fn compare(&self, other: &Self) -> Comparison {
         let transport_cmp = self.transport.compare(&other.transport);
-        if transport_cmp == Comparison::Same && self.inner != other.inner {
+        #[cfg(feature = "compression")]
+        let compression_differs = self.compression != other.compression;
+        #[cfg(not(feature = "compression"))]
+        let compression_differs = false;
+        if transport_cmp == Comparison::Same && (self.inner != other.inner || compression_differs) {
             Comparison::Similar
         } else {
             transport_cmp
@@ -235,6 +420,12 @@ where
             HttpMode::Http1Only => (true, false),
             HttpMode::Http2Only => (false, true),
         };
+        #[cfg(feature = "auth")]
+        {
+            if let Some(auth) = &self.inner.auth {
+                auth.guard()?;
+            }
+        }
         let transport = self.transport.make_resource(seed, name)?;
         let builder = Server::builder(transport.into_incoming())
             .http1_keepalive(self.inner.http1_keepalive)
@@ -246,11 +437,175 @@ where
     }
 }
 
+impl<Transport> HyperServer<Transport> {
+    /// The alternative services this server should advertise.
+    ///
+    /// Meant to be passed to the [`Advertised`][alt_svc::Advertised] wrapper.
+    pub fn alt_svc(&self) -> &[alt_svc::AltSvc] {
+        layers::consume("alt-svc");
+        &self.inner.alt_svc
+    }
+
+    /// Adds another alternative service to advertise.
+    ///
+    /// This allows advertising a server configured elsewhere, like an HTTP/3 one from the
+    /// `spirit-h3` crate, without repeating it in the `alt-svc` option. An alternative already
+    /// present is not added again.
+    pub fn with_alt_svc(mut self, alt_svc: alt_svc::AltSvc) -> Self {
+        if !self.inner.alt_svc.contains(&alt_svc) {
+            self.inner.alt_svc.push(alt_svc);
+        }
+        self
+    }
+
+    /// The formats of trace context headers to propagate.
+    ///
+    /// Meant to be passed to the [`Traced`][trace::Traced] wrapper.
+    pub fn trace_propagation(&self) -> &[trace::TraceFormat] {
+        layers::consume("trace-propagation");
+        &self.inner.trace_propagation
+    }
+
+    /// The configuration of request IDs, if they are turned on.
+    ///
+    /// Meant to be passed to the [`RequestIds`][request_id::RequestIds] wrapper.
+    pub fn request_id(&self) -> Option<&request_id::RequestIdCfg> {
+        layers::consume("request-id");
+        self.inner.request_id.as_ref()
+    }
+
+    /// The maintenance mode configuration, if the server takes part in it.
+    ///
+    /// Meant to be passed to the [`MaintenanceMode`][maintenance::MaintenanceMode] wrapper.
+    pub fn maintenance(&self) -> Option<&maintenance::Maintenance> {
+        layers::consume("maintenance");
+        self.inner.maintenance.as_ref()
+    }
+
+    /// The time and size limits of the requests.
+    ///
+    /// Meant to be passed to the [`Limited`][request_limits::Limited] wrapper.
+    pub fn request_limits(&self) -> &request_limits::RequestLimits {
+        layers::consume("request limits");
+        &self.inner.request_limits
+    }
+
+    /// The authentication configuration, if the requests need to be authenticated.
+    ///
+    /// Meant to be passed to the [`Authenticated`][auth::Authenticated] wrapper.
+    #[cfg(feature = "auth")]
+    pub fn auth(&self) -> Option<&auth::Auth> {
+        layers::consume("auth");
+        self.inner.auth.as_ref()
+    }
+
+    /// The compression of the responses.
+    ///
+    /// Meant to be passed to the [`Compressed`][compression::Compressed] wrapper.
+    #[cfg(feature = "compression")]
+    pub fn compression(&self) -> &compression::Compression {
+        layers::consume("compression");
+        &self.compression
+    }
+
+    /// Is the metrics collection turned on in the configuration?
+    pub fn metrics_enabled(&self) -> bool {
+        self.inner.metrics
+    }
+
+    /// The options configured, but not taken by the closure of [`BuildServer`].
+    fn unconsumed(&self, consumed: &[&str]) -> Vec<&'static str> {
+        #[cfg(feature = "compression")]
+        let compression = self.compression.enabled();
+        #[cfg(not(feature = "compression"))]
+        let compression = false;
+        #[cfg(feature = "auth")]
+        let auth = self.inner.auth.is_some();
+        #[cfg(not(feature = "auth"))]
+        let auth = false;
+        let configured = [
+            ("metrics", self.inner.metrics),
+            ("alt-svc", !self.inner.alt_svc.is_empty()),
+            (
+                "trace-propagation",
+                !self.inner.trace_propagation.is_empty(),
+            ),
+            ("request-id", self.inner.request_id.is_some()),
+            ("maintenance", self.inner.maintenance.is_some()),
+            ("request limits", !self.inner.request_limits.is_empty()),
+            ("compression", compression),
+            ("auth", auth),
+        ];
+        configured
+            .iter()
+            .filter(|(option, on)| *on && !consumed.contains(option))
+            .map(|(option, _)| *option)
+            .collect()
+    }
+
+    /// Creates the metrics for this server, if they are turned on.
+    ///
+    /// The metrics are labeled by the `name` (usually the name of the pipeline) and the address
+    /// the transport listens on. Returns `None` if the collection is turned off in the
+    /// configuration. The result can be used with the [`Measured`][metrics::Measured] wrapper.
+    #[cfg(feature = "metrics")]
+    pub fn server_metrics(
+        &self,
+        monitor: &spirit_dipstick::Monitor,
+        name: &str,
+    ) -> Option<metrics::ServerMetrics>
+    where
+        Transport: Endpoint,
+    {
+        layers::consume("metrics");
+        if self.inner.metrics {
+            Some(metrics::ServerMetrics::new(
+                monitor,
+                name,
+                &self.transport.endpoint(),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
 impl<Transport> Stackable for HyperServer<Transport> where Transport: Stackable {}
 
 /// A type alias for http (plain TCP) hyper server.
 pub type HttpServer<ExtraCfg = Empty> = HyperServer<WithLimits<TcpListen<ExtraCfg>>>;
 
+/// A type alias for https (TLS over TCP) hyper server.
+///
+/// The TLS configuration, including client certificate authentication, is described in the
+/// [`tls`][spirit_tokio::net::tls] module of `spirit-tokio`. For the clients to negotiate HTTP/2,
+/// offer it in the `tls-alpn` option (eg. `tls-alpn = ["h2", "http/1.1"]`).
+///
+/// The verified client certificate is available on the connection through the
+/// [`TlsPeer`][spirit_tokio::net::tls::TlsPeer] trait, for example inside
+/// [`make_service_fn`][hyper::service::make_service_fn]:
+///
+/// ```rust
+/// use hyper::{Body, Request, Response, StatusCode};
+/// use spirit_tokio::net::tls::TlsPeer;
+///
+/// // Called from within `make_service_fn(|conn| ...)`, where the connection is available.
+/// # #[allow(dead_code)]
+/// fn handle<C: TlsPeer>(conn: &C, _req: Request<Body>) -> Response<Body> {
+///     match conn.peer_fingerprint() {
+///         Some(client) => Response::new(Body::from(format!("Hello {}\n", client))),
+///         None => {
+///             let mut response = Response::new(Body::empty());
+///             *response.status_mut() = StatusCode::FORBIDDEN;
+///             response
+///         }
+///     }
+/// }
+/// /// ```
+#[cfg(feature = "tls")]
+pub type HttpsServer<ExtraCfg = Empty> =
+    HyperServer<WithLimits<spirit_tokio::net::tls::TlsListen<TcpListen<ExtraCfg>>>>;
+
 struct ActivateInner<Transport, MS> {
     server: Server<Transport, MS>,
     receiver: Receiver<()>,
@@ -316,6 +671,11 @@ where
 /// method. It also pairs the resource with an [`Installer`][spirit::fragment::Installer].
 ///
 /// Note that a graceful shutdown of the [`Server`] is done as part of the automatic plumbing.
+///
+/// The wrappers configured in the [`HyperServer`] are up to the closure. If it doesn't take some
+/// configured option from the [`HyperServer`], a warning is logged. A configured, but not taken
+/// `auth` makes the configuration refused. The [`Serve`] transformation takes care of the
+/// wrappers on its own.
 pub struct BuildServer<BS>(pub BS);
 
 impl<Transport, Inst, BS, Incoming, S, B>
@@ -340,8 +700,25 @@ where
         cfg: &HyperServer<Transport>,
         name: &'static str,
     ) -> Result<Self::OutputResource, AnyError> {
+        let (server, consumed) = layers::tracked(|| self.0(builder, cfg, name));
+        let unconsumed = cfg.unconsumed(&consumed);
+        if unconsumed.contains(&"auth") {
+            return Err(format!(
+                "HTTP server {} has auth configured, but doesn't use it; refusing to serve \
+                 unauthenticated requests (use Serve or the Authenticated wrapper)",
+                name
+            )
+            .into());
+        }
+        if !unconsumed.is_empty() {
+            warn!(
+                "HTTP server {} doesn't use its configured {}; these are not applied \
+                 (use Serve or the corresponding wrappers)",
+                name,
+                unconsumed.join(", ")
+            );
+        }
         let (sender, receiver) = oneshot::channel();
-        let server = self.0(builder, cfg, name);
         Ok(Activate {
             inner: Some(ActivateInner { server, receiver }),
             sender: Some(sender),
@@ -349,3 +726,134 @@ where
         })
     }
 }
+
+/// A [`Transformation`] serving a [`MakeService`] with all the configured wrappers.
+///
+/// The wrapped closure takes the configuration fragment ([`HyperServer`]) and a `&str` name and
+/// produces the [`MakeService`] (eg. `|| service_fn_ok(handler)`). It is wrapped in all the
+/// wrappers the [`HyperServer`] configures (see [`Layered`]) and served the same way as with
+/// [`BuildServer`].
+///
+/// The metrics need a monitor to report into, see [`with_metrics`][Serve::with_metrics].
+///
+/// # Examples
+///
+/// ```rust
+/// use hyper::{Body, Request, Response};
+/// use hyper::service::service_fn_ok;
+/// use serde::Deserialize;
+/// use spirit::{Empty, Pipeline, Spirit};
+/// use spirit::prelude::*;
+/// use spirit_hyper::{HttpServer, Serve};
+///
+/// const DEFAULT_CONFIG: &str = r#"
+/// [server]
+/// port = 1234
+/// request-timeout = "10s"
+///
+/// [server.maintenance]
+/// active = false
+/// "#;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Config {
+///     server: HttpServer,
+/// }
+///
+/// fn request(_req: Request<Body>) -> Response<Body> {
+///     Response::new(Body::from("Hello world\n"))
+/// }
+///
+/// fn main() {
+///     Spirit::<Empty, Config>::new()
+///         .config_defaults(DEFAULT_CONFIG)
+///         .with(
+///             Pipeline::new("listen")
+///                 .extract_cfg(|cfg: &Config| cfg.server.clone())
+///                 .transform(Serve::new(|_: &HttpServer, _: &str| || service_fn_ok(request)))
+///         )
+///         .run(|spirit| {
+/// #           let spirit = std::sync::Arc::clone(spirit);
+/// #           std::thread::spawn(move || spirit.terminate());
+///             Ok(())
+///         });
+/// }
+/// ```
+///
+/// [`MakeService`]: hyper::service::MakeService
+pub struct Serve<F> {
+    make: F,
+    #[cfg(feature = "metrics")]
+    monitor: Option<spirit_dipstick::Monitor>,
+}
+
+impl<F> Serve<F> {
+    /// Creates the transformation from a closure producing the
+    /// [`MakeService`][hyper::service::MakeService].
+    pub fn new(make: F) -> Self {
+        Serve {
+            make,
+            #[cfg(feature = "metrics")]
+            monitor: None,
+        }
+    }
+
+    /// Reports the metrics into this monitor, if they are turned on in the configuration.
+    ///
+    /// See [`HyperServer::server_metrics`].
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, monitor: &spirit_dipstick::Monitor) -> Self {
+        Serve {
+            monitor: Some(monitor.clone()),
+            ..self
+        }
+    }
+}
+
+impl<Transport, Inst, F, Incoming, MS>
+    Transformation<Builder<Incoming>, Inst, HyperServer<Transport>> for Serve<F>
+where
+    Transport: Fragment + Endpoint + 'static,
+    Transport::Resource: IntoIncoming<Incoming = Incoming, Connection = Incoming::Item>,
+    Incoming: Stream<Error = IoError> + Send + Sync + 'static,
+    Incoming::Item: AsyncRead + AsyncWrite + Send + Sync + 'static,
+    F: Fn(&HyperServer<Transport>, &'static str) -> MS,
+    Layered<MS>: MakeServiceRef<Incoming::Item, ReqBody = Body, ResBody = Body> + 'static,
+    <Layered<MS> as MakeServiceRef<Incoming::Item>>::Service: Send + 'static,
+    <<Layered<MS> as MakeServiceRef<Incoming::Item>>::Service as Service>::Future: Send + 'static,
+{
+    type OutputResource = Activate<Incoming, Layered<MS>>;
+    type OutputInstaller = FutureInstaller<Self::OutputResource>;
+    fn installer(&mut self, _ii: Inst, _name: &'static str) -> Self::OutputInstaller {
+        FutureInstaller::default()
+    }
+    fn transform(
+        &mut self,
+        builder: Builder<Incoming>,
+        cfg: &HyperServer<Transport>,
+        name: &'static str,
+    ) -> Result<Self::OutputResource, AnyError> {
+        let inner = (self.make)(cfg, name);
+        #[cfg(feature = "metrics")]
+        let metrics = self
+            .monitor
+            .as_ref()
+            .and_then(|monitor| cfg.server_metrics(monitor, name));
+        let service = layers::wrap(
+            cfg,
+            #[cfg(feature = "metrics")]
+            metrics,
+            name,
+            inner,
+        );
+        let (sender, receiver) = oneshot::channel();
+        Ok(Activate {
+            inner: Some(ActivateInner {
+                server: builder.serve(service),
+                receiver,
+            }),
+            sender: Some(sender),
+            name,
+        })
+    }
+}
@@ -0,0 +1,308 @@
+//! Maintenance mode of the servers.
+//!
+//! To drain the traffic away from an instance (before a maintenance, a deployment...), the
+//! servers can answer all requests with `503 Service Unavailable` and the `Retry-After` header,
+//! while keeping the listening sockets bound. The clients and load balancers then go elsewhere
+//! (or come back later), but nobody gets a connection refused.
+//!
+//! The `maintenance` option of the [`HyperServer`] ([`Maintenance`]) marks the servers taking
+//! part and the [`MaintenanceMode`] wrapper does the answering. A server is in the maintenance
+//! mode if either:
+//!
+//! * The `active` field in its configuration is set (changed by the usual configuration reload).
+//! * The process-wide [`spirit::maintenance`] switch is on. This can be flipped at runtime, for
+//!   example through the `maintenance on` command of the `spirit-admin` control socket.
+//!
+//! Servers without the option are not affected by the switch, so eg. the admin or metrics
+//! endpoints stay available.
+//!
+//! The [`Serve`][crate::Serve] transformation includes the wrapper, with
+//! [`BuildServer`][crate::BuildServer] it is up to the closure.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::maintenance::MaintenanceMode;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//!
+//! [server.maintenance]
+//! retry-after = "2m"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     let service = || service_fn_ok(request);
+//!                     builder.serve(MaintenanceMode::new(cfg.maintenance(), service))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             // Drain the traffic until told otherwise
+//!             spirit::maintenance::set(true);
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::time::Duration;
+
+use futures::{try_ready, Async, Future, Poll};
+use hyper::header::{HeaderValue, RETRY_AFTER};
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_retry_after() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Configuration of the maintenance mode of a server.
+///
+/// # Fields
+///
+/// * `active`: Force the maintenance mode, regardless of the [`spirit::maintenance`] switch.
+///   Defaults to `false`.
+/// * `retry-after`: When the clients should try again. Sent in the `Retry-After` header, rounded
+///   to whole seconds. Defaults to `1m`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Maintenance {
+    /// Force the maintenance mode.
+    #[serde(default)]
+    pub active: bool,
+
+    /// When the clients should try again.
+    #[serde(
+        default = "default_retry_after",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub retry_after: Duration,
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance {
+            active: false,
+            retry_after: default_retry_after(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    active: bool,
+    retry_after: HeaderValue,
+}
+
+impl Settings {
+    fn active(&self) -> bool {
+        self.active || spirit::maintenance::is_active()
+    }
+
+    fn unavailable<B: Default>(&self) -> Response<B> {
+        let mut response = Response::new(B::default());
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, self.retry_after.clone());
+        response
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] answering `503` during maintenance.
+///
+/// See the [module documentation][crate::maintenance].
+///
+/// The body of the responses needs to be [`Default`] to create the `503` ones.
+pub struct MaintenanceMode<MS> {
+    settings: Option<Settings>,
+    inner: MS,
+}
+
+impl<MS> MaintenanceMode<MS> {
+    /// Wraps the [`MakeService`].
+    ///
+    /// If the configuration is `None`, the server doesn't take part in maintenance and all
+    /// requests pass through.
+    pub fn new(cfg: Option<&Maintenance>, inner: MS) -> Self {
+        let settings = cfg.map(|cfg| Settings {
+            active: cfg.active,
+            retry_after: HeaderValue::from(cfg.retry_after.as_secs()),
+        });
+        MaintenanceMode { settings, inner }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for MaintenanceMode<MS>
+where
+    MS: MakeService<&'a Ctx>,
+    MS::ResBody: Default,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = MaintenanceService<MS::Service>;
+    type Future = MaintenanceMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        MaintenanceMake {
+            settings: self.settings.clone(),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`MaintenanceService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct MaintenanceMake<F> {
+    settings: Option<Settings>,
+    inner: F,
+}
+
+impl<F: Future> Future for MaintenanceMake<F> {
+    type Item = MaintenanceService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(MaintenanceService {
+            settings: self.settings.take(),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that answers `503` during maintenance.
+///
+/// The user should not need to interact with this directly.
+pub struct MaintenanceService<S> {
+    settings: Option<Settings>,
+    inner: S,
+}
+
+impl<S> Service for MaintenanceService<S>
+where
+    S: Service,
+    S::ResBody: Default,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = MaintenanceResponse<S::Future, S::ResBody>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        match &self.settings {
+            Some(settings) if settings.active() => {
+                MaintenanceResponse(State::Unavailable(Some(settings.unavailable())))
+            }
+            _ => MaintenanceResponse(State::Running(self.inner.call(req))),
+        }
+    }
+}
+
+enum State<F, B> {
+    Running(F),
+    Unavailable(Option<Response<B>>),
+}
+
+/// A plumbing future of the response of a [`MaintenanceService`].
+///
+/// The user should not need to interact with this directly.
+pub struct MaintenanceResponse<F, B>(State<F, B>);
+
+impl<F, B> Future for MaintenanceResponse<F, B>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match &mut self.0 {
+            State::Running(inner) => inner.poll(),
+            State::Unavailable(response) => {
+                let response = response
+                    .take()
+                    .expect("Maintenance response polled after completion");
+                Ok(Async::Ready(response))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+
+    use super::*;
+
+    fn status(cfg: Option<&Maintenance>) -> Response<Body> {
+        let mut service = MaintenanceService {
+            settings: MaintenanceMode::new(cfg, ()).settings,
+            inner: hyper::service::service_fn_ok(|_: Request<Body>| Response::new(Body::empty())),
+        };
+        service.call(Request::new(Body::empty())).wait().unwrap()
+    }
+
+    #[test]
+    fn unavailable() {
+        assert_eq!(StatusCode::OK, status(None).status());
+        let cfg = Maintenance::default();
+        assert_eq!(StatusCode::OK, status(Some(&cfg)).status());
+        let cfg = Maintenance {
+            active: true,
+            retry_after: Duration::from_millis(90_500),
+        };
+        let response = status(Some(&cfg));
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+        assert_eq!("90", response.headers()[RETRY_AFTER]);
+
+        // The global switch affects only the servers taking part
+        spirit::maintenance::set(true);
+        let cfg = Maintenance::default();
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, status(Some(&cfg)).status());
+        assert_eq!(StatusCode::OK, status(None).status());
+        spirit::maintenance::set(false);
+    }
+}
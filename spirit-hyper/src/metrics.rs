@@ -0,0 +1,261 @@
+//! Per-server HTTP metrics.
+//!
+//! When the `metrics` configuration option of a [`HyperServer`] is turned on, the server can
+//! report some basic statistics about itself into a [`Monitor`] (the shared metrics sink
+//! configured through [`spirit-dipstick`]):
+//!
+//! * `requests`: Number of handled requests.
+//! * `status.1xx` ‒ `status.5xx`: Number of responses in each status class.
+//! * `latency`: A timer of how long it takes to produce the response.
+//! * `connections`: A level of currently active connections.
+//!
+//! The metrics are placed under the name of the pipeline and the address the server listens on,
+//! so multiple configured servers don't mix together.
+//!
+//! The [`Serve`][crate::Serve] transformation reports the metrics into the monitor passed to its
+//! [`with_metrics`][crate::Serve::with_metrics]. Otherwise, the service needs to be wrapped in
+//! the [`Measured`] wrapper.
+//!
+//! This is available under the `metrics` feature flag.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_dipstick::Monitor;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::metrics::Measured;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! metrics = true
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     let monitor = Monitor::new();
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(move |builder: Builder<_>, cfg: &HttpServer, name| {
+//!                     let metrics = cfg.server_metrics(&monitor, name);
+//!                     builder.serve(Measured::new(metrics, || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+//! [`spirit-dipstick`]: spirit_dipstick
+
+use std::error::Error;
+
+use dipstick::{Counter, InputScope, Level, Prefixed, TimeHandle, Timer};
+use futures::{Async, Future, Poll};
+use hyper::body::Payload;
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response};
+use log::debug;
+use spirit_dipstick::Monitor;
+
+/// The metrics of a single server.
+///
+/// This is created by [`HyperServer::server_metrics`][crate::HyperServer::server_metrics]. It
+/// is cheap to clone, all clones report into the same metrics.
+#[derive(Clone, Debug)]
+pub struct ServerMetrics {
+    requests: Counter,
+    status: [Counter; 5],
+    latency: Timer,
+    connections: Level,
+}
+
+impl ServerMetrics {
+    /// Creates the metrics for a server.
+    ///
+    /// The metrics are created under `name` and `endpoint` inside the given `monitor`.
+    pub fn new(monitor: &Monitor, name: &str, endpoint: &str) -> Self {
+        debug!("Creating HTTP metrics for {} on {}", name, endpoint);
+        let scope = monitor.add_name(name).add_name(endpoint);
+        let status = scope.add_name("status");
+        ServerMetrics {
+            requests: scope.counter("requests"),
+            status: [
+                status.counter("1xx"),
+                status.counter("2xx"),
+                status.counter("3xx"),
+                status.counter("4xx"),
+                status.counter("5xx"),
+            ],
+            latency: scope.timer("latency"),
+            connections: scope.level("connections"),
+        }
+    }
+
+    fn record_status(&self, status: u16) {
+        let class = (status / 100) as usize;
+        if class >= 1 && class <= self.status.len() {
+            self.status[class - 1].count(1);
+        }
+    }
+}
+
+/// Decrements the active connections when the per-connection service goes away.
+struct ConnectionGuard(Level);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.adjust(-1);
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] that collects [`ServerMetrics`].
+///
+/// If there are no metrics (because they are turned off in the configuration), this passes
+/// everything through without any overhead except for a branch.
+pub struct Measured<MS> {
+    metrics: Option<ServerMetrics>,
+    inner: MS,
+}
+
+impl<MS> Measured<MS> {
+    /// Wraps the given [`MakeService`].
+    pub fn new(metrics: Option<ServerMetrics>, inner: MS) -> Self {
+        Measured { metrics, inner }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Measured<MS>
+where
+    MS: MakeService<&'a Ctx>,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = MeasuredService<MS::Service>;
+    type Future = MeasuredMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        MeasuredMake {
+            metrics: self.metrics.clone(),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`MeasuredService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct MeasuredMake<F> {
+    metrics: Option<ServerMetrics>,
+    inner: F,
+}
+
+impl<F> Future for MeasuredMake<F>
+where
+    F: Future,
+{
+    type Item = MeasuredService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = futures::try_ready!(self.inner.poll());
+        let metrics = self.metrics.take();
+        let guard = metrics.as_ref().map(|m| {
+            m.connections.adjust(1);
+            ConnectionGuard(m.connections.clone())
+        });
+        Ok(Async::Ready(MeasuredService {
+            metrics,
+            inner,
+            _guard: guard,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that collects the metrics.
+///
+/// The user should not need to interact with this directly.
+pub struct MeasuredService<S> {
+    metrics: Option<ServerMetrics>,
+    inner: S,
+    _guard: Option<ConnectionGuard>,
+}
+
+impl<S> Service for MeasuredService<S>
+where
+    S: Service,
+    S::ResBody: Payload,
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = MeasuredResponse<S::Future>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        let started = self.metrics.as_ref().map(|m| {
+            m.requests.count(1);
+            (m.clone(), m.latency.start())
+        });
+        MeasuredResponse {
+            started,
+            inner: self.inner.call(req),
+        }
+    }
+}
+
+/// A plumbing future that records the latency and status of one response.
+///
+/// The user should not need to interact with this directly.
+pub struct MeasuredResponse<F> {
+    started: Option<(ServerMetrics, TimeHandle)>,
+    inner: F,
+}
+
+impl<F, B> Future for MeasuredResponse<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let response = futures::try_ready!(self.inner.poll());
+        if let Some((metrics, start)) = self.started.take() {
+            metrics.latency.stop(start);
+            metrics.record_status(response.status().as_u16());
+        }
+        Ok(Async::Ready(response))
+    }
+}
@@ -0,0 +1,451 @@
+//! Request IDs.
+//!
+//! Giving each request an ID and passing it along to other services (and back to the client)
+//! makes it possible to find everything related to a request in the logs. The `request-id`
+//! option of the [`HyperServer`] ([`RequestIdCfg`]) turns it on for the server and the
+//! [`RequestIds`] wrapper does the work:
+//!
+//! * The ID is taken from the request header (`X-Request-Id` by default) if the client sent a
+//!   sane one, otherwise a new one is generated.
+//! * The handler gets it as the [`RequestId`] in the [extensions][hyper::Request::extensions] of
+//!   the request and through the [`current`] function, while the handler is running.
+//! * The response gets the same header.
+//! * The ID is included in the access log (on the debug level, under the `spirit_hyper::access`
+//!   target) and in the log of failed requests.
+//!
+//! If the option is not present, the wrapper does nothing. Servers created by the
+//! [`Serve`][crate::Serve] transformation have the wrapper already.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::request_id::{RequestId, RequestIds};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//!
+//! [server.request-id]
+//! trust-incoming = false
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(req: Request<Body>) -> Response<Body> {
+//!     let id = req.extensions().get::<RequestId>().cloned();
+//!     Response::new(Body::from(format!("Hello {:?}\n", id)))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     builder.serve(RequestIds::new(cfg.request_id(), || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::cell::RefCell;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+use futures::{try_ready, Async, Future, Poll};
+use hyper::header::{HeaderName, HeaderValue};
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response};
+use log::warn;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+use crate::access::{self, Entry};
+use crate::trace;
+
+/// The longest ID accepted from the client.
+const MAX_LEN: usize = 128;
+
+fn default_header() -> String {
+    "X-Request-Id".to_owned()
+}
+
+fn default_on() -> bool {
+    true
+}
+
+/// Configuration of request IDs.
+///
+/// # Fields
+///
+/// * `header`: The header carrying the ID. Defaults to `X-Request-Id`.
+/// * `trust-incoming`: Use the ID sent by the client (or a proxy in front of the server), if any.
+///   Defaults to `true`.
+/// * `respond`: Send the ID back in the response. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct RequestIdCfg {
+    /// The header carrying the ID.
+    #[serde(default = "default_header")]
+    pub header: String,
+
+    /// Use the ID sent by the client.
+    #[serde(default = "default_on")]
+    pub trust_incoming: bool,
+
+    /// Send the ID back in the response.
+    #[serde(default = "default_on")]
+    pub respond: bool,
+}
+
+impl Default for RequestIdCfg {
+    fn default() -> Self {
+        RequestIdCfg {
+            header: default_header(),
+            trust_incoming: true,
+            respond: true,
+        }
+    }
+}
+
+/// The ID of a request.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    /// Generates a new random ID.
+    pub fn generate() -> Self {
+        let id = format!("{:016x}{:016x}", trace::random(), trace::random());
+        RequestId(id.into())
+    }
+
+    /// The ID as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Accepts an ID from the client, if it looks sane.
+    ///
+    /// Only printable ASCII is accepted, so the ID can't mess up the logs.
+    fn incoming(value: &HeaderValue) -> Option<Self> {
+        let value = value.to_str().ok()?.trim();
+        let sane = !value.is_empty()
+            && value.len() <= MAX_LEN
+            && value.bytes().all(|b| b.is_ascii_graphic());
+        if sane {
+            Some(RequestId(value.into()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for RequestId {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str(&self.0)
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<RequestId>> = RefCell::new(None);
+}
+
+/// The ID of the request being handled by the current thread.
+///
+/// This is set while the handler wrapped in [`RequestIds`] runs.
+pub fn current() -> Option<RequestId> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Restores the previous current ID when dropped.
+struct CurrentGuard(Option<RequestId>);
+
+impl CurrentGuard {
+    fn enter(id: &RequestId) -> Self {
+        CurrentGuard(CURRENT.with(|current| current.replace(Some(id.clone()))))
+    }
+}
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        let previous = self.0.take();
+        CURRENT.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    header: HeaderName,
+    trust_incoming: bool,
+    respond: bool,
+}
+
+/// A wrapper around a hyper [`MakeService`] that assigns IDs to requests.
+///
+/// See the [module documentation][crate::request_id].
+pub struct RequestIds<MS> {
+    settings: Option<Settings>,
+    inner: MS,
+}
+
+impl<MS> RequestIds<MS> {
+    /// Wraps the [`MakeService`].
+    ///
+    /// If the configuration is `None`, requests pass through untouched.
+    pub fn new(cfg: Option<&RequestIdCfg>, inner: MS) -> Self {
+        let settings = cfg.and_then(|cfg| match HeaderName::from_bytes(cfg.header.as_bytes()) {
+            Ok(header) => Some(Settings {
+                header,
+                trust_incoming: cfg.trust_incoming,
+                respond: cfg.respond,
+            }),
+            Err(e) => {
+                warn!("Can't use {} as request ID header: {}", cfg.header, e);
+                None
+            }
+        });
+        RequestIds { settings, inner }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for RequestIds<MS>
+where
+    MS: MakeService<&'a Ctx>,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = RequestIdService<MS::Service>;
+    type Future = RequestIdMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        RequestIdMake {
+            settings: self.settings.clone(),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`RequestIdService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct RequestIdMake<F> {
+    settings: Option<Settings>,
+    inner: F,
+}
+
+impl<F: Future> Future for RequestIdMake<F> {
+    type Item = RequestIdService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(RequestIdService {
+            settings: self.settings.take(),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that assigns the IDs.
+///
+/// The user should not need to interact with this directly.
+pub struct RequestIdService<S> {
+    settings: Option<Settings>,
+    inner: S,
+}
+
+impl<S: Service> Service for RequestIdService<S> {
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = RequestIdResponse<S::Future>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, mut req: Request<Self::ReqBody>) -> Self::Future {
+        let settings = match &self.settings {
+            Some(settings) => settings,
+            None => {
+                return RequestIdResponse {
+                    assigned: None,
+                    inner: self.inner.call(req),
+                };
+            }
+        };
+        let id = if settings.trust_incoming {
+            req.headers()
+                .get(&settings.header)
+                .and_then(RequestId::incoming)
+        } else {
+            None
+        };
+        let id = id.unwrap_or_else(RequestId::generate);
+        req.extensions_mut().insert(id.clone());
+        let access = access::start(&mut req);
+        access::record(&req, |fields| fields.request_id = Some(id.to_string()));
+        let inner = {
+            let _current = CurrentGuard::enter(&id);
+            self.inner.call(req)
+        };
+        let header = if settings.respond {
+            Some(settings.header.clone())
+        } else {
+            None
+        };
+        RequestIdResponse {
+            assigned: Some(Assigned { id, header, access }),
+            inner,
+        }
+    }
+}
+
+struct Assigned {
+    id: RequestId,
+    header: Option<HeaderName>,
+    access: Option<Entry>,
+}
+
+/// A plumbing future running the handler with the request ID.
+///
+/// The user should not need to interact with this directly.
+pub struct RequestIdResponse<F> {
+    assigned: Option<Assigned>,
+    inner: F,
+}
+
+impl<F, B> Future for RequestIdResponse<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let assigned = match self.assigned.as_ref() {
+            Some(assigned) => assigned,
+            None => return self.inner.poll(),
+        };
+        let result = {
+            let _current = CurrentGuard::enter(&assigned.id);
+            self.inner.poll()
+        };
+        match result {
+            Ok(Async::Ready(mut response)) => {
+                if let Some(access) = &assigned.access {
+                    access.finished(response.status().as_u16());
+                }
+                if let Some(header) = &assigned.header {
+                    // Generated IDs are hex and incoming ones were a valid header already
+                    let value = HeaderValue::from_str(assigned.id.as_str())
+                        .expect("Request ID is a valid header value");
+                    response.headers_mut().insert(header.clone(), value);
+                }
+                Ok(Async::Ready(response))
+            }
+            Err(e) => {
+                if let Some(access) = &assigned.access {
+                    access.failed();
+                }
+                Err(e)
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::Body;
+
+    use super::*;
+
+    #[test]
+    fn incoming_ids() {
+        let check = |value: &str| RequestId::incoming(&HeaderValue::from_str(value).unwrap());
+        assert_eq!("abc-123", check(" abc-123 ").unwrap().as_str());
+        assert!(check("").is_none());
+        assert!(check("has space").is_none());
+        assert!(check(&"x".repeat(MAX_LEN + 1)).is_none());
+        assert_ne!(RequestId::generate(), RequestId::generate());
+        assert_eq!(32, RequestId::generate().as_str().len());
+    }
+
+    fn respond(cfg: &RequestIdCfg, req: Request<Body>) -> (Response<Body>, Option<RequestId>) {
+        let mut service = RequestIdService {
+            settings: RequestIds::new(Some(cfg), ()).settings,
+            inner: hyper::service::service_fn_ok(|req: Request<Body>| {
+                let mut response = Response::new(Body::empty());
+                response.extensions_mut().insert(current());
+                *response.headers_mut() = req.headers().clone();
+                response.headers_mut().insert(
+                    "seen",
+                    HeaderValue::from_str(req.extensions().get::<RequestId>().unwrap().as_str())
+                        .unwrap(),
+                );
+                response
+            }),
+        };
+        let mut response = service.call(req).wait().unwrap();
+        let current = response
+            .extensions_mut()
+            .remove::<Option<RequestId>>()
+            .unwrap();
+        (response, current)
+    }
+
+    #[test]
+    fn assigned() {
+        let cfg = RequestIdCfg::default();
+        let req = Request::builder()
+            .header("x-request-id", "from-proxy")
+            .body(Body::empty())
+            .unwrap();
+        let (response, current) = respond(&cfg, req);
+        assert_eq!("from-proxy", response.headers()["seen"]);
+        assert_eq!("from-proxy", response.headers()["x-request-id"]);
+        assert_eq!(Some("from-proxy"), current.as_ref().map(RequestId::as_str));
+
+        let cfg = RequestIdCfg {
+            trust_incoming: false,
+            ..RequestIdCfg::default()
+        };
+        let req = Request::builder()
+            .header("x-request-id", "from-proxy")
+            .body(Body::empty())
+            .unwrap();
+        let (response, _) = respond(&cfg, req);
+        assert_ne!("from-proxy", response.headers()["seen"]);
+        assert_eq!(
+            response.headers()["seen"],
+            response.headers()["x-request-id"]
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! Time and size limits of requests.
+//!
+//! Different parts of an application usually need different limits ‒ an upload endpoint needs
+//! large bodies and plenty of time, while a health check should answer in a second or be
+//! considered dead. The options of the [`HyperServer`] described by [`RequestLimits`] set the
+//! limits for the whole server and override them for path prefixes. The [`Limited`] wrapper
+//! enforces them:
+//!
+//! * If the handler doesn't produce the response in time, the client gets `503 Service
+//!   Unavailable` and the handler's future is dropped.
+//! * If the request declares a larger body (by `Content-Length`), it is refused with `413 Payload
+//!   Too Large` without calling the handler. Otherwise, reading more than the limit from the
+//!   body results in an error.
+//!
+//! The limits are part of the server configuration, so they change with a configuration reload
+//! (without closing the listening socket). The [`Serve`][crate::Serve] transformation enforces
+//! them without further ado.
+//!
+//! # Configuration options
+//!
+//! * `request-timeout`: How long a handler may take to produce the response. Unlimited if not
+//!   set.
+//! * `max-body-size`: The largest accepted request body (eg. `10MiB`). Unlimited if not set.
+//! * `route-limits`: Array of overrides for path prefixes. The longest matching prefix is used.
+//!   Each has these fields:
+//!   - `prefix`: The path prefix, matched on whole path segments (mandatory).
+//!   - `timeout`: Overrides the `request-timeout`.
+//!   - `max-body-size`: Overrides the `max-body-size`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::request_limits::Limited;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! request-timeout = "30s"
+//! max-body-size = "1MiB"
+//!
+//! [[server.route-limits]]
+//! prefix = "/upload"
+//! timeout = "10m"
+//! max-body-size = "1GiB"
+//!
+//! [[server.route-limits]]
+//! prefix = "/healthz"
+//! timeout = "1s"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     builder.serve(Limited::new(cfg.request_limits(), || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{try_ready, Async, Future, Poll, Stream};
+use hyper::header::CONTENT_LENGTH;
+use hyper::service::{MakeService, Service};
+use hyper::{Body, Chunk, Request, Response, StatusCode};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use spirit::cfg::ByteSize;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::clock;
+use tokio::timer::Delay;
+
+use crate::routing;
+
+/// Limits overridden for a path prefix.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct RouteLimits {
+    /// The path prefix, matched on whole path segments.
+    pub prefix: String,
+
+    /// How long a handler may take to produce the response.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// The largest accepted request body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<ByteSize>,
+}
+
+/// Limits of the requests of a server.
+///
+/// See the [module documentation][crate::request_limits] for the options.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct RequestLimits {
+    /// How long a handler may take to produce the response.
+    ///
+    /// Unlimited if not set.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub request_timeout: Option<Duration>,
+
+    /// The largest accepted request body.
+    ///
+    /// Unlimited if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_body_size: Option<ByteSize>,
+
+    /// Overrides of the limits for path prefixes.
+    ///
+    /// The longest matching prefix is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub route_limits: Vec<RouteLimits>,
+}
+
+/// The limits applying to one request.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+struct Applied {
+    timeout: Option<Duration>,
+    max_body_size: Option<u64>,
+}
+
+impl RequestLimits {
+    /// Are there no limits at all?
+    pub fn is_empty(&self) -> bool {
+        self.request_timeout.is_none()
+            && self.max_body_size.is_none()
+            && self
+                .route_limits
+                .iter()
+                .all(|route| route.timeout.is_none() && route.max_body_size.is_none())
+    }
+
+    fn applied(&self, path: &str) -> Applied {
+        let route = self
+            .route_limits
+            .iter()
+            .filter(|route| routing::prefix_matches(&route.prefix, path))
+            .max_by_key(|route| route.prefix.trim_end_matches('/').len());
+        let timeout = route.and_then(|route| route.timeout);
+        let max_body_size = route.and_then(|route| route.max_body_size);
+        Applied {
+            timeout: timeout.or(self.request_timeout),
+            max_body_size: max_body_size.or(self.max_body_size).map(ByteSize::bytes),
+        }
+    }
+}
+
+fn refusal<B: Default>(status: StatusCode) -> Response<B> {
+    let mut response = Response::new(B::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// The request body, failing once it gets over the limit.
+struct CappedBody {
+    body: Body,
+    remaining: u64,
+}
+
+impl Stream for CappedBody {
+    type Item = Chunk;
+    type Error = AnyError;
+    fn poll(&mut self) -> Poll<Option<Chunk>, AnyError> {
+        let chunk = try_ready!(self.body.poll());
+        if let Some(chunk) = &chunk {
+            let len = chunk.len() as u64;
+            if len > self.remaining {
+                return Err("Request body over the size limit".into());
+            }
+            self.remaining -= len;
+        }
+        Ok(Async::Ready(chunk))
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] enforcing the [`RequestLimits`].
+///
+/// See the [module documentation][crate::request_limits].
+///
+/// The body of the responses needs to be [`Default`] to create the refusals.
+pub struct Limited<MS> {
+    limits: Arc<RequestLimits>,
+    inner: MS,
+}
+
+impl<MS> Limited<MS> {
+    /// Wraps the [`MakeService`].
+    pub fn new(limits: &RequestLimits, inner: MS) -> Self {
+        Limited {
+            limits: Arc::new(limits.clone()),
+            inner,
+        }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Limited<MS>
+where
+    MS: MakeService<&'a Ctx, ReqBody = Body>,
+    MS::ResBody: Default,
+{
+    type ReqBody = Body;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = LimitedService<MS::Service>;
+    type Future = LimitedMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        LimitedMake {
+            limits: Some(Arc::clone(&self.limits)),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`LimitedService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct LimitedMake<F> {
+    limits: Option<Arc<RequestLimits>>,
+    inner: F,
+}
+
+impl<F: Future> Future for LimitedMake<F> {
+    type Item = LimitedService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(LimitedService {
+            limits: self.limits.take().expect("Polled after completion"),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] enforcing the limits.
+///
+/// The user should not need to interact with this directly.
+pub struct LimitedService<S> {
+    limits: Arc<RequestLimits>,
+    inner: S,
+}
+
+impl<S> Service for LimitedService<S>
+where
+    S: Service<ReqBody = Body>,
+    S::ResBody: Default,
+{
+    type ReqBody = Body;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = LimitedResponse<S::Future, S::ResBody>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let applied = self.limits.applied(req.uri().path());
+        if let Some(max) = applied.max_body_size {
+            let declared = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|len| len.to_str().ok())
+                .and_then(|len| len.parse::<u64>().ok());
+            if declared.map(|len| len > max).unwrap_or(false) {
+                debug!("Refusing too large body of {}", req.uri());
+                let refused = refusal(StatusCode::PAYLOAD_TOO_LARGE);
+                return LimitedResponse {
+                    state: State::Refused(Some(refused)),
+                    deadline: None,
+                };
+            }
+            req = req.map(|body| {
+                Body::wrap_stream(CappedBody {
+                    body,
+                    remaining: max,
+                })
+            });
+        }
+        LimitedResponse {
+            state: State::Running(self.inner.call(req)),
+            deadline: applied
+                .timeout
+                .map(|timeout| Delay::new(clock::now() + timeout)),
+        }
+    }
+}
+
+enum State<F, B> {
+    Running(F),
+    Refused(Option<Response<B>>),
+}
+
+/// A plumbing future of the response of a [`LimitedService`].
+///
+/// The user should not need to interact with this directly.
+pub struct LimitedResponse<F, B> {
+    state: State<F, B>,
+    deadline: Option<Delay>,
+}
+
+impl<F, B> Future for LimitedResponse<F, B>
+where
+    F: Future<Item = Response<B>>,
+    B: Default,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match &mut self.state {
+            State::Running(inner) => {
+                if let Async::Ready(response) = inner.poll()? {
+                    return Ok(Async::Ready(response));
+                }
+            }
+            State::Refused(response) => {
+                let response = response
+                    .take()
+                    .expect("Limited response polled after completion");
+                return Ok(Async::Ready(response));
+            }
+        }
+        if let Some(deadline) = &mut self.deadline {
+            match deadline.poll() {
+                Ok(Async::Ready(())) => {
+                    debug!("Request handler timed out");
+                    return Ok(Async::Ready(refusal(StatusCode::SERVICE_UNAVAILABLE)));
+                }
+                Ok(Async::NotReady) => (),
+                Err(e) => {
+                    warn!("Request timeout failed, letting the request run: {}", e);
+                    self.deadline = None;
+                }
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future;
+    use hyper::service::service_fn;
+    use tokio::runtime::current_thread::Runtime;
+
+    use super::*;
+
+    fn limits() -> RequestLimits {
+        serde_json::from_str(
+            r#"{
+                "request-timeout": "30s",
+                "max-body-size": "1KiB",
+                "route-limits": [
+                    {"prefix": "/upload", "timeout": "10m", "max-body-size": "1MiB"},
+                    {"prefix": "/upload/small", "max-body-size": 10},
+                    {"prefix": "/healthz", "timeout": "50ms"}
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn applied() {
+        let limits = limits();
+        let applied = |timeout: u64, size: u64| Applied {
+            timeout: Some(Duration::from_millis(timeout)),
+            max_body_size: Some(size),
+        };
+        assert_eq!(applied(30_000, 1024), limits.applied("/"));
+        assert_eq!(applied(30_000, 1024), limits.applied("/uploads"));
+        assert_eq!(applied(600_000, 1 << 20), limits.applied("/upload/big"));
+        assert_eq!(applied(30_000, 10), limits.applied("/upload/small/x"));
+        assert_eq!(applied(50, 1024), limits.applied("/healthz"));
+        assert!(RequestLimits::default().is_empty());
+        assert!(!limits.is_empty());
+    }
+
+    type Responding = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+
+    fn respond(path: &str, body: Vec<u8>) -> Response<Body> {
+        let mut service = LimitedService {
+            limits: Arc::new(limits()),
+            inner: service_fn(|req: Request<Body>| -> Responding {
+                if req.uri().path() == "/healthz" {
+                    Box::new(future::empty())
+                } else {
+                    Box::new(
+                        req.into_body()
+                            .concat2()
+                            .map(|body| Response::new(Body::from(body.len().to_string()))),
+                    )
+                }
+            }),
+        };
+        let req = Request::post(path)
+            .header(CONTENT_LENGTH, body.len().to_string())
+            .body(Body::from(body))
+            .unwrap();
+        Runtime::new().unwrap().block_on(service.call(req)).unwrap()
+    }
+
+    #[test]
+    fn enforced() {
+        let big = vec![0; 2048];
+        assert_eq!(StatusCode::OK, respond("/", b"hello".to_vec()).status());
+        assert_eq!(StatusCode::OK, respond("/upload", big.clone()).status());
+        let response = respond("/", big.clone());
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, response.status());
+        let response = respond("/healthz", Vec::new());
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, response.status());
+
+        // Without the Content-Length, it is cut when reading the body
+        let capped = CappedBody {
+            body: Body::from(big),
+            remaining: 1024,
+        };
+        assert!(capped.concat2().wait().is_err());
+    }
+}
@@ -0,0 +1,501 @@
+//! Routing of requests to named handlers, configured in the configuration.
+//!
+//! The application registers its handlers under names in code, by the
+//! [`route_handler`][Router::route_handler] method. Which handler serves which part of the URL
+//! space is then decided by the configuration ‒ by matching the host of the request and the
+//! prefix of the path. That way a deployment can remap the URL space without recompiling the
+//! application.
+//!
+//! # Configuration options
+//!
+//! The [`Routing`] configuration has these options:
+//!
+//! * `routes`: An array of routes. The first matching one is used. Each has these fields:
+//!   - `host`: A host pattern. Either an exact host name, `*` for any host or `*.example.com` to
+//!     match any subdomain. Defaults to `*`.
+//!   - `prefix`: The path prefix, matched on whole path segments. Defaults to `/`.
+//!   - `handler`: The name of the handler to use (mandatory).
+//!   - `strip-prefix`: Remove the prefix from the path before passing the request to the
+//!     handler. Defaults to `false`.
+//! * `fallback`: What to do with requests matching no route. One of:
+//!   - `{ type = "not-found" }` (the default).
+//!   - `{ type = "redirect", location = "https://example.com/", permanent = false }`.
+//!   - `{ type = "handler", name = "handler-name" }`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::sync::Arc;
+//!
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit::validation::Action;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::routing::{Router, Routing};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//!
+//! [[server.routes]]
+//! prefix = "/api"
+//! handler = "api"
+//! strip-prefix = true
+//!
+//! [[server.routes]]
+//! handler = "hello"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer<Routing>,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer<Routing> {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn hello(_req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from("Hello world\n"))
+//! }
+//!
+//! fn api(req: Request<Body>) -> Response<Body> {
+//!     Response::new(Body::from(format!("API call {}\n", req.uri().path())))
+//! }
+//!
+//! fn main() {
+//!     let router = Router::new()
+//!         .route_handler("hello", service_fn_ok(hello))
+//!         .route_handler("api", service_fn_ok(api));
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .config_validator({
+//!             // Refuse configurations referencing handlers we don't have
+//!             let router = router.clone();
+//!             move |_old: &Arc<Config>, new: &Arc<Config>, _: &Empty| {
+//!                 router.check(&new.server.transport.listener.extra_cfg)?;
+//!                 Ok(Action::new())
+//!             }
+//!         })
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(
+//!                     move |builder: Builder<_>, cfg: &HttpServer<Routing>, _: &str| {
+//!                         builder.serve(router.make_service(&cfg.transport.listener.extra_cfg))
+//!                     },
+//!                 ))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use futures::future::{self, FutureResult};
+use futures::Future;
+use hyper::header::{HeaderValue, HOST, LOCATION};
+use hyper::service::{MakeService, Service};
+use hyper::{Body, Request, Response, StatusCode, Uri};
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_host() -> String {
+    "*".to_owned()
+}
+
+fn default_prefix() -> String {
+    "/".to_owned()
+}
+
+/// A single route in the [`Routing`] configuration.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Route {
+    /// The host pattern to match.
+    ///
+    /// Either exact host name, `*` for any host, or `*.example.com` for any subdomain. Defaults
+    /// to `*`.
+    #[serde(default = "default_host")]
+    pub host: String,
+
+    /// The prefix of the path to match.
+    ///
+    /// It matches on whole path segments only (`/api` matches `/api` and `/api/x`, but not
+    /// `/apis`). Defaults to `/`.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+
+    /// Name of the handler serving the requests.
+    pub handler: String,
+
+    /// Remove the prefix from the path before passing the request to the handler.
+    #[serde(default)]
+    pub strip_prefix: bool,
+}
+
+/// Does the prefix match the path on whole segments (`/api` matches `/api/x`, but not `/apix`)?
+pub(crate) fn prefix_matches(prefix: &str, path: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    path.starts_with(prefix)
+        && (path.len() == prefix.len() || path[prefix.len()..].starts_with('/'))
+}
+
+impl Route {
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        if self.host == "*" {
+            return true;
+        }
+        let host = match host {
+            Some(host) => host,
+            None => return false,
+        };
+        if self.host.starts_with("*.") {
+            let suffix = &self.host[1..];
+            host.len() > suffix.len() && host.to_ascii_lowercase().ends_with(suffix)
+        } else {
+            host.eq_ignore_ascii_case(&self.host)
+        }
+    }
+
+    fn matches_path(&self, path: &str) -> bool {
+        prefix_matches(&self.prefix, path)
+    }
+
+    fn strip(&self, path: &str) -> String {
+        let rest = &path[self.prefix.trim_end_matches('/').len()..];
+        if rest.is_empty() {
+            "/".to_owned()
+        } else {
+            rest.to_owned()
+        }
+    }
+}
+
+/// What to do with a request that matches no route.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Fallback {
+    /// Answer with 404 Not Found.
+    NotFound,
+
+    /// Redirect to the given location.
+    Redirect {
+        /// Where to redirect to.
+        location: String,
+
+        /// Use permanent (301) redirect instead of a temporary (307) one.
+        #[serde(default)]
+        permanent: bool,
+    },
+
+    /// Pass the request to a named handler.
+    Handler {
+        /// The name of the handler.
+        name: String,
+    },
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback::NotFound
+    }
+}
+
+/// The routing configuration.
+///
+/// See the [module documentation](index.html) for the options. This is usually put into the
+/// `ExtraCfg` of the server.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Routing {
+    /// The routes, the first matching one is used.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub routes: Vec<Route>,
+
+    /// What to do with requests matching no route.
+    #[serde(default)]
+    pub fallback: Fallback,
+}
+
+impl Routing {
+    fn handler_names(&self) -> impl Iterator<Item = &str> {
+        let fallback = match &self.fallback {
+            Fallback::Handler { name } => Some(name.as_str()),
+            _ => None,
+        };
+        self.routes
+            .iter()
+            .map(|r| r.handler.as_str())
+            .chain(fallback)
+    }
+}
+
+/// A future of a response produced by a handler.
+pub type ResponseFuture = Box<dyn Future<Item = Response<Body>, Error = AnyError> + Send>;
+
+type Handler = Arc<dyn Fn(Request<Body>) -> ResponseFuture + Send + Sync>;
+
+/// A set of named handlers.
+///
+/// Handlers are registered by the [`route_handler`][Router::route_handler] method. The router
+/// is then combined with a [`Routing`] configuration to form a [`MakeService`].
+///
+/// Cloning is cheap-ish (it clones a map of reference counted handlers).
+#[derive(Clone, Default)]
+pub struct Router {
+    handlers: HashMap<String, Handler>,
+}
+
+impl Debug for Router {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Router")
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Router {
+    /// Creates a router with no handlers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler under the given name.
+    ///
+    /// The service is shared between all the connections (and all the servers the router is used
+    /// with). If a handler of the same name already exists, it is replaced.
+    pub fn route_handler<N, S>(mut self, name: N, service: S) -> Self
+    where
+        N: Into<String>,
+        S: Service<ReqBody = Body, ResBody = Body> + Send + 'static,
+        S::Future: Send + 'static,
+        S::Error: Into<AnyError>,
+    {
+        let service = Mutex::new(service);
+        let handler: Handler = Arc::new(move |req| {
+            let fut = service
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .call(req)
+                .map_err(Into::into);
+            Box::new(fut) as ResponseFuture
+        });
+        self.handlers.insert(name.into(), handler);
+        self
+    }
+
+    /// Checks that all the handlers referenced by the configuration are registered.
+    ///
+    /// This is suitable for use in a validation of the configuration (eg. the
+    /// [`check`][spirit::Pipeline::check] of a pipeline), so unknown names are refused before the
+    /// configuration is accepted.
+    pub fn check(&self, routing: &Routing) -> Result<(), AnyError> {
+        for name in routing.handler_names() {
+            if !self.handlers.contains_key(name) {
+                return Err(format!("Unknown route handler {}", name).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Creates a [`MakeService`] routing according to the configuration.
+    ///
+    /// Routes referencing unknown handlers are skipped (with a warning). Use
+    /// [`check`][Router::check] to catch them sooner.
+    pub fn make_service(&self, routing: &Routing) -> RoutedMake {
+        let resolve = |name: &str| {
+            let handler = self.handlers.get(name).cloned();
+            if handler.is_none() {
+                warn!("Route handler {} is not registered", name);
+            }
+            handler
+        };
+        let routes = routing
+            .routes
+            .iter()
+            .filter_map(|route| resolve(&route.handler).map(|h| (route.clone(), h)))
+            .collect();
+        let fallback = match &routing.fallback {
+            Fallback::NotFound => Resolved::NotFound,
+            Fallback::Redirect {
+                location,
+                permanent,
+            } => Resolved::Redirect(location.clone(), *permanent),
+            Fallback::Handler { name } => resolve(name)
+                .map(Resolved::Handler)
+                .unwrap_or(Resolved::NotFound),
+        };
+        debug!("Created router with {} routes", routing.routes.len());
+        RoutedMake(RoutedService(Arc::new(Routes { routes, fallback })))
+    }
+}
+
+enum Resolved {
+    NotFound,
+    Redirect(String, bool),
+    Handler(Handler),
+}
+
+struct Routes {
+    routes: Vec<(Route, Handler)>,
+    fallback: Resolved,
+}
+
+impl Routes {
+    fn route(&self, mut req: Request<Body>) -> ResponseFuture {
+        let host = req
+            .headers()
+            .get(HOST)
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri().host())
+            .map(|h| h.split(':').next().unwrap_or(h).to_owned());
+        let path = req.uri().path().to_owned();
+        let found = self.routes.iter().find(|(route, _)| {
+            route.matches_host(host.as_ref().map(|h| h as &str)) && route.matches_path(&path)
+        });
+        if let Some((route, handler)) = found {
+            trace!("Routing {} to {}", path, route.handler);
+            if route.strip_prefix {
+                let stripped = route.strip(&path);
+                let new_uri = match req.uri().query() {
+                    Some(query) => format!("{}?{}", stripped, query),
+                    None => stripped,
+                };
+                match new_uri.parse::<Uri>() {
+                    Ok(uri) => *req.uri_mut() = uri,
+                    Err(e) => return Box::new(future::err(e.into())),
+                }
+            }
+            return handler(req);
+        }
+        let response = match &self.fallback {
+            Resolved::Handler(handler) => return handler(req),
+            Resolved::NotFound => {
+                let mut response = Response::new(Body::from("Not Found\n"));
+                *response.status_mut() = StatusCode::NOT_FOUND;
+                response
+            }
+            Resolved::Redirect(location, permanent) => {
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = if *permanent {
+                    StatusCode::MOVED_PERMANENTLY
+                } else {
+                    StatusCode::TEMPORARY_REDIRECT
+                };
+                match HeaderValue::from_str(location) {
+                    Ok(location) => {
+                        response.headers_mut().insert(LOCATION, location);
+                    }
+                    Err(e) => return Box::new(future::err(e.into())),
+                }
+                response
+            }
+        };
+        Box::new(future::ok(response))
+    }
+}
+
+/// The [`Service`] doing the routing.
+///
+/// Created through [`Router::make_service`].
+#[derive(Clone)]
+pub struct RoutedService(Arc<Routes>);
+
+impl Service for RoutedService {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = AnyError;
+    type Future = ResponseFuture;
+    fn call(&mut self, req: Request<Body>) -> ResponseFuture {
+        self.0.route(req)
+    }
+}
+
+/// A [`MakeService`] producing the [`RoutedService`].
+///
+/// Created through [`Router::make_service`].
+#[derive(Clone)]
+pub struct RoutedMake(RoutedService);
+
+impl<Ctx> MakeService<Ctx> for RoutedMake {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = AnyError;
+    type Service = RoutedService;
+    type Future = FutureResult<RoutedService, AnyError>;
+    type MakeError = AnyError;
+    fn make_service(&mut self, _: Ctx) -> Self::Future {
+        future::ok(self.0.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route(host: &str, prefix: &str) -> Route {
+        Route {
+            host: host.to_owned(),
+            prefix: prefix.to_owned(),
+            handler: "h".to_owned(),
+            strip_prefix: true,
+        }
+    }
+
+    #[test]
+    fn host_patterns() {
+        assert!(route("*", "/").matches_host(None));
+        assert!(route("example.com", "/").matches_host(Some("Example.com")));
+        assert!(!route("example.com", "/").matches_host(None));
+        assert!(route("*.example.com", "/").matches_host(Some("www.example.com")));
+        assert!(!route("*.example.com", "/").matches_host(Some("example.com")));
+        assert!(!route("*.example.com", "/").matches_host(Some("badexample.com")));
+    }
+
+    #[test]
+    fn path_prefixes() {
+        let api = route("*", "/api/");
+        assert!(api.matches_path("/api"));
+        assert!(api.matches_path("/api/users"));
+        assert!(!api.matches_path("/apis"));
+        assert_eq!("/users", api.strip("/api/users"));
+        assert_eq!("/", api.strip("/api"));
+        assert!(route("*", "/").matches_path("/anything"));
+    }
+
+    #[test]
+    fn check_names() {
+        let router = Router::new().route_handler(
+            "h",
+            hyper::service::service_fn_ok(|_| Response::new(Body::empty())),
+        );
+        let mut routing = Routing {
+            routes: vec![route("*", "/")],
+            fallback: Fallback::NotFound,
+        };
+        router.check(&routing).unwrap();
+        routing.fallback = Fallback::Handler {
+            name: "missing".to_owned(),
+        };
+        assert!(router.check(&routing).is_err());
+    }
+}
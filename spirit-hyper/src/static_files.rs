@@ -0,0 +1,376 @@
+//! Serving static files from a directory.
+//!
+//! Sometimes a server needs to provide some static content ‒ a dashboard, assets for a web UI,
+//! etc. This module allows doing so with a directory configured per server, without pulling in a
+//! whole web framework.
+//!
+//! The [`StaticFiles`] is the configuration of the directory. It can be either turned into a
+//! [`Service`] manually (for composing with other services) or the [`static_files`] function can
+//! be used as a whole [`BuildServer`][crate::BuildServer] closure.
+//!
+//! This is available under the `static-files` feature flag.
+//!
+//! # Configuration options
+//!
+//! * `root`: The directory to serve (mandatory).
+//! * `index`: Names of files to serve when a directory is requested. Defaults to
+//!   `["index.html"]`.
+//! * `cache-control`: Value of the `Cache-Control` header sent with the files. Not sent if not
+//!   set.
+//! * `etag`: Send the `ETag` header and handle the `If-None-Match` header of requests. Defaults
+//!   to `true`.
+//! * `listing`: Produce a listing of directories without index. Defaults to `false` (such
+//!   directories produce 404).
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::static_files::{static_files, StaticFiles};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! root = "/var/www"
+//! listing = true
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer<StaticFiles>,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer<StaticFiles> {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("assets")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(static_files(|cfg: &HttpServer<StaticFiles>| {
+//!                     cfg.transport.listener.extra_cfg.clone()
+//!                 })))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use futures::future::{self, FutureResult, PollFn};
+use futures::Poll;
+use hyper::header::{
+    HeaderValue, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, LOCATION,
+};
+use hyper::server::{Builder, Server};
+use hyper::service::{MakeService, Service};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::{debug, trace};
+use percent_encoding::percent_decode;
+use serde::{Deserialize, Serialize};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_index() -> Vec<String> {
+    vec!["index.html".to_owned()]
+}
+
+fn default_on() -> bool {
+    true
+}
+
+/// Configuration of a directory with static files.
+///
+/// See the [module documentation](index.html) for the options and examples.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct StaticFiles {
+    /// The directory to serve the files from.
+    pub root: PathBuf,
+
+    /// The files to look for when a directory is requested.
+    ///
+    /// Defaults to `index.html`.
+    #[serde(default = "default_index")]
+    pub index: Vec<String>,
+
+    /// The value of `Cache-Control` header.
+    ///
+    /// If not set, no such header is sent.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+
+    /// Send ETags with the files and handle conditional requests with them.
+    ///
+    /// Default is on.
+    #[serde(default = "default_on")]
+    pub etag: bool,
+
+    /// Produce listing of directories without index file.
+    ///
+    /// Default is off.
+    #[serde(default)]
+    pub listing: bool,
+}
+
+impl StaticFiles {
+    /// Creates a [`Service`] serving the configured directory.
+    pub fn service(&self) -> StaticFilesService {
+        StaticFilesService(Arc::new(self.clone()))
+    }
+
+    /// Turns the request path into a path on the file system.
+    ///
+    /// Returns `None` if the path tries to escape the root directory or is otherwise weird.
+    fn resolve(&self, uri_path: &str) -> Option<PathBuf> {
+        let decoded = percent_decode(uri_path.as_bytes()).decode_utf8().ok()?;
+        let mut path = self.root.clone();
+        for segment in decoded.split('/') {
+            match segment {
+                "" | "." => (),
+                ".." => return None,
+                s if s.contains('\\') || s.contains('\0') => return None,
+                s => path.push(s),
+            }
+        }
+        Some(path)
+    }
+
+    fn etag(meta: &fs::Metadata) -> String {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        format!("W/\"{:x}-{:x}\"", meta.len(), mtime)
+    }
+
+    fn file(&self, req: &Request<Body>, path: &Path, meta: &fs::Metadata) -> Response<Body> {
+        let mut response = Response::builder();
+        let mime = mime_guess::from_path(path).first_or_octet_stream();
+        response
+            .header(CONTENT_TYPE, mime.as_ref())
+            .header(CONTENT_LENGTH, meta.len());
+        if let Some(cache) = &self.cache_control {
+            response.header(CACHE_CONTROL, cache.as_str());
+        }
+        if self.etag {
+            let etag = Self::etag(meta);
+            let matches = req
+                .headers()
+                .get(IF_NONE_MATCH)
+                .and_then(|h| h.to_str().ok())
+                .map(|h| h.split(',').any(|t| t.trim() == etag || t.trim() == "*"))
+                .unwrap_or(false);
+            response.header(ETAG, etag.as_str());
+            if matches {
+                return response
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .unwrap();
+            }
+        }
+        if req.method() == Method::HEAD {
+            return response.body(Body::empty()).unwrap();
+        }
+        match File::open(path).and_then(|mut f| {
+            let mut content = Vec::with_capacity(meta.len() as usize);
+            f.read_to_end(&mut content).map(|_| content)
+        }) {
+            Ok(content) => response.body(Body::from(content)).unwrap(),
+            Err(e) => error_response(&e),
+        }
+    }
+
+    fn listing(&self, req: &Request<Body>, dir: &Path) -> Response<Body> {
+        let mut entries = match fs::read_dir(dir).and_then(|d| d.collect::<Result<Vec<_>, _>>()) {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|e| {
+                    let mut name = e.file_name().to_string_lossy().into_owned();
+                    if e.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                        name.push('/');
+                    }
+                    name
+                })
+                .collect::<Vec<_>>(),
+            Err(e) => return error_response(&e),
+        };
+        entries.sort();
+        let title = escape(req.uri().path());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><title>{0}</title></head><body><h1>{0}</h1><ul>\n",
+            title
+        );
+        for entry in entries {
+            let entry = escape(&entry);
+            let _ = writeln!(html, "<li><a href=\"{0}\">{0}</a></li>", entry);
+        }
+        html.push_str("</ul></body></html>\n");
+        Response::builder()
+            .header(CONTENT_TYPE, "text/html; charset=utf-8")
+            .body(Body::from(html))
+            .unwrap()
+    }
+
+    fn handle(&self, req: &Request<Body>) -> Response<Body> {
+        if req.method() != Method::GET && req.method() != Method::HEAD {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let path = match self.resolve(req.uri().path()) {
+            Some(path) => path,
+            None => return status(StatusCode::BAD_REQUEST),
+        };
+        trace!("Serving static {:?} for {}", path, req.uri());
+        let meta = match fs::metadata(&path) {
+            Ok(meta) => meta,
+            Err(e) => return error_response(&e),
+        };
+        if !meta.is_dir() {
+            return self.file(req, &path, &meta);
+        }
+        if !req.uri().path().ends_with('/') {
+            let location = format!("{}/", req.uri().path());
+            return Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(LOCATION, location.as_str())
+                .body(Body::empty())
+                .unwrap();
+        }
+        for index in &self.index {
+            let index = path.join(index);
+            if let Ok(meta) = fs::metadata(&index) {
+                if meta.is_file() {
+                    return self.file(req, &index, &meta);
+                }
+            }
+        }
+        if self.listing {
+            self.listing(req, &path)
+        } else {
+            status(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::from(
+        status.canonical_reason().unwrap_or_default().to_owned() + "\n",
+    ));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+fn error_response(e: &IoError) -> Response<Body> {
+    match e.kind() {
+        ErrorKind::NotFound => status(StatusCode::NOT_FOUND),
+        ErrorKind::PermissionDenied => status(StatusCode::FORBIDDEN),
+        _ => {
+            debug!("Failed to serve static file: {}", e);
+            status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+/// The [`Service`] serving the static files.
+///
+/// Created by [`StaticFiles::service`]. The file system access is done through the blocking
+/// section of the tokio threadpool, so it doesn't block the other tasks.
+#[derive(Clone, Debug)]
+pub struct StaticFilesService(Arc<StaticFiles>);
+
+/// The future produced by the [`StaticFilesService`].
+pub type StaticFilesFuture = PollFn<Box<dyn FnMut() -> Poll<Response<Body>, AnyError> + Send>>;
+
+impl Service for StaticFilesService {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = AnyError;
+    type Future = StaticFilesFuture;
+    fn call(&mut self, req: Request<Body>) -> StaticFilesFuture {
+        let cfg = Arc::clone(&self.0);
+        future::poll_fn(Box::new(move || {
+            tokio_threadpool::blocking(|| cfg.handle(&req)).map_err(AnyError::from)
+        }))
+    }
+}
+
+/// A [`MakeService`] creating [`StaticFilesService`]s for each connection.
+#[derive(Clone, Debug)]
+pub struct StaticFilesMake(Arc<StaticFiles>);
+
+impl<Ctx> MakeService<Ctx> for StaticFilesMake {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = AnyError;
+    type Service = StaticFilesService;
+    type Future = FutureResult<StaticFilesService, IoError>;
+    type MakeError = IoError;
+    fn make_service(&mut self, _: Ctx) -> Self::Future {
+        future::ok(StaticFilesService(Arc::clone(&self.0)))
+    }
+}
+
+/// Creates a closure for [`BuildServer`][crate::BuildServer] that serves static files.
+///
+/// The `extract` closure picks the [`StaticFiles`] configuration out of the server
+/// configuration, usually out of the `extra_cfg`. See the [module example](index.html#examples).
+pub fn static_files<Incoming, Cfg, F>(
+    extract: F,
+) -> impl Fn(Builder<Incoming>, &Cfg, &'static str) -> Server<Incoming, StaticFilesMake>
+where
+    F: Fn(&Cfg) -> StaticFiles,
+{
+    move |builder, cfg, name| {
+        let files = extract(cfg);
+        debug!("Serving static files from {:?} on {}", files.root, name);
+        builder.serve(StaticFilesMake(Arc::new(files)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_paths() {
+        let files = StaticFiles {
+            root: PathBuf::from("/srv"),
+            ..StaticFiles::default()
+        };
+        assert_eq!(Some(PathBuf::from("/srv/a/b")), files.resolve("/a//./b"));
+        assert_eq!(Some(PathBuf::from("/srv/a b")), files.resolve("/a%20b"));
+        assert_eq!(None, files.resolve("/a/../../etc/passwd"));
+        assert_eq!(None, files.resolve("/%2e%2e/etc"));
+    }
+}
@@ -0,0 +1,582 @@
+//! Propagation of distributed tracing context.
+//!
+//! Requests passing through several services can be tied together by a trace ID carried in their
+//! headers. The `trace-propagation` option of the [`HyperServer`] lists the header formats the
+//! server understands ([`TraceFormat`]) ‒ the W3C `traceparent` and the Zipkin B3 ones. The
+//! [`Traced`] wrapper then takes the trace context from each request (or starts a new trace if
+//! there's none) and makes it available to the handler as a [`TraceContext`]:
+//!
+//! * In the [extensions][hyper::Request::extensions] of the request.
+//! * Through the [`current`] function, while the handler is running (useful for log formatting).
+//!
+//! The context is for the handling of the request (a child of the one in the headers). When the
+//! handler calls other services, it can [`inject`][TraceContext::inject] a child of it into the
+//! outgoing request.
+//!
+//! Each finished request is logged on the debug level (under the `spirit_hyper::access` target)
+//! together with its trace ID (failed ones on the warning level). With the `tracing` feature, a
+//! [tracing] span is created for each request too, carrying the trace ID.
+//!
+//! If no formats are configured, the wrapper does nothing. The [`Serve`][crate::Serve]
+//! transformation applies it without being asked to.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use hyper::{Body, Request, Response};
+//! use hyper::server::Builder;
+//! use hyper::service::service_fn_ok;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::{BuildServer, HttpServer};
+//! use spirit_hyper::trace::{TraceContext, Traced};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! trace-propagation = ["w3c", "b3"]
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! impl Config {
+//!     fn server(&self) -> HttpServer {
+//!         self.server.clone()
+//!     }
+//! }
+//!
+//! fn request(req: Request<Body>) -> Response<Body> {
+//!     let trace = req.extensions().get::<TraceContext>().map(TraceContext::trace_id);
+//!     Response::new(Body::from(format!("Hello from {:?}\n", trace)))
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::server)
+//!                 .transform(BuildServer(|builder: Builder<_>, cfg: &HttpServer, _: &str| {
+//!                     let formats = cfg.trace_propagation();
+//!                     builder.serve(Traced::new(formats, || service_fn_ok(request)))
+//!                 }))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`HyperServer`]: crate::HyperServer
+//! [tracing]: https://crates.io/crates/tracing
+
+use std::cell::Cell;
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+use futures::{try_ready, Async, Future, Poll};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::service::{MakeService, Service};
+use hyper::{Request, Response};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+use crate::access::{self, Entry};
+
+const TRACEPARENT: &str = "traceparent";
+const B3: &str = "b3";
+const B3_TRACE_ID: &str = "x-b3-traceid";
+const B3_SPAN_ID: &str = "x-b3-spanid";
+const B3_PARENT_SPAN_ID: &str = "x-b3-parentspanid";
+const B3_SAMPLED: &str = "x-b3-sampled";
+const B3_FLAGS: &str = "x-b3-flags";
+
+/// A format of headers carrying the trace context.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum TraceFormat {
+    /// The W3C `traceparent` header.
+    W3c,
+
+    /// The Zipkin B3 headers (either the single `b3` one or the `X-B3-*` ones).
+    B3,
+}
+
+/// The context of one span of a distributed trace.
+///
+/// See the [module documentation][crate::trace].
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct TraceContext {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    sampled: bool,
+}
+
+pub(crate) fn random() -> u64 {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    // The std hasher is keyed randomly, which is enough for IDs (they need to be unique, not
+    // unpredictable).
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_usize(COUNTER.fetch_add(1, Ordering::Relaxed));
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    match hasher.finish() {
+        // Zero IDs are invalid
+        0 => 1,
+        id => id,
+    }
+}
+
+fn hex_id(value: &str, len: usize) -> Option<u128> {
+    if value.len() != len || !value.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    match u128::from_str_radix(value, 16) {
+        Ok(0) | Err(_) => None,
+        Ok(id) => Some(id),
+    }
+}
+
+fn b3_trace_id(value: &str) -> Option<u128> {
+    hex_id(value, 32).or_else(|| hex_id(value, 16))
+}
+
+fn span_id(value: &str) -> Option<u64> {
+    hex_id(value, 16).map(|id| id as u64)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+impl TraceContext {
+    /// Starts a new trace.
+    pub fn root() -> Self {
+        TraceContext {
+            trace_id: u128::from(random()) << 64 | u128::from(random()),
+            span_id: random(),
+            parent_id: None,
+            sampled: true,
+        }
+    }
+
+    /// Creates a context of a span inside this one.
+    pub fn child(&self) -> Self {
+        TraceContext {
+            span_id: random(),
+            parent_id: Some(self.span_id),
+            ..*self
+        }
+    }
+
+    /// The ID of the whole trace (32 hex digits).
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    /// The ID of this span (16 hex digits).
+    pub fn span_id(&self) -> String {
+        format!("{:016x}", self.span_id)
+    }
+
+    /// The ID of the parent span, if any.
+    pub fn parent_id(&self) -> Option<String> {
+        self.parent_id.map(|id| format!("{:016x}", id))
+    }
+
+    /// Should the trace be recorded?
+    ///
+    /// This is the decision of whoever started the trace, new traces are sampled.
+    pub fn sampled(&self) -> bool {
+        self.sampled
+    }
+
+    fn from_traceparent(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let version = parts.next()?;
+        let trace_id = hex_id(parts.next()?, 32)?;
+        let span_id = span_id(parts.next()?)?;
+        let flags = parts.next()?;
+        // Future versions may add more fields, but must keep these
+        if version.len() != 2 || flags.len() != 2 {
+            return None;
+        }
+        let version = u8::from_str_radix(version, 16).ok()?;
+        if version == 0xff || (version == 0 && parts.next().is_some()) {
+            return None;
+        }
+        let flags = u8::from_str_radix(flags, 16).ok()?;
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            parent_id: None,
+            sampled: flags & 1 == 1,
+        })
+    }
+
+    fn from_b3(value: &str) -> Option<Self> {
+        let mut parts = value.trim().split('-');
+        let trace_id = b3_trace_id(parts.next()?)?;
+        let span_id = span_id(parts.next()?)?;
+        let sampled = parts.next().map(|s| s == "1" || s == "d").unwrap_or(true);
+        let parent_id = match parts.next() {
+            Some(parent) => Some(self::span_id(parent)?),
+            None => None,
+        };
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            parent_id,
+            sampled,
+        })
+    }
+
+    fn from_b3_multi(headers: &HeaderMap) -> Option<Self> {
+        let trace_id = b3_trace_id(header(headers, B3_TRACE_ID)?)?;
+        let span_id = span_id(header(headers, B3_SPAN_ID)?)?;
+        let parent_id = header(headers, B3_PARENT_SPAN_ID).and_then(self::span_id);
+        let debug = header(headers, B3_FLAGS) == Some("1");
+        let sampled = header(headers, B3_SAMPLED)
+            .map(|s| s == "1" || s == "true")
+            .unwrap_or(true);
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            parent_id,
+            sampled: sampled || debug,
+        })
+    }
+
+    /// Takes the context out of headers, in the first of the formats that is present.
+    pub fn extract(headers: &HeaderMap, formats: &[TraceFormat]) -> Option<Self> {
+        formats.iter().find_map(|format| match format {
+            TraceFormat::W3c => header(headers, TRACEPARENT).and_then(Self::from_traceparent),
+            TraceFormat::B3 => header(headers, B3)
+                .and_then(Self::from_b3)
+                .or_else(|| Self::from_b3_multi(headers)),
+        })
+    }
+
+    /// Puts the context into headers, in all the given formats.
+    ///
+    /// This is meant for outgoing requests. Usually, a [`child`][TraceContext::child] of the
+    /// context of the handled request is injected.
+    pub fn inject(&self, headers: &mut HeaderMap, formats: &[TraceFormat]) {
+        let mut set = |name: &'static str, value: String| {
+            let value = HeaderValue::from_str(&value).expect("Hex digits are valid header");
+            headers.insert(HeaderName::from_static(name), value);
+        };
+        let sampled = if self.sampled { "1" } else { "0" };
+        for format in formats {
+            match format {
+                TraceFormat::W3c => set(TRACEPARENT, self.to_string()),
+                TraceFormat::B3 => {
+                    set(B3_TRACE_ID, self.trace_id());
+                    set(B3_SPAN_ID, self.span_id());
+                    if let Some(parent) = self.parent_id() {
+                        set(B3_PARENT_SPAN_ID, parent);
+                    }
+                    set(B3_SAMPLED, sampled.to_owned());
+                }
+            }
+        }
+    }
+}
+
+/// Formats the context as the value of the W3C `traceparent` header.
+impl Display for TraceContext {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(
+            fmt,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+}
+
+thread_local! {
+    static CURRENT: Cell<Option<TraceContext>> = Cell::new(None);
+}
+
+/// The trace context of the request being handled by the current thread.
+///
+/// This is set while the handler wrapped in [`Traced`] runs.
+pub fn current() -> Option<TraceContext> {
+    CURRENT.with(Cell::get)
+}
+
+/// Restores the previous current context when dropped.
+struct CurrentGuard(Option<TraceContext>);
+
+impl CurrentGuard {
+    fn enter(ctx: TraceContext) -> Self {
+        CurrentGuard(CURRENT.with(|current| current.replace(Some(ctx))))
+    }
+}
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+/// A wrapper around a hyper [`MakeService`] that propagates the trace context.
+///
+/// See the [module documentation][crate::trace].
+pub struct Traced<MS> {
+    formats: Vec<TraceFormat>,
+    inner: MS,
+}
+
+impl<MS> Traced<MS> {
+    /// Wraps the [`MakeService`], understanding the given header formats.
+    pub fn new(formats: &[TraceFormat], inner: MS) -> Self {
+        Traced {
+            formats: formats.to_vec(),
+            inner,
+        }
+    }
+}
+
+impl<'a, Ctx, MS> MakeService<&'a Ctx> for Traced<MS>
+where
+    MS: MakeService<&'a Ctx>,
+{
+    type ReqBody = MS::ReqBody;
+    type ResBody = MS::ResBody;
+    type Error = MS::Error;
+    type Service = TracedService<MS::Service>;
+    type Future = TracedMake<MS::Future>;
+    type MakeError = MS::MakeError;
+    fn poll_ready(&mut self) -> Poll<(), Self::MakeError> {
+        self.inner.poll_ready()
+    }
+    fn make_service(&mut self, ctx: &'a Ctx) -> Self::Future {
+        TracedMake {
+            formats: self.formats.clone(),
+            inner: self.inner.make_service(ctx),
+        }
+    }
+}
+
+/// A plumbing future creating [`TracedService`]s.
+///
+/// The user should not need to interact with this directly.
+pub struct TracedMake<F> {
+    formats: Vec<TraceFormat>,
+    inner: F,
+}
+
+impl<F: Future> Future for TracedMake<F> {
+    type Item = TracedService<F::Item>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let inner = try_ready!(self.inner.poll());
+        Ok(Async::Ready(TracedService {
+            formats: std::mem::replace(&mut self.formats, Vec::new()),
+            inner,
+        }))
+    }
+}
+
+/// A wrapper of one per-connection [`Service`] that propagates the trace context.
+///
+/// The user should not need to interact with this directly.
+pub struct TracedService<S> {
+    formats: Vec<TraceFormat>,
+    inner: S,
+}
+
+impl<S: Service> Service for TracedService<S> {
+    type ReqBody = S::ReqBody;
+    type ResBody = S::ResBody;
+    type Error = S::Error;
+    type Future = TracedResponse<S::Future>;
+    fn poll_ready(&mut self) -> Poll<(), Self::Error> {
+        self.inner.poll_ready()
+    }
+    fn call(&mut self, mut req: Request<Self::ReqBody>) -> Self::Future {
+        if self.formats.is_empty() {
+            return TracedResponse {
+                trace: None,
+                inner: self.inner.call(req),
+            };
+        }
+        let ctx = TraceContext::extract(req.headers(), &self.formats)
+            .map(|parent| parent.child())
+            .unwrap_or_else(TraceContext::root);
+        req.extensions_mut().insert(ctx);
+        let access = access::start(&mut req);
+        access::record(&req, |fields| fields.trace_id = Some(ctx.trace_id()));
+        #[cfg(feature = "tracing")]
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            trace_id = %ctx.trace_id()
+        );
+        let inner = {
+            #[cfg(feature = "tracing")]
+            let _entered = span.enter();
+            let _current = CurrentGuard::enter(ctx);
+            self.inner.call(req)
+        };
+        TracedResponse {
+            trace: Some(Trace {
+                ctx,
+                access,
+                #[cfg(feature = "tracing")]
+                span,
+            }),
+            inner,
+        }
+    }
+}
+
+struct Trace {
+    ctx: TraceContext,
+    access: Option<Entry>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+/// A plumbing future running the handler inside the trace context.
+///
+/// The user should not need to interact with this directly.
+pub struct TracedResponse<F> {
+    trace: Option<Trace>,
+    inner: F,
+}
+
+impl<F, B> Future for TracedResponse<F>
+where
+    F: Future<Item = Response<B>>,
+{
+    type Item = Response<B>;
+    type Error = F::Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let trace = match self.trace.as_ref() {
+            Some(trace) => trace,
+            None => return self.inner.poll(),
+        };
+        #[cfg(feature = "tracing")]
+        let _entered = trace.span.enter();
+        let _current = CurrentGuard::enter(trace.ctx);
+        let result = self.inner.poll();
+        if let Some(access) = &trace.access {
+            match &result {
+                Ok(Async::Ready(response)) => access.finished(response.status().as_u16()),
+                Err(_) => access.failed(),
+                Ok(Async::NotReady) => (),
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const SPAN: &str = "00f067aa0ba902b7";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn w3c() {
+        let parent = format!("00-{}-{}-01", TRACE, SPAN);
+        let hdrs = headers(&[(TRACEPARENT, &parent)]);
+        let ctx = TraceContext::extract(&hdrs, &[TraceFormat::W3c]).unwrap();
+        assert_eq!(TRACE, ctx.trace_id());
+        assert_eq!(SPAN, ctx.span_id());
+        assert!(ctx.sampled());
+        assert_eq!(parent, ctx.to_string());
+        assert!(TraceContext::extract(&hdrs, &[TraceFormat::B3]).is_none());
+
+        let child = ctx.child();
+        assert_eq!(TRACE, child.trace_id());
+        assert_eq!(Some(SPAN.to_owned()), child.parent_id());
+        assert_ne!(SPAN, child.span_id());
+
+        for invalid in &[
+            format!("00-{}-{}-01-extra", TRACE, SPAN),
+            format!("ff-{}-{}-01", TRACE, SPAN),
+            format!("00-{}-{}-01", "0".repeat(32), SPAN),
+            format!("00-{}-{}-01", TRACE, "0".repeat(16)),
+            format!("00-{}-{}", TRACE, SPAN),
+            format!("00-{}-{}-01", &TRACE[1..], SPAN),
+        ] {
+            assert!(
+                TraceContext::from_traceparent(invalid).is_none(),
+                "{}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn b3() {
+        let single = format!("{}-{}-0-{}", TRACE, SPAN, "0000000000000001");
+        let hdrs = headers(&[(B3, &single)]);
+        let ctx = TraceContext::extract(&hdrs, &[TraceFormat::W3c, TraceFormat::B3]).unwrap();
+        assert_eq!(TRACE, ctx.trace_id());
+        assert_eq!(Some("0000000000000001".to_owned()), ctx.parent_id());
+        assert!(!ctx.sampled());
+
+        let hdrs = headers(&[
+            (B3_TRACE_ID, "a3ce929d0e0e4736"),
+            (B3_SPAN_ID, SPAN),
+            (B3_SAMPLED, "1"),
+        ]);
+        let ctx = TraceContext::extract(&hdrs, &[TraceFormat::B3]).unwrap();
+        assert_eq!("0000000000000000a3ce929d0e0e4736", ctx.trace_id());
+        assert!(ctx.sampled());
+        assert!(TraceContext::extract(&headers(&[(B3, "0")]), &[TraceFormat::B3]).is_none());
+    }
+
+    #[test]
+    fn inject() {
+        let ctx = TraceContext::root().child();
+        let mut hdrs = HeaderMap::new();
+        ctx.inject(&mut hdrs, &[TraceFormat::W3c, TraceFormat::B3]);
+        assert_eq!(
+            Some(ctx),
+            TraceContext::extract(&hdrs, &[TraceFormat::W3c]).map(|c| TraceContext {
+                parent_id: ctx.parent_id,
+                ..c
+            })
+        );
+        assert_eq!(Some(ctx), TraceContext::extract(&hdrs, &[TraceFormat::B3]));
+    }
+
+    #[test]
+    fn current_context() {
+        assert!(current().is_none());
+        let ctx = TraceContext::root();
+        {
+            let _guard = CurrentGuard::enter(ctx);
+            assert_eq!(Some(ctx), current());
+        }
+        assert!(current().is_none());
+    }
+}
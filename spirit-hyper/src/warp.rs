@@ -0,0 +1,116 @@
+//! Serving [warp] filters.
+//!
+//! The [`WarpServer`] transformation is an alternative to [`BuildServer`][crate::BuildServer]
+//! that takes a warp [`Filter`] directly, instead of a closure adapting it to the hyper
+//! [`Builder`] by hand.
+//!
+//! Only warp is supported here. Axum builds on a newer hyper and tokio than this crate does, so
+//! it can't run on top of these servers.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_hyper::HttpServer;
+//! use spirit_hyper::warp::WarpServer;
+//! use warp::Filter;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [server]
+//! port = 1234
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     server: HttpServer,
+//! }
+//!
+//! fn main() {
+//!     let hello = warp::path("hello").map(|| "Hello world\n");
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(|cfg: &Config| cfg.server.clone())
+//!                 .transform(WarpServer(hello))
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [warp]: https://crates.io/crates/warp
+
+use std::io::Error as IoError;
+
+use ::warp::{Filter, Rejection, Reply};
+use futures::sync::oneshot;
+use futures::{Future, Stream};
+use hyper::server::Builder;
+use spirit::fragment::{Fragment, Transformation};
+use spirit::AnyError;
+use spirit_tokio::installer::FutureInstaller;
+use spirit_tokio::net::{IntoIncoming, PeerAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{layers, Activate, ActivateInner, HyperServer};
+
+/// The server future created by [`WarpServer`].
+///
+/// The user should not need to interact with this directly.
+pub type WarpActivate = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// A [`Transformation`] serving a warp [`Filter`] on the configured server.
+///
+/// The filter is cloned for each (re)started server. Graceful shutdown is done by the same
+/// plumbing as with [`BuildServer`][crate::BuildServer].
+///
+/// The service is wrapped in the wrappers configured in the [`HyperServer`], the same way as with
+/// [`Serve`][crate::Serve]. The only exception are the metrics, as there's no monitor to report
+/// them into ‒ use [`Serve`][crate::Serve] with [`warp::service`][::warp::service] for them.
+#[derive(Clone, Debug)]
+pub struct WarpServer<F>(pub F);
+
+impl<Transport, Inst, F, Incoming> Transformation<Builder<Incoming>, Inst, HyperServer<Transport>>
+    for WarpServer<F>
+where
+    Transport: Fragment + 'static,
+    Transport::Resource: IntoIncoming<Incoming = Incoming, Connection = Incoming::Item>,
+    Incoming: Stream<Error = IoError> + Send + Sync + 'static,
+    Incoming::Item: AsyncRead + AsyncWrite + PeerAddr + Send + Sync + 'static,
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    type OutputResource = WarpActivate;
+    type OutputInstaller = FutureInstaller<WarpActivate>;
+    fn installer(&mut self, _ii: Inst, _name: &'static str) -> Self::OutputInstaller {
+        FutureInstaller::default()
+    }
+    fn transform(
+        &mut self,
+        builder: Builder<Incoming>,
+        cfg: &HyperServer<Transport>,
+        name: &'static str,
+    ) -> Result<WarpActivate, AnyError> {
+        let service = ::warp::service(self.0.clone());
+        let service = layers::wrap(
+            cfg,
+            #[cfg(feature = "metrics")]
+            None,
+            name,
+            move || Ok::<_, IoError>(service.clone()),
+        );
+        let server = builder.serve(service);
+        let (sender, receiver) = oneshot::channel();
+        Ok(Box::new(Activate {
+            inner: Some(ActivateInner { server, receiver }),
+            sender: Some(sender),
+            name,
+        }))
+    }
+}
@@ -0,0 +1,828 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-jobs/0.1.0/spirit_jobs/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Background jobs processed by configured worker pools.
+//!
+//! The application registers handlers of jobs by their kind (a name) in the [`Jobs`] handle and
+//! submits jobs to named pools. The pools are described by the [`JobPool`] configuration
+//! fragment ‒ how many workers process the jobs, where the queue lives, how failed jobs are
+//! retried and if the ones failing for good are kept in a dead-letter queue.
+//!
+//! The [`Jobs`] handle is also the [`Installer`] of the pools. When the configuration of a pool
+//! changes, its workers are replaced, but the queue is kept (if it is still in memory, it is
+//! handed over to the new workers; if the backend changed, the waiting jobs are moved to the new
+//! queue). A pool removed from the configuration stops processing, but its queue stays and it'll
+//! get processed when the pool is configured again.
+//!
+//! When the application terminates, all the pools first process all the jobs waiting in their
+//! queues and only then the workers exit. New jobs are refused from that point on.
+//!
+//! # Backends
+//!
+//! The in-memory queue is the default one. The `redis` feature adds queues stored in redis,
+//! which survive restarts of the application and can be shared between multiple instances of it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_jobs::{Job, JobPool, Jobs};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [[pools]]
+//! name = "email"
+//! concurrency = 2
+//!
+//! [pools.retry]
+//! attempts = 5
+//! backoff-base = "100ms"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     pools: Vec<JobPool>,
+//! }
+//!
+//! fn main() {
+//!     let jobs = Jobs::new();
+//!     jobs.handler("welcome", |job: &Job| {
+//!         println!("Sending welcome email to {}", job.payload);
+//!         Ok(())
+//!     });
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("pools")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.pools.clone())
+//!                 .install(jobs.clone())
+//!         )
+//!         .run(move |_| {
+//!             jobs.submit("email", "welcome", "user@example.com")?;
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use err_context::prelude::*;
+use log::{debug, error, info, trace, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::retry::Backoff;
+use spirit::fragment::{Installer, Stackable};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+/// How long workers wait for a job before checking if they should stop.
+const POLL: Duration = Duration::from_millis(100);
+
+fn default_concurrency() -> usize {
+    4
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_base() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_backoff_cap() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_dead_letter() -> bool {
+    true
+}
+
+/// A single job.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Job {
+    /// The kind of the job, selecting the handler.
+    pub kind: String,
+    /// The data of the job.
+    ///
+    /// It's up to the application what it puts inside, serialized JSON is a common choice.
+    pub payload: String,
+    /// How many times the job was already tried and failed.
+    pub failures: u32,
+}
+
+/// Where the queue of a pool lives.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Backend {
+    /// A queue inside the application's memory.
+    Memory {
+        /// Maximum number of waiting jobs, unlimited if not set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        capacity: Option<usize>,
+    },
+    /// A queue stored in a redis list.
+    #[cfg(feature = "redis")]
+    Redis {
+        /// The URL of the redis server.
+        url: String,
+        /// The key of the list, `spirit-jobs:<name>` by default.
+        ///
+        /// The dead-letter queue is in the `<key>:dead` list.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+    },
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Memory { capacity: None }
+    }
+}
+
+/// Retrying of failed jobs.
+///
+/// # Fields
+///
+/// * `attempts`: How many times a job is tried in total before giving up on it. Defaults to 3.
+/// * `backoff-base`: The delay after the first failure. It doubles with each further failure.
+///   Defaults to `1s`.
+/// * `backoff-cap`: The maximum delay between attempts. Defaults to `1m`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Retry {
+    /// How many times a job is tried in total.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+
+    /// The delay after the first failure.
+    #[serde(
+        default = "default_backoff_base",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_base: Duration,
+
+    /// The maximum delay between attempts.
+    #[serde(
+        default = "default_backoff_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_cap: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: default_attempts(),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+        }
+    }
+}
+
+impl Retry {
+    /// The delays before retrying a failed job.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.backoff_base, self.backoff_cap)
+    }
+}
+
+/// Configuration of a worker pool.
+///
+/// # Fields
+///
+/// * `name`: The name of the pool, used to submit jobs to it.
+/// * `concurrency`: Number of worker threads. Defaults to 4.
+/// * `backend`: Where the queue lives. The `type` is either `memory` (the default, with an
+///   optional `capacity`) or `redis` (with the `url` of the server and optional `key` of the list;
+///   available with the `redis` feature).
+/// * `retry`: How failed jobs are retried (see [`Retry`]).
+/// * `dead-letter`: Keep the jobs that failed all the attempts (or have no handler) in a
+///   dead-letter queue. If turned off, they are only logged. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct JobPool {
+    /// The name of the pool.
+    pub name: String,
+
+    /// Number of worker threads.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+
+    /// Where the queue lives.
+    #[serde(default)]
+    pub backend: Backend,
+
+    /// How failed jobs are retried.
+    #[serde(default)]
+    pub retry: Retry,
+
+    /// Keep the jobs that failed for good in a dead-letter queue.
+    #[serde(default = "default_dead_letter")]
+    pub dead_letter: bool,
+}
+
+impl Default for JobPool {
+    fn default() -> Self {
+        JobPool {
+            name: "default".to_owned(),
+            concurrency: default_concurrency(),
+            backend: Backend::default(),
+            retry: Retry::default(),
+            dead_letter: default_dead_letter(),
+        }
+    }
+}
+
+impl JobPool {
+    /// Checks the configuration and connects to the backend.
+    pub fn spec(&self) -> Result<PoolSpec, AnyError> {
+        if self.name.is_empty() {
+            return Err("Job pool without a name".into());
+        }
+        if self.concurrency == 0 {
+            return Err(format!("Job pool {} has no workers", self.name).into());
+        }
+        if self.retry.attempts == 0 {
+            return Err(format!("Job pool {} has 0 attempts", self.name).into());
+        }
+        let queue: Option<Arc<dyn Queue>> = match &self.backend {
+            // Created on install, so an existing one can be reused
+            Backend::Memory { .. } => None,
+            #[cfg(feature = "redis")]
+            Backend::Redis { url, key } => {
+                let key = key
+                    .clone()
+                    .unwrap_or_else(|| format!("spirit-jobs:{}", self.name));
+                let queue = redis::RedisQueue::new(url, key, self.concurrency)
+                    .with_context(|_| format!("Failed to set up redis queue of {}", self.name))?;
+                Some(Arc::new(queue))
+            }
+        };
+        Ok(PoolSpec {
+            cfg: self.clone(),
+            queue,
+        })
+    }
+}
+
+impl Stackable for JobPool {}
+
+spirit::simple_fragment! {
+    impl Fragment for JobPool {
+        type Driver = CacheEq<JobPool>;
+        type Resource = PoolSpec;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<PoolSpec, AnyError> {
+            self.spec()
+        }
+    }
+}
+
+/// A checked pool configuration, with connected backend.
+///
+/// This is what the [`JobPool`] fragment creates. The user should not need to interact with this
+/// directly.
+#[derive(Clone)]
+pub struct PoolSpec {
+    cfg: JobPool,
+    queue: Option<Arc<dyn Queue>>,
+}
+
+impl Debug for PoolSpec {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("PoolSpec")
+            .field("cfg", &self.cfg)
+            .finish()
+    }
+}
+
+trait Queue: Send + Sync {
+    fn push(&self, job: Job) -> Result<(), AnyError>;
+    fn pop(&self, timeout: Duration) -> Result<Option<Job>, AnyError>;
+    fn dead_letter(&self, job: Job) -> Result<(), AnyError>;
+    fn dead_letters(&self) -> Result<Vec<Job>, AnyError>;
+    /// Is this an in-memory queue of the given capacity?
+    fn memory(&self, _capacity: Option<usize>) -> bool {
+        false
+    }
+}
+
+#[derive(Default)]
+struct MemoryQueue {
+    capacity: Option<usize>,
+    jobs: Mutex<VecDeque<Job>>,
+    ready: Condvar,
+    dead: Mutex<Vec<Job>>,
+}
+
+impl Queue for MemoryQueue {
+    fn push(&self, job: Job) -> Result<(), AnyError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if self.capacity.map(|cap| jobs.len() >= cap).unwrap_or(false) {
+            return Err("Job queue is full".into());
+        }
+        jobs.push_back(job);
+        self.ready.notify_one();
+        Ok(())
+    }
+    fn pop(&self, timeout: Duration) -> Result<Option<Job>, AnyError> {
+        let jobs = self.jobs.lock().unwrap();
+        let (mut jobs, _) = self
+            .ready
+            .wait_timeout_while(jobs, timeout, |jobs| jobs.is_empty())
+            .unwrap();
+        Ok(jobs.pop_front())
+    }
+    fn dead_letter(&self, job: Job) -> Result<(), AnyError> {
+        self.dead.lock().unwrap().push(job);
+        Ok(())
+    }
+    fn dead_letters(&self) -> Result<Vec<Job>, AnyError> {
+        Ok(self.dead.lock().unwrap().clone())
+    }
+    fn memory(&self, capacity: Option<usize>) -> bool {
+        self.capacity == capacity
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis {
+    use std::time::Duration;
+
+    use r2d2::Pool;
+    use redis_crate::{cmd, Client};
+    use spirit::AnyError;
+
+    use super::{Job, Queue};
+
+    pub(crate) struct RedisQueue {
+        pool: Pool<Client>,
+        key: String,
+        dead_key: String,
+    }
+
+    impl RedisQueue {
+        pub(crate) fn new(url: &str, key: String, workers: usize) -> Result<Self, AnyError> {
+            let client = Client::open(url)?;
+            // One blocking connection for each worker and some for submitting
+            let pool = Pool::builder().max_size(workers as u32 + 2).build(client)?;
+            let dead_key = format!("{}:dead", key);
+            Ok(RedisQueue {
+                pool,
+                key,
+                dead_key,
+            })
+        }
+    }
+
+    impl Queue for RedisQueue {
+        fn push(&self, job: Job) -> Result<(), AnyError> {
+            let job = serde_json::to_string(&job)?;
+            cmd("LPUSH")
+                .arg(&self.key)
+                .arg(job)
+                .query::<()>(&mut *self.pool.get()?)?;
+            Ok(())
+        }
+        fn pop(&self, timeout: Duration) -> Result<Option<Job>, AnyError> {
+            // Redis before 6 takes only whole seconds
+            let timeout = std::cmp::max(timeout.as_secs(), 1);
+            let popped: Option<(String, String)> = cmd("BRPOP")
+                .arg(&self.key)
+                .arg(timeout)
+                .query(&mut *self.pool.get()?)?;
+            match popped {
+                Some((_, job)) => Ok(Some(serde_json::from_str(&job)?)),
+                None => Ok(None),
+            }
+        }
+        fn dead_letter(&self, job: Job) -> Result<(), AnyError> {
+            let job = serde_json::to_string(&job)?;
+            cmd("LPUSH")
+                .arg(&self.dead_key)
+                .arg(job)
+                .query::<()>(&mut *self.pool.get()?)?;
+            Ok(())
+        }
+        fn dead_letters(&self) -> Result<Vec<Job>, AnyError> {
+            let jobs: Vec<String> = cmd("LRANGE")
+                .arg(&self.dead_key)
+                .arg(0)
+                .arg(-1)
+                .query(&mut *self.pool.get()?)?;
+            jobs.iter()
+                .rev()
+                .map(|job| serde_json::from_str(job).map_err(AnyError::from))
+                .collect()
+        }
+    }
+}
+
+type Handler = dyn Fn(&Job) -> Result<(), AnyError> + Send + Sync;
+
+struct Pool {
+    name: String,
+    cfg: JobPool,
+    queue: Arc<dyn Queue>,
+    stop: AtomicBool,
+    drain: AtomicBool,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Pool {
+    /// Waits, returns false if asked to stop meanwhile.
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        loop {
+            if self.stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            thread::sleep(cmp::min(deadline - now, POLL));
+        }
+    }
+
+    fn give_up(&self, job: Job) {
+        if self.cfg.dead_letter {
+            if let Err(e) = self.queue.dead_letter(job) {
+                error!(
+                    "Failed to put job into dead-letter queue of {}: {}",
+                    self.name,
+                    e.display("; ")
+                );
+            }
+        } else {
+            warn!("Dropping job {:?} of {}", job, self.name);
+        }
+    }
+
+    fn run(&self, inner: &Inner, mut job: Job) {
+        loop {
+            let handler = inner.handlers.read().unwrap().get(&job.kind).cloned();
+            let handler = match handler {
+                Some(handler) => handler,
+                None => {
+                    error!("No handler for job {} in pool {}", job.kind, self.name);
+                    return self.give_up(job);
+                }
+            };
+            trace!("Running job {} in pool {}", job.kind, self.name);
+            let e = match handler(&job) {
+                Ok(()) => return,
+                Err(e) => e,
+            };
+            job.failures += 1;
+            if job.failures >= self.cfg.retry.attempts {
+                error!(
+                    "Job {} in pool {} failed for good: {}",
+                    job.kind,
+                    self.name,
+                    e.display("; ")
+                );
+                return self.give_up(job);
+            }
+            let backoff = self.cfg.retry.backoff().delay(job.failures - 1);
+            warn!(
+                "Job {} in pool {} failed (retrying in {:?}): {}",
+                job.kind,
+                self.name,
+                backoff,
+                e.display("; ")
+            );
+            if !self.sleep(backoff) {
+                // Being replaced, let the next workers retry it
+                if let Err(e) = self.queue.push(job) {
+                    error!("Failed to return job to {}: {}", self.name, e.display("; "));
+                }
+                return;
+            }
+        }
+    }
+
+    fn work(&self, inner: &Inner) {
+        while !self.stop.load(Ordering::Relaxed) {
+            match self.queue.pop(POLL) {
+                Ok(Some(job)) => self.run(inner, job),
+                Ok(None) if self.drain.load(Ordering::Relaxed) => break,
+                Ok(None) => (),
+                Err(e) => {
+                    error!(
+                        "Failed to fetch job from {}: {}",
+                        self.name,
+                        e.display("; ")
+                    );
+                    self.sleep(Duration::from_secs(1));
+                }
+            }
+        }
+    }
+
+    fn join(&self) {
+        for worker in self.workers.lock().unwrap().drain(..) {
+            if worker.join().is_err() {
+                error!("Worker of job pool {} panicked", self.name);
+            }
+        }
+    }
+}
+
+/// A running worker pool.
+///
+/// This is the uninstall handle of the [`Jobs`] installer. Dropping it stops the workers (after
+/// they finish their current jobs), but leaves the queue intact. The user should not need to
+/// interact with this directly.
+pub struct RunningPool(Arc<Pool>);
+
+impl Debug for RunningPool {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple("RunningPool").field(&self.0.name).finish()
+    }
+}
+
+impl Drop for RunningPool {
+    fn drop(&mut self) {
+        debug!("Stopping workers of job pool {}", self.0.name);
+        self.0.stop.store(true, Ordering::Relaxed);
+        self.0.join();
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    handlers: RwLock<HashMap<String, Arc<Handler>>>,
+    queues: Mutex<HashMap<String, Arc<dyn Queue>>>,
+    running: Mutex<Vec<Weak<Pool>>>,
+    terminated: AtomicBool,
+}
+
+/// The registry of job handlers and queues.
+///
+/// This is the handle the application submits jobs through and it is also the [`Installer`] of
+/// the [`JobPool`]s. It is cheap to clone, all the clones share the same handlers and pools.
+///
+/// When the application terminates, the pools are drained ‒ the workers process all the waiting
+/// jobs before they exit ‒ and further submissions are refused.
+#[derive(Clone, Default)]
+pub struct Jobs(Arc<Inner>);
+
+impl Debug for Jobs {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Jobs").finish()
+    }
+}
+
+impl Jobs {
+    /// Creates the registry, without any handlers and pools.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a handler for jobs of the given kind.
+    ///
+    /// Replaces a previous handler of the same kind, if any. A job without handler is given up on
+    /// right away.
+    pub fn handler<K, F>(&self, kind: K, handler: F)
+    where
+        K: Into<String>,
+        F: Fn(&Job) -> Result<(), AnyError> + Send + Sync + 'static,
+    {
+        self.0
+            .handlers
+            .write()
+            .unwrap()
+            .insert(kind.into(), Arc::new(handler));
+    }
+
+    fn queue(&self, pool: &str) -> Result<Arc<dyn Queue>, AnyError> {
+        self.0
+            .queues
+            .lock()
+            .unwrap()
+            .get(pool)
+            .cloned()
+            .ok_or_else(|| format!("No job pool named {}", pool).into())
+    }
+
+    /// Submits a job to the named pool.
+    ///
+    /// This fails if there's no such pool (yet), if its queue is full or if the application is
+    /// shutting down.
+    pub fn submit<P, K, D>(&self, pool: P, kind: K, payload: D) -> Result<(), AnyError>
+    where
+        P: AsRef<str>,
+        K: Into<String>,
+        D: Into<String>,
+    {
+        if self.0.terminated.load(Ordering::Relaxed) {
+            return Err("Shutting down, not accepting new jobs".into());
+        }
+        let job = Job {
+            kind: kind.into(),
+            payload: payload.into(),
+            failures: 0,
+        };
+        self.queue(pool.as_ref())?.push(job)
+    }
+
+    /// Returns the jobs in the dead-letter queue of the pool.
+    pub fn dead_letters(&self, pool: &str) -> Result<Vec<Job>, AnyError> {
+        self.queue(pool)?.dead_letters()
+    }
+
+    /// Stops accepting new jobs, processes all the waiting ones and stops the workers.
+    ///
+    /// Blocks until all the pools are drained. This is called automatically when the application
+    /// terminates.
+    pub fn shutdown(&self) {
+        self.0.terminated.store(true, Ordering::Relaxed);
+        let running = self.0.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        let running = running
+            .iter()
+            .filter_map(Weak::upgrade)
+            .inspect(|pool| pool.drain.store(true, Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        for pool in running {
+            info!("Draining job pool {}", pool.name);
+            pool.join();
+        }
+    }
+
+    /// Picks the queue for a pool, reusing or migrating the previous one.
+    fn install_queue(&self, spec: &PoolSpec) -> Arc<dyn Queue> {
+        let mut queues = self.0.queues.lock().unwrap();
+        let name = &spec.cfg.name;
+        let old = queues.get(name).cloned();
+        let new = match (&spec.queue, &spec.cfg.backend) {
+            (Some(queue), _) => Arc::clone(queue),
+            (None, Backend::Memory { capacity }) => match &old {
+                Some(old) if old.memory(*capacity) => return Arc::clone(old),
+                _ => Arc::new(MemoryQueue {
+                    capacity: *capacity,
+                    ..MemoryQueue::default()
+                }) as Arc<dyn Queue>,
+            },
+            #[cfg(feature = "redis")]
+            (None, Backend::Redis { .. }) => unreachable!("Redis queue created in spec"),
+        };
+        if let Some(old) = old {
+            // Move what's waiting in the old queue. It's idle, as the old workers are gone.
+            let mut moved = 0;
+            while let Ok(Some(job)) = old.pop(Duration::from_millis(0)) {
+                if let Err(e) = new.push(job) {
+                    error!(
+                        "Failed to move job to new queue of {}: {}",
+                        name,
+                        e.display("; ")
+                    );
+                }
+                moved += 1;
+            }
+            if moved > 0 {
+                info!("Moved {} jobs to the new queue of {}", moved, name);
+            }
+        }
+        queues.insert(name.clone(), Arc::clone(&new));
+        new
+    }
+}
+
+impl<O, C> Installer<PoolSpec, O, C> for Jobs {
+    type UninstallHandle = RunningPool;
+    fn install(&mut self, spec: PoolSpec, _: &'static str) -> RunningPool {
+        let name = spec.cfg.name.clone();
+        debug!(
+            "Starting job pool {} with {} workers",
+            name, spec.cfg.concurrency
+        );
+        let pool = Arc::new(Pool {
+            name: name.clone(),
+            queue: self.install_queue(&spec),
+            cfg: spec.cfg,
+            stop: AtomicBool::new(false),
+            drain: AtomicBool::new(self.0.terminated.load(Ordering::Relaxed)),
+            workers: Mutex::new(Vec::new()),
+        });
+        for i in 0..pool.cfg.concurrency {
+            let worker_pool = Arc::clone(&pool);
+            let inner = Arc::clone(&self.0);
+            let worker = thread::Builder::new()
+                .name(format!("job-{}-{}", name, i))
+                .spawn(move || worker_pool.work(&inner));
+            match worker {
+                Ok(worker) => pool.workers.lock().unwrap().push(worker),
+                Err(e) => error!("Failed to start worker of job pool {}: {}", name, e),
+            }
+        }
+        let mut running = self.0.running.lock().unwrap();
+        running.retain(|pool| pool.strong_count() > 0);
+        running.push(Arc::downgrade(&pool));
+        RunningPool(pool)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn pool(name: &str) -> PoolSpec {
+        JobPool {
+            name: name.to_owned(),
+            concurrency: 2,
+            retry: Retry {
+                attempts: 2,
+                backoff_base: Duration::from_millis(1),
+                ..Retry::default()
+            },
+            ..JobPool::default()
+        }
+        .spec()
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid() {
+        let cfg = JobPool {
+            concurrency: 0,
+            ..JobPool::default()
+        };
+        assert!(cfg.spec().is_err());
+    }
+
+    #[test]
+    fn unknown_pool() {
+        let jobs = Jobs::new();
+        assert!(jobs.submit("nope", "job", "").is_err());
+    }
+
+    /// Jobs submitted while no workers run are processed once the pool is back, retries and
+    /// dead-letters work and shutdown processes everything.
+    #[test]
+    fn process() {
+        let mut jobs = Jobs::new();
+        let done = Arc::new(AtomicUsize::new(0));
+        let done_cp = Arc::clone(&done);
+        jobs.handler("count", move |_: &Job| {
+            done_cp.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        });
+        jobs.handler("fail", |_: &Job| Err("Failed".into()));
+
+        let running = Installer::<_, (), ()>::install(&mut jobs, pool("p"), "pools");
+        drop(running);
+        for _ in 0..10 {
+            jobs.submit("p", "count", "").unwrap();
+        }
+        jobs.submit("p", "fail", "data").unwrap();
+        jobs.submit("p", "missing", "").unwrap();
+        // Reconfiguration keeps the queue
+        let _running = Installer::<_, (), ()>::install(&mut jobs, pool("p"), "pools");
+        jobs.shutdown();
+
+        assert_eq!(10, done.load(Ordering::Relaxed));
+        let dead = jobs.dead_letters("p").unwrap();
+        assert_eq!(2, dead.len());
+        let failed = dead.iter().find(|job| job.kind == "fail").unwrap();
+        assert_eq!(2, failed.failures);
+        assert!(jobs.submit("p", "count", "").is_err());
+    }
+}
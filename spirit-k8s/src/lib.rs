@@ -0,0 +1,557 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-k8s/0.1.0/spirit_k8s/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration from Kubernetes ConfigMaps and Secrets.
+//!
+//! ConfigMaps and Secrets can be mounted into a pod as files, but the kubelet updates them by
+//! swapping a symlink of the whole directory (and only after a delay). Naive watching of the
+//! files misses that. This crate instead reads the objects directly from the API server and
+//! watches them there.
+//!
+//! A [`Source`] describes one ConfigMap or Secret. When plugged into the spirit
+//! [`Builder`][spirit::Builder], it:
+//!
+//! * Adds a [configuration layer][spirit::ConfigBuilder::config_layer], merged on top of the
+//!   configuration files. By default, the keys of the object that look like configuration files
+//!   (`*.toml`, `*.json`, `*.yaml`, ...) are merged, but specific keys can be selected instead.
+//!   A key can also be placed as a single string value somewhere in the configuration, which is
+//!   useful for passwords stored in Secrets.
+//! * Watches the object and [reloads][spirit::Spirit::config_reload] the configuration when it
+//!   changes.
+//!
+//! The API server is contacted with the credentials of the service account of the pod (the
+//! in-cluster authentication), which needs the permissions to `get`, `list` and `watch` the
+//! objects. The token is read for each request, so rotated tokens are picked up.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_k8s::Source;
+//!
+//! #[derive(Debug, Default, Deserialize)]
+//! struct Cfg {
+//!     message: String,
+//!     #[serde(default)]
+//!     password: String,
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(Source::config_map("my-app"))
+//!         .with(Source::secret("my-app").value("password", "password"))
+//!         .on_config(|_, cfg| println!("Message: {}", cfg.message))
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::thread;
+use std::time::Duration;
+
+use err_context::prelude::*;
+use log::{debug, info, trace};
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Certificate, Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use spirit::extension::{Extensible, Extension};
+use spirit::{AnyError, ConfigBuilder, Spirit};
+use structopt::StructOpt;
+use toml::value::{Table, Value};
+
+/// Where the credentials of the service account are mounted.
+const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+
+/// How long a single watch request lasts before it is renewed.
+const WATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long to wait before watching again after an error.
+const RETRY: Duration = Duration::from_secs(5);
+
+/// The extensions of keys merged as configuration by default.
+const EXTENSIONS: &[&str] = &["toml", "json", "yaml", "yml", "ini", "hjson"];
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+enum Kind {
+    ConfigMap,
+    Secret,
+}
+
+impl Kind {
+    fn resource(self) -> &'static str {
+        match self {
+            Kind::ConfigMap => "configmaps",
+            Kind::Secret => "secrets",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Kind::ConfigMap => "configmap",
+            Kind::Secret => "secret",
+        }
+    }
+}
+
+/// Access to the API server from inside the cluster.
+struct Cluster {
+    url: String,
+    client: Client,
+    namespace: String,
+}
+
+impl Cluster {
+    fn in_cluster() -> Result<Self, AnyError> {
+        let host = env::var("KUBERNETES_SERVICE_HOST")
+            .context("KUBERNETES_SERVICE_HOST not set, not running inside Kubernetes?")?;
+        let port = env::var("KUBERNETES_SERVICE_PORT").unwrap_or_else(|_| "443".to_owned());
+        let url = if host.contains(':') {
+            format!("https://[{}]:{}", host, port)
+        } else {
+            format!("https://{}:{}", host, port)
+        };
+        let account = Path::new(SERVICE_ACCOUNT);
+        let ca = fs::read(account.join("ca.crt"))
+            .context("Failed to read the CA certificate of the cluster")?;
+        let client = Client::builder()
+            .add_root_certificate(Certificate::from_pem(&ca)?)
+            // The watches are terminated by the server, this is only a safety net
+            .timeout(WATCH_TIMEOUT + Duration::from_secs(10))
+            .build()
+            .context("Failed to create the HTTP client")?;
+        let namespace = fs::read_to_string(account.join("namespace"))
+            .context("Failed to read the namespace of the pod")?;
+        Ok(Cluster {
+            url,
+            client,
+            namespace: namespace.trim().to_owned(),
+        })
+    }
+
+    fn get(&self, path: &str) -> Result<Response, AnyError> {
+        // Read every time, the token may get rotated
+        let token = fs::read_to_string(Path::new(SERVICE_ACCOUNT).join("token"))
+            .context("Failed to read the service account token")?;
+        let url = format!("{}{}", self.url, path);
+        trace!("Requesting {}", url);
+        let response = self
+            .client
+            .get(&url)
+            .header(AUTHORIZATION, format!("Bearer {}", token.trim()))
+            .send()
+            .with_context(|_| format!("Request to {} failed", url))?;
+        Ok(response)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    resource_version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Object {
+    metadata: Metadata,
+    #[serde(default)]
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    object: serde_json::Value,
+}
+
+/// The resource version of the object seen by the last load.
+type Version = Arc<Mutex<Option<String>>>;
+
+/// Creates a TOML document with a single string value at the dot-separated path.
+fn value_document(path: &str, content: &str) -> Result<String, AnyError> {
+    let mut value = Value::String(content.to_owned());
+    for part in path.rsplit('.') {
+        if part.is_empty() {
+            return Err(format!("Invalid configuration path {}", path).into());
+        }
+        let mut table = Table::new();
+        table.insert(part.to_owned(), value);
+        value = Value::Table(table);
+    }
+    Ok(toml::to_string(&value)?)
+}
+
+/// A ConfigMap or Secret to load the configuration from.
+///
+/// This is an [`Extension`] of the spirit [`Builder`][spirit::Builder]. See the [crate
+/// documentation](index.html).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Source {
+    kind: Kind,
+    name: String,
+    namespace: Option<String>,
+    keys: Option<Vec<String>>,
+    values: Vec<(String, String)>,
+    optional: bool,
+    watch: bool,
+}
+
+impl Source {
+    fn new(kind: Kind, name: String) -> Self {
+        Source {
+            kind,
+            name,
+            namespace: None,
+            keys: None,
+            values: Vec::new(),
+            optional: false,
+            watch: true,
+        }
+    }
+
+    /// Loads the configuration from a ConfigMap of the given name.
+    pub fn config_map<N: Into<String>>(name: N) -> Self {
+        Self::new(Kind::ConfigMap, name.into())
+    }
+
+    /// Loads the configuration from a Secret of the given name.
+    pub fn secret<N: Into<String>>(name: N) -> Self {
+        Self::new(Kind::Secret, name.into())
+    }
+
+    /// Sets the namespace of the object.
+    ///
+    /// Defaults to the namespace of the pod.
+    pub fn namespace<N: Into<String>>(self, namespace: N) -> Self {
+        Source {
+            namespace: Some(namespace.into()),
+            ..self
+        }
+    }
+
+    /// Merges the given key as a configuration document.
+    ///
+    /// If called (possibly multiple times), only the listed keys are merged, in the order they
+    /// were added, and it is an error if any of them is missing. Otherwise, all the keys with an
+    /// extension of a configuration file are merged, in the alphabetical order.
+    ///
+    /// The format of the document is decided by the extension of the key.
+    pub fn key<K: Into<String>>(mut self, key: K) -> Self {
+        self.keys.get_or_insert_with(Vec::new).push(key.into());
+        self
+    }
+
+    /// Places the content of a key as a string value into the configuration.
+    ///
+    /// The `path` is a dot-separated path in the configuration (eg. `database.password`). These
+    /// are merged after the documents.
+    pub fn value<K: Into<String>, P: Into<String>>(mut self, key: K, path: P) -> Self {
+        self.values.push((key.into(), path.into()));
+        self
+    }
+
+    /// Allows the object not to exist.
+    ///
+    /// A missing optional object provides no configuration. It is still watched and the
+    /// configuration is reloaded when it gets created. Defaults to `false`.
+    pub fn optional(self, optional: bool) -> Self {
+        Source { optional, ..self }
+    }
+
+    /// Turns watching for changes on or off.
+    ///
+    /// Defaults to `true`.
+    pub fn watch(self, watch: bool) -> Self {
+        Source { watch, ..self }
+    }
+
+    fn namespace_in<'a>(&'a self, cluster: &'a Cluster) -> &'a str {
+        self.namespace.as_ref().unwrap_or(&cluster.namespace)
+    }
+
+    fn describe(&self, namespace: &str) -> String {
+        format!("{} {}/{}", self.kind.name(), namespace, self.name)
+    }
+
+    fn url_path(&self, namespace: &str) -> String {
+        format!("/api/v1/namespaces/{}/{}", namespace, self.kind.resource())
+    }
+
+    fn fetch(&self, cluster: &Cluster) -> Result<Option<Object>, AnyError> {
+        let namespace = self.namespace_in(cluster);
+        let path = format!("{}/{}", self.url_path(namespace), self.name);
+        let response = cluster.get(&path)?;
+        if response.status() == StatusCode::NOT_FOUND && self.optional {
+            debug!("Optional {} doesn't exist", self.describe(namespace));
+            return Ok(None);
+        }
+        let object = response
+            .error_for_status()
+            .and_then(|mut response| response.json())
+            .with_context(|_| format!("Failed to get {}", self.describe(namespace)))?;
+        Ok(Some(object))
+    }
+
+    /// Turns the data of the object into the configuration documents.
+    fn documents(
+        &self,
+        prefix: &str,
+        mut data: BTreeMap<String, String>,
+    ) -> Result<Vec<(String, String)>, AnyError> {
+        if self.kind == Kind::Secret {
+            for (key, value) in &mut data {
+                let decoded =
+                    base64::decode(value.as_bytes()).with_context(|_| format!("Key {}", key))?;
+                *value = String::from_utf8(decoded).with_context(|_| format!("Key {}", key))?;
+            }
+        }
+        let get = |key: &str| {
+            data.get(key)
+                .ok_or_else(|| format!("Key {} missing in {}", key, prefix))
+        };
+        let mut documents = Vec::new();
+        match &self.keys {
+            Some(keys) => {
+                for key in keys {
+                    documents.push((format!("{}/{}", prefix, key), get(key)?.clone()));
+                }
+            }
+            None => {
+                let is_value = |key: &str| self.values.iter().any(|(k, _)| k == key);
+                for (key, content) in &data {
+                    let is_config = Path::new(key)
+                        .extension()
+                        .and_then(|ext| ext.to_str())
+                        .map(|ext| EXTENSIONS.contains(&ext))
+                        .unwrap_or(false);
+                    if is_config && !is_value(key) {
+                        documents.push((format!("{}/{}", prefix, key), content.clone()));
+                    } else {
+                        trace!("Skipping key {} of {}", key, prefix);
+                    }
+                }
+            }
+        }
+        for (key, path) in &self.values {
+            let document = value_document(path, get(key)?)?;
+            documents.push((format!("{}/{}.toml", prefix, key), document));
+        }
+        Ok(documents)
+    }
+
+    fn load(
+        &self,
+        cluster: &Cluster,
+        version: &Version,
+    ) -> Result<Vec<(String, String)>, AnyError> {
+        let namespace = self.namespace_in(cluster);
+        let prefix = format!("k8s:{}/{}/{}", self.kind.name(), namespace, self.name);
+        let object = self.fetch(cluster)?;
+        *version.lock().unwrap_or_else(PoisonError::into_inner) = object
+            .as_ref()
+            .map(|object| object.metadata.resource_version.clone());
+        match object {
+            Some(object) => self.documents(&prefix, object.data),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Runs one watch request.
+    ///
+    /// Returns true if the object changed since the last load.
+    fn watch_once(&self, cluster: &Cluster, version: &Version) -> Result<bool, AnyError> {
+        let namespace = self.namespace_in(cluster);
+        let current = version
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        let mut path = format!(
+            "{}?watch=1&fieldSelector=metadata.name%3D{}&timeoutSeconds={}",
+            self.url_path(namespace),
+            self.name,
+            WATCH_TIMEOUT.as_secs()
+        );
+        if let Some(current) = &current {
+            path.push_str("&resourceVersion=");
+            path.push_str(current);
+        }
+        let response = cluster
+            .get(&path)?
+            .error_for_status()
+            .with_context(|_| format!("Failed to watch {}", self.describe(namespace)))?;
+        for line in BufReader::new(response).lines() {
+            let line = line.context("Failed to read watch events")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event: WatchEvent = serde_json::from_str(&line).context("Invalid watch event")?;
+            trace!("Watch event {} on {}", event.kind, self.describe(namespace));
+            match event.kind.as_str() {
+                "ADDED" | "MODIFIED" | "DELETED" => {
+                    let object: Object =
+                        serde_json::from_value(event.object).context("Invalid watch event")?;
+                    let seen = object.metadata.resource_version;
+                    if current.as_ref() != Some(&seen) {
+                        // Remember it even if the reload fails, so it is not reported again
+                        *version.lock().unwrap_or_else(PoisonError::into_inner) = Some(seen);
+                        return Ok(true);
+                    }
+                }
+                "ERROR" => {
+                    // Usually the version is too old to watch from. Reload to get a fresh one.
+                    debug!(
+                        "Watch of {} failed: {}",
+                        self.describe(namespace),
+                        event.object
+                    );
+                    *version.lock().unwrap_or_else(PoisonError::into_inner) = None;
+                    return Ok(true);
+                }
+                _ => (),
+            }
+        }
+        Ok(false)
+    }
+
+    fn watch_thread<O, C>(self, version: Version, spirit: Weak<Spirit<O, C>>)
+    where
+        C: DeserializeOwned + Send + Sync,
+        O: StructOpt,
+    {
+        let alive = || {
+            spirit
+                .upgrade()
+                .map(|spirit| !spirit.is_terminated())
+                .unwrap_or(false)
+        };
+        let mut cluster = None;
+        while alive() {
+            let result = match cluster {
+                Some(ref cluster) => self.watch_once(cluster, &version),
+                None => Cluster::in_cluster().map(|created| {
+                    cluster = Some(created);
+                    false
+                }),
+            };
+            match result {
+                Ok(false) => (),
+                Ok(true) => {
+                    if let Some(spirit) = spirit.upgrade() {
+                        info!("{} changed, reloading configuration", self.name);
+                        if let Err(e) = spirit.config_reload() {
+                            spirit::log_error!(Error, "Failed to reload configuration" => e);
+                        }
+                    }
+                }
+                Err(e) => {
+                    spirit::log_error!(Error, "Failed to watch for changes" => e);
+                    thread::sleep(RETRY);
+                }
+            }
+        }
+        debug!("Stopped watching {}", self.name);
+    }
+}
+
+impl<E> Extension<E> for Source
+where
+    E: Extensible<Ok = E> + ConfigBuilder,
+    E::Config: DeserializeOwned + Send + Sync + 'static,
+    E::Opts: StructOpt + Send + Sync + 'static,
+{
+    fn apply(self, ext: E) -> Result<E, AnyError> {
+        let version = Version::default();
+        let layer_version = Arc::clone(&version);
+        let source = self.clone();
+        let mut cluster = None;
+        let ext = ext.config_layer(move || {
+            if cluster.is_none() {
+                cluster = Some(Cluster::in_cluster()?);
+            }
+            let cluster = cluster.as_ref().expect("Cluster created above");
+            source.load(cluster, &layer_version)
+        });
+        if !self.watch {
+            return Ok(ext);
+        }
+        ext.run_before(move |spirit| {
+            let spirit = Arc::downgrade(spirit);
+            thread::Builder::new()
+                .name(format!("k8s-{}", self.name))
+                .spawn(move || self.watch_thread(version, spirit))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_documents() {
+        assert_eq!(
+            "[database]\npassword = \"secret\\n\"\n",
+            value_document("database.password", "secret\n").unwrap()
+        );
+        assert!(value_document("database..password", "x").is_err());
+    }
+
+    #[test]
+    fn documents() {
+        let data = vec![
+            ("app.toml", "a = 1"),
+            ("extra.yaml", "b: 2"),
+            ("README", "Not config"),
+            ("password", "c2VjcmV0"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_owned(), v.to_owned()))
+        .collect::<BTreeMap<_, _>>();
+
+        let source = Source::config_map("app");
+        let docs = source.documents("p", data.clone()).unwrap();
+        let names = docs.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["p/app.toml", "p/extra.yaml"], names);
+
+        let source = Source::config_map("app").key("README").key("app.toml");
+        let docs = source.documents("p", data.clone()).unwrap();
+        let names = docs.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(vec!["p/README", "p/app.toml"], names);
+        assert!(Source::config_map("app")
+            .key("missing")
+            .documents("p", data.clone())
+            .is_err());
+
+        let source = Source::secret("app")
+            .key("password")
+            .value("password", "db.password");
+        let encoded = data
+            .into_iter()
+            .filter(|(k, _)| k == "password")
+            .collect::<BTreeMap<_, _>>();
+        let docs = source.documents("p", encoded).unwrap();
+        assert_eq!(
+            vec![
+                ("p/password".to_owned(), "secret".to_owned()),
+                (
+                    "p/password.toml".to_owned(),
+                    "[db]\npassword = \"secret\"\n".to_owned()
+                ),
+            ],
+            docs
+        );
+    }
+}
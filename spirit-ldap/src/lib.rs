@@ -0,0 +1,557 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-ldap/0.1.0/spirit_ldap/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [LDAP] connection pools.
+//!
+//! The [`LdapPool`] configuration fragment describes an [r2d2] pool of connections to an LDAP
+//! server (or Active Directory). Several server URLs can be listed, a new connection goes to the
+//! first one that accepts it. Each connection is bound with the configured service account, so
+//! the connections in the pool are ready for lookups. Connections that break are replaced by the
+//! pool.
+//!
+//! The pool is installed into an [`AtomicLdap`] handle, which always holds the pool of the
+//! current configuration, together with the default search base.
+//!
+//! Unless turned off, a new pool is checked by binding one of its connections before it is
+//! installed, so wrong credentials are refused at reload time. The same probe is available at
+//! any time through [`AtomicLdap::probe`], eg. for health endpoints.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_ldap::{AtomicLdap, LdapPool};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [ldap]
+//! urls = ["ldaps://dc1.example.com", "ldaps://dc2.example.com"]
+//! bind-dn = "cn=service,dc=example,dc=com"
+//! bind-password = "secret"
+//! base-dn = "ou=people,dc=example,dc=com"
+//! health-check = false
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     ldap: LdapPool,
+//! }
+//!
+//! fn main() {
+//!     let ldap = AtomicLdap::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("ldap")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.ldap.clone())
+//!                 .install(ldap.clone())
+//!         )
+//!         .run(move |_| {
+//!             // Not actually connecting in the example
+//!             if false {
+//!                 let _users = ldap.search("(uid=alice)", vec!["mail"])?;
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [LDAP]: https://crates.io/crates/ldap3
+//! [r2d2]: https://crates.io/crates/r2d2
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use ldap3::exop::WhoAmI;
+use ldap3::{LdapConn, LdapConnSettings, LdapError, Scope, SearchEntry};
+use log::{debug, trace, warn};
+use native_tls::{Certificate, TlsConnector};
+use r2d2::{ManageConnection, PooledConnection};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_pool_size() -> u32 {
+    10
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_connection_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_health_check() -> bool {
+    true
+}
+
+/// The pool of LDAP connections.
+pub type Pool = r2d2::Pool<LdapManager>;
+
+/// Configuration of an LDAP connection pool.
+///
+/// # Fields
+///
+/// * `urls`: The servers to connect to, tried in order (eg. `ldap://dc1.example.com` or
+///   `ldaps://dc1.example.com:636`). At least one is needed.
+/// * `bind-dn`: The DN to bind the connections as. Anonymous if not set.
+/// * `bind-password`: The password to bind with. Hidden when dumping the configuration.
+/// * `bind-password-file`: Read the password from this file instead (surrounding whitespace is
+///   stripped). Useful with secrets mounted as files.
+/// * `starttls`: Upgrade plain `ldap://` connections with `STARTTLS`. Defaults to `false`.
+/// * `ca-file`: A PEM file with an additional CA to trust for TLS.
+/// * `tls-no-verify`: Don't verify the server certificates. Dangerous, for testing only.
+///   Defaults to `false`.
+/// * `base-dn`: The default base of searches done through [`AtomicLdap::search`].
+/// * `pool-size`: Maximum number of connections. Defaults to 10.
+/// * `connect-timeout`: Time limit for connecting to one server. Defaults to `5s`.
+/// * `operation-timeout`: Time limit of each operation (bind, search...). Defaults to `nil`
+///   (unlimited).
+/// * `connection-timeout`: How long to wait for a connection from the pool. Defaults to `30s`.
+/// * `health-check`: Bind a connection before using a new configuration. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct LdapPool {
+    /// The servers to connect to, in order of preference.
+    pub urls: Vec<String>,
+
+    /// The DN to bind as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_dn: Option<String>,
+
+    /// The password to bind with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_password: Option<Hidden<String>>,
+
+    /// A file to read the bind password from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bind_password_file: Option<PathBuf>,
+
+    /// Upgrade plain connections with STARTTLS.
+    #[serde(default)]
+    pub starttls: bool,
+
+    /// An additional CA to trust.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+
+    /// Don't verify the server certificates.
+    #[serde(default)]
+    pub tls_no_verify: bool,
+
+    /// The default base of searches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_dn: Option<String>,
+
+    /// Maximum number of connections.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+
+    /// Time limit for connecting to one server.
+    #[serde(
+        default = "default_connect_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub connect_timeout: Duration,
+
+    /// Time limit of each operation.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub operation_timeout: Option<Duration>,
+
+    /// How long to wait for a connection from the pool.
+    #[serde(
+        default = "default_connection_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub connection_timeout: Duration,
+
+    /// Bind a connection before using a new configuration.
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+}
+
+impl Default for LdapPool {
+    fn default() -> Self {
+        LdapPool {
+            urls: vec!["ldap://localhost".to_owned()],
+            bind_dn: None,
+            bind_password: None,
+            bind_password_file: None,
+            starttls: false,
+            ca_file: None,
+            tls_no_verify: false,
+            base_dn: None,
+            pool_size: default_pool_size(),
+            connect_timeout: default_connect_timeout(),
+            operation_timeout: None,
+            connection_timeout: default_connection_timeout(),
+            health_check: default_health_check(),
+        }
+    }
+}
+
+impl LdapPool {
+    fn bind_password(&self) -> Result<Option<String>, AnyError> {
+        match (&self.bind_password, &self.bind_password_file) {
+            (Some(_), Some(_)) => Err("Both bind-password and bind-password-file are set".into()),
+            (Some(password), None) => Ok(Some(password.0.clone())),
+            (None, Some(file)) => {
+                let password = fs::read_to_string(file).with_context(|_| {
+                    format!("Failed to read LDAP password from {}", file.display())
+                })?;
+                Ok(Some(password.trim().to_owned()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn tls_connector(&self) -> Result<Option<TlsConnector>, AnyError> {
+        if self.ca_file.is_none() && !self.tls_no_verify {
+            return Ok(None);
+        }
+        let mut builder = TlsConnector::builder();
+        if let Some(ca_file) = &self.ca_file {
+            let pem = fs::read(ca_file)
+                .with_context(|_| format!("Failed to read CA {}", ca_file.display()))?;
+            let ca = Certificate::from_pem(&pem)
+                .with_context(|_| format!("Invalid CA {}", ca_file.display()))?;
+            builder.add_root_certificate(ca);
+        }
+        if self.tls_no_verify {
+            warn!("LDAP server certificates are not verified, this is insecure");
+            builder.danger_accept_invalid_certs(true);
+        }
+        Ok(Some(builder.build().context("Failed to set up LDAP TLS")?))
+    }
+
+    /// Creates the connection manager according to the configuration.
+    pub fn manager(&self) -> Result<LdapManager, AnyError> {
+        if self.urls.is_empty() {
+            return Err("No LDAP servers configured".into());
+        }
+        let bind = match (&self.bind_dn, self.bind_password()?) {
+            (Some(dn), Some(password)) => Some((dn.clone(), password)),
+            (Some(dn), None) => Some((dn.clone(), String::new())),
+            (None, Some(_)) => return Err("LDAP bind password set without a bind-dn".into()),
+            (None, None) => None,
+        };
+        Ok(LdapManager {
+            urls: self.urls.clone(),
+            bind,
+            starttls: self.starttls,
+            connector: self.tls_connector()?,
+            connect_timeout: self.connect_timeout,
+            operation_timeout: self.operation_timeout,
+        })
+    }
+
+    /// Creates the connection pool according to the configuration.
+    ///
+    /// This also runs the health check if configured.
+    pub fn create_directory(&self) -> Result<Directory, AnyError> {
+        debug!("Creating LDAP pool of up to {} connections", self.pool_size);
+        let pool = r2d2::Pool::builder()
+            .max_size(self.pool_size)
+            .min_idle(Some(0))
+            .connection_timeout(self.connection_timeout)
+            .build(self.manager()?)
+            .context("Failed to create LDAP pool")?;
+        if self.health_check {
+            trace!("Checking health of the new LDAP pool");
+            probe(&pool).context("Health check of the new LDAP pool failed")?;
+        }
+        Ok(Directory {
+            pool,
+            base_dn: self.base_dn.clone(),
+        })
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for LdapPool {
+        type Driver = CacheEq<LdapPool>;
+        type Resource = Directory;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Directory, AnyError> {
+            self.create_directory()
+        }
+    }
+}
+
+fn probe(pool: &Pool) -> Result<(), AnyError> {
+    let mut conn = pool.get().context("Can't get an LDAP connection")?;
+    if conn.bind.is_some() {
+        conn.rebind()
+    } else {
+        conn.conn.extended(WhoAmI)?.success()?;
+        Ok(())
+    }
+}
+
+/// The r2d2 connection manager for LDAP.
+///
+/// Created by [`LdapPool::manager`]. The user should not need to interact with this directly.
+pub struct LdapManager {
+    urls: Vec<String>,
+    bind: Option<(String, String)>,
+    starttls: bool,
+    connector: Option<TlsConnector>,
+    connect_timeout: Duration,
+    operation_timeout: Option<Duration>,
+}
+
+impl Debug for LdapManager {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("LdapManager")
+            .field("urls", &self.urls)
+            .field("bind_dn", &self.bind.as_ref().map(|(dn, _)| dn))
+            .field("starttls", &self.starttls)
+            .finish()
+    }
+}
+
+impl LdapManager {
+    fn connect_to(&self, url: &str) -> Result<LdapConn, LdapError> {
+        let mut settings = LdapConnSettings::new()
+            .set_conn_timeout(self.connect_timeout)
+            .set_starttls(self.starttls);
+        if let Some(connector) = &self.connector {
+            settings = settings.set_connector(connector.clone());
+        }
+        let mut conn = LdapConn::with_settings(settings, url)?;
+        if let Some((dn, password)) = &self.bind {
+            self.timed(&mut conn).simple_bind(dn, password)?.success()?;
+        }
+        Ok(conn)
+    }
+
+    fn timed<'a>(&self, conn: &'a mut LdapConn) -> &'a mut LdapConn {
+        match self.operation_timeout {
+            Some(timeout) => conn.with_timeout(timeout),
+            None => conn,
+        }
+    }
+}
+
+impl ManageConnection for LdapManager {
+    type Connection = Connection;
+    type Error = LdapError;
+    fn connect(&self) -> Result<Connection, LdapError> {
+        let mut last_error = None;
+        for url in &self.urls {
+            match self.connect_to(url) {
+                Ok(conn) => {
+                    trace!("Connected to LDAP server {}", url);
+                    return Ok(Connection {
+                        conn,
+                        bind: self.bind.clone(),
+                    });
+                }
+                Err(e) => {
+                    debug!("Failed to connect to LDAP server {}: {}", url, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("At least one URL is checked in config"))
+    }
+    fn is_valid(&self, conn: &mut Connection) -> Result<(), LdapError> {
+        self.timed(&mut conn.conn).extended(WhoAmI)?.success()?;
+        Ok(())
+    }
+    fn has_broken(&self, conn: &mut Connection) -> bool {
+        conn.conn.is_closed()
+    }
+}
+
+/// A bound LDAP connection, as checked out of the pool.
+pub struct Connection {
+    conn: LdapConn,
+    bind: Option<(String, String)>,
+}
+
+impl Connection {
+    /// The underlying connection.
+    ///
+    /// If the connection gets re-bound as a different user (eg. to verify a user's password), it
+    /// should be re-bound back by [`rebind`][Connection::rebind] before being returned to the
+    /// pool.
+    pub fn conn(&mut self) -> &mut LdapConn {
+        &mut self.conn
+    }
+
+    /// Binds the connection as the configured service account again.
+    pub fn rebind(&mut self) -> Result<(), AnyError> {
+        if let Some((dn, password)) = &self.bind {
+            self.conn
+                .simple_bind(dn, password)?
+                .success()
+                .with_context(|_| format!("Failed to bind as {}", dn))?;
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Connection {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Connection").finish()
+    }
+}
+
+/// The pool together with the default search base.
+///
+/// This is what the [`LdapPool`] fragment creates.
+#[derive(Debug)]
+pub struct Directory {
+    pool: Pool,
+    base_dn: Option<String>,
+}
+
+impl Directory {
+    /// The connection pool.
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// The configured default search base.
+    pub fn base_dn(&self) -> Option<&str> {
+        self.base_dn.as_deref()
+    }
+}
+
+/// A handle to the currently configured LDAP pool.
+///
+/// Cheap to clone, all the clones share the same pool. It can be used as an [`Installer`] for
+/// the [`LdapPool`] fragment. The old pool stays alive until all connections checked out of it
+/// are returned.
+///
+/// # Panics
+///
+/// Accessing the pool before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicLdap(Arc<ArcSwapOption<Directory>>);
+
+impl Debug for AtomicLdap {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicLdap").finish()
+    }
+}
+
+impl AtomicLdap {
+    /// Creates an [`AtomicLdap`] without any pool inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the pool inside.
+    pub fn replace(&self, directory: Directory) {
+        self.0.store(Some(Arc::new(directory)));
+    }
+
+    /// Returns the current pool and search base.
+    pub fn directory(&self) -> Arc<Directory> {
+        self.0
+            .load_full()
+            .expect("Accessing LDAP pool before setting it up")
+    }
+
+    /// Checks out a connection from the current pool.
+    pub fn get(&self) -> Result<PooledConnection<LdapManager>, AnyError> {
+        self.directory()
+            .pool
+            .get()
+            .context("Failed to get an LDAP connection")
+            .map_err(AnyError::from)
+    }
+
+    /// Searches the subtree of the configured base DN.
+    pub fn search(&self, filter: &str, attrs: Vec<&str>) -> Result<Vec<SearchEntry>, AnyError> {
+        let directory = self.directory();
+        let base = directory
+            .base_dn()
+            .ok_or_else(|| AnyError::from("No LDAP base-dn configured"))?;
+        let mut conn = directory
+            .pool
+            .get()
+            .context("Failed to get an LDAP connection")?;
+        let (entries, _) = conn
+            .conn
+            .search(base, Scope::Subtree, filter, attrs)?
+            .success()
+            .with_context(|_| format!("LDAP search for {} failed", filter))?;
+        Ok(entries.into_iter().map(SearchEntry::construct).collect())
+    }
+
+    /// Checks the server is reachable and the service account can bind.
+    pub fn probe(&self) -> Result<(), AnyError> {
+        probe(&self.directory().pool)
+    }
+}
+
+impl<O, C> Installer<Directory, O, C> for AtomicLdap {
+    type UninstallHandle = ();
+    fn install(&mut self, directory: Directory, name: &'static str) {
+        debug!("Installing LDAP pool {}", name);
+        self.replace(directory);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_urls() {
+        let cfg = LdapPool {
+            urls: Vec::new(),
+            ..LdapPool::default()
+        };
+        assert!(cfg.manager().is_err());
+    }
+
+    #[test]
+    fn password_without_dn() {
+        let cfg = LdapPool {
+            bind_password: Some(Hidden("secret".to_owned())),
+            ..LdapPool::default()
+        };
+        assert!(cfg.manager().is_err());
+    }
+
+    #[test]
+    fn failover_to_nothing() {
+        let cfg = LdapPool {
+            urls: vec![
+                "ldap://127.0.0.1:1".to_owned(),
+                "ldap://127.0.0.1:2".to_owned(),
+            ],
+            connect_timeout: Duration::from_secs(1),
+            ..LdapPool::default()
+        };
+        assert!(cfg.manager().unwrap().connect().is_err());
+    }
+}
@@ -0,0 +1,360 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-lettre/0.1.0/spirit_lettre/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of SMTP transports of [lettre].
+//!
+//! The [`SmtpMailer`] fragment describes how to reach the mail server ‒ the host and port, the
+//! encryption, the credentials and the connection pool. The created transport is installed into
+//! an [`AtomicMailer`] handle, which always holds the transport of the current configuration.
+//!
+//! Unless turned off, the new transport connects to the server (and logs in) before it is
+//! installed. If that fails, the new configuration is refused, so wrong credentials are noticed
+//! at reload time instead of with the first sent mail.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_lettre::{AtomicMailer, SmtpMailer};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [smtp]
+//! host = "smtp.example.com"
+//! security = "starttls"
+//! username = "app"
+//! password = "secret"
+//! test-connection = false
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     smtp: SmtpMailer,
+//! }
+//!
+//! fn main() {
+//!     let mailer = AtomicMailer::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("smtp")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.smtp.clone())
+//!                 .install(mailer.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _transport = mailer.mailer();
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [lettre]: https://crates.io/crates/lettre
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::response::Response;
+use lettre::transport::smtp::PoolConfig;
+use lettre::{Message, SmtpTransport, Transport};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+/// How the connection to the mail server is secured.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Security {
+    /// Plain text connection.
+    ///
+    /// Everything, including the password, goes over the wire unencrypted.
+    None,
+    /// Plain connection upgraded by the `STARTTLS` command (usually port 587).
+    Starttls,
+    /// TLS from the start (usually port 465).
+    Tls,
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Security::Starttls
+    }
+}
+
+fn default_pool_size() -> u32 {
+    4
+}
+
+fn default_idle_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_test_connection() -> bool {
+    true
+}
+
+/// Configuration of an SMTP transport.
+///
+/// # Fields
+///
+/// * `host`: The mail server.
+/// * `port`: The port to connect to. Defaults to 25, 587 or 465, depending on `security`.
+/// * `security`: One of `none`, `starttls` and `tls`. Defaults to `starttls`.
+/// * `username`: The user to log in as. No authentication is done if not set.
+/// * `password`: The password to log in with. Hidden when dumping the configuration.
+/// * `password-file`: Read the password from this file instead (surrounding whitespace is
+///   stripped). Useful with secrets mounted as files.
+/// * `pool-size`: Maximum number of connections kept open. Defaults to 4.
+/// * `idle-timeout`: How long to keep an unused connection open. Defaults to `60s`.
+/// * `timeout`: Timeout of network operations. Defaults to `nil` (lettre's default of a minute).
+/// * `test-connection`: Connect and log in before using a new configuration. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct SmtpMailer {
+    /// The mail server.
+    pub host: String,
+
+    /// The port to connect to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+
+    /// How the connection is secured.
+    #[serde(default)]
+    pub security: Security,
+
+    /// The user to log in as.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+
+    /// The password to log in with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Hidden<String>>,
+
+    /// A file to read the password from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+
+    /// Maximum number of connections kept open.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+
+    /// How long to keep an unused connection open.
+    #[serde(
+        default = "default_idle_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub idle_timeout: Duration,
+
+    /// Timeout of network operations.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// Connect and log in before using a new configuration.
+    #[serde(default = "default_test_connection")]
+    pub test_connection: bool,
+}
+
+impl Default for SmtpMailer {
+    fn default() -> Self {
+        SmtpMailer {
+            host: "localhost".to_owned(),
+            port: None,
+            security: Security::default(),
+            username: None,
+            password: None,
+            password_file: None,
+            pool_size: default_pool_size(),
+            idle_timeout: default_idle_timeout(),
+            timeout: None,
+            test_connection: default_test_connection(),
+        }
+    }
+}
+
+impl SmtpMailer {
+    fn credentials(&self) -> Result<Option<Credentials>, AnyError> {
+        let password = match (&self.password, &self.password_file) {
+            (Some(_), Some(_)) => return Err("Both password and password-file are set".into()),
+            (Some(password), None) => Some(password.0.clone()),
+            (None, Some(file)) => {
+                let password = fs::read_to_string(file).with_context(|_| {
+                    format!("Failed to read SMTP password from {}", file.display())
+                })?;
+                Some(password.trim().to_owned())
+            }
+            (None, None) => None,
+        };
+        match (&self.username, password) {
+            (Some(username), Some(password)) => {
+                Ok(Some(Credentials::new(username.clone(), password)))
+            }
+            (Some(_), None) => Err("SMTP username set without a password".into()),
+            (None, Some(_)) => Err("SMTP password set without a username".into()),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Creates the transport.
+    ///
+    /// This doesn't connect anywhere, the connections are opened on demand. The connection test
+    /// is part of the fragment, not of this method.
+    pub fn create_transport(&self) -> Result<SmtpTransport, AnyError> {
+        let mut builder = match self.security {
+            Security::None => SmtpTransport::builder_dangerous(&self.host),
+            Security::Starttls => SmtpTransport::starttls_relay(&self.host)
+                .with_context(|_| format!("Invalid SMTP server {}", self.host))?,
+            Security::Tls => SmtpTransport::relay(&self.host)
+                .with_context(|_| format!("Invalid SMTP server {}", self.host))?,
+        };
+        if let Some(port) = self.port {
+            builder = builder.port(port);
+        }
+        if let Some(credentials) = self.credentials()? {
+            builder = builder.credentials(credentials);
+        }
+        if self.timeout.is_some() {
+            builder = builder.timeout(self.timeout);
+        }
+        let pool = PoolConfig::new()
+            .max_size(self.pool_size)
+            .idle_timeout(self.idle_timeout);
+        Ok(builder.pool_config(pool).build())
+    }
+
+    fn checked_transport(&self) -> Result<SmtpTransport, AnyError> {
+        let transport = self.create_transport()?;
+        if self.test_connection {
+            debug!("Testing connection to SMTP server {}", self.host);
+            let ok = transport
+                .test_connection()
+                .with_context(|_| format!("Failed to connect to SMTP server {}", self.host))?;
+            if !ok {
+                return Err(format!("SMTP server {} doesn't respond", self.host).into());
+            }
+        }
+        Ok(transport)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for SmtpMailer {
+        type Driver = CacheEq<SmtpMailer>;
+        type Resource = SmtpTransport;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<SmtpTransport, AnyError> {
+            self.checked_transport()
+        }
+    }
+}
+
+/// A handle to the current SMTP transport.
+///
+/// Cheap to clone, all the clones share the same transport. It can be used as an [`Installer`]
+/// for the [`SmtpMailer`] fragment.
+///
+/// # Panics
+///
+/// Accessing the transport before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicMailer(Arc<ArcSwapOption<SmtpTransport>>);
+
+impl Debug for AtomicMailer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicMailer").finish()
+    }
+}
+
+impl AtomicMailer {
+    /// Creates an [`AtomicMailer`] without any transport inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the transport inside.
+    pub fn replace(&self, transport: SmtpTransport) {
+        self.0.store(Some(Arc::new(transport)));
+    }
+
+    /// Returns the current transport.
+    pub fn mailer(&self) -> Arc<SmtpTransport> {
+        self.0
+            .load_full()
+            .expect("Accessing SMTP transport before setting it up")
+    }
+
+    /// Sends a message through the current transport.
+    pub fn send(&self, message: &Message) -> Result<Response, AnyError> {
+        self.mailer()
+            .send(message)
+            .context("Failed to send mail")
+            .map_err(AnyError::from)
+    }
+}
+
+impl<O, C> Installer<SmtpTransport, O, C> for AtomicMailer {
+    type UninstallHandle = ();
+    fn install(&mut self, transport: SmtpTransport, name: &'static str) {
+        debug!("Installing SMTP transport {}", name);
+        self.replace(transport);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_without_password() {
+        let mailer = SmtpMailer {
+            username: Some("app".to_owned()),
+            ..SmtpMailer::default()
+        };
+        assert!(mailer.create_transport().is_err());
+    }
+
+    #[test]
+    fn both_passwords() {
+        let mailer = SmtpMailer {
+            username: Some("app".to_owned()),
+            password: Some(Hidden("secret".to_owned())),
+            password_file: Some(PathBuf::from("/run/secrets/smtp")),
+            ..SmtpMailer::default()
+        };
+        assert!(mailer.create_transport().is_err());
+    }
+
+    #[test]
+    fn unreachable_server() {
+        let mailer = SmtpMailer {
+            port: Some(1),
+            security: Security::None,
+            timeout: Some(Duration::from_secs(1)),
+            ..SmtpMailer::default()
+        };
+        assert!(mailer.create_transport().is_ok());
+        assert!(mailer.checked_transport().is_err());
+    }
+}
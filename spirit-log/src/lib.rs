@@ -191,8 +191,10 @@ use log::{debug, trace, LevelFilter, Log, STATIC_MAX_LEVEL};
 use serde::de::{Deserializer, Error as DeError};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
+use spirit::cfg::Duration;
 use spirit::extension::{Extensible, Extension};
 use spirit::fragment::driver::Trivial as TrivialDriver;
+use spirit::fragment::logging::level_overrides;
 use spirit::fragment::{Fragment, Installer};
 use spirit::AnyError;
 #[cfg(feature = "cfg-help")]
@@ -240,6 +242,7 @@ impl Opts {
                 .iter()
                 .map(|(module, lf)| (module.clone(), LevelFilterSerde(*lf)))
                 .collect(),
+            error_repeat_interval: None,
             clock: Clock::Local,
             time_format: cmdline_time_format(),
             format: Format::Short,
@@ -479,6 +482,13 @@ struct Logger {
     /// This allows silencing a verbose one or getting more info out of misbehaving one.
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     per_module: HashMap<String, LevelFilterSerde>,
+
+    /// Collapse identical errors repeating within this interval into one message.
+    ///
+    /// This is process-wide (see [`spirit::error`]); if multiple loggers set it, the shortest one
+    /// is used. Off if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_repeat_interval: Option<Duration>,
 }
 
 impl Logger {
@@ -491,6 +501,14 @@ impl Logger {
             .fold(logger, |logger, (module, level)| {
                 logger.level_for(module.clone(), level.0)
             });
+        // Let through the messages of fragments with overridden log level, unless configured
+        // explicitly here.
+        logger = level_overrides()
+            .into_iter()
+            .filter(|(target, _)| !self.per_module.contains_key(target))
+            .fold(logger, |logger, (target, level)| {
+                logger.level_for(target, level)
+            });
         let clock = self.clock;
         let time_format = self.time_format.clone();
         let format = self.format;
@@ -656,6 +674,7 @@ impl Default for Logger {
             destination: LogDestination::StdErr,
             level: LevelFilterSerde(LevelFilter::Warn),
             per_module: HashMap::new(),
+            error_repeat_interval: None,
             clock: Clock::Local,
             time_format: cmdline_time_format(),
             format: Format::Short,
@@ -668,11 +687,18 @@ where
     I: IntoIterator<Item = &'a Logger>,
 {
     debug!("Creating loggers");
-    logging
+    let logging = logging.into_iter().collect::<Vec<_>>();
+    let repeat_interval = logging
+        .iter()
+        .filter_map(|l| l.error_repeat_interval)
+        .min()
+        .map(Into::into);
+    let dispatch = logging
         .into_iter()
         .map(Logger::create)
-        .fold_results(Dispatch::new(), Dispatch::chain)
-        .map_err(AnyError::from)
+        .fold_results(Dispatch::new(), Dispatch::chain)?;
+    spirit::error::set_repeat_interval(repeat_interval);
+    Ok(dispatch)
 }
 
 /// A configuration fragment to set up logging.
@@ -689,8 +715,12 @@ where
 ///
 /// * `level`: The log level to use. Valid options are `OFF`, `ERROR`, `WARN`, `INFO`, `DEBUG` and
 ///   `TRACE`.
+/// * `error-repeat-interval`: Identical errors (logged through [`spirit::error`]) repeating within
+///   this interval are collapsed into one message with a count. The setting is process-wide, the
+///   shortest one of all the loggers is used. Off by default.
 /// * `per-module`: A map, setting log level overrides for specific modules (logging targets). This
-///   one is optional.
+///   one is optional. Fragments with their own `log-level` (see
+///   [`spirit::fragment::logging`]) are added to it automatically.
 /// * `type`: Specifies the type of logger destination. Some of them allow specifying other
 ///   options.
 /// * `clock`: Either `LOCAL` or `UTC`. Defaults to `LOCAL` if not present.
@@ -745,6 +775,7 @@ impl Cfg {
                     destination: LogDestination::StdErr,
                     level: LevelFilterSerde(LevelFilter::Warn),
                     per_module: HashMap::new(),
+                    error_repeat_interval: None,
                     clock: Clock::Local,
                     time_format: cmdline_time_format(),
                     format: Format::Short,
@@ -899,7 +930,9 @@ impl<O, C> Installer<Dispatch, O, C> for LogInstaller {
         install(logger);
     }
     fn init<B: Extensible<Ok = B>>(&mut self, builder: B, _name: &str) -> Result<B, AnyError> {
-        builder.with(Cfg::init_extension())
+        builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .with(Cfg::init_extension())
     }
 }
 
@@ -909,6 +942,8 @@ impl<O, C> Installer<(LevelFilter, Box<dyn Log>), O, C> for LogInstaller {
         install_parts(level, logger);
     }
     fn init<B: Extensible<Ok = B>>(&mut self, builder: B, _name: &str) -> Result<B, AnyError> {
-        builder.with(Cfg::init_extension())
+        builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .with(Cfg::init_extension())
     }
 }
@@ -0,0 +1,310 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-memcache/0.1.0/spirit_memcache/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [memcached] clients.
+//!
+//! The [`MemcachePool`] configuration fragment describes a ring of memcached servers, how the
+//! keys are distributed between them and the connection settings. It creates a [`Client`] which
+//! keeps a small pool of connections to each of the servers. It is installed into an
+//! [`AtomicMemcache`] handle, which always holds the client of the current configuration.
+//!
+//! Unless turned off, a new client is checked by asking all the servers for their version before
+//! it is installed. The same probe is available at any time through [`AtomicMemcache::probe`].
+//!
+//! # Key distribution
+//!
+//! The key is hashed and the server is picked by the hash modulo the number of servers. When
+//! sharing the cache with clients in other languages, they need to agree on the hash function,
+//! which is selected by the `hashing` option.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_memcache::{AtomicMemcache, MemcachePool};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [cache]
+//! servers = ["memcache://cache1:11211", "memcache://cache2:11211"]
+//! hashing = "crc32"
+//! read-timeout = "200ms"
+//! health-check = false
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     cache: MemcachePool,
+//! }
+//!
+//! fn main() {
+//!     let cache = AtomicMemcache::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("cache")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.cache.clone())
+//!                 .install(cache.clone())
+//!         )
+//!         .run(move |_| {
+//!             // Not actually connecting in the example
+//!             if false {
+//!                 cache.client().set("key", 42, 60)?;
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [memcached]: https://crates.io/crates/memcache
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::{debug, trace};
+use memcache::Client;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+/// How keys are hashed to pick a server.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Hashing {
+    /// The default of the memcache crate (Rust's `DefaultHasher`).
+    ///
+    /// Compatible only with other Rust clients built against the same compiler.
+    Default,
+    /// CRC32, as used by libmemcached and many other clients.
+    Crc32,
+    /// 64-bit FNV-1a.
+    Fnv1a64,
+}
+
+impl Default for Hashing {
+    fn default() -> Self {
+        Hashing::Default
+    }
+}
+
+fn crc32(key: &str) -> u64 {
+    let mut crc = !0u32;
+    for byte in key.bytes() {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    // Same as libmemcached, which uses only the upper half
+    u64::from((!crc >> 16) & 0x7fff)
+}
+
+fn fnv1a64(key: &str) -> u64 {
+    key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn default_pool_size() -> u32 {
+    4
+}
+
+fn default_health_check() -> bool {
+    true
+}
+
+/// Configuration of a memcached client.
+///
+/// # Fields
+///
+/// * `servers`: URLs of the servers (eg. `memcache://localhost:11211`, `memcache+udp://...` or
+///   `memcache:///run/memcached.sock`). At least one is needed. The order matters, as it
+///   determines which keys go where.
+/// * `hashing`: How keys are hashed, one of `default`, `crc32` and `fnv1a64`. Defaults to
+///   `default`.
+/// * `pool-size`: Maximum number of connections kept open to each server. Defaults to 4.
+/// * `read-timeout`, `write-timeout`: Time limits of network operations. Unlimited if not set.
+/// * `health-check`: Check all the servers respond before using a new configuration. Defaults to
+///   `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct MemcachePool {
+    /// URLs of the servers.
+    pub servers: Vec<String>,
+
+    /// How keys are hashed to pick a server.
+    #[serde(default)]
+    pub hashing: Hashing,
+
+    /// Maximum number of connections to each server.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+
+    /// Time limit of reads.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub read_timeout: Option<Duration>,
+
+    /// Time limit of writes.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub write_timeout: Option<Duration>,
+
+    /// Check the servers respond before using a new configuration.
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+}
+
+impl Default for MemcachePool {
+    fn default() -> Self {
+        MemcachePool {
+            servers: vec!["memcache://localhost:11211".to_owned()],
+            hashing: Hashing::default(),
+            pool_size: default_pool_size(),
+            read_timeout: None,
+            write_timeout: None,
+            health_check: default_health_check(),
+        }
+    }
+}
+
+impl MemcachePool {
+    /// Creates the client according to the configuration.
+    ///
+    /// This also runs the health check if configured.
+    pub fn create_client(&self) -> Result<Client, AnyError> {
+        if self.servers.is_empty() {
+            return Err("No memcached servers configured".into());
+        }
+        debug!("Creating memcached client for {:?}", self.servers);
+        let mut client = Client::with_pool_size(self.servers.clone(), self.pool_size)
+            .context("Failed to create memcached client")?;
+        match self.hashing {
+            Hashing::Default => (),
+            Hashing::Crc32 => client.hash_function = crc32,
+            Hashing::Fnv1a64 => client.hash_function = fnv1a64,
+        }
+        client
+            .set_read_timeout(self.read_timeout)
+            .context("Failed to set memcached read timeout")?;
+        client
+            .set_write_timeout(self.write_timeout)
+            .context("Failed to set memcached write timeout")?;
+        if self.health_check {
+            trace!("Checking health of the new memcached client");
+            probe(&client).context("Health check of the new memcached client failed")?;
+        }
+        Ok(client)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for MemcachePool {
+        type Driver = CacheEq<MemcachePool>;
+        type Resource = Client;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Client, AnyError> {
+            self.create_client()
+        }
+    }
+}
+
+fn probe(client: &Client) -> Result<(), AnyError> {
+    let versions = client.version()?;
+    trace!("Memcached versions: {:?}", versions);
+    Ok(())
+}
+
+/// A handle to the currently configured memcached client.
+///
+/// Cheap to clone, all the clones share the same client. It can be used as an [`Installer`] for
+/// the [`MemcachePool`] fragment.
+///
+/// # Panics
+///
+/// Accessing the client before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicMemcache(Arc<ArcSwapOption<Client>>);
+
+impl Debug for AtomicMemcache {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicMemcache").finish()
+    }
+}
+
+impl AtomicMemcache {
+    /// Creates an [`AtomicMemcache`] without any client inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the client inside.
+    pub fn replace(&self, client: Client) {
+        self.0.store(Some(Arc::new(client)));
+    }
+
+    /// Returns the current client.
+    pub fn client(&self) -> Arc<Client> {
+        self.0
+            .load_full()
+            .expect("Accessing memcached client before setting it up")
+    }
+
+    /// Checks all the servers respond, by asking for their versions.
+    pub fn probe(&self) -> Result<(), AnyError> {
+        probe(&self.client())
+    }
+}
+
+impl<O, C> Installer<Client, O, C> for AtomicMemcache {
+    type UninstallHandle = ();
+    fn install(&mut self, client: Client, name: &'static str) {
+        debug!("Installing memcached client {}", name);
+        self.replace(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_servers() {
+        let cfg = MemcachePool {
+            servers: Vec::new(),
+            ..MemcachePool::default()
+        };
+        assert!(cfg.create_client().is_err());
+    }
+
+    #[test]
+    fn hashes() {
+        // Reference values of the FNV-1a test suite
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a64(""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a64("a"));
+        // CRC32 of "123456789" is 0xCBF43926, libmemcached takes bits 16..31
+        assert_eq!(0x4bf4, crc32("123456789"));
+    }
+}
@@ -0,0 +1,472 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-mongodb/0.1.0/spirit_mongodb/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [MongoDB] clients.
+//!
+//! The [`MongoClient`] fragment wraps the driver's [`ClientOptions`] ‒ the connection URI plus
+//! the pool sizes, read and write concerns and TLS settings, which override whatever the URI
+//! says. The created client is installed into an [`AtomicMongo`] handle, which always holds the
+//! client of the current configuration.
+//!
+//! A new client is created whenever the configuration changes. Unless turned off, it is first
+//! checked by the same probe as is available through [`AtomicMongo::probe`] ‒ it selects a server
+//! and `ping`s it. If that fails, the new configuration is refused.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_mongodb::{AtomicMongo, MongoClient};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [mongo]
+//! uri = "mongodb://localhost:27017"
+//! max-pool-size = 20
+//! read-concern = "majority"
+//! health-check = false
+//!
+//! [mongo.write-concern]
+//! w = "majority"
+//! journal = true
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     mongo: MongoClient,
+//! }
+//!
+//! fn main() {
+//!     let mongo = AtomicMongo::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("mongo")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.mongo.clone())
+//!                 .install(mongo.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _db = mongo.client().database("app");
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [MongoDB]: https://crates.io/crates/mongodb
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::{debug, trace, warn};
+use mongodb::bson::doc;
+use mongodb::options::{Acknowledgment, ClientOptions, ReadConcern, Tls, TlsOptions, WriteConcern};
+use mongodb::Client;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_health_check() -> bool {
+    true
+}
+
+/// The read concern level.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+#[allow(missing_docs)]
+pub enum ReadConcernLevel {
+    Local,
+    Majority,
+    Linearizable,
+    Available,
+    Snapshot,
+}
+
+impl ReadConcernLevel {
+    fn concern(self) -> ReadConcern {
+        match self {
+            ReadConcernLevel::Local => ReadConcern::local(),
+            ReadConcernLevel::Majority => ReadConcern::majority(),
+            ReadConcernLevel::Linearizable => ReadConcern::linearizable(),
+            ReadConcernLevel::Available => ReadConcern::available(),
+            ReadConcernLevel::Snapshot => ReadConcern::snapshot(),
+        }
+    }
+}
+
+/// The write concern.
+///
+/// # Fields
+///
+/// * `w`: Which nodes need to acknowledge the write. Either `majority`, a number of nodes or a
+///   custom tag. Defaults to `nil` (the server default).
+/// * `journal`: Wait for the write to be journaled.
+/// * `timeout`: How long to wait for the acknowledgement.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct WriteConcernCfg {
+    /// Which nodes need to acknowledge the write.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<String>,
+
+    /// Wait for the write to be journaled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub journal: Option<bool>,
+
+    /// How long to wait for the acknowledgement.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+}
+
+impl WriteConcernCfg {
+    fn concern(&self) -> WriteConcern {
+        let mut concern = WriteConcern::default();
+        concern.w = self.w.as_ref().map(|w| {
+            if w == "majority" {
+                Acknowledgment::Majority
+            } else if let Ok(nodes) = w.parse() {
+                Acknowledgment::Nodes(nodes)
+            } else {
+                Acknowledgment::Custom(w.clone())
+            }
+        });
+        concern.journal = self.journal;
+        concern.w_timeout = self.timeout;
+        concern
+    }
+}
+
+/// The TLS settings.
+///
+/// # Fields
+///
+/// * `enabled`: Use TLS. Defaults to `true`.
+/// * `ca-file`: The certificate authority to check the server against.
+/// * `cert-key-file`: A file with the client certificate and its private key.
+/// * `allow-invalid-certificates`: Don't check the server certificate. Dangerous. Defaults to
+///   `false`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsCfg {
+    /// Use TLS.
+    #[serde(default = "default_tls_enabled")]
+    pub enabled: bool,
+
+    /// The certificate authority.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+
+    /// The client certificate and key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_key_file: Option<PathBuf>,
+
+    /// Don't check the server certificate.
+    #[serde(default)]
+    pub allow_invalid_certificates: bool,
+}
+
+fn default_tls_enabled() -> bool {
+    true
+}
+
+fn path_str(path: &Path) -> Result<String, AnyError> {
+    path.to_str()
+        .map(str::to_owned)
+        .ok_or_else(|| format!("Non-UTF8 path {}", path.display()).into())
+}
+
+impl TlsCfg {
+    fn tls(&self) -> Result<Tls, AnyError> {
+        if !self.enabled {
+            return Ok(Tls::Disabled);
+        }
+        let mut options = TlsOptions::default();
+        options.ca_file_path = self.ca_file.as_deref().map(path_str).transpose()?;
+        options.cert_key_file_path = self.cert_key_file.as_deref().map(path_str).transpose()?;
+        if self.allow_invalid_certificates {
+            warn!("Validation of MongoDB server certificates is turned off");
+            options.allow_invalid_certificates = Some(true);
+        }
+        Ok(Tls::Enabled(options))
+    }
+}
+
+/// Configuration of a MongoDB client.
+///
+/// Unset options are taken from the URI (or the driver defaults).
+///
+/// # Fields
+///
+/// * `uri`: The connection string (`mongodb://` or `mongodb+srv://`). Hidden when dumping the
+///   configuration.
+/// * `app-name`: The application name, as seen by the server.
+/// * `min-pool-size`: Minimum number of connections per server.
+/// * `max-pool-size`: Maximum number of connections per server.
+/// * `connect-timeout`: Timeout of establishing a connection.
+/// * `server-selection-timeout`: How long to wait for a suitable server for an operation.
+/// * `read-concern`: One of `local`, `majority`, `linearizable`, `available` and `snapshot`.
+/// * `write-concern`: See [`WriteConcernCfg`].
+/// * `tls`: See [`TlsCfg`].
+/// * `health-check`: Probe the servers when creating a new client. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct MongoClient {
+    /// The connection string.
+    pub uri: Hidden<String>,
+
+    /// The application name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+
+    /// Minimum number of connections per server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_pool_size: Option<u32>,
+
+    /// Maximum number of connections per server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_pool_size: Option<u32>,
+
+    /// Timeout of establishing a connection.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub connect_timeout: Option<Duration>,
+
+    /// How long to wait for a suitable server.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub server_selection_timeout: Option<Duration>,
+
+    /// The read concern level.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_concern: Option<ReadConcernLevel>,
+
+    /// The write concern.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write_concern: Option<WriteConcernCfg>,
+
+    /// The TLS settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsCfg>,
+
+    /// Probe the servers when creating a new client.
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+}
+
+impl Default for MongoClient {
+    fn default() -> Self {
+        MongoClient {
+            uri: Hidden("mongodb://localhost:27017".to_owned()),
+            app_name: None,
+            min_pool_size: None,
+            max_pool_size: None,
+            connect_timeout: None,
+            server_selection_timeout: None,
+            read_concern: None,
+            write_concern: None,
+            tls: None,
+            health_check: default_health_check(),
+        }
+    }
+}
+
+/// Runs the future to completion on a temporary runtime.
+///
+/// The clients are created outside of any runtime (during configuration loading), but parsing
+/// the URI (which may need DNS lookups) and the health check are asynchronous.
+fn block_on<F: Future>(fut: F) -> Result<F::Output, AnyError> {
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(fut))
+}
+
+async fn probe(client: &Client) -> Result<(), AnyError> {
+    client
+        .database("admin")
+        .run_command(doc! { "ping": 1 }, None)
+        .await
+        .context("MongoDB ping failed")?;
+    Ok(())
+}
+
+impl MongoClient {
+    /// Creates the driver options.
+    pub async fn options(&self) -> Result<ClientOptions, AnyError> {
+        let mut options = ClientOptions::parse(&self.uri)
+            .await
+            .context("Invalid MongoDB URI")?;
+        if let Some(name) = &self.app_name {
+            options.app_name = Some(name.clone());
+        }
+        if self.min_pool_size.is_some() {
+            options.min_pool_size = self.min_pool_size;
+        }
+        if self.max_pool_size.is_some() {
+            options.max_pool_size = self.max_pool_size;
+        }
+        if self.connect_timeout.is_some() {
+            options.connect_timeout = self.connect_timeout;
+        }
+        if self.server_selection_timeout.is_some() {
+            options.server_selection_timeout = self.server_selection_timeout;
+        }
+        if let Some(level) = self.read_concern {
+            options.read_concern = Some(level.concern());
+        }
+        if let Some(concern) = &self.write_concern {
+            options.write_concern = Some(concern.concern());
+        }
+        if let Some(tls) = &self.tls {
+            options.tls = Some(tls.tls()?);
+        }
+        Ok(options)
+    }
+
+    /// Creates the client, including the health check if configured.
+    pub fn create_client(&self) -> Result<Client, AnyError> {
+        block_on(async {
+            let options = self.options().await?;
+            debug!("Creating MongoDB client for {:?}", options.hosts);
+            let client = Client::with_options(options)?;
+            if self.health_check {
+                trace!("Checking health of the new MongoDB client");
+                probe(&client)
+                    .await
+                    .context("Health check of the new MongoDB client failed")?;
+            }
+            Ok(client)
+        })?
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for MongoClient {
+        type Driver = CacheEq<MongoClient>;
+        type Resource = Client;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Client, AnyError> {
+            self.create_client()
+        }
+    }
+}
+
+/// A handle to the current MongoDB client.
+///
+/// Cheap to clone, all the clones share the same client. It can be used as an [`Installer`] for
+/// the [`MongoClient`] fragment.
+///
+/// # Panics
+///
+/// Accessing the client before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicMongo(Arc<ArcSwapOption<Client>>);
+
+impl Debug for AtomicMongo {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicMongo").finish()
+    }
+}
+
+impl AtomicMongo {
+    /// Creates an [`AtomicMongo`] without any client inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the client inside.
+    pub fn replace(&self, client: Client) {
+        self.0.store(Some(Arc::new(client)));
+    }
+
+    /// Returns the current client.
+    ///
+    /// The [`Client`] is cheap to clone, so this returns an owned one.
+    pub fn client(&self) -> Client {
+        Client::clone(
+            self.0
+                .load()
+                .as_ref()
+                .expect("Accessing MongoDB client before setting it up"),
+        )
+    }
+
+    /// Checks a server is available, by selecting one and sending it a `ping`.
+    ///
+    /// This is suitable for health endpoints. It waits for the server at most the configured
+    /// `server-selection-timeout`.
+    pub async fn probe(&self) -> Result<(), AnyError> {
+        probe(&self.client()).await
+    }
+}
+
+impl<O, C> Installer<Client, O, C> for AtomicMongo {
+    type UninstallHandle = ();
+    fn install(&mut self, client: Client, name: &'static str) {
+        debug!("Installing MongoDB client {}", name);
+        self.replace(client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_concern() {
+        let cfg = WriteConcernCfg {
+            w: Some("majority".to_owned()),
+            journal: Some(true),
+            timeout: None,
+        };
+        let concern = cfg.concern();
+        assert_eq!(Some(Acknowledgment::Majority), concern.w);
+        assert_eq!(Some(true), concern.journal);
+        let cfg = WriteConcernCfg {
+            w: Some("2".to_owned()),
+            ..cfg
+        };
+        assert_eq!(Some(Acknowledgment::Nodes(2)), cfg.concern().w);
+    }
+
+    #[test]
+    fn invalid_uri() {
+        let cfg = MongoClient {
+            uri: Hidden("http://localhost".to_owned()),
+            health_check: false,
+            ..MongoClient::default()
+        };
+        assert!(cfg.create_client().is_err());
+    }
+}
@@ -0,0 +1,598 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-nats/0.1.0/spirit_nats/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [NATS] clients.
+//!
+//! The [`NatsClient`] fragment describes a connection to a NATS cluster, including the
+//! authentication, TLS and reconnect policy, and a set of subscriptions. It is installed into a
+//! [`NatsHandle`], which:
+//!
+//! * Holds the current connection, for publishing and requests.
+//! * Runs a thread for each configured subscription, passing the messages to the handler.
+//!
+//! Reconnecting after the connection is lost is done by the NATS client itself, according to the
+//! configured policy. When the configuration changes, a new connection is made and the old one is
+//! drained ‒ the messages already received are still handled before it is closed. The same
+//! happens on application shutdown.
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_nats::{NatsClient, NatsHandle};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [nats]
+//! servers = ["nats://localhost:4222"]
+//!
+//! [nats.subscriptions.orders]
+//! subject = "orders.*"
+//! queue-group = "workers"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     nats: NatsClient,
+//! }
+//!
+//! fn main() {
+//!     let nats = NatsHandle::new(|subscription, msg| {
+//!         println!("{}: {} bytes on {}", subscription, msg.data.len(), msg.subject);
+//!         Ok(())
+//!     });
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("nats")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.nats.clone())
+//!                 .install(nats.clone())
+//!         )
+//!         .run(move |_| {
+//!             nats.connection().publish("hello", "world")?;
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [NATS]: https://nats.io
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::{debug, error, info, warn};
+use nats::{Connection, Message, Options};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+use spirit_tls::TlsConfig;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+fn default_max_reconnects() -> Option<usize> {
+    Some(60)
+}
+
+fn default_reconnect_delay() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_reconnect_delay_cap() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Configuration of one subscription.
+///
+/// # Fields
+///
+/// * `subject`: The subject to subscribe to. May contain wildcards.
+/// * `queue-group`: Join this queue group ‒ each message is delivered to only one member of the
+///   group. Defaults to `nil` (a plain subscription).
+/// * `ack`: Acknowledge the messages after the handler succeeds (used with JetStream push
+///   consumers). Defaults to `false`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Subscription {
+    /// The subject to subscribe to.
+    pub subject: String,
+
+    /// The queue group to join.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub queue_group: Option<String>,
+
+    /// Acknowledge the handled messages.
+    #[serde(default)]
+    pub ack: bool,
+}
+
+/// Configuration of a NATS client.
+///
+/// # Fields
+///
+/// * `servers`: The server URLs (`nats://host:port` or `tls://host:port`).
+/// * `name`: The name of the connection, as seen by the server. Defaults to `nil`.
+/// * `credentials`: A file with the user credentials (JWT and NKey seed).
+/// * `token`: An authentication token. Hidden when dumping the configuration.
+/// * `user`, `password`: A user name and password. The password is hidden when dumping the
+///   configuration.
+/// * `tls`: Require TLS. Defaults to `false`.
+/// * `tls-ca`: An additional certificate authority to trust.
+/// * `tls-cert`, `tls-key`: A client certificate and its key.
+/// * `tls-root-cert`, `tls-client-cert`, `tls-client-key`: The older spellings of `tls-ca`,
+///   `tls-cert` and `tls-key`.
+///
+/// The `tls-ca`, `tls-cert` and `tls-key` options are shared with the other crates, see
+/// [`spirit-tls`][spirit_tls]. The rest of the shared TLS options are not supported and are
+/// refused. In particular, there's no `tls-ca-refresh`: the NATS client can't swap its
+/// authorities in place, so it's not among the stores re-read by
+/// [`refresh_all`][spirit_tls::trust::refresh_all]. A changed `tls-ca` file is picked up only
+/// when a configuration change reconnects the client.
+/// * `max-reconnects`: How many times to try reconnecting before giving up, `nil` for no limit.
+///   Defaults to `60`.
+/// * `reconnect-delay`: The delay after the first failed reconnect attempt. It grows linearly
+///   with each next one. Defaults to `100ms`.
+/// * `reconnect-delay-cap`: The maximal delay between reconnect attempts. Defaults to `5s`.
+/// * `reconnect-buffer-size`: How many bytes of published messages to keep while reconnecting.
+///   Defaults to `nil` (the client default).
+/// * `jetstream`: Check the server has JetStream enabled on connecting. Defaults to `false`.
+/// * `subscriptions`: The subscriptions, by the names passed to the handler. See
+///   [`Subscription`].
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct NatsClient {
+    /// The server URLs.
+    pub servers: Vec<String>,
+
+    /// The name of the connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    /// A credentials file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials: Option<PathBuf>,
+
+    /// An authentication token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<Hidden<String>>,
+
+    /// A user name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+
+    /// A password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Hidden<String>>,
+
+    /// Require TLS.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// The TLS options shared with the other spirit crates.
+    ///
+    /// Only the certificate authority and the client certificate with its key are supported.
+    #[serde(flatten)]
+    pub tls_config: TlsConfig,
+
+    /// An additional certificate authority (the older spelling of `tls-ca`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_root_cert: Option<PathBuf>,
+
+    /// A client certificate (the older spelling of `tls-cert`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_client_cert: Option<PathBuf>,
+
+    /// The key of the client certificate (the older spelling of `tls-key`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_client_key: Option<PathBuf>,
+
+    /// How many times to try reconnecting.
+    #[serde(default = "default_max_reconnects")]
+    pub max_reconnects: Option<usize>,
+
+    /// The delay after the first failed reconnect attempt.
+    #[serde(
+        default = "default_reconnect_delay",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub reconnect_delay: Duration,
+
+    /// The maximal delay between reconnect attempts.
+    #[serde(
+        default = "default_reconnect_delay_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub reconnect_delay_cap: Duration,
+
+    /// How many bytes of published messages to keep while reconnecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reconnect_buffer_size: Option<usize>,
+
+    /// Check JetStream is available.
+    #[serde(default)]
+    pub jetstream: bool,
+
+    /// The subscriptions.
+    #[serde(default)]
+    pub subscriptions: BTreeMap<String, Subscription>,
+}
+
+impl Default for NatsClient {
+    fn default() -> Self {
+        NatsClient {
+            servers: vec!["nats://localhost:4222".to_owned()],
+            name: None,
+            credentials: None,
+            token: None,
+            user: None,
+            password: None,
+            tls: false,
+            tls_config: TlsConfig::default(),
+            tls_root_cert: None,
+            tls_client_cert: None,
+            tls_client_key: None,
+            max_reconnects: default_max_reconnects(),
+            reconnect_delay: default_reconnect_delay(),
+            reconnect_delay_cap: default_reconnect_delay_cap(),
+            reconnect_buffer_size: None,
+            jetstream: false,
+            subscriptions: BTreeMap::new(),
+        }
+    }
+}
+
+impl NatsClient {
+    /// The delay before the given reconnect attempt (the first one is `1`).
+    pub fn reconnect_delay(&self, attempt: usize) -> Duration {
+        let attempt = cmp::min(attempt, u32::max_value() as usize) as u32;
+        self.reconnect_delay
+            .checked_mul(attempt)
+            .map(|delay| cmp::min(delay, self.reconnect_delay_cap))
+            .unwrap_or(self.reconnect_delay_cap)
+    }
+
+    /// Creates the client options according to the configuration.
+    pub fn options(&self) -> Result<Options, AnyError> {
+        let mut options = match (&self.credentials, &self.token, &self.user, &self.password) {
+            (Some(credentials), None, None, None) => Options::with_credentials(credentials),
+            (None, Some(token), None, None) => Options::with_token(token),
+            (None, None, Some(user), Some(password)) => Options::with_user_pass(user, password),
+            (None, None, None, None) => Options::new(),
+            _ => {
+                return Err("Exactly one of credentials, token or user+password may be set".into())
+            }
+        };
+        if let Some(name) = &self.name {
+            options = options.with_name(name);
+        }
+        options = options.tls_required(self.tls);
+        let tls = &self.tls_config;
+        if !tls.files_only() {
+            return Err("Only the tls-ca, tls-cert and tls-key TLS options work with NATS".into());
+        }
+        let root_cert = match (&tls.tls_ca, &self.tls_root_cert) {
+            (Some(_), Some(_)) => return Err("Can't set both tls-ca and tls-root-cert".into()),
+            (ca, root_cert) => ca.as_ref().or_else(|| root_cert.as_ref()),
+        };
+        if let Some(cert) = root_cert {
+            options = options.add_root_certificate(cert);
+        }
+        let client_cert = match (&tls.tls_cert, &tls.tls_key) {
+            (None, None) => (&self.tls_client_cert, &self.tls_client_key),
+            _ if self.tls_client_cert.is_some() || self.tls_client_key.is_some() => {
+                return Err("Can't mix tls-cert and tls-key with tls-client-cert".into());
+            }
+            (cert, key) => (cert, key),
+        };
+        match client_cert {
+            (Some(cert), Some(key)) => options = options.client_cert(cert, key),
+            (None, None) => (),
+            _ => return Err("Both the client certificate and its key need to be set".into()),
+        }
+        options = options.max_reconnects(self.max_reconnects);
+        if let Some(size) = self.reconnect_buffer_size {
+            options = options.reconnect_buffer_size(size);
+        }
+        let policy = self.clone();
+        options = options
+            .reconnect_delay_callback(move |attempt| policy.reconnect_delay(attempt))
+            .disconnect_callback(|| warn!("Disconnected from NATS"))
+            .reconnect_callback(|| info!("Reconnected to NATS"));
+        Ok(options)
+    }
+
+    /// Connects to the servers and subscribes the configured subscriptions.
+    pub fn connect(&self) -> Result<NatsConnection, AnyError> {
+        if self.servers.is_empty() {
+            return Err("No NATS servers configured".into());
+        }
+        let servers = self.servers.join(",");
+        debug!("Connecting to NATS at {}", servers);
+        let connection = self
+            .options()?
+            .connect(&servers)
+            .context("Failed to connect to NATS")?;
+        if self.jetstream {
+            connection
+                .request_timeout("$JS.API.INFO", "", Duration::from_secs(5))
+                .context("JetStream is not available on the NATS server")?;
+        }
+        let subscriptions = self
+            .subscriptions
+            .iter()
+            .map(|(name, sub)| {
+                let subscription = match &sub.queue_group {
+                    Some(group) => connection.queue_subscribe(&sub.subject, group),
+                    None => connection.subscribe(&sub.subject),
+                }
+                .with_context(|_| format!("Failed to subscribe to {}", sub.subject))?;
+                Ok((name.clone(), subscription, sub.ack))
+            })
+            .collect::<Result<Vec<_>, AnyError>>()?;
+        Ok(NatsConnection {
+            connection,
+            subscriptions,
+        })
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for NatsClient {
+        type Driver = CacheEq<NatsClient>;
+        type Resource = NatsConnection;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<NatsConnection, AnyError> {
+            self.connect()
+        }
+    }
+}
+
+/// An open connection with its subscriptions.
+///
+/// This is the resource created by the [`NatsClient`] fragment. The user should not need to
+/// interact with this directly.
+pub struct NatsConnection {
+    connection: Connection,
+    subscriptions: Vec<(String, nats::Subscription, bool)>,
+}
+
+impl Debug for NatsConnection {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let names = self
+            .subscriptions
+            .iter()
+            .map(|(name, _, _)| name)
+            .collect::<Vec<_>>();
+        fmt.debug_struct("NatsConnection")
+            .field("subscriptions", &names)
+            .finish()
+    }
+}
+
+/// The handler of messages, as used by [`NatsHandle`].
+///
+/// It gets the name of the subscription (the key in the configuration) and the message.
+pub type Handler = dyn Fn(&str, &Message) -> Result<(), AnyError> + Send + Sync;
+
+struct Running {
+    connection: Connection,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Running {
+    fn drain(&self) {
+        debug!("Draining NATS connection");
+        if let Err(e) = self.connection.drain() {
+            warn!("Failed to drain NATS connection: {}", e);
+        }
+        // The subscriptions end when drained
+        for thread in self.threads.lock().unwrap().drain(..) {
+            if thread.join().is_err() {
+                error!("NATS subscription thread panicked");
+            }
+        }
+    }
+}
+
+struct Inner {
+    handler: Arc<Handler>,
+    current: ArcSwapOption<Connection>,
+    running: Mutex<Vec<Arc<Running>>>,
+}
+
+/// A handle to the current NATS connection.
+///
+/// Cheap to clone, all the clones share the same connection. It is an [`Installer`] for the
+/// [`NatsClient`] fragment.
+///
+/// # Panics
+///
+/// Accessing the connection before one is set panics.
+#[derive(Clone)]
+pub struct NatsHandle(Arc<Inner>);
+
+impl Debug for NatsHandle {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("NatsHandle").finish()
+    }
+}
+
+impl NatsHandle {
+    /// Creates the handle, passing messages of the subscriptions to the handler.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&str, &Message) -> Result<(), AnyError> + Send + Sync + 'static,
+    {
+        NatsHandle(Arc::new(Inner {
+            handler: Arc::new(handler),
+            current: ArcSwapOption::empty(),
+            running: Mutex::new(Vec::new()),
+        }))
+    }
+
+    /// The current connection.
+    pub fn connection(&self) -> Connection {
+        Connection::clone(
+            self.0
+                .current
+                .load()
+                .as_ref()
+                .expect("Accessing NATS connection before setting it up"),
+        )
+    }
+
+    /// Drains all the connections installed through this handle.
+    ///
+    /// This happens automatically when the application terminates.
+    pub fn drain(&self) {
+        let running = self.0.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for running in running {
+            running.drain();
+        }
+    }
+}
+
+fn handle(name: &str, msg: &Message, ack: bool, handler: &Handler) {
+    match handler(name, msg) {
+        Ok(()) if ack && msg.reply.is_some() => {
+            if let Err(e) = msg.respond("+ACK") {
+                warn!("Failed to acknowledge NATS message on {}: {}", name, e);
+            }
+        }
+        Ok(()) => (),
+        Err(e) => error!(
+            "Failed to handle NATS message on {}: {}",
+            msg.subject,
+            e.display("; ")
+        ),
+    }
+}
+
+/// The running connection of one configuration.
+///
+/// This is the uninstall handle of [`NatsHandle`]. Dropping it drains the connection. The user
+/// should not need to interact with this directly.
+pub struct Drain(Arc<Running>);
+
+impl Debug for Drain {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Drain").finish()
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        self.0.drain();
+    }
+}
+
+impl<O, C> Installer<NatsConnection, O, C> for NatsHandle {
+    type UninstallHandle = Drain;
+    fn install(&mut self, conn: NatsConnection, name: &'static str) -> Drain {
+        debug!("Installing NATS connection {}", name);
+        let threads = conn
+            .subscriptions
+            .into_iter()
+            .filter_map(|(sub_name, subscription, ack)| {
+                let handler = Arc::clone(&self.0.handler);
+                let thread = thread::Builder::new()
+                    .name(format!("nats-{}", sub_name))
+                    .spawn(move || {
+                        for msg in subscription.messages() {
+                            handle(&sub_name, &msg, ack, &*handler);
+                        }
+                        debug!("NATS subscription {} terminated", sub_name);
+                    });
+                match thread {
+                    Ok(thread) => Some(thread),
+                    Err(e) => {
+                        error!("Failed to start NATS subscription thread: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        self.0
+            .current
+            .store(Some(Arc::new(conn.connection.clone())));
+        let running = Arc::new(Running {
+            connection: conn.connection,
+            threads: Mutex::new(threads),
+        });
+        let mut all = self.0.running.lock().unwrap();
+        // Forget the ones already uninstalled
+        all.retain(|r| Arc::strong_count(r) > 1);
+        all.push(Arc::clone(&running));
+        Drain(running)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.drain()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay() {
+        let cfg = NatsClient::default();
+        assert_eq!(Duration::from_millis(100), cfg.reconnect_delay(1));
+        assert_eq!(Duration::from_millis(300), cfg.reconnect_delay(3));
+        assert_eq!(Duration::from_secs(5), cfg.reconnect_delay(1000));
+    }
+
+    #[test]
+    fn auth_conflict() {
+        let cfg = NatsClient {
+            token: Some(Hidden("token".to_owned())),
+            user: Some("user".to_owned()),
+            ..NatsClient::default()
+        };
+        assert!(cfg.options().is_err());
+    }
+
+    #[test]
+    fn tls_spellings() {
+        let mut cfg = NatsClient::default();
+        cfg.tls_config.tls_cert = Some(PathBuf::from("/cert.pem"));
+        assert!(cfg.options().is_err());
+        cfg.tls_client_key = Some(PathBuf::from("/key.pem"));
+        assert!(cfg.options().is_err());
+        cfg.tls_client_key = None;
+        cfg.tls_config.tls_ca = Some(PathBuf::from("/ca.pem"));
+        cfg.tls_root_cert = Some(PathBuf::from("/ca.pem"));
+        assert!(cfg.options().is_err());
+    }
+}
@@ -0,0 +1,375 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-notify/0.1.0/spirit_notify/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Watching files listed in the configuration.
+//!
+//! The [`Watch`] fragment describes paths to watch for changes (through inotify, kqueue or
+//! whatever the OS provides, by the [notify] crate), optionally filtered by glob patterns. The
+//! events are debounced, so a file being written in several steps produces a single event.
+//!
+//! The watches are installed by a [`Watcher`], which passes the events to a callback. Each watch
+//! has its own thread. When the configuration changes, the watches are replaced, and the removed
+//! ones stop.
+//!
+//! This is useful to reload things that live outside of the configuration ‒ assets, GeoIP
+//! databases, TLS material and such.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_notify::{Event, Watch, Watcher};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [assets]
+//! paths = ["/tmp"]
+//! patterns = ["*.mmdb"]
+//! debounce = "1s"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     assets: Watch,
+//! }
+//!
+//! fn main() {
+//!     let watcher = Watcher::new(|name: &str, event: &Event| {
+//!         println!("{}: {:?}", name, event);
+//!     });
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("assets")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.assets.clone())
+//!                 .install(watcher)
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [notify]: https://crates.io/crates/notify
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use err_context::prelude::*;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::{debug, error, trace, warn};
+use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher as _};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::{Installer, Stackable};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_debounce() -> Duration {
+    Duration::from_millis(500)
+}
+
+/// Configuration of watched paths.
+///
+/// # Fields
+///
+/// * `paths`: The files and directories to watch. A directory is watched for changes of the files
+///   inside it.
+/// * `recursive`: Watch the directories including all their subdirectories. Defaults to `false`.
+/// * `patterns`: Glob patterns (eg. `*.pem`) to filter the events by. They are matched against the
+///   whole path of the changed file and `*` matches `/` too. If empty, all the events are
+///   reported.
+/// * `debounce`: How long to wait for the changes to settle before reporting them. Defaults to
+///   `500ms`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Watch {
+    /// The files and directories to watch.
+    pub paths: Vec<PathBuf>,
+
+    /// Watch the subdirectories too.
+    #[serde(default)]
+    pub recursive: bool,
+
+    /// Glob patterns to filter the events by.
+    #[serde(default)]
+    pub patterns: Vec<String>,
+
+    /// How long to wait for the changes to settle.
+    #[serde(
+        default = "default_debounce",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub debounce: Duration,
+}
+
+impl Default for Watch {
+    fn default() -> Self {
+        Watch {
+            paths: Vec::new(),
+            recursive: false,
+            patterns: Vec::new(),
+            debounce: default_debounce(),
+        }
+    }
+}
+
+impl Watch {
+    /// Checks the configuration and compiles the patterns.
+    pub fn spec(&self) -> Result<WatchSpec, AnyError> {
+        if self.paths.is_empty() {
+            return Err("No paths to watch".into());
+        }
+        let filter = if self.patterns.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in &self.patterns {
+                let glob = Glob::new(pattern)
+                    .with_context(|_| format!("Invalid watch pattern {}", pattern))?;
+                builder.add(glob);
+            }
+            Some(builder.build()?)
+        };
+        Ok(WatchSpec {
+            cfg: self.clone(),
+            filter,
+        })
+    }
+}
+
+impl Stackable for Watch {}
+
+spirit::simple_fragment! {
+    impl Fragment for Watch {
+        type Driver = CacheEq<Watch>;
+        type Resource = WatchSpec;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<WatchSpec, AnyError> {
+            self.spec()
+        }
+    }
+}
+
+/// A checked description of what to watch.
+///
+/// This is what the [`Watch`] fragment creates. The user should not need to interact with this
+/// directly.
+#[derive(Clone, Debug)]
+pub struct WatchSpec {
+    cfg: Watch,
+    filter: Option<GlobSet>,
+}
+
+impl WatchSpec {
+    fn matches(&self, path: &Path) -> bool {
+        self.filter
+            .as_ref()
+            .map(|filter| filter.is_match(path))
+            .unwrap_or(true)
+    }
+
+    fn watch(&self) -> Result<(RecommendedWatcher, Receiver<DebouncedEvent>), AnyError> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher: RecommendedWatcher =
+            notify::watcher(sender, self.cfg.debounce).context("Failed to create file watcher")?;
+        let mode = if self.cfg.recursive {
+            RecursiveMode::Recursive
+        } else {
+            RecursiveMode::NonRecursive
+        };
+        for path in &self.cfg.paths {
+            watcher
+                .watch(path, mode)
+                .with_context(|_| format!("Failed to watch {}", path.display()))?;
+        }
+        Ok((watcher, receiver))
+    }
+
+    /// Turns the event into ours, if it's interesting.
+    fn event(&self, event: DebouncedEvent) -> Option<Event> {
+        let event = match event {
+            DebouncedEvent::Create(path) => Event::Created(path),
+            DebouncedEvent::Write(path) | DebouncedEvent::Chmod(path) => Event::Changed(path),
+            DebouncedEvent::Remove(path) => Event::Removed(path),
+            DebouncedEvent::Rename(from, to) => {
+                return if self.matches(&from) || self.matches(&to) {
+                    Some(Event::Renamed(from, to))
+                } else {
+                    None
+                };
+            }
+            DebouncedEvent::Rescan => return Some(Event::Rescan),
+            // Notices come before the debounced events
+            DebouncedEvent::NoticeWrite(_) | DebouncedEvent::NoticeRemove(_) => return None,
+            DebouncedEvent::Error(e, path) => {
+                warn!("Error watching {:?}: {}", path, e);
+                return None;
+            }
+        };
+        if self.matches(event.path()) {
+            Some(event)
+        } else {
+            None
+        }
+    }
+}
+
+/// A change of a watched path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// A file or directory was created.
+    Created(PathBuf),
+    /// The content or metadata of a file changed.
+    Changed(PathBuf),
+    /// A file or directory was removed.
+    Removed(PathBuf),
+    /// A file or directory was renamed (from, to).
+    Renamed(PathBuf, PathBuf),
+    /// Some events might have been lost, everything watched should be checked.
+    Rescan,
+}
+
+impl Event {
+    fn path(&self) -> &Path {
+        match self {
+            Event::Created(path) | Event::Changed(path) | Event::Removed(path) => path,
+            Event::Renamed(_, to) => to,
+            Event::Rescan => Path::new(""),
+        }
+    }
+}
+
+type Callback = dyn Fn(&str, &Event) + Send + Sync;
+
+/// An active watch.
+///
+/// This is the uninstall handle of the [`Watcher`]. Dropping it stops watching. The user should
+/// not need to interact with this directly.
+pub struct ActiveWatch {
+    // Dropping the watcher closes the channel, which ends the thread
+    watcher: Option<RecommendedWatcher>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Debug for ActiveWatch {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ActiveWatch").finish()
+    }
+}
+
+impl Drop for ActiveWatch {
+    fn drop(&mut self) {
+        drop(self.watcher.take());
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("File watcher thread panicked");
+            }
+        }
+    }
+}
+
+/// The [`Installer`] of [`Watch`]es.
+///
+/// Passes the events of all the watches to the callback, together with the name of the pipeline.
+#[derive(Clone)]
+pub struct Watcher(Arc<Callback>);
+
+impl Debug for Watcher {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Watcher").finish()
+    }
+}
+
+impl Watcher {
+    /// Creates the installer, passing the events to the callback.
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(&str, &Event) + Send + Sync + 'static,
+    {
+        Watcher(Arc::new(callback))
+    }
+
+    fn start(&self, spec: WatchSpec, name: &'static str) -> Result<ActiveWatch, AnyError> {
+        let (watcher, receiver) = spec.watch()?;
+        let callback = Arc::clone(&self.0);
+        let thread = thread::Builder::new()
+            .name(format!("watch-{}", name))
+            .spawn(move || {
+                for event in receiver {
+                    trace!("Watch {} got {:?}", name, event);
+                    if let Some(event) = spec.event(event) {
+                        callback(name, &event);
+                    }
+                }
+                debug!("Stopped watching {}", name);
+            })?;
+        Ok(ActiveWatch {
+            watcher: Some(watcher),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl<O, C> Installer<WatchSpec, O, C> for Watcher {
+    type UninstallHandle = Option<ActiveWatch>;
+    fn install(&mut self, spec: WatchSpec, name: &'static str) -> Option<ActiveWatch> {
+        debug!("Watching {:?} for {}", spec.cfg.paths, name);
+        match self.start(spec, name) {
+            Ok(watch) => Some(watch),
+            Err(e) => {
+                error!("{}", e.display("; "));
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_paths() {
+        assert!(Watch::default().spec().is_err());
+    }
+
+    #[test]
+    fn filter() {
+        let cfg = Watch {
+            paths: vec![PathBuf::from("/tmp")],
+            patterns: vec!["*.pem".to_owned()],
+            ..Watch::default()
+        };
+        let spec = cfg.spec().unwrap();
+        assert_eq!(
+            Some(Event::Changed(PathBuf::from("/tmp/certs/a.pem"))),
+            spec.event(DebouncedEvent::Write(PathBuf::from("/tmp/certs/a.pem")))
+        );
+        assert_eq!(
+            None,
+            spec.event(DebouncedEvent::Write(PathBuf::from("/tmp/a.key")))
+        );
+        assert_eq!(Some(Event::Rescan), spec.event(DebouncedEvent::Rescan));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let cfg = Watch {
+            paths: vec![PathBuf::from("/tmp")],
+            patterns: vec!["[".to_owned()],
+            ..Watch::default()
+        };
+        assert!(cfg.spec().is_err());
+    }
+}
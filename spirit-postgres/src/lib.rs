@@ -0,0 +1,402 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-postgres/0.1.0/spirit_postgres/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! A managed [tokio-postgres] client.
+//!
+//! Unlike a connection pool, this keeps a single connection to the database, configured by the
+//! [`PgClient`] fragment. The [`ManagedClient`] handle takes care of keeping it alive:
+//!
+//! * If the connection is lost (or can't be established in the first place), it is reconnected
+//!   with an exponential backoff.
+//! * Statements registered through [`ManagedClient::register`] are prepared again on each new
+//!   connection (prepared statements belong to a connection and don't survive its loss).
+//! * The connection `LISTEN`s to the channels from the configuration and the notifications are
+//!   passed to the [`subscribers`][ManagedClient::subscribe].
+//!
+//! When the configuration changes, a new connection is made and the old one is closed.
+//!
+//! The connections don't use TLS.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_postgres::{ManagedClient, PgClient};
+//! use tokio::runtime::Runtime;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [db]
+//! connection = "host=localhost user=postgres dbname=app"
+//! listen = ["jobs"]
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     db: PgClient,
+//! }
+//!
+//! fn main() {
+//!     let runtime = Runtime::new().unwrap();
+//!     let client = ManagedClient::new(runtime.handle().clone());
+//!     client.register("job", "SELECT payload FROM jobs WHERE id = $1");
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("postgres")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.db.clone())
+//!                 .install(client.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _notifications = client.subscribe();
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [tokio-postgres]: https://crates.io/crates/tokio-postgres
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use futures::future::{self, Either};
+use futures::stream::{self, StreamExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::retry::Backoff;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, oneshot};
+use tokio_postgres::{AsyncMessage, Client, Config, Error, NoTls, Notification, Statement};
+
+fn default_backoff_base() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_backoff_cap() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// How many notifications may wait for a slow subscriber before it starts losing them.
+const NOTIFICATION_BUFFER: usize = 128;
+
+/// Configuration of a managed PostgreSQL connection.
+///
+/// # Fields
+///
+/// * `connection`: The connection string, either in the `key=value` or the URL form. Hidden
+///   when dumping the configuration, as it may contain a password.
+/// * `backoff-base`: The delay before the first reconnect attempt. Each next one doubles it.
+///   Defaults to `100ms`.
+/// * `backoff-cap`: The maximal delay between reconnect attempts. Defaults to `30s`.
+/// * `listen`: Channels to `LISTEN` to. Defaults to none.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct PgClient {
+    /// The connection string.
+    pub connection: Hidden<String>,
+
+    /// The delay before the first reconnect attempt.
+    #[serde(
+        default = "default_backoff_base",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_base: Duration,
+
+    /// The maximal delay between reconnect attempts.
+    #[serde(
+        default = "default_backoff_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_cap: Duration,
+
+    /// Channels to listen to.
+    #[serde(default)]
+    pub listen: Vec<String>,
+}
+
+impl Default for PgClient {
+    fn default() -> Self {
+        PgClient {
+            connection: Hidden(String::new()),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+            listen: Vec::new(),
+        }
+    }
+}
+
+impl PgClient {
+    /// The delays between reconnect attempts.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.backoff_base, self.backoff_cap)
+    }
+
+    /// Checks the configuration and turns it into a [`PgConnector`].
+    pub fn connector(&self) -> Result<PgConnector, AnyError> {
+        let config = self
+            .connection
+            .parse::<Config>()
+            .context("Invalid postgres connection string")?;
+        Ok(PgConnector {
+            cfg: self.clone(),
+            config,
+        })
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for PgClient {
+        type Driver = CacheEq<PgClient>;
+        type Resource = PgConnector;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<PgConnector, AnyError> {
+            self.connector()
+        }
+    }
+}
+
+/// A validated [`PgClient`], ready to connect.
+///
+/// This is the resource created by the [`PgClient`] fragment. The user should not need to
+/// interact with this directly.
+#[derive(Clone, Debug)]
+pub struct PgConnector {
+    cfg: PgClient,
+    config: Config,
+}
+
+/// Quotes an identifier (eg. a channel name) for use in a query.
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+struct Inner {
+    handle: Handle,
+    client: ArcSwapOption<Client>,
+    registered: Mutex<HashMap<String, String>>,
+    prepared: Mutex<HashMap<String, Statement>>,
+    notifications: broadcast::Sender<Notification>,
+}
+
+/// A handle to the managed connection.
+///
+/// Cheap to clone, all the clones share the same connection. It is an [`Installer`] for the
+/// [`PgClient`] fragment. The connection is maintained by a task spawned onto the runtime passed
+/// to [`new`][ManagedClient::new].
+#[derive(Clone)]
+pub struct ManagedClient(Arc<Inner>);
+
+impl Debug for ManagedClient {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ManagedClient")
+            .field("connected", &self.client().is_some())
+            .finish()
+    }
+}
+
+impl ManagedClient {
+    /// Creates the handle, without any connection yet.
+    ///
+    /// The connection tasks are spawned onto the runtime behind the `handle`.
+    pub fn new(handle: Handle) -> Self {
+        let (notifications, _) = broadcast::channel(NOTIFICATION_BUFFER);
+        ManagedClient(Arc::new(Inner {
+            handle,
+            client: ArcSwapOption::empty(),
+            registered: Mutex::new(HashMap::new()),
+            prepared: Mutex::new(HashMap::new()),
+            notifications,
+        }))
+    }
+
+    /// The current client.
+    ///
+    /// Returns `None` if there's no connection at the moment (it was lost and is being
+    /// reconnected, or there was no configuration yet).
+    pub fn client(&self) -> Option<Arc<Client>> {
+        self.0.client.load_full()
+    }
+
+    /// Registers a statement to be prepared on each connection.
+    ///
+    /// If already connected, the statement is prepared when first asked for by
+    /// [`statement`][ManagedClient::statement].
+    pub fn register<N: Into<String>, S: Into<String>>(&self, name: N, sql: S) {
+        let name = name.into();
+        self.0.prepared.lock().unwrap().remove(&name);
+        self.0.registered.lock().unwrap().insert(name, sql.into());
+    }
+
+    /// Returns the prepared statement of the given name on the current connection.
+    ///
+    /// # Errors
+    ///
+    /// If there's no connection, no such statement was [registered][ManagedClient::register] or
+    /// the statement can't be prepared.
+    pub async fn statement(&self, name: &str) -> Result<Statement, AnyError> {
+        if let Some(statement) = self.0.prepared.lock().unwrap().get(name) {
+            return Ok(statement.clone());
+        }
+        let sql = self
+            .0
+            .registered
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("Statement {} is not registered", name))?;
+        let client = self.client().ok_or("Not connected to the database")?;
+        let statement = client
+            .prepare(&sql)
+            .await
+            .with_context(|_| format!("Failed to prepare statement {}", name))?;
+        self.0
+            .prepared
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), statement.clone());
+        Ok(statement)
+    }
+
+    /// Subscribes to the notifications from the channels in the configuration.
+    ///
+    /// Only notifications arriving after the subscription are received.
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.0.notifications.subscribe()
+    }
+
+    async fn setup(&self, client: Client, cfg: &PgClient) -> Result<(), Error> {
+        for channel in &cfg.listen {
+            debug!("Listening to postgres channel {}", channel);
+            client
+                .batch_execute(&format!("LISTEN {}", quote_ident(channel)))
+                .await?;
+        }
+        let registered = self.0.registered.lock().unwrap().clone();
+        let mut prepared = HashMap::with_capacity(registered.len());
+        for (name, sql) in registered {
+            prepared.insert(name, client.prepare(&sql).await?);
+        }
+        *self.0.prepared.lock().unwrap() = prepared;
+        self.0.client.store(Some(Arc::new(client)));
+        Ok(())
+    }
+
+    fn disconnected(&self) {
+        self.0.client.store(None);
+        self.0.prepared.lock().unwrap().clear();
+    }
+
+    async fn supervise(self, connector: PgConnector, mut shutdown: oneshot::Receiver<()>) {
+        let cfg = &connector.cfg;
+        let mut attempt = 0;
+        loop {
+            match connector.config.connect(NoTls).await {
+                Ok((client, mut connection)) => {
+                    info!("Connected to postgres");
+                    attempt = 0;
+                    let notifications = self.0.notifications.clone();
+                    let driver = async move {
+                        let mut messages = stream::poll_fn(move |ctx| connection.poll_message(ctx));
+                        while let Some(message) = messages.next().await {
+                            if let AsyncMessage::Notification(notification) = message? {
+                                // Nobody listening is fine
+                                let _ = notifications.send(notification);
+                            }
+                        }
+                        Ok::<_, Error>(())
+                    };
+                    let driver = self.0.handle.spawn(driver);
+                    if let Err(e) = self.setup(client, cfg).await {
+                        error!("Failed to set up postgres connection: {}", e);
+                    }
+                    match future::select(driver, &mut shutdown).await {
+                        Either::Left((Ok(Ok(())), _)) => warn!("Postgres connection closed"),
+                        Either::Left((Ok(Err(e)), _)) => error!("Postgres connection lost: {}", e),
+                        Either::Left((Err(e), _)) => error!("Postgres connection panicked: {}", e),
+                        Either::Right(_) => {
+                            // Dropping the client closes the connection and ends the driver.
+                            debug!("Closing postgres connection");
+                            self.disconnected();
+                            return;
+                        }
+                    }
+                    self.disconnected();
+                }
+                Err(e) => error!("Failed to connect to postgres: {}", e),
+            }
+            let delay = cfg.backoff().delay(attempt);
+            attempt += 1;
+            debug!("Reconnecting to postgres in {:?}", delay);
+            let sleep = tokio::time::delay_for(delay);
+            if let Either::Right(_) = future::select(sleep, &mut shutdown).await {
+                return;
+            }
+        }
+    }
+}
+
+/// Stops the connection task of one configuration once dropped.
+///
+/// This is the uninstall handle of [`ManagedClient`]. The user should not need to interact with
+/// this directly.
+pub struct Supervisor {
+    _shutdown: oneshot::Sender<()>,
+}
+
+impl Debug for Supervisor {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Supervisor").finish()
+    }
+}
+
+impl<O, C> Installer<PgConnector, O, C> for ManagedClient {
+    type UninstallHandle = Supervisor;
+    fn install(&mut self, connector: PgConnector, name: &'static str) -> Supervisor {
+        debug!("Installing postgres connection {}", name);
+        let (shutdown, shutdown_recv) = oneshot::channel();
+        let supervisor = self.clone().supervise(connector, shutdown_recv);
+        self.0.handle.spawn(supervisor);
+        Supervisor {
+            _shutdown: shutdown,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        assert_eq!("\"jobs\"", quote_ident("jobs"));
+        assert_eq!("\"a\"\"b\"", quote_ident("a\"b"));
+    }
+
+    #[test]
+    fn invalid_connection() {
+        let cfg = PgClient {
+            connection: Hidden("postgres://localhost:notaport/db".to_owned()),
+            ..PgClient::default()
+        };
+        assert!(cfg.connector().is_err());
+    }
+}
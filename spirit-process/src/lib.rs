@@ -0,0 +1,533 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-process/0.1.0/spirit_process/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Supervising child processes described in the configuration.
+//!
+//! The [`ChildProcess`] fragment describes a helper process (a sidecar) the application needs
+//! running next to it ‒ the command, its arguments, environment and working directory, what to
+//! do when it exits and how to stop it. Each configured process gets a supervisor thread that
+//! starts it, waits for it and restarts it according to the [`Restart`] policy, with an
+//! exponential backoff between the restarts.
+//!
+//! When the configuration of a process changes, the old process is stopped and a new one is
+//! started. Processes removed from the configuration are stopped. All of them are stopped when
+//! the application terminates.
+//!
+//! Stopping sends the configured signal (`SIGTERM` by default) and waits for the grace period.
+//! If the process is still alive after that, it is killed. On non-unix systems the process is
+//! killed right away.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_process::ChildProcess;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [[sidecars]]
+//! command = "sleep"
+//! args = ["3600"]
+//! restart = "always"
+//! grace-period = "2s"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     sidecars: Vec<ChildProcess>,
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("sidecars")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.sidecars.clone())
+//!         )
+//!         .run(|spirit| {
+//! #           let spirit = std::sync::Arc::clone(spirit);
+//! #           std::thread::spawn(move || spirit.terminate());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use err_context::prelude::*;
+use log::{debug, error, info, warn};
+#[cfg(unix)]
+use nix::sys::signal::{self, Signal};
+#[cfg(unix)]
+use nix::unistd::Pid;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::retry::Backoff;
+use spirit::fragment::{Fragment, Installer, Stackable};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+/// How often the supervisor checks on the process.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// When to restart a process that exited.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    /// Never, the process runs only once.
+    Never,
+    /// Only if it failed (exited with non-zero code or was killed by a signal).
+    OnFailure,
+    /// Always, even if it exited successfully.
+    Always,
+}
+
+impl Default for Restart {
+    fn default() -> Self {
+        Restart::OnFailure
+    }
+}
+
+impl Restart {
+    fn should_restart(self, status: Option<ExitStatus>) -> bool {
+        match self {
+            Restart::Never => false,
+            Restart::OnFailure => status.map(|s| !s.success()).unwrap_or(true),
+            Restart::Always => true,
+        }
+    }
+}
+
+fn default_backoff_base() -> Duration {
+    Duration::from_secs(1)
+}
+
+fn default_backoff_cap() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_stop_signal() -> String {
+    "SIGTERM".to_owned()
+}
+
+fn default_grace_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// Configuration of a supervised child process.
+///
+/// # Fields
+///
+/// * `command`: The program to run. Looked up in `PATH` if it is not a path.
+/// * `args`: The arguments of the program.
+/// * `env`: Environment variables to set for the process (in addition to the inherited ones).
+/// * `clear-env`: Don't inherit the environment of the application. Defaults to `false`.
+/// * `working-dir`: The working directory of the process. Inherited if not set.
+/// * `restart`: When to restart the process after it exits, one of `never`, `on-failure` and
+///   `always`. Defaults to `on-failure`.
+/// * `backoff-base`: The delay before the first restart, doubled with each further one. Defaults
+///   to `1s`. The backoff starts anew when the process runs for at least `backoff-cap`.
+/// * `backoff-cap`: The maximum delay between restarts. Defaults to `60s`.
+/// * `stop-signal`: The signal to ask the process to stop with (unix only). Defaults to
+///   `SIGTERM`.
+/// * `grace-period`: How long to wait after the stop signal before killing the process. Defaults
+///   to `10s`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct ChildProcess {
+    /// The program to run.
+    pub command: PathBuf,
+
+    /// The arguments of the program.
+    #[serde(default)]
+    pub args: Vec<String>,
+
+    /// Environment variables to set.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+
+    /// Don't inherit the environment.
+    #[serde(default)]
+    pub clear_env: bool,
+
+    /// The working directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub working_dir: Option<PathBuf>,
+
+    /// When to restart the process.
+    #[serde(default)]
+    pub restart: Restart,
+
+    /// The delay before the first restart.
+    #[serde(
+        default = "default_backoff_base",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_base: Duration,
+
+    /// The maximum delay between restarts.
+    #[serde(
+        default = "default_backoff_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_cap: Duration,
+
+    /// The signal to stop the process with.
+    #[serde(default = "default_stop_signal")]
+    pub stop_signal: String,
+
+    /// How long to wait before killing the process.
+    #[serde(
+        default = "default_grace_period",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub grace_period: Duration,
+}
+
+impl Default for ChildProcess {
+    fn default() -> Self {
+        ChildProcess {
+            command: PathBuf::from("true"),
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            clear_env: false,
+            working_dir: None,
+            restart: Restart::default(),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+            stop_signal: default_stop_signal(),
+            grace_period: default_grace_period(),
+        }
+    }
+}
+
+impl ChildProcess {
+    /// The delays between restarts.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.backoff_base, self.backoff_cap)
+    }
+
+    /// The command to start the process with.
+    pub fn command(&self) -> Command {
+        let mut command = Command::new(&self.command);
+        command.args(&self.args);
+        if self.clear_env {
+            command.env_clear();
+        }
+        command.envs(&self.env);
+        if let Some(dir) = &self.working_dir {
+            command.current_dir(dir);
+        }
+        command
+    }
+
+    /// Checks the configuration and turns it into a [`ProcessSpec`].
+    pub fn spec(&self) -> Result<ProcessSpec, AnyError> {
+        if self.command.as_os_str().is_empty() {
+            return Err("No command to run".into());
+        }
+        #[cfg(unix)]
+        let stop_signal = self
+            .stop_signal
+            .parse::<Signal>()
+            .with_context(|_| format!("Invalid stop signal {}", self.stop_signal))?;
+        Ok(ProcessSpec {
+            cfg: self.clone(),
+            #[cfg(unix)]
+            stop_signal,
+        })
+    }
+}
+
+impl Stackable for ChildProcess {}
+
+impl Fragment for ChildProcess {
+    type Driver = CacheEq<ChildProcess>;
+    type Installer = Supervisor;
+    type Seed = ();
+    type Resource = ProcessSpec;
+    fn make_seed(&self, _: &'static str) -> Result<(), AnyError> {
+        Ok(())
+    }
+    fn make_resource(&self, _: &mut (), _: &'static str) -> Result<ProcessSpec, AnyError> {
+        self.spec()
+    }
+}
+
+/// A checked description of a process to run.
+///
+/// This is what the [`ChildProcess`] fragment creates. The user should not need to interact with
+/// this directly.
+#[derive(Clone, Debug)]
+pub struct ProcessSpec {
+    cfg: ChildProcess,
+    #[cfg(unix)]
+    stop_signal: Signal,
+}
+
+impl ProcessSpec {
+    /// Asks the process to stop, waits for the grace period and kills it if it's still there.
+    fn stop(&self, name: &str, child: &mut Child) {
+        #[cfg(unix)]
+        {
+            let pid = Pid::from_raw(child.id() as i32);
+            match signal::kill(pid, self.stop_signal) {
+                Ok(()) => {
+                    let deadline = Instant::now() + self.cfg.grace_period;
+                    while Instant::now() < deadline {
+                        match child.try_wait() {
+                            Ok(Some(status)) => {
+                                debug!("Process {} stopped: {}", name, status);
+                                return;
+                            }
+                            Ok(None) => thread::sleep(POLL_INTERVAL),
+                            Err(e) => {
+                                warn!("Failed to check process {}: {}", name, e);
+                                break;
+                            }
+                        }
+                    }
+                    warn!(
+                        "Process {} didn't stop in {:?}, killing it",
+                        name, self.cfg.grace_period
+                    );
+                }
+                Err(e) => warn!("Failed to signal process {}: {}", name, e),
+            }
+        }
+        if let Err(e) = child.kill() {
+            debug!("Failed to kill process {}: {}", name, e);
+        }
+        if let Err(e) = child.wait() {
+            warn!("Failed to wait for process {}: {}", name, e);
+        }
+    }
+}
+
+/// Sleeps for the duration, waking up early if asked to stop.
+///
+/// Returns if it should stop.
+fn sleep(duration: Duration, stop: &AtomicBool) -> bool {
+    let deadline = Instant::now() + duration;
+    while !stop.load(Ordering::Relaxed) {
+        let now = Instant::now();
+        if now >= deadline {
+            return false;
+        }
+        thread::sleep(cmp::min(deadline - now, POLL_INTERVAL));
+    }
+    true
+}
+
+fn supervise(name: &str, spec: &ProcessSpec, stop: &AtomicBool) {
+    let mut attempt = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let started = Instant::now();
+        let status = match spec.cfg.command().spawn() {
+            Ok(mut child) => {
+                info!("Started process {} ({})", name, child.id());
+                loop {
+                    if stop.load(Ordering::Relaxed) {
+                        debug!("Stopping process {}", name);
+                        spec.stop(name, &mut child);
+                        return;
+                    }
+                    match child.try_wait() {
+                        Ok(Some(status)) => break Some(status),
+                        Ok(None) => thread::sleep(POLL_INTERVAL),
+                        Err(e) => {
+                            error!("Failed to check process {}: {}", name, e);
+                            spec.stop(name, &mut child);
+                            break None;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to start process {}: {}", name, e);
+                None
+            }
+        };
+        match status {
+            Some(status) if status.success() => info!("Process {} exited", name),
+            Some(status) => warn!("Process {} failed: {}", name, status),
+            None => (),
+        }
+        if !spec.cfg.restart.should_restart(status) {
+            debug!("Not restarting process {}", name);
+            return;
+        }
+        if started.elapsed() >= spec.cfg.backoff_cap {
+            attempt = 0;
+        }
+        let delay = spec.cfg.backoff().delay(attempt);
+        attempt += 1;
+        debug!("Restarting process {} in {:?}", name, delay);
+        if sleep(delay, stop) {
+            return;
+        }
+    }
+}
+
+struct Task {
+    stop: AtomicBool,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Task {
+    fn shutdown(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if thread.join().is_err() {
+                error!("Process supervisor thread panicked");
+            }
+        }
+    }
+}
+
+/// A supervised process.
+///
+/// This is the uninstall handle of the [`Supervisor`]. Dropping it stops the process (and waits
+/// for it to stop). The user should not need to interact with this directly.
+pub struct RunningProcess(Arc<Task>);
+
+impl Debug for RunningProcess {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("RunningProcess").finish()
+    }
+}
+
+impl Drop for RunningProcess {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// The [`Installer`] of [`ChildProcess`]es.
+///
+/// Starts a supervisor thread for each process. All the processes are stopped when the
+/// application terminates. This is the default installer of the fragment, so there's no need to
+/// provide it explicitly.
+#[derive(Clone, Default)]
+pub struct Supervisor {
+    running: Arc<Mutex<Vec<Weak<Task>>>>,
+}
+
+impl Debug for Supervisor {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Supervisor").finish()
+    }
+}
+
+impl Supervisor {
+    /// Stops all the processes started by this supervisor.
+    pub fn shutdown(&self) {
+        let running = self.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        // Ask all of them first, so their grace periods run in parallel
+        let running = running
+            .iter()
+            .filter_map(Weak::upgrade)
+            .inspect(|task| task.stop.store(true, Ordering::Relaxed))
+            .collect::<Vec<_>>();
+        for task in running {
+            task.shutdown();
+        }
+    }
+}
+
+impl<O, C> Installer<ProcessSpec, O, C> for Supervisor {
+    type UninstallHandle = RunningProcess;
+    fn install(&mut self, spec: ProcessSpec, name: &'static str) -> RunningProcess {
+        let task = Arc::new(Task {
+            stop: AtomicBool::new(false),
+            thread: Mutex::new(None),
+        });
+        let thread_task = Arc::clone(&task);
+        let thread = thread::Builder::new()
+            .name(format!("supervise-{}", name))
+            .spawn(move || supervise(name, &spec, &thread_task.stop));
+        match thread {
+            Ok(thread) => *task.thread.lock().unwrap() = Some(thread),
+            Err(e) => error!("Failed to start supervisor of process {}: {}", name, e),
+        }
+        let mut running = self.running.lock().unwrap();
+        running.retain(|task| task.strong_count() > 0);
+        running.push(Arc::downgrade(&task));
+        RunningProcess(task)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn invalid_signal() {
+        let cfg = ChildProcess {
+            stop_signal: "SIGNOPE".to_owned(),
+            ..ChildProcess::default()
+        };
+        assert!(cfg.spec().is_err());
+    }
+
+    #[test]
+    fn restart_policy() {
+        assert!(!Restart::Never.should_restart(None));
+        assert!(Restart::OnFailure.should_restart(None));
+        assert!(Restart::Always.should_restart(None));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stops_on_drop() {
+        let cfg = ChildProcess {
+            command: PathBuf::from("sleep"),
+            args: vec!["60".to_owned()],
+            ..ChildProcess::default()
+        };
+        let mut supervisor = Supervisor::default();
+        let running =
+            Installer::<_, (), ()>::install(&mut supervisor, cfg.spec().unwrap(), "sleep");
+        thread::sleep(Duration::from_millis(200));
+        let start = Instant::now();
+        drop(running);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
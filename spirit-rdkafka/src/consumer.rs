@@ -0,0 +1,299 @@
+//! Kafka consumers.
+//!
+//! The [`KafkaConsumer`] fragment creates a consumer subscribed to the configured topics. It is
+//! installed by the [`ConsumerInstaller`], which starts a thread for each consumer and feeds the
+//! received messages into a handler.
+//!
+//! The offsets are stored after the handler finishes with a message and are committed
+//! periodically (by librdkafka, according to `auto.commit.interval.ms`) and once more when the
+//! consumer stops. The consumer stops when it is removed from the configuration, when the
+//! configuration changes (a new consumer is started in its place) and when the application
+//! terminates. Therefore, a message is handled at least once, but it may be handled again after a
+//! crash.
+//!
+//! A message whose handler fails is logged and skipped.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use err_context::prelude::*;
+use log::{debug, error, info, trace, warn};
+use rdkafka::consumer::{BaseConsumer, CommitMode, Consumer as _, ConsumerContext, Rebalance};
+use rdkafka::message::{BorrowedMessage, Message};
+use rdkafka::ClientContext;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+use crate::KafkaConnection;
+
+/// How often the consumer thread checks if it should stop.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The consumer created by the [`KafkaConsumer`] fragment.
+pub type Consumer = BaseConsumer<SpiritContext>;
+
+/// The handler of messages, as used by [`ConsumerInstaller`].
+pub type Handler = dyn Fn(&BorrowedMessage) -> Result<(), AnyError> + Send + Sync;
+
+/// Where to start reading if the group has no committed offset yet.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum OffsetReset {
+    /// From the oldest message available.
+    Earliest,
+    /// Only new messages.
+    Latest,
+}
+
+impl Default for OffsetReset {
+    fn default() -> Self {
+        OffsetReset::Latest
+    }
+}
+
+/// Configuration of a Kafka consumer.
+///
+/// # Fields
+///
+/// All the fields of [`KafkaConnection`], plus:
+///
+/// * `group-id`: The consumer group to join.
+/// * `topics`: The topics to subscribe to.
+/// * `offset-reset`: Where to start if the group has no committed offset, either `earliest` or
+///   `latest`. Defaults to `latest`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct KafkaConsumer {
+    /// The connection to the brokers.
+    #[serde(flatten)]
+    pub connection: KafkaConnection,
+
+    /// The consumer group to join.
+    pub group_id: String,
+
+    /// The topics to subscribe to.
+    pub topics: Vec<String>,
+
+    /// Where to start if the group has no committed offset.
+    #[serde(default)]
+    pub offset_reset: OffsetReset,
+}
+
+impl KafkaConsumer {
+    /// Creates the consumer and subscribes it to the topics.
+    pub fn create_consumer(&self) -> Result<Consumer, AnyError> {
+        if self.topics.is_empty() {
+            return Err("No kafka topics to consume".into());
+        }
+        debug!(
+            "Creating kafka consumer in group {} for {:?}",
+            self.group_id, self.topics
+        );
+        let mut config = self.connection.client_config()?;
+        let offset_reset = match self.offset_reset {
+            OffsetReset::Earliest => "earliest",
+            OffsetReset::Latest => "latest",
+        };
+        config
+            .set("group.id", &self.group_id)
+            .set("auto.offset.reset", offset_reset)
+            // Store offsets only after the handler is done with the message
+            .set("enable.auto.offset.store", "false");
+        let consumer: Consumer = config
+            .create_with_context(SpiritContext)
+            .context("Failed to create kafka consumer")?;
+        let topics = self.topics.iter().map(String::as_str).collect::<Vec<_>>();
+        consumer
+            .subscribe(&topics)
+            .context("Failed to subscribe to kafka topics")?;
+        Ok(consumer)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for KafkaConsumer {
+        type Driver = CacheEq<KafkaConsumer>;
+        type Resource = Consumer;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Consumer, AnyError> {
+            self.create_consumer()
+        }
+    }
+}
+
+/// The context of the consumers, logging the group rebalances.
+///
+/// The user should not need to interact with this directly.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpiritContext;
+
+impl ClientContext for SpiritContext {}
+
+impl ConsumerContext for SpiritContext {
+    fn pre_rebalance(&self, rebalance: &Rebalance) {
+        info!("Kafka consumer group rebalancing: {:?}", rebalance);
+    }
+    fn post_rebalance(&self, rebalance: &Rebalance) {
+        debug!("Kafka consumer group rebalanced: {:?}", rebalance);
+    }
+}
+
+fn consume(name: &str, consumer: Consumer, handler: &Handler, stop: &AtomicBool) {
+    while !stop.load(Ordering::Relaxed) {
+        match consumer.poll(POLL_INTERVAL) {
+            None => (),
+            Some(Err(e)) => warn!("Kafka consumer {} error: {}", name, e),
+            Some(Ok(msg)) => {
+                trace!(
+                    "Kafka consumer {} got message {}:{}@{}",
+                    name,
+                    msg.topic(),
+                    msg.partition(),
+                    msg.offset()
+                );
+                if let Err(e) = handler(&msg) {
+                    error!(
+                        "Failed to handle kafka message {}:{}@{}, skipping: {}",
+                        msg.topic(),
+                        msg.partition(),
+                        msg.offset(),
+                        e.display("; ")
+                    );
+                }
+                if let Err(e) = consumer.store_offset(&msg) {
+                    warn!("Failed to store kafka offset: {}", e);
+                }
+            }
+        }
+    }
+    debug!("Stopping kafka consumer {}", name);
+    // Fails if there's nothing new to commit, which is fine
+    if let Err(e) = consumer.commit_consumer_state(CommitMode::Sync) {
+        debug!("Final commit of kafka consumer {}: {}", name, e);
+    }
+}
+
+struct Task {
+    stop: Arc<AtomicBool>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Task {
+    fn shutdown(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            if thread.join().is_err() {
+                error!("Kafka consumer thread panicked");
+            }
+        }
+    }
+}
+
+/// A running consumer.
+///
+/// This is the uninstall handle of [`ConsumerInstaller`]. Dropping it stops the consumer (and
+/// waits for it to commit the offsets). The user should not need to interact with this directly.
+pub struct ConsumerTask(Arc<Task>);
+
+impl Debug for ConsumerTask {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ConsumerTask").finish()
+    }
+}
+
+impl Drop for ConsumerTask {
+    fn drop(&mut self) {
+        self.0.shutdown();
+    }
+}
+
+/// An [`Installer`] of [`KafkaConsumer`]s.
+///
+/// Each installed consumer gets its own thread, passing the messages to the handler. All the
+/// consumers are stopped when the application terminates.
+#[derive(Clone)]
+pub struct ConsumerInstaller {
+    handler: Arc<Handler>,
+    running: Arc<Mutex<Vec<Weak<Task>>>>,
+}
+
+impl Debug for ConsumerInstaller {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ConsumerInstaller").finish()
+    }
+}
+
+impl ConsumerInstaller {
+    /// Creates the installer, passing messages to the given handler.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&BorrowedMessage) -> Result<(), AnyError> + Send + Sync + 'static,
+    {
+        ConsumerInstaller {
+            handler: Arc::new(handler),
+            running: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Stops all the consumers installed through this installer.
+    pub fn shutdown(&self) {
+        let running = self.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for task in running.iter().filter_map(Weak::upgrade) {
+            task.shutdown();
+        }
+    }
+}
+
+impl<O, C> Installer<Consumer, O, C> for ConsumerInstaller {
+    type UninstallHandle = ConsumerTask;
+    fn install(&mut self, consumer: Consumer, name: &'static str) -> ConsumerTask {
+        debug!("Starting kafka consumer {}", name);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handler = Arc::clone(&self.handler);
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::Builder::new()
+            .name(format!("kafka-{}", name))
+            .spawn(move || consume(name, consumer, &*handler, &thread_stop));
+        let thread = match thread {
+            Ok(thread) => Some(thread),
+            Err(e) => {
+                error!("Failed to start kafka consumer {}: {}", name, e);
+                None
+            }
+        };
+        let task = Arc::new(Task {
+            stop,
+            thread: Mutex::new(thread),
+        });
+        let mut running = self.running.lock().unwrap();
+        running.retain(|task| task.strong_count() > 0);
+        running.push(Arc::downgrade(&task));
+        ConsumerTask(task)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
@@ -0,0 +1,243 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-rdkafka/0.1.0/spirit_rdkafka/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of Kafka producers and consumers.
+//!
+//! This is built on top of [rdkafka]. The configuration common to both producers and consumers
+//! (brokers, security settings, arbitrary librdkafka properties) is in [`KafkaConnection`], which
+//! is flattened into the [`KafkaProducer`][producer::KafkaProducer] and
+//! [`KafkaConsumer`][consumer::KafkaConsumer] fragments.
+//!
+//! * Producers are installed into an [`AtomicProducer`][producer::AtomicProducer] handle, which
+//!   always holds the producer of the current configuration.
+//! * Consumers get a thread each, feeding the messages into a handler. When the configuration
+//!   changes, the consumer is recreated (and joins its group anew). When the consumer goes away
+//!   (either because of reconfiguration or termination of the application), the offsets of
+//!   handled messages are committed before it leaves. See the [`consumer`] module.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use rdkafka::Message;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_rdkafka::consumer::{ConsumerInstaller, KafkaConsumer};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [consumer]
+//! brokers = ["localhost:9092"]
+//! group-id = "example"
+//! topics = ["events"]
+//!
+//! [consumer.properties]
+//! "fetch.wait.max.ms" = "100"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     consumer: KafkaConsumer,
+//! }
+//!
+//! fn main() {
+//!     let installer = ConsumerInstaller::new(|msg| {
+//!         println!("Got message at offset {}", msg.offset());
+//!         Ok(())
+//!     });
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("consumer")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.consumer.clone())
+//!                 .install(installer)
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [rdkafka]: https://crates.io/crates/rdkafka
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use rdkafka::ClientConfig;
+use serde::{Deserialize, Serialize};
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+pub mod consumer;
+pub mod producer;
+
+/// The protocol to talk to the brokers.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum SecurityProtocol {
+    /// No authentication, no encryption.
+    Plaintext,
+    /// TLS.
+    Ssl,
+    /// SASL authentication without encryption.
+    SaslPlaintext,
+    /// SASL authentication over TLS.
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    fn name(self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "plaintext",
+            SecurityProtocol::Ssl => "ssl",
+            SecurityProtocol::SaslPlaintext => "sasl_plaintext",
+            SecurityProtocol::SaslSsl => "sasl_ssl",
+        }
+    }
+}
+
+impl Default for SecurityProtocol {
+    fn default() -> Self {
+        SecurityProtocol::Plaintext
+    }
+}
+
+/// The part of configuration shared by producers and consumers.
+///
+/// This is usually not used directly, but flattened into the producer and consumer
+/// configuration.
+///
+/// # Fields
+///
+/// * `brokers`: The initial list of brokers (`host:port`).
+/// * `security-protocol`: One of `plaintext`, `ssl`, `sasl-plaintext` and `sasl-ssl`. Defaults
+///   to `plaintext`.
+/// * `sasl-mechanism`: The SASL mechanism (eg. `PLAIN`, `SCRAM-SHA-256`).
+/// * `sasl-username`: The SASL user name.
+/// * `sasl-password`: The SASL password. Hidden when dumping the configuration.
+/// * `ssl-ca`: File with the certificate authority to check the brokers against.
+/// * `ssl-cert`: File with the client certificate.
+/// * `ssl-key`: File with the private key of the client certificate.
+/// * `ssl-key-password`: Password of the private key. Hidden when dumping the configuration.
+/// * `properties`: Arbitrary librdkafka properties, passed as they are. These take precedence
+///   over the above.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct KafkaConnection {
+    /// The initial list of brokers.
+    pub brokers: Vec<String>,
+
+    /// The protocol to talk to the brokers.
+    #[serde(default)]
+    pub security_protocol: SecurityProtocol,
+
+    /// The SASL mechanism.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_mechanism: Option<String>,
+
+    /// The SASL user name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_username: Option<String>,
+
+    /// The SASL password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sasl_password: Option<Hidden<String>>,
+
+    /// The certificate authority file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_ca: Option<PathBuf>,
+
+    /// The client certificate file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_cert: Option<PathBuf>,
+
+    /// The private key file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_key: Option<PathBuf>,
+
+    /// Password of the private key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssl_key_password: Option<Hidden<String>>,
+
+    /// Arbitrary librdkafka properties.
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+
+impl KafkaConnection {
+    /// Creates the librdkafka configuration.
+    pub fn client_config(&self) -> Result<ClientConfig, AnyError> {
+        if self.brokers.is_empty() {
+            return Err("No kafka brokers configured".into());
+        }
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers.join(","))
+            .set("security.protocol", self.security_protocol.name());
+        let mut set_opt = |key: &str, value: Option<&str>| {
+            if let Some(value) = value {
+                config.set(key, value);
+            }
+        };
+        set_opt("sasl.mechanism", self.sasl_mechanism.as_deref());
+        set_opt("sasl.username", self.sasl_username.as_deref());
+        set_opt(
+            "sasl.password",
+            self.sasl_password.as_ref().map(|p| p.as_str()),
+        );
+        set_opt(
+            "ssl.key.password",
+            self.ssl_key_password.as_ref().map(|p| p.as_str()),
+        );
+        let paths = [
+            ("ssl.ca.location", &self.ssl_ca),
+            ("ssl.certificate.location", &self.ssl_cert),
+            ("ssl.key.location", &self.ssl_key),
+        ];
+        for (key, path) in &paths {
+            if let Some(path) = path {
+                let path = path
+                    .to_str()
+                    .ok_or_else(|| format!("Non-UTF8 path in {}", key))?;
+                config.set(key, path);
+            }
+        }
+        for (key, value) in &self.properties {
+            config.set(key, value);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn properties() {
+        let mut cfg = KafkaConnection {
+            brokers: vec!["a:9092".to_owned(), "b:9092".to_owned()],
+            security_protocol: SecurityProtocol::SaslSsl,
+            sasl_password: Some(Hidden("secret".to_owned())),
+            ..KafkaConnection::default()
+        };
+        cfg.properties
+            .insert("security.protocol".to_owned(), "ssl".to_owned());
+        let config = cfg.client_config().unwrap();
+        assert_eq!(Some("a:9092,b:9092"), config.get("bootstrap.servers"));
+        assert_eq!(Some("secret"), config.get("sasl.password"));
+        // The passthrough properties win
+        assert_eq!(Some("ssl"), config.get("security.protocol"));
+        assert_eq!(None, config.get("ssl.ca.location"));
+    }
+
+    #[test]
+    fn no_brokers() {
+        assert!(KafkaConnection::default().client_config().is_err());
+    }
+}
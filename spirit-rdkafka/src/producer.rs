@@ -0,0 +1,196 @@
+//! Kafka producers.
+//!
+//! The [`KafkaProducer`] fragment creates a [`Producer`] (one that polls for delivery reports in
+//! its own thread). It can be installed into an [`AtomicProducer`], which always holds the
+//! producer of the current configuration.
+//!
+//! When the application terminates, the current producer is flushed, so the messages in flight
+//! are not lost.
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::debug;
+use rdkafka::producer::{DefaultProducerContext, ThreadedProducer};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+use crate::KafkaConnection;
+
+/// The producer created by the [`KafkaProducer`] fragment.
+pub type Producer = ThreadedProducer<DefaultProducerContext>;
+
+fn default_flush_timeout() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Configuration of a Kafka producer.
+///
+/// # Fields
+///
+/// All the fields of [`KafkaConnection`], plus:
+///
+/// * `flush-timeout`: How long to wait for the messages in flight to be delivered on shutdown.
+///   Defaults to `5s`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct KafkaProducer {
+    /// The connection to the brokers.
+    #[serde(flatten)]
+    pub connection: KafkaConnection,
+
+    /// How long to wait for messages in flight on shutdown.
+    #[serde(
+        default = "default_flush_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub flush_timeout: Duration,
+}
+
+impl KafkaProducer {
+    /// Creates the producer.
+    pub fn create_producer(&self) -> Result<Producer, AnyError> {
+        debug!("Creating kafka producer for {:?}", self.connection.brokers);
+        let producer = self
+            .connection
+            .client_config()?
+            .create()
+            .context("Failed to create kafka producer")?;
+        Ok(producer)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for KafkaProducer {
+        type Driver = CacheEq<KafkaProducer>;
+        type Resource = ProducerResource;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<ProducerResource, AnyError> {
+            Ok(ProducerResource {
+                producer: self.create_producer()?,
+                flush_timeout: self.flush_timeout,
+            })
+        }
+    }
+}
+
+/// The producer with settings about its shutdown.
+///
+/// This is what the [`KafkaProducer`] fragment creates. The user should not need to interact with
+/// this directly.
+pub struct ProducerResource {
+    producer: Producer,
+    flush_timeout: Duration,
+}
+
+impl Debug for ProducerResource {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ProducerResource")
+            .field("flush_timeout", &self.flush_timeout)
+            .finish()
+    }
+}
+
+/// A handle to the current producer.
+///
+/// Cheap to clone, all the clones share the same producer. It can be used as an [`Installer`]
+/// for the [`KafkaProducer`] fragment.
+///
+/// # Panics
+///
+/// Accessing the producer before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicProducer(Arc<ArcSwapOption<ProducerResource>>);
+
+impl Debug for AtomicProducer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicProducer").finish()
+    }
+}
+
+impl AtomicProducer {
+    /// Creates an [`AtomicProducer`] without any producer inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the producer inside.
+    pub fn replace(&self, producer: ProducerResource) {
+        if let Some(old) = self.0.swap(Some(Arc::new(producer))) {
+            // Someone might still be using it, but we can at least send out what is queued up.
+            old.producer.flush(old.flush_timeout);
+        }
+    }
+
+    /// Returns the current producer.
+    ///
+    /// The producer is shared, it is only reference counted, not cloned.
+    pub fn producer(&self) -> ProducerRef {
+        ProducerRef(
+            self.0
+                .load_full()
+                .expect("Accessing kafka producer before setting it up"),
+        )
+    }
+
+    /// Waits for the messages in flight of the current producer to be delivered.
+    ///
+    /// Does nothing if there's no producer yet.
+    pub fn flush(&self) {
+        if let Some(current) = self.0.load_full() {
+            debug!("Flushing kafka producer");
+            current.producer.flush(current.flush_timeout);
+        }
+    }
+}
+
+/// A reference to a shared producer, as returned by [`AtomicProducer::producer`].
+#[derive(Clone)]
+pub struct ProducerRef(Arc<ProducerResource>);
+
+impl std::ops::Deref for ProducerRef {
+    type Target = Producer;
+    fn deref(&self) -> &Producer {
+        &self.0.producer
+    }
+}
+
+impl Debug for ProducerRef {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("ProducerRef").finish()
+    }
+}
+
+impl<O, C> Installer<ProducerResource, O, C> for AtomicProducer {
+    type UninstallHandle = ();
+    fn install(&mut self, producer: ProducerResource, name: &'static str) {
+        debug!("Installing kafka producer {}", name);
+        self.replace(producer);
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.flush()))
+    }
+}
@@ -0,0 +1,500 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-redis/0.1.0/spirit_redis/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [redis] connection pools.
+//!
+//! The [`RedisPool`] configuration fragment describes an [r2d2] pool of connections to either a
+//! single redis server or a redis cluster. It is installed into an [`AtomicRedis`] handle, which
+//! always holds the pool corresponding to the current configuration.
+//!
+//! Similar to other pools, a new pool is built on reconfiguration and, unless turned off, checked
+//! by sending it a `PING`. Only if that succeeds the pool is swapped in. The same probe is
+//! available at any time through [`AtomicRedis::ping`], eg. for health endpoints.
+//!
+//! TLS connections need the `tls` feature.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_redis::{AtomicRedis, RedisPool};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [redis]
+//! urls = ["redis://localhost"]
+//! pool-size = 4
+//! command-timeout = "2s"
+//! health-check = false
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     redis: RedisPool,
+//! }
+//!
+//! fn main() {
+//!     let redis = AtomicRedis::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("redis")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.redis.clone())
+//!                 .install(redis.clone())
+//!         )
+//!         .run(move |_| {
+//!             // Not actually connecting in the example
+//!             if false {
+//!                 let mut conn = redis.get()?;
+//!                 let _: () = redis::cmd("SET").arg("key").arg(42).query(&mut *conn)?;
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [r2d2]: https://crates.io/crates/r2d2
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::{debug, trace};
+use r2d2::{ManageConnection, PooledConnection};
+use redis::cluster::{ClusterClient, ClusterClientBuilder, ClusterConnection};
+use redis::{
+    Client, Cmd, Connection, ConnectionLike, IntoConnectionInfo, RedisError, RedisResult, Value,
+};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_pool_size() -> u32 {
+    10
+}
+
+fn default_connection_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+fn default_health_check() -> bool {
+    true
+}
+
+/// The pool of redis connections.
+pub type Pool = r2d2::Pool<RedisManager>;
+
+/// Configuration of a redis connection pool.
+///
+/// # Fields
+///
+/// * `urls`: The server URLs (`redis://host:port/db`, or `rediss://` for TLS). A single one
+///   unless `cluster` is set, in which case these are the initial nodes of the cluster.
+/// * `cluster`: Connect to a redis cluster. Defaults to `false`.
+/// * `password`: The password to authenticate with. Hidden when dumping the configuration.
+/// * `password-file`: Read the password from this file instead (surrounding whitespace is
+///   stripped). Useful with secrets mounted as files.
+/// * `tls`: Connect with TLS even if the URLs use the plain `redis://` scheme. Needs the `tls`
+///   feature. Defaults to `false`.
+/// * `pool-size`: Maximum number of connections. Defaults to 10.
+/// * `min-idle`: Number of idle connections to keep around. Defaults to `nil` (same as
+///   `pool-size`).
+/// * `connection-timeout`: How long to wait for a connection from the pool. Defaults to `30s`.
+/// * `command-timeout`: Timeout of reading and writing each command. Defaults to `nil` (no
+///   timeout).
+/// * `health-check`: `PING` the server(s) when creating a new pool. Defaults to `true`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct RedisPool {
+    /// The server URLs.
+    pub urls: Vec<String>,
+
+    /// Connect to a redis cluster.
+    #[serde(default)]
+    pub cluster: bool,
+
+    /// The password to authenticate with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<Hidden<String>>,
+
+    /// A file to read the password from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<PathBuf>,
+
+    /// Force TLS connections.
+    #[serde(default)]
+    pub tls: bool,
+
+    /// Maximum number of connections.
+    #[serde(default = "default_pool_size")]
+    pub pool_size: u32,
+
+    /// Number of idle connections to keep around.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_idle: Option<u32>,
+
+    /// How long to wait for a connection from the pool.
+    #[serde(
+        default = "default_connection_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub connection_timeout: Duration,
+
+    /// Timeout of each command.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub command_timeout: Option<Duration>,
+
+    /// Check the new pool by a `PING`.
+    #[serde(default = "default_health_check")]
+    pub health_check: bool,
+}
+
+impl Default for RedisPool {
+    fn default() -> Self {
+        RedisPool {
+            urls: vec!["redis://localhost".to_owned()],
+            cluster: false,
+            password: None,
+            password_file: None,
+            tls: false,
+            pool_size: default_pool_size(),
+            min_idle: None,
+            connection_timeout: default_connection_timeout(),
+            command_timeout: None,
+            health_check: default_health_check(),
+        }
+    }
+}
+
+/// Switches a plain URL to TLS.
+fn force_tls(url: &str) -> Result<String, AnyError> {
+    if !cfg!(feature = "tls") {
+        return Err("TLS connections to redis need the tls feature".into());
+    }
+    if url.starts_with("rediss://") {
+        Ok(url.to_owned())
+    } else if url.starts_with("redis://") {
+        Ok(format!("rediss://{}", &url["redis://".len()..]))
+    } else {
+        Err(format!("Can't use TLS with redis URL {}", url).into())
+    }
+}
+
+impl RedisPool {
+    fn password(&self) -> Result<Option<String>, AnyError> {
+        match (&self.password, &self.password_file) {
+            (Some(_), Some(_)) => Err("Both password and password-file are set".into()),
+            (Some(password), None) => Ok(Some(password.0.clone())),
+            (None, Some(file)) => {
+                let password = fs::read_to_string(file).with_context(|_| {
+                    format!("Failed to read redis password from {}", file.display())
+                })?;
+                Ok(Some(password.trim().to_owned()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn urls(&self) -> Result<Vec<String>, AnyError> {
+        if self.tls {
+            self.urls.iter().map(|url| force_tls(url)).collect()
+        } else {
+            Ok(self.urls.clone())
+        }
+    }
+
+    /// Creates the connection manager, without connecting yet.
+    pub fn manager(&self) -> Result<RedisManager, AnyError> {
+        let password = self.password()?;
+        let urls = self.urls()?;
+        let target = if self.cluster {
+            if urls.is_empty() {
+                return Err("No initial nodes of the redis cluster".into());
+            }
+            let mut builder = ClusterClientBuilder::new(urls);
+            if let Some(password) = password {
+                builder = builder.password(password);
+            }
+            Target::Cluster(builder.open().context("Invalid redis cluster config")?)
+        } else {
+            if urls.len() != 1 {
+                return Err(format!(
+                    "Expected one redis URL, got {} (set cluster = true for a cluster)",
+                    urls.len()
+                )
+                .into());
+            }
+            let mut info = urls[0]
+                .as_str()
+                .into_connection_info()
+                .context("Invalid redis URL")?;
+            if password.is_some() {
+                info.passwd = password;
+            }
+            Target::Single(Client::open(info)?)
+        };
+        Ok(RedisManager {
+            target,
+            command_timeout: self.command_timeout,
+        })
+    }
+
+    /// Creates the connection pool according to the configuration.
+    ///
+    /// This also runs the health check if configured.
+    pub fn create_pool(&self) -> Result<Pool, AnyError> {
+        debug!(
+            "Creating redis pool of up to {} connections",
+            self.pool_size
+        );
+        let manager = self.manager()?;
+        let pool = r2d2::Pool::builder()
+            .max_size(self.pool_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(self.connection_timeout)
+            .build(manager)
+            .context("Failed to create redis pool")?;
+        if self.health_check {
+            trace!("Checking health of the new redis pool");
+            ping(&pool).context("Health check of the new redis pool failed")?;
+        }
+        Ok(pool)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for RedisPool {
+        type Driver = CacheEq<RedisPool>;
+        type Resource = Pool;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Pool, AnyError> {
+            self.create_pool()
+        }
+    }
+}
+
+fn ping(pool: &Pool) -> Result<(), AnyError> {
+    let mut conn = pool.get().context("Can't get a redis connection")?;
+    redis::cmd("PING").query::<()>(&mut *conn)?;
+    Ok(())
+}
+
+enum Target {
+    Single(Client),
+    Cluster(ClusterClient),
+}
+
+/// The r2d2 connection manager for redis.
+///
+/// Created by [`RedisPool::manager`]. The user should not need to interact with this directly.
+pub struct RedisManager {
+    target: Target,
+    command_timeout: Option<Duration>,
+}
+
+impl Debug for RedisManager {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let kind = match self.target {
+            Target::Single(_) => "single",
+            Target::Cluster(_) => "cluster",
+        };
+        fmt.debug_struct("RedisManager")
+            .field("target", &kind)
+            .field("command_timeout", &self.command_timeout)
+            .finish()
+    }
+}
+
+impl ManageConnection for RedisManager {
+    type Connection = RedisConnection;
+    type Error = RedisError;
+    fn connect(&self) -> RedisResult<RedisConnection> {
+        let timeout = self.command_timeout;
+        match &self.target {
+            Target::Single(client) => {
+                let conn = client.get_connection()?;
+                conn.set_read_timeout(timeout)?;
+                conn.set_write_timeout(timeout)?;
+                Ok(RedisConnection::Single(conn))
+            }
+            Target::Cluster(client) => {
+                let conn = client.get_connection()?;
+                conn.set_read_timeout(timeout)?;
+                conn.set_write_timeout(timeout)?;
+                Ok(RedisConnection::Cluster(conn))
+            }
+        }
+    }
+    fn is_valid(&self, conn: &mut RedisConnection) -> RedisResult<()> {
+        redis::cmd("PING").query(conn)
+    }
+    fn has_broken(&self, conn: &mut RedisConnection) -> bool {
+        !conn.is_open()
+    }
+}
+
+/// A connection to either a single server or a cluster.
+///
+/// It is used through the [`ConnectionLike`] trait, the same way as the connections of the
+/// [redis] crate.
+pub enum RedisConnection {
+    /// A connection to a single server.
+    Single(Connection),
+    /// A connection to a cluster.
+    Cluster(ClusterConnection),
+}
+
+macro_rules! delegate {
+    ($self: ident, $conn: ident => $e: expr) => {
+        match $self {
+            RedisConnection::Single($conn) => $e,
+            RedisConnection::Cluster($conn) => $e,
+        }
+    };
+}
+
+impl ConnectionLike for RedisConnection {
+    fn req_packed_command(&mut self, cmd: &[u8]) -> RedisResult<Value> {
+        delegate!(self, conn => conn.req_packed_command(cmd))
+    }
+    fn req_packed_commands(
+        &mut self,
+        cmd: &[u8],
+        offset: usize,
+        count: usize,
+    ) -> RedisResult<Vec<Value>> {
+        delegate!(self, conn => conn.req_packed_commands(cmd, offset, count))
+    }
+    fn req_command(&mut self, cmd: &Cmd) -> RedisResult<Value> {
+        delegate!(self, conn => conn.req_command(cmd))
+    }
+    fn get_db(&self) -> i64 {
+        delegate!(self, conn => conn.get_db())
+    }
+    fn check_connection(&mut self) -> bool {
+        delegate!(self, conn => conn.check_connection())
+    }
+    fn is_open(&self) -> bool {
+        delegate!(self, conn => conn.is_open())
+    }
+}
+
+/// A handle to the currently configured redis pool.
+///
+/// Cheap to clone, all the clones share the same pool. It can be used as an [`Installer`] for
+/// the [`RedisPool`] fragment. The old pool stays alive until all connections checked out of it
+/// are returned.
+///
+/// # Panics
+///
+/// Accessing the pool before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicRedis(Arc<ArcSwapOption<Pool>>);
+
+impl Debug for AtomicRedis {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicRedis").finish()
+    }
+}
+
+impl AtomicRedis {
+    /// Creates an [`AtomicRedis`] without any pool inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the pool inside.
+    pub fn replace(&self, pool: Pool) {
+        self.0.store(Some(Arc::new(pool)));
+    }
+
+    /// Returns the current pool.
+    pub fn pool(&self) -> Arc<Pool> {
+        self.0
+            .load_full()
+            .expect("Accessing redis pool before setting it up")
+    }
+
+    /// Checks out a connection from the current pool.
+    pub fn get(&self) -> Result<PooledConnection<RedisManager>, AnyError> {
+        self.pool()
+            .get()
+            .context("Failed to get a redis connection")
+            .map_err(AnyError::from)
+    }
+
+    /// Checks the server(s) are reachable, by sending a `PING`.
+    pub fn ping(&self) -> Result<(), AnyError> {
+        ping(&self.pool())
+    }
+}
+
+impl<O, C> Installer<Pool, O, C> for AtomicRedis {
+    type UninstallHandle = ();
+    fn install(&mut self, pool: Pool, name: &'static str) {
+        debug!("Installing redis pool {}", name);
+        self.replace(pool);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_count() {
+        let cfg = RedisPool {
+            urls: vec!["redis://a".to_owned(), "redis://b".to_owned()],
+            ..RedisPool::default()
+        };
+        assert!(cfg.manager().is_err());
+        let cfg = RedisPool {
+            cluster: true,
+            ..cfg
+        };
+        cfg.manager().unwrap();
+        let cfg = RedisPool {
+            urls: Vec::new(),
+            ..cfg
+        };
+        assert!(cfg.manager().is_err());
+    }
+
+    #[test]
+    fn password_conflict() {
+        let cfg = RedisPool {
+            password: Some(Hidden("secret".to_owned())),
+            password_file: Some(PathBuf::from("/nonexistent")),
+            ..RedisPool::default()
+        };
+        assert!(cfg.manager().is_err());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_urls() {
+        assert_eq!("rediss://host:1", force_tls("redis://host:1").unwrap());
+        assert_eq!("rediss://host", force_tls("rediss://host").unwrap());
+        assert!(force_tls("unix:///tmp/sock").is_err());
+    }
+}
@@ -0,0 +1,284 @@
+//! Recording and replaying of outgoing requests.
+//!
+//! For tests and CI, it is handy to not depend on the real upstream services. With the `record`
+//! option of the [`ReqwestClient`], the responses are saved into a file (a *cassette*). With the
+//! `replay` option, the requests are not sent at all, the responses are taken from the cassette
+//! instead. The application code stays the same, only the configuration of the test environment
+//! differs.
+//!
+//! The requests are matched by the method and URL. If the same request was recorded multiple
+//! times, the responses are replayed in the same order (the last one repeats when they run out).
+//! A request that is not in the cassette fails with [`NotRecorded`].
+//!
+//! The mode applies to the requests sent through [`AtomicClient::execute`] of the
+//! [`AtomicClient`] the client is installed into by a [`Pipeline`]. Requests sent directly (eg.
+//! by [`RequestBuilder::send`][reqwest::RequestBuilder::send]) bypass it.
+//!
+//! The cassette is a JSON file. The bodies are base64 encoded.
+//!
+//! # Examples
+//!
+//! The configuration of the test environment:
+//!
+//! ```toml
+//! [client]
+//! replay = "tests/cassettes/rust-lang.json"
+//! ```
+//!
+//! The application sends the requests through the [`AtomicClient`]:
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_reqwest::{AtomicClient, ReqwestClient};
+//!
+//! #[derive(Debug, Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     client: ReqwestClient,
+//! }
+//!
+//! impl Cfg {
+//!     fn client(&self) -> ReqwestClient {
+//!         self.client.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let client = AtomicClient::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(Pipeline::new("http client").extract_cfg(Cfg::client).install(client.clone()))
+//!         .run(move |_| {
+//!             let request = client.get("https://www.rust-lang.org").build()?;
+//!             // Not actually sending it in the example
+//!             if false {
+//!                 let page = client.execute(request)?.text()?;
+//!                 println!("{}", page);
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`ReqwestClient`]: crate::ReqwestClient
+//! [`AtomicClient`]: crate::AtomicClient
+//! [`AtomicClient::execute`]: crate::AtomicClient::execute
+//! [`Pipeline`]: spirit::Pipeline
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use err_context::prelude::*;
+use log::{debug, warn};
+use reqwest::header::HeaderName;
+use reqwest::{Request, Response};
+use serde::{Deserialize, Serialize};
+use spirit::AnyError;
+
+/// One recorded request with its response.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Interaction {
+    method: String,
+    url: String,
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Interaction {
+    fn matches(&self, request: &Request) -> bool {
+        self.method == request.method().as_str() && self.url == request.url().as_str()
+    }
+
+    fn response(&self) -> Result<Response, AnyError> {
+        let mut response = http::Response::builder();
+        response.status(self.status);
+        for (name, value) in &self.headers {
+            response.header(HeaderName::from_bytes(name.as_bytes())?, value.as_str());
+        }
+        let body = base64::decode(&self.body)?;
+        Ok(response.body(body)?.into())
+    }
+}
+
+/// The error returned when replaying a request that is not in the cassette.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NotRecorded {
+    /// The method of the request.
+    pub method: String,
+    /// The URL of the request.
+    pub url: String,
+}
+
+impl Display for NotRecorded {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Request {} {} is not recorded", self.method, self.url)
+    }
+}
+
+impl Error for NotRecorded {}
+
+#[derive(Debug)]
+enum Mode {
+    Record,
+    Replay { played: HashMap<usize, usize> },
+}
+
+/// A file with recorded requests, either being recorded or replayed.
+#[derive(Debug)]
+pub(crate) struct Cassette {
+    path: PathBuf,
+    state: Mutex<(Mode, Vec<Interaction>)>,
+}
+
+impl Cassette {
+    /// Starts recording into the file, replacing whatever was in there.
+    pub(crate) fn record(path: &Path) -> Result<Self, AnyError> {
+        fs::write(path, "[]")
+            .with_context(|_| format!("Failed to create cassette {}", path.display()))?;
+        Ok(Cassette {
+            path: path.to_owned(),
+            state: Mutex::new((Mode::Record, Vec::new())),
+        })
+    }
+
+    /// Loads the file for replaying.
+    pub(crate) fn replay(path: &Path) -> Result<Self, AnyError> {
+        let content = fs::read(path)
+            .with_context(|_| format!("Failed to read cassette {}", path.display()))?;
+        let interactions: Vec<Interaction> = serde_json::from_slice(&content)
+            .with_context(|_| format!("Invalid cassette {}", path.display()))?;
+        Ok(Cassette {
+            path: path.to_owned(),
+            state: Mutex::new((
+                Mode::Replay {
+                    played: HashMap::new(),
+                },
+                interactions,
+            )),
+        })
+    }
+
+    fn play(&self, request: &Request) -> Result<Response, AnyError> {
+        let mut state = self.state.lock().unwrap();
+        let (mode, interactions) = &mut *state;
+        let played = match mode {
+            Mode::Replay { played } => played,
+            Mode::Record => unreachable!("Playing a recording cassette"),
+        };
+        let candidates = interactions
+            .iter()
+            .enumerate()
+            .filter(|(_, interaction)| interaction.matches(request))
+            .map(|(idx, _)| idx)
+            .collect::<Vec<_>>();
+        let last = match candidates.last() {
+            Some(last) => *last,
+            None => {
+                return Err(NotRecorded {
+                    method: request.method().to_string(),
+                    url: request.url().to_string(),
+                }
+                .into());
+            }
+        };
+        let first = candidates[0];
+        let turn = played.entry(first).or_insert(0);
+        let idx = candidates.get(*turn).cloned().unwrap_or(last);
+        *turn += 1;
+        debug!("Replaying {} {}", request.method(), request.url());
+        interactions[idx].response()
+    }
+
+    fn capture(&self, request: &Request, response: Response) -> Result<Response, AnyError> {
+        let method = request.method().to_string();
+        let url = request.url().to_string();
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_owned())))
+            .collect();
+        let mut body = Vec::new();
+        let mut response = response;
+        response
+            .read_to_end(&mut body)
+            .with_context(|_| format!("Failed to read response of {} {}", method, url))?;
+        let interaction = Interaction {
+            method,
+            url,
+            status,
+            headers,
+            body: base64::encode(&body),
+        };
+        let replayed = interaction.response()?;
+        let mut state = self.state.lock().unwrap();
+        state.1.push(interaction);
+        let content = serde_json::to_vec_pretty(&state.1)?;
+        if let Err(e) = fs::write(&self.path, content) {
+            warn!("Failed to write cassette {}: {}", self.path.display(), e);
+        }
+        Ok(replayed)
+    }
+
+    /// Executes the request according to the mode.
+    ///
+    /// When recording, the request is actually sent by `send`.
+    pub(crate) fn execute<F>(&self, request: Request, send: F) -> Result<Response, AnyError>
+    where
+        F: FnOnce(Request) -> Result<Response, AnyError>,
+    {
+        let recording = match self.state.lock().unwrap().0 {
+            Mode::Record => true,
+            Mode::Replay { .. } => false,
+        };
+        if recording {
+            let response = send(request.try_clone().ok_or("Can't record streamed body")?)?;
+            self.capture(&request, response)
+        } else {
+            self.play(&request)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay() {
+        let path = std::env::temp_dir().join(format!("cassette-{}.json", std::process::id()));
+        let interaction = |status, body: &str| Interaction {
+            method: "GET".to_owned(),
+            url: "http://example.com/".to_owned(),
+            status,
+            headers: vec![("x-test".to_owned(), "yes".to_owned())],
+            body: base64::encode(body),
+        };
+        let interactions = vec![interaction(503, "down"), interaction(200, "up")];
+        fs::write(&path, serde_json::to_vec(&interactions).unwrap()).unwrap();
+        let cassette = Cassette::replay(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let client = reqwest::Client::new();
+        let request = || client.get("http://example.com/").build().unwrap();
+        let first = cassette.play(&request()).unwrap();
+        assert_eq!(503, first.status().as_u16());
+        assert_eq!("yes", first.headers()["x-test"]);
+        let mut second = cassette.play(&request()).unwrap();
+        assert_eq!(200, second.status().as_u16());
+        assert_eq!("up", second.text().unwrap());
+        assert_eq!(200, cassette.play(&request()).unwrap().status().as_u16());
+
+        let other = client.post("http://example.com/").build().unwrap();
+        let err = cassette.play(&other).unwrap_err();
+        assert!(err.downcast_ref::<NotRecorded>().is_some());
+    }
+}
@@ -0,0 +1,491 @@
+//! Circuit breaking of requests to failing hosts.
+//!
+//! When an upstream host is down, sending it more requests only wastes time of the callers (each
+//! waits for its timeout) and makes it harder for the host to come back up. A circuit breaker
+//! watches the results of requests to each host and if too many of them fail, it *opens* the
+//! circuit ‒ further requests to the host fail right away with [`CircuitOpen`], without being
+//! sent. After a while, the circuit becomes *half-open* and lets a single trial request through.
+//! If it succeeds, the circuit closes again, otherwise it stays open for another while.
+//!
+//! Errors without a response and `5xx` responses count as failures.
+//!
+//! The [`CircuitBreakerCfg`] is configured as the `circuit-breaker` option of the
+//! [`ReqwestClient`]. Each [`AtomicClient`] holds its own [`CircuitBreaker`], configured when the
+//! client is installed by a [`Pipeline`] (the state of the hosts survives configuration reloads),
+//! and the requests sent through [`AtomicClient::execute`] are guarded by it.
+//!
+//! While the circuit breaking is configured, the client registers a
+//! [health probe][spirit::health] (named by the pipeline and [`HEALTH_PROBE_SUFFIX`]) reporting
+//! the circuits that are not closed, for example to the `health` command of `spirit-admin`. The
+//! states are also available through [`CircuitBreaker::states`] and, with the `metrics` feature,
+//! as metrics (see [`AtomicClient::with_metrics`][crate::AtomicClient::with_metrics]).
+//!
+//! A standalone [`CircuitBreaker`] can also be used with any client, through
+//! [`AtomicClient::execute_guarded`] or [`CircuitBreaker::execute`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_reqwest::{AtomicClient, ReqwestClient};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [client.circuit-breaker]
+//! failure-threshold = 50
+//! min-requests = 20
+//! window = "10s"
+//! open-for = "30s"
+//! "#;
+//!
+//! #[derive(Debug, Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     client: ReqwestClient,
+//! }
+//!
+//! impl Cfg {
+//!     fn client(&self) -> ReqwestClient {
+//!         self.client.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let client = AtomicClient::unconfigured();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(Pipeline::new("http client").extract_cfg(Cfg::client).install(client.clone()))
+//!         .run(move |_| {
+//!             let request = client.get("https://www.rust-lang.org").build()?;
+//!             // Not actually sending it in the example
+//!             if false {
+//!                 let page = client.execute(request)?.text()?;
+//!                 println!("{}", page);
+//!             }
+//!             assert!(client.circuit_breaker().all_closed());
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`ReqwestClient`]: crate::ReqwestClient
+//! [`AtomicClient`]: crate::AtomicClient
+//! [`AtomicClient::execute`]: crate::AtomicClient::execute
+//! [`AtomicClient::execute_guarded`]: crate::AtomicClient::execute_guarded
+//! [`Pipeline`]: spirit::Pipeline
+
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use arc_swap::ArcSwapOption;
+use log::{info, warn};
+use reqwest::{Client, Request, Response};
+use serde::{Deserialize, Serialize};
+use spirit::{health, AnyError};
+
+#[cfg(feature = "metrics")]
+use crate::instrument::ClientMetrics;
+
+/// Appended to the name of the pipeline to name the [health probe][spirit::health] of its
+/// circuits.
+pub const HEALTH_PROBE_SUFFIX: &str = "/circuit-breaker";
+
+fn default_failure_threshold() -> u8 {
+    50
+}
+
+fn default_min_requests() -> u32 {
+    10
+}
+
+fn default_window() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_open_for() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// Configuration of a circuit breaker.
+///
+/// # Fields
+///
+/// * `failure-threshold`: The percentage of failed requests (within the `window`) that opens the
+///   circuit. Defaults to `50`.
+/// * `min-requests`: The circuit is not opened before there were at least this many requests in
+///   the `window`. Defaults to `10`.
+/// * `window`: The time over which the failures are counted. Defaults to `10s`.
+/// * `open-for`: How long the circuit stays open before a trial request is let through. Defaults
+///   to `30s`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct CircuitBreakerCfg {
+    /// Percentage of failed requests that opens the circuit.
+    #[serde(default = "default_failure_threshold")]
+    pub failure_threshold: u8,
+
+    /// Minimal number of requests in the window before the circuit can open.
+    #[serde(default = "default_min_requests")]
+    pub min_requests: u32,
+
+    /// The time over which the failures are counted.
+    #[serde(
+        default = "default_window",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub window: Duration,
+
+    /// How long the circuit stays open before trying again.
+    #[serde(
+        default = "default_open_for",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub open_for: Duration,
+}
+
+impl Default for CircuitBreakerCfg {
+    fn default() -> Self {
+        CircuitBreakerCfg {
+            failure_threshold: default_failure_threshold(),
+            min_requests: default_min_requests(),
+            window: default_window(),
+            open_for: default_open_for(),
+        }
+    }
+}
+
+/// The state of the circuit to one host.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CircuitState {
+    /// Requests pass through.
+    Closed,
+    /// Requests are refused.
+    Open,
+    /// A trial request may go through.
+    HalfOpen,
+}
+
+/// The error returned instead of sending a request to a host with an open circuit.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CircuitOpen {
+    /// The host the request was for.
+    pub host: String,
+}
+
+impl Display for CircuitOpen {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Circuit to {} is open", self.host)
+    }
+}
+
+impl Error for CircuitOpen {}
+
+#[derive(Copy, Clone, Debug)]
+enum Phase {
+    Closed,
+    Open { until: Instant },
+    HalfOpen { trying: bool },
+}
+
+#[derive(Clone, Debug)]
+struct Host {
+    phase: Phase,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+}
+
+impl Host {
+    fn new(now: Instant) -> Self {
+        Host {
+            phase: Phase::Closed,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+        }
+    }
+
+    fn state(&self, now: Instant) -> CircuitState {
+        match self.phase {
+            Phase::Closed => CircuitState::Closed,
+            Phase::Open { until } if now < until => CircuitState::Open,
+            Phase::Open { .. } | Phase::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    fn permit(&mut self, now: Instant) -> bool {
+        match self.phase {
+            Phase::Closed => true,
+            Phase::Open { until } if now < until => false,
+            Phase::Open { .. } | Phase::HalfOpen { trying: false } => {
+                self.phase = Phase::HalfOpen { trying: true };
+                true
+            }
+            Phase::HalfOpen { trying: true } => false,
+        }
+    }
+
+    fn record(&mut self, cfg: &CircuitBreakerCfg, host: &str, success: bool, now: Instant) {
+        match self.phase {
+            Phase::HalfOpen { .. } if success => {
+                info!("Circuit to {} closed", host);
+                *self = Host::new(now);
+            }
+            Phase::HalfOpen { .. } => {
+                warn!("Trial request to {} failed, circuit stays open", host);
+                self.phase = Phase::Open {
+                    until: now + cfg.open_for,
+                };
+            }
+            // Requests sent before the circuit opened
+            Phase::Open { .. } => (),
+            Phase::Closed => {
+                if now.duration_since(self.window_start) >= cfg.window {
+                    *self = Host::new(now);
+                }
+                self.requests += 1;
+                if !success {
+                    self.failures += 1;
+                }
+                let rate_exceeded = u64::from(self.failures) * 100
+                    >= u64::from(cfg.failure_threshold) * u64::from(self.requests);
+                if self.requests >= cfg.min_requests && rate_exceeded {
+                    warn!(
+                        "Opening circuit to {} after {} failures out of {} requests",
+                        host, self.failures, self.requests
+                    );
+                    self.phase = Phase::Open {
+                        until: now + cfg.open_for,
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cfg: ArcSwapOption<CircuitBreakerCfg>,
+    hosts: Mutex<HashMap<String, Host>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<ClientMetrics>,
+}
+
+/// The state of circuit breaking of requests.
+///
+/// It keeps the circuit of each host separately. Cloning is cheap and the clones share the state.
+/// It does nothing until [`configure`][CircuitBreaker::configure]d.
+///
+/// See the [module documentation][crate::circuit].
+#[derive(Clone, Debug, Default)]
+pub struct CircuitBreaker(Arc<Inner>);
+
+impl CircuitBreaker {
+    /// Creates a circuit breaker without a configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a circuit breaker reporting the states of the circuits into the metrics.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(metrics: ClientMetrics) -> Self {
+        CircuitBreaker(Arc::new(Inner {
+            metrics: Some(metrics),
+            ..Inner::default()
+        }))
+    }
+
+    /// Sets a new configuration, keeping the state of the circuits.
+    ///
+    /// With `None`, the circuit breaking is turned off.
+    pub fn configure(&self, cfg: Option<&CircuitBreakerCfg>) {
+        if cfg.is_none() {
+            self.0.hosts.lock().unwrap().clear();
+        }
+        self.0.cfg.store(cfg.cloned().map(Arc::new));
+    }
+
+    /// The states of the circuits to the hosts that were contacted.
+    pub fn states(&self) -> BTreeMap<String, CircuitState> {
+        let now = Instant::now();
+        self.0
+            .hosts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, host)| (name.clone(), host.state(now)))
+            .collect()
+    }
+
+    /// Are all the circuits closed?
+    ///
+    /// Meant for health checks.
+    pub fn all_closed(&self) -> bool {
+        self.states()
+            .values()
+            .all(|state| *state == CircuitState::Closed)
+    }
+
+    #[cfg(feature = "metrics")]
+    fn report(&self, host: &str, state: CircuitState, refused: bool) {
+        if let Some(metrics) = &self.0.metrics {
+            metrics.record_circuit(host, state, refused);
+        }
+    }
+
+    #[cfg(not(feature = "metrics"))]
+    fn report(&self, _host: &str, _state: CircuitState, _refused: bool) {}
+
+    fn permit(&self, host: &str, now: Instant) -> Result<(), CircuitOpen> {
+        let mut hosts = self.0.hosts.lock().unwrap();
+        let state = hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| Host::new(now));
+        let permitted = state.permit(now);
+        self.report(host, state.state(now), !permitted);
+        if permitted {
+            Ok(())
+        } else {
+            Err(CircuitOpen {
+                host: host.to_owned(),
+            })
+        }
+    }
+
+    fn record(&self, cfg: &CircuitBreakerCfg, host: &str, success: bool, now: Instant) {
+        let mut hosts = self.0.hosts.lock().unwrap();
+        let state = hosts
+            .entry(host.to_owned())
+            .or_insert_with(|| Host::new(now));
+        state.record(cfg, host, success, now);
+        self.report(host, state.state(now), false);
+    }
+
+    /// Executes the request on the client, unless the circuit to its host is open.
+    ///
+    /// The error is either [`CircuitOpen`] or the [`reqwest::Error`] of the request.
+    pub fn execute(&self, client: &Client, request: Request) -> Result<Response, AnyError> {
+        self.guard(request, |request| {
+            client.execute(request).map_err(AnyError::from)
+        })
+    }
+
+    /// Lets the request be sent by `send`, unless the circuit to its host is open.
+    pub(crate) fn guard<F>(&self, request: Request, send: F) -> Result<Response, AnyError>
+    where
+        F: FnOnce(Request) -> Result<Response, AnyError>,
+    {
+        let cfg = match self.0.cfg.load_full() {
+            Some(cfg) => cfg,
+            None => return send(request),
+        };
+        let host = request.url().host_str().unwrap_or("unknown").to_owned();
+        self.permit(&host, Instant::now())?;
+        let result = send(request);
+        let success = match &result {
+            Ok(response) => !response.status().is_server_error(),
+            Err(_) => false,
+        };
+        self.record(&cfg, &host, success, Instant::now());
+        result
+    }
+
+    /// Describes the circuits that are not closed, for the health probe.
+    fn problems(&self) -> Option<String> {
+        let open = self
+            .states()
+            .into_iter()
+            .filter(|(_, state)| *state != CircuitState::Closed)
+            .map(|(host, state)| format!("{} ({:?})", host, state))
+            .collect::<Vec<_>>();
+        if open.is_empty() {
+            None
+        } else {
+            Some(format!("Circuits not closed: {}", open.join(", ")))
+        }
+    }
+
+    /// Configures the breaker and registers (or removes) its health probe.
+    pub(crate) fn install(&self, name: &str, cfg: Option<&CircuitBreakerCfg>) {
+        self.configure(cfg);
+        let probe = format!("{}{}", name, HEALTH_PROBE_SUFFIX);
+        if cfg.is_some() {
+            let me = self.clone();
+            health::register(probe, move || me.problems());
+        } else {
+            health::unregister(&probe);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transitions() {
+        let breaker = CircuitBreaker::new();
+        let cfg = CircuitBreakerCfg {
+            min_requests: 4,
+            ..CircuitBreakerCfg::default()
+        };
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        // Not enough requests to open it
+        for _ in 0..3 {
+            breaker.permit("a", at(0)).unwrap();
+            breaker.record(&cfg, "a", false, at(0));
+        }
+        // A new window
+        breaker.record(&cfg, "a", false, at(11));
+        breaker.record(&cfg, "a", true, at(11));
+        breaker.record(&cfg, "a", true, at(11));
+        assert!(breaker.all_closed());
+        breaker.record(&cfg, "a", false, at(12));
+        assert_eq!(CircuitState::Open, breaker.states()["a"]);
+        assert!(breaker.permit("a", at(20)).is_err());
+        breaker.permit("b", at(20)).unwrap();
+
+        // Trial request
+        breaker.permit("a", at(42)).unwrap();
+        assert!(breaker.permit("a", at(42)).is_err());
+        breaker.record(&cfg, "a", false, at(43));
+        assert!(breaker.permit("a", at(50)).is_err());
+        breaker.permit("a", at(73)).unwrap();
+        breaker.record(&cfg, "a", true, at(74));
+        breaker.permit("a", at(74)).unwrap();
+        assert!(breaker.all_closed());
+        assert!(breaker.problems().is_none());
+    }
+
+    #[test]
+    fn health_probe() {
+        let breaker = CircuitBreaker::new();
+        let cfg = CircuitBreakerCfg {
+            min_requests: 1,
+            ..CircuitBreakerCfg::default()
+        };
+        breaker.install("circuit-test", Some(&cfg));
+        let probe = format!("circuit-test{}", HEALTH_PROBE_SUFFIX);
+        let problems = || {
+            health::check()
+                .into_iter()
+                .filter(|problem| problem.probe == probe)
+                .count()
+        };
+        assert_eq!(0, problems());
+        breaker.record(&cfg, "a", false, Instant::now());
+        assert_eq!(1, problems());
+        breaker.install("circuit-test", None);
+        assert_eq!(0, problems());
+    }
+}
@@ -0,0 +1,154 @@
+//! Time budgets of outgoing requests.
+//!
+//! A service answering its own clients often needs to ask some other service first. If its client
+//! gives up after a second, there's no point in retrying the downstream request for ten more
+//! seconds. A [`Deadline`] caps the whole [`AtomicClient::execute_within`] (or
+//! [`RetryPolicy::execute_within`] and [`AtomicClient::execute_with_deadline`]) call ‒ no attempt
+//! is started and no retry is waited for after it passes.
+//!
+//! The remaining time is also sent to the downstream service in the [`TIMEOUT_HEADER`] (in
+//! milliseconds), so it can give up in time too. A proxy can continue the chain by taking its
+//! deadline from the incoming request with [`Deadline::from_headers`] (probably
+//! [`reserve`][Deadline::reserve]ing a bit of the time for itself).
+//!
+//! Note that the blocking client can't interrupt an attempt already in progress. Such attempt is
+//! bounded by the `timeout` of the client and its result is replaced by [`DeadlineExceeded`] if it
+//! comes too late.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use reqwest::header::HeaderMap;
+//! use spirit_reqwest::AtomicClient;
+//! use spirit_reqwest::deadline::Deadline;
+//!
+//! # fn main() -> Result<(), spirit::AnyError> {
+//! let client = AtomicClient::unconfigured();
+//! # let incoming_headers = HeaderMap::new();
+//! // Take the budget of the request we are answering, or use our own.
+//! let deadline = Deadline::from_headers(&incoming_headers)
+//!     .unwrap_or_else(|| Deadline::after(Duration::from_secs(5)))
+//!     .reserve(Duration::from_millis(50));
+//! let request = client.get("https://www.rust-lang.org").build()?;
+//! // Not actually sending it in the example
+//! if false {
+//!     let page = client.execute_within(request, deadline)?.text()?;
+//!     println!("{}", page);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`RetryPolicy::execute_within`]: crate::retry::RetryPolicy::execute_within
+//! [`AtomicClient::execute_within`]: crate::AtomicClient::execute_within
+//! [`AtomicClient::execute_with_deadline`]: crate::AtomicClient::execute_with_deadline
+
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::time::{Duration, Instant};
+
+use reqwest::header::{HeaderMap, HeaderValue};
+
+/// The header carrying the remaining time budget of a request, in milliseconds.
+pub const TIMEOUT_HEADER: &str = "x-request-timeout";
+
+/// A point in time after which the result of a request is no longer interesting.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// A deadline at the given instant.
+    pub fn at(instant: Instant) -> Self {
+        Deadline(instant)
+    }
+
+    /// A deadline the given time from now.
+    pub fn after(budget: Duration) -> Self {
+        Deadline(Instant::now() + budget)
+    }
+
+    /// Takes the deadline from the [`TIMEOUT_HEADER`] of an incoming request.
+    ///
+    /// Returns `None` if the header is missing or invalid.
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        headers
+            .get(TIMEOUT_HEADER)
+            .and_then(|budget| budget.to_str().ok())
+            .and_then(|budget| budget.trim().parse().ok())
+            .map(|budget| Self::after(Duration::from_millis(budget)))
+    }
+
+    /// Moves the deadline earlier, keeping some time for the caller's own work.
+    pub fn reserve(self, margin: Duration) -> Self {
+        Deadline(self.0.checked_sub(margin).unwrap_or_else(Instant::now))
+    }
+
+    /// The instant of the deadline.
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    /// The time left until the deadline (zero if it already passed).
+    pub fn remaining(&self) -> Duration {
+        let now = Instant::now();
+        if now < self.0 {
+            self.0 - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    /// Has the deadline already passed?
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    /// Sets the [`TIMEOUT_HEADER`] to the remaining time.
+    pub fn propagate(&self, headers: &mut HeaderMap) {
+        let remaining = self.remaining();
+        let millis = remaining.as_secs() * 1000 + u64::from(remaining.subsec_millis());
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from(millis));
+    }
+}
+
+/// The error returned when the [`Deadline`] of a request passes.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DeadlineExceeded;
+
+impl Display for DeadlineExceeded {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "Deadline of the request exceeded")
+    }
+}
+
+impl Error for DeadlineExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let mut headers = HeaderMap::new();
+        assert!(Deadline::from_headers(&headers).is_none());
+        headers.insert(TIMEOUT_HEADER, HeaderValue::from_static("nonsense"));
+        assert!(Deadline::from_headers(&headers).is_none());
+
+        Deadline::after(Duration::from_secs(10)).propagate(&mut headers);
+        let budget: u64 = headers[TIMEOUT_HEADER].to_str().unwrap().parse().unwrap();
+        assert!(budget > 9_000 && budget <= 10_000);
+        let deadline = Deadline::from_headers(&headers).unwrap();
+        assert!(!deadline.is_expired());
+        assert!(deadline.remaining() > Duration::from_secs(9));
+
+        let reserved = deadline.reserve(Duration::from_secs(5));
+        assert!(reserved.remaining() <= Duration::from_secs(5));
+        let gone = deadline.reserve(Duration::from_secs(20));
+        assert!(gone.is_expired());
+        assert_eq!(Duration::from_secs(0), gone.remaining());
+        gone.propagate(&mut headers);
+        assert_eq!("0", headers[TIMEOUT_HEADER]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Logging and metrics of outgoing requests.
+//!
+//! The [`Instrumentation`] configuration fragment turns on logging of slow requests. With the
+//! `metrics` feature, the requests can also be measured into a [`spirit-dipstick`] monitor (the
+//! shared metrics sink), see [`ClientMetrics`].
+//!
+//! The [`Instrumentation`] is usually configured as the `instrumentation` option of the
+//! [`ReqwestClient`][crate::ReqwestClient] and the metrics given to
+//! [`AtomicClient::with_metrics`][crate::AtomicClient::with_metrics]. Then each attempt of the
+//! requests sent through [`AtomicClient::execute`][crate::AtomicClient::execute] is observed.
+//! Otherwise, the requests are observed only when sent through [`Instrumented::execute`] or
+//! [`AtomicClient::execute_instrumented`][crate::AtomicClient::execute_instrumented].
+//!
+//! # Metrics
+//!
+//! These are placed under the name given to [`ClientMetrics::new`] and the host the request goes
+//! to:
+//!
+//! * `requests`: Number of requests sent.
+//! * `latency`: A timer of how long it takes to get the response.
+//! * `status.1xx` ‒ `status.5xx`: Number of responses in each status class.
+//! * `errors.timeout`, `errors.connect`, `errors.redirect`, `errors.other`: Number of requests
+//!   failed without a response, by the kind of the failure.
+//! * `circuit.state`: The state of the circuit to the host (`0` closed, `1` half-open, `2` open),
+//!   if the [`ClientMetrics`] are given to a [`CircuitBreaker`][crate::circuit::CircuitBreaker].
+//! * `circuit.refused`: Number of requests refused because of an open circuit.
+//!
+//! [`spirit-dipstick`]: https://crates.io/crates/spirit-dipstick
+
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "metrics")]
+use dipstick::{InputScope, Prefixed};
+use log::{debug, warn};
+use reqwest::{Client, Error, Request, Response};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "metrics")]
+use spirit_dipstick::Monitor;
+
+#[cfg(feature = "metrics")]
+use crate::circuit::CircuitState;
+
+/// Configuration of the observation of outgoing requests.
+///
+/// # Fields
+///
+/// * `slow-request-threshold`: Requests taking longer than this are logged as warnings. Default
+///   is `nil` (no logging).
+/// * `log-requests`: Log each request and its result on the debug level. Defaults to `false`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Instrumentation {
+    /// Log requests that take longer than this.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub slow_request_threshold: Option<Duration>,
+
+    /// Log each request on the debug level.
+    #[serde(default)]
+    pub log_requests: bool,
+}
+
+/// How a request failed.
+fn error_class(error: &Error) -> &'static str {
+    if error.is_timeout() {
+        "timeout"
+    } else if error.is_redirect() {
+        "redirect"
+    } else if error.is_http() {
+        "connect"
+    } else {
+        "other"
+    }
+}
+
+/// Metrics of outgoing requests.
+///
+/// Available with the `metrics` feature. See the [module documentation](index.html#metrics) for
+/// the list of metrics.
+#[cfg(feature = "metrics")]
+#[derive(Clone, Debug)]
+pub struct ClientMetrics {
+    scope: Monitor,
+}
+
+#[cfg(feature = "metrics")]
+impl ClientMetrics {
+    /// Creates the metrics under the given name inside the monitor.
+    pub fn new(monitor: &Monitor, name: &str) -> Self {
+        ClientMetrics {
+            scope: monitor.add_name(name),
+        }
+    }
+
+    fn record(&self, host: &str, elapsed: Duration, result: &Result<Response, Error>) {
+        let scope = self.scope.add_name(host);
+        scope.counter("requests").count(1);
+        let elapsed_us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        scope.timer("latency").interval_us(elapsed_us);
+        match result {
+            Ok(response) => {
+                let class = format!("{}xx", response.status().as_u16() / 100);
+                scope.add_name("status").counter(&class).count(1);
+            }
+            Err(e) => scope.add_name("errors").counter(error_class(e)).count(1),
+        }
+    }
+
+    pub(crate) fn record_circuit(&self, host: &str, state: CircuitState, refused: bool) {
+        let scope = self.scope.add_name(host).add_name("circuit");
+        let state = match state {
+            CircuitState::Closed => 0,
+            CircuitState::HalfOpen => 1,
+            CircuitState::Open => 2,
+        };
+        scope.gauge("state").value(state);
+        if refused {
+            scope.counter("refused").count(1);
+        }
+    }
+}
+
+/// A request observer.
+///
+/// This is built from [`Instrumentation`] (and optionally [`ClientMetrics`]) and it observes
+/// requests sent through it.
+#[derive(Clone, Debug, Default)]
+pub struct Instrumented {
+    cfg: Instrumentation,
+    #[cfg(feature = "metrics")]
+    metrics: Option<ClientMetrics>,
+}
+
+impl Instrumented {
+    /// Creates the observer according to the configuration.
+    pub fn new(cfg: Instrumentation) -> Self {
+        Instrumented {
+            cfg,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Adds metrics collection.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: ClientMetrics) -> Self {
+        Instrumented {
+            metrics: Some(metrics),
+            ..self
+        }
+    }
+
+    /// Executes the request on the client, observing it.
+    pub fn execute(&self, client: &Client, request: Request) -> Result<Response, Error> {
+        self.observe(request, |request| client.execute(request))
+    }
+
+    /// Observes the request sent by `send`.
+    pub(crate) fn observe<F>(&self, request: Request, send: F) -> Result<Response, Error>
+    where
+        F: FnOnce(Request) -> Result<Response, Error>,
+    {
+        let method = request.method().clone();
+        let url = request.url().clone();
+        let start = Instant::now();
+        let result = send(request);
+        let elapsed = start.elapsed();
+        let status = match &result {
+            Ok(response) => response.status().to_string(),
+            Err(e) => format!("error ({}): {}", error_class(e), e),
+        };
+        if self.cfg.log_requests {
+            debug!("{} {}: {} in {:?}", method, url, status, elapsed);
+        }
+        match self.cfg.slow_request_threshold {
+            Some(threshold) if elapsed > threshold => {
+                warn!(
+                    "Slow request {} {}: {} in {:?}",
+                    method, url, status, elapsed
+                );
+            }
+            _ => (),
+        }
+        #[cfg(feature = "metrics")]
+        {
+            if let Some(metrics) = &self.metrics {
+                let host = url.host_str().unwrap_or("unknown");
+                metrics.record(host, elapsed, &result);
+            }
+        }
+        result
+    }
+}
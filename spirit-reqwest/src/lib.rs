@@ -10,11 +10,15 @@
 //! This is part of the [`spirit`] system.
 //!
 //! There are two levels of support. The first one is just letting the [`Spirit`] to load the
-//! [`ReqwestClient`] configuration fragment and calling [`create`] or [`builder`] on it manually.
+//! [`ReqwestClient`] configuration fragment and calling [`create_client`] or [`builder`] on it
+//! manually.
 //!
 //! The other, more convenient way, is pairing an extractor function with the [`AtomicClient`] and
 //! letting [`Spirit`] keep an up to date version of [`Client`] in there at all times.
 //!
+//! For asynchronous (tokio based) code, the same configuration can be turned into the
+//! [`AsyncReqwestClient`] fragment and paired with the [`AtomicAsyncClient`].
+//!
 //! # Examples
 //!
 //! ```rust
@@ -51,7 +55,7 @@
 //! }
 //! ```
 //!
-//! [`create`]: ReqwestClient::create
+//! [`create_client`]: ReqwestClient::create_client
 //! [`builder`]: ReqwestClient::builder
 //! [`Spirit`]: spirit::Spirit
 
@@ -63,24 +67,45 @@ use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 
-use arc_swap::ArcSwapOption;
+use arc_swap::{ArcSwap, ArcSwapOption};
 use err_context::prelude::*;
-use log::{debug, trace};
+use futures::Future;
+use log::{debug, trace, warn};
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::r#async::{
+    Client as AsyncClient, ClientBuilder as AsyncClientBuilder, Request as AsyncRequest,
+    RequestBuilder as AsyncRequestBuilder, Response as AsyncResponse,
+};
 use reqwest::{
-    Certificate, Client, ClientBuilder, Identity, IntoUrl, Method, Proxy, RedirectPolicy,
-    RequestBuilder,
+    Certificate, Client, ClientBuilder, Identity, IntoUrl, Method, Proxy, RedirectPolicy, Request,
+    RequestBuilder, Response,
 };
 use serde::de::Deserializer;
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 use serde_humantime::De;
+use spirit::cfg::Duration as CfgDuration;
 use spirit::fragment::driver::CacheEq;
-use spirit::fragment::Installer;
+use spirit::fragment::{Installer, Stackable};
 use spirit::utils::Hidden;
 use spirit::AnyError;
+use spirit_tls::TlsConfig;
 use url_serde::SerdeUrl;
 
+use crate::cassette::Cassette;
+use crate::circuit::{CircuitBreaker, CircuitBreakerCfg};
+use crate::deadline::Deadline;
+#[cfg(feature = "metrics")]
+use crate::instrument::ClientMetrics;
+use crate::instrument::{Instrumentation, Instrumented};
+use crate::retry::RetryPolicy;
+
+pub mod cassette;
+pub mod circuit;
+pub mod deadline;
+pub mod instrument;
+pub mod retry;
+
 fn default_timeout() -> Option<Duration> {
     Some(Duration::from_secs(30))
 }
@@ -97,27 +122,88 @@ fn default_referer() -> bool {
     true
 }
 
-fn load_cert(path: &Path) -> Result<Certificate, AnyError> {
+fn default_redirect_cross_origin() -> bool {
+    true
+}
+
+const BEGIN_CERT: &str = "-----BEGIN CERTIFICATE-----";
+const END_CERT: &str = "-----END CERTIFICATE-----";
+
+fn read_file(path: &Path) -> Result<Vec<u8>, AnyError> {
     let mut input = File::open(path)?;
-    let mut cert = Vec::new();
-    input.read_to_end(&mut cert)?;
-    const BEGIN_CERT: &[u8] = b"-----BEGIN CERTIFICATE-----";
-    let contains_begin_cert = cert.windows(BEGIN_CERT.len()).any(|w| w == BEGIN_CERT);
-    let result = if contains_begin_cert {
-        trace!("Loading as PEM");
-        Certificate::from_pem(&cert)?
-    } else {
+    let mut content = Vec::new();
+    input.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn is_pem(content: &[u8]) -> bool {
+    content.windows(5).any(|w| w == b"-----")
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>, AnyError> {
+    let content = read_file(path)?;
+    if !is_pem(&content) {
         trace!("Loading as DER");
-        Certificate::from_der(&cert)?
-    };
+        let cert = Certificate::from_der(&content)?;
+        spirit_tls::expiry::track_file(path);
+        return Ok(vec![cert]);
+    }
+    trace!("Loading as PEM bundle");
+    let content = String::from_utf8(content)?;
+    let mut result = Vec::new();
+    let mut rest = content.as_str();
+    // A bundle may contain multiple certificates (and whatever comments between them), but the
+    // Certificate reads only one at a time.
+    while let Some(start) = rest.find(BEGIN_CERT) {
+        let end = rest[start..]
+            .find(END_CERT)
+            .map(|end| start + end + END_CERT.len())
+            .ok_or("Unterminated certificate in PEM bundle")?;
+        result.push(Certificate::from_pem(rest[start..end].as_bytes())?);
+        rest = &rest[end..];
+    }
+    if result.is_empty() {
+        return Err("No certificate found in the PEM file".into());
+    }
+    spirit_tls::expiry::track_file(path);
     Ok(result)
 }
 
-fn load_identity(path: &Path, passwd: &str) -> Result<Identity, AnyError> {
-    let mut input = File::open(path)?;
-    let mut identity = Vec::new();
-    input.read_to_end(&mut identity)?;
-    Ok(Identity::from_pkcs12_der(&identity, passwd)?)
+/// Loads the client identity.
+///
+/// Returns the identity and if it is in the PEM format (which needs the rustls backend).
+fn load_identity(
+    path: &Path,
+    key: Option<&Path>,
+    passwd: &str,
+) -> Result<(Identity, bool), AnyError> {
+    let mut identity = read_file(path)?;
+    if !is_pem(&identity) {
+        if key.is_some() {
+            return Err("A separate key can be used only with PEM identity".into());
+        }
+        trace!("Loading identity as PKCS12");
+        return Ok((Identity::from_pkcs12_der(&identity, passwd)?, false));
+    }
+    if let Some(key) = key {
+        let key = read_file(key).with_context(|_| format!("Failed to load key {:?}", key))?;
+        identity.push(b'\n');
+        identity.extend(key);
+    }
+    let identity = load_pem_identity(&identity)?;
+    spirit_tls::expiry::track_file(path);
+    Ok((identity, true))
+}
+
+#[cfg(feature = "rustls-tls")]
+fn load_pem_identity(identity: &[u8]) -> Result<Identity, AnyError> {
+    trace!("Loading identity as PEM");
+    Ok(Identity::from_pem(identity)?)
+}
+
+#[cfg(not(feature = "rustls-tls"))]
+fn load_pem_identity(_: &[u8]) -> Result<Identity, AnyError> {
+    Err("PEM client identities need the rustls-tls feature".into())
 }
 
 #[allow(clippy::trivially_copy_pass_by_ref)]
@@ -136,6 +222,175 @@ fn deserialize_opt_dur<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duratio
     Ok(dur.into_inner())
 }
 
+/// Deserializes either a single path or an array of them.
+fn deserialize_paths<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<PathBuf>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        One(PathBuf),
+        Many(Vec<PathBuf>),
+    }
+
+    match Raw::deserialize(d)? {
+        Raw::One(path) => Ok(vec![path]),
+        Raw::Many(paths) => Ok(paths),
+    }
+}
+
+/// Applies the configuration common for both the blocking and asynchronous client builders.
+///
+/// The builders are different types, but have the same methods.
+macro_rules! configure_builder {
+    ($cfg: expr, $builder: expr) => {{
+        let cfg = $cfg;
+        let mut headers = HeaderMap::new();
+        for (key, val) in &cfg.default_headers {
+            let name = HeaderName::from_bytes(key.as_bytes())
+                .with_context(|_| format!("{} is not a valiad header name", key))?;
+            let header = HeaderValue::from_bytes(val.as_bytes())
+                .with_context(|_| format!("{} is not a valid header", val))?;
+            headers.insert(name, header);
+        }
+        for (key, val) in &cfg.secret_headers {
+            let name = HeaderName::from_bytes(key.as_bytes())
+                .with_context(|_| format!("{} is not a valiad header name", key))?;
+            let mut header = HeaderValue::from_bytes(val.as_bytes())
+                .with_context(|_| format!("Secret header {} is not valid", key))?;
+            header.set_sensitive(true);
+            headers.insert(name, header);
+        }
+        if cfg.tls_accept_invalid_certs {
+            warn!(
+                "TLS certificate validation is turned off by tls-accept-invalid-certs, \
+                 the connections are NOT SECURE"
+            );
+        }
+        if cfg.tls_accept_invalid_hostnames {
+            warn!(
+                "TLS hostname validation is turned off by tls-accept-invalid-hostnames, \
+                 the connections are NOT SECURE"
+            );
+        }
+        let redirects = cfg.redirect_policy();
+        let mut builder = $builder
+            .danger_accept_invalid_certs(cfg.tls_accept_invalid_certs)
+            .danger_accept_invalid_hostnames(cfg.tls_accept_invalid_hostnames)
+            .gzip(cfg.enable_gzip)
+            .max_idle_per_host(cfg.max_idle_per_host.unwrap_or(usize::max_value()))
+            .local_address(cfg.local_address)
+            .default_headers(headers)
+            .redirect(redirects)
+            .referer(cfg.referer)
+            .cookie_store(cfg.cookies);
+        if cfg.tcp_nodelay {
+            builder = builder.tcp_nodelay();
+        }
+        if cfg.http2_only {
+            builder = builder.h2_prior_knowledge();
+        }
+        if cfg.http1_case_sensitive_headers {
+            builder = builder.http1_title_case_headers();
+        }
+        for cert_path in &cfg.tls_extra_root_certs {
+            trace!("Adding root certificate {:?}", cert_path);
+            let certs = load_certs(cert_path)
+                .with_context(|_| format!("Failed to load certificate {:?}", cert_path))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if !cfg.tls.files_only() {
+            return Err(
+                "Only the tls-cert, tls-key and tls-ca TLS options work with reqwest".into(),
+            );
+        }
+        let identity = match (&cfg.tls_identity, &cfg.tls.tls_cert, &cfg.tls.tls_key) {
+            (None, None, None) => None,
+            (Some(identity), None, None) => Some((identity, cfg.tls_identity_key.as_ref())),
+            (None, Some(cert), Some(key)) => Some((cert, Some(key))),
+            (Some(_), _, _) => {
+                return Err("Can't use tls-identity together with tls-cert or tls-key".into());
+            }
+            _ => return Err("Both tls-cert and tls-key need to be set".into()),
+        };
+        if let Some((identity_path, key)) = identity {
+            trace!("Setting TLS client identity {:?}", identity_path);
+            let passwd: &str = cfg
+                .tls_identity_password
+                .as_ref()
+                .map(|s| s as &str)
+                .unwrap_or_default();
+            let key = key.map(PathBuf::as_path);
+            let (identity, pem) = load_identity(&identity_path, key, passwd)
+                .with_context(|_| format!("Failed to load identity {:?}", identity_path))?;
+            if pem {
+                // PEM identities are supported only by the rustls backend (and we won't get here
+                // without it)
+                #[cfg(feature = "rustls-tls")]
+                {
+                    builder = builder.use_rustls_tls();
+                }
+            }
+            builder = builder.identity(identity);
+        }
+        if let Some(proxy) = &cfg.http_proxy {
+            let proxy_url = proxy.clone().into_inner();
+            let proxy = Proxy::http(proxy_url)
+                .with_context(|_| format!("Failed to configure http proxy to {:?}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(proxy) = &cfg.https_proxy {
+            let proxy_url = proxy.clone().into_inner();
+            let proxy = Proxy::https(proxy_url)
+                .with_context(|_| format!("Failed to configure https proxy to {:?}", proxy))?;
+            builder = builder.proxy(proxy);
+        }
+
+        Ok(builder)
+    }};
+}
+
+/// Settings overridden for a specific host.
+///
+/// Used inside the `host-overrides` of [`ReqwestClient`]. Whatever is set here replaces the
+/// setting of the [`ReqwestClient`], headers are added to the ones there.
+///
+/// # Fields
+///
+/// * `timeout`, `connect-timeout`: The timeouts for this host (with units, like `500ms`).
+/// * `http-proxy`, `https-proxy`: The proxies for this host.
+/// * `default-headers`, `secret-headers`: Additional headers for this host.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct HostOverride {
+    /// A whole-request timeout for this host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timeout: Option<CfgDuration>,
+
+    /// A timeout for connecting to this host.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<CfgDuration>,
+
+    /// An URL for proxy to use on HTTP requests to this host.
+    #[structdoc(leaf = "URL")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http_proxy: Option<SerdeUrl>,
+
+    /// An URL for proxy to use on HTTPS requests to this host.
+    #[structdoc(leaf = "URL")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    https_proxy: Option<SerdeUrl>,
+
+    /// Additional headers for this host.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    default_headers: HashMap<String, String>,
+
+    /// Additional headers with secret values for this host.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    secret_headers: HashMap<String, Hidden<String>>,
+}
+
 /// A configuration fragment to configure the reqwest [`Client`]
 ///
 /// This carries configuration used to build a reqwest [`Client`]. An empty configuration
@@ -146,10 +401,23 @@ fn deserialize_opt_dur<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duratio
 ///
 /// # Fields
 ///
-/// * `extra-root-certs`: Array of paths, all will be loaded and *added* to the default
-///   certification store. Can be either PEM or DER.
-/// * `tls-identity`: A client identity to use to authenticate to the server. Needs to be a PKCS12
-///   DER bundle. A password might be specified by the `tls-identity-password` field.
+/// * `tls-extra-root-certs` (or `tls-ca`, the spelling shared with the other crates, see
+///   [`spirit-tls`][spirit_tls]): Array of paths (or a single path), all will be loaded and
+///   *added* to the default certification store. Can be either PEM (possibly a bundle of
+///   multiple certificates) or DER.
+/// * `tls-identity`: A client identity to use to authenticate to the server. Either a PKCS12 DER
+///   bundle (a password might be specified by the `tls-identity-password` field) or a PEM file
+///   with the certificate chain (needs the `rustls-tls` feature).
+/// * `tls-identity-key`: The private key for a PEM `tls-identity`, if it is not in the same file.
+/// * `tls-cert`, `tls-key`: A client identity in the spelling shared with the other crates, see
+///   [`spirit-tls`][spirit_tls]. A PEM certificate chain and its private key (needs the
+///   `rustls-tls` feature). Can't be used together with `tls-identity`.
+///   The other options of [`spirit-tls`][spirit_tls] (`tls-min-version`, `tls-max-version`,
+///   `tls-cipher-suites` and `tls-alpn`) can't be honored by reqwest and are refused. So is the
+///   `tls-ca-refresh` ‒ reqwest reads the authorities only when the client is built, so changed
+///   files take effect with the next configuration change that rebuilds the client. The
+///   [`refresh_all`][spirit_tls::trust::refresh_all] (and the `refresh-ca` command of
+///   `spirit-admin`) doesn't reach these clients.
 /// * `tls-accept-invalid-hostnames`: If set to true, it accepts invalid hostnames on https.
 ///   **Dangerous**, avoid if possible (default is `false`).
 /// * `tls-accept-invalid-certs`: Allow accepting invalid https certificates. **Dangerous**, avoid
@@ -157,21 +425,48 @@ fn deserialize_opt_dur<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Duratio
 /// * `enable-gzip`: Enable gzip compression of transferred data. Default is `true`.
 /// * `default-headers`: A bundle of headers a request starts with. Map of name-value, defaults to
 ///   empty.
+/// * `secret-headers`: Same as `default-headers`, but the values are not shown when dumping the
+///   configuration (eg. authentication tokens).
+/// * `host-overrides`: Map of host name to a [`HostOverride`] block. These settings apply to a
+///   client created through [`for_host`][ReqwestClient::for_host].
 /// * `timeout`: Default whole-request timeout. Can be a time specification (with units) or `nil`
 ///   for no timeout. Default is `30s`.
 /// * `connect-timeout`: Timeout for the connection phase of a request (with units) or `nil` for no
 ///   such timeout. Default is no timeout.
-/// * `max-idle-per-host`: Maximal number of idle connection per one host in the pool. Defaults to
-///   `nil` (no limit).
-/// * `http2-only`: Use only HTTP/2. Default is false (both HTTP/1 and HTTP/2 are allowed).
+/// * `max-idle-per-host` (or `pool-max-idle-per-host`): Maximal number of idle connection per one
+///   host in the pool. Defaults to `nil` (no limit).
+/// * `http2-only` (or `http2-prior-knowledge`): Use only HTTP/2. Default is false (both HTTP/1
+///   and HTTP/2 are allowed).
 /// * `http1-case-sensitive-headers`: Consider HTTP/1 headers case sensitive.
 /// * `local-address`: Make the requests from this address. Default is `nil`, which lets the OS to
 ///   choose.
 /// * `http-proxy`: An URL of proxy that serves http requests.
 /// * `https-proxy`: An URL of proxy that servers https requests.
 /// * `redirects`: Number of allowed redirects per one request, `nil` to disable. Defaults to `10`.
+/// * `redirect-cross-origin`: Follow redirects to other origins (scheme, host, port). Defaults to
+///   `true`.
+/// * `cookies`: Keep cookies in an in-memory cookie store. Defaults to `false`.
 /// * `referer`: Allow automatic setting of the referer header. Defaults to `true`.
 /// * `tcp-nodelay`: Use the `SO_NODELAY` flag on all connections.
+/// * `record`: Path to a file to record the responses into, see the [`cassette`] module.
+/// * `replay`: Path to a file to replay the responses from instead of sending the requests, see
+///   the [`cassette`] module. Can't be used together with `record`.
+/// * `circuit-breaker`: Stop sending requests to failing hosts for a while, see the [`circuit`]
+///   module. Off if not set.
+/// * `retry`: Retry failed requests according to this [`RetryPolicy`], see the [`retry`] module.
+///   Off if not set.
+/// * `instrumentation`: Log the requests, see the [`instrument`] module. Off if not set.
+///
+/// The `record`, `replay`, `circuit-breaker`, `retry` and `instrumentation` options apply to the
+/// requests sent through [`AtomicClient::execute`] (and
+/// [`execute_within`][AtomicClient::execute_within]) of the [`AtomicClient`] the client is
+/// installed into.
+///
+/// The `pool-idle-timeout`, `http2-keep-alive` and `tcp-keepalive` options known from newer
+/// reqwest versions are not available, reqwest 0.9 used here has no such settings.
+///
+/// The expiry of the loaded certificates (except for PKCS12 identities) is tracked, see the
+/// [`expiry`][spirit_tls::expiry] module of `spirit-tls`.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 #[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
 #[serde(rename_all = "kebab-case")]
@@ -187,19 +482,35 @@ pub struct ReqwestClient {
     /// Certificates in these files will be considered trusted in addition to the system trust
     /// store.
     ///
-    /// Accepts PEM and DER formats (autodetected).
-    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    /// Accepts PEM and DER formats (autodetected). A PEM file may contain a whole bundle of
+    /// certificates.
+    ///
+    /// Can also be spelled as `tls-ca` and can be a single path.
+    #[serde(
+        default,
+        alias = "tls-ca",
+        deserialize_with = "deserialize_paths",
+        skip_serializing_if = "Vec::is_empty"
+    )]
     tls_extra_root_certs: Vec<PathBuf>,
 
     /// Client identity.
     ///
     /// A file with client certificate and private key that'll be used to authenticate against the
-    /// server. This needs to be a PKCS12 format.
+    /// server. This can be either in the PKCS12 format or PEM (autodetected). PEM identities
+    /// need the `rustls-tls` feature.
     ///
     /// If not set, no client identity is used.
     #[serde(skip_serializing_if = "Option::is_none")]
     tls_identity: Option<PathBuf>,
 
+    /// A private key for the client identity.
+    ///
+    /// Can be used only if the `tls-identity` is in the PEM format and doesn't contain the key
+    /// itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_identity_key: Option<PathBuf>,
+
     /// A password for the client identity file.
     ///
     /// If tls-identity is not set, the value here is ignored. If not set and the tls-identity is
@@ -207,6 +518,12 @@ pub struct ReqwestClient {
     #[serde(skip_serializing_if = "Option::is_none")]
     tls_identity_password: Option<Hidden<String>>,
 
+    /// The TLS options shared with the other spirit crates.
+    ///
+    /// Only the certificate, key and certificate authorities are supported.
+    #[serde(flatten)]
+    tls: TlsConfig,
+
     /// When validating the server certificate, accept even invalid or not matching hostnames.
     ///
     /// **DANGEROUS**
@@ -243,6 +560,21 @@ pub struct ReqwestClient {
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     default_headers: HashMap<String, String>,
 
+    /// Headers added to each request, with secret values.
+    ///
+    /// This is like `default-headers`, but the values are hidden when dumping the configuration
+    /// and marked as sensitive in the requests. Useful for authentication tokens (which can be
+    /// passed through an environment variable, for example).
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    secret_headers: HashMap<String, Hidden<String>>,
+
+    /// Settings specific for some hosts.
+    ///
+    /// Keyed by the host name. These are used by clients created for the specific host through
+    /// [`for_host`][ReqwestClient::for_host].
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    host_overrides: HashMap<String, HostOverride>,
+
     /// A whole-request timeout.
     ///
     /// If the request doesn't happen during this time, it gives up.
@@ -286,6 +618,22 @@ pub struct ReqwestClient {
     #[serde(default = "default_redirects")]
     redirects: Option<usize>,
 
+    /// Follow redirects leading to a different origin (scheme, host and port) than the one of the
+    /// original request.
+    ///
+    /// Default is on.
+    #[serde(default = "default_redirect_cross_origin")]
+    redirect_cross_origin: bool,
+
+    /// Keep cookies set by the servers and send them back in further requests.
+    ///
+    /// The cookies are held in memory only and are lost when the client is replaced by a
+    /// reconfiguration.
+    ///
+    /// Default is off.
+    #[serde(default, skip_serializing_if = "is_false")]
+    cookies: bool,
+
     /// Manages automatic setting of the Referer header.
     ///
     /// Default is on.
@@ -294,14 +642,23 @@ pub struct ReqwestClient {
 
     /// Maximum number of idle connections per one host.
     ///
+    /// Can also be spelled as `pool-max-idle-per-host`.
+    ///
     /// Default is no limit.
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(
+        default,
+        alias = "pool-max-idle-per-host",
+        skip_serializing_if = "Option::is_none"
+    )]
     max_idle_per_host: Option<usize>,
 
     /// Use only HTTP/2.
     ///
+    /// This assumes the server speaks HTTP/2 without negotiating it first. Can also be spelled as
+    /// `http2-prior-knowledge`.
+    ///
     /// Default is false.
-    #[serde(default)]
+    #[serde(default, alias = "http2-prior-knowledge")]
     http2_only: bool,
 
     /// Use HTTP/1 headers in case sensitive manner.
@@ -313,6 +670,36 @@ pub struct ReqwestClient {
     /// Default is no address (the OS will choose).
     #[serde(default, skip_serializing_if = "Option::is_none")]
     local_address: Option<IpAddr>,
+
+    /// Configuration of the circuit breaking.
+    ///
+    /// Applies to requests through [`AtomicClient::execute`]. Off if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    circuit_breaker: Option<CircuitBreakerCfg>,
+
+    /// Record the responses into this file.
+    ///
+    /// Applies to requests through [`AtomicClient::execute`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    record: Option<PathBuf>,
+
+    /// Replay the responses from this file instead of sending the requests.
+    ///
+    /// Applies to requests through [`AtomicClient::execute`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replay: Option<PathBuf>,
+
+    /// Retry the failed requests.
+    ///
+    /// Applies to requests through [`AtomicClient::execute`]. Off if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry: Option<RetryPolicy>,
+
+    /// Log the requests.
+    ///
+    /// Applies to requests through [`AtomicClient::execute`]. Off if not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    instrumentation: Option<Instrumentation>,
 }
 
 impl Default for ReqwestClient {
@@ -320,100 +707,175 @@ impl Default for ReqwestClient {
         ReqwestClient {
             tls_extra_root_certs: Vec::new(),
             tls_identity: None,
+            tls_identity_key: None,
             tls_identity_password: None,
+            tls: TlsConfig::default(),
             tls_accept_invalid_hostnames: false,
             tls_accept_invalid_certs: false,
             enable_gzip: default_gzip(),
             default_headers: HashMap::new(),
+            secret_headers: HashMap::new(),
+            host_overrides: HashMap::new(),
             timeout: default_timeout(),
             connect_timeout: None,
             http_proxy: None,
             https_proxy: None,
             redirects: default_redirects(),
+            redirect_cross_origin: default_redirect_cross_origin(),
+            cookies: false,
             referer: default_referer(),
             http2_only: false,
             http1_case_sensitive_headers: false,
             max_idle_per_host: None,
             tcp_nodelay: false,
             local_address: None,
+            circuit_breaker: None,
+            record: None,
+            replay: None,
+            retry: None,
+            instrumentation: None,
         }
     }
 }
 
 impl ReqwestClient {
+    /// Returns the configuration for a client talking to a specific host.
+    ///
+    /// This applies the override block for the host from `host-overrides` (if any). The result
+    /// can be used as a fragment on its own, for example to keep a separate [`AtomicClient`] for
+    /// a specific upstream:
+    ///
+    /// ```rust
+    /// use serde::Deserialize;
+    /// use spirit::{Empty, Pipeline, Spirit};
+    /// use spirit::prelude::*;
+    /// use spirit_reqwest::{AtomicClient, ReqwestClient};
+    ///
+    /// #[derive(Debug, Default, Deserialize)]
+    /// struct Cfg {
+    ///     #[serde(default)]
+    ///     client: ReqwestClient,
+    /// }
+    ///
+    /// impl Cfg {
+    ///     fn upstream_client(&self) -> ReqwestClient {
+    ///         self.client.for_host("upstream.example.com")
+    ///     }
+    /// }
+    ///
+    /// fn main() {
+    ///     let upstream = AtomicClient::empty();
+    ///     Spirit::<Empty, Cfg>::new()
+    ///         .with(
+    ///             Pipeline::new("upstream client")
+    ///                 .extract_cfg(Cfg::upstream_client)
+    ///                 .install(upstream.clone())
+    ///         )
+    ///         .run(|_| Ok(()));
+    /// }
+    /// ```
+    pub fn for_host(&self, host: &str) -> ReqwestClient {
+        let mut cfg = self.clone();
+        cfg.host_overrides.clear();
+        if let Some(host_override) = self.host_overrides.get(host) {
+            trace!("Applying overrides for host {}", host);
+            if host_override.timeout.is_some() {
+                cfg.timeout = host_override.timeout.map(Into::into);
+            }
+            if host_override.connect_timeout.is_some() {
+                cfg.connect_timeout = host_override.connect_timeout.map(Into::into);
+            }
+            if host_override.http_proxy.is_some() {
+                cfg.http_proxy = host_override.http_proxy.clone();
+            }
+            if host_override.https_proxy.is_some() {
+                cfg.https_proxy = host_override.https_proxy.clone();
+            }
+            cfg.default_headers.extend(
+                host_override
+                    .default_headers
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+            cfg.secret_headers.extend(
+                host_override
+                    .secret_headers
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone())),
+            );
+        }
+        cfg
+    }
+
+    /// The configuration of the circuit breaking, if it is turned on.
+    ///
+    /// The [`AtomicClient`] configures its breaker from here by itself. This is for configuring a
+    /// standalone [`CircuitBreaker`] by [`CircuitBreaker::configure`].
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreakerCfg> {
+        self.circuit_breaker.as_ref()
+    }
+
+    fn cassette(&self) -> Result<Option<Cassette>, AnyError> {
+        match (&self.record, &self.replay) {
+            (Some(_), Some(_)) => Err("Can't both record and replay requests".into()),
+            (Some(path), None) => Cassette::record(path).map(Some),
+            (None, Some(path)) => Cassette::replay(path).map(Some),
+            (None, None) => Ok(None),
+        }
+    }
+
+    fn redirect_policy(&self) -> RedirectPolicy {
+        match (self.redirects, self.redirect_cross_origin) {
+            (None, _) => RedirectPolicy::none(),
+            (Some(limit), true) => RedirectPolicy::limited(limit),
+            (Some(limit), false) => RedirectPolicy::custom(move |attempt| {
+                let same_origin = attempt
+                    .previous()
+                    .first()
+                    .map(|first| first.origin() == attempt.url().origin())
+                    .unwrap_or(true);
+                if attempt.previous().len() > limit {
+                    attempt.too_many_redirects()
+                } else if same_origin {
+                    attempt.follow()
+                } else {
+                    debug!("Not following cross-origin redirect to {}", attempt.url());
+                    attempt.stop()
+                }
+            }),
+        }
+    }
+
     /// Creates a pre-configured [`ClientBuilder`]
     ///
     /// This configures everything according to `self` and then returns the builder. The caller can
     /// modify it further and then create the client.
     ///
-    /// Unless there's a need to tweak the configuration, the [`create`] is more comfortable.
+    /// Unless there's a need to tweak the configuration, the [`create_client`] is more
+    /// comfortable.
     ///
-    /// [`create_client`]: ReqwestClient::create
+    /// [`create_client`]: ReqwestClient::create_client
     pub fn builder(&self) -> Result<ClientBuilder, AnyError> {
         debug!("Creating Reqwest client from {:?}", self);
-        let mut headers = HeaderMap::new();
-        for (key, val) in &self.default_headers {
-            let name = HeaderName::from_bytes(key.as_bytes())
-                .with_context(|_| format!("{} is not a valiad header name", key))?;
-            let header = HeaderValue::from_bytes(val.as_bytes())
-                .with_context(|_| format!("{} is not a valid header", val))?;
-            headers.insert(name, header);
-        }
-        let redirects = match self.redirects {
-            None => RedirectPolicy::none(),
-            Some(limit) => RedirectPolicy::limited(limit),
-        };
-        let mut builder = Client::builder()
-            .danger_accept_invalid_certs(self.tls_accept_invalid_certs)
-            .danger_accept_invalid_hostnames(self.tls_accept_invalid_hostnames)
-            .gzip(self.enable_gzip)
+        let builder = Client::builder()
             .timeout(self.timeout)
-            .connect_timeout(self.connect_timeout)
-            .max_idle_per_host(self.max_idle_per_host.unwrap_or(usize::max_value()))
-            .local_address(self.local_address)
-            .default_headers(headers)
-            .redirect(redirects)
-            .referer(self.referer);
-        if self.tcp_nodelay {
-            builder = builder.tcp_nodelay();
-        }
-        if self.http2_only {
-            builder = builder.h2_prior_knowledge();
-        }
-        if self.http1_case_sensitive_headers {
-            builder = builder.http1_title_case_headers();
-        }
-        for cert_path in &self.tls_extra_root_certs {
-            trace!("Adding root certificate {:?}", cert_path);
-            let cert = load_cert(cert_path)
-                .with_context(|_| format!("Failed to load certificate {:?}", cert_path))?;
-            builder = builder.add_root_certificate(cert);
-        }
-        if let Some(identity_path) = &self.tls_identity {
-            trace!("Setting TLS client identity {:?}", identity_path);
-            let passwd: &str = self
-                .tls_identity_password
-                .as_ref()
-                .map(|s| s as &str)
-                .unwrap_or_default();
-            let identity = load_identity(&identity_path, passwd)
-                .with_context(|_| format!("Failed to load identity {:?}", identity_path))?;
-            builder = builder.identity(identity);
-        }
-        if let Some(proxy) = &self.http_proxy {
-            let proxy_url = proxy.clone().into_inner();
-            let proxy = Proxy::http(proxy_url)
-                .with_context(|_| format!("Failed to configure http proxy to {:?}", proxy))?;
-            builder = builder.proxy(proxy);
+            .connect_timeout(self.connect_timeout);
+        configure_builder!(self, builder)
+    }
+
+    /// Creates a pre-configured asynchronous [`ClientBuilder`][AsyncClientBuilder].
+    ///
+    /// This is the same as [`builder`][ReqwestClient::builder], but for the asynchronous client.
+    pub fn async_builder(&self) -> Result<AsyncClientBuilder, AnyError> {
+        debug!("Creating asynchronous Reqwest client from {:?}", self);
+        let mut builder = AsyncClient::builder();
+        if let Some(timeout) = self.timeout {
+            builder = builder.timeout(timeout);
         }
-        if let Some(proxy) = &self.http_proxy {
-            let proxy_url = proxy.clone().into_inner();
-            let proxy = Proxy::https(proxy_url)
-                .with_context(|_| format!("Failed to configure https proxy to {:?}", proxy))?;
-            builder = builder.proxy(proxy);
+        if let Some(timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(timeout);
         }
-
-        Ok(builder)
+        configure_builder!(self, builder)
     }
 
     /// Creates a [`Client`] according to the configuration inside `self`.
@@ -426,6 +888,17 @@ impl ReqwestClient {
             .context("Failed to finish creating Reqwest HTTP client")
             .map_err(AnyError::from)
     }
+
+    /// Creates an asynchronous [`Client`][AsyncClient] according to the configuration.
+    ///
+    /// It is also possible to get one by pairing [`AsyncReqwestClient`] with
+    /// [`AtomicAsyncClient`].
+    pub fn create_async_client(&self) -> Result<AsyncClient, AnyError> {
+        self.async_builder()?
+            .build()
+            .context("Failed to finish creating asynchronous Reqwest HTTP client")
+            .map_err(AnyError::from)
+    }
 }
 
 /// A storage for one [`Client`] that can be atomically exchanged under the hood.
@@ -461,7 +934,13 @@ impl ReqwestClient {
 /// [`client`]: AtomicClient::client
 /// [`get`]: AtomicClient::get
 #[derive(Clone, Debug)]
-pub struct AtomicClient(Arc<ArcSwapOption<Client>>);
+pub struct AtomicClient {
+    client: Arc<ArcSwapOption<Client>>,
+    layers: Arc<ArcSwap<Layers>>,
+    breaker: CircuitBreaker,
+    #[cfg(feature = "metrics")]
+    metrics: Option<ClientMetrics>,
+}
 
 impl Default for AtomicClient {
     fn default() -> Self {
@@ -471,16 +950,18 @@ impl Default for AtomicClient {
 
 impl<C: Into<Arc<Client>>> From<C> for AtomicClient {
     fn from(c: C) -> Self {
-        AtomicClient(Arc::new(ArcSwapOption::from(Some(c.into()))))
+        let client = AtomicClient::empty();
+        client.replace(c);
+        client
     }
 }
 
 macro_rules! method {
-    ($($(#[$attr: meta])* $name: ident();)*) => {
+    ($builder: ty; $($(#[$attr: meta])* $name: ident();)*) => {
         $(
             $(#[$attr])*
-            pub fn $name<U: IntoUrl>(&self, url: U) -> RequestBuilder {
-                self.0
+            pub fn $name<U: IntoUrl>(&self, url: U) -> $builder {
+                self.client
                     .load()
                     .as_ref()
                     .expect("Accessing Reqwest HTTP client before setting it up")
@@ -501,12 +982,36 @@ impl AtomicClient {
     /// [`replace`]: AtomicClient::replace
     /// [`Spirit`]: spirit::Spirit
     pub fn empty() -> Self {
-        AtomicClient(Arc::new(ArcSwapOption::empty()))
+        AtomicClient {
+            client: Arc::new(ArcSwapOption::empty()),
+            layers: Arc::new(ArcSwap::from_pointee(Layers::default())),
+            breaker: CircuitBreaker::new(),
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
     }
 
     /// Creates an [`AtomicClient`] with default [`Client`] inside.
     pub fn unconfigured() -> Self {
-        AtomicClient(Arc::new(ArcSwapOption::from_pointee(Client::new())))
+        Client::new().into()
+    }
+
+    /// Reports the requests sent through [`execute`][AtomicClient::execute] and the states of
+    /// the circuits into the metrics.
+    ///
+    /// This replaces the [`CircuitBreaker`] of this client (forgetting the states of the
+    /// circuits), so it is meant to be called right after creating it, before it is cloned (eg.
+    /// into a [`Pipeline`][spirit::Pipeline]). The requests are measured once the client is
+    /// installed by the [`Pipeline`][spirit::Pipeline].
+    ///
+    /// Available with the `metrics` feature.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics(self, metrics: ClientMetrics) -> Self {
+        AtomicClient {
+            breaker: CircuitBreaker::with_metrics(metrics.clone()),
+            metrics: Some(metrics),
+            ..self
+        }
     }
 
     /// Replaces the content of this [`AtomicClient`] with a new [`Client`].
@@ -518,7 +1023,15 @@ impl AtomicClient {
     /// original [`AtomicClient`]).
     pub fn replace<C: Into<Arc<Client>>>(&self, by: C) {
         let client = by.into();
-        self.0.store(Some(client));
+        self.client.store(Some(client));
+    }
+
+    /// The circuit breaker guarding the requests sent through [`execute`][AtomicClient::execute].
+    ///
+    /// It is configured from the `circuit-breaker` option when the client is installed by a
+    /// [`Pipeline`][spirit::Pipeline]. See the [`circuit`] module.
+    pub fn circuit_breaker(&self) -> &CircuitBreaker {
+        &self.breaker
     }
 
     /// Returns a handle to the [`Client`] currently held inside.
@@ -531,7 +1044,7 @@ impl AtomicClient {
     ///   the [`Arc`] can't. While it is possible the client inside [`AtomicClient`] exchanged, the
     ///   [`Arc`] keeps its [`Client`] around (which may lead to multiple [`Client`]s in memory).
     pub fn client(&self) -> Arc<Client> {
-        self.0
+        self.client
             .load_full()
             .expect("Accessing Reqwest HTTP client before setting it up")
     }
@@ -540,13 +1053,130 @@ impl AtomicClient {
     ///
     /// This is forwarded to [`Client::request`].
     pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> RequestBuilder {
-        self.0
+        self.client
             .load()
             .as_ref()
             .expect("Accessing Reqwest HTTP client before setting it up")
             .request(method, url)
     }
+
+    /// Executes a request using the current client.
+    ///
+    /// The request goes through the layers configured in the [`ReqwestClient`] the client was
+    /// installed from. From the outside in, these are:
+    ///
+    /// * Recording or replaying (see the [`cassette`] module). A replayed request is not sent at
+    ///   all.
+    /// * The [circuit breaker][AtomicClient::circuit_breaker] (see the [`circuit`] module).
+    /// * The retries (see the [`retry`] module). All the attempts are made using the same client.
+    /// * The instrumentation of each attempt (see the [`instrument`] module), measured into the
+    ///   [metrics][AtomicClient::with_metrics] if there are any.
+    ///
+    /// The layers not configured pass the request through.
+    pub fn execute(&self, request: Request) -> Result<Response, AnyError> {
+        self.send(request, None)
+    }
+
+    /// Executes a request using the current client, but only until the deadline.
+    ///
+    /// This is like [`execute`][AtomicClient::execute], but no attempt is started and no retry
+    /// is waited for after the deadline passes, failing with
+    /// [`DeadlineExceeded`][deadline::DeadlineExceeded]. See the [`deadline`] module.
+    pub fn execute_within(
+        &self,
+        request: Request,
+        deadline: Deadline,
+    ) -> Result<Response, AnyError> {
+        self.send(request, Some(deadline))
+    }
+
+    fn send(&self, request: Request, deadline: Option<Deadline>) -> Result<Response, AnyError> {
+        let client = self.client();
+        let layers = self.layers.load_full();
+        let attempt = |request| {
+            layers
+                .instrumented
+                .observe(request, |request| client.execute(request))
+        };
+        // The deadline is enforced by the retrying, so make a single attempt if there's no policy
+        let single;
+        let policy = match (&layers.retry, deadline) {
+            (Some(policy), _) => Some(policy),
+            (None, Some(_)) => {
+                single = RetryPolicy {
+                    max_attempts: 1,
+                    ..RetryPolicy::default()
+                };
+                Some(&single)
+            }
+            (None, None) => None,
+        };
+        let send = |request| {
+            self.breaker.guard(request, |request| match policy {
+                Some(policy) => policy.execute_through(request, deadline, &attempt),
+                None => attempt(request).map_err(AnyError::from),
+            })
+        };
+        match &layers.cassette {
+            Some(cassette) => cassette.execute(request, send),
+            None => send(request),
+        }
+    }
+
+    /// Executes a request, retrying it according to the policy.
+    ///
+    /// All the attempts are made using the same client. See [`RetryPolicy::execute`]. This uses
+    /// the given policy instead of the configured one and skips the other layers of
+    /// [`execute`][AtomicClient::execute].
+    pub fn execute_with_retry(
+        &self,
+        policy: &RetryPolicy,
+        request: Request,
+    ) -> Result<Response, reqwest::Error> {
+        policy.execute(&self.client(), request)
+    }
+
+    /// Executes a request, retrying it according to the policy, but only until the deadline.
+    ///
+    /// All the attempts are made using the same client. See [`RetryPolicy::execute_within`] and
+    /// the [`deadline`] module. This uses the given policy instead of the configured one and
+    /// skips the other layers of [`execute_within`][AtomicClient::execute_within].
+    pub fn execute_with_deadline(
+        &self,
+        policy: &RetryPolicy,
+        deadline: Deadline,
+        request: Request,
+    ) -> Result<Response, AnyError> {
+        policy.execute_within(&self.client(), request, deadline)
+    }
+
+    /// Executes a request, unless the circuit to its host is open.
+    ///
+    /// This uses the given breaker instead of the [own one][AtomicClient::circuit_breaker] and
+    /// skips the other layers of [`execute`][AtomicClient::execute]. See the [`circuit`] module.
+    pub fn execute_guarded(
+        &self,
+        breaker: &CircuitBreaker,
+        request: Request,
+    ) -> Result<Response, AnyError> {
+        breaker.execute(&self.client(), request)
+    }
+
+    /// Executes a request, logging it and collecting metrics about it.
+    ///
+    /// This uses the given observer instead of the configured one and skips the other layers of
+    /// [`execute`][AtomicClient::execute]. See the [`instrument`] module.
+    pub fn execute_instrumented(
+        &self,
+        instrumented: &Instrumented,
+        request: Request,
+    ) -> Result<Response, reqwest::Error> {
+        instrumented.execute(&self.client(), request)
+    }
+
     method! {
+        RequestBuilder;
+
         /// Starts building a GET request.
         ///
         /// This is forwarded to [`Client::get`].
@@ -582,18 +1212,289 @@ impl AtomicClient {
 spirit::simple_fragment! {
     impl Fragment for ReqwestClient {
         type Driver = CacheEq<ReqwestClient>;
-        type Resource = Client;
+        type Resource = ConfiguredClient;
         type Installer = ();
-        fn create(&self, _: &'static str) -> Result<Client, AnyError> {
-            self.create_client()
+        fn create(&self, _: &'static str) -> Result<ConfiguredClient, AnyError> {
+            let client = self.create_client()?;
+            let layers = Layers {
+                cassette: self.cassette()?.map(Arc::new),
+                circuit_breaker: self.circuit_breaker.clone(),
+                retry: self.retry.clone(),
+                instrumented: Instrumented::new(self.instrumentation.clone().unwrap_or_default()),
+            };
+            Ok(ConfiguredClient { client, layers })
         }
     }
 }
 
-impl<O, C> Installer<Client, O, C> for AtomicClient {
+impl Stackable for ReqwestClient {}
+
+/// A [`Client`] together with the layers configured for it.
+///
+/// This is the resource produced by the [`ReqwestClient`] fragment and installed into an
+/// [`AtomicClient`], which sends the requests through the layers (see
+/// [`AtomicClient::execute`]). Usually doesn't need to be used directly (this is more of a
+/// plumbing type).
+#[derive(Debug)]
+pub struct ConfiguredClient {
+    client: Client,
+    layers: Layers,
+}
+
+impl ConfiguredClient {
+    /// The client itself.
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// Disassembles it into the client, dropping the layers.
+    pub fn into_client(self) -> Client {
+        self.client
+    }
+}
+
+/// What the [`AtomicClient`] sends the requests through, besides the client itself.
+#[derive(Debug, Default)]
+struct Layers {
+    cassette: Option<Arc<Cassette>>,
+    circuit_breaker: Option<CircuitBreakerCfg>,
+    retry: Option<RetryPolicy>,
+    instrumented: Instrumented,
+}
+
+impl<O, C> Installer<ConfiguredClient, O, C> for AtomicClient {
     type UninstallHandle = ();
-    fn install(&mut self, client: Client, name: &'static str) {
+    fn install(&mut self, configured: ConfiguredClient, name: &'static str) {
         debug!("Installing http client '{}'", name);
+        let ConfiguredClient { client, layers } = configured;
+        #[cfg(feature = "metrics")]
+        let layers = match self.metrics.clone() {
+            Some(metrics) => Layers {
+                instrumented: layers.instrumented.with_metrics(metrics),
+                ..layers
+            },
+            None => layers,
+        };
+        self.replace(client);
+        self.breaker.install(name, layers.circuit_breaker.as_ref());
+        self.layers.store(Arc::new(layers));
+    }
+}
+
+/// A configuration fragment for the asynchronous reqwest [`Client`][AsyncClient].
+///
+/// This is just a thin wrapper around [`ReqwestClient`] (the configuration is exactly the same),
+/// but it produces the asynchronous client instead of the blocking one. It is meant to be paired
+/// with [`AtomicAsyncClient`].
+///
+/// # Examples
+///
+/// ```rust
+/// use serde::Deserialize;
+/// use spirit::{Empty, Pipeline, Spirit};
+/// use spirit::prelude::*;
+/// use spirit_reqwest::{AsyncReqwestClient, AtomicAsyncClient, ReqwestClient};
+///
+/// #[derive(Debug, Default, Deserialize)]
+/// struct Cfg {
+///     #[serde(default)]
+///     client: ReqwestClient,
+/// }
+///
+/// impl Cfg {
+///     fn client(&self) -> AsyncReqwestClient {
+///         self.client.clone().into()
+///     }
+/// }
+///
+/// fn main() {
+///     let client = AtomicAsyncClient::empty();
+///     Spirit::<Empty, Cfg>::new()
+///         .with(Pipeline::new("http client").extract_cfg(Cfg::client).install(client.clone()))
+///         .run(move |_| {
+///             // Not actually sending it in the example
+///             let _request = client.get("https://www.rust-lang.org").send();
+///             Ok(())
+///         });
+/// }
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AsyncReqwestClient(pub ReqwestClient);
+
+impl From<ReqwestClient> for AsyncReqwestClient {
+    fn from(cfg: ReqwestClient) -> Self {
+        AsyncReqwestClient(cfg)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for AsyncReqwestClient {
+        type Driver = CacheEq<AsyncReqwestClient>;
+        type Resource = AsyncClient;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<AsyncClient, AnyError> {
+            self.0.create_async_client()
+        }
+    }
+}
+
+impl Stackable for AsyncReqwestClient {}
+
+/// A storage for one asynchronous [`Client`][AsyncClient] that can be atomically exchanged.
+///
+/// This is the asynchronous counterpart of [`AtomicClient`] and works in the same way (including
+/// the warnings and panics described there). It is best paired with the [`AsyncReqwestClient`]
+/// fragment.
+#[derive(Clone, Debug)]
+pub struct AtomicAsyncClient(Arc<ArcSwapOption<AsyncClient>>);
+
+impl Default for AtomicAsyncClient {
+    fn default() -> Self {
+        Self::unconfigured()
+    }
+}
+
+impl<C: Into<Arc<AsyncClient>>> From<C> for AtomicAsyncClient {
+    fn from(c: C) -> Self {
+        AtomicAsyncClient(Arc::new(ArcSwapOption::from(Some(c.into()))))
+    }
+}
+
+impl AtomicAsyncClient {
+    /// Creates an empty [`AtomicAsyncClient`].
+    ///
+    /// It'll panic until a value is set, either by [`replace`][AtomicAsyncClient::replace] or
+    /// by [`Spirit`][spirit::Spirit] behind the scenes.
+    pub fn empty() -> Self {
+        AtomicAsyncClient(Arc::new(ArcSwapOption::empty()))
+    }
+
+    /// Creates an [`AtomicAsyncClient`] with default client inside.
+    pub fn unconfigured() -> Self {
+        AtomicAsyncClient(Arc::new(ArcSwapOption::from_pointee(AsyncClient::new())))
+    }
+
+    /// Replaces the content of this [`AtomicAsyncClient`] with a new client.
+    ///
+    /// This replaces it for *all* connected handles.
+    pub fn replace<C: Into<Arc<AsyncClient>>>(&self, by: C) {
+        let client = by.into();
+        self.0.store(Some(client));
+    }
+
+    /// Returns a handle to the client currently held inside.
+    ///
+    /// See [`AtomicClient::client`] for details.
+    pub fn client(&self) -> Arc<AsyncClient> {
+        self.0
+            .load_full()
+            .expect("Accessing Reqwest HTTP client before setting it up")
+    }
+
+    /// Starts building an arbitrary request using the current client.
+    pub fn request<U: IntoUrl>(&self, method: Method, url: U) -> AsyncRequestBuilder {
+        self.0
+            .load()
+            .as_ref()
+            .expect("Accessing Reqwest HTTP client before setting it up")
+            .request(method, url)
+    }
+
+    /// Executes a request using the current client.
+    pub fn execute(
+        &self,
+        request: AsyncRequest,
+    ) -> impl Future<Item = AsyncResponse, Error = reqwest::Error> {
+        self.client().execute(request)
+    }
+
+    method! {
+        AsyncRequestBuilder;
+
+        /// Starts building a GET request.
+        get();
+
+        /// Starts building a POST request.
+        post();
+
+        /// Starts building a PUT request.
+        put();
+
+        /// Starts building a PATCH request.
+        patch();
+
+        /// Starts building a DELETE request.
+        delete();
+
+        /// Starts building a HEAD request.
+        head();
+    }
+}
+
+impl<O, C> Installer<AsyncClient, O, C> for AtomicAsyncClient {
+    type UninstallHandle = ();
+    fn install(&mut self, client: AsyncClient, name: &'static str) {
+        debug!("Installing asynchronous http client '{}'", name);
         self.replace(client);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::time::Instant;
+
+    use spirit::prelude::*;
+    use spirit::testing::TestSpirit;
+    use spirit::{Empty, Pipeline, Spirit};
+
+    use super::*;
+    use crate::cassette::NotRecorded;
+
+    #[test]
+    fn tls_ca_alias() {
+        let cfg: ReqwestClient = serde_json::from_str(r#"{"tls-ca": "/ca.pem"}"#).unwrap();
+        assert_eq!(vec![PathBuf::from("/ca.pem")], cfg.tls_extra_root_certs);
+        assert!(cfg.tls.tls_ca.is_none());
+        let cfg: ReqwestClient =
+            serde_json::from_str(r#"{"tls-extra-root-certs": ["/a.pem", "/b.pem"]}"#).unwrap();
+        assert_eq!(2, cfg.tls_extra_root_certs.len());
+    }
+
+    #[test]
+    fn execute_within_expired() {
+        let client = AtomicClient::unconfigured();
+        let request = client.get("http://localhost/").build().unwrap();
+        let deadline = Deadline::at(Instant::now() - Duration::from_secs(1));
+        let err = client.execute_within(request, deadline).unwrap_err();
+        assert!(err.is::<deadline::DeadlineExceeded>());
+    }
+
+    #[test]
+    fn map_pipeline_layers() {
+        #[derive(Debug, Default, Deserialize)]
+        struct Cfg {
+            clients: BTreeMap<String, ReqwestClient>,
+        }
+
+        let path = std::env::temp_dir().join(format!("clients-{}.json", std::process::id()));
+        fs::write(&path, "[]").unwrap();
+        let client = AtomicClient::empty();
+        // The map names the fragment `clients.upstream`, but installs it as `clients`
+        let builder = Spirit::<Empty, Cfg>::new().with(
+            Pipeline::new("clients")
+                .extract_cfg(|cfg: &Cfg| cfg.clients.clone())
+                .map(|mut clients: Vec<ConfiguredClient>| clients.pop().unwrap())
+                .install(client.clone()),
+        );
+        let cfg = format!("[clients.upstream]\nreplay = {:?}", path);
+        let _app = TestSpirit::new(builder, &cfg).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        // Replayed from the (empty) cassette instead of being sent
+        let request = client.get("http://example.com/").build().unwrap();
+        let err = client.execute(request).unwrap_err();
+        assert!(err.is::<NotRecorded>());
+    }
+}
@@ -0,0 +1,331 @@
+//! Retrying of failed requests.
+//!
+//! The [`RetryPolicy`] is a configuration fragment describing when and how many times a request
+//! is retried. It is usually configured as the `retry` option of the
+//! [`ReqwestClient`][crate::ReqwestClient], applying to the requests sent through
+//! [`AtomicClient::execute`][crate::AtomicClient::execute]. A policy embedded elsewhere in the
+//! application's configuration can be passed to
+//! [`AtomicClient::execute_with_retry`][crate::AtomicClient::execute_with_retry] instead. The
+//! retries can be limited by a [`Deadline`] too, see the [`deadline`][crate::deadline] module.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_reqwest::{AtomicClient, ReqwestClient};
+//! use spirit_reqwest::retry::RetryPolicy;
+//!
+//! #[derive(Debug, Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     client: ReqwestClient,
+//!     #[serde(default)]
+//!     retry: RetryPolicy,
+//! }
+//!
+//! impl Cfg {
+//!     fn client(&self) -> ReqwestClient {
+//!         self.client.clone()
+//!     }
+//! }
+//!
+//! fn main() {
+//!     let client = AtomicClient::unconfigured();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(Pipeline::new("http client").extract_cfg(Cfg::client).install(client.clone()))
+//!         .run(move |spirit| {
+//!             let request = client.get("https://www.rust-lang.org").build()?;
+//!             // Not actually sending it in the example
+//!             if false {
+//!                 let policy = spirit.config().retry.clone();
+//!                 let page = client
+//!                     .execute_with_retry(&policy, request)?
+//!                     .error_for_status()?
+//!                     .text()?;
+//!                 println!("{}", page);
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, warn};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Error, Request, Response};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::retry::Backoff;
+use spirit::AnyError;
+
+use crate::deadline::{Deadline, DeadlineExceeded};
+
+fn default_max_attempts() -> usize {
+    3
+}
+
+fn default_backoff_base() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_backoff_cap() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_retry_on_status() -> Vec<u16> {
+    vec![429, 502, 503, 504]
+}
+
+fn default_on() -> bool {
+    true
+}
+
+/// A configuration of retrying failed requests.
+///
+/// # Fields
+///
+/// * `max-attempts`: How many times a request is tried in total. Setting to `1` disables
+///   retries. Defaults to `3`.
+/// * `backoff-base`: The delay before the first retry. Each next one doubles it. Defaults to
+///   `100ms`.
+/// * `backoff-cap`: The maximal delay between two attempts. Defaults to `10s`.
+/// * `retry-on-status`: Response status codes that cause a retry. Defaults to
+///   `[429, 502, 503, 504]`.
+/// * `retry-on-connect-error`: Retry if the request fails before getting a response (connection
+///   refused, reset, timeouts…). Defaults to `true`.
+/// * `respect-retry-after`: Wait as long as the server asks by the `Retry-After` header (in the
+///   seconds form). If it asks for longer than `backoff-cap`, the response is returned without
+///   retrying. Defaults to `true`.
+/// * `idempotent-only`: Retry only requests with idempotent methods (eg. not `POST`). Defaults
+///   to `true`.
+///
+/// Requests with a body that can't be cloned (eg. a streamed one) are never retried.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct RetryPolicy {
+    /// How many times a request is tried in total.
+    ///
+    /// `1` means no retries.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: usize,
+
+    /// The delay before the first retry.
+    ///
+    /// Each next retry waits twice as long as the previous one.
+    #[serde(
+        default = "default_backoff_base",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_base: Duration,
+
+    /// The maximal delay between two attempts.
+    #[serde(
+        default = "default_backoff_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_cap: Duration,
+
+    /// Response status codes that are retried.
+    #[serde(default = "default_retry_on_status")]
+    pub retry_on_status: Vec<u16>,
+
+    /// Retry if the request failed before getting any response.
+    #[serde(default = "default_on")]
+    pub retry_on_connect_error: bool,
+
+    /// Honor the `Retry-After` header of the response.
+    #[serde(default = "default_on")]
+    pub respect_retry_after: bool,
+
+    /// Retry only requests with idempotent methods.
+    #[serde(default = "default_on")]
+    pub idempotent_only: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: default_max_attempts(),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+            retry_on_status: default_retry_on_status(),
+            retry_on_connect_error: true,
+            respect_retry_after: true,
+            idempotent_only: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delays between retries, without `Retry-After`.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.backoff_base, self.backoff_cap)
+    }
+
+    /// The delay before the given retry (the first retry is `1`).
+    fn delay(&self, retry: usize) -> Duration {
+        self.backoff().delay(retry as u32 - 1)
+    }
+
+    fn error_retriable(&self, error: &Error) -> bool {
+        // Errors produced after getting the response or before sending anything are not helped
+        // by retrying.
+        self.retry_on_connect_error
+            && error.status().is_none()
+            && !error.is_serialization()
+            && !error.is_redirect()
+    }
+
+    fn response_delay(&self, response: &Response, retry: usize) -> Option<Duration> {
+        if !self.retry_on_status.contains(&response.status().as_u16()) {
+            return None;
+        }
+        let backoff = self.delay(retry);
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.trim().parse::<u64>().ok())
+            .map(Duration::from_secs);
+        match retry_after {
+            Some(after) if self.respect_retry_after => {
+                if after > self.backoff_cap {
+                    debug!(
+                        "Server asks to retry after {:?}, not waiting that long",
+                        after
+                    );
+                    None
+                } else {
+                    Some(cmp::max(after, backoff))
+                }
+            }
+            _ => Some(backoff),
+        }
+    }
+
+    /// Executes the request on the client, retrying it according to the policy.
+    ///
+    /// This blocks the current thread for the time of the backoff. The result of the last
+    /// attempt is returned.
+    pub fn execute(&self, client: &Client, request: Request) -> Result<Response, Error> {
+        self.run(request, None, &|request| client.execute(request))
+            .map_err(|failure| match failure {
+                Failure::Request(e) => e,
+                Failure::Deadline => unreachable!("Deadline exceeded without a deadline"),
+            })
+    }
+
+    /// Executes the request like [`execute`][RetryPolicy::execute], but within a deadline.
+    ///
+    /// No attempt is started after the deadline and no retry is made if its backoff would end
+    /// after it. The remaining time is propagated to the server in each attempt. See the
+    /// [`deadline`][crate::deadline] module.
+    ///
+    /// The error is either the [`reqwest::Error`] of the last attempt or [`DeadlineExceeded`].
+    pub fn execute_within(
+        &self,
+        client: &Client,
+        request: Request,
+        deadline: Deadline,
+    ) -> Result<Response, AnyError> {
+        self.execute_through(request, Some(deadline), &|request| client.execute(request))
+    }
+
+    /// Like [`execute_within`][RetryPolicy::execute_within], but the attempts are made by `send`
+    /// and the deadline is optional.
+    pub(crate) fn execute_through(
+        &self,
+        request: Request,
+        deadline: Option<Deadline>,
+        send: &dyn Fn(Request) -> Result<Response, Error>,
+    ) -> Result<Response, AnyError> {
+        self.run(request, deadline, send)
+            .map_err(|failure| match failure {
+                Failure::Request(e) => e.into(),
+                Failure::Deadline => DeadlineExceeded.into(),
+            })
+    }
+
+    fn run(
+        &self,
+        request: Request,
+        deadline: Option<Deadline>,
+        send: &dyn Fn(Request) -> Result<Response, Error>,
+    ) -> Result<Response, Failure> {
+        let attempt = |mut request: Request| {
+            if let Some(deadline) = deadline {
+                if deadline.is_expired() {
+                    return Err(Failure::Deadline);
+                }
+                deadline.propagate(request.headers_mut());
+            }
+            let result = send(request).map_err(Failure::Request);
+            match deadline {
+                Some(deadline) if deadline.is_expired() => Err(Failure::Deadline),
+                _ => result,
+            }
+        };
+        let fits = |delay: Duration| match deadline {
+            Some(deadline) => delay < deadline.remaining(),
+            None => true,
+        };
+        if self.idempotent_only && !request.method().is_idempotent() {
+            return attempt(request);
+        }
+        let mut request = request;
+        let mut retry = 0;
+        loop {
+            retry += 1;
+            let next = if retry < self.max_attempts {
+                request.try_clone()
+            } else {
+                None
+            };
+            let next = match next {
+                Some(next) => next,
+                // Either the last attempt or can't repeat it.
+                None => return attempt(request),
+            };
+            let url = request.url().clone();
+            let delay = match attempt(request) {
+                Ok(response) => match self.response_delay(&response, retry) {
+                    Some(delay) if fits(delay) => {
+                        warn!(
+                            "Request to {} returned {}, retrying",
+                            url,
+                            response.status()
+                        );
+                        delay
+                    }
+                    _ => return Ok(response),
+                },
+                Err(Failure::Request(e)) => {
+                    let delay = self.delay(retry);
+                    if self.error_retriable(&e) && fits(delay) {
+                        warn!("Request to {} failed, retrying: {}", url, e);
+                        delay
+                    } else {
+                        return Err(Failure::Request(e));
+                    }
+                }
+                Err(Failure::Deadline) => return Err(Failure::Deadline),
+            };
+            debug!("Waiting {:?} before retrying request to {}", delay, url);
+            thread::sleep(delay);
+            request = next;
+        }
+    }
+}
+
+/// Why a request didn't get a response.
+enum Failure {
+    Request(Error),
+    Deadline,
+}
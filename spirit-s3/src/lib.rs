@@ -0,0 +1,447 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-s3/0.1.0/spirit_s3/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of S3-compatible object storage.
+//!
+//! The [`S3Storage`] fragment describes a bucket ‒ its name, the region or a custom endpoint (for
+//! S3-compatible services like MinIO or Ceph), the credentials and the addressing style. It
+//! creates a [`Storage`], which is the [rust-s3] [`Bucket`] together with the configured retry
+//! policy. It is installed into an [`AtomicStorage`] handle, which always holds the storage of
+//! the current configuration.
+//!
+//! If no access key is configured, the credentials are looked up the usual AWS way (environment
+//! variables, the credentials file, instance metadata).
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_s3::{AtomicStorage, S3Storage};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [backup]
+//! bucket = "backups"
+//! endpoint = "http://localhost:9000"
+//! path-style = true
+//! access-key = "minio"
+//! secret-key = "minio123"
+//!
+//! [backup.retry]
+//! attempts = 5
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     backup: S3Storage,
+//! }
+//!
+//! fn main() {
+//!     let storage = AtomicStorage::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("backup")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.backup.clone())
+//!                 .install(storage.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _bucket = storage.storage().bucket().name.clone();
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [rust-s3]: https://crates.io/crates/rust-s3
+
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::{debug, warn};
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::retry::Backoff;
+use spirit::fragment::Installer;
+use spirit::utils::Hidden;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_attempts() -> u32 {
+    3
+}
+
+fn default_backoff_base() -> Duration {
+    Duration::from_millis(100)
+}
+
+fn default_backoff_cap() -> Duration {
+    Duration::from_secs(10)
+}
+
+/// How failed operations are retried.
+///
+/// # Fields
+///
+/// * `attempts`: How many times to try an operation in total. Defaults to 3.
+/// * `backoff-base`: The delay before the first retry, doubled with each further one. Defaults
+///   to `100ms`.
+/// * `backoff-cap`: The maximum delay between retries. Defaults to `10s`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Retry {
+    /// How many times to try an operation in total.
+    #[serde(default = "default_attempts")]
+    pub attempts: u32,
+
+    /// The delay before the first retry.
+    #[serde(
+        default = "default_backoff_base",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_base: Duration,
+
+    /// The maximum delay between retries.
+    #[serde(
+        default = "default_backoff_cap",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub backoff_cap: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            attempts: default_attempts(),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+        }
+    }
+}
+
+impl Retry {
+    /// The delays between retries.
+    pub fn backoff(&self) -> Backoff {
+        Backoff::new(self.backoff_base, self.backoff_cap)
+    }
+}
+
+/// Configuration of an S3 bucket.
+///
+/// # Fields
+///
+/// * `bucket`: The name of the bucket.
+/// * `region`: The region of the bucket. Defaults to `us-east-1`.
+/// * `endpoint`: A custom endpoint URL, for S3-compatible services. If set, the `region` is only
+///   used for signing the requests.
+/// * `path-style`: Address the bucket in the path (`https://endpoint/bucket/key`) instead of in
+///   the host name (`https://bucket.endpoint/key`). Most S3-compatible services need this.
+///   Defaults to `false`.
+/// * `access-key`: The access key ID. If not set, the credentials are looked up from the
+///   environment.
+/// * `secret-key`: The secret access key. Hidden when dumping the configuration.
+/// * `secret-key-file`: Read the secret key from this file instead (surrounding whitespace is
+///   stripped). Useful with secrets mounted as files.
+/// * `session-token`: A session token for temporary credentials. Hidden when dumping the
+///   configuration.
+/// * `retry`: How failed operations are retried, see [`Retry`].
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct S3Storage {
+    /// The name of the bucket.
+    pub bucket: String,
+
+    /// The region of the bucket.
+    #[serde(default = "default_region")]
+    pub region: String,
+
+    /// A custom endpoint URL.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+
+    /// Address the bucket in the path.
+    #[serde(default)]
+    pub path_style: bool,
+
+    /// The access key ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_key: Option<String>,
+
+    /// The secret access key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<Hidden<String>>,
+
+    /// A file to read the secret key from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key_file: Option<PathBuf>,
+
+    /// A session token for temporary credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_token: Option<Hidden<String>>,
+
+    /// How failed operations are retried.
+    #[serde(default)]
+    pub retry: Retry,
+}
+
+impl S3Storage {
+    fn secret_key(&self) -> Result<Option<String>, AnyError> {
+        match (&self.secret_key, &self.secret_key_file) {
+            (Some(_), Some(_)) => Err("Both secret-key and secret-key-file are set".into()),
+            (Some(key), None) => Ok(Some(key.0.clone())),
+            (None, Some(file)) => {
+                let key = fs::read_to_string(file).with_context(|_| {
+                    format!("Failed to read S3 secret key from {}", file.display())
+                })?;
+                Ok(Some(key.trim().to_owned()))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Creates the credentials, either the configured ones or from the environment.
+    pub fn credentials(&self) -> Result<Credentials, AnyError> {
+        let secret_key = self.secret_key()?;
+        match (&self.access_key, secret_key) {
+            (Some(access_key), Some(secret_key)) => {
+                let token = self.session_token.as_ref().map(|t| t.0.as_str());
+                Credentials::new(Some(access_key), Some(&secret_key), token, None, None)
+                    .context("Invalid S3 credentials")
+                    .map_err(AnyError::from)
+            }
+            (None, None) => {
+                debug!("No S3 credentials configured, looking them up in the environment");
+                Credentials::default()
+                    .context("Failed to find S3 credentials")
+                    .map_err(AnyError::from)
+            }
+            (Some(_), None) => Err("S3 access-key set without a secret key".into()),
+            (None, Some(_)) => Err("S3 secret key set without an access-key".into()),
+        }
+    }
+
+    /// The region, possibly with the custom endpoint.
+    pub fn region(&self) -> Result<Region, AnyError> {
+        match &self.endpoint {
+            Some(endpoint) => Ok(Region::Custom {
+                region: self.region.clone(),
+                endpoint: endpoint.clone(),
+            }),
+            None => self
+                .region
+                .parse()
+                .with_context(|_| format!("Invalid S3 region {}", self.region))
+                .map_err(AnyError::from),
+        }
+    }
+
+    /// Creates the storage.
+    ///
+    /// This doesn't contact the server.
+    pub fn create_storage(&self) -> Result<Storage, AnyError> {
+        if self.bucket.is_empty() {
+            return Err("No S3 bucket configured".into());
+        }
+        let region = self.region()?;
+        let credentials = self.credentials()?;
+        let bucket = if self.path_style {
+            Bucket::new_with_path_style(&self.bucket, region, credentials)
+        } else {
+            Bucket::new(&self.bucket, region, credentials)
+        }
+        .with_context(|_| format!("Failed to create S3 bucket {}", self.bucket))?;
+        Ok(Storage {
+            bucket,
+            retry: self.retry.clone(),
+        })
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for S3Storage {
+        type Driver = CacheEq<S3Storage>;
+        type Resource = Storage;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Storage, AnyError> {
+            self.create_storage()
+        }
+    }
+}
+
+/// A configured bucket with its retry policy.
+///
+/// This is what the [`S3Storage`] fragment creates.
+pub struct Storage {
+    bucket: Bucket,
+    retry: Retry,
+}
+
+impl Debug for Storage {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Storage")
+            .field("bucket", &self.bucket.name)
+            .field("retry", &self.retry)
+            .finish()
+    }
+}
+
+impl Storage {
+    /// The bucket, to perform operations on.
+    pub fn bucket(&self) -> &Bucket {
+        &self.bucket
+    }
+
+    /// The configured retry policy.
+    pub fn retry_policy(&self) -> &Retry {
+        &self.retry
+    }
+
+    /// Runs an operation on the bucket, retrying it according to the configuration.
+    ///
+    /// The error of the last attempt is returned if all of them fail. The delays between the
+    /// attempts block the current thread.
+    pub fn retry<T, E, F>(&self, mut operation: F) -> Result<T, E>
+    where
+        F: FnMut(&Bucket) -> Result<T, E>,
+        E: Display,
+    {
+        let mut attempt = 1;
+        loop {
+            match operation(&self.bucket) {
+                Ok(result) => return Ok(result),
+                Err(e) if attempt < self.retry.attempts => {
+                    let delay = self.retry.backoff().delay(attempt - 1);
+                    warn!(
+                        "S3 operation on {} failed, retrying in {:?}: {}",
+                        self.bucket.name, delay, e
+                    );
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A handle to the current storage.
+///
+/// Cheap to clone, all the clones share the same storage. It can be used as an [`Installer`] for
+/// the [`S3Storage`] fragment.
+///
+/// # Panics
+///
+/// Accessing the storage before one is set panics.
+#[derive(Clone, Default)]
+pub struct AtomicStorage(Arc<ArcSwapOption<Storage>>);
+
+impl Debug for AtomicStorage {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicStorage").finish()
+    }
+}
+
+impl AtomicStorage {
+    /// Creates an [`AtomicStorage`] without any storage inside.
+    pub fn empty() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the storage inside.
+    pub fn replace(&self, storage: Storage) {
+        self.0.store(Some(Arc::new(storage)));
+    }
+
+    /// Returns the current storage.
+    ///
+    /// Operations started on it finish there even if the configuration changes meanwhile.
+    pub fn storage(&self) -> Arc<Storage> {
+        self.0
+            .load_full()
+            .expect("Accessing S3 storage before setting it up")
+    }
+}
+
+impl<O, C> Installer<Storage, O, C> for AtomicStorage {
+    type UninstallHandle = ();
+    fn install(&mut self, storage: Storage, name: &'static str) {
+        debug!("Installing S3 storage {}", name);
+        self.replace(storage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn storage() -> S3Storage {
+        S3Storage {
+            bucket: "test".to_owned(),
+            region: default_region(),
+            access_key: Some("key".to_owned()),
+            secret_key: Some(Hidden("secret".to_owned())),
+            ..S3Storage::default()
+        }
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut cfg = storage();
+        cfg.retry.backoff_base = Duration::from_millis(1);
+        let storage = cfg.create_storage().unwrap();
+        let mut calls = 0;
+        let result: Result<(), &str> = storage.retry(|_| {
+            calls += 1;
+            Err("nope")
+        });
+        assert_eq!(Err("nope"), result);
+        assert_eq!(3, calls);
+    }
+
+    #[test]
+    fn key_without_secret() {
+        let cfg = S3Storage {
+            secret_key: None,
+            ..storage()
+        };
+        assert!(cfg.create_storage().is_err());
+    }
+
+    #[test]
+    fn custom_endpoint() {
+        let cfg = S3Storage {
+            endpoint: Some("http://localhost:9000".to_owned()),
+            path_style: true,
+            ..storage()
+        };
+        match cfg.region().unwrap() {
+            Region::Custom { region, endpoint } => {
+                assert_eq!("us-east-1", region);
+                assert_eq!("http://localhost:9000", endpoint);
+            }
+            region => panic!("Unexpected region {:?}", region),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-sqlx/0.1.0/spirit_sqlx/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [sqlx] connection pools.
+//!
+//! The [`SqlxPool`] configuration fragment describes a pool of database connections, including
+//! its sizing and TLS settings. After choosing the database by [`SqlxPool::typed`], it can be
+//! installed into an [`AtomicPool`], which always holds the pool matching the current
+//! configuration.
+//!
+//! The pools are created lazily ‒ connections are opened only once they are needed. If the
+//! `migrate-on-start` option is turned on and a [`Migrator`] is attached (needs the `migrate`
+//! feature), the migrations are run when the pool is created. As that happens while loading the
+//! configuration, failing migrations at startup prevent the application from starting (and
+//! failing migrations on reconfiguration keep the previous pool).
+//!
+//! The databases are enabled by the `postgres` and `mysql` features.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_sqlx::{AtomicPool, SqlxPool};
+//! use sqlx::Postgres;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [db]
+//! url = "postgres://localhost/db"
+//! max-connections = 4
+//! tls = "require"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     db: SqlxPool,
+//! }
+//!
+//! fn main() {
+//!     let pool = AtomicPool::<Postgres>::empty();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("database")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.db.typed::<Postgres>())
+//!                 .install(pool.clone())
+//!         )
+//!         .run(move |_| {
+//!             let _pool = pool.pool();
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`Migrator`]: sqlx::migrate::Migrator
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::{Fragment, Installer};
+use spirit::utils::Hidden;
+use spirit::AnyError;
+use spirit_tls::TlsConfig;
+#[cfg(feature = "migrate")]
+use sqlx::migrate::Migrator;
+use sqlx::pool::PoolOptions;
+use sqlx::{Database, Pool};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+fn default_max_connections() -> u32 {
+    10
+}
+
+fn default_connect_timeout() -> Duration {
+    Duration::from_secs(30)
+}
+
+/// How to use TLS for the connections.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum TlsMode {
+    /// Don't use TLS.
+    Disable,
+
+    /// Use TLS if the server supports it.
+    Prefer,
+
+    /// Use TLS, but don't check the server certificate.
+    Require,
+
+    /// Use TLS and check the server certificate is signed by a trusted authority.
+    VerifyCa,
+
+    /// Use TLS, check the certificate and that it belongs to the server.
+    VerifyFull,
+}
+
+impl Default for TlsMode {
+    fn default() -> Self {
+        TlsMode::Prefer
+    }
+}
+
+/// A configuration fragment of an sqlx connection pool.
+///
+/// # Fields
+///
+/// * `url`: The database URL. Hidden when dumping the configuration.
+/// * `max-connections`: Maximum number of connections. Defaults to 10.
+/// * `min-connections`: Number of connections to keep open even when idle. Defaults to 0.
+/// * `connect-timeout`: How long to wait for a connection. Defaults to `30s`.
+/// * `idle-timeout`: Close connections idle for longer than this. Defaults to `nil` (never).
+/// * `max-lifetime`: Close connections older than this. Defaults to `nil` (never).
+/// * `tls`: One of `disable`, `prefer`, `require`, `verify-ca` and `verify-full`. Defaults to
+///   `prefer`.
+/// * `tls-ca`: A certificate of the authority to check the server certificate against, spelled
+///   the same as in the other crates (see [`spirit-tls`][spirit_tls]). The rest of the shared TLS
+///   options are not supported and are refused, including `tls-ca-refresh`. The file is read
+///   only when the pool is created, [`refresh_all`][spirit_tls::trust::refresh_all] doesn't
+///   reach it.
+/// * `tls-root-cert`: The older spelling of `tls-ca`.
+/// * `migrate-on-start`: Run the migrations when creating the pool (needs the `migrate` feature
+///   and a migrator attached through [`TypedPool::with_migrator`]). Defaults to `false`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct SqlxPool {
+    /// The database URL.
+    pub url: Hidden<String>,
+
+    /// Maximum number of connections.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: u32,
+
+    /// Number of connections kept open even when idle.
+    #[serde(default)]
+    pub min_connections: u32,
+
+    /// How long to wait for a connection.
+    #[serde(
+        default = "default_connect_timeout",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub connect_timeout: Duration,
+
+    /// Close connections idle for longer than this.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub idle_timeout: Option<Duration>,
+
+    /// Close connections older than this.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub max_lifetime: Option<Duration>,
+
+    /// How to use TLS.
+    #[serde(default)]
+    pub tls: TlsMode,
+
+    /// The TLS options shared with the other spirit crates.
+    ///
+    /// Only the `tls-ca` is supported.
+    #[serde(flatten)]
+    pub tls_config: TlsConfig,
+
+    /// The certificate authority to check the server certificate against.
+    ///
+    /// The older spelling of `tls-ca`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_root_cert: Option<PathBuf>,
+
+    /// Run the migrations when creating the pool.
+    #[serde(default)]
+    pub migrate_on_start: bool,
+}
+
+impl Default for SqlxPool {
+    fn default() -> Self {
+        SqlxPool {
+            url: Hidden(String::new()),
+            max_connections: default_max_connections(),
+            min_connections: 0,
+            connect_timeout: default_connect_timeout(),
+            idle_timeout: None,
+            max_lifetime: None,
+            tls: TlsMode::default(),
+            tls_config: TlsConfig::default(),
+            tls_root_cert: None,
+            migrate_on_start: false,
+        }
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// A database supported by this crate.
+///
+/// This is implemented for the databases enabled by the features and can't be implemented
+/// outside of this crate.
+pub trait Backend: Database + sealed::Sealed {
+    #[doc(hidden)]
+    fn lazy_pool(cfg: &SqlxPool, options: PoolOptions<Self>) -> Result<Pool<Self>, AnyError>;
+
+    #[doc(hidden)]
+    #[cfg(feature = "migrate")]
+    fn migrate(migrator: &'static Migrator, pool: &Pool<Self>) -> Result<(), AnyError>;
+}
+
+/// Runs the future to completion on a temporary runtime.
+///
+/// The pools are created outside of any runtime (during configuration loading), so the
+/// migrations need one of their own.
+#[cfg(feature = "migrate")]
+fn block_on<F, T, E>(fut: F) -> Result<T, AnyError>
+where
+    F: std::future::Future<Output = Result<T, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut runtime = tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()?;
+    Ok(runtime.block_on(fut)?)
+}
+
+#[cfg(feature = "postgres")]
+impl sealed::Sealed for sqlx::Postgres {}
+
+#[cfg(feature = "postgres")]
+impl Backend for sqlx::Postgres {
+    fn lazy_pool(cfg: &SqlxPool, options: PoolOptions<Self>) -> Result<Pool<Self>, AnyError> {
+        use sqlx::postgres::{PgConnectOptions, PgSslMode};
+
+        let mode = match cfg.tls {
+            TlsMode::Disable => PgSslMode::Disable,
+            TlsMode::Prefer => PgSslMode::Prefer,
+            TlsMode::Require => PgSslMode::Require,
+            TlsMode::VerifyCa => PgSslMode::VerifyCa,
+            TlsMode::VerifyFull => PgSslMode::VerifyFull,
+        };
+        let mut connect = cfg
+            .url
+            .parse::<PgConnectOptions>()
+            .context("Invalid database URL")?
+            .ssl_mode(mode);
+        if let Some(cert) = cfg.root_cert()? {
+            connect = connect.ssl_root_cert(cert);
+        }
+        Ok(options.connect_lazy_with(connect))
+    }
+
+    #[cfg(feature = "migrate")]
+    fn migrate(migrator: &'static Migrator, pool: &Pool<Self>) -> Result<(), AnyError> {
+        block_on(migrator.run(pool))
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl sealed::Sealed for sqlx::MySql {}
+
+#[cfg(feature = "mysql")]
+impl Backend for sqlx::MySql {
+    fn lazy_pool(cfg: &SqlxPool, options: PoolOptions<Self>) -> Result<Pool<Self>, AnyError> {
+        use sqlx::mysql::{MySqlConnectOptions, MySqlSslMode};
+
+        let mode = match cfg.tls {
+            TlsMode::Disable => MySqlSslMode::Disabled,
+            TlsMode::Prefer => MySqlSslMode::Preferred,
+            TlsMode::Require => MySqlSslMode::Required,
+            TlsMode::VerifyCa => MySqlSslMode::VerifyCa,
+            TlsMode::VerifyFull => MySqlSslMode::VerifyIdentity,
+        };
+        let mut connect = cfg
+            .url
+            .parse::<MySqlConnectOptions>()
+            .context("Invalid database URL")?
+            .ssl_mode(mode);
+        if let Some(cert) = cfg.root_cert()? {
+            connect = connect.ssl_ca(cert);
+        }
+        Ok(options.connect_lazy_with(connect))
+    }
+
+    #[cfg(feature = "migrate")]
+    fn migrate(migrator: &'static Migrator, pool: &Pool<Self>) -> Result<(), AnyError> {
+        block_on(migrator.run(pool))
+    }
+}
+
+impl SqlxPool {
+    /// The certificate authority, from either of the spellings.
+    fn root_cert(&self) -> Result<Option<&PathBuf>, AnyError> {
+        let tls = &self.tls_config;
+        if tls.tls_cert.is_some() || tls.tls_key.is_some() {
+            return Err("Client certificates are not supported for database connections".into());
+        }
+        if !tls.files_only() {
+            return Err("Only the tls-ca TLS option is supported for database connections".into());
+        }
+        match (&tls.tls_ca, &self.tls_root_cert) {
+            (Some(_), Some(_)) => Err("Can't set both tls-ca and tls-root-cert".into()),
+            (ca, root_cert) => Ok(ca.as_ref().or_else(|| root_cert.as_ref())),
+        }
+    }
+
+    /// Chooses the database, making it into a [`Fragment`].
+    pub fn typed<DB: Backend>(&self) -> TypedPool<DB> {
+        TypedPool {
+            cfg: self.clone(),
+            #[cfg(feature = "migrate")]
+            migrator: None,
+            _db: Default::default(),
+        }
+    }
+
+    /// Creates the pool (without connecting to the database yet).
+    pub fn create_pool<DB: Backend>(&self) -> Result<Pool<DB>, AnyError> {
+        debug!(
+            "Creating database pool of up to {} connections",
+            self.max_connections
+        );
+        let options = PoolOptions::<DB>::new()
+            .max_connections(self.max_connections)
+            .min_connections(self.min_connections)
+            .connect_timeout(self.connect_timeout)
+            .idle_timeout(self.idle_timeout)
+            .max_lifetime(self.max_lifetime);
+        DB::lazy_pool(self, options)
+    }
+}
+
+/// The [`SqlxPool`] with the chosen database.
+///
+/// This is the actual [`Fragment`], created by [`SqlxPool::typed`].
+pub struct TypedPool<DB> {
+    cfg: SqlxPool,
+    #[cfg(feature = "migrate")]
+    migrator: Option<&'static Migrator>,
+    _db: std::marker::PhantomData<fn() -> DB>,
+}
+
+impl<DB> TypedPool<DB> {
+    /// Attaches the migrations to run if `migrate-on-start` is set.
+    ///
+    /// The migrator is usually created by the `sqlx::migrate!` macro.
+    #[cfg(feature = "migrate")]
+    pub fn with_migrator(self, migrator: &'static Migrator) -> Self {
+        TypedPool {
+            migrator: Some(migrator),
+            ..self
+        }
+    }
+}
+
+impl<DB> Clone for TypedPool<DB> {
+    fn clone(&self) -> Self {
+        TypedPool {
+            cfg: self.cfg.clone(),
+            #[cfg(feature = "migrate")]
+            migrator: self.migrator,
+            _db: Default::default(),
+        }
+    }
+}
+
+impl<DB> Debug for TypedPool<DB> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        self.cfg.fmt(fmt)
+    }
+}
+
+impl<DB> PartialEq for TypedPool<DB> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cfg == other.cfg
+    }
+}
+
+impl<DB> Eq for TypedPool<DB> {}
+
+impl<DB: Backend> TypedPool<DB> {
+    #[cfg(feature = "migrate")]
+    fn migrate(&self, pool: &Pool<DB>) -> Result<(), AnyError> {
+        match (self.cfg.migrate_on_start, self.migrator) {
+            (true, Some(migrator)) => {
+                debug!("Running database migrations");
+                DB::migrate(migrator, pool).context("Database migrations failed")?;
+                Ok(())
+            }
+            (true, None) => Err("migrate-on-start is set, but there are no migrations".into()),
+            (false, _) => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "migrate"))]
+    fn migrate(&self, _: &Pool<DB>) -> Result<(), AnyError> {
+        if self.cfg.migrate_on_start {
+            Err("migrate-on-start needs the migrate feature".into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<DB: Backend> Fragment for TypedPool<DB> {
+    type Driver = CacheEq<Self>;
+    type Installer = ();
+    type Seed = ();
+    type Resource = Pool<DB>;
+    fn make_seed(&self, _: &'static str) -> Result<(), AnyError> {
+        Ok(())
+    }
+    fn make_resource(&self, _: &mut (), name: &'static str) -> Result<Pool<DB>, AnyError> {
+        let pool = self
+            .cfg
+            .create_pool()
+            .with_context(|_| format!("Failed to create database pool {}", name))?;
+        self.migrate(&pool)?;
+        Ok(pool)
+    }
+}
+
+/// A handle to the currently configured pool.
+///
+/// Cheap to clone, all the clones share the same pool. It can be used as an [`Installer`] for
+/// the [`TypedPool`] fragment. The old pool is closed once all its users are gone.
+///
+/// # Panics
+///
+/// Accessing the pool before one is set panics.
+pub struct AtomicPool<DB: Database>(Arc<ArcSwapOption<Pool<DB>>>);
+
+impl<DB: Database> Clone for AtomicPool<DB> {
+    fn clone(&self) -> Self {
+        AtomicPool(Arc::clone(&self.0))
+    }
+}
+
+impl<DB: Database> Debug for AtomicPool<DB> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicPool").finish()
+    }
+}
+
+impl<DB: Database> Default for AtomicPool<DB> {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+impl<DB: Database> AtomicPool<DB> {
+    /// Creates an [`AtomicPool`] without any pool inside.
+    pub fn empty() -> Self {
+        AtomicPool(Arc::new(ArcSwapOption::empty()))
+    }
+
+    /// Replaces the pool inside.
+    pub fn replace(&self, pool: Pool<DB>) {
+        self.0.store(Some(Arc::new(pool)));
+    }
+
+    /// Returns the current pool.
+    ///
+    /// The [`Pool`] is cheap to clone, so this returns an owned one.
+    pub fn pool(&self) -> Pool<DB> {
+        Pool::clone(
+            &self
+                .0
+                .load()
+                .as_ref()
+                .expect("Accessing database pool before setting it up"),
+        )
+    }
+}
+
+impl<DB: Database, O, C> Installer<Pool<DB>, O, C> for AtomicPool<DB> {
+    type UninstallHandle = ();
+    fn install(&mut self, pool: Pool<DB>, name: &'static str) {
+        debug!("Installing database pool {}", name);
+        self.replace(pool);
+    }
+}
+
+#[cfg(all(test, feature = "postgres"))]
+mod tests {
+    use sqlx::Postgres;
+
+    use super::*;
+
+    #[test]
+    fn invalid_url() {
+        let cfg = SqlxPool {
+            url: Hidden("not a url".to_owned()),
+            ..SqlxPool::default()
+        };
+        assert!(cfg.create_pool::<Postgres>().is_err());
+    }
+
+    #[test]
+    fn migrate_without_migrator() {
+        let cfg = SqlxPool {
+            url: Hidden("postgres://localhost/db".to_owned()),
+            migrate_on_start: true,
+            ..SqlxPool::default()
+        };
+        let fragment = cfg.typed::<Postgres>();
+        assert!(fragment.make_resource(&mut (), "test").is_err());
+    }
+    #[test]
+    fn root_cert() {
+        let mut cfg = SqlxPool::default();
+        assert!(cfg.root_cert().unwrap().is_none());
+        cfg.tls_root_cert = Some(PathBuf::from("/old.pem"));
+        assert_eq!(Some(&PathBuf::from("/old.pem")), cfg.root_cert().unwrap());
+        cfg.tls_config.tls_ca = Some(PathBuf::from("/new.pem"));
+        assert!(cfg.root_cert().is_err());
+        cfg.tls_root_cert = None;
+        assert_eq!(Some(&PathBuf::from("/new.pem")), cfg.root_cert().unwrap());
+        cfg.tls_config.tls_alpn = vec!["h2".to_owned()];
+        assert!(cfg.root_cert().is_err());
+    }
+}
@@ -0,0 +1,551 @@
+//! Monitoring of certificate expiry.
+//!
+//! Certificates have the bad habit of expiring at the least convenient moment and nobody notices
+//! until the clients start failing. To make that less likely, every certificate file loaded by
+//! [`load_certs`][crate::load_certs] (which includes the certificates and authorities of all the
+//! [`TlsConfig`][crate::TlsConfig] consumers, servers and clients alike) is remembered together
+//! with the time it expires. Crates loading the certificates by other means can add them by
+//! [`track_file`].
+//!
+//! A file with multiple certificates (a chain, a bundle of authorities) is represented by the one
+//! expiring first.
+//!
+//! The remembered certificates are listed by [`tracked`]. The [`ExpiryMonitor`] configuration
+//! fragment describes how soon before the expiry to start complaining. It can be used as a health
+//! probe directly ([`ExpiryMonitor::check`]) or turned into a background check by the
+//! [`extension`], logging a warning for each certificate close to its expiry (and an error for the
+//! already expired ones) every `check-interval`. The extension also registers a
+//! [health probe][spirit::health], so these certificates show in the `health` command of the
+//! `spirit-admin` control socket.
+//!
+//! With the `metrics` feature, the [`extension_with_metrics`] also sets a gauge for each
+//! certificate file in a [`spirit-dipstick`] monitor. The gauges are named by the path of the file
+//! and hold the number of whole days until the expiry (negative once expired).
+//!
+//! A certificate stays tracked after the configuration stops using it, until the process ends.
+//! Loading the same file again replaces its expiry.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_tls::expiry::{self, ExpiryMonitor};
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     cert_expiry: Option<ExpiryMonitor>,
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(expiry::extension(|cfg: &Cfg| cfg.cert_expiry.clone()))
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [`spirit-dipstick`]: https://crates.io/crates/spirit-dipstick
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "metrics")]
+use dipstick::InputScope;
+use err_context::prelude::*;
+use log::{debug, error, warn};
+use once_cell::sync::Lazy;
+use rustls::Certificate;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::{Extensible, Extension};
+use spirit::{health, Spirit};
+#[cfg(feature = "metrics")]
+use spirit_dipstick::Monitor;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// The name of the [health probe][spirit::health] registered by the [`extension`].
+pub const HEALTH_PROBE: &str = "tls-certificate-expiry";
+
+/// How often the background thread looks if it's time for a check (or to terminate).
+const POLL: Duration = Duration::from_secs(1);
+
+/// The expiry of the loaded certificate files.
+static TRACKED: Lazy<Mutex<BTreeMap<PathBuf, SystemTime>>> = Lazy::new(Default::default);
+
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_VERSION: u8 = 0xa0;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Splits one DER element into its tag, content and whatever follows it.
+fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len & 0x80 == 0 {
+        usize::from(len)
+    } else {
+        let bytes = usize::from(len & 0x7f);
+        if bytes == 0 || bytes > 4 || rest.len() < bytes {
+            return None;
+        }
+        let (len, tail) = rest.split_at(bytes);
+        rest = tail;
+        len.iter().fold(0, |acc, b| acc << 8 | usize::from(*b))
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// Days since the unix epoch of the given date (in the proleptic Gregorian calendar).
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+    // Counting the years from March makes the leap day the last one of the year
+    let (year, month) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).checked_sub(719_468)
+}
+
+/// Parses the `UTCTime` or `GeneralizedTime` of the certificate validity.
+fn parse_time(tag: u8, time: &[u8]) -> Option<SystemTime> {
+    let time = str::from_utf8(time).ok()?;
+    if !time.ends_with('Z') {
+        return None;
+    }
+    let time = &time[..time.len() - 1];
+    if !time.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (year, rest) = match tag {
+        UTC_TIME => {
+            let year: u64 = time.get(..2)?.parse().ok()?;
+            let year = if year >= 50 { 1900 + year } else { 2000 + year };
+            (year, time.get(2..)?)
+        }
+        GENERALIZED_TIME => (time.get(..4)?.parse().ok()?, time.get(4..)?),
+        _ => return None,
+    };
+    if rest.len() != 10 {
+        return None;
+    }
+    let num = |pos: usize| -> Option<u64> { rest[pos..pos + 2].parse().ok() };
+    let days = days_from_civil(year, num(0)?, num(2)?)?;
+    let secs = days * DAY + num(4)? * 3600 + num(6)? * 60 + num(8)?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Extracts the end of the validity from a DER encoded certificate.
+fn not_after(der: &[u8]) -> Option<SystemTime> {
+    let (tag, cert, _) = element(der)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (tag, tbs, _) = element(cert)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    // The version is optional, the serial number follows
+    let (tag, _, rest) = element(tbs)?;
+    let rest = if tag == EXPLICIT_VERSION {
+        element(rest)?.2
+    } else {
+        rest
+    };
+    // The signature algorithm and the issuer
+    let rest = element(rest)?.2;
+    let rest = element(rest)?.2;
+    let (tag, validity, _) = element(rest)?;
+    if tag != SEQUENCE {
+        return None;
+    }
+    let (_, _, validity) = element(validity)?;
+    let (tag, time, _) = element(validity)?;
+    parse_time(tag, time)
+}
+
+/// Remembers the expiry of the certificates loaded from the file.
+pub(crate) fn track(path: &Path, certs: &[Certificate]) {
+    match certs.iter().filter_map(|cert| not_after(&cert.0)).min() {
+        Some(expiry) => {
+            TRACKED.lock().unwrap().insert(path.to_owned(), expiry);
+        }
+        None => debug!(
+            "Can't find the expiry of certificates in {}",
+            path.display()
+        ),
+    }
+}
+
+/// Starts tracking the certificates in a file loaded by other means than
+/// [`load_certs`][crate::load_certs].
+///
+/// Accepts PEM and DER files. Files not containing a recognizable certificate (eg. a PKCS12
+/// bundle) are skipped.
+pub fn track_file(path: &Path) {
+    // PEM files are tracked by the loading itself
+    if crate::load_certs(path).is_ok() {
+        return;
+    }
+    match fs::read(path) {
+        Ok(der) => track(path, &[Certificate(der)]),
+        Err(e) => debug!("Can't read {} to track its expiry: {}", path.display(), e),
+    }
+}
+
+/// The expiry of one tracked certificate file.
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CertExpiry {
+    /// The file the certificate was loaded from.
+    pub path: PathBuf,
+    /// The end of the validity of the certificate.
+    pub not_after: SystemTime,
+}
+
+impl CertExpiry {
+    /// Has the certificate already expired?
+    pub fn is_expired(&self) -> bool {
+        SystemTime::now() >= self.not_after
+    }
+
+    /// The time left until the expiry (zero if already expired).
+    pub fn remaining(&self) -> Duration {
+        self.not_after
+            .duration_since(SystemTime::now())
+            .unwrap_or_default()
+    }
+
+    /// Whole days left until the expiry (rounded down), negative if already expired.
+    pub fn days_left(&self) -> i64 {
+        match self.not_after.duration_since(SystemTime::now()) {
+            Ok(left) => (left.as_secs() / DAY) as i64,
+            // Rounding down, like for the positive ones (1 second after the expiry is day -1)
+            Err(passed) => -(((passed.duration().as_secs() + DAY - 1) / DAY) as i64),
+        }
+    }
+}
+
+/// Lists all the tracked certificate files, the ones expiring first at the front.
+pub fn tracked() -> Vec<CertExpiry> {
+    let mut certs = TRACKED
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(path, not_after)| CertExpiry {
+            path: path.clone(),
+            not_after: *not_after,
+        })
+        .collect::<Vec<_>>();
+    certs.sort_by_key(|cert| cert.not_after);
+    certs
+}
+
+fn default_warn_before() -> Duration {
+    Duration::from_secs(14 * DAY)
+}
+
+fn default_check_interval() -> Duration {
+    Duration::from_secs(3600)
+}
+
+/// Configuration of the certificate expiry monitoring.
+///
+/// # Fields
+///
+/// * `warn-before`: Complain about certificates expiring sooner than this. Defaults to `14days`.
+/// * `check-interval`: How often the [`extension`] checks the certificates. Defaults to `1h`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct ExpiryMonitor {
+    /// Complain about certificates expiring sooner than this.
+    #[serde(
+        default = "default_warn_before",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub warn_before: Duration,
+
+    /// How often to check the certificates.
+    #[serde(
+        default = "default_check_interval",
+        deserialize_with = "serde_humantime::deserialize",
+        serialize_with = "spirit::utils::serialize_duration"
+    )]
+    pub check_interval: Duration,
+}
+
+impl Default for ExpiryMonitor {
+    fn default() -> Self {
+        ExpiryMonitor {
+            warn_before: default_warn_before(),
+            check_interval: default_check_interval(),
+        }
+    }
+}
+
+impl ExpiryMonitor {
+    /// Returns the tracked certificates expiring within `warn-before`, without logging.
+    ///
+    /// Empty result means healthy.
+    pub fn expiring(&self) -> Vec<CertExpiry> {
+        let limit = SystemTime::now() + self.warn_before;
+        tracked()
+            .into_iter()
+            .take_while(|cert| cert.not_after < limit)
+            .collect()
+    }
+
+    /// Checks the tracked certificates, logging the ones close to their expiry.
+    ///
+    /// Returns the same as [`expiring`][ExpiryMonitor::expiring].
+    pub fn check(&self) -> Vec<CertExpiry> {
+        let expiring = self.expiring();
+        for cert in &expiring {
+            if cert.is_expired() {
+                error!("Certificate {} has expired", cert.path.display());
+            } else {
+                warn!(
+                    "Certificate {} expires in {} days",
+                    cert.path.display(),
+                    cert.days_left()
+                );
+            }
+        }
+        expiring
+    }
+}
+
+/// Describes the problems for the health probe.
+fn problems(expiring: &[CertExpiry]) -> Option<String> {
+    if expiring.is_empty() {
+        return None;
+    }
+    let problems = expiring
+        .iter()
+        .map(|cert| {
+            if cert.is_expired() {
+                format!("Certificate {} has expired", cert.path.display())
+            } else {
+                format!(
+                    "Certificate {} expires in {} days",
+                    cert.path.display(),
+                    cert.days_left()
+                )
+            }
+        })
+        .collect::<Vec<_>>();
+    Some(problems.join("; "))
+}
+
+fn run<O, C, E, R>(spirit: Weak<Spirit<O, C>>, extract: E, report: R)
+where
+    C: DeserializeOwned + Send + Sync,
+    O: StructOpt,
+    E: Fn(&C) -> Option<ExpiryMonitor>,
+    R: Fn(&[CertExpiry]),
+{
+    let mut last_check: Option<Instant> = None;
+    let mut probe: Option<ExpiryMonitor> = None;
+    loop {
+        let monitor = match spirit.upgrade() {
+            Some(spirit) if !spirit.is_terminated() => extract(&spirit.config()),
+            _ => break,
+        };
+        if monitor != probe {
+            match &monitor {
+                Some(monitor) => {
+                    let monitor = monitor.clone();
+                    health::register(HEALTH_PROBE, move || problems(&monitor.expiring()));
+                }
+                None => health::unregister(HEALTH_PROBE),
+            }
+            probe = monitor.clone();
+        }
+        if let Some(monitor) = monitor {
+            let due = last_check
+                .map(|last| last.elapsed() >= monitor.check_interval)
+                .unwrap_or(true);
+            if due {
+                monitor.check();
+                report(&tracked());
+                last_check = Some(Instant::now());
+            }
+        }
+        thread::sleep(POLL);
+    }
+    health::unregister(HEALTH_PROBE);
+}
+
+fn spawn<B, E, R>(extract: E, report: R) -> impl Extension<B>
+where
+    B: Extensible<Ok = B>,
+    B::Config: DeserializeOwned + Send + Sync + 'static,
+    B::Opts: StructOpt + Send + Sync + 'static,
+    E: Fn(&B::Config) -> Option<ExpiryMonitor> + Send + 'static,
+    R: Fn(&[CertExpiry]) + Send + 'static,
+{
+    |builder: B| {
+        builder.run_before(|spirit| {
+            let spirit = Arc::downgrade(spirit);
+            thread::Builder::new()
+                .name("spirit-tls-expiry".to_owned())
+                .spawn(move || run(spirit, extract, report))
+                .context("Failed to start the certificate expiry thread")?;
+            Ok(())
+        })
+    }
+}
+
+/// An extension periodically checking the expiry of the tracked certificates.
+///
+/// The parameter is an extractor of the [`ExpiryMonitor`] configuration. If it returns `None`,
+/// nothing is checked. The checks run in their own thread, started once the application starts
+/// running and ending when it terminates.
+///
+/// While configured, it also registers a [health probe][spirit::health] (named [`HEALTH_PROBE`])
+/// reporting the certificates close to their expiry.
+pub fn extension<B, E>(extract: E) -> impl Extension<B>
+where
+    B: Extensible<Ok = B>,
+    B::Config: DeserializeOwned + Send + Sync + 'static,
+    B::Opts: StructOpt + Send + Sync + 'static,
+    E: Fn(&B::Config) -> Option<ExpiryMonitor> + Send + 'static,
+{
+    spawn(extract, |_: &[CertExpiry]| ())
+}
+
+/// Like [`extension`], but also setting the days until expiry as gauges in the monitor.
+///
+/// Available with the `metrics` feature. See the [module documentation](index.html) for the
+/// metrics.
+#[cfg(feature = "metrics")]
+pub fn extension_with_metrics<B, E>(extract: E, monitor: &Monitor) -> impl Extension<B>
+where
+    B: Extensible<Ok = B>,
+    B::Config: DeserializeOwned + Send + Sync + 'static,
+    B::Opts: StructOpt + Send + Sync + 'static,
+    E: Fn(&B::Config) -> Option<ExpiryMonitor> + Send + 'static,
+{
+    let monitor = monitor.clone();
+    spawn(extract, move |certs: &[CertExpiry]| {
+        for cert in certs {
+            let name = cert.path.display().to_string();
+            monitor.gauge(&name).value(cert.days_left());
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut result = vec![tag, content.len() as u8];
+        result.extend_from_slice(content);
+        result
+    }
+
+    fn cert(not_after: (u8, &str)) -> Vec<u8> {
+        let validity = [
+            der(UTC_TIME, b"200101000000Z"),
+            der(not_after.0, not_after.1.as_bytes()),
+        ]
+        .concat();
+        let tbs = [
+            der(EXPLICIT_VERSION, &der(0x02, &[2])),
+            der(0x02, &[1]),
+            der(SEQUENCE, &[]),
+            der(SEQUENCE, &[]),
+            der(SEQUENCE, &validity),
+            der(SEQUENCE, &[]),
+        ]
+        .concat();
+        der(SEQUENCE, &der(SEQUENCE, &tbs))
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(Some(0), days_from_civil(1970, 1, 1));
+        assert_eq!(Some(11_016), days_from_civil(2000, 2, 29));
+        assert_eq!(Some(11_017), days_from_civil(2000, 3, 1));
+        assert_eq!(None, days_from_civil(2000, 13, 1));
+        assert_eq!(None, days_from_civil(1969, 12, 31));
+        assert_eq!(None, days_from_civil(0, 1, 1));
+    }
+
+    #[test]
+    fn parse_not_after() {
+        let at = |secs| Some(UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(
+            at(1_893_456_000),
+            not_after(&cert((UTC_TIME, "300101000000Z")))
+        );
+        assert_eq!(
+            at(2_524_608_000 + 3661),
+            not_after(&cert((GENERALIZED_TIME, "20500101010101Z")))
+        );
+        assert_eq!(None, not_after(&cert((UTC_TIME, "300101000000"))));
+        assert_eq!(None, not_after(&cert((0x05, "300101000000Z"))));
+        assert_eq!(None, not_after(b"not a certificate"));
+    }
+
+    #[test]
+    fn expiring() {
+        let soon = Path::new("/expiry-test/soon.pem");
+        let later = Path::new("/expiry-test/later.pem");
+        let in_days = |days| SystemTime::now() + Duration::from_secs(days * DAY + 60);
+        {
+            let mut tracked = TRACKED.lock().unwrap();
+            tracked.insert(soon.to_owned(), in_days(3));
+            tracked.insert(later.to_owned(), in_days(30));
+        }
+
+        let expiring = ExpiryMonitor::default().check();
+        let expiring = expiring
+            .iter()
+            .filter(|cert| cert.path.starts_with("/expiry-test"))
+            .collect::<Vec<_>>();
+        assert_eq!(1, expiring.len());
+        assert_eq!(soon, expiring[0].path);
+        assert_eq!(3, expiring[0].days_left());
+        assert!(!expiring[0].is_expired());
+        assert!(problems(&[]).is_none());
+        assert!(problems(&[expiring[0].clone()])
+            .unwrap()
+            .contains("expires in 3 days"));
+    }
+
+    #[test]
+    fn days_left_expired() {
+        let expired = |secs| CertExpiry {
+            path: PathBuf::from("/expired.pem"),
+            not_after: SystemTime::now() - Duration::from_secs(secs),
+        };
+        assert_eq!(-1, expired(60).days_left());
+        assert_eq!(-1, expired(DAY - 60).days_left());
+        assert_eq!(-2, expired(DAY + 60).days_left());
+        assert!(expired(60).is_expired());
+    }
+}
@@ -0,0 +1,467 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-tls/0.1.0/spirit_tls/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! A TLS configuration fragment shared by the spirit crates.
+//!
+//! Many crates of the [`spirit`] system speak TLS ‒ the listeners of [`spirit-tokio`] (and
+//! therefore the servers of [`spirit-hyper`]), the [`spirit-reqwest`] clients, the database
+//! pools of [`spirit-sqlx`]... The [`TlsConfig`] gives them the same set of options, spelled the
+//! same way, so the user doesn't have to learn them for each crate anew. The crates flatten it
+//! into their own configuration, so the options live next to the rest of the options of the
+//! listener or client.
+//!
+//! # Configuration options
+//!
+//! * `tls-cert`: Path to the certificate chain in PEM format (own certificate first). The server
+//!   certificate of servers, the client certificate of clients.
+//! * `tls-key`: Path to the private key of the `tls-cert`, PKCS8 or RSA in PEM format.
+//! * `tls-ca`: Path to a PEM bundle of certificate authorities. Servers verify client certificates
+//!   against them, clients trust them in addition to the usual public authorities.
+//! * `tls-min-version`, `tls-max-version`: The range of allowed protocol versions, `"1.2"` or
+//!   `"1.3"`. All supported versions are allowed by default.
+//! * `tls-cipher-suites`: Names of the allowed cipher suites, in the order of preference (like
+//!   `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256`). All supported
+//!   ones are allowed by default.
+//! * `tls-alpn`: Protocols offered through ALPN, in the order of preference (like `h2` and
+//!   `http/1.1`).
+//! * `tls-system-ca`: Clients trust the bundle of certificate authorities of the operating system
+//!   instead of the public authorities compiled in. Defaults to `false`.
+//! * `tls-ca-refresh`: Re-read the certificate authorities this often (eg. `1h`), see the
+//!   [`trust`] module. Not set by default (they are re-read only on demand).
+//!
+//! Not every consumer supports every option (a consumer built on top of a different TLS library
+//! may not be able to limit the cipher suites, for example). Consumers refuse the configuration
+//! with options they can't honor instead of silently ignoring them, see the documentation of each
+//! consumer.
+//!
+//! # Reloading
+//!
+//! The certificates and keys are loaded whenever the consumer creates its TLS configuration,
+//! which it does when the [`TlsConfig`] changes. Replacing the files in place is not noticed
+//! until then.
+//!
+//! The certificate authorities are an exception, they can be re-read without replacing the
+//! configuration (periodically or on demand), see the [`trust`] module.
+//!
+//! The [`TlsConfig`] is also a [`Fragment`] on its own, creating a rustls [`ClientConfig`]. This
+//! is useful for clients built directly on top of rustls.
+//!
+//! # Certificate expiry
+//!
+//! The expiry of every loaded certificate is remembered and can be checked periodically, see the
+//! [`expiry`] module.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit_tls::TlsConfig;
+//!
+//! const CONFIG: &str = r#"{
+//!     "tls-ca": "/etc/my-app/ca.pem",
+//!     "tls-min-version": "1.3",
+//!     "tls-alpn": ["h2"]
+//! }"#;
+//!
+//! #[derive(Deserialize)]
+//! struct Upstream {
+//!     #[serde(flatten)]
+//!     tls: TlsConfig,
+//! }
+//!
+//! let upstream: Upstream = serde_json::from_str(CONFIG).unwrap();
+//! assert_eq!(vec![b"h2".to_vec()], upstream.tls.alpn_protocols());
+//! ```
+//!
+//! [`Fragment`]: spirit::fragment::Fragment
+//! [`spirit-tokio`]: https://crates.io/crates/spirit-tokio
+//! [`spirit-hyper`]: https://crates.io/crates/spirit-hyper
+//! [`spirit-reqwest`]: https://crates.io/crates/spirit-reqwest
+//! [`spirit-sqlx`]: https://crates.io/crates/spirit-sqlx
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use err_context::prelude::*;
+use log::debug;
+use rustls::internal::pemfile;
+use rustls::{
+    Certificate, ClientCertVerifier, ClientConfig, NoClientAuth, PrivateKey, ProtocolVersion,
+    RootCertStore, ServerConfig, SupportedCipherSuite, ALL_CIPHERSUITES,
+};
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+use crate::trust::TrustStore;
+
+pub mod expiry;
+pub mod trust;
+
+/// Loads a PEM certificate chain (or a bundle of certificates) from a file.
+///
+/// It is an error if the file contains no certificates. The expiry of the certificates is
+/// [tracked][expiry].
+pub fn load_certs(path: &Path) -> Result<Vec<Certificate>, AnyError> {
+    let file = File::open(path)?;
+    let certs = pemfile::certs(&mut BufReader::new(file))
+        .map_err(|()| format!("Invalid PEM certificates in {}", path.display()))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path.display()).into());
+    }
+    expiry::track(path, &certs);
+    Ok(certs)
+}
+
+/// Loads a PEM private key (either PKCS8 or RSA) from a file.
+pub fn load_key(path: &Path) -> Result<PrivateKey, AnyError> {
+    let load = |pkcs8| -> Result<Vec<PrivateKey>, AnyError> {
+        let mut file = BufReader::new(File::open(path)?);
+        let keys = if pkcs8 {
+            pemfile::pkcs8_private_keys(&mut file)
+        } else {
+            pemfile::rsa_private_keys(&mut file)
+        };
+        keys.map_err(|()| format!("Invalid PEM key in {}", path.display()).into())
+    };
+    let mut keys = load(true)?;
+    if keys.is_empty() {
+        keys = load(false)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", path.display()).into())
+}
+
+/// A version of the TLS protocol.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+pub enum TlsVersion {
+    /// TLS 1.2.
+    #[serde(rename = "1.2")]
+    Tls12,
+    /// TLS 1.3.
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+impl TlsVersion {
+    const ALL: [TlsVersion; 2] = [TlsVersion::Tls12, TlsVersion::Tls13];
+
+    fn protocol(self) -> ProtocolVersion {
+        match self {
+            TlsVersion::Tls12 => ProtocolVersion::TLSv1_2,
+            TlsVersion::Tls13 => ProtocolVersion::TLSv1_3,
+        }
+    }
+}
+
+/// The TLS configuration shared by the spirit crates.
+///
+/// See the [crate documentation][crate] for the options. It is meant to be flattened into the
+/// configuration of the consumer.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsConfig {
+    /// The certificate chain, in PEM format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// The private key of the certificate, in PEM format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_key: Option<PathBuf>,
+
+    /// The certificate authorities, in PEM format.
+    ///
+    /// For servers, these verify the client certificates. Clients trust them in addition to the
+    /// public authorities.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// The lowest allowed protocol version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_min_version: Option<TlsVersion>,
+
+    /// The highest allowed protocol version.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_max_version: Option<TlsVersion>,
+
+    /// The allowed cipher suites, in the order of preference.
+    ///
+    /// All supported ones are allowed if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tls_cipher_suites: Vec<String>,
+
+    /// The protocols offered through ALPN, in the order of preference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tls_alpn: Vec<String>,
+
+    /// Trust the certificate authorities of the operating system instead of the compiled-in ones.
+    ///
+    /// Applies to clients only.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub tls_system_ca: bool,
+
+    /// Re-read the certificate authorities this often.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tls_ca_refresh: Option<Duration>,
+}
+
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn is_false(b: &bool) -> bool {
+    !*b
+}
+
+impl TlsConfig {
+    /// Is the version range and the cipher suites left to the defaults?
+    ///
+    /// Useful for consumers that can't set these.
+    pub fn default_protocols(&self) -> bool {
+        self.tls_min_version.is_none()
+            && self.tls_max_version.is_none()
+            && self.tls_cipher_suites.is_empty()
+    }
+
+    /// Are only the certificate, its key and the certificate authorities set?
+    ///
+    /// Useful for consumers built on top of other TLS libraries, which support only these.
+    pub fn files_only(&self) -> bool {
+        self.default_protocols()
+            && self.tls_alpn.is_empty()
+            && !self.tls_system_ca
+            && self.tls_ca_refresh.is_none()
+    }
+
+    /// Loads the certificate chain and its private key, if configured.
+    ///
+    /// It is an error to set only one of them.
+    pub fn identity(&self) -> Result<Option<(Vec<Certificate>, PrivateKey)>, AnyError> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => {
+                let certs = load_certs(cert)
+                    .with_context(|_| format!("Failed to load certificate {}", cert.display()))?;
+                let key = load_key(key)
+                    .with_context(|_| format!("Failed to load key {}", key.display()))?;
+                Ok(Some((certs, key)))
+            }
+            (None, None) => Ok(None),
+            (Some(_), None) => Err("The tls-cert is set without the tls-key".into()),
+            (None, Some(_)) => Err("The tls-key is set without the tls-cert".into()),
+        }
+    }
+
+    /// Loads the certificate authorities, if configured.
+    ///
+    /// This is a static snapshot. The configurations created here use a [`TrustStore`] instead, so
+    /// the authorities can be refreshed.
+    pub fn ca_store(&self) -> Result<Option<RootCertStore>, AnyError> {
+        let path = match &self.tls_ca {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let mut store = RootCertStore::empty();
+        for cert in
+            load_certs(path).with_context(|_| format!("Failed to load CA {}", path.display()))?
+        {
+            store
+                .add(&cert)
+                .map_err(|e| format!("Invalid CA in {}: {:?}", path.display(), e))?;
+        }
+        Ok(Some(store))
+    }
+
+    /// The allowed protocol versions, as rustls wants them.
+    pub fn versions(&self) -> Result<Vec<ProtocolVersion>, AnyError> {
+        let min = self.tls_min_version.unwrap_or(TlsVersion::Tls12);
+        let max = self.tls_max_version.unwrap_or(TlsVersion::Tls13);
+        if min > max {
+            return Err("The tls-min-version is higher than tls-max-version".into());
+        }
+        // rustls prefers the versions in the given order, we want the newest one
+        Ok(TlsVersion::ALL
+            .iter()
+            .rev()
+            .filter(|version| min <= **version && **version <= max)
+            .map(|version| version.protocol())
+            .collect())
+    }
+
+    /// The allowed cipher suites, as rustls wants them.
+    pub fn cipher_suites(&self) -> Result<Vec<&'static SupportedCipherSuite>, AnyError> {
+        if self.tls_cipher_suites.is_empty() {
+            return Ok(ALL_CIPHERSUITES.to_vec());
+        }
+        self.tls_cipher_suites
+            .iter()
+            .map(|name| {
+                ALL_CIPHERSUITES
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite).eq_ignore_ascii_case(name))
+                    .cloned()
+                    .ok_or_else(|| format!("Unsupported cipher suite {}", name).into())
+            })
+            .collect()
+    }
+
+    /// The protocols to offer through ALPN.
+    pub fn alpn_protocols(&self) -> Vec<Vec<u8>> {
+        self.tls_alpn
+            .iter()
+            .map(|proto| proto.as_bytes().to_vec())
+            .collect()
+    }
+
+    /// Applies the versions, cipher suites, ALPN protocols and the certificate to a rustls
+    /// server configuration.
+    ///
+    /// The verification of clients (by the `tls-ca`) is not set, as it needs to be passed to the
+    /// constructor of the [`ServerConfig`] ‒ see [`client_verifier`][TlsConfig::client_verifier].
+    ///
+    /// Returns the certificate chain and key it has set (if configured), for callers needing them
+    /// for something else, so they don't have to load them again.
+    pub fn configure_server(
+        &self,
+        config: &mut ServerConfig,
+    ) -> Result<Option<(Vec<Certificate>, PrivateKey)>, AnyError> {
+        config.versions = self.versions()?;
+        config.ciphersuites = self.cipher_suites()?;
+        if !self.tls_alpn.is_empty() {
+            config.set_protocols(&self.alpn_protocols());
+        }
+        let identity = self.identity()?;
+        if let Some((certs, key)) = &identity {
+            config.set_single_cert(certs.clone(), key.clone())?;
+        }
+        Ok(identity)
+    }
+
+    /// The verifier of client certificates for a server, by the `tls-ca`.
+    ///
+    /// If the `tls-ca` is not set, client certificates are not asked for. Otherwise, they are
+    /// verified against a [`TrustStore`]. Clients without a certificate are let in only if it is
+    /// not `mandatory`.
+    pub fn client_verifier(
+        &self,
+        mandatory: bool,
+    ) -> Result<Arc<dyn ClientCertVerifier>, AnyError> {
+        if self.tls_system_ca {
+            return Err("The tls-system-ca applies only to clients".into());
+        }
+        let store = TrustStore::server(self).context("Failed to load client CA")?;
+        Ok(match store {
+            Some(store) => store.client_verifier(mandatory),
+            None => NoClientAuth::new(),
+        })
+    }
+
+    /// Creates a rustls client configuration.
+    ///
+    /// The client trusts the public certificate authorities (compiled in or of the system) and the
+    /// `tls-ca`, through a [`TrustStore`]. The `tls-cert` is used as the client certificate.
+    pub fn client_config(&self) -> Result<ClientConfig, AnyError> {
+        debug!("Creating TLS client configuration from {:?}", self);
+        let mut config = ClientConfig::new();
+        let store = TrustStore::client(self)?;
+        config.root_store = store.roots();
+        config
+            .dangerous()
+            .set_certificate_verifier(store.server_verifier());
+        config.versions = self.versions()?;
+        config.ciphersuites = self.cipher_suites()?;
+        config.set_protocols(&self.alpn_protocols());
+        if let Some((certs, key)) = self.identity()? {
+            config.set_single_client_cert(certs, key);
+        }
+        Ok(config)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for TlsConfig {
+        type Driver = CacheEq<TlsConfig>;
+        type Resource = Arc<ClientConfig>;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Arc<ClientConfig>, AnyError> {
+            self.client_config().map(Arc::new)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::CipherSuite;
+
+    use super::*;
+
+    #[test]
+    fn versions() {
+        let mut cfg = TlsConfig::default();
+        assert_eq!(
+            vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+            cfg.versions().unwrap()
+        );
+        cfg.tls_min_version = Some(TlsVersion::Tls13);
+        assert_eq!(vec![ProtocolVersion::TLSv1_3], cfg.versions().unwrap());
+        cfg.tls_max_version = Some(TlsVersion::Tls12);
+        assert!(cfg.versions().is_err());
+    }
+
+    #[test]
+    fn cipher_suites() {
+        let mut cfg: TlsConfig = serde_json::from_str(
+            r#"{
+                "tls-cipher-suites": [
+                    "tls13_chacha20_poly1305_sha256",
+                    "TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256"
+                ]
+            }"#,
+        )
+        .unwrap();
+        let suites = cfg
+            .cipher_suites()
+            .unwrap()
+            .into_iter()
+            .map(|suite| suite.suite)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+                CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+            ],
+            suites
+        );
+        assert!(!cfg.default_protocols());
+        cfg.tls_cipher_suites
+            .push("TLS_RSA_WITH_RC4_128_MD5".to_owned());
+        assert!(cfg.cipher_suites().is_err());
+        assert_eq!(
+            ALL_CIPHERSUITES.len(),
+            TlsConfig::default().cipher_suites().unwrap().len()
+        );
+    }
+
+    #[test]
+    fn identity() {
+        assert!(TlsConfig::default().identity().unwrap().is_none());
+        let cfg = TlsConfig {
+            tls_cert: Some(PathBuf::from("/nonexistent/cert.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(cfg.identity().is_err());
+    }
+}
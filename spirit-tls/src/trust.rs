@@ -0,0 +1,387 @@
+//! Certificate authorities reloadable at runtime.
+//!
+//! A long-running process may outlive a rotation of the certificate authorities. Replacing the
+//! whole TLS configuration for that would also mean replacing the listeners or clients built from
+//! it, so the rustls configurations created from the [`TlsConfig`] verify the peers against a
+//! [`TrustStore`] instead, which can be re-read in place. The new authorities apply to new
+//! connections, the established ones are not affected.
+//!
+//! The store is re-read:
+//!
+//! * Periodically, if the `tls-ca-refresh` option is set.
+//! * On demand by [`refresh_all`] (for example through the `refresh-ca` command of the
+//!   `spirit-admin` control socket).
+//!
+//! If re-reading fails, the previous authorities are kept.
+//!
+//! Only the rustls configurations created here use a store ‒ the servers and clients of
+//! `spirit-tokio`, `spirit-hyper` and the [`TlsConfig`] fragment itself. Crates built on other
+//! TLS libraries (`spirit-reqwest`, `spirit-sqlx`, `spirit-nats`) load the `tls-ca` once when
+//! their client is created. They refuse the `tls-ca-refresh` option and are not refreshed (nor
+//! counted) by [`refresh_all`], a changed file reaches them only when a configuration change
+//! re-creates the client.
+//!
+//! The store of a client contains the public authorities (either the ones compiled in or the
+//! bundle of the operating system if `tls-system-ca` is set) and the `tls-ca`. The store of a
+//! server contains only the `tls-ca`, used to verify the client certificates.
+//!
+//! The system bundle is taken from the `SSL_CERT_FILE` environment variable or the usual
+//! locations on Linux and BSD systems.
+
+use std::env;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use arc_swap::ArcSwap;
+use err_context::prelude::*;
+use log::{debug, info};
+use once_cell::sync::Lazy;
+use rustls::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, Certificate,
+    ClientCertVerified, ClientCertVerifier, DistinguishedNames, RootCertStore, ServerCertVerified,
+    ServerCertVerifier, TLSError, WebPKIVerifier,
+};
+use spirit::AnyError;
+use webpki::{DNSName, DNSNameRef};
+
+use crate::{load_certs, TlsConfig};
+
+/// Where to look for the system bundle of certificate authorities.
+const SYSTEM_BUNDLES: &[&str] = &[
+    // Debian, Ubuntu, Arch, Gentoo
+    "/etc/ssl/certs/ca-certificates.crt",
+    // Fedora, RHEL
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    // OpenSUSE
+    "/etc/ssl/ca-bundle.pem",
+    // Alpine, BSDs
+    "/etc/ssl/cert.pem",
+];
+
+/// All the stores still in use, for the [`refresh_all`].
+static STORES: Lazy<Mutex<Vec<Weak<Inner>>>> = Lazy::new(Default::default);
+
+fn system_bundle() -> Result<PathBuf, AnyError> {
+    if let Some(path) = env::var_os("SSL_CERT_FILE") {
+        return Ok(path.into());
+    }
+    SYSTEM_BUNDLES
+        .iter()
+        .map(Path::new)
+        .find(|path| path.is_file())
+        .map(Path::to_owned)
+        .ok_or_else(|| "No system bundle of certificate authorities found".into())
+}
+
+/// The public authorities trusted by clients.
+#[derive(Copy, Clone, Debug)]
+enum Public {
+    Compiled,
+    System,
+}
+
+#[derive(Clone, Debug)]
+struct Sources {
+    public: Option<Public>,
+    ca: Option<PathBuf>,
+}
+
+impl Sources {
+    fn load(&self) -> Result<RootCertStore, AnyError> {
+        let mut store = RootCertStore::empty();
+        match self.public {
+            None => (),
+            Some(Public::Compiled) => {
+                store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+            }
+            Some(Public::System) => {
+                let path = system_bundle()?;
+                let file = File::open(&path)
+                    .with_context(|_| format!("Failed to open {}", path.display()))?;
+                // The system bundles sometimes contain certificates webpki doesn't like, these
+                // are skipped.
+                let (valid, skipped) = store
+                    .add_pem_file(&mut BufReader::new(file))
+                    .map_err(|()| format!("Invalid PEM in {}", path.display()))?;
+                if valid == 0 {
+                    return Err(format!("No usable certificates in {}", path.display()).into());
+                }
+                debug!(
+                    "Loaded {} system authorities from {} ({} skipped)",
+                    valid,
+                    path.display(),
+                    skipped
+                );
+            }
+        }
+        if let Some(path) = &self.ca {
+            let certs = load_certs(path)
+                .with_context(|_| format!("Failed to load CA {}", path.display()))?;
+            for cert in certs {
+                store
+                    .add(&cert)
+                    .map_err(|e| format!("Invalid CA in {}: {:?}", path.display(), e))?;
+            }
+        }
+        Ok(store)
+    }
+}
+
+/// The loaded authorities, with the rustls verifiers of client certificates built from them.
+struct Current {
+    roots: RootCertStore,
+    required: Arc<dyn ClientCertVerifier>,
+    optional: Arc<dyn ClientCertVerifier>,
+}
+
+impl Current {
+    fn new(roots: RootCertStore) -> Self {
+        Current {
+            required: AllowAnyAuthenticatedClient::new(roots.clone()),
+            optional: AllowAnyAnonymousOrAuthenticatedClient::new(roots.clone()),
+            roots,
+        }
+    }
+}
+
+struct Inner {
+    sources: Sources,
+    current: ArcSwap<Current>,
+    // Dropping it terminates the refreshing thread
+    _stop: Mutex<Sender<()>>,
+}
+
+impl Inner {
+    fn refresh(&self) -> Result<(), AnyError> {
+        let roots = self.sources.load()?;
+        debug!("Refreshed {} certificate authorities", roots.len());
+        self.current.store(Arc::new(Current::new(roots)));
+        Ok(())
+    }
+}
+
+fn refresh_periodically(store: Weak<Inner>, interval: Duration, stop: Receiver<()>) {
+    loop {
+        match stop.recv_timeout(interval) {
+            Err(RecvTimeoutError::Timeout) => (),
+            // The store is gone
+            _ => break,
+        }
+        let store = match store.upgrade() {
+            Some(store) => store,
+            None => break,
+        };
+        if let Err(e) = store.refresh() {
+            spirit::log_error!(multi Warn, "Failed to refresh certificate authorities" => e);
+        }
+    }
+    debug!("Terminating refresh of certificate authorities");
+}
+
+/// A set of certificate authorities that can be re-read at runtime.
+///
+/// See the [module documentation][crate::trust]. Usually, the user doesn't need to interact with
+/// it directly, the configurations created by [`TlsConfig`] use it internally.
+#[derive(Clone)]
+pub struct TrustStore(Arc<Inner>);
+
+impl TrustStore {
+    fn new(sources: Sources, refresh: Option<Duration>) -> Result<Self, AnyError> {
+        let current = ArcSwap::from_pointee(Current::new(sources.load()?));
+        let (stop, stop_recv) = mpsc::channel();
+        let inner = Arc::new(Inner {
+            sources,
+            current,
+            _stop: Mutex::new(stop),
+        });
+        if let Some(interval) = refresh {
+            let store = Arc::downgrade(&inner);
+            thread::Builder::new()
+                .name("spirit-tls-ca".to_owned())
+                .spawn(move || refresh_periodically(store, interval, stop_recv))?;
+        }
+        let mut stores = STORES.lock().unwrap();
+        stores.retain(|store| store.upgrade().is_some());
+        stores.push(Arc::downgrade(&inner));
+        Ok(TrustStore(inner))
+    }
+
+    /// Loads the store of a client.
+    ///
+    /// It contains the public authorities and the `tls-ca`.
+    pub fn client(cfg: &TlsConfig) -> Result<Self, AnyError> {
+        let public = if cfg.tls_system_ca {
+            Public::System
+        } else {
+            Public::Compiled
+        };
+        let sources = Sources {
+            public: Some(public),
+            ca: cfg.tls_ca.clone(),
+        };
+        Self::new(sources, cfg.tls_ca_refresh)
+    }
+
+    /// Loads the store of a server, verifying the client certificates.
+    ///
+    /// It contains only the `tls-ca`. Returns `None` if it is not set.
+    pub fn server(cfg: &TlsConfig) -> Result<Option<Self>, AnyError> {
+        let sources = match &cfg.tls_ca {
+            Some(ca) => Sources {
+                public: None,
+                ca: Some(ca.clone()),
+            },
+            None => return Ok(None),
+        };
+        Self::new(sources, cfg.tls_ca_refresh).map(Some)
+    }
+
+    /// A snapshot of the current authorities.
+    pub fn roots(&self) -> RootCertStore {
+        self.0.current.load().roots.clone()
+    }
+
+    /// Re-reads the authorities.
+    ///
+    /// On error, the previous ones are kept.
+    pub fn refresh(&self) -> Result<(), AnyError> {
+        self.0.refresh()
+    }
+
+    /// A verifier of client certificates, for servers.
+    ///
+    /// If the certificate is not `mandatory`, clients without one are let in too.
+    pub fn client_verifier(&self, mandatory: bool) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(ClientVerifier {
+            store: self.clone(),
+            mandatory,
+        })
+    }
+
+    /// A verifier of server certificates, for clients.
+    pub fn server_verifier(&self) -> Arc<dyn ServerCertVerifier> {
+        Arc::new(ServerVerifier {
+            store: self.clone(),
+            webpki: WebPKIVerifier::new(),
+        })
+    }
+}
+
+struct ClientVerifier {
+    store: TrustStore,
+    mandatory: bool,
+}
+
+impl ClientVerifier {
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        let current = self.store.0.current.load();
+        if self.mandatory {
+            Arc::clone(&current.required)
+        } else {
+            Arc::clone(&current.optional)
+        }
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn client_auth_mandatory(&self, _: Option<&DNSName>) -> Option<bool> {
+        Some(self.mandatory)
+    }
+
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.current().client_auth_root_subjects(sni)
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        self.current().verify_client_cert(presented_certs, sni)
+    }
+}
+
+struct ServerVerifier {
+    store: TrustStore,
+    webpki: WebPKIVerifier,
+}
+
+impl ServerCertVerifier for ServerVerifier {
+    fn verify_server_cert(
+        &self,
+        _: &RootCertStore,
+        presented_certs: &[Certificate],
+        dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        // The roots passed by rustls are the ones from the time the configuration was created
+        let current = self.store.0.current.load();
+        self.webpki
+            .verify_server_cert(&current.roots, presented_certs, dns_name, ocsp_response)
+    }
+}
+
+/// Re-reads the authorities of all the stores in use.
+///
+/// All the stores are tried, even if some of them fail. Returns the number of refreshed stores.
+///
+/// Note that the clients not built on the stores (see the [module documentation][crate::trust])
+/// are not refreshed and not included in the count.
+pub fn refresh_all() -> Result<usize, AnyError> {
+    let stores = STORES
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect::<Vec<_>>();
+    let mut errors = Vec::new();
+    for store in &stores {
+        if let Err(e) = store.refresh() {
+            errors.push(e);
+        }
+    }
+    match errors.pop() {
+        None => {
+            info!(
+                "Refreshed {} stores of certificate authorities",
+                stores.len()
+            );
+            Ok(stores.len())
+        }
+        Some(e) => {
+            let msg = format!(
+                "Failed to refresh {} of {} stores of certificate authorities",
+                errors.len() + 1,
+                stores.len()
+            );
+            Err(e.context(msg).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh() {
+        let cfg = TlsConfig::default();
+        let store = TrustStore::client(&cfg).unwrap();
+        let public = store.roots().len();
+        assert!(public > 0);
+        assert!(TrustStore::server(&cfg).unwrap().is_none());
+        assert!(refresh_all().unwrap() >= 1);
+        assert_eq!(public, store.roots().len());
+
+        let missing = TlsConfig {
+            tls_ca: Some(PathBuf::from("/does/not/exist.pem")),
+            ..TlsConfig::default()
+        };
+        assert!(TrustStore::server(&missing).is_err());
+    }
+}
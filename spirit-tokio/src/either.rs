@@ -1,9 +1,12 @@
 //! Support for alternative choices of configuration.
 
 use std::io::{BufRead, Error as IoError, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
 
 use futures::future::Either as FutEither;
 use futures::{Async, Future, Poll, Sink, StartSend, Stream};
+#[cfg(feature = "tls")]
+use rustls::Certificate;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use spirit::extension::Extensible;
@@ -15,7 +18,9 @@ use structdoc::StructDoc;
 use structopt::StructOpt;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-use crate::net::IntoIncoming;
+#[cfg(feature = "tls")]
+use crate::net::tls::TlsPeer;
+use crate::net::{Endpoint, IntoIncoming, PeerAddr};
 
 /// The [`Either`] type allows to wrap two similar [`Fragment`]s and let the user choose
 /// which one will be used.
@@ -330,6 +335,46 @@ where
     }
 }
 
+impl<A, B> Endpoint for Either<A, B>
+where
+    A: Endpoint,
+    B: Endpoint,
+{
+    fn endpoint(&self) -> String {
+        match self {
+            Either::A(a) => a.endpoint(),
+            Either::B(b) => b.endpoint(),
+        }
+    }
+}
+
+impl<A, B> PeerAddr for Either<A, B>
+where
+    A: PeerAddr,
+    B: PeerAddr,
+{
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        either!(self, v => v.remote_addr()).into_inner()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<A, B> TlsPeer for Either<A, B>
+where
+    A: TlsPeer,
+    B: TlsPeer,
+{
+    fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        either!(self, v => v.peer_certificates()).into_inner()
+    }
+    fn sni_hostname(&self) -> Option<String> {
+        either!(self, v => v.sni_hostname()).into_inner()
+    }
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        either!(self, v => v.alpn_protocol()).into_inner()
+    }
+}
+
 impl<A, B> Stackable for Either<A, B>
 where
     A: Stackable,
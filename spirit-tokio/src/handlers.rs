@@ -13,16 +13,28 @@
 //! [`Transformation`]: spirit::fragment::Transformation
 //! [`Future`]: futures::Future
 use std::fmt::Debug;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, Read, Write};
+use std::net::SocketAddr;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use err_context::prelude::*;
 use futures::{try_ready, Async, Future, IntoFuture, Poll, Stream};
-use log::{trace, warn};
+use log::{debug, trace, warn};
+#[cfg(feature = "tls")]
+use rustls::Certificate;
 use spirit::fragment::Transformation;
 use spirit::AnyError;
+use tokio::clock;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
 
 use crate::installer::FutureInstaller;
-use crate::net::IntoIncoming;
+#[cfg(feature = "tls")]
+use crate::net::tls::TlsPeer;
+use crate::net::{IntoIncoming, PeerAddr};
 
 /// A [`Transformation`] to handle the whole socket.
 ///
@@ -232,3 +244,502 @@ where
         })
     }
 }
+
+/// Connections currently handled by a lazily activated listener.
+#[derive(Debug)]
+struct Activity {
+    active: AtomicUsize,
+    last: Mutex<Instant>,
+}
+
+impl Activity {
+    fn last(&self) -> Instant {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn touch(&self) {
+        *self.last.lock().unwrap_or_else(PoisonError::into_inner) = clock::now();
+    }
+}
+
+/// Marks a connection as active for its lifetime.
+struct ActiveGuard(Arc<Activity>);
+
+impl ActiveGuard {
+    fn new(activity: &Arc<Activity>) -> Self {
+        activity.active.fetch_add(1, Ordering::SeqCst);
+        ActiveGuard(Arc::clone(activity))
+    }
+}
+
+impl Drop for ActiveGuard {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[doc(hidden)]
+pub struct LazyAcceptor<Incoming, Cfg, I, F, Ctx> {
+    name: &'static str,
+    incoming: Incoming,
+    cfg: Cfg,
+    init: I,
+    handler: F,
+    ctx: Option<Arc<Ctx>>,
+    idle_shutdown: Duration,
+    activity: Arc<Activity>,
+    timer: Option<Delay>,
+}
+
+impl<Incoming, Cfg, I, F, Ctx> LazyAcceptor<Incoming, Cfg, I, F, Ctx>
+where
+    I: FnMut(&Cfg) -> Result<Ctx, AnyError>,
+{
+    fn context(&mut self) -> Result<Arc<Ctx>, AnyError> {
+        if let Some(ctx) = &self.ctx {
+            return Ok(Arc::clone(ctx));
+        }
+        debug!("Activating {} on the first connection", self.name);
+        let ctx =
+            (self.init)(&self.cfg).with_context(|_| format!("Failed to activate {}", self.name))?;
+        let ctx = Arc::new(ctx);
+        self.ctx = Some(Arc::clone(&ctx));
+        self.activity.touch();
+        Ok(ctx)
+    }
+
+    /// Drops the context if there were no connections for long enough.
+    ///
+    /// Otherwise it sets up a timer to check again.
+    fn check_idle(&mut self) {
+        if self.ctx.is_none() {
+            self.timer = None;
+            return;
+        }
+        loop {
+            let now = clock::now();
+            let deadline = if self.activity.active.load(Ordering::SeqCst) > 0 {
+                now + self.idle_shutdown
+            } else {
+                self.activity.last() + self.idle_shutdown
+            };
+            if now >= deadline {
+                debug!("Deactivating {} after being idle", self.name);
+                self.ctx = None;
+                self.timer = None;
+                return;
+            }
+            let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+            timer.reset(deadline);
+            match timer.poll() {
+                Ok(Async::NotReady) => return,
+                // Re-check, something might have changed in the meantime
+                Ok(Async::Ready(())) => (),
+                Err(e) => {
+                    debug!("Can't watch idleness of {}: {}", self.name, e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+impl<Incoming, Cfg, I, F, Ctx, Fut> Future for LazyAcceptor<Incoming, Cfg, I, F, Ctx>
+where
+    Incoming: Stream<Error = IoError>,
+    I: FnMut(&Cfg) -> Result<Ctx, AnyError>,
+    F: Fn(Incoming::Item, &Arc<Ctx>) -> Fut,
+    Fut: IntoFuture<Item = ()>,
+    Fut::Future: Send + 'static,
+    Fut::Error: Into<AnyError>,
+{
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.check_idle();
+        loop {
+            let incoming = self.incoming.poll().map_err(|e| {
+                let e = e.context("Listening socket terminated unexpectedly").into();
+                spirit::log_error!(multi Error, e);
+            });
+            let conn = match try_ready!(incoming) {
+                Some(conn) => conn,
+                None => {
+                    warn!("The listening socket on {} terminated", self.name);
+                    return Ok(Async::Ready(()));
+                }
+            };
+            let ctx = match self.context() {
+                Ok(ctx) => ctx,
+                Err(e) => {
+                    // Drop the connection, try again with the next one
+                    spirit::log_error!(multi Error, e);
+                    continue;
+                }
+            };
+            let guard = ActiveGuard::new(&self.activity);
+            let name = self.name;
+            let future = (self.handler)(conn, &ctx)
+                .into_future()
+                .then(move |result| {
+                    drop(guard);
+                    result.map_err(move |e| {
+                        let e = e
+                            .into()
+                            .context(format!("Failed to handle connection on {}", name));
+                        spirit::log_error!(multi Error, e.into());
+                    })
+                });
+            tokio::spawn(future);
+            self.check_idle();
+        }
+    }
+}
+
+/// A handler of incoming connections with lazy initialization on the first connection.
+///
+/// This is like the [`HandleListenerInit`], except that the context is created only once the
+/// first connection arrives on the listening socket (which is bound right away, as usual). The
+/// context is then kept around for further connections, until there are no connections for the
+/// [`idle_shutdown`][HandleListenerLazy::idle_shutdown] time. Then it is dropped and created again
+/// on the next connection.
+///
+/// This is useful for binaries with many services, most of them used rarely. Heavy resources
+/// (database pools, caches, ...) of the services can live in the context and exist only while the
+/// service is in use.
+///
+/// If creation of the context fails, the error is logged and the connection that triggered it is
+/// closed. The next connection tries again.
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::Arc;
+///
+/// use spirit::AnyError;
+/// use spirit_tokio::handlers::HandleListenerLazy;
+/// use spirit_tokio::TcpListen;
+/// use tokio::net::TcpStream;
+/// use tokio::prelude::*;
+///
+/// struct Expensive {
+///     greeting: String,
+/// }
+///
+/// let handler = HandleListenerLazy::new(
+///     |_cfg: &TcpListen| -> Result<_, AnyError> {
+///         Ok(Expensive {
+///             greeting: "Hello\n".to_owned(),
+///         })
+///     },
+///     |conn: TcpStream, ctx: &Arc<Expensive>| {
+///         tokio::io::write_all(conn, ctx.greeting.clone()).map(|_| ())
+///     },
+/// );
+/// # drop(handler);
+/// ```
+#[derive(Clone, Debug)]
+pub struct HandleListenerLazy<I, F> {
+    init: I,
+    handler: F,
+    idle_shutdown: Duration,
+}
+
+impl<I, F> HandleListenerLazy<I, F> {
+    /// Creates the handler.
+    ///
+    /// The `init` closure creates the context from the configuration of the listener, the
+    /// `handler` handles each connection.
+    pub fn new(init: I, handler: F) -> Self {
+        HandleListenerLazy {
+            init,
+            handler,
+            idle_shutdown: Duration::from_secs(600),
+        }
+    }
+
+    /// Sets how long the context stays around without any connections.
+    ///
+    /// The default is 10 minutes.
+    pub fn idle_shutdown(self, idle_shutdown: Duration) -> Self {
+        HandleListenerLazy {
+            idle_shutdown,
+            ..self
+        }
+    }
+}
+
+impl<Listener, InputInstaller, SubFragment, I, Ctx, F, Fut>
+    Transformation<Listener, InputInstaller, SubFragment> for HandleListenerLazy<I, F>
+where
+    Listener: IntoIncoming,
+    I: FnMut(&SubFragment) -> Result<Ctx, AnyError> + Clone + 'static,
+    F: Fn(Listener::Connection, &Arc<Ctx>) -> Fut + Clone + 'static,
+    Fut: IntoFuture<Item = ()>,
+    Fut::Error: Into<AnyError>,
+    SubFragment: Clone + Debug + 'static,
+    Ctx: 'static,
+{
+    type OutputResource = LazyAcceptor<Listener::Incoming, SubFragment, I, F, Ctx>;
+    type OutputInstaller = FutureInstaller<Self::OutputResource>;
+    fn installer(&mut self, _: InputInstaller, name: &str) -> Self::OutputInstaller {
+        trace!("Creating future installer for listener {}", name);
+        FutureInstaller::default()
+    }
+    fn transform(
+        &mut self,
+        listener: Listener,
+        cfg: &SubFragment,
+        name: &'static str,
+    ) -> Result<Self::OutputResource, AnyError> {
+        trace!("Creating lazy acceptor for {} on {:?}", name, cfg);
+        Ok(LazyAcceptor {
+            name,
+            incoming: listener.into_incoming(),
+            cfg: cfg.clone(),
+            init: self.init.clone(),
+            handler: self.handler.clone(),
+            ctx: None,
+            idle_shutdown: self.idle_shutdown,
+            activity: Arc::new(Activity {
+                active: AtomicUsize::new(0),
+                last: Mutex::new(clock::now()),
+            }),
+            timer: None,
+        })
+    }
+}
+
+/// A [`Transformation`] attaching a per-connection context to the accepted connections.
+///
+/// The wrapped closure is called for each accepted connection, together with the [`Fragment`]
+/// of the listener. It can examine the connection (the peer address, the SNI host name of a
+/// [`TlsPeer`][crate::net::tls::TlsPeer], ...) and produce an arbitrary context, for example the
+/// tenant the client belongs to. The connections are then wrapped in [`Enriched`], carrying the
+/// context along.
+///
+/// This is meant to be chained before one of the handlers (like [`HandleListener`]), which then
+/// gets both the enriched connection and the fragment (with its `ExtraCfg`).
+///
+/// If the closure fails, the error is logged and the connection is closed.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use std::net::IpAddr;
+///
+/// use serde::Deserialize;
+/// use spirit::{AnyError, Empty, Pipeline, Spirit};
+/// use spirit::prelude::*;
+/// use spirit_tokio::handlers::{EnrichConnections, Enriched, HandleListener};
+/// use spirit_tokio::TcpListen;
+/// use tokio::net::TcpStream;
+/// use tokio::prelude::*;
+///
+/// #[derive(Default, Deserialize)]
+/// struct Config {
+///     listen: TcpListen,
+/// }
+///
+/// fn tenant_of(_ip: IpAddr) -> String {
+///     // Look it up somewhere
+///     "acme".to_owned()
+/// }
+///
+/// fn main() {
+///     Spirit::<Empty, Config>::new()
+///         .with(
+///             Pipeline::new("listener")
+///                 .extract_cfg(|cfg: &Config| cfg.listen.clone())
+///                 .transform(EnrichConnections(
+///                     |conn: &TcpStream, _: &TcpListen| -> Result<String, AnyError> {
+///                         Ok(tenant_of(conn.peer_addr()?.ip()))
+///                     },
+///                 ))
+///                 .transform(HandleListener(
+///                     |conn: Enriched<TcpStream, String>, _: &TcpListen| {
+///                         let greeting = format!("Hello {}\n", conn.context());
+///                         tokio::io::write_all(conn, greeting).map(|_| ())
+///                     },
+///                 )),
+///         )
+///         .run(|_| Ok(()));
+/// }
+/// ```
+///
+/// [`Fragment`]: spirit::Fragment
+#[derive(Clone, Debug)]
+pub struct EnrichConnections<E>(pub E);
+
+impl<Listener, InputInstaller, SubFragment, E, Ctx>
+    Transformation<Listener, InputInstaller, SubFragment> for EnrichConnections<E>
+where
+    Listener: IntoIncoming,
+    E: Fn(&Listener::Connection, &SubFragment) -> Result<Ctx, AnyError>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    SubFragment: Clone + Debug + Send + Sync + 'static,
+    Ctx: Send + Sync + 'static,
+{
+    type OutputResource = EnrichedListener<Listener, SubFragment, E>;
+    type OutputInstaller = InputInstaller;
+    fn installer(&mut self, installer: InputInstaller, _: &str) -> InputInstaller {
+        installer
+    }
+    fn transform(
+        &mut self,
+        listener: Listener,
+        cfg: &SubFragment,
+        name: &'static str,
+    ) -> Result<Self::OutputResource, AnyError> {
+        Ok(EnrichedListener {
+            inner: listener,
+            cfg: cfg.clone(),
+            hook: self.0.clone(),
+            name,
+        })
+    }
+}
+
+/// A listener producing [`Enriched`] connections.
+///
+/// This is a plumbing type produced by [`EnrichConnections`].
+#[derive(Clone, Debug)]
+pub struct EnrichedListener<Listener, Cfg, E> {
+    inner: Listener,
+    cfg: Cfg,
+    hook: E,
+    name: &'static str,
+}
+
+impl<Listener, Cfg, E, Ctx> IntoIncoming for EnrichedListener<Listener, Cfg, E>
+where
+    Listener: IntoIncoming,
+    E: Fn(&Listener::Connection, &Cfg) -> Result<Ctx, AnyError> + Send + Sync + 'static,
+    Cfg: Send + Sync + 'static,
+    Ctx: Send + Sync + 'static,
+{
+    type Connection = Enriched<Listener::Connection, Ctx>;
+    type Incoming = EnrichedIncoming<Listener::Incoming, Cfg, E>;
+    fn into_incoming(self) -> Self::Incoming {
+        EnrichedIncoming {
+            inner: self.inner.into_incoming(),
+            cfg: self.cfg,
+            hook: self.hook,
+            name: self.name,
+        }
+    }
+}
+
+/// The stream of [`Enriched`] connections.
+///
+/// This is a plumbing type produced by [`EnrichedListener`].
+pub struct EnrichedIncoming<Incoming, Cfg, E> {
+    inner: Incoming,
+    cfg: Cfg,
+    hook: E,
+    name: &'static str,
+}
+
+impl<Incoming, Cfg, E, Ctx> Stream for EnrichedIncoming<Incoming, Cfg, E>
+where
+    Incoming: Stream<Error = IoError>,
+    E: Fn(&Incoming::Item, &Cfg) -> Result<Ctx, AnyError>,
+{
+    type Item = Enriched<Incoming::Item, Ctx>;
+    type Error = IoError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        loop {
+            let conn = match try_ready!(self.inner.poll()) {
+                Some(conn) => conn,
+                None => return Ok(Async::Ready(None)),
+            };
+            match (self.hook)(&conn, &self.cfg) {
+                Ok(ctx) => return Ok(Async::Ready(Some(Enriched { conn, ctx }))),
+                Err(e) => {
+                    let msg = format!("Refusing connection on {}", self.name);
+                    spirit::log_error!(multi Warn, msg => e);
+                }
+            }
+        }
+    }
+}
+
+/// A connection with a context attached.
+///
+/// Produced by the [`EnrichConnections`] transformation. It can be used as the connection itself.
+#[derive(Debug)]
+pub struct Enriched<Conn, Ctx> {
+    conn: Conn,
+    ctx: Ctx,
+}
+
+impl<Conn, Ctx> Enriched<Conn, Ctx> {
+    /// The context of the connection.
+    pub fn context(&self) -> &Ctx {
+        &self.ctx
+    }
+
+    /// Splits the connection and its context.
+    pub fn into_parts(self) -> (Conn, Ctx) {
+        (self.conn, self.ctx)
+    }
+}
+
+impl<Conn, Ctx> Deref for Enriched<Conn, Ctx> {
+    type Target = Conn;
+    fn deref(&self) -> &Conn {
+        &self.conn
+    }
+}
+
+impl<Conn, Ctx> DerefMut for Enriched<Conn, Ctx> {
+    fn deref_mut(&mut self) -> &mut Conn {
+        &mut self.conn
+    }
+}
+
+impl<Conn: Read, Ctx> Read for Enriched<Conn, Ctx> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        self.conn.read(buf)
+    }
+}
+
+impl<Conn: Write, Ctx> Write for Enriched<Conn, Ctx> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.conn.write(buf)
+    }
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.conn.flush()
+    }
+}
+
+impl<Conn: AsyncRead, Ctx> AsyncRead for Enriched<Conn, Ctx> {}
+
+impl<Conn: AsyncWrite, Ctx> AsyncWrite for Enriched<Conn, Ctx> {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        self.conn.shutdown()
+    }
+}
+
+impl<Conn: PeerAddr, Ctx> PeerAddr for Enriched<Conn, Ctx> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.conn.remote_addr()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<Conn: TlsPeer, Ctx> TlsPeer for Enriched<Conn, Ctx> {
+    fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        self.conn.peer_certificates()
+    }
+    fn sni_hostname(&self) -> Option<String> {
+        self.conn.sni_hostname()
+    }
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.conn.alpn_protocol()
+    }
+}
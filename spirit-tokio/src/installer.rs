@@ -3,18 +3,32 @@
 //! The [`FutureInstaller`] is an [`Installer`] that allows installing (spawning) futures, but also
 //! canceling them when they are no longer required by the configuration.
 //!
+//! If an installed future panics, the panic is caught and logged, the failure is recorded in the
+//! [status][spirit::status] of the pipeline and the [`RestartPolicy`] decides what happens next.
+//!
 //! [`FutureInstaller`]: crate::installer::FutureInstaller
 //! [`Installer`]: spirit::fragment::Installer
 
+use std::any::Any;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use futures::future;
 use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use futures::sync::oneshot::{self, Receiver, Sender};
 use futures::{Future, IntoFuture, Stream};
 use log::{debug, error, trace, warn};
 use serde::de::DeserializeOwned;
+use spirit::events::Events;
 use spirit::extension::Extensible;
+use spirit::fragment::retry::Backoff;
 use spirit::fragment::Installer;
+use spirit::status::ResourceFailed;
 use spirit::AnyError;
 use structopt::StructOpt;
+use tokio::timer::Delay;
 
 use crate::runtime::Runtime;
 
@@ -42,6 +56,104 @@ impl Drop for RemoteDrop {
     }
 }
 
+/// What happens when an installed future panics.
+///
+/// In any case, the panic is logged and recorded in the [status][spirit::status] of the pipeline.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RestartPolicy {
+    /// The future is not restarted.
+    ///
+    /// It is started again only when the pipeline creates a new resource (eg. due to a change of
+    /// configuration).
+    Never,
+
+    /// A new copy of the resource is started after a delay.
+    ///
+    /// The delay grows with each restart.
+    Always(Backoff),
+
+    /// The whole application is terminated.
+    ///
+    /// This is useful if there's a supervisor (eg. systemd) that will start it again.
+    Terminate,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Never
+    }
+}
+
+/// The shared parts needed to handle panics.
+struct PanicHandling<R> {
+    policy: RestartPolicy,
+    copy: Option<fn(&R) -> R>,
+    events: Events,
+    terminate: Box<dyn Fn() + Send + Sync>,
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .cloned()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("<unknown>")
+}
+
+type BoxFuture = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+fn run<R>(
+    resource: R,
+    name: &'static str,
+    handling: Arc<PanicHandling<R>>,
+    attempt: u32,
+) -> BoxFuture
+where
+    R: IntoFuture<Item = (), Error = ()> + Send + 'static,
+    R::Future: Send + 'static,
+{
+    let spare = match (&handling.policy, handling.copy) {
+        (RestartPolicy::Always(_), Some(copy)) => Some(copy(&resource)),
+        _ => None,
+    };
+    let fut = AssertUnwindSafe(resource.into_future())
+        .catch_unwind()
+        .then(move |result| -> BoxFuture {
+            let panic = match result {
+                Ok(Ok(())) => return Box::new(future::ok(())),
+                Ok(Err(())) => {
+                    error!("{} unexpectedly failed", name);
+                    return Box::new(future::err(()));
+                }
+                Err(panic) => panic,
+            };
+            let error = format!("Resource {} panicked: {}", name, panic_message(&*panic));
+            let e: AnyError = error.clone().into();
+            spirit::log_error!(Error, e);
+            handling.events.publish(ResourceFailed {
+                pipeline: name,
+                error,
+            });
+            match (&handling.policy, spare) {
+                (RestartPolicy::Always(backoff), Some(spare)) => {
+                    let delay = backoff.delay(attempt);
+                    warn!("Restarting {} in {:?}", name, delay);
+                    let restart = Delay::new(Instant::now() + delay)
+                        .map_err(move |e| error!("Can't restart {}: {}", name, e))
+                        .and_then(move |()| run(spare, name, handling, attempt + 1));
+                    Box::new(restart)
+                }
+                (RestartPolicy::Terminate, _) => {
+                    error!("Terminating because {} panicked", name);
+                    (handling.terminate)();
+                    Box::new(future::ok(()))
+                }
+                _ => Box::new(future::ok(())),
+            }
+        });
+    Box::new(fut)
+}
+
 struct Install<R> {
     resource: R,
     drop_req: Receiver<()>,
@@ -50,16 +162,13 @@ struct Install<R> {
 
 impl<R> Install<R>
 where
-    R: IntoFuture<Item = (), Error = ()> + Send,
+    R: IntoFuture<Item = (), Error = ()> + Send + 'static,
     R::Future: Send + 'static,
 {
-    fn spawn(self, name: &'static str) {
+    fn spawn(self, name: &'static str, handling: Arc<PanicHandling<R>>) {
         let drop_req = self.drop_req;
         let confirm_drop = self.confirm_drop;
-        let fut = self
-            .resource
-            .into_future()
-            .map_err(move |()| error!("{} unexpectedly failed", name))
+        let fut = run(self.resource, name, handling, 0)
             .select(drop_req.map_err(|_| ()))
             .then(move |orig| {
                 // Just make sure the original future is dropped first
@@ -83,11 +192,17 @@ where
 /// all the [`handlers`][crate::handlers]. However, if you're writing a new [`Fragment`] or new
 /// [`Transformation`], you might want to reuse it.
 ///
+/// The default [`RestartPolicy`] is [`Never`][RestartPolicy::Never]. Other can be set with
+/// [`restart_policy`][FutureInstaller::restart_policy] and the installer then used explicitly
+/// with [`Pipeline::install`][spirit::Pipeline::install].
+///
 /// [`Fragment`]: spirit::fragment::Fragment
 /// [`Transformation`]: spirit::fragment::Transformation
 pub struct FutureInstaller<R> {
     receiver: Option<UnboundedReceiver<Install<R>>>,
     sender: UnboundedSender<Install<R>>,
+    policy: RestartPolicy,
+    copy: Option<fn(&R) -> R>,
 }
 
 impl<R> Default for FutureInstaller<R> {
@@ -96,6 +211,22 @@ impl<R> Default for FutureInstaller<R> {
         FutureInstaller {
             receiver: Some(receiver),
             sender,
+            policy: RestartPolicy::default(),
+            copy: None,
+        }
+    }
+}
+
+impl<R: Clone> FutureInstaller<R> {
+    /// Sets what happens when an installed future panics.
+    ///
+    /// Restarting needs a fresh copy of the resource (the original one is consumed by running),
+    /// therefore this is available only for resources that are [`Clone`].
+    pub fn restart_policy(self, policy: RestartPolicy) -> Self {
+        FutureInstaller {
+            policy,
+            copy: Some(R::clone),
+            ..self
         }
     }
 }
@@ -136,13 +267,48 @@ where
         B::Opts: StructOpt + Send + Sync + 'static,
     {
         let receiver = self.receiver.take().expect("Init called multiple times");
-        let installer = receiver.for_each(move |install| {
-            install.spawn(name);
-            Ok(())
-        });
-        builder.with_singleton(Runtime::default()).run_before(|_| {
-            tokio::spawn(installer);
-            Ok(())
-        })
+        let policy = self.policy.clone();
+        let copy = self.copy;
+        builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .with_singleton(Runtime::default())
+            .run_before(move |spirit| {
+                let weak = Arc::downgrade(spirit);
+                // Terminating blocks, so we don't do that inside the runtime
+                let terminate = move || {
+                    if let Some(spirit) = weak.upgrade() {
+                        thread::spawn(move || spirit.terminate());
+                    }
+                };
+                let handling = Arc::new(PanicHandling {
+                    policy,
+                    copy,
+                    events: spirit.events().clone(),
+                    terminate: Box::new(terminate),
+                });
+                let installer = receiver.for_each(move |install| {
+                    install.spawn(name, Arc::clone(&handling));
+                    Ok(())
+                });
+                tokio::spawn(installer);
+                Ok(())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::panic;
+
+    use super::*;
+
+    #[test]
+    fn panic_messages() {
+        let panic = panic::catch_unwind(|| panic!("Static")).unwrap_err();
+        assert_eq!("Static", panic_message(&*panic));
+        let panic = panic::catch_unwind(|| panic!("Formatted {}", 42)).unwrap_err();
+        assert_eq!("Formatted 42", panic_message(&*panic));
+        let panic = panic::catch_unwind(|| panic::resume_unwind(Box::new(42))).unwrap_err();
+        assert_eq!("<unknown>", panic_message(&*panic));
     }
 }
@@ -23,11 +23,23 @@
 //!
 //! * [`TcpListen`] for [`TcpListener`]
 //! * [`UdpListen`] for [`UdpSocket`] (bound to an address)
+//! * [`UdpIntake`] for a [`UdpSocket`] together with a loop receiving datagrams and dispatching
+//!   them to workers (used with the [`HandleDatagrams`] handler)
 //! * [`UnixListen`] for [`UnixListener`] (available on unix systems)
 //! * [`DatagramListen`] for [`UnixDatagram`] (available on unix systems)
+//! * [`TcpConnect`] for outgoing TCP connections (dialing multiple addresses of a host by the
+//!   Happy Eyeballs algorithm)
 //!
-//! The [`WithListenLimits`] is a wrapper that adds limits to number of concurrent connections as
-//! well as a backoff timeout in case of soft errors (like „Too many open files“). There are also
+//! With the `tls` feature, the [`TlsListen`] wrapper adds TLS (including client certificate
+//! authentication) on top of the stream listeners.
+//!
+//! With the `upgrade` feature (unix only), the sockets are handed over to the new instance of the
+//! application on a [`spirit-upgrade`](https://docs.rs/spirit-upgrade) binary upgrade instead of
+//! being bound anew.
+//!
+//! The [`WithListenLimits`] is a wrapper that adds limits to number of concurrent connections,
+//! idle time and lifetime of connections as well as a backoff timeout in case of soft errors (like
+//! „Too many open files“). There are also
 //! type aliases [`TcpListenWithLimits`] and [`UnixListenWithLimits`].
 //!
 //! # Runtime
@@ -112,9 +124,13 @@
 //! [`UdpSocket`]: ::tokio::net::UdpSocket
 //! [`UnixListen`]: net::unix::UnixListen
 //! [`UnixListener`]: ::tokio::net::unix::UnixListener
+//! [`TcpConnect`]: net::connect::TcpConnect
+//! [`UdpIntake`]: net::intake::UdpIntake
+//! [`HandleDatagrams`]: net::intake::HandleDatagrams
 //! [`DatagramListen`]: net::unix::DatagramListen
 //! [`UnixDatagram`]: ::tokio::net::unix::UnixDatagram
 //! [`WithListenLimits`]: net::limits::WithListenLimits
+//! [`TlsListen`]: https://docs.rs/spirit-tokio/*/spirit_tokio/net/tls/struct.TlsListen.html
 //! [`UnixListenWithLimits`]: net::unix::UnixListenWithLimits
 //! [`Builder`]: spirit::Builder
 
@@ -125,6 +141,6 @@ pub mod net;
 pub mod runtime;
 // pub mod scaled; XXX
 
-pub use crate::handlers::{HandleListener, HandleListenerInit, HandleSocket};
+pub use crate::handlers::{HandleListener, HandleListenerInit, HandleListenerLazy, HandleSocket};
 pub use crate::net::{TcpListen, TcpListenWithLimits, UdpListen};
 pub use crate::runtime::Runtime;
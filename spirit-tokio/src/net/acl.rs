@@ -0,0 +1,201 @@
+//! Filtering of accepted connections by the address of the peer.
+//!
+//! The [`IpAcl`] configuration fragment adds the `allow` and `deny` lists of network ranges to a
+//! listener (it is part of [`TcpListen`], so the TLS listeners built on top of it get it too). The
+//! connections are checked right after they are accepted, before anything else happens to them
+//! (like the TLS handshake) and the ones not permitted are closed right away.
+//!
+//! The lists can be changed with a configuration reload without closing the listening socket.
+//! Each listener counts the connections it rejected, see [`AclListener::rejected`].
+//!
+//! [`TcpListen`]: crate::net::TcpListen
+
+use std::io::Error as IoError;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::{try_ready, Async, Poll, Stream};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use spirit::cfg::Cidr;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::net::tcp::Incoming;
+use tokio::net::{TcpListener, TcpStream};
+
+use super::IntoIncoming;
+
+/// Lists of network ranges allowed and denied to connect.
+///
+/// # Fields
+///
+/// * `allow`: If not empty, only peers from these ranges are accepted.
+/// * `deny`: Peers from these ranges are rejected. Takes precedence over `allow`.
+///
+/// Both are lists of ranges in the CIDR notation (eg. `["10.0.0.0/8", "2001:db8::/32"]`). If both
+/// are empty (the default), everyone is accepted.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct IpAcl {
+    /// Network ranges allowed to connect.
+    ///
+    /// If empty, everything not denied is allowed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<Cidr>,
+
+    /// Network ranges not allowed to connect.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deny: Vec<Cidr>,
+}
+
+impl IpAcl {
+    /// Checks if a peer with the given address may connect.
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        let allowed = self.allow.is_empty() || self.allow.iter().any(|net| net.contains(addr));
+        allowed && !self.deny.iter().any(|net| net.contains(addr))
+    }
+
+    /// Are there any restrictions?
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+}
+
+/// A handle to the number of connections rejected by one listener.
+///
+/// Obtained from [`AclListener::rejected`] (or [`AclIncoming::rejected`]). It stays valid (and
+/// keeps counting) after the listener is turned into the stream of connections.
+#[derive(Clone, Debug, Default)]
+pub struct RejectCounter(Arc<AtomicUsize>);
+
+impl RejectCounter {
+    /// The number of connections rejected so far.
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A TCP listener filtering the accepted connections by an [`IpAcl`].
+///
+/// This is produced by the [`TcpListen`][crate::net::TcpListen] fragment.
+#[derive(Debug)]
+pub struct AclListener {
+    listener: TcpListener,
+    acl: Arc<IpAcl>,
+    rejected: RejectCounter,
+    name: &'static str,
+}
+
+impl AclListener {
+    /// Wraps the listener.
+    ///
+    /// The `name` is used for logging.
+    pub fn new(listener: TcpListener, acl: IpAcl, name: &'static str) -> Self {
+        Self {
+            listener,
+            acl: Arc::new(acl),
+            rejected: RejectCounter::default(),
+            name,
+        }
+    }
+
+    /// Access to the wrapped listener.
+    pub fn listener(&self) -> &TcpListener {
+        &self.listener
+    }
+
+    /// The counter of connections rejected by this listener.
+    pub fn rejected(&self) -> RejectCounter {
+        self.rejected.clone()
+    }
+}
+
+impl IntoIncoming for AclListener {
+    type Connection = TcpStream;
+    type Incoming = AclIncoming;
+    fn into_incoming(self) -> Self::Incoming {
+        AclIncoming {
+            incoming: self.listener.incoming(),
+            acl: self.acl,
+            rejected: self.rejected,
+            name: self.name,
+        }
+    }
+}
+
+/// The stream of connections permitted by an [`IpAcl`].
+///
+/// Produced by the [`AclListener`].
+#[derive(Debug)]
+pub struct AclIncoming {
+    incoming: Incoming,
+    acl: Arc<IpAcl>,
+    rejected: RejectCounter,
+    name: &'static str,
+}
+
+impl AclIncoming {
+    /// The counter of connections rejected by this listener.
+    pub fn rejected(&self) -> RejectCounter {
+        self.rejected.clone()
+    }
+}
+
+impl Stream for AclIncoming {
+    type Item = TcpStream;
+    type Error = IoError;
+    fn poll(&mut self) -> Poll<Option<TcpStream>, IoError> {
+        loop {
+            let conn = match try_ready!(self.incoming.poll()) {
+                Some(conn) => conn,
+                None => return Ok(Async::Ready(None)),
+            };
+            if self.acl.is_empty() {
+                return Ok(Async::Ready(Some(conn)));
+            }
+            match conn.peer_addr() {
+                Ok(addr) if self.acl.permits(addr.ip()) => return Ok(Async::Ready(Some(conn))),
+                Ok(addr) => debug!("Rejecting connection from {} on {}", addr, self.name),
+                Err(e) => debug!(
+                    "Rejecting connection of unknown peer on {}: {}",
+                    self.name, e
+                ),
+            }
+            self.rejected.inc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn permits() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let net = |s: &str| s.parse::<Cidr>().unwrap();
+        let open = IpAcl::default();
+        assert!(open.permits(ip("192.0.2.1")));
+        let acl = IpAcl {
+            allow: vec![net("10.0.0.0/8"), net("2001:db8::/32")],
+            deny: vec![net("10.1.0.0/16")],
+        };
+        assert!(acl.permits(ip("10.2.3.4")));
+        assert!(acl.permits(ip("::ffff:10.2.3.4")));
+        assert!(acl.permits(ip("2001:db8::1")));
+        assert!(!acl.permits(ip("10.1.3.4")));
+        assert!(!acl.permits(ip("192.0.2.1")));
+        let blacklist = IpAcl {
+            allow: Vec::new(),
+            deny: vec![net("192.0.2.0/24")],
+        };
+        assert!(!blacklist.permits(ip("192.0.2.1")));
+        assert!(blacklist.permits(ip("198.51.100.1")));
+    }
+}
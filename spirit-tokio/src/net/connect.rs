@@ -0,0 +1,386 @@
+//! Outgoing TCP connections.
+//!
+//! The [`TcpConnect`] fragment describes where to connect to. It creates a [`TcpConnector`], which
+//! is then used to make the actual connections.
+//!
+//! If the host name resolves to multiple addresses, they are tried according to the Happy
+//! Eyeballs algorithm ([RFC 8305](https://tools.ietf.org/html/rfc8305)). The addresses of the two
+//! IP families are interleaved and a new connection attempt is started every `attempt-delay`
+//! (or as soon as the previous one fails) without giving up on the previous ones. The first
+//! connection to succeed wins and the rest are dropped. This keeps the connection latency low on
+//! dual-stack hosts where one of the families is broken.
+//!
+//! Note that the [`spirit-reqwest`](https://docs.rs/spirit-reqwest) clients already use the
+//! same algorithm internally (with a fixed 300ms delay).
+//!
+//! # Configuration options
+//!
+//! * `address`: The host and port to connect to, like `example.com:443` or `[::1]:80`
+//!   (mandatory). The host name is resolved anew for each connection.
+//! * `connect-timeout`: Give up if no connection succeeds within this time (unlimited if not
+//!   set).
+//! * `attempt-delay`: The delay between starting connection attempts to the addresses. Defaults
+//!   to `250ms`, as recommended by the RFC.
+//! * `first-family-count`: How many addresses of the preferred IP family (the one of the first
+//!   resolved address, usually IPv6) are tried before switching to the other family. Defaults to
+//!   1.
+//!
+//! Furthermore, the options of the `TcpStreamConfigure` (by default [`TcpConfig`]) are applied to
+//! the connected stream.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit_tokio::net::connect::TcpConnect;
+//! use tokio::prelude::*;
+//!
+//! #[derive(Deserialize)]
+//! struct Cfg {
+//!     upstream: TcpConnect,
+//! }
+//!
+//! # let cfg: Cfg = serde_json::from_str(r#"{"upstream": {"address": "localhost:1"}}"#).unwrap();
+//! let connection = cfg
+//!     .upstream
+//!     .connector()
+//!     .connect()
+//!     .and_then(|conn| tokio::io::write_all(conn, "Hello\n"));
+//! # drop(connection);
+//! ```
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::oneshot::{self, Receiver};
+use futures::{Async, Future, Poll};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use spirit::cfg::Duration as CfgDuration;
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Fragment;
+use spirit::{AnyError, Empty};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::clock;
+use tokio::net::tcp::ConnectFuture;
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+
+use super::{StreamConfig, TcpConfig};
+
+spirit::cfg_defaults! {
+    fn default_attempt_delay() -> CfgDuration = CfgDuration(Duration::from_millis(250));
+    fn default_first_family_count() -> usize = 1;
+}
+
+/// A configuration fragment describing an outgoing TCP connection.
+///
+/// See the [module documentation][crate::net::connect] for the configuration options.
+///
+/// # Type parameters
+///
+/// * `ExtraCfg`: Arbitrary application specific configuration, not influencing the connections.
+/// * `TcpStreamConfigure`: Configuration applied to the connected streams.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct TcpConnect<ExtraCfg = Empty, TcpStreamConfigure = TcpConfig> {
+    /// The host and port to connect to.
+    address: String,
+
+    /// Give up connecting after this time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    connect_timeout: Option<CfgDuration>,
+
+    /// The delay between connection attempts to multiple addresses of the host.
+    #[serde(default = "default_attempt_delay")]
+    attempt_delay: CfgDuration,
+
+    /// Number of addresses of the preferred IP family to try before the other family.
+    #[serde(default = "default_first_family_count")]
+    first_family_count: usize,
+
+    #[serde(flatten)]
+    tcp_config: TcpStreamConfigure,
+
+    /// Arbitrary application specific configuration that doesn't influence the connections.
+    #[serde(flatten)]
+    pub extra_cfg: ExtraCfg,
+}
+
+impl<ExtraCfg, TcpStreamConfigure: Clone> TcpConnect<ExtraCfg, TcpStreamConfigure> {
+    /// Creates the connector described by this configuration.
+    pub fn connector(&self) -> TcpConnector<TcpStreamConfigure> {
+        TcpConnector {
+            address: self.address.clone(),
+            connect_timeout: self.connect_timeout.map(|t| *t),
+            attempt_delay: *self.attempt_delay,
+            first_family_count: cmp::max(self.first_family_count, 1),
+            tcp_config: self.tcp_config.clone(),
+        }
+    }
+}
+
+impl<ExtraCfg, TcpStreamConfigure> Fragment for TcpConnect<ExtraCfg, TcpStreamConfigure>
+where
+    ExtraCfg: Clone + Debug + PartialEq,
+    TcpStreamConfigure: Clone + Debug + PartialEq,
+{
+    type Driver = CacheEq<Self>;
+    type Installer = ();
+    type Seed = ();
+    type Resource = TcpConnector<TcpStreamConfigure>;
+    fn make_seed(&self, _: &'static str) -> Result<(), AnyError> {
+        Ok(())
+    }
+    fn make_resource(&self, _: &mut (), _: &'static str) -> Result<Self::Resource, AnyError> {
+        Ok(self.connector())
+    }
+}
+
+/// A factory of outgoing TCP connections.
+///
+/// Created by the [`TcpConnect`] fragment.
+#[derive(Clone, Debug)]
+pub struct TcpConnector<TcpStreamConfigure = TcpConfig> {
+    address: String,
+    connect_timeout: Option<Duration>,
+    attempt_delay: Duration,
+    first_family_count: usize,
+    tcp_config: TcpStreamConfigure,
+}
+
+impl<TcpStreamConfigure> TcpConnector<TcpStreamConfigure>
+where
+    TcpStreamConfigure: Clone + StreamConfig<TcpStream>,
+{
+    /// Connects to the configured address.
+    ///
+    /// The host name is resolved in a separate thread, so it doesn't block the reactor.
+    pub fn connect(&self) -> Connecting<TcpStreamConfigure> {
+        let resolving = match self.address.parse::<SocketAddr>() {
+            Ok(addr) => Resolving::Done(Ok(vec![addr])),
+            Err(_) => {
+                let (sender, receiver) = oneshot::channel();
+                let address = self.address.clone();
+                thread::spawn(move || {
+                    let resolved = address.to_socket_addrs().map(Iterator::collect);
+                    // The connection attempt may have been abandoned in the meantime
+                    let _ = sender.send(resolved);
+                });
+                Resolving::Pending(receiver)
+            }
+        };
+        Connecting {
+            address: self.address.clone(),
+            resolving: Some(resolving),
+            addrs: VecDeque::new(),
+            attempts: Vec::new(),
+            next_attempt: None,
+            deadline: self.connect_timeout.map(|t| Delay::new(clock::now() + t)),
+            attempt_delay: self.attempt_delay,
+            first_family_count: self.first_family_count,
+            last_error: None,
+            tcp_config: self.tcp_config.clone(),
+        }
+    }
+}
+
+enum Resolving {
+    Pending(Receiver<Result<Vec<SocketAddr>, IoError>>),
+    Done(Result<Vec<SocketAddr>, IoError>),
+}
+
+/// Orders the addresses so the IP families alternate, after the first `first_family_count`
+/// addresses of the preferred family.
+fn interleave(addrs: Vec<SocketAddr>, first_family_count: usize) -> VecDeque<SocketAddr> {
+    let first_v6 = addrs.first().map(SocketAddr::is_ipv6).unwrap_or(true);
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_v6);
+    let mut result = VecDeque::with_capacity(first.len() + second.len());
+    let count = cmp::min(first_family_count, first.len());
+    result.extend(first.drain(..count));
+    while !first.is_empty() || !second.is_empty() {
+        result.extend(second.pop_front());
+        result.extend(first.pop_front());
+    }
+    result
+}
+
+/// A future of the connection being established.
+///
+/// Returned by [`TcpConnector::connect`].
+pub struct Connecting<TcpStreamConfigure> {
+    address: String,
+    resolving: Option<Resolving>,
+    addrs: VecDeque<SocketAddr>,
+    attempts: Vec<(SocketAddr, ConnectFuture)>,
+    next_attempt: Option<Delay>,
+    deadline: Option<Delay>,
+    attempt_delay: Duration,
+    first_family_count: usize,
+    last_error: Option<IoError>,
+    tcp_config: TcpStreamConfigure,
+}
+
+impl<TcpStreamConfigure> Connecting<TcpStreamConfigure> {
+    fn resolved(&mut self) -> Poll<(), IoError> {
+        let resolved = match self.resolving.take() {
+            None => return Ok(Async::Ready(())),
+            Some(Resolving::Done(resolved)) => resolved,
+            Some(Resolving::Pending(mut receiver)) => match receiver.poll() {
+                Ok(Async::Ready(resolved)) => resolved,
+                Ok(Async::NotReady) => {
+                    self.resolving = Some(Resolving::Pending(receiver));
+                    return Ok(Async::NotReady);
+                }
+                Err(_) => Err(IoError::new(ErrorKind::Other, "Resolver thread panicked")),
+            },
+        };
+        let addrs = resolved?;
+        if addrs.is_empty() {
+            let msg = format!("No addresses for {}", self.address);
+            return Err(IoError::new(ErrorKind::NotFound, msg));
+        }
+        trace!("Resolved {} to {:?}", self.address, addrs);
+        self.addrs = interleave(addrs, self.first_family_count);
+        Ok(Async::Ready(()))
+    }
+
+    fn start_attempt(&mut self) {
+        let addr = self
+            .addrs
+            .pop_front()
+            .expect("Starting attempt without addresses");
+        debug!("Connecting to {} ({})", self.address, addr);
+        self.attempts.push((addr, TcpStream::connect(&addr)));
+        self.next_attempt = Some(Delay::new(clock::now() + self.attempt_delay));
+    }
+}
+
+fn timer_fired(timer: &mut Option<Delay>) -> Result<bool, IoError> {
+    match timer {
+        Some(timer) => timer
+            .poll()
+            .map(|ready| ready.is_ready())
+            .map_err(|e| IoError::new(ErrorKind::Other, e)),
+        None => Ok(false),
+    }
+}
+
+impl<TcpStreamConfigure> Future for Connecting<TcpStreamConfigure>
+where
+    TcpStreamConfigure: StreamConfig<TcpStream>,
+{
+    type Item = TcpStream;
+    type Error = IoError;
+    fn poll(&mut self) -> Poll<TcpStream, IoError> {
+        if timer_fired(&mut self.deadline)? {
+            let msg = format!("Timed out connecting to {}", self.address);
+            return Err(IoError::new(ErrorKind::TimedOut, msg));
+        }
+        if self.resolved()?.is_not_ready() {
+            return Ok(Async::NotReady);
+        }
+        loop {
+            let mut failed = false;
+            let mut i = 0;
+            while i < self.attempts.len() {
+                match self.attempts[i].1.poll() {
+                    Ok(Async::Ready(mut stream)) => {
+                        debug!("Connected to {} ({})", self.address, self.attempts[i].0);
+                        self.tcp_config.configure(&mut stream)?;
+                        return Ok(Async::Ready(stream));
+                    }
+                    Ok(Async::NotReady) => i += 1,
+                    Err(e) => {
+                        let (addr, _) = self.attempts.swap_remove(i);
+                        debug!("Failed to connect to {} ({}): {}", self.address, addr, e);
+                        self.last_error = Some(e);
+                        failed = true;
+                    }
+                }
+            }
+            let fired = timer_fired(&mut self.next_attempt)?;
+            if !self.addrs.is_empty() && (failed || fired || self.attempts.is_empty()) {
+                self.start_attempt();
+                // Poll the new attempt and timer so we get woken up by them
+                continue;
+            }
+            if self.attempts.is_empty() {
+                return Err(self.last_error.take().unwrap_or_else(|| {
+                    let msg = format!("Failed to connect to {}", self.address);
+                    IoError::new(ErrorKind::Other, msg)
+                }));
+            }
+            return Ok(Async::NotReady);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener as StdTcpListener;
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn interleaving() {
+        let addrs = vec![
+            addr("[::1]:1"),
+            addr("[::2]:1"),
+            addr("[::3]:1"),
+            addr("127.0.0.1:1"),
+            addr("127.0.0.2:1"),
+        ];
+        let expected = vec![
+            addr("[::1]:1"),
+            addr("127.0.0.1:1"),
+            addr("[::2]:1"),
+            addr("127.0.0.2:1"),
+            addr("[::3]:1"),
+        ];
+        assert_eq!(expected, Vec::from(interleave(addrs.clone(), 1)));
+        let expected = vec![
+            addr("[::1]:1"),
+            addr("[::2]:1"),
+            addr("127.0.0.1:1"),
+            addr("[::3]:1"),
+            addr("127.0.0.2:1"),
+        ];
+        assert_eq!(expected, Vec::from(interleave(addrs, 2)));
+    }
+
+    #[test]
+    fn falls_back_to_working_address() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let working = listener.local_addr().unwrap();
+        // A port that was free a moment ago, most likely refusing connections now
+        let refusing = StdTcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let connector = TcpConnector {
+            address: "test".to_owned(),
+            connect_timeout: Some(Duration::from_secs(5)),
+            attempt_delay: Duration::from_secs(60),
+            first_family_count: 1,
+            tcp_config: Empty {},
+        };
+        let mut connecting = connector.connect();
+        connecting.resolving = Some(Resolving::Done(Ok(vec![refusing, working])));
+        let stream = tokio::runtime::current_thread::block_on_all(connecting).unwrap();
+        assert_eq!(working, stream.peer_addr().unwrap());
+    }
+}
@@ -0,0 +1,522 @@
+//! Consuming datagrams received on UDP sockets.
+//!
+//! Applications implementing a UDP protocol (statsd-style metric intake, syslog, ...) mostly
+//! share the same receiving loop ‒ read a datagram, possibly split it into messages, hand them
+//! over to something that processes them and make sure a slow processing doesn't stall the
+//! receiving. The [`UdpIntake`] fragment together with the [`HandleDatagrams`] transformation
+//! implement that loop.
+//!
+//! Each socket has a receiving task. It dispatches the messages to a configurable number of
+//! worker tasks through bounded queues. If a queue is full, the messages are dropped (this is UDP
+//! after all and it's better than letting the kernel buffer overflow and drop messages
+//! indiscriminately). The handler closure is called in the workers, one message at a time for each
+//! worker.
+//!
+//! When the socket is reconfigured or removed, the receiving task ends and the workers finish
+//! processing the messages already in their queues.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{AnyError, Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_tokio::net::intake::{Datagram, HandleDatagrams, UdpIntake};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [intake]
+//! port = 8125
+//! framing = "lines"
+//! workers = 4
+//! fan-out = "by-source"
+//! recv-buffer = "4MiB"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     intake: UdpIntake,
+//! }
+//!
+//! impl Config {
+//!     fn intake(&self) -> UdpIntake {
+//!         self.intake.clone()
+//!     }
+//! }
+//!
+//! fn metric(datagram: Datagram, _: &UdpIntake) -> Result<(), AnyError> {
+//!     println!("{}: {}", datagram.peer, String::from_utf8_lossy(&datagram.payload));
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("intake")
+//!                 .extract_cfg(Config::intake)
+//!                 .transform(HandleDatagrams(metric))
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::Debug;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
+
+use err_context::prelude::*;
+use futures::sync::mpsc::{self, Sender};
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use log::{debug, trace, warn};
+use net2::UdpSocketExt;
+use serde::{Deserialize, Serialize};
+use spirit::cfg::ByteSize;
+use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
+use spirit::fragment::{Fragment, Stackable, Transformation};
+use spirit::{AnyError, Empty};
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::net::UdpSocket;
+
+use super::{Endpoint, UdpListen};
+use crate::installer::FutureInstaller;
+
+/// How the received datagrams are split into messages.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// Each datagram is one message.
+    Datagram,
+
+    /// Each non-empty line of a datagram is one message (statsd clients usually pack multiple
+    /// metrics into one datagram this way).
+    Lines,
+}
+
+impl Default for Framing {
+    fn default() -> Self {
+        Framing::Datagram
+    }
+}
+
+/// How the messages are distributed between the workers.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum FanOut {
+    /// Each message goes to the next worker.
+    RoundRobin,
+
+    /// Messages from the same source address always go to the same worker, therefore they are
+    /// processed in the order they arrived.
+    BySource,
+}
+
+impl Default for FanOut {
+    fn default() -> Self {
+        FanOut::RoundRobin
+    }
+}
+
+spirit::cfg_defaults! {
+    fn default_max_datagram() -> ByteSize = ByteSize(65_507);
+    fn default_workers() -> usize = 1;
+    fn default_queue() -> usize = 1024;
+}
+
+/// The configuration of the receiving loop.
+///
+/// # Fields
+///
+/// * `max-datagram`: The largest datagram accepted. Bigger ones are dropped. Defaults to the
+///   largest possible UDP payload.
+/// * `recv-buffer`: The size of the kernel receive buffer (`SO_RCVBUF`) of the socket. Left on
+///   the OS default if not set.
+/// * `workers`: Number of workers processing the messages. Defaults to 1.
+/// * `fan-out`: How the messages are distributed between the workers, either `round-robin` (the
+///   default) or `by-source`.
+/// * `framing`: How datagrams are split into messages, either `datagram` (the default, one
+///   message per datagram) or `lines`.
+/// * `queue`: How many messages may wait for each worker before more are dropped. Defaults to
+///   1024.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct IntakeCfg {
+    /// The largest accepted datagram.
+    #[serde(default = "default_max_datagram")]
+    max_datagram: ByteSize,
+
+    /// The size of the kernel receive buffer.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recv_buffer: Option<ByteSize>,
+
+    /// Number of workers processing the messages.
+    #[serde(default = "default_workers")]
+    workers: usize,
+
+    /// How the messages are distributed between the workers (round-robin or by-source).
+    #[serde(default)]
+    fan_out: FanOut,
+
+    /// How datagrams are split into messages (datagram or lines).
+    #[serde(default)]
+    framing: Framing,
+
+    /// How many messages may wait for each worker.
+    #[serde(default = "default_queue")]
+    queue: usize,
+}
+
+impl Default for IntakeCfg {
+    fn default() -> Self {
+        IntakeCfg {
+            max_datagram: default_max_datagram(),
+            recv_buffer: None,
+            workers: default_workers(),
+            fan_out: FanOut::default(),
+            framing: Framing::default(),
+            queue: default_queue(),
+        }
+    }
+}
+
+/// A configuration fragment of a UDP socket together with the loop consuming it.
+///
+/// This is the [`UdpListen`] with the options of [`IntakeCfg`] added. It is meant to be used with
+/// the [`HandleDatagrams`] transformation.
+///
+/// Changing only the receive buffer size or the options of the loop keeps the socket, but
+/// restarts the loop.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+pub struct UdpIntake<ExtraCfg = Empty> {
+    /// The socket.
+    ///
+    /// This is available publicly to allow reading the extra configuration out of it.
+    #[serde(flatten)]
+    pub listener: UdpListen<ExtraCfg>,
+
+    #[serde(flatten)]
+    intake: IntakeCfg,
+}
+
+impl<ExtraCfg> Stackable for UdpIntake<ExtraCfg> {}
+
+impl<ExtraCfg> Endpoint for UdpIntake<ExtraCfg> {
+    fn endpoint(&self) -> String {
+        self.listener.endpoint()
+    }
+}
+
+impl<ExtraCfg: PartialEq> Comparable for UdpIntake<ExtraCfg> {
+    fn compare(&self, other: &Self) -> Comparison {
+        let listener_cmp = self.listener.compare(&other.listener);
+        if listener_cmp == Comparison::Same && self.intake != other.intake {
+            Comparison::Similar
+        } else {
+            listener_cmp
+        }
+    }
+}
+
+impl<ExtraCfg> Fragment for UdpIntake<ExtraCfg>
+where
+    ExtraCfg: Clone + Debug + PartialEq,
+{
+    type Driver = CacheSimilar<Self>;
+    type Installer = ();
+    type Seed = StdUdpSocket;
+    type Resource = IntakeSocket;
+    fn make_seed(&self, name: &str) -> Result<Self::Seed, AnyError> {
+        self.listener.make_seed(name)
+    }
+    fn make_resource(&self, seed: &mut Self::Seed, name: &str) -> Result<IntakeSocket, AnyError> {
+        if let Some(size) = self.intake.recv_buffer {
+            seed.set_recv_buffer_size(size.0 as usize)
+                .with_context(|_| {
+                    format!("Failed to set receive buffer of {} to {}", name, size)
+                })?;
+        }
+        let socket = self.listener.make_resource(seed, name)?;
+        Ok(IntakeSocket {
+            socket,
+            cfg: self.intake.clone(),
+        })
+    }
+}
+
+/// A socket ready to be consumed by [`HandleDatagrams`].
+///
+/// This is a plumbing type created by the [`UdpIntake`] fragment.
+#[derive(Debug)]
+pub struct IntakeSocket {
+    socket: UdpSocket,
+    cfg: IntakeCfg,
+}
+
+impl IntakeSocket {
+    /// Access to the socket.
+    ///
+    /// This can be used to set further socket options (eg. join a multicast group).
+    pub fn socket(&self) -> &UdpSocket {
+        &self.socket
+    }
+}
+
+/// A single received message.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Datagram {
+    /// The content of the message.
+    pub payload: Vec<u8>,
+
+    /// Who sent it.
+    pub peer: SocketAddr,
+}
+
+/// Splits a datagram into messages.
+fn frames(payload: &[u8], framing: Framing) -> Vec<&[u8]> {
+    match framing {
+        Framing::Datagram => vec![payload],
+        Framing::Lines => payload
+            .split(|b| *b == b'\n')
+            .map(|line| {
+                if line.ends_with(b"\r") {
+                    &line[..line.len() - 1]
+                } else {
+                    line
+                }
+            })
+            .filter(|line| !line.is_empty())
+            .collect(),
+    }
+}
+
+/// Picks the worker for the next message.
+fn pick(fan_out: FanOut, peer: &SocketAddr, workers: usize, next: &mut usize) -> usize {
+    match fan_out {
+        FanOut::RoundRobin => {
+            let picked = *next % workers;
+            *next = picked + 1;
+            picked
+        }
+        FanOut::BySource => {
+            let mut hasher = DefaultHasher::new();
+            peer.hash(&mut hasher);
+            (hasher.finish() % workers as u64) as usize
+        }
+    }
+}
+
+type Worker = Box<dyn Future<Item = (), Error = ()> + Send>;
+
+/// The receiving loop of one socket.
+///
+/// This is a plumbing type created by [`HandleDatagrams`]. It is a future that receives the
+/// datagrams and dispatches them to the workers (which are spawned when it is first polled).
+pub struct DatagramReceiver {
+    name: &'static str,
+    socket: UdpSocket,
+    cfg: IntakeCfg,
+    buffer: Vec<u8>,
+    pending: Vec<Worker>,
+    queues: Vec<Sender<Datagram>>,
+    next: usize,
+    dropped: usize,
+}
+
+impl DatagramReceiver {
+    /// Sends the message to a worker, or drops it if the worker's queue is full.
+    ///
+    /// Returns false if the worker is gone.
+    fn dispatch(&mut self, datagram: Datagram) -> bool {
+        let idx = pick(
+            self.cfg.fan_out,
+            &datagram.peer,
+            self.queues.len(),
+            &mut self.next,
+        );
+        match self.queues[idx].try_send(datagram) {
+            Ok(()) => {
+                if self.dropped > 0 {
+                    warn!(
+                        "Dropped {} messages on {} because of full queues",
+                        self.dropped, self.name
+                    );
+                    self.dropped = 0;
+                }
+                true
+            }
+            Err(ref e) if e.is_full() => {
+                self.dropped += 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+impl Future for DatagramReceiver {
+    type Item = ();
+    type Error = ();
+    fn poll(&mut self) -> Poll<(), ()> {
+        for worker in self.pending.drain(..) {
+            tokio::spawn(worker);
+        }
+        loop {
+            let (size, peer) = match self.socket.poll_recv_from(&mut self.buffer) {
+                Ok(Async::Ready(received)) => received,
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                // ICMP errors of previous sends may be reported this way, that's not a problem
+                // of the socket itself.
+                Err(ref e)
+                    if e.kind() == ErrorKind::ConnectionRefused
+                        || e.kind() == ErrorKind::ConnectionReset =>
+                {
+                    debug!("Ignoring error on {}: {}", self.name, e);
+                    continue;
+                }
+                Err(e) => {
+                    let e = e.context(format!("Failed to receive on {}", self.name));
+                    spirit::log_error!(multi Error, e.into());
+                    return Err(());
+                }
+            };
+            // The buffer is one byte longer than the limit, to detect (possibly truncated)
+            // oversized datagrams.
+            if size > self.cfg.max_datagram.0 as usize {
+                warn!("Dropping oversized datagram from {} on {}", peer, self.name);
+                continue;
+            }
+            trace!("Received {} bytes from {} on {}", size, peer, self.name);
+            let messages = frames(&self.buffer[..size], self.cfg.framing)
+                .into_iter()
+                .map(|payload| Datagram {
+                    payload: payload.to_vec(),
+                    peer,
+                })
+                .collect::<Vec<_>>();
+            for message in messages {
+                if !self.dispatch(message) {
+                    warn!("The workers of {} are gone", self.name);
+                    return Err(());
+                }
+            }
+        }
+    }
+}
+
+/// A [`Transformation`] consuming the datagrams of an [`UdpIntake`].
+///
+/// The closure is called in the workers for each received message, with the configuration
+/// fragment. It returns a future (or a `Result`) and the worker waits for it to finish before
+/// taking the next message. Errors are logged.
+///
+/// See the [module documentation][crate::net::intake].
+#[derive(Clone, Debug)]
+pub struct HandleDatagrams<F>(pub F);
+
+impl<InputInstaller, SubFragment, F, Fut> Transformation<IntakeSocket, InputInstaller, SubFragment>
+    for HandleDatagrams<F>
+where
+    F: Fn(Datagram, &SubFragment) -> Fut + Clone + Send + 'static,
+    Fut: IntoFuture<Item = ()>,
+    Fut::Error: Into<AnyError>,
+    Fut::Future: Send + 'static,
+    SubFragment: Clone + Debug + Send + 'static,
+{
+    type OutputResource = DatagramReceiver;
+    type OutputInstaller = FutureInstaller<DatagramReceiver>;
+    fn installer(&mut self, _: InputInstaller, name: &str) -> Self::OutputInstaller {
+        trace!("Creating future installer for intake {}", name);
+        FutureInstaller::default()
+    }
+    fn transform(
+        &mut self,
+        intake: IntakeSocket,
+        cfg: &SubFragment,
+        name: &'static str,
+    ) -> Result<DatagramReceiver, AnyError> {
+        trace!("Creating datagram receiver for {} on {:?}", name, cfg);
+        let IntakeSocket {
+            socket,
+            cfg: intake,
+        } = intake;
+        let workers = intake.workers.max(1);
+        let mut queues = Vec::with_capacity(workers);
+        let mut pending = Vec::with_capacity(workers);
+        for _ in 0..workers {
+            let (sender, receiver) = mpsc::channel(intake.queue);
+            let handler = self.0.clone();
+            let cfg = cfg.clone();
+            let worker = receiver.for_each(move |datagram| {
+                handler(datagram, &cfg).into_future().then(move |result| {
+                    if let Err(e) = result {
+                        let e = e
+                            .into()
+                            .context(format!("Failed to handle datagram on {}", name));
+                        spirit::log_error!(multi Error, e.into());
+                    }
+                    Ok(())
+                })
+            });
+            queues.push(sender);
+            pending.push(Box::new(worker) as Worker);
+        }
+        Ok(DatagramReceiver {
+            name,
+            socket,
+            buffer: vec![0; intake.max_datagram.0 as usize + 1],
+            cfg: intake,
+            pending,
+            queues,
+            next: 0,
+            dropped: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn framing() {
+        let payload = b"a:1|c\nb:2|g\r\n\nc:3|ms";
+        assert_eq!(vec![&payload[..]], frames(payload, Framing::Datagram));
+        let lines: Vec<&[u8]> = vec![b"a:1|c", b"b:2|g", b"c:3|ms"];
+        assert_eq!(lines, frames(payload, Framing::Lines));
+    }
+
+    #[test]
+    fn fan_out() {
+        let peer: SocketAddr = "127.0.0.1:1234".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:1235".parse().unwrap();
+        let mut next = 0;
+        let picked = (0..4)
+            .map(|_| pick(FanOut::RoundRobin, &peer, 3, &mut next))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![0, 1, 2, 0], picked);
+        let first = pick(FanOut::BySource, &peer, 3, &mut next);
+        for _ in 0..10 {
+            assert_eq!(first, pick(FanOut::BySource, &peer, 3, &mut next));
+            assert!(pick(FanOut::BySource, &other, 3, &mut next) < 3);
+        }
+    }
+
+    #[test]
+    fn config() {
+        let cfg: UdpIntake = serde_json::from_str(
+            r#"{"port": 8125, "framing": "lines", "fan-out": "by-source", "workers": 4}"#,
+        )
+        .unwrap();
+        assert_eq!(Framing::Lines, cfg.intake.framing);
+        assert_eq!(FanOut::BySource, cfg.intake.fan_out);
+        assert_eq!(4, cfg.intake.workers);
+        assert_eq!(1024, cfg.intake.queue);
+        assert!(cfg.intake.recv_buffer.is_none());
+    }
+}
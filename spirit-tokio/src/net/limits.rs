@@ -10,21 +10,34 @@
 //! This module provides tools to address these problems in the form of [`WithListenLimits`]
 //! wrapper. There are also type aliases for already wrapped sockets, like [`TcpListenWithLimits`]
 //!
+//! The wrapper can also close connections that stay idle for too long or exceed their maximum
+//! lifetime. This takes care of connections leaked by misbehaving clients (or half-open ones,
+//! where the other side went away without closing them). Each listener counts the connections it
+//! closed, see [`LimitedListener::reaped`].
+//!
+//! Finally, it can limit the bandwidth (separately for reading and writing), both of each
+//! connection and of all the connections of the listener together. This bounds the throughput of
+//! each client without the need for a separate proxy.
+//!
 //! [`WithListenLimits`]: crate::net::limits::WithListenLimits
 //! [`TcpListenWithLimits`]: crate::net::TcpListenWithLimits
 
 use std::fmt::Debug;
-use std::io::{Error as IoError, Read, Write};
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use futures::task::AtomicTask;
 use futures::{Async, Poll, Stream};
+use log::{debug, warn};
+#[cfg(feature = "tls")]
+use rustls::Certificate;
 use serde::de::DeserializeOwned;
-use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
+use spirit::cfg::{ByteSize, Duration as CfgDuration};
 use spirit::extension::Extensible;
 use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
 use spirit::fragment::{Fragment, Stackable};
@@ -33,9 +46,13 @@ use spirit::AnyError;
 use structdoc::StructDoc;
 use structopt::StructOpt;
 use tk_listen::{ListenExt, SleepOnError};
+use tokio::clock;
 use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
 
-use super::IntoIncoming;
+#[cfg(feature = "tls")]
+use super::tls::TlsPeer;
+use super::{Endpoint, IntoIncoming, PeerAddr};
 
 /// Additional configuration for limiting of connections & error handling when accepting.
 ///
@@ -52,6 +69,35 @@ pub trait ListenLimits {
     /// If you don't want the limit, return some huge number (`usize::max_value() / 2 - 1` is
     /// recommended maximum).
     fn max_conn(&self) -> usize;
+
+    /// Connections without any activity for this long are closed.
+    ///
+    /// Defaults to no timeout.
+    fn idle_timeout(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Connections are closed after being open for this long.
+    ///
+    /// Defaults to no limit.
+    fn max_lifetime(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Maximum bytes per second read from (and separately written to) each connection.
+    ///
+    /// Defaults to no limit.
+    fn conn_bandwidth(&self) -> Option<u64> {
+        None
+    }
+
+    /// Maximum bytes per second read from (and separately written to) all the connections of the
+    /// listener together.
+    ///
+    /// Defaults to no limit.
+    fn listener_bandwidth(&self) -> Option<u64> {
+        None
+    }
 }
 
 /// A wrapper around a listening socket [`Fragment`] that adds limits and error handling to it.
@@ -82,6 +128,15 @@ pub type WithLimits<Listener> = WithListenLimits<Listener, Limits>;
 
 impl<Listener, Limits> Stackable for WithListenLimits<Listener, Limits> where Listener: Stackable {}
 
+impl<Listener, Limits> Endpoint for WithListenLimits<Listener, Limits>
+where
+    Listener: Endpoint,
+{
+    fn endpoint(&self) -> String {
+        self.listener.endpoint()
+    }
+}
+
 impl<Listener, Limits> Comparable for WithListenLimits<Listener, Limits>
 where
     Listener: Comparable,
@@ -116,10 +171,17 @@ where
         name: &'static str,
     ) -> Result<Self::Resource, AnyError> {
         let inner = self.listener.make_resource(seed, name)?;
+        let max_conn = self.limits.max_conn();
+        check_fd_limit(max_conn, name);
         Ok(LimitedListener {
             inner,
             error_sleep: self.limits.error_sleep(),
-            max_conn: self.limits.max_conn(),
+            max_conn,
+            idle_timeout: self.limits.idle_timeout(),
+            max_lifetime: self.limits.max_lifetime(),
+            reaped: ReapCounter::default(),
+            conn_bandwidth: self.limits.conn_bandwidth(),
+            listener_bandwidth: self.limits.listener_bandwidth(),
         })
     }
     fn init<B: Extensible<Ok = B>>(builder: B, name: &'static str) -> Result<B, AnyError>
@@ -131,12 +193,32 @@ where
     }
 }
 
-fn default_error_sleep() -> Duration {
-    Duration::from_millis(100)
+/// Warns if the connection limit can't be reached because of the limit on open files.
+///
+/// Running out of file descriptors makes the listener fail to accept (and other things, like
+/// opening files, fail too), which is much worse than refusing to accept more connections.
+#[cfg(unix)]
+fn check_fd_limit(max_conn: usize, name: &str) {
+    // Not set explicitly
+    if max_conn >= usize::max_value() / 2 - 1 {
+        return;
+    }
+    match rlimit::getrlimit(rlimit::Resource::NOFILE) {
+        Ok((soft, _)) if max_conn as u64 > soft => warn!(
+            "Listener {} allows up to {} connections, but the limit of open files (nofile) is \
+             only {}",
+            name, max_conn, soft
+        ),
+        Ok(_) => (),
+        Err(e) => debug!("Failed to check the limit of open files: {}", e),
+    }
 }
 
-fn serialize_duration<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
-    s.serialize_str(&::humantime::format_duration(*d).to_string())
+#[cfg(not(unix))]
+fn check_fd_limit(_max_conn: usize, _name: &str) {}
+
+spirit::cfg_defaults! {
+    fn default_error_sleep() -> CfgDuration = CfgDuration(Duration::from_millis(100));
 }
 
 /// An implementation of [`ListenLimits`] that reads the limits from configuration.
@@ -151,6 +233,13 @@ fn serialize_duration<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Err
 /// * `max-conn`: Maximum number of parallel connections on this listener. Defaults to no limit
 ///   (well, to `usize::max_value() / 2 - 1`, actually, for technical reasons, but that should be
 ///   effectively no limit).
+/// * `idle-timeout`: Connections without any reads or writes for this long are closed. Defaults
+///   to no timeout.
+/// * `max-lifetime`: Connections open for longer than this are closed. Defaults to no limit.
+/// * `conn-bandwidth`: Bytes per second each connection may read and write (with units, like
+///   `1MiB`). Reading and writing are limited separately. Defaults to no limit.
+/// * `listener-bandwidth`: Bytes per second all the connections of the listener may read and write
+///   together. Defaults to no limit.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
 #[cfg_attr(feature = "cfg-help", derive(StructDoc))]
 pub struct Limits {
@@ -162,13 +251,8 @@ pub struct Limits {
     /// then.
     ///
     /// Defaults to `100ms` if not set.
-    #[serde(
-        rename = "error-sleep",
-        default = "default_error_sleep",
-        deserialize_with = "::serde_humantime::deserialize",
-        serialize_with = "serialize_duration"
-    )]
-    error_sleep: Duration,
+    #[serde(rename = "error-sleep", default = "default_error_sleep")]
+    error_sleep: CfgDuration,
 
     /// Maximum number of connections per one listener.
     ///
@@ -180,6 +264,30 @@ pub struct Limits {
     /// assume that if not set, there's no limit.
     #[serde(skip_serializing_if = "Option::is_none")]
     max_conn: Option<usize>,
+
+    /// Close connections idle for this long.
+    ///
+    /// A connection is idle if nothing is read from it or written into it. Note that the
+    /// connection is closed on the next attempt to read or write, so it works only if the
+    /// application keeps trying to read from it (which is usually the case).
+    #[serde(rename = "idle-timeout", skip_serializing_if = "Option::is_none")]
+    idle_timeout: Option<CfgDuration>,
+
+    /// Close connections open for longer than this.
+    #[serde(rename = "max-lifetime", skip_serializing_if = "Option::is_none")]
+    max_lifetime: Option<CfgDuration>,
+
+    /// Bytes per second each connection may read and write.
+    ///
+    /// Short bursts of up to one second worth of data are allowed.
+    #[serde(rename = "conn-bandwidth", skip_serializing_if = "Option::is_none")]
+    conn_bandwidth: Option<ByteSize>,
+
+    /// Bytes per second all the connections of one listener may read and write together.
+    ///
+    /// The bandwidth is not split fairly, connections that try more often get more of it.
+    #[serde(rename = "listener-bandwidth", skip_serializing_if = "Option::is_none")]
+    listener_bandwidth: Option<ByteSize>,
 }
 
 impl Default for Limits {
@@ -187,17 +295,68 @@ impl Default for Limits {
         Self {
             error_sleep: default_error_sleep(),
             max_conn: None,
+            idle_timeout: None,
+            max_lifetime: None,
+            conn_bandwidth: None,
+            listener_bandwidth: None,
         }
     }
 }
 
 impl ListenLimits for Limits {
     fn error_sleep(&self) -> Duration {
-        self.error_sleep
+        *self.error_sleep
     }
     fn max_conn(&self) -> usize {
         self.max_conn.unwrap_or_else(|| usize::max_value() / 2 - 1)
     }
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(|t| *t)
+    }
+    fn max_lifetime(&self) -> Option<Duration> {
+        self.max_lifetime.map(|t| *t)
+    }
+    fn conn_bandwidth(&self) -> Option<u64> {
+        self.conn_bandwidth.map(|b| b.0)
+    }
+    fn listener_bandwidth(&self) -> Option<u64> {
+        self.listener_bandwidth.map(|b| b.0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct ReapCounters {
+    idle: AtomicUsize,
+    lifetime: AtomicUsize,
+}
+
+/// Numbers of connections closed because of the [`idle_timeout`] and [`max_lifetime`] limits.
+///
+/// [`idle_timeout`]: ListenLimits::idle_timeout
+/// [`max_lifetime`]: ListenLimits::max_lifetime
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Reaped {
+    /// Closed because of being idle for too long.
+    pub idle: usize,
+    /// Closed because of being open for too long.
+    pub lifetime: usize,
+}
+
+/// A handle to the numbers of connections closed by one listener.
+///
+/// Obtained from [`LimitedListener::reaped`] (or [`LimitedIncoming::reaped`]). It stays valid (and
+/// keeps counting) after the listener is turned into the stream of connections.
+#[derive(Clone, Debug, Default)]
+pub struct ReapCounter(Arc<ReapCounters>);
+
+impl ReapCounter {
+    /// The numbers of connections closed so far.
+    pub fn get(&self) -> Reaped {
+        Reaped {
+            idle: self.0.idle.load(Ordering::Relaxed),
+            lifetime: self.0.lifetime.load(Ordering::Relaxed),
+        }
+    }
 }
 
 /// Wrapper around a listener instance.
@@ -206,11 +365,32 @@ impl ListenLimits for Limits {
 /// [`IntoIncoming`] trait, which is the interesting property.
 ///
 /// This is created by the [`Fragment`] trait of [`WithListenLimits`].
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Clone, Debug)]
 pub struct LimitedListener<Inner> {
     inner: Inner,
     error_sleep: Duration,
     max_conn: usize,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    reaped: ReapCounter,
+    conn_bandwidth: Option<u64>,
+    listener_bandwidth: Option<u64>,
+}
+
+impl<Inner> LimitedListener<Inner> {
+    /// Access to the wrapped listener.
+    pub fn inner(&self) -> &Inner {
+        &self.inner
+    }
+
+    /// The counter of connections closed by this listener because of the [`idle_timeout`] and
+    /// [`max_lifetime`] limits.
+    ///
+    /// [`idle_timeout`]: ListenLimits::idle_timeout
+    /// [`max_lifetime`]: ListenLimits::max_lifetime
+    pub fn reaped(&self) -> ReapCounter {
+        self.reaped.clone()
+    }
 }
 
 impl<Inner: IntoIncoming> IntoIncoming for LimitedListener<Inner> {
@@ -224,6 +404,13 @@ impl<Inner: IntoIncoming> IntoIncoming for LimitedListener<Inner> {
                 max_conn: self.max_conn,
                 active_cnt: AtomicUsize::new(0),
                 wakeup: AtomicTask::new(),
+                idle_timeout: self.idle_timeout,
+                max_lifetime: self.max_lifetime,
+                reaped: self.reaped,
+                conn_bandwidth: self.conn_bandwidth,
+                listener_buckets: self
+                    .listener_bandwidth
+                    .map(|rate| Arc::new(Mutex::new(Buckets::new(rate)))),
             }),
         }
     }
@@ -234,6 +421,12 @@ struct ConnLimit {
     // 2 * count of connections + I'm blocked flag
     active_cnt: AtomicUsize,
     wakeup: AtomicTask,
+    idle_timeout: Option<Duration>,
+    max_lifetime: Option<Duration>,
+    reaped: ReapCounter,
+    conn_bandwidth: Option<u64>,
+    // Shared by all the connections of the listener
+    listener_buckets: Option<Arc<Mutex<Buckets>>>,
 }
 
 // # Encoding of active_cnt
@@ -303,6 +496,15 @@ pub struct LimitedIncoming<Inner> {
     limit: Arc<ConnLimit>,
 }
 
+impl<Inner> LimitedIncoming<Inner> {
+    /// The counter of connections closed by this listener.
+    ///
+    /// This is the same counter as [`LimitedListener::reaped`].
+    pub fn reaped(&self) -> ReapCounter {
+        self.limit.reaped.clone()
+    }
+}
+
 impl<Inner> Stream for LimitedIncoming<Inner>
 where
     Inner: Stream<Error = IoError>,
@@ -321,6 +523,8 @@ where
                         self.limit.active_cnt.fetch_add(2, Ordering::AcqRel);
                         LimitedConn {
                             inner: i,
+                            reaper: Reaper::new(&self.limit),
+                            shaper: Shaper::new(&self.limit),
                             limit: Arc::clone(&self.limit),
                         }
                     })
@@ -336,9 +540,280 @@ where
 /// are. You can mostly use it as the connection itself.
 pub struct LimitedConn<Inner> {
     inner: Inner,
+    reaper: Option<Reaper>,
+    shaper: Option<Shaper>,
     limit: Arc<ConnLimit>,
 }
 
+impl<Inner> LimitedConn<Inner> {
+    /// Checks if the connection should be closed before doing an operation.
+    fn check(&mut self) -> Result<(), IoError> {
+        match &mut self.reaper {
+            Some(reaper) => reaper.check(&self.limit.reaped, false),
+            None => Ok(()),
+        }
+    }
+
+    /// Updates the activity by the result of an operation.
+    fn track<T>(&mut self, result: Result<T, IoError>) -> Result<T, IoError> {
+        let reaper = match &mut self.reaper {
+            Some(reaper) => reaper,
+            None => return result,
+        };
+        match result {
+            Ok(result) => {
+                reaper.last_activity = clock::now();
+                Ok(result)
+            }
+            // Make sure we get woken up to close the connection, even if nothing happens on it
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                reaper.check(&self.limit.reaped, true)?;
+                result
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Closing of idle and too old connections.
+struct Reaper {
+    idle_timeout: Option<Duration>,
+    last_activity: Instant,
+    deadline: Option<Instant>,
+    timer: Option<Delay>,
+    reaped: bool,
+}
+
+impl Reaper {
+    fn new(limit: &ConnLimit) -> Option<Self> {
+        if limit.idle_timeout.is_none() && limit.max_lifetime.is_none() {
+            return None;
+        }
+        let now = clock::now();
+        Some(Reaper {
+            idle_timeout: limit.idle_timeout,
+            last_activity: now,
+            deadline: limit.max_lifetime.map(|lifetime| now + lifetime),
+            timer: None,
+            reaped: false,
+        })
+    }
+
+    fn reap(&mut self, counter: &AtomicUsize, reason: &str) -> Result<(), IoError> {
+        if !self.reaped {
+            self.reaped = true;
+            counter.fetch_add(1, Ordering::Relaxed);
+            debug!("Closing connection: {}", reason);
+        }
+        Err(IoError::new(ErrorKind::TimedOut, reason))
+    }
+
+    /// Checks the limits.
+    ///
+    /// If `wakeup` is set, a timer is set to wake the current task when the nearest limit
+    /// expires.
+    fn check(&mut self, counters: &ReapCounter, wakeup: bool) -> Result<(), IoError> {
+        let now = clock::now();
+        let idle_deadline = self.idle_timeout.map(|idle| self.last_activity + idle);
+        match (self.deadline, idle_deadline) {
+            (Some(deadline), _) if now >= deadline => {
+                return self.reap(
+                    &counters.0.lifetime,
+                    "Connection exceeded its maximum lifetime",
+                );
+            }
+            (_, Some(deadline)) if now >= deadline => {
+                return self.reap(&counters.0.idle, "Connection was idle for too long");
+            }
+            _ => (),
+        }
+        if wakeup {
+            let nearest = match (self.deadline, idle_deadline) {
+                (Some(a), Some(b)) => a.min(b),
+                (a, b) => a.or(b).expect("At least one limit is set"),
+            };
+            let timer = self.timer.get_or_insert_with(|| Delay::new(nearest));
+            if timer.deadline() != nearest {
+                timer.reset(nearest);
+            }
+            match timer.poll() {
+                // Already expired ‒ the next check will handle it
+                Ok(Async::Ready(())) => futures::task::current().notify(),
+                Ok(Async::NotReady) => (),
+                Err(e) => debug!("Can't watch connection limits: {}", e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A token bucket limiting the throughput in one direction.
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second.
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Bucket {
+            rate,
+            // Start full, allowing a burst of one second worth of data
+            tokens: rate,
+            last: clock::now(),
+        }
+    }
+
+    fn available(&mut self, now: Instant) -> f64 {
+        let elapsed = if now > self.last {
+            now - self.last
+        } else {
+            Duration::from_secs(0)
+        };
+        let elapsed = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens
+    }
+
+    /// How long until there are `wanted` tokens.
+    fn wait(&self, wanted: f64) -> Duration {
+        let missing = (wanted - self.tokens).max(0.0);
+        let nanos = missing * 1e9 / self.rate;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    read: Bucket,
+    write: Bucket,
+}
+
+impl Buckets {
+    fn new(rate: u64) -> Self {
+        Buckets {
+            read: Bucket::new(rate),
+            write: Bucket::new(rate),
+        }
+    }
+
+    fn get(&mut self, direction: Direction) -> &mut Bucket {
+        match direction {
+            Direction::Read => &mut self.read,
+            Direction::Write => &mut self.write,
+        }
+    }
+}
+
+/// Limiting the bandwidth of a connection.
+struct Shaper {
+    conn: Option<Buckets>,
+    listener: Option<Arc<Mutex<Buckets>>>,
+    timer: Option<Delay>,
+}
+
+impl Shaper {
+    fn new(limit: &ConnLimit) -> Option<Self> {
+        if limit.conn_bandwidth.is_none() && limit.listener_buckets.is_none() {
+            return None;
+        }
+        Some(Shaper {
+            conn: limit.conn_bandwidth.map(Buckets::new),
+            listener: limit.listener_buckets.clone(),
+            timer: None,
+        })
+    }
+
+    /// How many bytes can be transferred right now.
+    ///
+    /// If none, a timer to wake up the current task is set and `WouldBlock` is returned.
+    fn allowance(&mut self, direction: Direction, wanted: usize) -> Result<usize, IoError> {
+        if wanted == 0 {
+            return Ok(0);
+        }
+        let now = clock::now();
+        let mut listener = self
+            .listener
+            .as_ref()
+            .map(|buckets| buckets.lock().unwrap_or_else(PoisonError::into_inner));
+        let mut buckets = self
+            .conn
+            .iter_mut()
+            .chain(listener.as_mut().map(|l| &mut **l))
+            .map(|buckets| buckets.get(direction))
+            .collect::<Vec<_>>();
+        let available = buckets
+            .iter_mut()
+            .map(|bucket| bucket.available(now))
+            .fold(wanted as f64, f64::min);
+        if available >= 1.0 {
+            return Ok(available as usize);
+        }
+        // Don't wake up for every single byte, wait for a reasonable chunk
+        let wait = buckets
+            .iter()
+            .map(|bucket| bucket.wait((wanted as f64).min(bucket.rate / 20.0).max(1.0)))
+            .max()
+            .expect("At least one bucket is present");
+        drop(buckets);
+        drop(listener);
+        let deadline = now + wait;
+        let timer = self.timer.get_or_insert_with(|| Delay::new(deadline));
+        timer.reset(deadline);
+        match timer.poll() {
+            Ok(Async::Ready(())) => futures::task::current().notify(),
+            Ok(Async::NotReady) => (),
+            Err(e) => debug!("Can't wait for bandwidth: {}", e),
+        }
+        Err(IoError::new(
+            ErrorKind::WouldBlock,
+            "Bandwidth limit reached",
+        ))
+    }
+
+    /// Takes the transferred bytes from the buckets.
+    fn consume(&mut self, direction: Direction, amount: usize) {
+        let amount = amount as f64;
+        if let Some(conn) = &mut self.conn {
+            conn.get(direction).tokens -= amount;
+        }
+        if let Some(listener) = &self.listener {
+            let mut listener = listener.lock().unwrap_or_else(PoisonError::into_inner);
+            listener.get(direction).tokens -= amount;
+        }
+    }
+}
+
+impl<Inner> LimitedConn<Inner> {
+    fn allowance(&mut self, direction: Direction, wanted: usize) -> Result<usize, IoError> {
+        match &mut self.shaper {
+            Some(shaper) => shaper.allowance(direction, wanted),
+            None => Ok(wanted),
+        }
+    }
+
+    fn shaped(
+        &mut self,
+        direction: Direction,
+        result: Result<usize, IoError>,
+    ) -> Result<usize, IoError> {
+        if let (Some(shaper), Ok(amount)) = (&mut self.shaper, &result) {
+            shaper.consume(direction, *amount);
+        }
+        result
+    }
+}
+
 impl<Inner> Drop for LimitedConn<Inner> {
     fn drop(&mut self) {
         self.limit.dec()
@@ -347,13 +822,21 @@ impl<Inner> Drop for LimitedConn<Inner> {
 
 impl<I: Read> Read for LimitedConn<I> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
-        self.inner.read(buf)
+        self.check()?;
+        let allowed = self.allowance(Direction::Read, buf.len())?;
+        let result = self.inner.read(&mut buf[..allowed]);
+        let result = self.shaped(Direction::Read, result);
+        self.track(result)
     }
 }
 
 impl<I: Write> Write for LimitedConn<I> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
-        self.inner.write(buf)
+        self.check()?;
+        let allowed = self.allowance(Direction::Write, buf.len())?;
+        let result = self.inner.write(&buf[..allowed]);
+        let result = self.shaped(Direction::Write, result);
+        self.track(result)
     }
     fn flush(&mut self) -> Result<(), IoError> {
         self.inner.flush()
@@ -381,6 +864,25 @@ impl<I> DerefMut for LimitedConn<I> {
     }
 }
 
+impl<I: PeerAddr> PeerAddr for LimitedConn<I> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.inner.remote_addr()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl<I: TlsPeer> TlsPeer for LimitedConn<I> {
+    fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        self.inner.peer_certificates()
+    }
+    fn sni_hostname(&self) -> Option<String> {
+        self.inner.sni_hostname()
+    }
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.inner.alpn_protocol()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::net::{IpAddr, Ipv4Addr};
@@ -396,6 +898,7 @@ mod tests {
     use tokio::timer::Delay;
 
     use super::*;
+    use crate::net::acl::IpAcl;
     use crate::net::{Listen, TcpListen};
 
     #[test]
@@ -410,12 +913,17 @@ mod tests {
                             host: IpAddr::V4(Ipv4Addr::LOCALHOST),
                             ..Listen::default()
                         },
+                        acl: IpAcl::default(),
                         tcp_config: Empty {},
                         extra_cfg: Empty {},
                     },
                     limits: Limits {
-                        error_sleep: Duration::from_millis(100),
+                        error_sleep: CfgDuration(Duration::from_millis(100)),
                         max_conn: Some(2),
+                        idle_timeout: None,
+                        max_lifetime: None,
+                        conn_bandwidth: None,
+                        listener_bandwidth: None,
                     },
                 };
                 let mut seed = incoming_cfg.make_seed("test_listener").unwrap();
@@ -458,4 +966,48 @@ mod tests {
             })
             .unwrap();
     }
+
+    #[test]
+    fn reaper() {
+        let limit = ConnLimit {
+            max_conn: 1,
+            active_cnt: AtomicUsize::new(0),
+            wakeup: AtomicTask::new(),
+            idle_timeout: Some(Duration::from_millis(0)),
+            max_lifetime: Some(Duration::from_secs(3600)),
+            reaped: ReapCounter::default(),
+            conn_bandwidth: None,
+            listener_buckets: None,
+        };
+        let mut reaper = Reaper::new(&limit).unwrap();
+        let err = reaper.check(&limit.reaped, false).unwrap_err();
+        assert_eq!(ErrorKind::TimedOut, err.kind());
+        assert!(reaper.check(&limit.reaped, false).is_err());
+        // Counted only once
+        let expected = Reaped {
+            idle: 1,
+            lifetime: 0,
+        };
+        assert_eq!(expected, limit.reaped.get());
+        assert_eq!(Reaped::default(), ReapCounter::default().get());
+
+        let unlimited = ConnLimit {
+            idle_timeout: None,
+            max_lifetime: None,
+            ..limit
+        };
+        assert!(Reaper::new(&unlimited).is_none());
+    }
+
+    #[test]
+    fn bucket() {
+        let mut bucket = Bucket::new(1000);
+        let start = bucket.last;
+        assert_eq!(1000.0, bucket.available(start));
+        bucket.tokens -= 1000.0;
+        assert_eq!(Duration::from_millis(100), bucket.wait(100.0));
+        // Refills with time, but only up to the burst size
+        assert_eq!(500.0, bucket.available(start + Duration::from_millis(500)));
+        assert_eq!(1000.0, bucket.available(start + Duration::from_secs(10)));
+    }
 }
@@ -1,9 +1,10 @@
 //! Autoconfiguration of network primitives of [tokio]
 //!
 //! This is where the „meat“ of this crate lives. It contains various configuration [`Fragment`]s
-//! and utilities to manage network primitives. However, currently only listening (bound) sockets
-//! are present. Keeping a set of connecting socket (eg. a connection pool or something like that)
-//! is vaguely planned, though it is not clear how it'll look exactly. Input is welcome.
+//! and utilities to manage network primitives. Most of them are listening (bound) sockets, outgoing
+//! TCP connections are described by the [`connect`] module. Keeping a set of connecting sockets
+//! (eg. a connection pool or something like that) is vaguely planned, though it is not clear how
+//! it'll look exactly. Input is welcome.
 //!
 //! Note that many common types are reexported to the root of the crate.
 //!
@@ -12,7 +13,9 @@
 use std::cmp;
 use std::fmt::Debug;
 use std::io::Error as IoError;
-use std::net::{IpAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+use std::net::{IpAddr, SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+#[cfg(feature = "upgrade")]
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::time::Duration;
 
 use err_context::prelude::*;
@@ -25,6 +28,9 @@ use serde::de::{Deserializer, Error as DeError, Unexpected};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 use serde_humantime;
+#[cfg(target_os = "linux")]
+use socket2::{Domain, Socket, Type};
+use spirit::cfg::{ByteSize, ListenAddr};
 use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
 use spirit::fragment::{Fragment, Stackable};
 use spirit::AnyError;
@@ -35,7 +41,14 @@ use tokio::net::tcp::Incoming;
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::reactor::Handle;
 
+use self::acl::{AclListener, IpAcl};
+
+pub mod acl;
+pub mod connect;
+pub mod intake;
 pub mod limits;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(unix)]
 pub mod unix;
 
@@ -67,12 +80,58 @@ impl IntoIncoming for TcpListener {
     }
 }
 
-fn default_host() -> IpAddr {
-    "::".parse().unwrap()
+/// Describes where a listening [`Fragment`] binds to.
+///
+/// This is used to label things that belong to one specific socket (like metrics) in a human
+/// readable way. It is implemented for the fragments of this crate and wrappers around them.
+pub trait Endpoint {
+    /// A human readable description of the address, like `[::]:1234` or a filesystem path.
+    fn endpoint(&self) -> String;
+}
+
+/// Access to the address of the remote side of an accepted connection.
+///
+/// This is implemented for the connections produced by the listeners of this crate, including the
+/// wrappers around them (limits, TLS, ...), so code generic over the transport can find out who it
+/// talks to.
+pub trait PeerAddr {
+    /// The address of the peer.
+    ///
+    /// This is `None` if the connection has no such address (unix domain sockets) or if it can't
+    /// be found out.
+    fn remote_addr(&self) -> Option<SocketAddr>;
+}
+
+impl PeerAddr for TcpStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr().ok()
+    }
+}
+
+/// Creates a socket, or takes over the one handed over by the previous instance of the application.
+///
+/// See [`spirit-upgrade`](https://docs.rs/spirit-upgrade).
+#[cfg(feature = "upgrade")]
+pub(crate) fn handover<S, F>(key: &str, create: F) -> Result<S, AnyError>
+where
+    S: AsRawFd + FromRawFd,
+    F: FnOnce() -> Result<S, AnyError>,
+{
+    spirit_upgrade::inherit_or_create(key, create)
+}
+
+#[cfg(not(feature = "upgrade"))]
+pub(crate) fn handover<S, F>(_key: &str, create: F) -> Result<S, AnyError>
+where
+    F: FnOnce() -> Result<S, AnyError>,
+{
+    create()
 }
 
-fn default_backlog() -> u32 {
-    128 // Number taken from rust standard library implementation
+spirit::cfg_defaults! {
+    fn default_host() -> IpAddr = "::".parse().unwrap();
+    // Number taken from rust standard library implementation
+    fn default_backlog() -> u32 = 128;
 }
 
 /// A description of listening interface and port.
@@ -86,8 +145,11 @@ fn default_backlog() -> u32 {
 ///
 /// # Configuration options
 ///
-/// * `port` (mandatory)
+/// * `port` (mandatory unless `address` is set)
 /// * `host` (optional, if not present, `::` is used)
+/// * `address` (optional, the whole address in one string, like `[::1]:8080`, `8080` or `eth0:80`
+///   ‒ see [`ListenAddr`]; if present, it takes precedence over `host` and `port`). Binding to a
+///   network interface by its name is supported only on Linux.
 /// * `reuse-addr` (optional, boolean, if not present the OS default is used)
 /// * `reuse-port` (optional, boolean, if not present the OS default is used, does something only
 ///   on unix).
@@ -101,7 +163,10 @@ fn default_backlog() -> u32 {
 #[serde(rename_all = "kebab-case")]
 pub struct Listen {
     /// The port to bind to.
-    port: u16,
+    ///
+    /// Mandatory, unless the address is set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    port: Option<u16>,
 
     /// The interface to bind to.
     ///
@@ -109,6 +174,13 @@ pub struct Listen {
     #[serde(default = "default_host")]
     host: IpAddr,
 
+    /// The whole address to bind to, in place of the host and port.
+    ///
+    /// Can be an IP address with port (`[::1]:8080`), just a port (`8080`) or a network interface
+    /// name with a port (`eth0:80`, Linux only).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<ListenAddr>,
+
     /// The SO_REUSEADDR socket option.
     ///
     /// Usually, the OS reserves the host-port pair for a short time after it has been released, so
@@ -166,8 +238,9 @@ pub struct Listen {
 impl Default for Listen {
     fn default() -> Self {
         Listen {
-            port: 0,
+            port: Some(0),
             host: default_host(),
+            address: None,
             reuse_addr: None,
             reuse_port: None,
             only_v6: None,
@@ -181,8 +254,97 @@ impl Listen {
     /// Creates a TCP socket described by the loaded configuration.
     ///
     /// This is the synchronous socket from standard library. See [`TcpListener::from_std`].
+    ///
+    /// With the `upgrade` feature, a socket handed over by the previous instance of the
+    /// application is reused if there's one.
     pub fn create_tcp(&self) -> Result<StdTcpListener, AnyError> {
-        let builder = match self.host {
+        let listener = handover(&format!("tcp:{}", self.endpoint()), || self.bind_tcp())?;
+        spirit::banner::listening(format_args!("tcp {}", listener.local_addr()?));
+        Ok(listener)
+    }
+
+    /// The configured port.
+    ///
+    /// `None` if neither the port nor the address is set, or if the address is a unix domain
+    /// socket.
+    pub fn port(&self) -> Option<u16> {
+        match &self.address {
+            Some(address) => address.port(),
+            None => self.port,
+        }
+    }
+
+    /// Decides where to bind ‒ the host, port and possibly the network interface.
+    fn bind_addr(&self) -> Result<(IpAddr, u16, Option<&str>), AnyError> {
+        match &self.address {
+            Some(ListenAddr::Inet { host, port }) => Ok((*host, *port, None)),
+            Some(ListenAddr::Interface { name, port }) if cfg!(target_os = "linux") => {
+                Ok((self.host, *port, Some(name)))
+            }
+            Some(ListenAddr::Interface { name, .. }) => Err(format!(
+                "Can't bind to interface {}, binding to interfaces is supported only on Linux",
+                name
+            )
+            .into()),
+            Some(ListenAddr::Unix(path)) => Err(format!(
+                "Can't listen on unix domain socket {} here, use the unix fragments",
+                path.display()
+            )
+            .into()),
+            None => {
+                let port = self
+                    .port
+                    .ok_or("Either the port or the address must be set")?;
+                Ok((self.host, port, None))
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn bind_device(
+        &self,
+        device: &str,
+        host: IpAddr,
+        port: u16,
+        ty: Type,
+    ) -> Result<Socket, AnyError> {
+        let domain = match host {
+            IpAddr::V4(_) => Domain::ipv4(),
+            IpAddr::V6(_) => Domain::ipv6(),
+        };
+        let socket = Socket::new(domain, ty, None)?;
+        socket
+            .bind_device(Some(device.as_bytes()))
+            .with_context(|_| format!("Failed to bind to interface {}", device))?;
+        if let (Some(only_v6), IpAddr::V6(_)) = (self.only_v6, host) {
+            socket.set_only_v6(only_v6)?;
+        }
+        if let Some(reuse_addr) = self.reuse_addr {
+            socket.set_reuse_address(reuse_addr)?;
+        }
+        if let Some(reuse_port) = self.reuse_port {
+            socket.set_reuse_port(reuse_port)?;
+        }
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        socket.bind(&SocketAddr::new(host, port).into())?;
+        Ok(socket)
+    }
+
+    fn bind_tcp(&self) -> Result<StdTcpListener, AnyError> {
+        let (host, port, device) = self.bind_addr()?;
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(device) = device {
+                let socket = self.bind_device(device, host, port, Type::stream())?;
+                socket.listen(cmp::min(self.backlog, i32::max_value() as u32) as i32)?;
+                return Ok(socket.into_tcp_listener());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = device; // Refused by bind_addr already
+        let builder = match host {
             IpAddr::V4(_) => TcpBuilder::new_v4(),
             IpAddr::V6(_) => TcpBuilder::new_v6(),
         }?;
@@ -202,15 +364,34 @@ impl Listen {
         if let Some(ttl) = self.ttl {
             builder.ttl(ttl)?;
         }
-        builder.bind((self.host, self.port))?;
+        builder.bind((host, port))?;
         Ok(builder.listen(cmp::min(self.backlog, i32::max_value() as u32) as i32)?)
     }
 
     /// Creates a UDP socket described by the loaded configuration.
     ///
     /// This is the synchronous socket from standard library. See [`UdpSocket::from_std`].
+    ///
+    /// With the `upgrade` feature, a socket handed over by the previous instance of the
+    /// application is reused if there's one.
     pub fn create_udp(&self) -> Result<StdUdpSocket, AnyError> {
-        let builder = match self.host {
+        let socket = handover(&format!("udp:{}", self.endpoint()), || self.bind_udp())?;
+        spirit::banner::listening(format_args!("udp {}", socket.local_addr()?));
+        Ok(socket)
+    }
+
+    fn bind_udp(&self) -> Result<StdUdpSocket, AnyError> {
+        let (host, port, device) = self.bind_addr()?;
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(device) = device {
+                let socket = self.bind_device(device, host, port, Type::dgram())?;
+                return Ok(socket.into_udp_socket());
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        let _ = device; // Refused by bind_addr already
+        let builder = match host {
             IpAddr::V4(_) => UdpBuilder::new_v4(),
             IpAddr::V6(_) => UdpBuilder::new_v6(),
         }?;
@@ -230,7 +411,16 @@ impl Listen {
         if let Some(ttl) = self.ttl {
             builder.ttl(ttl)?;
         }
-        Ok(builder.bind((self.host, self.port))?)
+        Ok(builder.bind((host, port))?)
+    }
+}
+
+impl Endpoint for Listen {
+    fn endpoint(&self) -> String {
+        match &self.address {
+            Some(address) => address.to_string(),
+            None => SocketAddr::new(self.host, self.port.unwrap_or(0)).to_string(),
+        }
     }
 }
 
@@ -341,7 +531,7 @@ impl Serialize for MaybeDuration {
 ///
 /// * `tcp-nodelay` (optional, boolean, if not set uses OS default)
 /// * `tcp-recv-buf-size` (optional, size of the OS buffer on the receive end of the socket,
-///   if not set uses the OS default)
+///   either in bytes or with units like `256KiB`, if not set uses the OS default)
 /// * `tcp-send-buf-size` (similar, but for the send end)
 /// * `tcp-keepalive` (optional, see [`MaybeDuration`])
 /// * `accepted-ttl` (optional, uses OS default if not set)
@@ -361,17 +551,17 @@ pub struct TcpConfig {
     #[serde(rename = "tcp-nodelay", skip_serializing_if = "Option::is_none")]
     nodelay: Option<bool>,
 
-    /// The receive buffer size of the connection, in bytes.
+    /// The receive buffer size of the connection (in bytes or with units, like `256KiB`).
     ///
     /// Left to OS default if not set.
     #[serde(rename = "tcp-recv-buf-size", skip_serializing_if = "Option::is_none")]
-    recv_buf_size: Option<usize>,
+    recv_buf_size: Option<ByteSize>,
 
-    /// The send buffer size of the connection, in bytes.
+    /// The send buffer size of the connection (in bytes or with units, like `256KiB`).
     ///
     /// Left to the OS default if not set.
     #[serde(rename = "tcp-send-buf-size", skip_serializing_if = "Option::is_none")]
-    send_buf_size: Option<usize>,
+    send_buf_size: Option<ByteSize>,
 
     /// The TCP keepalive time.
     ///
@@ -400,10 +590,10 @@ impl StreamConfig<TcpStream> for TcpConfig {
             stream.set_nodelay(nodelay)?;
         }
         if let Some(recv_buf_size) = self.recv_buf_size {
-            stream.set_recv_buffer_size(recv_buf_size)?;
+            stream.set_recv_buffer_size(recv_buf_size.as_usize())?;
         }
         if let Some(send_buf_size) = self.send_buf_size {
-            stream.set_send_buffer_size(send_buf_size)?;
+            stream.set_send_buffer_size(send_buf_size.as_usize())?;
         }
         match self.keepalive {
             MaybeDuration::Unset => (),
@@ -433,6 +623,11 @@ impl<Listener, Config> ConfiguredStreamListener<Listener, Config> {
         Self { listener, config }
     }
 
+    /// Access to the wrapped listener.
+    pub fn listener(&self) -> &Listener {
+        &self.listener
+    }
+
     /// Disassembles it into the components.
     pub fn into_parts(self) -> (Listener, Config) {
         (self.listener, self.config)
@@ -487,8 +682,9 @@ where
 
 /// A configuration fragment of a TCP listening socket.
 ///
-/// The [`Fragment`] creates a [`TcpListener`] (wrapped in [`ConfiguredIncoming`]). It can be
-/// handled directly, or through [`Pipeline`]s and [`handlers`].
+/// The [`Fragment`] creates a [`TcpListener`] (wrapped in [`AclListener`] and
+/// [`ConfiguredIncoming`]). It can be handled directly, or through [`Pipeline`]s and
+/// [`handlers`].
 ///
 /// Note that this stream sometimes returns errors „in the middle“, but most stream consumers
 /// terminate on the first error. You might be interested in the [`WithListenLimits`] wrapper to
@@ -505,9 +701,14 @@ where
 /// # Fields
 ///
 /// The configuration fields are pooled from all three type parameters above and from the
-/// [`Listen`] configuration fragment.
+/// [`Listen`] and [`IpAcl`] configuration fragments. The latter allows restricting who may connect
+/// by the `allow` and `deny` lists; these can be changed without re-creating the socket.
+///
+/// The number of connections rejected by the lists is counted by each created listener and can be
+/// read through [`AclListener::rejected`] (eg. in [`Pipeline::map`]).
 ///
 /// [`Pipeline`]: spirit::fragment::Pipeline.
+/// [`Pipeline::map`]: spirit::fragment::pipeline::Pipeline::map
 /// [`handlers`]: crate::handlers
 /// [`WithListenLimits`]: crate::net::limits::WithListenLimits
 #[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
@@ -516,6 +717,8 @@ pub struct TcpListen<ExtraCfg = Empty, TcpStreamConfigure = TcpConfig> {
     #[serde(flatten)]
     listen: Listen,
     #[serde(flatten)]
+    acl: IpAcl,
+    #[serde(flatten)]
     tcp_config: TcpStreamConfigure,
 
     /// Arbitrary application specific configuration that doesn't influence the sockets created.
@@ -545,24 +748,36 @@ where
     type Driver = CacheSimilar<Self>;
     type Installer = ();
     type Seed = StdTcpListener;
-    type Resource = ConfiguredStreamListener<TcpListener, TcpConfig>;
+    type Resource = ConfiguredStreamListener<AclListener, TcpConfig>;
     fn make_seed(&self, name: &str) -> Result<StdTcpListener, AnyError> {
         self.listen
             .create_tcp()
             .with_context(|_| format!("Failed to create STD socket {}/{:?}", name, self))
             .map_err(AnyError::from)
     }
-    fn make_resource(&self, seed: &mut Self::Seed, name: &str) -> Result<Self::Resource, AnyError> {
+    fn make_resource(
+        &self,
+        seed: &mut Self::Seed,
+        name: &'static str,
+    ) -> Result<Self::Resource, AnyError> {
         let config = self.tcp_config.clone();
+        let acl = self.acl.clone();
         seed.try_clone() // Another copy of the listener
             // std → tokio socket conversion
             .and_then(|listener| TcpListener::from_std(listener, &Handle::default()))
             .with_context(|_| format!("Failed to make socket {}/{:?} asynchronous", name, self))
             .map_err(AnyError::from)
+            .map(|listener| AclListener::new(listener, acl, name))
             .map(|listener| ConfiguredStreamListener::new(listener, config))
     }
 }
 
+impl<ExtraCfg, TcpConfig> Endpoint for TcpListen<ExtraCfg, TcpConfig> {
+    fn endpoint(&self) -> String {
+        self.listen.endpoint()
+    }
+}
+
 /// A [`TcpListen`] with all parameters set to [`Empty`].
 ///
 /// This doesn't configure much more than the minimum actually needed.
@@ -639,6 +854,12 @@ where
     }
 }
 
+impl<ExtraCfg> Endpoint for UdpListen<ExtraCfg> {
+    fn endpoint(&self) -> String {
+        self.listen.endpoint()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     extern crate serde_json;
@@ -679,4 +900,18 @@ mod tests {
     fn maybe_duration_default() {
         assert_eq!(MaybeDuration::Unset, MaybeDuration::load(r#"{}"#).unwrap());
     }
+
+    #[test]
+    fn endpoint() {
+        let listen = Listen {
+            port: Some(1234),
+            ..Listen::default()
+        };
+        assert_eq!("[::]:1234", listen.endpoint());
+        let listen = Listen {
+            address: Some("127.0.0.1:80".parse().unwrap()),
+            ..Listen::default()
+        };
+        assert_eq!("127.0.0.1:80", listen.endpoint());
+    }
 }
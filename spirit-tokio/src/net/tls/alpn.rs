@@ -0,0 +1,316 @@
+//! Dispatching TLS connections by the negotiated ALPN protocol.
+//!
+//! A single TLS port can serve several protocols ‒ the client tells which ones it wants to speak
+//! during the handshake ([ALPN]) and the server picks one of them. The `alpn` option of the
+//! [`TlsListen`] lists the protocols the server offers (in the order of its preference) and names
+//! the handler of each. The [`AlpnRouter`] then passes each connection to the handler registered
+//! under that name, after the handshake. This way, for example, gRPC (over `h2`) and a custom TCP
+//! protocol can share the port 443.
+//!
+//! # Configuration options
+//!
+//! * `alpn`: Array of tables with these fields:
+//!   - `protocol`: The ALPN protocol ID (like `h2`, `http/1.1` or `custom/1`).
+//!   - `handler`: Name of the handler of the protocol. Defaults to the protocol ID.
+//! * `alpn-fallback`: Name of the handler of connections without a negotiated protocol (clients
+//!   not using ALPN). These connections are closed if not set.
+//!
+//! The same handler may serve multiple protocols. The routes can be changed by a configuration
+//! reload, without closing the listening socket.
+//!
+//! A hyper server can be one of the handlers, by serving the single connection with
+//! [`Http::serve_connection`][hyper-serve].
+//!
+//! # Examples
+//!
+//! ```rust,no_run
+//! use futures::Future;
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_tokio::{HandleListener, TcpListen};
+//! use spirit_tokio::net::tls::TlsListen;
+//! use spirit_tokio::net::tls::alpn::AlpnRouter;
+//! use tokio::io::{self, AsyncRead};
+//! use tokio::net::TcpStream;
+//! use tokio_rustls::server::TlsStream;
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [listen]
+//! port = 1234
+//! tls-cert = "/etc/my-app/server.pem"
+//! tls-key = "/etc/my-app/server.key"
+//! alpn-fallback = "echo"
+//!
+//! [[listen.alpn]]
+//! protocol = "echo/1"
+//! handler = "echo"
+//!
+//! [[listen.alpn]]
+//! protocol = "hello/1"
+//! handler = "hello"
+//! "#;
+//!
+//! type Listen = TlsListen<TcpListen>;
+//! type Conn = TlsStream<TcpStream>;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Config {
+//!     listen: Listen,
+//! }
+//!
+//! impl Config {
+//!     fn listen(&self) -> Listen {
+//!         self.listen.clone()
+//!     }
+//! }
+//!
+//! fn echo(conn: Conn) -> impl Future<Item = (), Error = io::Error> {
+//!     let (read, write) = conn.split();
+//!     io::copy(read, write).map(|_| ())
+//! }
+//!
+//! fn hello(conn: Conn) -> impl Future<Item = (), Error = io::Error> {
+//!     io::write_all(conn, "Hello\n").map(|_| ())
+//! }
+//!
+//! fn main() {
+//!     let router = AlpnRouter::new()
+//!         .handler("echo", echo)
+//!         .handler("hello", hello);
+//!     Spirit::<Empty, Config>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("listen")
+//!                 .extract_cfg(Config::listen)
+//!                 .transform(HandleListener(move |conn, cfg: &Listen| {
+//!                     router.route(conn, cfg.alpn())
+//!                 }))
+//!         )
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+//!
+//! [ALPN]: https://en.wikipedia.org/wiki/Application-Layer_Protocol_Negotiation
+//! [`TlsListen`]: super::TlsListen
+//! [hyper-serve]: https://docs.rs/hyper/0.12/hyper/server/conn/struct.Http.html
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::future::{self, Future, IntoFuture};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+
+use super::TlsPeer;
+
+/// One protocol offered by the server.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct AlpnRoute {
+    /// The ALPN protocol ID.
+    pub protocol: String,
+
+    /// Name of the handler of the protocol.
+    ///
+    /// Defaults to the protocol ID.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handler: Option<String>,
+}
+
+impl AlpnRoute {
+    /// The name of the handler serving this protocol.
+    pub fn handler(&self) -> &str {
+        self.handler.as_deref().unwrap_or(&self.protocol)
+    }
+}
+
+/// The protocols offered by a [`TlsListen`][super::TlsListen] and their handlers.
+///
+/// This is part of the [`TlsListen`][super::TlsListen] configuration, see the
+/// [module documentation][crate::net::tls::alpn] for the options.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct AlpnRoutes {
+    /// The protocols offered by the server, in the order of preference.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alpn: Vec<AlpnRoute>,
+
+    /// Name of the handler of connections without a negotiated protocol.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alpn_fallback: Option<String>,
+}
+
+impl AlpnRoutes {
+    /// The protocol IDs to offer in the handshake.
+    pub fn protocols(&self) -> Vec<Vec<u8>> {
+        self.alpn
+            .iter()
+            .map(|route| route.protocol.as_bytes().to_vec())
+            .collect()
+    }
+
+    /// Looks up the handler of a negotiated protocol (or of no protocol).
+    pub fn handler(&self, protocol: Option<&[u8]>) -> Option<&str> {
+        match protocol {
+            Some(protocol) => self
+                .alpn
+                .iter()
+                .find(|route| route.protocol.as_bytes() == protocol)
+                .map(AlpnRoute::handler),
+            None => self.alpn_fallback.as_deref(),
+        }
+    }
+}
+
+type Handled = Box<dyn Future<Item = (), Error = AnyError> + Send>;
+type Handler<C> = Arc<dyn Fn(C) -> Handled + Send + Sync>;
+
+fn unrouted(msg: String) -> Handled {
+    Box::new(future::err(msg.into()))
+}
+
+/// Named handlers of connections, chosen by the negotiated protocol.
+///
+/// The handlers are registered by [`handler`][AlpnRouter::handler] and the connections are passed
+/// to them by [`route`][AlpnRouter::route], usually from within a
+/// [`HandleListener`][crate::HandleListener]. Cloning is cheap, the clones share the handlers.
+///
+/// See the [module documentation][crate::net::tls::alpn].
+pub struct AlpnRouter<C> {
+    handlers: HashMap<String, Handler<C>>,
+}
+
+impl<C> AlpnRouter<C>
+where
+    C: TlsPeer + 'static,
+{
+    /// Creates a router without any handlers.
+    pub fn new() -> Self {
+        AlpnRouter {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Registers a handler under the given name.
+    ///
+    /// The handler is called with each connection routed to it and the returned future is run to
+    /// completion. Registering another handler with the same name replaces the previous one.
+    pub fn handler<N, F, R>(mut self, name: N, handler: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn(C) -> R + Send + Sync + 'static,
+        R: IntoFuture<Item = ()>,
+        R::Future: Send + 'static,
+        R::Error: Into<AnyError>,
+    {
+        let handler =
+            move |conn| -> Handled { Box::new(handler(conn).into_future().map_err(Into::into)) };
+        self.handlers.insert(name.into(), Arc::new(handler));
+        self
+    }
+
+    /// Passes the connection to the handler of its negotiated protocol.
+    ///
+    /// The returned future fails (and the connection is closed) if there's no route for the
+    /// protocol or no handler of the name the route points to.
+    pub fn route(&self, conn: C, routes: &AlpnRoutes) -> Handled {
+        let protocol = conn.alpn_protocol();
+        let describe = || match &protocol {
+            Some(protocol) => format!("ALPN protocol {}", String::from_utf8_lossy(protocol)),
+            None => "connections without ALPN".to_owned(),
+        };
+        let name = match routes.handler(protocol.as_deref()) {
+            Some(name) => name,
+            None => return unrouted(format!("No route for {}", describe())),
+        };
+        match self.handlers.get(name) {
+            Some(handler) => {
+                debug!("Routing connection with {} to {}", describe(), name);
+                handler(conn)
+            }
+            None => unrouted(format!("No handler {} for {}", name, describe())),
+        }
+    }
+}
+
+impl<C> Clone for AlpnRouter<C> {
+    fn clone(&self) -> Self {
+        AlpnRouter {
+            handlers: self.handlers.clone(),
+        }
+    }
+}
+
+impl<C> Default for AlpnRouter<C>
+where
+    C: TlsPeer + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustls::Certificate;
+
+    use super::*;
+
+    struct Conn(Option<&'static str>);
+
+    impl TlsPeer for Conn {
+        fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+            None
+        }
+        fn alpn_protocol(&self) -> Option<Vec<u8>> {
+            self.0.map(|p| p.as_bytes().to_vec())
+        }
+    }
+
+    #[test]
+    fn routing() {
+        let routes: AlpnRoutes = serde_json::from_str(
+            r#"{
+                "alpn": [{"protocol": "h2", "handler": "web"}, {"protocol": "custom/1"}],
+                "alpn-fallback": "web"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            vec![b"h2".to_vec(), b"custom/1".to_vec()],
+            routes.protocols()
+        );
+        assert_eq!(Some("web"), routes.handler(Some(b"h2")));
+        assert_eq!(Some("custom/1"), routes.handler(Some(b"custom/1")));
+        assert_eq!(Some("web"), routes.handler(None));
+        assert_eq!(None, routes.handler(Some(b"http/1.1")));
+
+        let router = AlpnRouter::new()
+            .handler("web", |conn: Conn| {
+                assert_ne!(Some("custom/1"), conn.0);
+                Ok::<_, AnyError>(())
+            })
+            .handler("custom/1", |conn: Conn| {
+                assert_eq!(Some("custom/1"), conn.0);
+                Ok::<_, AnyError>(())
+            });
+        assert!(router.route(Conn(Some("h2")), &routes).wait().is_ok());
+        assert!(router.route(Conn(None), &routes).wait().is_ok());
+        assert!(router.route(Conn(Some("custom/1")), &routes).wait().is_ok());
+        assert!(router
+            .route(Conn(Some("http/1.1")), &routes)
+            .wait()
+            .is_err());
+        let routes = AlpnRoutes {
+            alpn_fallback: Some("missing".to_owned()),
+            ..routes
+        };
+        assert!(router.route(Conn(None), &routes).wait().is_err());
+    }
+}
@@ -0,0 +1,737 @@
+//! TLS on top of listening sockets.
+//!
+//! The [`TlsListen`] is a wrapper around another listening [`Fragment`] (like [`TcpListen`]). It
+//! adds the server certificate configuration and performs the TLS handshake on each accepted
+//! connection before handing it to the application.
+//!
+//! It also supports authentication of clients by certificates (mutual TLS). The verified
+//! certificate of the client is available through the [`TlsPeer`] trait on the accepted
+//! connection, so the application can do per-client authorization.
+//!
+//! This is available under the `tls` feature flag.
+//!
+//! # Configuration options
+//!
+//! In addition to the options of the wrapped listener, the TLS options shared with other crates
+//! are accepted (see [`spirit-tls`]):
+//!
+//! * `tls-cert`: Path to the certificate chain of the server, in PEM format (mandatory).
+//! * `tls-key`: Path to the private key of the server, PKCS8 or RSA in PEM format (mandatory).
+//! * `tls-ca`: Path to a PEM bundle of certificate authorities for client certificates. If set,
+//!   clients are asked for a certificate. Can also be spelled `client-ca`.
+//! * `tls-min-version`, `tls-max-version`, `tls-cipher-suites`: Limits of the protocol versions
+//!   and cipher suites.
+//! * `tls-alpn`: Protocols offered through ALPN, when not routing them by the `alpn` option.
+//! * `tls-ca-refresh`: Re-read the `tls-ca` this often, without replacing the listener (see the
+//!   [`trust`][spirit_tls::trust] module of `spirit-tls`).
+//!
+//! And these specific to listeners:
+//!
+//! * `require-client-cert`: If `tls-ca` is set, refuse clients without a certificate. Defaults
+//!   to `true`.
+//! * `client-cert-fingerprints`: If not empty, only clients whose certificate has one of these
+//!   SHA-256 fingerprints (hex encoded, optionally with `:` separators) are accepted.
+//! * `revoked-cert-fingerprints`: Clients with certificates of these SHA-256 fingerprints are
+//!   refused. This is a replacement of CRLs, which are not supported by the underlying TLS
+//!   library.
+//! * `session-cache`: Number of sessions remembered for resumption by a session ID, 0 disables
+//!   the cache. Defaults to 256.
+//! * `session-tickets`: Issue session tickets, for resumption without keeping any state on the
+//!   server. Defaults to `false`.
+//! * `ticket-rotation`: How often the key encrypting the session tickets is replaced. Tickets
+//!   encrypted by the previous key are still accepted, so a ticket stays valid for at least this
+//!   long. Defaults to `6h`.
+//! * `ticket-keys`: Path to a file with the ticket keys, to share them between servers behind a
+//!   load balancer (a client can resume its session on any of them). Each line is a 32-byte key,
+//!   hex encoded; the first one encrypts new tickets, all of them are accepted. The file is
+//!   re-read every `ticket-rotation`, so keys can be rotated by replacing the file. Setting it
+//!   turns the session tickets on.
+//! * `ocsp-stapling`: Staple the OCSP response of the certificate to the handshakes. The response
+//!   is fetched when the listener is created and refreshed in the background. This needs the
+//!   certificate of the issuer to be in the `tls-cert` chain. Defaults to `false`.
+//! * `ocsp-responder`: URL of the OCSP responder (only `http` is supported), overriding the one in
+//!   the certificate.
+//! * `ocsp-refresh`: How often the OCSP response is refreshed. Defaults to `1h`.
+//! * `ocsp-must-staple`: Refuse to create the listener if the OCSP response can't be fetched. This
+//!   is implied by a certificate with the must-staple extension. Defaults to `false`.
+//! * `alpn`, `alpn-fallback`: The protocols offered through ALPN and the handlers serving them.
+//!   See the [`alpn`] module.
+//! * `log-target`, `log-level`: Logging of the handshakes and refused clients of this listener
+//!   (see [`LogSettings`]).
+//!
+//! The ticket keys are rotated by the running listener, without creating a new socket or TLS
+//! configuration.
+//!
+//! [`LogSettings`]: spirit::fragment::logging::LogSettings
+//! [`spirit-tls`]: spirit_tls
+//! [`TcpListen`]: crate::TcpListen
+
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::io::Error as IoError;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use err_context::prelude::*;
+use futures::stream::FuturesUnordered;
+use futures::{Async, Poll, Stream};
+use log::Level;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    Certificate, NoServerSessionStorage, ProducesTickets, ServerConfig, ServerSessionMemoryCache,
+    Session,
+};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::cfg::Duration as CfgDuration;
+use spirit::extension::Extensible;
+use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
+use spirit::fragment::logging::{FragmentLog, LogSettings};
+use spirit::fragment::{Fragment, Stackable};
+use spirit::AnyError;
+use spirit_tls::TlsConfig;
+use structopt::StructOpt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::{Accept, TlsAcceptor};
+
+use self::alpn::AlpnRoutes;
+use super::{Endpoint, IntoIncoming, PeerAddr};
+
+pub mod alpn;
+mod ocsp;
+
+spirit::cfg_defaults! {
+    fn default_on() -> bool = true;
+    fn default_session_cache() -> usize = 256;
+    fn default_ticket_rotation() -> CfgDuration = CfgDuration(Duration::from_secs(6 * 3600));
+    fn default_ocsp_refresh() -> CfgDuration = CfgDuration(Duration::from_secs(3600));
+}
+
+const TICKET_KEY_LEN: usize = 32;
+
+/// Access to the TLS peer of a connection.
+///
+/// Implemented on the connections accepted through [`TlsListen`] and on the connection wrappers
+/// (like [`LimitedConn`][crate::net::limits::LimitedConn]) around them.
+pub trait TlsPeer {
+    /// The certificate chain the client presented, the client's own certificate first.
+    ///
+    /// This is `None` if the client didn't present any certificate.
+    fn peer_certificates(&self) -> Option<Vec<Certificate>>;
+
+    /// The verified certificate of the client.
+    fn peer_certificate(&self) -> Option<Certificate> {
+        self.peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+    }
+
+    /// The SHA-256 fingerprint of the client certificate, hex encoded.
+    fn peer_fingerprint(&self) -> Option<String> {
+        self.peer_certificate().map(|cert| fingerprint(&cert))
+    }
+
+    /// The host name the client asked for through SNI, if any.
+    fn sni_hostname(&self) -> Option<String> {
+        None
+    }
+
+    /// The protocol negotiated through ALPN, if any.
+    ///
+    /// See the [`alpn`] module.
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        None
+    }
+}
+
+impl<IO> TlsPeer for TlsStream<IO> {
+    fn peer_certificates(&self) -> Option<Vec<Certificate>> {
+        self.get_ref().1.get_peer_certificates()
+    }
+    fn sni_hostname(&self) -> Option<String> {
+        self.get_ref().1.get_sni_hostname().map(str::to_owned)
+    }
+    fn alpn_protocol(&self) -> Option<Vec<u8>> {
+        self.get_ref().1.get_alpn_protocol().map(<[u8]>::to_vec)
+    }
+}
+
+impl<IO: PeerAddr> PeerAddr for TlsStream<IO> {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        self.get_ref().0.remote_addr()
+    }
+}
+
+/// Computes the SHA-256 fingerprint of a certificate, as lower-case hex without separators.
+pub fn fingerprint(cert: &Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &cert.0)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn normalize_fingerprint(fp: &str) -> String {
+    fp.chars()
+        .filter(|c| *c != ':')
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// A configuration fragment adding TLS to a listening socket.
+///
+/// See the [module documentation](index.html) for the configuration options.
+///
+/// The certificates are loaded when the socket is created, which happens whenever its
+/// configuration changes.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct TlsListen<Listener> {
+    /// The inner listener.
+    ///
+    /// This is available publicly to allow reading the extra configuration out of it.
+    #[serde(flatten)]
+    pub listener: Listener,
+
+    /// The TLS options shared with other crates.
+    #[serde(flatten)]
+    tls: TlsConfig,
+
+    /// Certificate authorities for client certificates, in PEM format.
+    ///
+    /// The older spelling of tls-ca.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    client_ca: Option<PathBuf>,
+
+    /// Refuse clients not presenting a certificate.
+    ///
+    /// Has effect only if the tls-ca is set. Default is on.
+    #[serde(default = "default_on")]
+    require_client_cert: bool,
+
+    /// Accept only client certificates with these SHA-256 fingerprints.
+    ///
+    /// If empty, any certificate signed by the client-ca is accepted.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    client_cert_fingerprints: BTreeSet<String>,
+
+    /// Refuse client certificates with these SHA-256 fingerprints.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    revoked_cert_fingerprints: BTreeSet<String>,
+
+    /// Number of sessions remembered for resumption by a session ID.
+    ///
+    /// 0 disables the cache. Default is 256.
+    #[serde(default = "default_session_cache")]
+    session_cache: usize,
+
+    /// Issue session tickets.
+    ///
+    /// Turned on implicitly by setting the ticket-keys. Default is off.
+    #[serde(default)]
+    session_tickets: bool,
+
+    /// How often the session ticket key is replaced.
+    ///
+    /// Tickets encrypted by the previous key are still accepted. Default is 6 hours.
+    #[serde(default = "default_ticket_rotation")]
+    ticket_rotation: CfgDuration,
+
+    /// File with the session ticket keys shared by multiple servers.
+    ///
+    /// One hex encoded 32-byte key per line, the first one is used for new tickets. Re-read every
+    /// ticket-rotation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ticket_keys: Option<PathBuf>,
+
+    /// Staple the OCSP response of the certificate to the handshakes.
+    #[serde(default)]
+    ocsp_stapling: bool,
+
+    /// URL of the OCSP responder, overriding the one in the certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ocsp_responder: Option<String>,
+
+    /// How often the OCSP response is refreshed.
+    ///
+    /// Default is 1 hour.
+    #[serde(default = "default_ocsp_refresh")]
+    ocsp_refresh: CfgDuration,
+
+    /// Refuse to start the listener without an OCSP response.
+    #[serde(default)]
+    ocsp_must_staple: bool,
+
+    /// The protocols offered through ALPN and their handlers.
+    #[serde(flatten)]
+    alpn: AlpnRoutes,
+
+    /// Logging of this listener.
+    #[serde(flatten)]
+    log: LogSettings,
+}
+
+impl<Listener> TlsListen<Listener> {
+    /// The protocols offered through ALPN and the names of their handlers.
+    ///
+    /// Meant to be passed to the [`AlpnRouter`][alpn::AlpnRouter].
+    pub fn alpn(&self) -> &AlpnRoutes {
+        &self.alpn
+    }
+
+    /// The TLS options shared with other crates.
+    pub fn tls(&self) -> &TlsConfig {
+        &self.tls
+    }
+
+    /// Creates the rustls server configuration.
+    ///
+    /// This is a low-level function that loads all the certificates and keys.
+    pub fn server_config(&self) -> Result<ServerConfig, AnyError> {
+        if self.tls.tls_ca.is_some() && self.client_ca.is_some() {
+            return Err("Only one of tls-ca and client-ca can be set".into());
+        }
+        let tls = TlsConfig {
+            tls_ca: self.tls.tls_ca.clone().or_else(|| self.client_ca.clone()),
+            ..self.tls.clone()
+        };
+        let cert_path = match (&tls.tls_cert, &tls.tls_key) {
+            (Some(path), Some(_)) => path,
+            _ => return Err("The tls-cert and tls-key are mandatory for a TLS listener".into()),
+        };
+        if !tls.tls_alpn.is_empty() && !self.alpn.alpn.is_empty() {
+            return Err("Only one of tls-alpn and alpn can be set".into());
+        }
+        let verifier = tls.client_verifier(self.require_client_cert)?;
+        let mut config = ServerConfig::new(verifier);
+        // Also checks the key matches the certificate even if we replace the resolver below
+        let (certs, key) = tls
+            .configure_server(&mut config)?
+            .expect("Both the tls-cert and tls-key are set");
+        if self.ocsp_stapling {
+            let signing = sign::any_supported_type(&key).map_err(|_| "Unsupported private key")?;
+            let certified = CertifiedKey::new(certs, Arc::new(signing));
+            let stapling = ocsp::Stapling {
+                responder: self.ocsp_responder.as_ref().map(|r| &r[..]),
+                refresh: *self.ocsp_refresh,
+                must_staple: self.ocsp_must_staple,
+            };
+            config.cert_resolver = ocsp::resolver(cert_path, certified, &stapling)
+                .context("Failed to set up OCSP stapling")?;
+        } else if ocsp::is_must_staple(&certs[0].0) {
+            log::warn!(
+                "Certificate {} demands OCSP stapling, but it is not turned on",
+                cert_path.display()
+            );
+        }
+        config.session_storage = if self.session_cache == 0 {
+            Arc::new(NoServerSessionStorage {})
+        } else {
+            ServerSessionMemoryCache::new(self.session_cache)
+        };
+        if self.session_tickets || self.ticket_keys.is_some() {
+            let ticketer = RotatingTicketer::new(*self.ticket_rotation, self.ticket_keys.clone())
+                .context("Failed to set up session tickets")?;
+            config.ticketer = Arc::new(ticketer);
+        }
+        if !self.alpn.alpn.is_empty() {
+            config.set_protocols(&self.alpn.protocols());
+        }
+        Ok(config)
+    }
+
+    fn pins(&self) -> Pins {
+        Pins {
+            allowed: self
+                .client_cert_fingerprints
+                .iter()
+                .map(|f| normalize_fingerprint(f))
+                .collect(),
+            revoked: self
+                .revoked_cert_fingerprints
+                .iter()
+                .map(|f| normalize_fingerprint(f))
+                .collect(),
+        }
+    }
+
+    fn tls_eq(&self, other: &Self) -> bool {
+        self.tls == other.tls
+            && self.client_ca == other.client_ca
+            && self.require_client_cert == other.require_client_cert
+            && self.client_cert_fingerprints == other.client_cert_fingerprints
+            && self.revoked_cert_fingerprints == other.revoked_cert_fingerprints
+            && self.session_cache == other.session_cache
+            && self.session_tickets == other.session_tickets
+            && self.ticket_rotation == other.ticket_rotation
+            && self.ticket_keys == other.ticket_keys
+            && self.ocsp_stapling == other.ocsp_stapling
+            && self.ocsp_responder == other.ocsp_responder
+            && self.ocsp_refresh == other.ocsp_refresh
+            && self.ocsp_must_staple == other.ocsp_must_staple
+            && self.alpn == other.alpn
+            && self.log == other.log
+    }
+}
+
+impl<Listener: Endpoint> Endpoint for TlsListen<Listener> {
+    fn endpoint(&self) -> String {
+        self.listener.endpoint()
+    }
+}
+
+impl<Listener: Stackable> Stackable for TlsListen<Listener> {}
+
+impl<Listener: Comparable> Comparable for TlsListen<Listener> {
+    fn compare(&self, other: &Self) -> Comparison {
+        let listener_cmp = self.listener.compare(&other.listener);
+        if listener_cmp == Comparison::Same && !self.tls_eq(other) {
+            Comparison::Similar
+        } else {
+            listener_cmp
+        }
+    }
+}
+
+impl<Listener> Fragment for TlsListen<Listener>
+where
+    Listener: Clone + Debug + Fragment + Comparable,
+{
+    type Driver = CacheSimilar<Self>;
+    type Installer = ();
+    type Seed = Listener::Seed;
+    type Resource = TlsListener<Listener::Resource>;
+    const RUN_BEFORE_CONFIG: bool = Listener::RUN_BEFORE_CONFIG;
+    fn make_seed(&self, name: &'static str) -> Result<Self::Seed, AnyError> {
+        self.listener.make_seed(name)
+    }
+    fn make_resource(
+        &self,
+        seed: &mut Self::Seed,
+        name: &'static str,
+    ) -> Result<Self::Resource, AnyError> {
+        let log = FragmentLog::new(module_path!(), name, &self.log);
+        spirit::fragment_log!(log, Level::Debug, "Creating TLS configuration for {}", name);
+        let config = self
+            .server_config()
+            .with_context(|_| format!("Failed to configure TLS on {}", name))?;
+        let inner = self.listener.make_resource(seed, name)?;
+        Ok(TlsListener {
+            inner,
+            acceptor: TlsAcceptor::from(Arc::new(config)),
+            pins: Arc::new(self.pins()),
+            name,
+            log,
+        })
+    }
+    fn init<B: Extensible<Ok = B>>(builder: B, name: &'static str) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        Listener::init(builder, name)
+    }
+}
+
+struct Pins {
+    allowed: BTreeSet<String>,
+    revoked: BTreeSet<String>,
+}
+
+impl Pins {
+    fn check<P: TlsPeer>(&self, conn: &P) -> Result<(), String> {
+        if self.allowed.is_empty() && self.revoked.is_empty() {
+            return Ok(());
+        }
+        match conn.peer_fingerprint() {
+            Some(ref fp) if self.revoked.contains(fp) => Err(format!("Revoked certificate {}", fp)),
+            Some(ref fp) if !self.allowed.is_empty() && !self.allowed.contains(fp) => {
+                Err(format!("Certificate {} not pinned", fp))
+            }
+            None if !self.allowed.is_empty() => Err("Missing client certificate".to_owned()),
+            _ => Ok(()),
+        }
+    }
+}
+
+fn parse_ticket_key(line: &str) -> Result<LessSafeKey, AnyError> {
+    if line.len() != TICKET_KEY_LEN * 2 || !line.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("Ticket key must be {} hex digits", TICKET_KEY_LEN * 2).into());
+    }
+    let bytes = (0..TICKET_KEY_LEN)
+        .map(|i| u8::from_str_radix(&line[i * 2..i * 2 + 2], 16))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes).map_err(|_| "Invalid ticket key")?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn load_ticket_keys(path: &Path) -> Result<Vec<LessSafeKey>, AnyError> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|_| format!("Failed to read ticket keys {}", path.display()))?;
+    let keys = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_ticket_key)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|_| format!("Invalid ticket keys in {}", path.display()))?;
+    if keys.is_empty() {
+        return Err(format!("No ticket keys in {}", path.display()).into());
+    }
+    Ok(keys)
+}
+
+fn generate_ticket_key(rng: &SystemRandom) -> Result<LessSafeKey, AnyError> {
+    let mut bytes = [0; TICKET_KEY_LEN];
+    rng.fill(&mut bytes)
+        .map_err(|_| "Failed to generate a ticket key")?;
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes).map_err(|_| "Invalid ticket key")?;
+    Ok(LessSafeKey::new(key))
+}
+
+struct TicketKeys {
+    /// The first one encrypts, all of them decrypt.
+    keys: Vec<LessSafeKey>,
+    since: Instant,
+}
+
+/// Encryption of session tickets with keys rotated in regular intervals.
+///
+/// The rotation is checked whenever a ticket is issued or presented, so the keys change without
+/// replacing the TLS configuration (and therefore the listener). The keys are either generated
+/// (the previous one stays valid for one more interval) or read from a file.
+struct RotatingTicketer {
+    rotation: Duration,
+    source: Option<PathBuf>,
+    keys: Mutex<TicketKeys>,
+    rng: SystemRandom,
+}
+
+impl RotatingTicketer {
+    fn new(rotation: Duration, source: Option<PathBuf>) -> Result<Self, AnyError> {
+        let rng = SystemRandom::new();
+        let keys = match &source {
+            Some(path) => load_ticket_keys(path)?,
+            None => vec![generate_ticket_key(&rng)?],
+        };
+        Ok(RotatingTicketer {
+            // Rotating on every single ticket would make them useless
+            rotation: rotation.max(Duration::from_secs(1)),
+            source,
+            keys: Mutex::new(TicketKeys {
+                keys,
+                since: Instant::now(),
+            }),
+            rng,
+        })
+    }
+
+    fn rotate(&self, keys: &mut TicketKeys) {
+        // Even on failure, we try again only after another interval
+        keys.since = Instant::now();
+        let result = match &self.source {
+            Some(path) => load_ticket_keys(path).map(|new| keys.keys = new),
+            None => generate_ticket_key(&self.rng).map(|key| {
+                keys.keys.insert(0, key);
+                keys.keys.truncate(2);
+            }),
+        };
+        match result {
+            Ok(()) => log::debug!("Rotated TLS session ticket keys"),
+            Err(e) => spirit::log_error!(
+                multi Warn,
+                "Failed to rotate session ticket keys, keeping the old ones" => e
+            ),
+        }
+    }
+
+    fn with_keys<R, F: FnOnce(&[LessSafeKey]) -> R>(&self, f: F) -> R {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.since.elapsed() >= self.rotation {
+            self.rotate(&mut keys);
+        }
+        f(&keys.keys)
+    }
+}
+
+impl ProducesTickets for RotatingTicketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+    fn get_lifetime(&self) -> u32 {
+        self.rotation.as_secs().min(u64::from(u32::max_value())) as u32
+    }
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).ok()?;
+        let mut sealed = plain.to_vec();
+        self.with_keys(|keys| {
+            let nonce = Nonce::assume_unique_for_key(nonce);
+            keys[0].seal_in_place_append_tag(nonce, Aad::empty(), &mut sealed)
+        })
+        .ok()?;
+        let mut ticket = nonce.to_vec();
+        ticket.extend(sealed);
+        Some(ticket)
+    }
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        if ticket.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = ticket.split_at(NONCE_LEN);
+        self.with_keys(|keys| {
+            keys.iter().find_map(|key| {
+                let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+                let mut buf = sealed.to_vec();
+                let plain = key.open_in_place(nonce, Aad::empty(), &mut buf).ok()?;
+                Some(plain.to_vec())
+            })
+        })
+    }
+}
+
+/// A listener with TLS configuration.
+///
+/// This is a plumbing type produced by the [`TlsListen`] fragment. The interesting property is
+/// that it implements [`IntoIncoming`].
+pub struct TlsListener<Inner> {
+    inner: Inner,
+    acceptor: TlsAcceptor,
+    pins: Arc<Pins>,
+    name: &'static str,
+    log: FragmentLog,
+}
+
+impl<Inner> TlsListener<Inner> {
+    /// Access to the wrapped listener.
+    pub fn inner(&self) -> &Inner {
+        &self.inner
+    }
+}
+
+impl<Inner> IntoIncoming for TlsListener<Inner>
+where
+    Inner: IntoIncoming,
+    Inner::Connection: AsyncRead + AsyncWrite,
+{
+    type Connection = TlsStream<Inner::Connection>;
+    type Incoming = TlsIncoming<Inner::Incoming>;
+    fn into_incoming(self) -> Self::Incoming {
+        TlsIncoming {
+            inner: Some(self.inner.into_incoming()),
+            acceptor: self.acceptor,
+            pins: self.pins,
+            handshakes: FuturesUnordered::new(),
+            name: self.name,
+            log: self.log,
+        }
+    }
+}
+
+/// A stream of connections that have finished the TLS handshake.
+///
+/// Handshakes run concurrently. Connections that fail the handshake (or the certificate pinning)
+/// are logged and dropped, they don't terminate the stream.
+pub struct TlsIncoming<Inner: Stream> {
+    inner: Option<Inner>,
+    acceptor: TlsAcceptor,
+    pins: Arc<Pins>,
+    handshakes: FuturesUnordered<Accept<Inner::Item>>,
+    name: &'static str,
+    log: FragmentLog,
+}
+
+impl<Inner> Stream for TlsIncoming<Inner>
+where
+    Inner: Stream<Error = IoError>,
+    Inner::Item: AsyncRead + AsyncWrite,
+{
+    type Item = TlsStream<Inner::Item>;
+    type Error = IoError;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, IoError> {
+        // Start handshakes on everything that is waiting.
+        while let Some(inner) = self.inner.as_mut() {
+            match inner.poll()? {
+                Async::Ready(Some(conn)) => {
+                    spirit::fragment_log!(
+                        self.log,
+                        Level::Trace,
+                        "Starting TLS handshake on {}",
+                        self.name
+                    );
+                    self.handshakes.push(self.acceptor.accept(conn));
+                }
+                Async::Ready(None) => self.inner = None,
+                Async::NotReady => break,
+            }
+        }
+        loop {
+            match self.handshakes.poll() {
+                Ok(Async::Ready(Some(conn))) => match self.pins.check(&conn) {
+                    Ok(()) => return Ok(Async::Ready(Some(conn))),
+                    Err(e) => spirit::fragment_log!(
+                        self.log,
+                        Level::Warn,
+                        "Refusing TLS client on {}: {}",
+                        self.name,
+                        e
+                    ),
+                },
+                Ok(Async::Ready(None)) if self.inner.is_none() => return Ok(Async::Ready(None)),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => spirit::fragment_log!(
+                    self.log,
+                    Level::Warn,
+                    "TLS handshake on {} failed: {}",
+                    self.name,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fingerprint_normalization() {
+        assert_eq!("abcd01", normalize_fingerprint("AB:CD:01"));
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            fingerprint(&Certificate(Vec::new()))
+        );
+    }
+
+    #[test]
+    fn ticket_rotation() {
+        let ticketer = RotatingTicketer::new(Duration::from_secs(3600), None).unwrap();
+        let ticket = ticketer.encrypt(b"session").unwrap();
+        assert_eq!(b"session".to_vec(), ticketer.decrypt(&ticket).unwrap());
+        let mut tampered = ticket.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(ticketer.decrypt(&tampered).is_none());
+        assert!(ticketer.decrypt(b"short").is_none());
+
+        // The previous key is still accepted after a rotation, but not the one before
+        ticketer.rotate(&mut ticketer.keys.lock().unwrap());
+        assert!(ticketer.decrypt(&ticket).is_some());
+        let newer = ticketer.encrypt(b"session").unwrap();
+        ticketer.rotate(&mut ticketer.keys.lock().unwrap());
+        assert!(ticketer.decrypt(&ticket).is_none());
+        assert!(ticketer.decrypt(&newer).is_some());
+    }
+
+    #[test]
+    fn ticket_key_parsing() {
+        assert!(parse_ticket_key(&"0f".repeat(TICKET_KEY_LEN)).is_ok());
+        assert!(parse_ticket_key("0f0f").is_err());
+        assert!(parse_ticket_key(&"zz".repeat(TICKET_KEY_LEN)).is_err());
+    }
+}
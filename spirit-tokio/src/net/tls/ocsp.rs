@@ -0,0 +1,538 @@
+//! OCSP stapling of the server certificate.
+//!
+//! The response of the OCSP responder is fetched when the TLS configuration is created and then
+//! refreshed by a background thread. The thread lives as long as the TLS configuration (and
+//! therefore the listener) ‒ it terminates once the listener is dropped or replaced.
+//!
+//! Only the minimal parts of the certificates and OCSP responses are parsed. The signature of the
+//! response is not verified, that's the job of the client.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use err_context::prelude::*;
+use log::{debug, warn};
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientHello, ResolvesServerCert};
+use spirit::AnyError;
+
+const BOOLEAN: u8 = 0x01;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const OID: u8 = 0x06;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+const SEQUENCE: u8 = 0x30;
+const EXPLICIT_0: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+const URI: u8 = 0x86;
+
+/// 1.3.6.1.5.5.7.1.1 (authority information access)
+const OID_AIA: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x01];
+/// 1.3.6.1.5.5.7.1.24 (TLS feature, the must-staple)
+const OID_TLS_FEATURE: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x01, 0x18];
+/// 1.3.6.1.5.5.7.48.1 (OCSP access method)
+const OID_OCSP: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01];
+/// 1.3.6.1.5.5.7.48.1.1 (basic OCSP response)
+const OID_OCSP_BASIC: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01];
+/// 1.3.14.3.2.26 (SHA-1)
+const OID_SHA1: &[u8] = &[0x2b, 0x0e, 0x03, 0x02, 0x1a];
+
+/// The status_request TLS extension number.
+const STATUS_REQUEST: &[u8] = &[0x05];
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY: Duration = Duration::from_secs(60);
+
+/// A minimal reader of DER encoded data.
+struct Der<'a>(&'a [u8]);
+
+impl<'a> Der<'a> {
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.0.first().cloned()
+    }
+
+    /// Reads the next element, returns its tag, the whole encoding and the content.
+    fn next(&mut self) -> Result<(u8, &'a [u8], &'a [u8]), AnyError> {
+        let data = self.0;
+        if data.len() < 2 {
+            return Err("Truncated DER data".into());
+        }
+        let (len, header) = match data[1] {
+            len @ 0..=0x7f => (usize::from(len), 2),
+            long @ 0x81..=0x84 => {
+                let cnt = usize::from(long & 0x7f);
+                let bytes = data.get(2..2 + cnt).ok_or("Truncated DER length")?;
+                let len = bytes.iter().fold(0, |len, b| (len << 8) | usize::from(*b));
+                (len, 2 + cnt)
+            }
+            _ => return Err("Unsupported DER length".into()),
+        };
+        if data.len() < header + len {
+            return Err("Truncated DER data".into());
+        }
+        self.0 = &data[header + len..];
+        Ok((data[0], &data[..header + len], &data[header..header + len]))
+    }
+
+    /// Reads the next element, checking it has the given tag, and returns its content.
+    fn expect(&mut self, tag: u8) -> Result<&'a [u8], AnyError> {
+        match self.next()? {
+            (t, _, content) if t == tag => Ok(content),
+            (t, _, _) => Err(format!("Unexpected DER tag {:#x}, expected {:#x}", t, tag).into()),
+        }
+    }
+
+    fn skip_if(&mut self, tag: u8) -> Result<(), AnyError> {
+        if self.peek() == Some(tag) {
+            self.next()?;
+        }
+        Ok(())
+    }
+}
+
+/// Encodes a DER element.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        result.push(len as u8);
+    } else {
+        let bytes = (len as u32).to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        result.push(0x80 | (bytes.len() - skip) as u8);
+        result.extend(&bytes[skip..]);
+    }
+    result.extend(content);
+    result
+}
+
+/// The parts of a certificate needed for OCSP.
+#[derive(Debug)]
+struct CertInfo<'a> {
+    serial: &'a [u8],
+    /// The whole encoded issuer name.
+    issuer: &'a [u8],
+    public_key: &'a [u8],
+    ocsp_url: Option<String>,
+    must_staple: bool,
+}
+
+fn parse_cert(cert: &[u8]) -> Result<CertInfo, AnyError> {
+    let mut cert = Der(Der(cert).expect(SEQUENCE)?);
+    let mut tbs = Der(cert.expect(SEQUENCE)?);
+    tbs.skip_if(EXPLICIT_0)?;
+    let serial = tbs.expect(INTEGER)?;
+    tbs.expect(SEQUENCE)?; // Signature algorithm
+    let (_, issuer, _) = tbs.next()?;
+    tbs.expect(SEQUENCE)?; // Validity
+    tbs.expect(SEQUENCE)?; // Subject
+    let mut spki = Der(tbs.expect(SEQUENCE)?);
+    spki.expect(SEQUENCE)?; // Key algorithm
+                            // Without the number of unused bits
+    let public_key = spki
+        .expect(BIT_STRING)?
+        .get(1..)
+        .ok_or("Empty public key")?;
+    let mut info = CertInfo {
+        serial,
+        issuer,
+        public_key,
+        ocsp_url: None,
+        must_staple: false,
+    };
+    while !tbs.is_empty() {
+        let (tag, _, content) = tbs.next()?;
+        if tag != EXTENSIONS {
+            continue;
+        }
+        let mut extensions = Der(Der(content).expect(SEQUENCE)?);
+        while !extensions.is_empty() {
+            let mut extension = Der(extensions.expect(SEQUENCE)?);
+            let oid = extension.expect(OID)?;
+            extension.skip_if(BOOLEAN)?;
+            let mut value = Der(Der(extension.expect(OCTET_STRING)?).expect(SEQUENCE)?);
+            if oid == OID_AIA {
+                while !value.is_empty() {
+                    let mut access = Der(value.expect(SEQUENCE)?);
+                    if access.expect(OID)? == OID_OCSP && access.peek() == Some(URI) {
+                        let url = access.expect(URI)?;
+                        info.ocsp_url = Some(String::from_utf8(url.to_vec())?);
+                    }
+                }
+            } else if oid == OID_TLS_FEATURE {
+                while !value.is_empty() {
+                    if value.expect(INTEGER)? == STATUS_REQUEST {
+                        info.must_staple = true;
+                    }
+                }
+            }
+        }
+    }
+    Ok(info)
+}
+
+fn build_request(cert: &CertInfo, issuer: &CertInfo) -> Vec<u8> {
+    let algorithm = der(SEQUENCE, &[der(OID, OID_SHA1), der(NULL, &[])].concat());
+    let name_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, cert.issuer);
+    let key_hash = digest(&SHA1_FOR_LEGACY_USE_ONLY, issuer.public_key);
+    let cert_id = der(
+        SEQUENCE,
+        &[
+            algorithm,
+            der(OCTET_STRING, name_hash.as_ref()),
+            der(OCTET_STRING, key_hash.as_ref()),
+            der(INTEGER, cert.serial),
+        ]
+        .concat(),
+    );
+    let request = der(SEQUENCE, &cert_id);
+    let request_list = der(SEQUENCE, &request);
+    let tbs_request = der(SEQUENCE, &request_list);
+    der(SEQUENCE, &tbs_request)
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum CertStatus {
+    Good,
+    Revoked,
+    Unknown,
+}
+
+fn parse_response(response: &[u8]) -> Result<CertStatus, AnyError> {
+    let mut response = Der(Der(response).expect(SEQUENCE)?);
+    match response.expect(ENUMERATED)? {
+        [0] => (),
+        status => return Err(format!("OCSP responder returned error {:?}", status).into()),
+    }
+    let mut bytes = Der(Der(response.expect(EXPLICIT_0)?).expect(SEQUENCE)?);
+    if bytes.expect(OID)? != OID_OCSP_BASIC {
+        return Err("Unsupported type of OCSP response".into());
+    }
+    let mut basic = Der(Der(bytes.expect(OCTET_STRING)?).expect(SEQUENCE)?);
+    let mut data = Der(basic.expect(SEQUENCE)?);
+    data.skip_if(EXPLICIT_0)?; // Version
+    data.next()?; // Responder ID
+    data.expect(GENERALIZED_TIME)?; // Produced at
+    let mut responses = Der(data.expect(SEQUENCE)?);
+    let mut single = Der(responses.expect(SEQUENCE)?);
+    single.expect(SEQUENCE)?; // Cert ID
+    let status = match single.next()?.0 {
+        0x80 => CertStatus::Good,
+        0xa1 => CertStatus::Revoked,
+        _ => CertStatus::Unknown,
+    };
+    Ok(status)
+}
+
+/// Splits a http URL into the host with port and the path.
+fn split_url(url: &str) -> Result<(String, &str), AnyError> {
+    if !url.starts_with("http://") {
+        return Err(format!("Only http OCSP responders are supported, not {}", url).into());
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("Missing host in {}", url).into());
+    }
+    let host = if host.contains(':') && !host.ends_with(']') {
+        host.to_owned()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((host, path))
+}
+
+/// Everything needed to ask for the OCSP response of one certificate.
+#[derive(Clone, Debug)]
+struct Query {
+    cert: String,
+    url: String,
+    request: Vec<u8>,
+}
+
+impl Query {
+    fn post(&self) -> Result<Vec<u8>, AnyError> {
+        let (host, path) = split_url(&self.url)?;
+        let addr = host
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| format!("No address for {}", host))?;
+        let mut conn = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        conn.set_write_timeout(Some(TIMEOUT))?;
+        let header = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/ocsp-request\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            path,
+            host,
+            self.request.len()
+        );
+        conn.write_all(header.as_bytes())?;
+        conn.write_all(&self.request)?;
+        let mut response = Vec::new();
+        conn.read_to_end(&mut response)?;
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .ok_or("Malformed HTTP response")?;
+        let head = String::from_utf8_lossy(&response[..split]);
+        let status = head.lines().next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(format!("OCSP responder returned {}", status).into());
+        }
+        Ok(response[split + 4..].to_vec())
+    }
+
+    fn fetch(&self) -> Result<Vec<u8>, AnyError> {
+        let response = self
+            .post()
+            .with_context(|_| format!("Failed to query OCSP responder {}", self.url))?;
+        match parse_response(&response)
+            .with_context(|_| format!("Invalid OCSP response from {}", self.url))?
+        {
+            CertStatus::Good => debug!("Fetched OCSP response for {}", self.cert),
+            status => warn!(
+                "OCSP responder says certificate {} is {:?}",
+                self.cert, status
+            ),
+        }
+        Ok(response)
+    }
+}
+
+/// Configuration of the stapling.
+#[derive(Clone, Debug)]
+pub(crate) struct Stapling<'a> {
+    pub(crate) responder: Option<&'a str>,
+    pub(crate) refresh: Duration,
+    pub(crate) must_staple: bool,
+}
+
+/// A certificate resolver always returning the same certificate with the current OCSP response.
+struct Stapled {
+    key: CertifiedKey,
+    response: Arc<RwLock<Option<Vec<u8>>>>,
+    // Dropping it terminates the refreshing thread
+    _stop: Mutex<Sender<()>>,
+}
+
+impl ResolvesServerCert for Stapled {
+    fn resolve(&self, _: ClientHello) -> Option<CertifiedKey> {
+        let mut key = self.key.clone();
+        key.ocsp = self.response.read().unwrap().clone();
+        Some(key)
+    }
+}
+
+fn refresh(
+    query: Query,
+    response: Arc<RwLock<Option<Vec<u8>>>>,
+    interval: Duration,
+    stop: Receiver<()>,
+) {
+    loop {
+        let have = response.read().unwrap().is_some();
+        let wait = if have { interval } else { interval.min(RETRY) };
+        match stop.recv_timeout(wait) {
+            Err(RecvTimeoutError::Timeout) => (),
+            // The listener is gone
+            _ => break,
+        }
+        match query.fetch() {
+            Ok(new) => *response.write().unwrap() = Some(new),
+            Err(e) => spirit::log_error!(multi Warn, "Failed to refresh OCSP response" => e),
+        }
+    }
+    debug!("Terminating OCSP refresh of {}", query.cert);
+}
+
+/// Creates the certificate resolver stapling the OCSP responses.
+///
+/// The issuer certificate needs to be part of the chain.
+pub(crate) fn resolver(
+    path: &Path,
+    key: CertifiedKey,
+    stapling: &Stapling,
+) -> Result<Arc<dyn ResolvesServerCert>, AnyError> {
+    let issuer = key
+        .cert
+        .get(1)
+        .ok_or("The certificate chain doesn't contain the issuer")?;
+    let cert = &key.cert[0];
+    let cert = parse_cert(&cert.0).context("Failed to parse the certificate")?;
+    let issuer = parse_cert(&issuer.0).context("Failed to parse the issuer certificate")?;
+    let url = stapling
+        .responder
+        .map(str::to_owned)
+        .or_else(|| cert.ocsp_url.clone())
+        .ok_or("No OCSP responder in the certificate")?;
+    let query = Query {
+        cert: path.display().to_string(),
+        url,
+        request: build_request(&cert, &issuer),
+    };
+    let initial = match query.fetch() {
+        Ok(response) => Some(response),
+        Err(e) if stapling.must_staple || cert.must_staple => {
+            return Err(e.context("OCSP response is mandatory").into());
+        }
+        Err(e) => {
+            spirit::log_error!(multi Warn, "Starting without OCSP response" => e);
+            None
+        }
+    };
+    let response = Arc::new(RwLock::new(initial));
+    let (stop, stop_recv) = mpsc::channel();
+    let refresh_response = Arc::clone(&response);
+    let interval = stapling.refresh;
+    thread::Builder::new()
+        .name("spirit-ocsp".to_owned())
+        .spawn(move || refresh(query, refresh_response, interval, stop_recv))?;
+    Ok(Arc::new(Stapled {
+        key,
+        response,
+        _stop: Mutex::new(stop),
+    }))
+}
+
+/// Checks if the certificate demands stapling (has the must-staple extension).
+pub(crate) fn is_must_staple(cert: &[u8]) -> bool {
+    parse_cert(cert)
+        .map(|info| info.must_staple)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seq(parts: &[Vec<u8>]) -> Vec<u8> {
+        der(SEQUENCE, &parts.concat())
+    }
+
+    fn test_cert(serial: u8, key: &[u8]) -> Vec<u8> {
+        let name = seq(&[der(
+            0x31,
+            &seq(&[der(OID, &[0x55, 0x04, 0x03]), der(0x0c, b"ca")]),
+        )]);
+        let aia = seq(&[seq(&[
+            der(OID, OID_OCSP),
+            der(URI, b"http://ocsp.example.com"),
+        ])]);
+        let feature = seq(&[der(INTEGER, STATUS_REQUEST)]);
+        let extensions = seq(&[
+            seq(&[der(OID, OID_AIA), der(OCTET_STRING, &aia)]),
+            seq(&[
+                der(OID, OID_TLS_FEATURE),
+                der(BOOLEAN, &[0]),
+                der(OCTET_STRING, &feature),
+            ]),
+        ]);
+        let tbs = seq(&[
+            der(EXPLICIT_0, &der(INTEGER, &[2])),
+            der(INTEGER, &[serial]),
+            seq(&[]),
+            name.clone(),
+            seq(&[]),
+            name,
+            seq(&[seq(&[]), der(BIT_STRING, &[&[0][..], key].concat())]),
+            der(EXTENSIONS, &extensions),
+        ]);
+        seq(&[tbs, seq(&[]), der(BIT_STRING, &[0])])
+    }
+
+    #[test]
+    fn der_lengths() {
+        for len in &[0, 5, 0x7f, 0x80, 0xff, 0x100, 0x10000] {
+            let data = vec![0; *len];
+            let encoded = der(OCTET_STRING, &data);
+            let mut reader = Der(&encoded);
+            assert_eq!(&data[..], reader.expect(OCTET_STRING).unwrap());
+            assert!(reader.is_empty());
+        }
+        assert!(Der(&[SEQUENCE, 5, 0]).next().is_err());
+    }
+
+    #[test]
+    fn cert_info() {
+        let cert = test_cert(42, b"key");
+        let info = parse_cert(&cert).unwrap();
+        assert_eq!(&[42], info.serial);
+        assert_eq!(b"key", info.public_key);
+        assert_eq!(
+            Some("http://ocsp.example.com"),
+            info.ocsp_url.as_ref().map(|s| &s[..])
+        );
+        assert!(info.must_staple);
+        assert!(is_must_staple(&cert));
+
+        let issuer = test_cert(1, b"issuer key");
+        let issuer = parse_cert(&issuer).unwrap();
+        let request = build_request(&info, &issuer);
+        // Dig the cert ID back out of the request
+        let mut content = &request[..];
+        for _ in 0..5 {
+            content = Der(content).expect(SEQUENCE).unwrap();
+        }
+        let mut cert_id = Der(content);
+        cert_id.expect(SEQUENCE).unwrap();
+        assert_eq!(20, cert_id.expect(OCTET_STRING).unwrap().len());
+        assert_eq!(20, cert_id.expect(OCTET_STRING).unwrap().len());
+        assert_eq!(&[42], cert_id.expect(INTEGER).unwrap());
+    }
+
+    #[test]
+    fn response_status() {
+        let response = |status: Vec<u8>| {
+            let single = seq(&[seq(&[]), status, der(GENERALIZED_TIME, b"20200101000000Z")]);
+            let data = seq(&[
+                der(0xa2, &der(OCTET_STRING, &[1])),
+                der(GENERALIZED_TIME, b"20200101000000Z"),
+                seq(&[single]),
+            ]);
+            let basic = seq(&[data, seq(&[]), der(BIT_STRING, &[0])]);
+            let bytes = seq(&[der(OID, OID_OCSP_BASIC), der(OCTET_STRING, &basic)]);
+            seq(&[der(ENUMERATED, &[0]), der(EXPLICIT_0, &bytes)])
+        };
+        assert_eq!(
+            CertStatus::Good,
+            parse_response(&response(der(0x80, &[]))).unwrap()
+        );
+        assert_eq!(
+            CertStatus::Revoked,
+            parse_response(&response(der(0xa1, &[]))).unwrap()
+        );
+        let refused = seq(&[der(ENUMERATED, &[6])]);
+        assert!(parse_response(&refused).is_err());
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            ("ocsp.example.com:80".to_owned(), "/"),
+            split_url("http://ocsp.example.com").unwrap()
+        );
+        assert_eq!(
+            ("localhost:8080".to_owned(), "/ocsp"),
+            split_url("http://localhost:8080/ocsp").unwrap()
+        );
+        assert!(split_url("https://ocsp.example.com").is_err());
+        assert!(split_url("http:///ocsp").is_err());
+    }
+}
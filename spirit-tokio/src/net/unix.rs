@@ -10,6 +10,7 @@
 //! [`Either`]: crate::either::Either
 
 use std::fmt::Debug;
+use std::net::SocketAddr;
 use std::os::unix::net::{UnixDatagram as StdUnixDatagram, UnixListener as StdUnixListener};
 use std::path::PathBuf;
 
@@ -23,7 +24,7 @@ use tokio::net::unix::{Incoming, UnixDatagram, UnixListener, UnixStream};
 use tokio::reactor::Handle;
 
 use crate::net::limits::WithLimits;
-use crate::net::{ConfiguredStreamListener, IntoIncoming};
+use crate::net::{handover, ConfiguredStreamListener, Endpoint, IntoIncoming, PeerAddr};
 
 /// Configuration of where to bind a unix domain socket.
 ///
@@ -57,15 +58,35 @@ impl Listen {
     /// Creates a unix listener.
     ///
     /// This is a low-level function, returning the *blocking* (std) listener.
+    ///
+    /// With the `upgrade` feature, a socket handed over by the previous instance of the
+    /// application is reused if there's one.
     pub fn create_listener(&self) -> Result<StdUnixListener, AnyError> {
-        StdUnixListener::bind(&self.path).map_err(AnyError::from)
+        let listener = handover(&format!("unix:{}", self.endpoint()), || {
+            StdUnixListener::bind(&self.path).map_err(AnyError::from)
+        })?;
+        spirit::banner::listening(format_args!("unix {}", self.path.display()));
+        Ok(listener)
     }
 
     /// Creates a unix datagram socket.
     ///
     /// This is a low-level function, returning the *blocking* (std) socket.
+    ///
+    /// With the `upgrade` feature, a socket handed over by the previous instance of the
+    /// application is reused if there's one.
     pub fn create_datagram(&self) -> Result<StdUnixDatagram, AnyError> {
-        StdUnixDatagram::bind(&self.path).map_err(AnyError::from)
+        let socket = handover(&format!("unix-dgram:{}", self.endpoint()), || {
+            StdUnixDatagram::bind(&self.path).map_err(AnyError::from)
+        })?;
+        spirit::banner::listening(format_args!("unix-dgram {}", self.path.display()));
+        Ok(socket)
+    }
+}
+
+impl Endpoint for Listen {
+    fn endpoint(&self) -> String {
+        self.path.display().to_string()
     }
 }
 
@@ -86,6 +107,12 @@ impl IntoIncoming for UnixListener {
     }
 }
 
+impl PeerAddr for UnixStream {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
 /// A listener for unix domain stream sockets.
 ///
 /// This is the unix-domain equivalent of [`TcpListen`]. All notes about it apply here with the
@@ -159,6 +186,12 @@ where
     }
 }
 
+impl<ExtraCfg, UnixStreamConfig> Endpoint for UnixListen<ExtraCfg, UnixStreamConfig> {
+    fn endpoint(&self) -> String {
+        self.listen.endpoint()
+    }
+}
+
 /// Type alias for [`UnixListen`] without any unnecessary configuration options.
 pub type MinimalUnixListen<ExtraCfg = Empty> = UnixListen<ExtraCfg, Empty>;
 
@@ -230,3 +263,9 @@ where
             .map_err(AnyError::from)
     }
 }
+
+impl<ExtraCfg> Endpoint for DatagramListen<ExtraCfg> {
+    fn endpoint(&self) -> String {
+        self.listen.endpoint()
+    }
+}
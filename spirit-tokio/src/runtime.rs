@@ -4,11 +4,13 @@ use std::fmt::Debug;
 use std::sync::Arc;
 use std::time::Duration;
 
+use err_context::prelude::*;
 use futures::future::{self, Future};
 use log::{trace, warn};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use spirit::extension::{Extensible, Extension};
+use spirit::sched::Sched;
 use spirit::AnyError;
 use spirit::{Builder, Spirit};
 use structdoc::StructDoc;
@@ -156,6 +158,8 @@ impl Runtime {
         match self {
             Runtime::ThreadPool(mut mod_builder) => {
                 let mut builder = runtime::Builder::new();
+                // Respect the CPU quota of a container (tokio itself looks only at the host)
+                builder.core_threads(spirit::cgroup::cpus());
                 mod_builder(&mut builder);
                 let mut runtime = builder.build()?;
                 runtime.block_on(fut)?;
@@ -236,7 +240,8 @@ pub struct ThreadPoolConfig {
     /// These do most of the work. There's little reason to set it to more than number of CPUs, but
     /// it may make sense to set it lower.
     ///
-    /// If not set, the application will start with number of CPUs available in the system.
+    /// If not set, the application will start with number of CPUs available to it (taking the
+    /// CPU quota of the container into account, see [`spirit::cgroup`]).
     #[serde(skip_serializing_if = "Option::is_none")]
     pub async_threads: Option<usize>,
 
@@ -263,6 +268,14 @@ pub struct ThreadPoolConfig {
     ///
     /// Accepts human-parsable times, like „3days“ or „5s“.
     pub keep_alive: Option<Duration>,
+
+    /// CPU affinity and scheduling of the worker threads.
+    ///
+    /// See [`spirit::sched`]. Applied to each worker thread when it starts. Failures are logged,
+    /// but the thread still runs.
+    #[serde(default, skip_serializing_if = "Sched::is_empty")]
+    pub sched: Sched,
+
     #[serde(skip)]
     _sentinel: (),
 }
@@ -320,15 +333,25 @@ impl ThreadPoolConfig {
                         let spirit = Arc::clone(spirit);
                         Box::new(move |builder| {
                             let cfg = extract(&spirit.config());
-                            if let Some(threads) = cfg.async_threads {
-                                builder.core_threads(threads);
-                            }
+                            let threads = cfg.async_threads.unwrap_or_else(spirit::cgroup::cpus);
+                            builder.core_threads(threads);
                             if let Some(threads) = cfg.blocking_threads {
                                 builder.blocking_threads(threads);
                             }
                             if let Some(alive) = cfg.keep_alive {
                                 builder.keep_alive(Some(alive));
                             }
+                            if !cfg.sched.is_empty() {
+                                let sched = cfg.sched;
+                                builder.after_start(move || {
+                                    if let Err(e) = sched.apply() {
+                                        spirit::log_error!(
+                                            Error,
+                                            "Failed to set scheduling of a worker thread" => e
+                                        );
+                                    }
+                                });
+                            }
                             (post.take().unwrap())(builder)
                         })
                     })
@@ -0,0 +1,364 @@
+//! gRPC client channels.
+//!
+//! The [`GrpcChannel`] fragment describes how to reach a gRPC upstream ‒ a list of endpoints to
+//! balance the calls between, TLS, keep-alive and the default time limits. It is installed into
+//! an [`AtomicChannel`], which always holds the channel of the current configuration, similar to
+//! what `AtomicClient` of `spirit-reqwest` does for HTTP.
+//!
+//! The channel connects lazily, so an unreachable upstream doesn't prevent the configuration from
+//! being loaded. The calls already in progress finish on the old channel after a reload.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_tonic::client::{AtomicChannel, GrpcChannel};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [upstream]
+//! endpoints = ["http://10.0.0.1:50051", "http://10.0.0.2:50051"]
+//! timeout = "5s"
+//! http2-keepalive-interval = "30s"
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     upstream: GrpcChannel,
+//! }
+//!
+//! fn main() {
+//!     let runtime = tokio::runtime::Runtime::new().unwrap();
+//!     let channel = AtomicChannel::empty(runtime.handle().clone());
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("upstream")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.upstream.clone())
+//!                 .install(channel.clone())
+//!         )
+//!         .run(move |_| {
+//!             // Generated by tonic-build from the upstream's protobuf files
+//!             let mut client = GreeterClient::new(channel.channel());
+//!             // ...
+//!             Ok(())
+//!         });
+//! }
+//! ```
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use arc_swap::ArcSwapOption;
+use err_context::prelude::*;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use spirit::fragment::driver::CacheEq;
+use spirit::fragment::Installer;
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use tokio::runtime::Handle;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+/// TLS settings of a gRPC channel.
+///
+/// # Fields
+///
+/// * `ca`: Path to the PEM file with the CA certificate to verify the servers against. If not set,
+///   the system roots are used.
+/// * `domain`: The domain name to expect in the certificates of the servers. Defaults to the host
+///   of each endpoint.
+/// * `cert`, `key`: Paths to the PEM files with the client certificate and its private key. Both
+///   or neither need to be set.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct ChannelTls {
+    /// The CA to verify the servers against, in PEM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ca: Option<PathBuf>,
+
+    /// The domain name expected in the server certificates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+
+    /// The client certificate, in PEM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+
+    /// The private key of the client certificate, in PEM.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+}
+
+impl ChannelTls {
+    /// Loads the files and creates tonic's TLS configuration.
+    pub fn client_tls_config(&self) -> Result<ClientTlsConfig, AnyError> {
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = &self.ca {
+            let pem =
+                fs::read(ca).with_context(|_| format!("Failed to read CA {}", ca.display()))?;
+            config = config.ca_certificate(Certificate::from_pem(pem));
+        }
+        if let Some(domain) = &self.domain {
+            config = config.domain_name(domain.clone());
+        }
+        match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => {
+                let cert = fs::read(cert).with_context(|_| {
+                    format!("Failed to read client certificate {}", cert.display())
+                })?;
+                let key = fs::read(key)
+                    .with_context(|_| format!("Failed to read client key {}", key.display()))?;
+                config = config.identity(Identity::from_pem(cert, key));
+            }
+            (None, None) => (),
+            _ => return Err("Both cert and key need to be set for a client certificate".into()),
+        }
+        Ok(config)
+    }
+}
+
+/// Configuration of a gRPC client channel.
+///
+/// # Fields
+///
+/// * `endpoints`: The URIs of the servers (eg. `http://localhost:50051`). The calls are balanced
+///   between them. At least one is needed.
+/// * `tls`: If present, the servers are contacted over TLS. See [`ChannelTls`].
+/// * `connect-timeout`: Time limit for establishing a connection. Unlimited if not set.
+/// * `timeout`: The default time limit for a single call. Unlimited if not set.
+/// * `concurrency-limit`: Maximum number of calls in progress on one endpoint. Unlimited if not
+///   set.
+/// * `tcp-keepalive`: Interval of the TCP keep-alive probes. Turned off if not set.
+/// * `http2-keepalive-interval`: Interval of the HTTP/2 pings. Turned off if not set.
+/// * `keepalive-timeout`: How long to wait for the answer to an HTTP/2 ping before considering
+///   the connection dead. Left on the default if not set.
+/// * `keepalive-while-idle`: Send the HTTP/2 pings even when there are no calls in progress.
+///   Defaults to `false`.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct GrpcChannel {
+    /// The URIs of the servers.
+    pub endpoints: Vec<String>,
+
+    /// TLS settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<ChannelTls>,
+
+    /// Time limit for establishing a connection.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub connect_timeout: Option<Duration>,
+
+    /// The default time limit for a single call.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// Maximum number of calls in progress on one endpoint.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency_limit: Option<usize>,
+
+    /// Interval of the TCP keep-alive probes.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub tcp_keepalive: Option<Duration>,
+
+    /// Interval of the HTTP/2 pings.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub http2_keepalive_interval: Option<Duration>,
+
+    /// How long to wait for the answer to an HTTP/2 ping.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub keepalive_timeout: Option<Duration>,
+
+    /// Send the HTTP/2 pings even without calls in progress.
+    #[serde(default)]
+    pub keepalive_while_idle: bool,
+}
+
+impl GrpcChannel {
+    /// Creates the configured endpoint for one of the URIs.
+    pub fn endpoint(&self, uri: &str) -> Result<Endpoint, AnyError> {
+        let mut endpoint = Endpoint::from_shared(uri.to_owned())
+            .with_context(|_| format!("Invalid gRPC endpoint {}", uri))?
+            .tcp_keepalive(self.tcp_keepalive)
+            .keep_alive_while_idle(self.keepalive_while_idle);
+        if let Some(timeout) = self.connect_timeout {
+            endpoint = endpoint.connect_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            endpoint = endpoint.timeout(timeout);
+        }
+        if let Some(limit) = self.concurrency_limit {
+            endpoint = endpoint.concurrency_limit(limit);
+        }
+        if let Some(interval) = self.http2_keepalive_interval {
+            endpoint = endpoint.http2_keep_alive_interval(interval);
+        }
+        if let Some(timeout) = self.keepalive_timeout {
+            endpoint = endpoint.keep_alive_timeout(timeout);
+        }
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.client_tls_config()?)
+                .with_context(|_| format!("Invalid TLS configuration for {}", uri))?;
+        }
+        Ok(endpoint)
+    }
+
+    /// Creates the configured endpoints of all the URIs.
+    pub fn create_endpoints(&self) -> Result<Endpoints, AnyError> {
+        if self.endpoints.is_empty() {
+            return Err("No gRPC endpoints configured".into());
+        }
+        self.endpoints
+            .iter()
+            .map(|uri| self.endpoint(uri))
+            .collect::<Result<_, _>>()
+            .map(Endpoints)
+    }
+}
+
+spirit::simple_fragment! {
+    impl Fragment for GrpcChannel {
+        type Driver = CacheEq<GrpcChannel>;
+        type Resource = Endpoints;
+        type Installer = ();
+        fn create(&self, _: &'static str) -> Result<Endpoints, AnyError> {
+            self.create_endpoints()
+        }
+    }
+}
+
+/// The configured endpoints to create a channel from.
+///
+/// This is what the [`GrpcChannel`] fragment creates. The channel itself is created by the
+/// [`AtomicChannel`], as it needs a runtime. The user should not need to interact with this
+/// directly.
+#[derive(Clone, Debug)]
+pub struct Endpoints(Vec<Endpoint>);
+
+impl Endpoints {
+    /// Creates a lazily connecting channel balancing between the endpoints.
+    ///
+    /// Needs to be called within the context of a tokio runtime.
+    pub fn channel(self) -> Channel {
+        Channel::balance_list(self.0.into_iter())
+    }
+}
+
+/// A handle to the current gRPC channel.
+///
+/// Cheap to clone, all the clones share the same channel. It can be used as an [`Installer`] for
+/// the [`GrpcChannel`] fragment.
+///
+/// # Panics
+///
+/// Accessing the channel before one is set panics.
+#[derive(Clone)]
+pub struct AtomicChannel {
+    handle: Handle,
+    channel: Arc<ArcSwapOption<Channel>>,
+}
+
+impl Debug for AtomicChannel {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("AtomicChannel").finish()
+    }
+}
+
+impl AtomicChannel {
+    /// Creates an [`AtomicChannel`] without any channel inside.
+    ///
+    /// The channels are driven by the runtime of the `handle`.
+    pub fn empty(handle: Handle) -> Self {
+        AtomicChannel {
+            handle,
+            channel: Arc::new(ArcSwapOption::empty()),
+        }
+    }
+
+    /// Replaces the channel inside.
+    pub fn replace(&self, channel: Channel) {
+        self.channel.store(Some(Arc::new(channel)));
+    }
+
+    /// Returns the current channel.
+    ///
+    /// The channel is cheap to clone and it is what the clients generated by tonic are created
+    /// from.
+    pub fn channel(&self) -> Channel {
+        let channel = self
+            .channel
+            .load_full()
+            .expect("Accessing gRPC channel before setting it up");
+        Channel::clone(&channel)
+    }
+}
+
+impl<O, C> Installer<Endpoints, O, C> for AtomicChannel {
+    type UninstallHandle = ();
+    fn install(&mut self, endpoints: Endpoints, name: &'static str) {
+        debug!("Installing gRPC channel {}", name);
+        let _guard = self.handle.enter();
+        self.replace(endpoints.channel());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_endpoints() {
+        assert!(GrpcChannel::default().create_endpoints().is_err());
+    }
+
+    #[test]
+    fn invalid_endpoint() {
+        let channel = GrpcChannel {
+            endpoints: vec!["not a uri".to_owned()],
+            ..GrpcChannel::default()
+        };
+        assert!(channel.create_endpoints().is_err());
+    }
+
+    #[test]
+    fn cert_without_key() {
+        let tls = ChannelTls {
+            cert: Some(PathBuf::from("/does/not/matter.pem")),
+            ..ChannelTls::default()
+        };
+        assert!(tls.client_tls_config().is_err());
+    }
+}
@@ -0,0 +1,764 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-tonic/0.1.0/spirit_tonic/",
+    test(attr(deny(warnings)))
+)]
+#![forbid(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Configuration of [tonic] gRPC servers.
+//!
+//! The [`GrpcServer`] fragment describes where a gRPC server listens (a TCP or a unix domain
+//! socket, using the same socket configuration as [`spirit_tokio`]), whether it speaks TLS and
+//! the per-server limits. It is installed by a [`TonicInstaller`], which gets a closure to add
+//! the application's services to the server and runs it on the given runtime.
+//!
+//! Optionally, the standard [gRPC health] and [server reflection] services are added to the
+//! server, each toggled by a configuration option. The health status of the services is set
+//! through the [`HealthStatus`] handle of the installer.
+//!
+//! If the socket configuration doesn't change, the socket is kept across reloads and only the
+//! server on top of it is replaced. The old server stops accepting new connections and finishes
+//! the requests in flight. The same happens to all the servers when the application terminates.
+//!
+//! The client side is in the [`client`] module.
+//!
+//! # Examples
+//!
+//! ```rust,ignore
+//! use serde::Deserialize;
+//! use spirit::{Empty, Pipeline, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_tonic::{GrpcServer, TonicInstaller};
+//!
+//! const DEFAULT_CONFIG: &str = r#"
+//! [grpc]
+//! health = true
+//! concurrency-limit = 32
+//! timeout = "30s"
+//!
+//! [grpc.tcp]
+//! port = 50051
+//! "#;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     grpc: GrpcServer,
+//! }
+//!
+//! fn main() {
+//!     let runtime = tokio::runtime::Runtime::new().unwrap();
+//!     let installer = TonicInstaller::new(runtime.handle().clone(), |server| {
+//!         // Generated by tonic-build from the application's protobuf files
+//!         server.add_service(GreeterServer::new(MyGreeter::default()))
+//!     });
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(DEFAULT_CONFIG)
+//!         .with(
+//!             Pipeline::new("grpc")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.grpc.clone())
+//!                 .install(installer)
+//!         )
+//!         .run(|spirit| {
+//!             while !spirit.is_terminated() {
+//!                 std::thread::sleep(std::time::Duration::from_millis(100));
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [tonic]: https://crates.io/crates/tonic
+//! [gRPC health]: https://github.com/grpc/grpc/blob/master/doc/health-checking.md
+//! [server reflection]: https://github.com/grpc/grpc/blob/master/doc/server-reflection.md
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::Error as IoError;
+use std::net::TcpListener as StdTcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+#[cfg(unix)]
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use err_context::prelude::*;
+use futures::future::BoxFuture;
+use futures::{FutureExt, Stream};
+use log::{debug, error, info, trace};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use spirit::extension::Extensible;
+use spirit::fragment::driver::{CacheSimilar, Comparable, Comparison};
+use spirit::fragment::{Fragment, Installer, Stackable};
+use spirit::AnyError;
+#[cfg(feature = "cfg-help")]
+use structdoc::StructDoc;
+use structopt::StructOpt;
+#[cfg(unix)]
+use tokio::io::ReadBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::Handle;
+use tokio::sync::oneshot::{self, Sender};
+use tonic::body::BoxBody;
+use tonic::codegen::{http, Service};
+use tonic::transport::server::{Connected, Router};
+use tonic::transport::{Body, Certificate, Identity, NamedService, Server, ServerTlsConfig};
+use tonic_health::server::HealthReporter;
+pub use tonic_health::ServingStatus;
+
+pub mod client;
+
+/// TLS settings of a gRPC server.
+///
+/// # Fields
+///
+/// * `cert`: Path to the PEM file with the certificate chain of the server.
+/// * `key`: Path to the PEM file with the private key of the server.
+/// * `client-ca`: Path to the PEM file with the CA certificate. If set, clients are required to
+///   present a certificate signed by it.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct GrpcTls {
+    /// The certificate chain of the server, in PEM.
+    pub cert: PathBuf,
+
+    /// The private key of the server, in PEM.
+    pub key: PathBuf,
+
+    /// The CA to verify client certificates against, in PEM.
+    ///
+    /// If not set, clients are not asked for certificates.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_ca: Option<PathBuf>,
+}
+
+impl GrpcTls {
+    /// Loads the files and creates tonic's TLS configuration.
+    pub fn server_tls_config(&self) -> Result<ServerTlsConfig, AnyError> {
+        let cert = fs::read(&self.cert)
+            .with_context(|_| format!("Failed to read certificate {}", self.cert.display()))?;
+        let key = fs::read(&self.key)
+            .with_context(|_| format!("Failed to read private key {}", self.key.display()))?;
+        let mut config = ServerTlsConfig::new().identity(Identity::from_pem(cert, key));
+        if let Some(client_ca) = &self.client_ca {
+            let ca = fs::read(client_ca)
+                .with_context(|_| format!("Failed to read client CA {}", client_ca.display()))?;
+            config = config.client_ca_root(Certificate::from_pem(ca));
+        }
+        Ok(config)
+    }
+}
+
+/// Configuration of a gRPC server.
+///
+/// Exactly one of `tcp` and `unix` needs to be set.
+///
+/// # Fields
+///
+/// * `tcp`: The TCP socket to listen on. See [`spirit_tokio::net::Listen`] for the options.
+/// * `unix`: The unix domain socket to listen on (unix only). See
+///   [`spirit_tokio::net::unix::Listen`] for the options.
+/// * `tls`: If present, the server speaks TLS. See [`GrpcTls`].
+/// * `concurrency-limit`: Maximum number of requests processed concurrently on one connection.
+///   Unlimited if not set.
+/// * `timeout`: A time limit for handling a single request. Unlimited if not set.
+/// * `max-concurrent-streams`: The HTTP/2 `SETTINGS_MAX_CONCURRENT_STREAMS` announced to
+///   clients. Left on the default of the HTTP/2 implementation if not set.
+/// * `health`: Add the gRPC health checking service. Defaults to `false`.
+/// * `reflection`: Add the gRPC server reflection service. Defaults to `false`. This needs the
+///   file descriptor set to be provided to the installer with
+///   [`TonicInstaller::with_reflection`].
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct GrpcServer {
+    /// The TCP socket to listen on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<spirit_tokio::net::Listen>,
+
+    /// The unix domain socket to listen on.
+    #[cfg(unix)]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unix: Option<spirit_tokio::net::unix::Listen>,
+
+    /// TLS settings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<GrpcTls>,
+
+    /// Maximum number of concurrently processed requests on one connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency_limit: Option<usize>,
+
+    /// Time limit for handling a single request.
+    #[serde(
+        default,
+        deserialize_with = "spirit::utils::deserialize_opt_duration",
+        serialize_with = "spirit::utils::serialize_opt_duration",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Duration>,
+
+    /// The maximum number of concurrent HTTP/2 streams on a connection.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrent_streams: Option<u32>,
+
+    /// Add the gRPC health checking service.
+    #[serde(default)]
+    pub health: bool,
+
+    /// Add the gRPC server reflection service.
+    #[serde(default)]
+    pub reflection: bool,
+}
+
+impl GrpcServer {
+    #[cfg(unix)]
+    fn has_unix(&self) -> bool {
+        self.unix.is_some()
+    }
+
+    #[cfg(not(unix))]
+    fn has_unix(&self) -> bool {
+        false
+    }
+
+    fn same_transport(&self, other: &Self) -> bool {
+        #[cfg(unix)]
+        let same_unix = self.unix == other.unix;
+        #[cfg(not(unix))]
+        let same_unix = true;
+        self.tcp == other.tcp && same_unix
+    }
+
+    /// Creates the listening socket.
+    pub fn create_listener(&self) -> Result<StdListener, AnyError> {
+        match (&self.tcp, self.has_unix()) {
+            (Some(_), true) => Err("Both tcp and unix set for a gRPC server".into()),
+            (None, false) => Err("No socket (tcp or unix) set for a gRPC server".into()),
+            (Some(tcp), false) => Ok(StdListener::Tcp(tcp.create_tcp()?)),
+            #[cfg(unix)]
+            (None, true) => {
+                let unix = self.unix.as_ref().expect("Checked above");
+                Ok(StdListener::Unix(unix.create_listener()?))
+            }
+            #[cfg(not(unix))]
+            (None, true) => unreachable!(),
+        }
+    }
+
+    /// Creates the tonic server builder, with the limits and TLS applied.
+    pub fn create_server(&self) -> Result<Server, AnyError> {
+        let mut server = Server::builder();
+        if let Some(limit) = self.concurrency_limit {
+            server = server.concurrency_limit_per_connection(limit);
+        }
+        if let Some(timeout) = self.timeout {
+            server = server.timeout(timeout);
+        }
+        server = server.max_concurrent_streams(self.max_concurrent_streams);
+        if let Some(tls) = &self.tls {
+            server = server
+                .tls_config(tls.server_tls_config()?)
+                .context("Invalid TLS configuration of gRPC server")?;
+        }
+        Ok(server)
+    }
+}
+
+impl Stackable for GrpcServer {}
+
+impl Comparable for GrpcServer {
+    fn compare(&self, other: &Self) -> Comparison {
+        if !self.same_transport(other) {
+            Comparison::Dissimilar
+        } else if self != other {
+            Comparison::Similar
+        } else {
+            Comparison::Same
+        }
+    }
+}
+
+impl Fragment for GrpcServer {
+    type Driver = CacheSimilar<Self>;
+    type Installer = ();
+    type Seed = StdListener;
+    type Resource = GrpcResource;
+    fn make_seed(&self, name: &'static str) -> Result<StdListener, AnyError> {
+        self.create_listener()
+            .with_context(|_| format!("Failed to create socket {}/{:?}", name, self))
+            .map_err(AnyError::from)
+    }
+    fn make_resource(
+        &self,
+        seed: &mut StdListener,
+        name: &'static str,
+    ) -> Result<GrpcResource, AnyError> {
+        let listener = seed
+            .try_clone() // Another copy of the listener
+            .with_context(|_| format!("Failed to copy socket {}/{:?}", name, self))?;
+        Ok(GrpcResource {
+            listener,
+            server: self.create_server()?,
+            health: self.health,
+            reflection: self.reflection,
+        })
+    }
+}
+
+/// A bound (blocking) listening socket.
+///
+/// This is the seed of the [`GrpcServer`] fragment. The user should not need to interact with
+/// this directly.
+#[derive(Debug)]
+pub enum StdListener {
+    /// A TCP socket.
+    Tcp(StdTcpListener),
+    /// A unix domain socket.
+    #[cfg(unix)]
+    Unix(StdUnixListener),
+}
+
+impl StdListener {
+    /// Creates another handle to the same socket.
+    pub fn try_clone(&self) -> Result<Self, IoError> {
+        match self {
+            StdListener::Tcp(listener) => listener.try_clone().map(StdListener::Tcp),
+            #[cfg(unix)]
+            StdListener::Unix(listener) => listener.try_clone().map(StdListener::Unix),
+        }
+    }
+}
+
+/// A gRPC server ready to be started.
+///
+/// This is what the [`GrpcServer`] fragment creates. The user should not need to interact with
+/// this directly.
+pub struct GrpcResource {
+    listener: StdListener,
+    server: Server,
+    health: bool,
+    reflection: bool,
+}
+
+impl Debug for GrpcResource {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("GrpcResource")
+            .field("listener", &self.listener)
+            .field("health", &self.health)
+            .field("reflection", &self.reflection)
+            .finish()
+    }
+}
+
+/// A unix domain stream, as accepted by a gRPC server.
+///
+/// The user should not need to interact with this directly.
+#[cfg(unix)]
+#[derive(Debug)]
+pub struct UnixStream(tokio::net::UnixStream);
+
+#[cfg(unix)]
+impl Connected for UnixStream {}
+
+#[cfg(unix)]
+impl AsyncRead for UnixStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &mut ReadBuf,
+    ) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.0).poll_read(ctx, buf)
+    }
+}
+
+#[cfg(unix)]
+impl AsyncWrite for UnixStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+        buf: &[u8],
+    ) -> Poll<Result<usize, IoError>> {
+        Pin::new(&mut self.0).poll_write(ctx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.0).poll_flush(ctx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<(), IoError>> {
+        Pin::new(&mut self.0).poll_shutdown(ctx)
+    }
+}
+
+/// The health status of the services, shared by all the servers of a [`TonicInstaller`].
+///
+/// The statuses are remembered, so servers started later (after a reload) report the same.
+/// Servers without the health service turned on ignore it.
+///
+/// Note that the empty service name denotes the status of the whole server. It is set to
+/// [`ServingStatus::Serving`] by default and to [`ServingStatus::NotServing`] when the
+/// application terminates.
+#[derive(Clone, Default)]
+pub struct HealthStatus(Arc<Mutex<HealthInner>>);
+
+#[derive(Default)]
+struct HealthInner {
+    statuses: HashMap<String, ServingStatus>,
+    reporters: Vec<HealthReporter>,
+}
+
+impl Debug for HealthStatus {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("HealthStatus").finish()
+    }
+}
+
+impl HealthStatus {
+    /// Sets the status of a service by its name.
+    pub async fn set_service_status(&self, service: &str, status: ServingStatus) {
+        let reporters = {
+            let mut inner = self.0.lock().unwrap();
+            inner.statuses.insert(service.to_owned(), status);
+            inner.reporters.clone()
+        };
+        for mut reporter in reporters {
+            reporter.set_service_status(service, status).await;
+        }
+    }
+
+    /// Sets the status of a service.
+    pub async fn set_status<S: NamedService>(&self, status: ServingStatus) {
+        self.set_service_status(S::NAME, status).await;
+    }
+
+    async fn attach(&self, mut reporter: HealthReporter) {
+        let statuses = {
+            let mut inner = self.0.lock().unwrap();
+            inner.reporters.push(reporter.clone());
+            inner.statuses.clone()
+        };
+        for (service, status) in statuses {
+            reporter.set_service_status(&service, status).await;
+        }
+    }
+}
+
+type ServeFuture = BoxFuture<'static, Result<(), AnyError>>;
+
+/// Additional services to add to a server, as configured.
+///
+/// The user should not need to interact with this directly.
+#[doc(hidden)]
+pub struct Extras {
+    health: Option<HealthStatus>,
+    reflection: Option<&'static [u8]>,
+}
+
+/// Things that can be turned into a running gRPC server.
+///
+/// This is implemented for tonic's [`Router`], which is what is returned by
+/// [`Server::add_service`]. The user should not need to interact with this directly.
+pub trait Services: Send + 'static {
+    #[doc(hidden)]
+    fn serve<I, IO>(
+        self,
+        extras: Extras,
+        incoming: I,
+        shutdown: oneshot::Receiver<()>,
+    ) -> ServeFuture
+    where
+        I: Stream<Item = Result<IO, IoError>> + Send + 'static,
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static;
+}
+
+impl<A, B> Services for Router<A, B>
+where
+    A: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    A::Future: Send + 'static,
+    A::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+    B: Service<http::Request<Body>, Response = http::Response<BoxBody>> + Clone + Send + 'static,
+    B::Future: Send + 'static,
+    B::Error: Into<Box<dyn std::error::Error + Send + Sync>> + Send,
+{
+    fn serve<I, IO>(
+        self,
+        extras: Extras,
+        incoming: I,
+        shutdown: oneshot::Receiver<()>,
+    ) -> ServeFuture
+    where
+        I: Stream<Item = Result<IO, IoError>> + Send + 'static,
+        IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    {
+        async move {
+            let health = match extras.health {
+                Some(status) => {
+                    let (reporter, service) = tonic_health::server::health_reporter();
+                    status.attach(reporter).await;
+                    Some(service)
+                }
+                None => None,
+            };
+            let reflection = match extras.reflection {
+                Some(descriptors) => Some(
+                    tonic_reflection::server::Builder::configure()
+                        .register_encoded_file_descriptor_set(descriptors)
+                        .build()
+                        .context("Failed to create gRPC reflection service")?,
+                ),
+                None => None,
+            };
+            // Either the shutdown signal or the handle going away stops the server.
+            let shutdown = shutdown.map(|_| ());
+            self.add_optional_service(health)
+                .add_optional_service(reflection)
+                .serve_with_incoming_shutdown(incoming, shutdown)
+                .await
+                .context("gRPC server failed")?;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+/// A running gRPC server.
+///
+/// This is the uninstall handle of [`TonicInstaller`]. Dropping it shuts the server down
+/// gracefully ‒ it stops accepting new connections and lets the requests in flight finish. The
+/// user should not need to interact with this directly.
+pub struct RunningServer(Arc<Mutex<Option<Sender<()>>>>);
+
+impl RunningServer {
+    fn shutdown(shutdown: &Mutex<Option<Sender<()>>>) {
+        if let Some(sender) = shutdown.lock().unwrap().take() {
+            // The server might be gone already, due to an error
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Debug for RunningServer {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("RunningServer").finish()
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        Self::shutdown(&self.0);
+    }
+}
+
+/// An [`Installer`] of [`GrpcServer`]s.
+///
+/// The closure passed to [`new`][TonicInstaller::new] adds the application's services to the
+/// server builder. It is called each time a server is (re)started. The servers run on the given
+/// runtime and are shut down when the application terminates.
+pub struct TonicInstaller<F> {
+    handle: Handle,
+    services: Arc<F>,
+    reflection: Option<&'static [u8]>,
+    health: HealthStatus,
+    running: Arc<Mutex<Vec<Weak<Mutex<Option<Sender<()>>>>>>>,
+}
+
+impl<F> Clone for TonicInstaller<F> {
+    fn clone(&self) -> Self {
+        TonicInstaller {
+            handle: self.handle.clone(),
+            services: Arc::clone(&self.services),
+            reflection: self.reflection,
+            health: self.health.clone(),
+            running: Arc::clone(&self.running),
+        }
+    }
+}
+
+impl<F> Debug for TonicInstaller<F> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("TonicInstaller")
+            .field("reflection", &self.reflection.is_some())
+            .finish()
+    }
+}
+
+impl<F, R> TonicInstaller<F>
+where
+    F: Fn(Server) -> R + Send + Sync + 'static,
+    R: Services,
+{
+    /// Creates the installer.
+    ///
+    /// The servers are spawned onto the runtime of the `handle`.
+    pub fn new(handle: Handle, services: F) -> Self {
+        TonicInstaller {
+            handle,
+            services: Arc::new(services),
+            reflection: None,
+            health: HealthStatus::default(),
+            running: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl<F> TonicInstaller<F> {
+    /// Provides the encoded file descriptor set for the reflection service.
+    ///
+    /// This is usually generated by `tonic-build` with the `file_descriptor_set_path` option and
+    /// included by `include_bytes!`. Servers with `reflection` turned on fail to start without
+    /// it.
+    pub fn with_reflection(self, descriptors: &'static [u8]) -> Self {
+        TonicInstaller {
+            reflection: Some(descriptors),
+            ..self
+        }
+    }
+
+    /// The health status reported by the servers.
+    pub fn health(&self) -> &HealthStatus {
+        &self.health
+    }
+
+    /// Shuts down all the servers installed through this installer.
+    pub fn shutdown(&self) {
+        let health = self.health.clone();
+        self.handle.spawn(async move {
+            health
+                .set_service_status("", ServingStatus::NotServing)
+                .await;
+        });
+        let running = self.running.lock().unwrap().drain(..).collect::<Vec<_>>();
+        for server in running.iter().filter_map(Weak::upgrade) {
+            RunningServer::shutdown(&server);
+        }
+    }
+}
+
+impl<F, R, O, C> Installer<GrpcResource, O, C> for TonicInstaller<F>
+where
+    F: Fn(Server) -> R + Send + Sync + 'static,
+    R: Services,
+{
+    type UninstallHandle = RunningServer;
+    fn install(&mut self, resource: GrpcResource, name: &'static str) -> RunningServer {
+        let GrpcResource {
+            listener,
+            server,
+            health,
+            reflection,
+        } = resource;
+        let (sender, receiver) = oneshot::channel();
+        let extras = Extras {
+            health: if health {
+                Some(self.health.clone())
+            } else {
+                None
+            },
+            reflection: if reflection { self.reflection } else { None },
+        };
+        let reflection_missing = reflection && self.reflection.is_none();
+        let services = Arc::clone(&self.services);
+        let serve = async move {
+            if reflection_missing {
+                return Err(AnyError::from(
+                    "gRPC reflection turned on, but no file descriptors provided",
+                ));
+            }
+            let router = services(server);
+            // std → tokio socket conversion, needs to happen inside the runtime
+            match listener {
+                StdListener::Tcp(listener) => {
+                    listener.set_nonblocking(true)?;
+                    let listener = tokio::net::TcpListener::from_std(listener)?;
+                    let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
+                    router.serve(extras, incoming, receiver).await
+                }
+                #[cfg(unix)]
+                StdListener::Unix(listener) => {
+                    listener.set_nonblocking(true)?;
+                    let listener = tokio::net::UnixListener::from_std(listener)?;
+                    let incoming = tokio_stream::wrappers::UnixListenerStream::new(listener);
+                    let incoming = futures::StreamExt::map(incoming, |s| s.map(UnixStream));
+                    router.serve(extras, incoming, receiver).await
+                }
+            }
+        };
+        debug!("Starting gRPC server {}", name);
+        self.handle.spawn(async move {
+            match serve.await {
+                Ok(()) => info!("gRPC server {} terminated", name),
+                Err(e) => error!("gRPC server {} failed: {}", name, e.display("; ")),
+            }
+        });
+        let shutdown = Arc::new(Mutex::new(Some(sender)));
+        let mut running = self.running.lock().unwrap();
+        running.retain(|server| server.strong_count() > 0);
+        running.push(Arc::downgrade(&shutdown));
+        trace!("{} gRPC servers running", running.len());
+        RunningServer(shutdown)
+    }
+    fn init<B: Extensible<Opts = O, Config = C, Ok = B>>(
+        &mut self,
+        builder: B,
+        _name: &'static str,
+    ) -> Result<B, AnyError>
+    where
+        B::Config: DeserializeOwned + Send + Sync + 'static,
+        B::Opts: StructOpt + Send + Sync + 'static,
+    {
+        let me = self.clone();
+        Ok(builder
+            .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+            .on_terminate(move || me.shutdown()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_server() -> GrpcServer {
+        GrpcServer {
+            tcp: Some(spirit_tokio::net::Listen::default()),
+            ..GrpcServer::default()
+        }
+    }
+
+    #[test]
+    fn no_socket() {
+        assert!(GrpcServer::default().create_listener().is_err());
+    }
+
+    #[test]
+    fn compare_transport() {
+        let server = tcp_server();
+        let limited = GrpcServer {
+            concurrency_limit: Some(10),
+            ..tcp_server()
+        };
+        assert_eq!(Comparison::Same, server.compare(&tcp_server()));
+        assert_eq!(Comparison::Similar, server.compare(&limited));
+        assert_eq!(
+            Comparison::Dissimilar,
+            server.compare(&GrpcServer::default())
+        );
+    }
+
+    #[test]
+    fn missing_tls_files() {
+        let server = GrpcServer {
+            tls: Some(GrpcTls {
+                cert: PathBuf::from("/does/not/exist.pem"),
+                key: PathBuf::from("/does/not/exist.key"),
+                client_ca: None,
+            }),
+            ..tcp_server()
+        };
+        assert!(server.create_server().is_err());
+    }
+}
@@ -0,0 +1,484 @@
+#![doc(
+    html_root_url = "https://docs.rs/spirit-upgrade/0.1.0/spirit_upgrade/",
+    test(attr(deny(warnings)))
+)]
+// Adopting a socket from a raw file descriptor can't be done without unsafe. It is allowed only in
+// the few places that need it.
+#![deny(unsafe_code)]
+#![warn(missing_docs)]
+
+//! Zero-downtime upgrades of the application binary.
+//!
+//! Reloading the configuration is fine, but sometimes the binary itself needs to be replaced. If
+//! the application is simply restarted, there's a short window when nobody listens on its ports
+//! and connections are refused.
+//!
+//! This crate works similar to the upgrades of nginx or haproxy. When the application receives
+//! `SIGUSR2` (configurable), it starts a new instance of the current executable with the same
+//! command line arguments and hands all the registered listening sockets over to it (as inherited
+//! file descriptors, described in environment variables). The new instance loads its configuration
+//! and, instead of binding new sockets, picks up the inherited ones. Once it signals it is ready,
+//! the old instance terminates in the usual graceful way ‒ it stops accepting new connections and
+//! lets the already accepted ones finish. If the new instance fails to start (or doesn't become
+//! ready in time), it is killed and the old instance just continues running.
+//!
+//! This needs cooperation from whoever creates the sockets. They are supposed to be created
+//! through [`inherit_or_create`], which either reuses an inherited socket or creates a new one and
+//! registers it for future handover. The sockets of
+//! [`spirit-tokio`](https://crates.io/crates/spirit-tokio) do this when its `upgrade` feature is
+//! enabled. As the pipelines cache their sockets (the seeds) between configuration reloads, the
+//! same socket is handed over again and again through all the future upgrades.
+//!
+//! The new instance must have the [`extension`][Upgrade::extension] registered too, otherwise it
+//! doesn't tell the old one it is ready and gets killed after a timeout.
+//!
+//! # Limitations
+//!
+//! * Only the sockets are handed over. Anything else (eg. a PID file or state kept in memory) is
+//!   up to the application.
+//! * Sockets are matched by their key (the kind of socket and the address) only. If the new
+//!   configuration has a different set of sockets, the unused inherited ones are closed and the
+//!   missing ones are created.
+//! * The new instance is a child of the old one. If the old one was started by something that
+//!   watches its PID (eg. systemd without `PIDFile`), that supervisor will consider the service
+//!   terminated. This is meant for services that run in the background on their own.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::net::TcpListener;
+//!
+//! use spirit::{AnyError, Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit_upgrade::Upgrade;
+//!
+//! fn listener() -> Result<TcpListener, AnyError> {
+//!     spirit_upgrade::inherit_or_create("tcp:127.0.0.1:0", || {
+//!         TcpListener::bind("127.0.0.1:0").map_err(AnyError::from)
+//!     })
+//! }
+//!
+//! Spirit::<Empty, Empty>::new()
+//!     .with(Upgrade::new().extension())
+//!     .run(|_| {
+//!         let _listener = listener()?;
+//!         // Accept connections here
+//!         Ok(())
+//!     });
+//! ```
+
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use err_context::prelude::*;
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{self, FcntlArg, FdFlag, OFlag};
+use nix::libc::{self, c_int};
+use nix::poll::{self, PollFd, PollFlags};
+use nix::sys::signal::{self, Signal};
+use nix::sys::socket::{self, SockAddr};
+use nix::unistd;
+use once_cell::sync::Lazy;
+use spirit::extension::{Extensible, Extension};
+use spirit::AnyError;
+
+/// The environment variable listing the handed-over sockets.
+///
+/// Each line is in the form `fd:key`.
+pub const FDS_ENV: &str = "SPIRIT_UPGRADE_FDS";
+
+/// The environment variable with the file descriptor the new instance signals its readiness on.
+pub const READY_ENV: &str = "SPIRIT_UPGRADE_READY";
+
+/// A socket either inherited or registered for handover.
+#[derive(Clone, Debug)]
+struct Socket {
+    key: String,
+    fd: RawFd,
+    addr: SockAddr,
+}
+
+impl Socket {
+    fn new(key: String, fd: RawFd) -> Option<Self> {
+        socket::getsockname(fd)
+            .ok()
+            .map(|addr| Socket { key, fd, addr })
+    }
+
+    /// Checks the file descriptor still holds the same socket.
+    ///
+    /// The registered sockets are not owned by us, so they may have been closed in the meantime
+    /// (and the file descriptor reused for something else).
+    fn alive(&self) -> bool {
+        socket::getsockname(self.fd)
+            .map(|addr| addr == self.addr)
+            .unwrap_or(false)
+    }
+}
+
+struct State {
+    inherited: Vec<Socket>,
+    registered: Vec<Socket>,
+}
+
+static STATE: Lazy<Mutex<State>> = Lazy::new(|| {
+    Mutex::new(State {
+        inherited: from_env(),
+        registered: Vec::new(),
+    })
+});
+
+fn state() -> MutexGuard<'static, State> {
+    STATE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn encode(sockets: &[(String, RawFd)]) -> String {
+    sockets
+        .iter()
+        .map(|(key, fd)| format!("{}:{}\n", fd, key))
+        .collect()
+}
+
+fn parse(value: &str) -> Vec<(RawFd, String)> {
+    value
+        .lines()
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let fd = parts.next()?.parse().ok();
+            let key = parts.next();
+            if fd.is_none() || key.is_none() {
+                warn!("Invalid inherited socket description {}", line);
+            }
+            Some((fd?, key?.to_owned()))
+        })
+        .collect()
+}
+
+fn from_env() -> Vec<Socket> {
+    let value = match env::var(FDS_ENV) {
+        Ok(value) => value,
+        Err(_) => return Vec::new(),
+    };
+    // Don't pass it on to whatever we start
+    env::remove_var(FDS_ENV);
+    parse(&value)
+        .into_iter()
+        .filter_map(|(fd, key)| {
+            let socket = Socket::new(key, fd);
+            match &socket {
+                Some(socket) => {
+                    debug!("Inherited socket {} as fd {}", socket.key, fd);
+                    if let Err(e) = fcntl::fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                        warn!("Failed to set close-on-exec on inherited fd {}: {}", fd, e);
+                    }
+                }
+                None => warn!("Inherited fd {} is not a socket, ignoring", fd),
+            }
+            socket
+        })
+        .collect()
+}
+
+/// Takes over a socket inherited from the previous instance, if there's one with the given key.
+///
+/// Each inherited socket can be taken only once. The caller is responsible for choosing keys that
+/// identify the kind of the socket as well as its address (eg. `tcp:[::]:80`), so it doesn't get
+/// a UDP socket as a TCP listener.
+///
+/// Usually, [`inherit_or_create`] is more convenient.
+pub fn inherit<S: FromRawFd>(key: &str) -> Option<S> {
+    let mut state = state();
+    let pos = state.inherited.iter().position(|s| s.key == key)?;
+    let socket = state.inherited.remove(pos);
+    debug!("Taking over inherited socket {}", key);
+    // The file descriptor was handed to us by the previous instance and it was checked to be a
+    // socket at startup. We remove it from the list, so it can have only one owner.
+    #[allow(unsafe_code)]
+    let result = unsafe { S::from_raw_fd(socket.fd) };
+    Some(result)
+}
+
+/// Registers a socket to be handed over to the new instance on upgrade.
+///
+/// The socket is not owned by the registry. If it gets closed, it is simply not handed over. The
+/// registration is forgotten once the file descriptor no longer holds the same socket.
+///
+/// Usually, [`inherit_or_create`] is more convenient.
+pub fn register<S: AsRawFd>(key: &str, socket: &S) {
+    let fd = socket.as_raw_fd();
+    match Socket::new(key.to_owned(), fd) {
+        Some(socket) => {
+            let mut state = state();
+            state.registered.retain(|s| s.fd != fd);
+            state.registered.push(socket);
+        }
+        None => warn!("Can't register {} for upgrade, it is not a socket", key),
+    }
+}
+
+/// Reuses an inherited socket or creates a new one, and registers it for future upgrades.
+///
+/// See [`inherit`] for the requirements on the key.
+pub fn inherit_or_create<S, F>(key: &str, create: F) -> Result<S, AnyError>
+where
+    S: AsRawFd + FromRawFd,
+    F: FnOnce() -> Result<S, AnyError>,
+{
+    let socket = match inherit(key) {
+        Some(socket) => {
+            info!("Reusing socket {} from the previous instance", key);
+            socket
+        }
+        None => create()?,
+    };
+    register(key, &socket);
+    Ok(socket)
+}
+
+/// Closes the inherited sockets nobody claimed.
+fn close_unused() {
+    let unused = mem::replace(&mut state().inherited, Vec::new());
+    for socket in unused {
+        info!(
+            "Closing unused socket {} from the previous instance",
+            socket.key
+        );
+        if let Err(e) = unistd::close(socket.fd) {
+            warn!("Failed to close fd {}: {}", socket.fd, e);
+        }
+    }
+}
+
+/// Tells the previous instance we are up and running, if there's one.
+fn notify_ready() {
+    let value = match env::var(READY_ENV) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    env::remove_var(READY_ENV);
+    match value.parse::<RawFd>() {
+        Ok(fd) => {
+            if let Err(e) = unistd::write(fd, b"R") {
+                warn!("Failed to notify the previous instance: {}", e);
+            }
+            let _ = unistd::close(fd);
+        }
+        Err(_) => warn!("Invalid {} value {}", READY_ENV, value),
+    }
+}
+
+/// Closes the file descriptor when dropped.
+struct Fd(RawFd);
+
+impl Drop for Fd {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.0);
+    }
+}
+
+fn wait_ready(fd: RawFd, timeout: Duration) -> Result<(), AnyError> {
+    let millis = timeout.as_millis().min(c_int::max_value() as u128) as c_int;
+    let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+    loop {
+        match poll::poll(&mut fds, millis) {
+            Ok(0) => {
+                return Err(
+                    format!("The new instance didn't become ready in {:?}", timeout).into(),
+                );
+            }
+            Ok(_) => break,
+            Err(nix::Error::Sys(Errno::EINTR)) => continue,
+            Err(e) => return Err(e.context("Failed to wait for the new instance").into()),
+        }
+    }
+    let mut buf = [0; 1];
+    let read = unistd::read(fd, &mut buf).context("Failed to wait for the new instance")?;
+    if read == 0 {
+        Err("The new instance exited before becoming ready".into())
+    } else {
+        Ok(())
+    }
+}
+
+/// The upgrade extension.
+///
+/// See the [crate documentation](index.html).
+#[derive(Clone, Debug)]
+pub struct Upgrade {
+    signal: c_int,
+    timeout: Duration,
+}
+
+impl Upgrade {
+    /// Creates the upgrade with default settings.
+    ///
+    /// It is triggered by `SIGUSR2` and waits up to 30 seconds for the new instance to become
+    /// ready.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the signal that triggers the upgrade.
+    pub fn signal(self, signal: c_int) -> Self {
+        Self { signal, ..self }
+    }
+
+    /// Sets how long to wait for the new instance to become ready.
+    ///
+    /// If it doesn't make it in time, it is killed and the current instance continues to run.
+    pub fn timeout(self, timeout: Duration) -> Self {
+        Self { timeout, ..self }
+    }
+
+    /// Starts the new instance, hands the sockets over and waits for it to become ready.
+    ///
+    /// This doesn't terminate the current instance. That's up to the caller (the
+    /// [`extension`][Upgrade::extension] does so).
+    pub fn upgrade(&self) -> Result<(), AnyError> {
+        let sockets = {
+            let mut state = state();
+            state.registered.retain(|socket| {
+                let alive = socket.alive();
+                if !alive {
+                    debug!("Socket {} is gone, not handing it over", socket.key);
+                }
+                alive
+            });
+            state
+                .registered
+                .iter()
+                .map(|socket| (socket.key.clone(), socket.fd))
+                .collect::<Vec<_>>()
+        };
+        let exe = env::current_exe().context("Can't find the current executable")?;
+        let (ready_read, ready_write) =
+            unistd::pipe2(OFlag::O_CLOEXEC).context("Failed to create readiness pipe")?;
+        let ready_read = Fd(ready_read);
+        let ready_write = Fd(ready_write);
+        let mut inherit = sockets.iter().map(|(_, fd)| *fd).collect::<Vec<_>>();
+        inherit.push(ready_write.0);
+
+        let mut command = Command::new(&exe);
+        command
+            .args(env::args_os().skip(1))
+            .env(FDS_ENV, encode(&sockets))
+            .env(READY_ENV, ready_write.0.to_string());
+        // Everything we have is close-on-exec (so the new instance doesn't get random junk), clear
+        // it only in the child for what is handed over. This runs between fork and exec, where
+        // only async-signal-safe functions may be called. fcntl is one of them and we don't
+        // allocate.
+        #[allow(unsafe_code)]
+        unsafe {
+            command.pre_exec(move || {
+                for fd in &inherit {
+                    fcntl::fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))
+                        .map_err(|_| io::Error::last_os_error())?;
+                }
+                Ok(())
+            });
+        }
+
+        info!(
+            "Starting new instance {} with {} sockets",
+            exe.display(),
+            sockets.len()
+        );
+        let mut child = command
+            .spawn()
+            .context("Failed to start the new instance")?;
+        // Our copy must be closed, so we notice if the child exits without writing into it
+        drop(ready_write);
+        match wait_ready(ready_read.0, self.timeout) {
+            Ok(()) => {
+                info!("New instance {} is ready", child.id());
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(e) = child.kill() {
+                    debug!("Failed to kill the new instance: {}", e);
+                }
+                let _ = child.wait();
+                Err(e)
+            }
+        }
+    }
+
+    /// The extension that can be registered into the [`Spirit`][spirit::Spirit].
+    ///
+    /// It both triggers the upgrade on the signal and, in the new instance, finishes it once the
+    /// configuration is loaded (and therefore the sockets are taken over).
+    pub fn extension<E>(self) -> impl Extension<E>
+    where
+        E: Extensible<Ok = E>,
+    {
+        move |e: E| {
+            let signal = self.signal;
+            let upgrade = move || match self.upgrade() {
+                Ok(()) => {
+                    info!("Handing over to the new instance, terminating");
+                    if let Err(e) = signal::kill(unistd::getpid(), Signal::SIGTERM) {
+                        error!("Failed to terminate after upgrade: {}", e);
+                    }
+                }
+                Err(e) => {
+                    spirit::log_error!(multi Error, "Upgrade failed, continuing to run" => e);
+                }
+            };
+            e.on_signal(signal, upgrade).run_before(|_| {
+                close_unused();
+                notify_ready();
+                Ok(())
+            })
+        }
+    }
+}
+
+impl Default for Upgrade {
+    fn default() -> Self {
+        Self {
+            signal: libc::SIGUSR2,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let sockets = vec![
+            ("tcp:[::]:80".to_owned(), 3),
+            ("unix:/tmp/a:b".to_owned(), 42),
+        ];
+        let parsed = parse(&encode(&sockets));
+        assert_eq!(
+            vec![
+                (3, "tcp:[::]:80".to_owned()),
+                (42, "unix:/tmp/a:b".to_owned()),
+            ],
+            parsed
+        );
+        assert!(parse("garbage\n:x\n").is_empty());
+    }
+
+    #[test]
+    fn registered_liveness() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = Socket::new("tcp:test".to_owned(), listener.as_raw_fd()).unwrap();
+        assert!(socket.alive());
+        drop(listener);
+        assert!(!socket.alive());
+    }
+}
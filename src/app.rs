@@ -31,6 +31,10 @@ use crate::AnyError;
 /// that that one handles and logs errors from the application startup as well as from its runtime.
 /// Here it is up to the caller to handle the startup errors.
 ///
+/// If the application was asked only to check its configuration (by the `--check-config` command
+/// line option), the configuration is already checked by the time this is created and running it
+/// only returns the outcome, without running any bodies. See [`check_only`][App::check_only].
+///
 /// # Examples
 ///
 /// ```rust
@@ -51,6 +55,7 @@ pub struct App<O, C> {
     spirit: Arc<Spirit<O, C>>,
     inner: InnerBody,
     wrapper: WrapBody,
+    checked: Option<Result<(), AnyError>>,
 }
 
 impl<O, C> App<O, C>
@@ -63,9 +68,30 @@ where
             spirit,
             inner,
             wrapper,
+            checked: None,
+        }
+    }
+
+    /// An application that only checked its configuration, with the outcome of the check.
+    pub(crate) fn checked(spirit: Arc<Spirit<O, C>>, result: Result<(), AnyError>) -> Self {
+        Self {
+            spirit,
+            inner: Box::new(|| Ok(())),
+            wrapper: Box::new(|inner: InnerBody| inner()),
+            checked: Some(result),
         }
     }
 
+    /// Was the application asked only to check its configuration?
+    ///
+    /// This is the case with the `--check-config` command line option. The configuration is
+    /// already checked and the [`run`][App::run] returns the outcome of the check, without
+    /// running the body. The spirit is not fully started in that case (there's no loaded
+    /// configuration and no background thread), so the caller should not start anything.
+    pub fn check_only(&self) -> bool {
+        self.checked.is_some()
+    }
+
     /// Access to the built spirit object.
     ///
     /// The object can be used to manipulate the runtime of the application, access the current
@@ -93,10 +119,17 @@ where
     /// application itself.
     ///
     /// Any errors are simply returned and it is up to the caller to handle them somehow.
+    ///
+    /// If the application was asked only to [check][App::check_only] its configuration, this
+    /// returns the outcome of the check and doesn't run anything.
     pub fn run<B>(self, body: B) -> Result<(), AnyError>
     where
         B: FnOnce() -> Result<(), AnyError> + Send + 'static,
     {
+        if let Some(result) = self.checked {
+            debug!("Only checking the configuration, not running bodies");
+            return result;
+        }
         debug!("Running bodies");
         let inner = self.inner;
         let inner = move || inner().and_then(|()| body());
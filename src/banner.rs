@@ -0,0 +1,179 @@
+//! A startup report.
+//!
+//! When looking at logs of a deployed service, the first question is usually "what exactly is
+//! running there?". The [`Banner`] extension logs a report answering that right before the
+//! application body starts:
+//!
+//! * The name and version of the application, possibly with the git hash it was built from.
+//! * The cargo features it was compiled with (as provided by the application).
+//! * The configuration files that were actually loaded, with their CRC-32 checksums (see
+//!   [`LoadedFile`]).
+//! * The effective user and group the application runs as.
+//! * The addresses the application listens on, as reported by the fragments creating the
+//!   sockets through [`listening`] (the ones in `spirit-tokio` do that). Ports chosen by the
+//!   OS are shown resolved.
+//!
+//! Everything is logged on the `info` level.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//!
+//! Spirit::<Empty, Empty>::new()
+//!     .with(
+//!         spirit::banner!()
+//!             .git_hash(option_env!("GIT_HASH").unwrap_or("unknown"))
+//!             .features(if cfg!(feature = "fast") { &["fast"][..] } else { &[] }),
+//!     )
+//!     .run(|_| Ok(()));
+//! ```
+
+use std::fmt::Display;
+use std::sync::{Mutex, PoisonError};
+
+use log::info;
+use nix::unistd;
+use once_cell::sync::Lazy;
+
+use crate::cfg_loader::LoadedFile;
+use crate::extension::{Extensible, Extension};
+use crate::AnyError;
+
+/// The addresses reported by [`listening`].
+///
+/// `None` when there's no [`Banner`] waiting for them, so they don't pile up needlessly.
+static LISTENING: Lazy<Mutex<Option<Vec<String>>>> = Lazy::new(|| Mutex::new(None));
+
+/// Reports an address the application listens on.
+///
+/// Meant for fragments creating listening sockets. The address is included in the startup report
+/// of the [`Banner`]. If there's no banner to print or it was printed already, this does nothing.
+pub fn listening<A: Display>(addr: A) {
+    if let Some(addrs) = LISTENING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .as_mut()
+    {
+        addrs.push(addr.to_string());
+    }
+}
+
+/// The extension logging the startup report.
+///
+/// See the [module documentation](index.html). Usually created through the [`banner`] macro.
+///
+/// [`banner`]: crate::banner!
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Banner {
+    name: String,
+    version: String,
+    git_hash: Option<String>,
+    features: Vec<String>,
+}
+
+impl Banner {
+    /// Creates the banner with the application name and version.
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Self {
+        Self {
+            name: name.into(),
+            version: version.into(),
+            git_hash: None,
+            features: Vec::new(),
+        }
+    }
+
+    /// Sets the git hash the application was built from.
+    pub fn git_hash<H: Into<String>>(self, hash: H) -> Self {
+        Self {
+            git_hash: Some(hash.into()),
+            ..self
+        }
+    }
+
+    /// Sets the list of cargo features the application was built with.
+    ///
+    /// The library has no way to find out the features of the application, so they need to be
+    /// listed (usually with the help of `cfg!(feature = "...")`).
+    pub fn features<I, F>(self, features: I) -> Self
+    where
+        I: IntoIterator<Item = F>,
+        F: ToString,
+    {
+        Self {
+            features: features.into_iter().map(|f| f.to_string()).collect(),
+            ..self
+        }
+    }
+
+    fn log(&self, files: &[LoadedFile], addrs: &[String]) {
+        match &self.git_hash {
+            Some(hash) => info!("Starting {} {} (git {})", self.name, self.version, hash),
+            None => info!("Starting {} {}", self.name, self.version),
+        }
+        if !self.features.is_empty() {
+            info!("Features: {}", self.features.join(", "));
+        }
+        info!(
+            "Running as uid {}, gid {}",
+            unistd::geteuid(),
+            unistd::getegid()
+        );
+        if files.is_empty() {
+            info!("No configuration files loaded");
+        }
+        for file in files {
+            info!(
+                "Configuration file {} (crc32 {:08x})",
+                file.path.display(),
+                file.checksum
+            );
+        }
+        for addr in addrs {
+            info!("Listening on {}", addr);
+        }
+    }
+}
+
+impl<E: Extensible<Ok = E>> Extension<E> for Banner {
+    fn apply(self, ext: E) -> Result<E, AnyError> {
+        LISTENING
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_or_insert_with(Vec::new);
+        ext.run_before(move |spirit| {
+            let addrs = LISTENING
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .take()
+                .unwrap_or_default();
+            self.log(&spirit.loaded_config_files(), &addrs);
+            Ok(())
+        })
+    }
+}
+
+/// Creates a [`Banner`] with the name and version of the calling crate.
+///
+/// The values are taken from the `CARGO_PKG_NAME` and `CARGO_PKG_VERSION` at the place of the
+/// macro invocation.
+#[macro_export]
+macro_rules! banner {
+    () => {
+        $crate::banner::Banner::new(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn construct() {
+        let banner = banner!().git_hash("abcd").features(&["a", "b"]);
+        assert_eq!("spirit", banner.name);
+        assert_eq!(Some("abcd".to_owned()), banner.git_hash);
+        assert_eq!(vec!["a".to_owned(), "b".to_owned()], banner.features);
+    }
+}
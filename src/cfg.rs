@@ -0,0 +1,667 @@
+//! Human-friendly units for configuration values.
+//!
+//! Configuration is written by humans, who prefer `30s` over `30000` or `10MiB` over `10485760`.
+//! The types here deserialize such values (and serialize back to them), so all the time, size and
+//! address knobs of an application (and the spirit fragments) can be written the same way.
+//! Network ranges (like `10.0.0.0/8`) are covered by [`Cidr`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::cfg::{ByteSize, Duration};
+//!
+//! #[derive(Deserialize)]
+//! struct Cache {
+//!     ttl: Duration,
+//!     max_size: ByteSize,
+//! }
+//!
+//! let cache: Cache = toml::from_str(r#"
+//!     ttl = "2h 30m"
+//!     max_size = "10MiB"
+//! "#).unwrap();
+//! assert_eq!(std::time::Duration::from_secs(9000), *cache.ttl);
+//! assert_eq!(10 * 1024 * 1024, cache.max_size.bytes());
+//! ```
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration as StdDuration;
+
+use serde::de::{Deserializer, Error as DeError, Unexpected, Visitor};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::schema::{Schema, Schematic};
+use crate::AnyError;
+
+/// A time duration.
+///
+/// Deserialized from a human-readable specification, like `50ms`, `2h` or `1day 12h` (see the
+/// [`humantime`](https://docs.rs/humantime) crate for the details). Dereferences to the
+/// [`std::time::Duration`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Duration(pub StdDuration);
+
+impl Deref for Duration {
+    type Target = StdDuration;
+    fn deref(&self) -> &StdDuration {
+        &self.0
+    }
+}
+
+impl From<StdDuration> for Duration {
+    fn from(duration: StdDuration) -> Self {
+        Duration(duration)
+    }
+}
+
+impl From<Duration> for StdDuration {
+    fn from(duration: Duration) -> Self {
+        duration.0
+    }
+}
+
+impl Display for Duration {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}", humantime::format_duration(self.0))
+    }
+}
+
+impl FromStr for Duration {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, AnyError> {
+        Ok(Duration(humantime::parse_duration(s)?))
+    }
+}
+
+impl<'de> Deserialize<'de> for Duration {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse()
+            .map_err(|_| DeError::invalid_value(Unexpected::Str(&s), &"human readable duration"))
+    }
+}
+
+impl Serialize for Duration {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "cfg-help")]
+impl structdoc::StructDoc for Duration {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("time duration")
+    }
+}
+
+impl Schematic for Duration {
+    fn schema() -> Schema {
+        Schema::string().description("time duration")
+    }
+}
+
+const UNITS: &[(&str, u64)] = &[
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+    ("TB", 1_000_000_000_000),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("kB", 1_000),
+    ("KB", 1_000),
+    ("B", 1),
+];
+
+/// A size in bytes.
+///
+/// Deserialized either from a plain number of bytes or a string with a unit, like `512B`, `10kB`
+/// (decimal units, powers of 1000) or `10MiB` (binary units, powers of 1024). Serialized with the
+/// largest binary unit that represents the value exactly.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    /// The number of bytes.
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+
+    /// The number of bytes, as `usize` (saturating if it doesn't fit).
+    pub fn as_usize(self) -> usize {
+        if self.0 > usize::max_value() as u64 {
+            usize::max_value()
+        } else {
+            self.0 as usize
+        }
+    }
+}
+
+impl From<u64> for ByteSize {
+    fn from(bytes: u64) -> Self {
+        ByteSize(bytes)
+    }
+}
+
+impl Display for ByteSize {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let binary = UNITS
+            .iter()
+            .filter(|(name, _)| name.ends_with("iB"))
+            .find(|(_, size)| self.0 != 0 && self.0 % size == 0);
+        match binary {
+            Some((name, size)) => write!(fmt, "{}{}", self.0 / size, name),
+            None => write!(fmt, "{}B", self.0),
+        }
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, AnyError> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or_else(|| s.len());
+        let (num, unit) = s.split_at(split);
+        let unit = unit.trim();
+        let multiplier = if unit.is_empty() {
+            1
+        } else {
+            UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .map(|(_, size)| *size)
+                .ok_or_else(|| format!("Unknown size unit {}", unit))?
+        };
+        let size = if num.contains('.') {
+            let num: f64 = num.parse()?;
+            let size = num * multiplier as f64;
+            if size > u64::max_value() as f64 {
+                return Err(format!("Size {} is too large", s).into());
+            }
+            size.round() as u64
+        } else {
+            let num: u64 = num.parse()?;
+            num.checked_mul(multiplier)
+                .ok_or_else(|| format!("Size {} is too large", s))?
+        };
+        Ok(ByteSize(size))
+    }
+}
+
+struct ByteSizeVisitor;
+
+impl<'de> Visitor<'de> for ByteSizeVisitor {
+    type Value = ByteSize;
+
+    fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "a number of bytes or a size with unit, like 10MiB")
+    }
+
+    fn visit_u64<E: DeError>(self, v: u64) -> Result<ByteSize, E> {
+        Ok(ByteSize(v))
+    }
+
+    fn visit_i64<E: DeError>(self, v: i64) -> Result<ByteSize, E> {
+        if v < 0 {
+            Err(E::invalid_value(Unexpected::Signed(v), &self))
+        } else {
+            Ok(ByteSize(v as u64))
+        }
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<ByteSize, E> {
+        v.parse()
+            .map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(ByteSizeVisitor)
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "cfg-help")]
+impl structdoc::StructDoc for ByteSize {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("size (eg. 10MiB)")
+    }
+}
+
+impl Schematic for ByteSize {
+    fn schema() -> Schema {
+        Schema::any_of(vec![u64::schema(), Schema::string()]).description("size (eg. 10MiB)")
+    }
+}
+
+/// Maximum length of a network interface name (`IFNAMSIZ` without the terminating null byte).
+const MAX_INTERFACE_NAME: usize = 15;
+
+/// An address to listen on.
+///
+/// Deserialized from a string (or a plain number) in one of these forms:
+///
+/// * `0.0.0.0:80` or `[::1]:8080`: An IP address and a port.
+/// * `8080`: Just a port, listening on all the interfaces (`[::]:8080`).
+/// * `eth0:80`: A port on the named network interface. Binding to an interface is supported only
+///   on Linux.
+/// * `unix:/run/app.sock`: A unix domain socket.
+///
+/// Host names are not resolved, as it is rarely what one wants when listening.
+///
+/// Which of the forms are supported is up to the fragment using it (eg. a TCP fragment won't
+/// listen on a unix domain socket).
+#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum ListenAddr {
+    /// An IP address and a port.
+    Inet {
+        /// The IP address of the local interface.
+        host: IpAddr,
+        /// The port.
+        port: u16,
+    },
+    /// A port on a network interface, specified by its name.
+    Interface {
+        /// The name of the interface (eg. `eth0`).
+        name: String,
+        /// The port.
+        port: u16,
+    },
+    /// A unix domain socket.
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    /// The port, if the address has one.
+    pub fn port(&self) -> Option<u16> {
+        match self {
+            ListenAddr::Inet { port, .. } | ListenAddr::Interface { port, .. } => Some(*port),
+            ListenAddr::Unix(_) => None,
+        }
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            ListenAddr::Inet { host, port } => write!(fmt, "{}", SocketAddr::new(*host, *port)),
+            ListenAddr::Interface { name, port } => write!(fmt, "{}:{}", name, port),
+            ListenAddr::Unix(path) => write!(fmt, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, AnyError> {
+        let s = s.trim();
+        if s.starts_with("unix:") {
+            let path = &s["unix:".len()..];
+            if path.is_empty() {
+                return Err("Missing path of the unix domain socket".into());
+            }
+            return Ok(ListenAddr::Unix(path.into()));
+        }
+        if let Ok(port) = s.parse() {
+            let host = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+            return Ok(ListenAddr::Inet { host, port });
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(ListenAddr::Inet {
+                host: addr.ip(),
+                port: addr.port(),
+            });
+        }
+        let colon = s
+            .rfind(':')
+            .ok_or_else(|| format!("Missing port in address {}", s))?;
+        let (name, port) = (&s[..colon], &s[colon + 1..]);
+        let port = port
+            .parse()
+            .map_err(|_| format!("Invalid port in address {}", s))?;
+        if name.parse::<IpAddr>().is_ok() {
+            return Err(format!("IPv6 address needs to be in brackets in {}", s).into());
+        }
+        if name == "localhost" {
+            return Err("Host names are not resolved, use 127.0.0.1 or [::1]".into());
+        }
+        let valid = !name.is_empty()
+            && name.len() <= MAX_INTERFACE_NAME
+            && !name.contains(|c: char| c == '/' || c == ':' || c == '[' || c.is_whitespace());
+        if !valid {
+            return Err(format!("Invalid address {}", s).into());
+        }
+        let name = name.to_owned();
+        Ok(ListenAddr::Interface { name, port })
+    }
+}
+
+struct ListenAddrVisitor;
+
+impl<'de> Visitor<'de> for ListenAddrVisitor {
+    type Value = ListenAddr;
+
+    fn expecting(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(
+            fmt,
+            "an address to listen on, like [::]:80, 80, eth0:80 or unix:/path"
+        )
+    }
+
+    fn visit_u64<E: DeError>(self, v: u64) -> Result<ListenAddr, E> {
+        if v > u64::from(u16::max_value()) {
+            Err(E::invalid_value(Unexpected::Unsigned(v), &self))
+        } else {
+            let host = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+            Ok(ListenAddr::Inet {
+                host,
+                port: v as u16,
+            })
+        }
+    }
+
+    fn visit_i64<E: DeError>(self, v: i64) -> Result<ListenAddr, E> {
+        if v < 0 {
+            Err(E::invalid_value(Unexpected::Signed(v), &self))
+        } else {
+            self.visit_u64(v as u64)
+        }
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<ListenAddr, E> {
+        v.parse()
+            .map_err(|e: AnyError| E::custom(format!("{}: {}", v, e)))
+    }
+}
+
+impl<'de> Deserialize<'de> for ListenAddr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(ListenAddrVisitor)
+    }
+}
+
+impl Serialize for ListenAddr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "cfg-help")]
+impl structdoc::StructDoc for ListenAddr {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("address (eg. [::]:80, 80, eth0:80 or unix:/path)")
+    }
+}
+
+impl Schematic for ListenAddr {
+    fn schema() -> Schema {
+        Schema::any_of(vec![u16::schema(), Schema::string()])
+            .description("address (eg. [::]:80, 80, eth0:80 or unix:/path)")
+    }
+}
+
+/// A range of IP addresses, in the CIDR notation.
+///
+/// Written as the address and the length of the prefix, like `10.0.0.0/8` or `2001:db8::/32`. A
+/// plain address (without the prefix length) is a range of that single address.
+///
+/// IPv4 ranges also contain the IPv4-mapped IPv6 addresses (like `::ffff:10.0.0.1`), as these are
+/// what sockets listening on both IP families see.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+fn addr_bits(addr: IpAddr) -> (u128, u8) {
+    match addr {
+        IpAddr::V4(addr) => (u128::from(u32::from(addr)), 32),
+        IpAddr::V6(addr) => (u128::from(addr), 128),
+    }
+}
+
+fn unmap(addr: IpAddr) -> IpAddr {
+    match addr {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, hi, lo] => {
+                IpAddr::V4(Ipv4Addr::from((u32::from(hi) << 16) | u32::from(lo)))
+            }
+            _ => addr,
+        },
+        addr => addr,
+    }
+}
+
+impl Cidr {
+    /// Creates the range from its address and the prefix length.
+    ///
+    /// Fails if the prefix is longer than the address.
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, AnyError> {
+        let (_, width) = addr_bits(addr);
+        if prefix > width {
+            return Err(format!("Prefix /{} is too long for {}", prefix, addr).into());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+
+    /// The address of the range.
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// The length of the prefix, in bits.
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Checks if the address is in the range.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        let (net, width) = addr_bits(self.addr);
+        let (addr, addr_width) = addr_bits(unmap(addr));
+        if width != addr_width {
+            return false;
+        }
+        if self.prefix == 0 {
+            return true;
+        }
+        let shift = width - self.prefix;
+        net >> shift == addr >> shift
+    }
+}
+
+impl Display for Cidr {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, AnyError> {
+        let s = s.trim();
+        let mut parts = s.splitn(2, '/');
+        let addr: IpAddr = parts.next().unwrap_or_default().parse()?;
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse()?,
+            None => addr_bits(addr).1,
+        };
+        Cidr::new(addr, prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        s.parse()
+            .map_err(|e: AnyError| D::Error::custom(format!("{}: {}", s, e)))
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "cfg-help")]
+impl structdoc::StructDoc for Cidr {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("network range (eg. 10.0.0.0/8)")
+    }
+}
+
+impl Schematic for Cidr {
+    fn schema() -> Schema {
+        Schema::string().description("network range (eg. 10.0.0.0/8)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+    struct Cfg {
+        duration: Duration,
+        size: ByteSize,
+        listen: ListenAddr,
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(
+            StdDuration::from_millis(50),
+            *"50ms".parse::<Duration>().unwrap()
+        );
+        assert_eq!(
+            StdDuration::from_secs(7200),
+            *"2h".parse::<Duration>().unwrap()
+        );
+        assert!("50 parsecs".parse::<Duration>().is_err());
+        assert_eq!("1h 30m", Duration(StdDuration::from_secs(5400)).to_string());
+    }
+
+    #[test]
+    fn sizes() {
+        let parse = |s: &str| s.parse::<ByteSize>().unwrap().bytes();
+        assert_eq!(512, parse("512"));
+        assert_eq!(512, parse("512B"));
+        assert_eq!(10_000, parse("10kB"));
+        assert_eq!(10 * 1024 * 1024, parse("10MiB"));
+        assert_eq!(10 * 1024 * 1024, parse("10 MiB"));
+        assert_eq!(1536, parse("1.5KiB"));
+        assert!("10 parsecs".parse::<ByteSize>().is_err());
+        assert!("100000TiB".parse::<ByteSize>().is_err());
+        assert!("".parse::<ByteSize>().is_err());
+
+        assert_eq!("10MiB", ByteSize(10 * 1024 * 1024).to_string());
+        assert_eq!("1000B", ByteSize(1000).to_string());
+        assert_eq!("0B", ByteSize(0).to_string());
+    }
+
+    #[test]
+    fn listen_addrs() {
+        let parse = |s: &str| s.parse::<ListenAddr>().unwrap();
+        let inet = |s: &str| {
+            let addr: SocketAddr = s.parse().unwrap();
+            ListenAddr::Inet {
+                host: addr.ip(),
+                port: addr.port(),
+            }
+        };
+        assert_eq!(inet("0.0.0.0:80"), parse("0.0.0.0:80"));
+        assert_eq!(inet("[::1]:8080"), parse("[::1]:8080"));
+        assert_eq!(inet("[::]:8080"), parse("8080"));
+        assert_eq!(
+            ListenAddr::Interface {
+                name: "eth0.100".to_owned(),
+                port: 80,
+            },
+            parse("eth0.100:80")
+        );
+        assert_eq!(
+            ListenAddr::Unix("/run/app.sock".into()),
+            parse("unix:/run/app.sock")
+        );
+        for invalid in &[
+            "",
+            "unix:",
+            "eth0",
+            "eth0:http",
+            "::1:80",
+            "localhost:80",
+            ":80",
+            "a-very-long-interface-name:80",
+            "70000",
+            "[::1]:70000",
+        ] {
+            assert!(invalid.parse::<ListenAddr>().is_err(), "{}", invalid);
+        }
+
+        for addr in &["0.0.0.0:80", "[::1]:8080", "eth0:80", "unix:/run/app.sock"] {
+            assert_eq!(*addr, parse(addr).to_string());
+        }
+        assert_eq!("[::]:8080", parse("8080").to_string());
+        assert_eq!(Some(80), parse("eth0:80").port());
+        assert_eq!(None, parse("unix:/run/app.sock").port());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let cfg: Cfg = toml::from_str("duration = \"30s\"\nsize = 1024\nlisten = 8080").unwrap();
+        assert_eq!(
+            Cfg {
+                duration: Duration(StdDuration::from_secs(30)),
+                size: ByteSize(1024),
+                listen: ListenAddr::Inet {
+                    host: IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                    port: 8080,
+                },
+            },
+            cfg
+        );
+        let serialized = toml::to_string(&cfg).unwrap();
+        assert_eq!(
+            "duration = \"30s\"\nsize = \"1KiB\"\nlisten = \"[::]:8080\"\n",
+            serialized
+        );
+        assert_eq!(cfg, toml::from_str(&serialized).unwrap());
+    }
+
+    #[test]
+    fn cidrs() {
+        let parse = |s: &str| s.parse::<Cidr>().unwrap();
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let private = parse("10.0.0.0/8");
+        assert!(private.contains(ip("10.1.2.3")));
+        assert!(private.contains(ip("::ffff:10.1.2.3")));
+        assert!(!private.contains(ip("11.0.0.1")));
+        assert!(!private.contains(ip("::a00:1")));
+        let doc = parse("2001:db8::/32");
+        assert!(doc.contains(ip("2001:db8:1::1")));
+        assert!(!doc.contains(ip("2001:db9::1")));
+        assert!(!doc.contains(ip("10.0.0.1")));
+        assert!(parse("0.0.0.0/0").contains(ip("192.0.2.1")));
+        assert!(parse("::/0").contains(ip("::1")));
+        assert!(parse("::1").contains(ip("::1")));
+        assert!(!parse("::1").contains(ip("::2")));
+        assert_eq!("192.0.2.1/32", parse("192.0.2.1").to_string());
+        for invalid in &["", "10.0.0.0/33", "::/129", "10.0.0.0/x", "localhost/8"] {
+            assert!(invalid.parse::<Cidr>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -20,6 +20,40 @@
 //! 4. Load (even as many times as needed) the configuration using
 //!    [`load`][crate::cfg_loader::Loader::load].
 //!
+//! A configuration file may describe variations for different environments in profiles ‒
+//! sections like `[profile.prod]`. The one selected by the `--profile` command line option (or the
+//! `SPIRIT_PROFILE` environment variable) is merged over the rest of the configuration (but below
+//! the environment and command line overrides). The `profile` section itself is not passed to the
+//! application.
+//!
+//! Sources other than files (eg. a remote configuration service) can be merged in as
+//! [layers][ConfigBuilder::config_layer]. Encrypted files and values can be decrypted during the
+//! load by a [decryptor][ConfigBuilder::config_decryptor].
+//!
+//! With [references][ConfigBuilder::config_references] turned on, string values may reference
+//! other keys of the configuration after combining all the sources, so repeated values are
+//! defined only once:
+//!
+//! ```toml
+//! host = "example.com"
+//!
+//! [ports]
+//! api = 8080
+//!
+//! [listen]
+//! port = "${ports.api}"
+//! url = "https://${host}:${ports.api}/"
+//! ```
+//!
+//! A value consisting of a single reference takes the referenced value including its type (so
+//! `listen.port` above is a number), otherwise the referenced values are formatted into the
+//! string. Only scalar values (strings, numbers, booleans) can be referenced. References may be
+//! chained, but not cyclic. A literal `${` is written as `$${`. The references are resolved
+//! before decrypting the values, so the decrypted secrets are left as they are.
+//!
+//! Then variables describing the available resources (like `${spirit.cpus}`) are replaced in the
+//! string values. See the [`cgroup`][crate::cgroup] module.
+//!
 //! # Examples
 //!
 //! ```rust
@@ -43,12 +77,16 @@
 //! ```
 
 use std::collections::{HashMap, HashSet};
+use std::env;
 use std::error::Error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
 use std::path::{Path, PathBuf};
 
-use config_spirit_fork::{Config, Environment, File, FileFormat};
+use config_spirit_fork::{
+    Config, ConfigError, Environment, File, FileFormat, Source, Value as ConfigValue,
+};
 use err_context::prelude::*;
 use fallible_iterator::FallibleIterator;
 use log::{debug, trace, warn};
@@ -58,6 +96,8 @@ use structopt::clap::App;
 use structopt::StructOpt;
 use toml::Value;
 
+use crate::cgroup::{self, Interpolated};
+use crate::validation::Invalid;
 use crate::AnyError;
 
 #[derive(Default, StructOpt)]
@@ -71,6 +111,18 @@ struct CommonOpts {
     )]
     config_overrides: Vec<(String, String)>,
 
+    /// Only check the configuration can be loaded and used, then exit.
+    #[structopt(long = "check-config")]
+    check_config: bool,
+
+    /// Like --check-config, but print a JSON report of the problems to the standard output.
+    #[structopt(long = "check-config-json")]
+    check_config_json: bool,
+
+    /// Select a configuration profile (the [profile.<name>] section overlaid over the rest).
+    #[structopt(long = "profile")]
+    profile: Option<String>,
+
     /// Configuration files or directories to load.
     #[structopt(parse(from_os_str = crate::utils::absolute_from_os_str))]
     configs: Vec<PathBuf>,
@@ -333,6 +385,46 @@ pub trait ConfigBuilder: Sized {
     /// [`config_exts`](#method.config_exts).
     fn config_filter<F: FnMut(&Path) -> bool + Send + 'static>(self, filter: F) -> Self;
 
+    /// Adds a layer of configuration coming from somewhere else than files.
+    ///
+    /// The layer is called on every load of the configuration and returns a list of named
+    /// documents. The format of each document is decided by the extension of its name, the same
+    /// way as with files (TOML is used if the extension isn't recognized). The documents are merged
+    /// after the configuration files, but before the environment and command line overrides.
+    /// Multiple layers are merged in the order they were added.
+    ///
+    /// The names also appear in [`loaded_files`][Loader::loaded_files].
+    ///
+    /// This is meant for integrations with configuration services (see `spirit-k8s` for an
+    /// example).
+    fn config_layer<F>(self, layer: F) -> Self
+    where
+        F: FnMut() -> Result<Vec<(String, String)>, AnyError> + Send + 'static;
+
+    /// Sets the decryptor of encrypted configuration.
+    ///
+    /// With a decryptor set, the encrypted parts of the configuration are decrypted during the
+    /// load, so secrets can be stored (and committed) in the configuration files safely:
+    ///
+    /// * Whole files (including ones found in directories) with the `.enc` extension. The format
+    ///   is decided by the rest of the name (eg. `secrets.toml.enc`), and the decryption happens
+    ///   before the file is parsed. The [directory filter][ConfigBuilder::config_filter] also sees
+    ///   the name without the `.enc`.
+    /// * String values starting with `enc:`, followed by base64 of the encrypted data. These are
+    ///   replaced by the decrypted content after all the sources are merged.
+    ///
+    /// Without a decryptor, these are not treated specially. See `spirit-crypt` for
+    /// implementations using age or GPG.
+    fn config_decryptor<D: Decryptor + 'static>(self, decryptor: D) -> Self;
+
+    /// Turns on resolution of references to other keys in the string values.
+    ///
+    /// When turned on, `${key}` in a string value is replaced by the value of the (dotted) key
+    /// and `$${` by a literal `${`. See the [module documentation][crate::cfg_loader].
+    ///
+    /// The default is false, `${` is not treated specially.
+    fn config_references(self, resolve: bool) -> Self;
+
     /// Sets if warning should be produced for each unused configuration key.
     ///
     /// If set, a warning message is produced upon loading a configuration for each unused key.
@@ -363,11 +455,60 @@ impl<C: ConfigBuilder, Error> ConfigBuilder for Result<C, Error> {
         self.map(|c| c.config_filter(filter))
     }
 
+    fn config_layer<F>(self, layer: F) -> Self
+    where
+        F: FnMut() -> Result<Vec<(String, String)>, AnyError> + Send + 'static,
+    {
+        self.map(|c| c.config_layer(layer))
+    }
+
+    fn config_decryptor<D: Decryptor + 'static>(self, decryptor: D) -> Self {
+        self.map(|c| c.config_decryptor(decryptor))
+    }
+
+    fn config_references(self, resolve: bool) -> Self {
+        self.map(|c| c.config_references(resolve))
+    }
+
     fn warn_on_unused(self, warn: bool) -> Self {
         self.map(|c| c.warn_on_unused(warn))
     }
 }
 
+/// The section containing the configuration profiles.
+const PROFILE_KEY: &str = "profile";
+
+/// The environment variable selecting the profile if it's not set on the command line.
+const PROFILE_ENV: &str = "SPIRIT_PROFILE";
+
+/// The prefix of encrypted string values.
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+/// The start of a reference to another configuration key.
+const REF_START: &str = "${";
+
+/// The escape of a literal [`REF_START`].
+const REF_ESCAPE: char = '$';
+
+/// Decryption of encrypted configuration.
+///
+/// See [`config_decryptor`][ConfigBuilder::config_decryptor]. Implemented for closures too.
+pub trait Decryptor: Send {
+    /// Decrypts a whole file or a single value.
+    fn decrypt(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, AnyError>;
+}
+
+impl<F> Decryptor for F
+where
+    F: FnMut(&[u8]) -> Result<Vec<u8>, AnyError> + Send,
+{
+    fn decrypt(&mut self, encrypted: &[u8]) -> Result<Vec<u8>, AnyError> {
+        self(encrypted)
+    }
+}
+
+type Layer = Box<dyn FnMut() -> Result<Vec<(String, String)>, AnyError> + Send>;
+
 /// A builder for the [`Loader`].
 ///
 /// See the [module documentation][crate::cfg_loader] for details about the use.
@@ -376,7 +517,28 @@ pub struct Builder {
     defaults: Option<String>,
     env: Option<String>,
     filter: Box<dyn FnMut(&Path) -> bool + Send>,
+    layers: Vec<Layer>,
+    decryptor: Option<Box<dyn Decryptor>>,
+    references: bool,
     warn_on_unused: bool,
+    app_version: Option<String>,
+    components: Vec<(String, String)>,
+}
+
+/// The version texts for the command line.
+struct Versions {
+    app: Option<String>,
+    long: String,
+}
+
+impl Versions {
+    fn apply<'a, 'b>(&'b self, app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app.long_version(self.long.as_str());
+        match &self.app {
+            Some(version) => app.version(version.as_str()),
+            None => app,
+        }
+    }
 }
 
 impl Default for Builder {
@@ -393,7 +555,61 @@ impl Builder {
             defaults: None,
             env: None,
             filter: Box::new(|_| false),
+            layers: Vec::new(),
+            decryptor: None,
+            references: false,
             warn_on_unused: true,
+            app_version: None,
+            components: vec![(
+                env!("CARGO_PKG_NAME").to_owned(),
+                env!("CARGO_PKG_VERSION").to_owned(),
+            )],
+        }
+    }
+
+    /// Sets the version of the application shown by `--version`.
+    ///
+    /// By default, the version from the command line options is used (that's the version of the
+    /// crate deriving the [`StructOpt`]).
+    pub fn app_version<V: Into<String>>(self, version: V) -> Self {
+        Self {
+            app_version: Some(version.into()),
+            ..self
+        }
+    }
+
+    /// Registers a component (usually a spirit extension crate) and its version.
+    ///
+    /// The components are listed by `--version` (but not the short `-V`) after the version of the
+    /// application, so bug reports can identify the whole stack. Registering the same component
+    /// again replaces the version.
+    pub fn component<N: Into<String>, V: Into<String>>(mut self, name: N, version: V) -> Self {
+        let name = name.into();
+        let version = version.into();
+        match self.components.iter_mut().find(|(n, _)| *n == name) {
+            Some(component) => component.1 = version,
+            None => self.components.push((name, version)),
+        }
+        self
+    }
+
+    fn versions<O: StructOpt>(&self) -> Versions {
+        let app = self.app_version.clone().unwrap_or_else(|| {
+            // Clap has no way to get the version directly, it only writes it together with the
+            // name of the application.
+            let mut buf = Vec::new();
+            let _ = O::clap().write_version(&mut buf);
+            let version = String::from_utf8_lossy(&buf);
+            version.splitn(2, ' ').nth(1).unwrap_or_default().to_owned()
+        });
+        let mut long = app;
+        long.push('\n');
+        for (name, version) in &self.components {
+            long.push_str(&format!("\n{} {}", name, version));
+        }
+        Versions {
+            app: self.app_version.clone(),
+            long,
         }
     }
 
@@ -405,14 +621,26 @@ impl Builder {
             opts.configs
         };
         trace!("Parsed command line arguments");
+        let profile = opts
+            .profile
+            .or_else(|| env::var(PROFILE_ENV).ok())
+            .filter(|profile| !profile.is_empty());
 
         Loader {
             files,
             defaults: self.defaults,
             env: self.env,
             filter: self.filter,
+            layers: self.layers,
+            decryptor: self.decryptor,
+            references: self.references,
             overrides: opts.config_overrides.into_iter().collect(),
             warn_on_unused: self.warn_on_unused,
+            check_only: opts.check_config || opts.check_config_json,
+            check_json: opts.check_config_json,
+            injected: None,
+            loaded: Vec::new(),
+            profile,
         }
     }
 
@@ -425,7 +653,9 @@ impl Builder {
     ///
     /// If the command line parsing fails, the application terminates (and prints relevant help).
     pub fn build<O: StructOpt>(self) -> (O, Loader) {
-        let opts = OptWrapper::<O>::from_args();
+        let versions = self.versions::<O>();
+        let matches = versions.apply(OptWrapper::<O>::clap()).get_matches();
+        let opts = OptWrapper::<O>::from_clap(&matches);
         let loader = self.build_inner(opts.common);
         (opts.other, loader)
     }
@@ -455,7 +685,11 @@ impl Builder {
         I: IntoIterator,
         I::Item: Into<OsString> + Clone,
     {
-        let opts = OptWrapper::<O>::from_iter_safe(args)?;
+        let versions = self.versions::<O>();
+        let matches = versions
+            .apply(OptWrapper::<O>::clap())
+            .get_matches_from_safe(args)?;
+        let opts = OptWrapper::<O>::from_clap(&matches);
         let loader = self.build_inner(opts.common);
         Ok((opts.other, loader))
     }
@@ -495,6 +729,28 @@ impl ConfigBuilder for Builder {
         }
     }
 
+    fn config_layer<F>(mut self, layer: F) -> Self
+    where
+        F: FnMut() -> Result<Vec<(String, String)>, AnyError> + Send + 'static,
+    {
+        self.layers.push(Box::new(layer));
+        self
+    }
+
+    fn config_decryptor<D: Decryptor + 'static>(self, decryptor: D) -> Self {
+        Self {
+            decryptor: Some(Box::new(decryptor)),
+            ..self
+        }
+    }
+
+    fn config_references(self, resolve: bool) -> Self {
+        Self {
+            references: resolve,
+            ..self
+        }
+    }
+
     fn warn_on_unused(self, warn: bool) -> Self {
         Self {
             warn_on_unused: warn,
@@ -503,6 +759,16 @@ impl ConfigBuilder for Builder {
     }
 }
 
+/// A configuration file used by the last [`load`][Loader::load].
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct LoadedFile {
+    /// Path to the file.
+    pub path: PathBuf,
+
+    /// CRC-32 checksum of the file content, as read during the load.
+    pub checksum: u32,
+}
+
 /// The loader of configuration.
 ///
 /// This is created by the [`Builder`]. See the [module documentation][crate::cfg_loader] for
@@ -513,10 +779,59 @@ pub struct Loader {
     env: Option<String>,
     overrides: HashMap<String, String>,
     filter: Box<dyn FnMut(&Path) -> bool + Send>,
+    layers: Vec<Layer>,
+    decryptor: Option<Box<dyn Decryptor>>,
+    references: bool,
     warn_on_unused: bool,
+    check_only: bool,
+    check_json: bool,
+    injected: Option<String>,
+    loaded: Vec<LoadedFile>,
+    profile: Option<String>,
 }
 
 impl Loader {
+    /// Was the application asked to only check the configuration?
+    ///
+    /// This is set by the `--check-config` command line option. The application is expected to
+    /// load the configuration, validate it and exit, without actually starting. The
+    /// [`Spirit`][crate::Spirit] does so automatically.
+    pub fn check_only(&self) -> bool {
+        self.check_only
+    }
+
+    /// Should the configuration check print a JSON [report][crate::validation::Report]?
+    ///
+    /// This is set by the `--check-config-json` command line option (which implies
+    /// [`check_only`][Loader::check_only]).
+    pub fn check_json(&self) -> bool {
+        self.check_json
+    }
+
+    /// Uses the given configuration document instead of the configuration files.
+    ///
+    /// The [layers][ConfigBuilder::config_layer] are skipped too. The defaults, environment and
+    /// command line overrides still apply. This is used by the
+    /// [testing harness][crate::testing].
+    pub(crate) fn inject(&mut self, config: String) {
+        self.injected = Some(config);
+    }
+
+    /// The selected configuration profile, if any.
+    ///
+    /// Set by the `--profile` command line option or the `SPIRIT_PROFILE` environment variable.
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_ref().map(String::as_str)
+    }
+
+    /// The configuration files used by the last successful [`load`][Loader::load].
+    ///
+    /// Files found inside configured directories are listed individually, in the order they were
+    /// merged.
+    pub fn loaded_files(&self) -> &[LoadedFile] {
+        &self.loaded
+    }
+
     /// Loads configuration according to parameters configured on the originating [`Builder`] and on
     /// the command line.
     ///
@@ -527,6 +842,7 @@ impl Loader {
     pub fn load<C: DeserializeOwned>(&mut self) -> Result<C, AnyError> {
         debug!("Loading configuration");
         let mut config = Config::new();
+        let mut loaded = Vec::new();
         // To avoid problems with trying to parse without any configuration present (it would
         // complain that it found unit and whatever the config was is expected instead).
         config.merge(File::from_str("", FileFormat::Toml))?;
@@ -536,22 +852,41 @@ impl Loader {
                 .merge(File::from_str(defaults, FileFormat::Toml))
                 .context("Failed to read defaults")?;
         }
-        for path in &self.files {
+        let files = if let Some(injected) = self.injected.as_ref() {
+            trace!("Loading injected config");
+            config
+                .merge(File::from_str(injected, FileFormat::Toml))
+                .context("Failed to read injected config")?;
+            &[][..]
+        } else {
+            &self.files[..]
+        };
+        let layers = if self.injected.is_some() {
+            &mut [][..]
+        } else {
+            &mut self.layers[..]
+        };
+        for path in files {
             if path.is_file() {
-                trace!("Loading config file {:?}", path);
-                config
-                    .merge(File::from(path as &Path))
-                    .with_context(|_| format!("Failed to load config file {:?}", path))?;
+                let decryptor = self.decryptor.as_mut();
+                loaded.push(merge_file(&mut config, path.to_owned(), decryptor)?);
             } else if path.is_dir() {
                 trace!("Scanning directory {:?}", path);
                 // Take all the file entries passing the config file filter, handling errors on the
                 // way.
                 let filter = &mut self.filter;
+                let decrypting = self.decryptor.is_some();
                 let mut files = fallible_iterator::convert(path.read_dir()?)
                     .map(|entry| -> Result<Option<PathBuf>, std::io::Error> {
                         let path = entry.path();
                         let meta = path.symlink_metadata()?;
-                        if meta.is_file() && (filter)(&path) {
+                        // The filter decides by the name of the decrypted file
+                        let filtered = if decrypting && is_encrypted(&path) {
+                            path.with_extension("")
+                        } else {
+                            path.clone()
+                        };
+                        if meta.is_file() && (filter)(&filtered) {
                             Ok(Some(path))
                         } else {
                             trace!("Skipping {:?}", path);
@@ -563,10 +898,8 @@ impl Loader {
                 // Traverse them sorted.
                 files.sort();
                 for file in files {
-                    trace!("Loading config file {:?}", file);
-                    config
-                        .merge(File::from(&file as &Path))
-                        .with_context(|_| format!("Failed to load config file {:?}", file))?;
+                    let decryptor = self.decryptor.as_mut();
+                    loaded.push(merge_file(&mut config, file, decryptor)?);
                 }
             } else if path.exists() {
                 return Err(InvalidFileType(path.to_owned()).into());
@@ -574,6 +907,27 @@ impl Loader {
                 return Err(MissingFile(path.to_owned()).into());
             }
         }
+        for layer in layers {
+            let documents = layer().context("Failed to load config layer")?;
+            for (name, content) in documents {
+                trace!("Loading config layer {}", name);
+                config
+                    .merge(File::from_str(&content, layer_format(&name)))
+                    .with_context(|_| format!("Failed to load config layer {}", name))?;
+                loaded.push(LoadedFile {
+                    checksum: crc32fast::hash(content.as_bytes()),
+                    path: PathBuf::from(name),
+                });
+            }
+        }
+        if let Some(profile) = self.profile.as_ref() {
+            trace!("Applying config profile {}", profile);
+            if !apply_profile(&mut config, profile)
+                .with_context(|_| format!("Failed to apply config profile {}", profile))?
+            {
+                warn!("Config profile {} not found in the configuration", profile);
+            }
+        }
         if let Some(env_prefix) = self.env.as_ref() {
             trace!("Loading config from environment {}", env_prefix);
             config
@@ -586,23 +940,339 @@ impl Loader {
                 format!("Failed to push override {}={} into config", key, value)
             })?;
         }
+        // Before decrypting, the secrets may contain anything
+        if self.references {
+            resolve_references(&mut config)?;
+        }
+        if let Some(decryptor) = self.decryptor.as_mut() {
+            decrypt_values(&mut config, &mut **decryptor)?;
+        }
+        interpolate(&mut config)?;
 
         let mut ignored_cback = |ignored: serde_ignored::Path| {
-            if self.warn_on_unused {
+            let ignored = ignored.to_string();
+            // The profiles are consumed by us, not by the application
+            let profiles =
+                ignored == PROFILE_KEY || ignored.starts_with(&format!("{}.", PROFILE_KEY));
+            if self.warn_on_unused && !profiles {
                 warn!("Unused configuration key {}", ignored);
             }
         };
         let config = serde_ignored::Deserializer::new(config, &mut ignored_cback);
 
         let result = serde_path_to_error::deserialize(config).map_err(|e| {
-            let ctx = format!("Failed to decode configuration at {}", e.path());
-            e.into_inner().context(ctx)
+            let path = e.path().to_string();
+            Invalid::new(format!("Failed to decode configuration at {}", path))
+                .path(path)
+                .caused_by(e.into_inner())
         })?;
 
+        self.loaded = loaded;
         Ok(result)
     }
 }
 
+/// The whole configuration tree as a source, to put a rewritten tree back into a [`Config`].
+#[derive(Clone, Debug)]
+struct Tree(HashMap<String, ConfigValue>);
+
+impl Source for Tree {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<HashMap<String, ConfigValue>, ConfigError> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Rewrites the string values inside the value.
+///
+/// The `path` is only for the messages, the values are found and replaced in the tree itself. So
+/// keys containing dots (like host names in a map) are not split.
+fn rewrite_value<F>(
+    path: &str,
+    value: ConfigValue,
+    rewrite: &mut F,
+) -> Result<ConfigValue, AnyError>
+where
+    F: FnMut(&str, &str) -> Result<Option<ConfigValue>, AnyError>,
+{
+    if let Ok(table) = value.clone().into_table() {
+        let table = table
+            .into_iter()
+            .map(|(key, value)| {
+                let value = rewrite_value(&format!("{}.{}", path, key), value, rewrite)?;
+                Ok((key, value))
+            })
+            .collect::<Result<HashMap<_, _>, AnyError>>()?;
+        Ok(table.into())
+    } else if let Ok(array) = value.clone().into_array() {
+        let array = array
+            .into_iter()
+            .enumerate()
+            .map(|(idx, value)| rewrite_value(&format!("{}[{}]", path, idx), value, rewrite))
+            .collect::<Result<Vec<_>, AnyError>>()?;
+        Ok(array.into())
+    } else if let Ok(s) = value.clone().into_str() {
+        Ok(rewrite(path, &s)?.unwrap_or(value))
+    } else {
+        Ok(value)
+    }
+}
+
+/// Rewrites the string values in the whole configuration.
+///
+/// The `rewrite` gets the path and the value and returns the replacement, or `None` to leave the
+/// value alone.
+fn rewrite_strings<F>(config: &mut Config, mut rewrite: F) -> Result<(), AnyError>
+where
+    F: FnMut(&str, &str) -> Result<Option<ConfigValue>, AnyError>,
+{
+    let mut changed = false;
+    let mut tracked = |key: &str, value: &str| -> Result<Option<ConfigValue>, AnyError> {
+        let replacement = rewrite(key, value)?;
+        changed |= replacement.is_some();
+        Ok(replacement)
+    };
+    let mut tree = HashMap::new();
+    for (key, value) in config.collect()? {
+        let value = rewrite_value(&key, value, &mut tracked)?;
+        tree.insert(key, value);
+    }
+    if !changed {
+        return Ok(());
+    }
+    let mut rewritten = Config::new();
+    rewritten.merge(Tree(tree))?;
+    *config = rewritten;
+    Ok(())
+}
+
+/// Collects the leaves (non-table values) of the configuration, with their paths.
+fn find_leaves(path: String, value: ConfigValue, found: &mut Vec<(String, ConfigValue)>) {
+    match value.clone().into_table() {
+        Ok(table) => {
+            for (key, value) in table {
+                find_leaves(format!("{}.{}", path, key), value, found);
+            }
+        }
+        Err(_) => found.push((path, value)),
+    }
+}
+
+/// Overlays the `[profile.<name>]` section over the rest of the configuration.
+///
+/// Returns if the profile was found.
+fn apply_profile(config: &mut Config, profile: &str) -> Result<bool, AnyError> {
+    let overlay = config
+        .collect()?
+        .remove(PROFILE_KEY)
+        .map(ConfigValue::into_table)
+        .transpose()
+        .context("The profile section is not a table")?
+        .and_then(|mut profiles| profiles.remove(profile));
+    let overlay = match overlay {
+        Some(overlay) => overlay.into_table().context("The profile is not a table")?,
+        None => return Ok(false),
+    };
+    let mut leaves = Vec::new();
+    for (key, value) in overlay {
+        find_leaves(key, value, &mut leaves);
+    }
+    for (key, value) in leaves {
+        trace!("Profile {} sets {}", profile, key);
+        config.set(&key, value)?;
+    }
+    Ok(true)
+}
+
+/// Replaces the `enc:` prefixed values by their decrypted content.
+fn decrypt_values(config: &mut Config, decryptor: &mut dyn Decryptor) -> Result<(), AnyError> {
+    rewrite_strings(config, |key, value| {
+        if !value.starts_with(ENCRYPTED_PREFIX) {
+            return Ok(None);
+        }
+        trace!("Decrypting {}", key);
+        let decrypted = base64::decode(&value[ENCRYPTED_PREFIX.len()..])
+            .map_err(AnyError::from)
+            .and_then(|encrypted| decryptor.decrypt(&encrypted))
+            .and_then(|decrypted| Ok(String::from_utf8(decrypted)?))
+            .with_context(|_| format!("Failed to decrypt {}", key))?;
+        Ok(Some(decrypted.into()))
+    })
+}
+
+/// Resolution of references to other keys of the configuration.
+struct Resolver<'a> {
+    config: &'a Config,
+    resolved: HashMap<String, ConfigValue>,
+    resolving: Vec<String>,
+}
+
+impl Resolver<'_> {
+    /// The value of the key, with its own references resolved.
+    fn resolve_key(&mut self, key: &str) -> Result<ConfigValue, AnyError> {
+        if let Some(value) = self.resolved.get(key) {
+            return Ok(value.clone());
+        }
+        if self.resolving.iter().any(|k| k == key) {
+            let mut cycle = self.resolving.clone();
+            cycle.push(key.to_owned());
+            return Err(format!("Cyclic reference {}", cycle.join(" -> ")).into());
+        }
+        let value: ConfigValue = self
+            .config
+            .get(key)
+            .with_context(|_| format!("Unknown reference ${{{}}}", key))?;
+        if value.clone().into_table().is_ok() || value.clone().into_array().is_ok() {
+            return Err(format!("Reference ${{{}}} is not a scalar value", key).into());
+        }
+        self.resolving.push(key.to_owned());
+        let result = match value.clone().into_str() {
+            Ok(s) if s.contains(REF_START) => self.resolve_str(&s),
+            _ => Ok(value),
+        };
+        self.resolving.pop();
+        let value = result?;
+        self.resolved.insert(key.to_owned(), value.clone());
+        Ok(value)
+    }
+
+    /// Replaces the references in a string.
+    ///
+    /// The variables (`${spirit.*}`) are left in place, for [`interpolate`]. So are their escapes.
+    fn resolve_str(&mut self, s: &str) -> Result<ConfigValue, AnyError> {
+        let mut result = String::with_capacity(s.len());
+        let mut rest = s;
+        while let Some(start) = rest.find(REF_START) {
+            if rest[..start].ends_with(REF_ESCAPE) {
+                let variable = rest[start..].starts_with(cgroup::VAR_PREFIX);
+                let before = if variable {
+                    &rest[..start]
+                } else {
+                    &rest[..start - REF_ESCAPE.len_utf8()]
+                };
+                result.push_str(before);
+                result.push_str(REF_START);
+                rest = &rest[start + REF_START.len()..];
+                continue;
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unterminated reference in {}", s))?;
+            let before = &rest[..start];
+            let reference = &rest[start..=start + end];
+            let name = &rest[start + REF_START.len()..start + end];
+            rest = &rest[start + end + 1..];
+            result.push_str(before);
+            if reference.starts_with(cgroup::VAR_PREFIX) {
+                result.push_str(reference);
+                continue;
+            }
+            let value = self.resolve_key(name)?;
+            if reference.len() == s.len() {
+                return Ok(value);
+            }
+            result.push_str(&value.into_str()?);
+        }
+        result.push_str(rest);
+        Ok(result.into())
+    }
+}
+
+/// Replaces the references to other keys (like `${ports.api}`) in the configuration.
+fn resolve_references(config: &mut Config) -> Result<(), AnyError> {
+    // The references are looked up in the configuration as it was before the rewriting
+    let original = config.clone();
+    let mut resolver = Resolver {
+        config: &original,
+        resolved: HashMap::new(),
+        resolving: Vec::new(),
+    };
+    rewrite_strings(config, |key, value| {
+        if !value.contains(REF_START) {
+            return Ok(None);
+        }
+        let resolved = resolver
+            .resolve_str(value)
+            .with_context(|_| format!("Failed to resolve references in {}", key))?;
+        trace!("Resolved references in {} = {:?}", key, resolved);
+        Ok(Some(resolved))
+    })
+}
+
+/// Replaces the variables (like `${spirit.cpus}`) in the configuration.
+///
+/// See the [`cgroup`] module.
+fn interpolate(config: &mut Config) -> Result<(), AnyError> {
+    rewrite_strings(config, |key, value| {
+        if !value.contains(cgroup::VAR_PREFIX) {
+            return Ok(None);
+        }
+        let interpolated = cgroup::interpolate(value)
+            .with_context(|_| format!("Failed to interpolate {}", key))?;
+        trace!("Interpolated {} = {}: {:?}", key, value, interpolated);
+        Ok(match interpolated {
+            Some(Interpolated::Number(number)) => Some((number as i64).into()),
+            Some(Interpolated::Text(text)) => Some(text.into()),
+            None => None,
+        })
+    })
+}
+
+/// The format of a config layer document, by the extension of its name.
+fn layer_format(name: &str) -> FileFormat {
+    match Path::new(name).extension().and_then(OsStr::to_str) {
+        #[cfg(feature = "json")]
+        Some("json") => FileFormat::Json,
+        #[cfg(feature = "yaml")]
+        Some("yaml") | Some("yml") => FileFormat::Yaml,
+        #[cfg(feature = "ini")]
+        Some("ini") => FileFormat::Ini,
+        #[cfg(feature = "hjson")]
+        Some("hjson") => FileFormat::Hjson,
+        _ => FileFormat::Toml,
+    }
+}
+
+fn is_encrypted(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new("enc"))
+}
+
+/// Merges a single configuration file, decrypting it if needed.
+fn merge_file(
+    config: &mut Config,
+    path: PathBuf,
+    decryptor: Option<&mut Box<dyn Decryptor>>,
+) -> Result<LoadedFile, AnyError> {
+    trace!("Loading config file {:?}", path);
+    let content =
+        fs::read(&path).with_context(|_| format!("Failed to read config file {:?}", path))?;
+    match decryptor {
+        Some(decryptor) if is_encrypted(&path) => {
+            let decrypted = decryptor
+                .decrypt(&content)
+                .and_then(|decrypted| Ok(String::from_utf8(decrypted)?))
+                .with_context(|_| format!("Failed to decrypt config file {:?}", path))?;
+            let inner = path.with_extension("");
+            let format = layer_format(&inner.to_string_lossy());
+            config
+                .merge(File::from_str(&decrypted, format))
+                .with_context(|_| format!("Failed to load config file {:?}", path))?;
+        }
+        _ => {
+            config
+                .merge(File::from(&path as &Path))
+                .with_context(|_| format!("Failed to load config file {:?}", path))?;
+        }
+    }
+    Ok(LoadedFile {
+        path,
+        checksum: crc32fast::hash(&content),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use maplit::hashmap;
@@ -731,6 +1401,289 @@ mod tests {
         assert_eq!(cfg, Cfg { value: 12 });
     }
 
+    #[test]
+    fn check_config() {
+        let (Empty {}, loader) = Builder::new()
+            .build_explicit_opts(vec!["my-app", "--check-config"])
+            .unwrap();
+        assert!(loader.check_only());
+
+        let (Empty {}, loader) = Builder::new().build_explicit_opts(vec!["my-app"]).unwrap();
+        assert!(!loader.check_only());
+
+        let (Empty {}, loader) = Builder::new()
+            .build_explicit_opts(vec!["my-app", "--check-config-json"])
+            .unwrap();
+        assert!(loader.check_only());
+        assert!(loader.check_json());
+    }
+
+    #[test]
+    fn interpolation() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            threads: usize,
+            name: String,
+            list: Vec<String>,
+        }
+
+        const CFG: &str = r#"
+            threads = "${spirit.cpus}"
+            name = "worker-${spirit.cpus}"
+            list = ["${spirit.cpus}x"]
+        "#;
+
+        let cfg: Cfg = Builder::new()
+            .config_defaults(CFG)
+            .build_no_opts()
+            .load()
+            .unwrap();
+        let cpus = cgroup::cpus();
+        assert_eq!(cpus, cfg.threads);
+        assert_eq!(format!("worker-{}", cpus), cfg.name);
+        assert_eq!(vec![format!("{}x", cpus)], cfg.list);
+    }
+
+    #[test]
+    fn references() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Listen {
+            port: u16,
+            url: String,
+            threads: usize,
+        }
+
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            listen: Listen,
+        }
+
+        const CFG: &str = r#"
+            host = "example.com"
+            base = "https://${host}"
+            workers = "${spirit.cpus}"
+
+            [ports]
+            api = 8080
+
+            [listen]
+            port = "${ports.api}"
+            url = "${base}:${ports.api}/"
+            threads = "${workers}"
+        "#;
+
+        let cfg: Cfg = Builder::new()
+            .config_defaults(CFG)
+            .config_references(true)
+            .build_no_opts()
+            .load()
+            .unwrap();
+        assert_eq!(8080, cfg.listen.port);
+        assert_eq!("https://example.com:8080/", cfg.listen.url);
+        assert_eq!(cgroup::cpus(), cfg.listen.threads);
+
+        let load = |cfg: &str| {
+            Builder::new()
+                .config_defaults(cfg)
+                .config_references(true)
+                .build_no_opts()
+                .load::<Empty>()
+        };
+        assert!(load("a = \"${b}\"\nb = \"x${a}\"").is_err());
+        assert!(load("a = \"${a}\"").is_err());
+        assert!(load("a = \"${missing}\"").is_err());
+        assert!(load("a = \"${b}\"\n[b]\nc = 1").is_err());
+        assert!(load("a = \"${b\"").is_err());
+    }
+
+    #[test]
+    fn references_literal() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            home: String,
+            secret: String,
+        }
+
+        // A "cipher" good enough for a test
+        let reverse =
+            |data: &[u8]| -> Result<Vec<u8>, AnyError> { Ok(data.iter().rev().cloned().collect()) };
+        let secret = format!("enc:{}", base64::encode(b"}x{$"));
+        let load = |home: &str, references| {
+            Builder::new()
+                .config_defaults(format!("home = \"{}\"\nsecret = \"{}\"", home, secret))
+                .config_decryptor(reverse)
+                .config_references(references)
+                .build_no_opts()
+                .load::<Cfg>()
+                .unwrap()
+        };
+
+        // Not resolved unless turned on
+        let cfg = load("${HOME}", false);
+        assert_eq!("${HOME}", cfg.home);
+        assert_eq!("${x}", cfg.secret);
+
+        // Escaped, and the decrypted secret is left alone
+        let cfg = load("$${HOME}", true);
+        assert_eq!("${HOME}", cfg.home);
+        assert_eq!("${x}", cfg.secret);
+    }
+
+    #[test]
+    fn layers() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            a: u32,
+            b: u32,
+        }
+
+        let mut loader = Builder::new()
+            .config_defaults("a = 1\nb = 1")
+            .config_layer(|| Ok(vec![("first.toml".to_owned(), "a = 2\nb = 2".to_owned())]))
+            .config_layer(|| Ok(vec![("second".to_owned(), "b = 3".to_owned())]))
+            .build_no_opts();
+        let cfg: Cfg = loader.load().unwrap();
+        assert_eq!(Cfg { a: 2, b: 3 }, cfg);
+        let names = loader
+            .loaded_files()
+            .iter()
+            .map(|f| f.path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![PathBuf::from("first.toml"), PathBuf::from("second")],
+            names
+        );
+
+        let mut loader = Builder::new()
+            .config_layer(|| Err("Broken".into()))
+            .build_no_opts();
+        assert!(loader.load::<Empty>().is_err());
+    }
+
+    #[test]
+    fn decrypt_values() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            plain: String,
+            secret: String,
+            list: Vec<String>,
+        }
+
+        // A "cipher" good enough for a test
+        let reverse =
+            |data: &[u8]| -> Result<Vec<u8>, AnyError> { Ok(data.iter().rev().cloned().collect()) };
+        let encrypted = format!("enc:{}", base64::encode(b"2retnuh"));
+        let cfg = format!(
+            "plain = \"enc\"\nsecret = \"{0}\"\nlist = [\"{0}\"]",
+            encrypted
+        );
+        let parsed: Cfg = Builder::new()
+            .config_defaults(cfg.clone())
+            .config_decryptor(reverse)
+            .build_no_opts()
+            .load()
+            .unwrap();
+        assert_eq!("enc", parsed.plain);
+        assert_eq!("hunter2", parsed.secret);
+        assert_eq!(vec!["hunter2".to_owned()], parsed.list);
+
+        // A key with dots (like a host name) is not split into a path
+        #[derive(Debug, Deserialize)]
+        struct Hosts {
+            hosts: HashMap<String, HashMap<String, String>>,
+        }
+        let parsed: Hosts = Builder::new()
+            .config_defaults(format!(
+                "[hosts.\"api.example.com\"]\nsecret = \"{}\"",
+                encrypted
+            ))
+            .config_decryptor(reverse)
+            .build_no_opts()
+            .load()
+            .unwrap();
+        let expected = hashmap! {
+            "api.example.com".to_owned() => hashmap! {
+                "secret".to_owned() => "hunter2".to_owned(),
+            },
+        };
+        assert_eq!(expected, parsed.hosts);
+
+        // Without a decryptor, the values are left alone
+        let parsed: Cfg = Builder::new()
+            .config_defaults(cfg)
+            .build_no_opts()
+            .load()
+            .unwrap();
+        assert_eq!(encrypted, parsed.secret);
+
+        let broken = Builder::new()
+            .config_defaults("plain = \"\"\nsecret = \"enc:!!\"\nlist = []")
+            .config_decryptor(reverse)
+            .build_no_opts()
+            .load::<Cfg>();
+        assert!(broken.is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Server {
+            port: u16,
+            host: String,
+        }
+
+        #[derive(Debug, Deserialize, Eq, PartialEq)]
+        struct Cfg {
+            server: Server,
+        }
+
+        const CFG: &str = r#"
+            [server]
+            port = 8080
+            host = "localhost"
+
+            [profile.prod.server]
+            host = "0.0.0.0"
+        "#;
+
+        let (Empty {}, mut loader) = Builder::new()
+            .config_defaults(CFG)
+            .build_explicit_opts(vec!["my-app", "--profile", "prod", "-C", "server.port=80"])
+            .unwrap();
+        assert_eq!(Some("prod"), loader.profile());
+        let cfg: Cfg = loader.load().unwrap();
+        assert_eq!(80, cfg.server.port);
+        assert_eq!("0.0.0.0", cfg.server.host);
+
+        // Unknown profile changes nothing
+        let (Empty {}, mut loader) = Builder::new()
+            .config_defaults(CFG)
+            .build_explicit_opts(vec!["my-app", "--profile", "dev"])
+            .unwrap();
+        let cfg: Cfg = loader.load().unwrap();
+        assert_eq!(8080, cfg.server.port);
+        assert_eq!("localhost", cfg.server.host);
+    }
+
+    #[test]
+    fn versions() {
+        let versions = Builder::new()
+            .component("spirit-something", "1.2.3")
+            .component("spirit-other", "0.1.0")
+            .component("spirit-something", "1.2.4")
+            .versions::<Empty>();
+        assert!(versions.app.is_none());
+        let expected = format!(
+            "{0}\n\nspirit {0}\nspirit-something 1.2.4\nspirit-other 0.1.0",
+            env!("CARGO_PKG_VERSION")
+        );
+        assert_eq!(expected, versions.long);
+
+        let versions = Builder::new().app_version("4.2").versions::<Empty>();
+        assert_eq!(Some("4.2"), versions.app.as_ref().map(String::as_str));
+        assert!(versions.long.starts_with("4.2\n\nspirit "));
+    }
+
     #[test]
     fn combine_dir() {
         #[derive(Debug, Deserialize, Eq, PartialEq)]
@@ -760,5 +1713,11 @@ mod tests {
                 another: "Hello".to_owned(), // From the defaults
             }
         );
+        let loaded = loader
+            .loaded_files()
+            .iter()
+            .map(|f| f.path.file_name().unwrap().to_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["cfg1.yaml", "cfg2.toml"], loaded);
     }
 }
@@ -0,0 +1,301 @@
+//! Detection of the resources available to the application.
+//!
+//! Inside a container, the number of CPUs of the host says little about how much CPU time the
+//! application may actually use ‒ the container usually has a quota set through cgroups. Sizing
+//! thread pools by the host can then lead to a lot of threads fighting over little CPU time. This
+//! module detects the limits (both cgroup v1 and v2 are supported) and provides them as:
+//!
+//! * The [`cpus`] and [`memory`] functions, for computing defaults (the threadpool of
+//!   `spirit-tokio` uses it for its default number of threads).
+//! * Variables usable in the configuration. Any string value in the configuration may contain
+//!   `${spirit.cpus}` and `${spirit.memory}` (in bytes). If the whole value is just the variable,
+//!   it is replaced by a number, so it can be used for numeric options:
+//!
+//! ```toml
+//! [threadpool]
+//! async-threads = "${spirit.cpus}"
+//! ```
+//!
+//! A literal `${spirit.` is written as `$${spirit.`.
+//!
+//! The detection happens once, on the first use. On systems without cgroups, the values of the
+//! host are used.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use log::debug;
+use once_cell::sync::Lazy;
+
+use crate::AnyError;
+
+/// The prefix of the variables provided by this module.
+pub(crate) const VAR_PREFIX: &str = "${spirit.";
+
+/// Values this big are used by cgroup v1 to mean "no limit".
+const V1_UNLIMITED: u64 = 1 << 60;
+
+#[derive(Debug)]
+struct Detected {
+    cpus: usize,
+    memory: Option<u64>,
+}
+
+static DETECTED: Lazy<Detected> = Lazy::new(|| {
+    let host_cpus = num_cpus::get();
+    let cpus = cpu_quota()
+        .map(|quota| (quota.ceil() as usize).max(1).min(host_cpus))
+        .unwrap_or(host_cpus);
+    let memory = match (memory_limit(), host_memory()) {
+        (Some(limit), Some(host)) => Some(limit.min(host)),
+        (limit, host) => limit.or(host),
+    };
+    let detected = Detected { cpus, memory };
+    debug!("Detected available resources {:?}", detected);
+    detected
+});
+
+/// The number of CPUs the application may use.
+///
+/// This is the CPU quota of the cgroup (rounded up), or the number of CPUs available if there's no
+/// quota.
+pub fn cpus() -> usize {
+    DETECTED.cpus
+}
+
+/// The amount of memory the application may use, in bytes.
+///
+/// This is the memory limit of the cgroup or the total memory of the host, whichever is smaller.
+/// `None` if it can't be detected.
+pub fn memory() -> Option<u64> {
+    DETECTED.memory
+}
+
+fn read(path: &Path) -> Option<String> {
+    fs::read_to_string(path).ok()
+}
+
+/// Paths of the cgroups of the current process, as (controllers, path).
+///
+/// The cgroup v2 has an empty controller list.
+fn own_cgroups(content: &str) -> Vec<(Vec<&str>, &str)> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(3, ':');
+            let _id = parts.next()?;
+            let controllers = parts.next()?;
+            let path = parts.next()?;
+            let controllers = controllers.split(',').filter(|c| !c.is_empty()).collect();
+            Some((controllers, path))
+        })
+        .collect()
+}
+
+/// Candidate locations of a cgroup file.
+///
+/// Inside a container, the cgroup of the process is often mounted as the root, so both the full
+/// path and the root are tried.
+fn candidates(mount: &str, cgroup: &str, file: &str) -> Vec<PathBuf> {
+    let base = Path::new(mount);
+    let relative = cgroup.trim_start_matches('/');
+    let mut result = Vec::new();
+    if !relative.is_empty() {
+        result.push(base.join(relative).join(file));
+    }
+    result.push(base.join(file));
+    result
+}
+
+fn cgroup_file(controller: Option<&str>, file: &str) -> Option<String> {
+    let cgroups = read(Path::new("/proc/self/cgroup"))?;
+    let cgroups = own_cgroups(&cgroups);
+    let (controllers, path) = match controller {
+        None => cgroups.iter().find(|(c, _)| c.is_empty())?,
+        Some(controller) => cgroups.iter().find(|(c, _)| c.contains(&controller))?,
+    };
+    let mount = if controllers.is_empty() {
+        "/sys/fs/cgroup".to_owned()
+    } else {
+        format!("/sys/fs/cgroup/{}", controllers.join(","))
+    };
+    candidates(&mount, path, file)
+        .iter()
+        .find_map(|candidate| read(candidate))
+}
+
+/// Parses the `cpu.max` of cgroup v2.
+fn parse_cpu_max(content: &str) -> Option<f64> {
+    let mut parts = content.split_whitespace();
+    let quota = parts.next()?;
+    let period: f64 = parts.next().unwrap_or("100000").parse().ok()?;
+    if quota == "max" || period <= 0.0 {
+        return None;
+    }
+    Some(quota.parse::<f64>().ok()? / period)
+}
+
+/// Parses the `cpu.cfs_quota_us` and `cpu.cfs_period_us` of cgroup v1.
+fn parse_cfs(quota: &str, period: &str) -> Option<f64> {
+    let quota: i64 = quota.trim().parse().ok()?;
+    let period: i64 = period.trim().parse().ok()?;
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(quota as f64 / period as f64)
+}
+
+/// Parses a memory limit of either cgroup version.
+fn parse_memory(content: &str) -> Option<u64> {
+    let content = content.trim();
+    if content == "max" {
+        return None;
+    }
+    content.parse().ok().filter(|limit| *limit < V1_UNLIMITED)
+}
+
+fn parse_meminfo(content: &str) -> Option<u64> {
+    let line = content.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+fn cpu_quota() -> Option<f64> {
+    if let Some(max) = cgroup_file(None, "cpu.max") {
+        return parse_cpu_max(&max);
+    }
+    let quota = cgroup_file(Some("cpu"), "cpu.cfs_quota_us")?;
+    let period = cgroup_file(Some("cpu"), "cpu.cfs_period_us")?;
+    parse_cfs(&quota, &period)
+}
+
+fn memory_limit() -> Option<u64> {
+    if let Some(max) = cgroup_file(None, "memory.max") {
+        return parse_memory(&max);
+    }
+    parse_memory(&cgroup_file(Some("memory"), "memory.limit_in_bytes")?)
+}
+
+fn host_memory() -> Option<u64> {
+    parse_meminfo(&read(Path::new("/proc/meminfo"))?)
+}
+
+/// Finds the value of a variable (without the `${` and `}`).
+fn variable(name: &str) -> Result<u64, AnyError> {
+    match name {
+        "spirit.cpus" => Ok(cpus() as u64),
+        "spirit.memory" => memory().ok_or_else(|| "Failed to detect available memory".into()),
+        _ => Err(format!("Unknown variable ${{{}}}", name).into()),
+    }
+}
+
+/// The result of interpolating a string.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) enum Interpolated {
+    /// The whole string was a single variable.
+    Number(u64),
+    /// Variables were replaced inside a longer string.
+    Text(String),
+}
+
+/// Replaces the variables in a string.
+///
+/// Returns `None` if there are no variables.
+pub(crate) fn interpolate(s: &str) -> Result<Option<Interpolated>, AnyError> {
+    interpolate_with(s, variable)
+}
+
+fn interpolate_with<F>(s: &str, lookup: F) -> Result<Option<Interpolated>, AnyError>
+where
+    F: Fn(&str) -> Result<u64, AnyError>,
+{
+    if !s.contains(VAR_PREFIX) {
+        return Ok(None);
+    }
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find(VAR_PREFIX) {
+        // Escaped as `$${spirit.*}`
+        if rest[..start].ends_with('$') {
+            result.push_str(&rest[..start - 1]);
+            result.push_str(VAR_PREFIX);
+            rest = &rest[start + VAR_PREFIX.len()..];
+            continue;
+        }
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| format!("Unterminated variable in {}", s))?;
+        let name = &rest[start + 2..start + end];
+        let value = lookup(name)?;
+        if start == 0 && start + end + 1 == rest.len() && result.is_empty() {
+            return Ok(Some(Interpolated::Number(value)));
+        }
+        result.push_str(&rest[..start]);
+        result.push_str(&value.to_string());
+        rest = &rest[start + end + 1..];
+    }
+    result.push_str(rest);
+    Ok(Some(Interpolated::Text(result)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cgroups() {
+        let content = "12:cpu,cpuacct:/docker/abc\n11:memory:/docker/abc\n0::/system.slice/x\n";
+        let cgroups = own_cgroups(content);
+        assert_eq!(3, cgroups.len());
+        assert_eq!((vec!["cpu", "cpuacct"], "/docker/abc"), cgroups[0]);
+        assert_eq!((vec![], "/system.slice/x"), cgroups[2]);
+        assert_eq!(
+            vec![
+                PathBuf::from("/sys/fs/cgroup/system.slice/x/cpu.max"),
+                PathBuf::from("/sys/fs/cgroup/cpu.max"),
+            ],
+            candidates("/sys/fs/cgroup", "/system.slice/x", "cpu.max")
+        );
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(Some(2.5), parse_cpu_max("250000 100000\n"));
+        assert_eq!(None, parse_cpu_max("max 100000\n"));
+        assert_eq!(Some(0.5), parse_cfs("50000\n", "100000\n"));
+        assert_eq!(None, parse_cfs("-1\n", "100000\n"));
+        assert_eq!(Some(1024), parse_memory("1024\n"));
+        assert_eq!(None, parse_memory("max\n"));
+        assert_eq!(None, parse_memory("9223372036854771712\n"));
+        assert_eq!(
+            Some(2048 * 1024),
+            parse_meminfo("MemTotal:        2048 kB\nMemFree: 1 kB\n")
+        );
+    }
+
+    #[test]
+    fn interpolation() {
+        let lookup = |name: &str| -> Result<u64, AnyError> {
+            match name {
+                "spirit.cpus" => Ok(4),
+                _ => Err("Unknown".into()),
+            }
+        };
+        assert_eq!(None, interpolate_with("hello ${world}", lookup).unwrap());
+        assert_eq!(
+            Some(Interpolated::Number(4)),
+            interpolate_with("${spirit.cpus}", lookup).unwrap()
+        );
+        assert_eq!(
+            Some(Interpolated::Text("4 and 4!".to_owned())),
+            interpolate_with("${spirit.cpus} and ${spirit.cpus}!", lookup).unwrap()
+        );
+        assert!(interpolate_with("${spirit.whatever}", lookup).is_err());
+        assert!(interpolate_with("${spirit.cpus", lookup).is_err());
+        assert_eq!(
+            Some(Interpolated::Text("${spirit.cpus} is 4".to_owned())),
+            interpolate_with("$${spirit.cpus} is ${spirit.cpus}", lookup).unwrap()
+        );
+        assert!(cpus() >= 1);
+    }
+}
@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
+use crate::schema::{Schema, Schematic};
+
 /// A struct that may be used when either configuration or command line options are not needed.
 ///
 /// When the application doesn't need the configuration (in excess of the automatic part provided
@@ -25,3 +27,9 @@ use structopt::StructOpt;
 )]
 #[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
 pub struct Empty {}
+
+impl Schematic for Empty {
+    fn schema() -> Schema {
+        Schema::object()
+    }
+}
@@ -1,9 +1,34 @@
 //! Error handling utilities.
+//!
+//! # Repeated errors
+//!
+//! Some errors tend to repeat a lot (for example an accept loop failing on too many open files,
+//! every few milliseconds), flooding the logs with the same message. By setting the
+//! [repeat interval][set_repeat_interval], identical errors logged through [`log_error`] (and the
+//! functions and macros built on top of it) are collapsed ‒ the first one is logged, the following
+//! ones during the interval are only counted. Once the interval passes, a summary with the count
+//! is logged and the error gets logged in full again when it next happens.
+//!
+//! This is off by default. The [`spirit-log`](https://docs.rs/spirit-log) crate sets it from its
+//! configuration.
+//!
+//! # Structured errors
+//!
+//! To return an error to a client (as a response of a HTTP handler, for example), the
+//! [`SerializableError`] captures the message and the chain of causes ‒ the same thing
+//! [`log_error`] prints ‒ in a form that can be serialized. The [`to_json`] is a shortcut for the
+//! common case.
 
+use std::collections::hash_map::{Entry, HashMap};
 use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use err_context::prelude::*;
-use log::{log, Level};
+use log::{log, log_enabled, Level};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 
 /// A wrapper type for any error.
 ///
@@ -31,14 +56,96 @@ pub enum ErrorLogFormat {
     _NON_EXHAUSTIVE,
 }
 
+struct Seen {
+    since: Instant,
+    repeated: usize,
+}
+
+#[derive(Default)]
+struct Repeats {
+    interval: Option<Duration>,
+    /// The errors logged during the current interval, by (target, level, the error chain).
+    seen: HashMap<(String, Level, String), Seen>,
+}
+
+static REPEATS: Lazy<Mutex<Repeats>> = Lazy::new(Default::default);
+
+/// Sets the interval for collapsing repeated errors.
+///
+/// `None` turns the collapsing off. See the [module documentation](index.html).
+pub fn set_repeat_interval(interval: Option<Duration>) {
+    let mut repeats = REPEATS.lock().unwrap_or_else(PoisonError::into_inner);
+    repeats.interval = interval;
+    if interval.is_none() {
+        repeats.seen.clear();
+    }
+}
+
+/// Decides if the error is a repetition that should not be logged.
+///
+/// Also logs the summaries of the errors whose interval has passed.
+fn repeated(level: Level, target: &str, e: &AnyError) -> bool {
+    let mut summaries = Vec::new();
+    let result = {
+        let mut repeats = REPEATS.lock().unwrap_or_else(PoisonError::into_inner);
+        let interval = match repeats.interval {
+            Some(interval) => interval,
+            None => return false,
+        };
+        let now = Instant::now();
+        let expired = repeats
+            .seen
+            .iter()
+            .filter(|(_, seen)| now.duration_since(seen.since) >= interval)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            let seen = repeats.seen.remove(&key).expect("Key just found");
+            if seen.repeated > 0 {
+                summaries.push((key, seen.repeated));
+            }
+        }
+        let key = (target.to_owned(), level, e.display("; ").to_string());
+        match repeats.seen.entry(key) {
+            Entry::Occupied(mut seen) => {
+                seen.get_mut().repeated += 1;
+                true
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(Seen {
+                    since: now,
+                    repeated: 0,
+                });
+                false
+            }
+        }
+    };
+    // Log outside of the lock, in case the logger itself logs errors.
+    for ((target, level, error), count) in summaries {
+        log!(
+            target: target.as_str(),
+            level,
+            "The error repeated {} more times: {}",
+            count,
+            error
+        );
+    }
+    result
+}
+
 /// Log one error on given log level.
 ///
 /// It is printed to the log with all the causes and optionally a backtrace (if it is available and
 /// debug logging is enabled).
 ///
+/// Repeated errors may be collapsed, see the [module documentation](index.html).
+///
 /// This is the low-level version with full customization. You might also be interested in
 /// [`log_errors`] or one of the convenience macro ([`log_error`][macro@log_error]).
 pub fn log_error(level: Level, target: &str, e: &AnyError, format: ErrorLogFormat) {
+    if !log_enabled!(target: target, level) || repeated(level, target, e) {
+        return;
+    }
     match format {
         ErrorLogFormat::MultiLine => {
             for cause in e.chain() {
@@ -103,6 +210,94 @@ macro_rules! log_error {
     };
 }
 
+/// An error with its chain of causes, in a serializable form.
+///
+/// # Examples
+///
+/// ```rust
+/// use err_context::prelude::*;
+/// use spirit::AnyError;
+/// use spirit::error::SerializableError;
+///
+/// let error: AnyError = std::io::Error::from(std::io::ErrorKind::NotFound)
+///     .context("Failed to load the page")
+///     .into();
+/// let error = SerializableError::new(&*error);
+/// assert_eq!("Failed to load the page", error.message);
+/// assert_eq!(1, error.causes.len());
+/// ```
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SerializableError {
+    /// The message of the outermost error.
+    pub message: String,
+
+    /// The messages of the causes, from the outermost.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub causes: Vec<String>,
+
+    /// A backtrace, if captured.
+    ///
+    /// See [`capture_backtrace`][SerializableError::capture_backtrace].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backtrace: Option<String>,
+}
+
+impl SerializableError {
+    /// Captures the message and causes of the error.
+    pub fn new(error: &(dyn Error + 'static)) -> Self {
+        let mut causes = Vec::new();
+        let mut current = error.source();
+        while let Some(cause) = current {
+            causes.push(cause.to_string());
+            current = cause.source();
+        }
+        SerializableError {
+            message: error.to_string(),
+            causes,
+            backtrace: None,
+        }
+    }
+
+    /// Adds the current backtrace.
+    ///
+    /// The errors don't carry their backtraces, so this is the backtrace of the place where this
+    /// is called (usually where the error is handled). It is available only with the `backtrace`
+    /// feature, without it this does nothing.
+    pub fn capture_backtrace(self) -> Self {
+        #[cfg(feature = "backtrace")]
+        let backtrace = Some(format!("{:?}", backtrace::Backtrace::new()));
+        #[cfg(not(feature = "backtrace"))]
+        let backtrace = self.backtrace;
+        SerializableError { backtrace, ..self }
+    }
+}
+
+impl Display for SerializableError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}", self.message)?;
+        for cause in &self.causes {
+            write!(fmt, "; {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<&AnyError> for SerializableError {
+    fn from(error: &AnyError) -> Self {
+        SerializableError::new(&**error)
+    }
+}
+
+/// Formats the error with its causes as JSON.
+///
+/// See [`SerializableError`] for the format.
+#[cfg(feature = "json")]
+pub fn to_json(error: &(dyn Error + 'static)) -> String {
+    serde_json::to_string(&SerializableError::new(error))
+        .expect("Serializing only strings can't fail")
+}
+
 /// A wrapper around a fallible function, logging any returned errors.
 ///
 /// The errors will be logged in the provided target. You may want to provide `module_path!` as the
@@ -163,4 +358,49 @@ mod tests {
         let multi_err = err.context("Another level").into();
         log_error!(multi Info, multi_err);
     }
+
+    #[test]
+    fn serializable() {
+        let err: AnyError = Dummy.context("Another level").into();
+        let serializable = SerializableError::from(&err);
+        assert_eq!("Another level", serializable.message);
+        assert_eq!(vec!["Dummy error".to_owned()], serializable.causes);
+        assert_eq!(err.display("; ").to_string(), serializable.to_string());
+        assert!(serializable.backtrace.is_none());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json() {
+        let err: AnyError = Dummy.context("Another level").into();
+        assert_eq!(
+            r#"{"message":"Another level","causes":["Dummy error"]}"#,
+            to_json(&*err)
+        );
+        assert_eq!(r#"{"message":"Dummy error"}"#, to_json(&Dummy));
+    }
+
+    #[test]
+    fn repeats() {
+        let err: AnyError = Dummy.into();
+        // Nothing is counted while off
+        assert!(!repeated(Level::Error, "repeats", &err));
+        assert!(!repeated(Level::Error, "repeats", &err));
+
+        set_repeat_interval(Some(Duration::from_secs(3600)));
+        assert!(!repeated(Level::Error, "repeats", &err));
+        assert!(repeated(Level::Error, "repeats", &err));
+        // Different target, level or error is not a repetition
+        assert!(!repeated(Level::Error, "repeats-other", &err));
+        assert!(!repeated(Level::Warn, "repeats", &err));
+        let other: AnyError = Dummy.context("Another level").into();
+        assert!(!repeated(Level::Error, "repeats", &other));
+
+        set_repeat_interval(Some(Duration::from_secs(0)));
+        // The interval passed, so it's logged again
+        assert!(!repeated(Level::Error, "repeats", &err));
+
+        set_repeat_interval(None);
+        assert!(!repeated(Level::Error, "repeats", &err));
+    }
 }
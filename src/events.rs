@@ -0,0 +1,200 @@
+//! An in-process bus for broadcasting events between extensions.
+//!
+//! Every [`Spirit`] (and its [`Builder`]) owns an [`Events`] bus. Anyone can [`publish`] a value of
+//! any (`Clone + Send`) type and all the current subscribers of that type get a copy. This allows
+//! independent extensions to observe each other (eg. a metrics extension counting reloads or a
+//! health-check extension noticing a resource got installed) without wiring each pair together.
+//!
+//! Spirit itself publishes these lifecycle events:
+//!
+//! * [`ConfigChanged`] after a new configuration has been successfully loaded and all the
+//!   [`on_config`] hooks have run.
+//! * [`ResourceInstalled`] after a [`Pipeline`] installed the resources of a new configuration.
+//! * [`ResourceStatus`] whenever the status of a [`Pipeline`] changes (see the
+//!   [`status`][crate::status] module).
+//! * [`Terminating`] when the application starts terminating, before the [`on_terminate`]
+//!   hooks are run.
+//!
+//! The subscriptions are plain channels, the events are not delivered through callbacks. That
+//! way publishing never blocks and the subscribers are free to call back into [`Spirit`] (which
+//! would deadlock from inside a callback). After the [`Terminating`] event, the bus is closed ‒
+//! all the subscriptions get disconnected (so threads iterating over them end) and further events
+//! are dropped.
+//!
+//! The bus is reachable from any [`Extensible`] through [`Extensible::events`], so extensions can
+//! subscribe during their setup.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::thread;
+//!
+//! use spirit::{Empty, Spirit};
+//! use spirit::events::ConfigChanged;
+//! use spirit::prelude::*;
+//!
+//! #[derive(Clone, Debug)]
+//! struct CacheFlushed(usize);
+//!
+//! let builder = Spirit::<Empty, Empty>::new();
+//! let reloads = builder.events().subscribe::<ConfigChanged>();
+//! let flushes = builder.events().subscribe::<CacheFlushed>();
+//! thread::spawn(move || {
+//!     for _ in reloads {
+//!         println!("New configuration");
+//!     }
+//! });
+//! builder
+//!     .run(move |spirit| {
+//!         spirit.events().publish(CacheFlushed(42));
+//!         assert_eq!(42, flushes.recv().unwrap().0);
+//!         Ok(())
+//!     });
+//! ```
+//!
+//! [`Spirit`]: crate::Spirit
+//! [`Builder`]: crate::Builder
+//! [`publish`]: Events::publish
+//! [`on_config`]: crate::Extensible::on_config
+//! [`on_terminate`]: crate::Extensible::on_terminate
+//! [`Pipeline`]: crate::Pipeline
+//! [`ResourceStatus`]: crate::status::ResourceStatus
+//! [`Extensible`]: crate::Extensible
+//! [`Extensible::events`]: crate::Extensible::events
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::trace;
+
+/// A new configuration is in place.
+///
+/// Published by [`Spirit`][crate::Spirit] after every successful (re)load of the configuration.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ConfigChanged;
+
+/// A [`Pipeline`][crate::Pipeline] installed resources of a new configuration.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ResourceInstalled {
+    /// The name of the pipeline.
+    pub pipeline: &'static str,
+}
+
+/// The application is terminating.
+///
+/// This is the last event delivered through the bus.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Terminating;
+
+#[derive(Default)]
+struct Inner {
+    // The values are Sender<T> for T matching the TypeId
+    subscribers: HashMap<TypeId, Vec<Box<dyn Any + Send>>>,
+    closed: bool,
+}
+
+/// The event bus.
+///
+/// Cheap to clone, all the clones share the same subscribers. See the [module
+/// documentation][crate::events].
+#[derive(Clone, Default)]
+pub struct Events(Arc<Mutex<Inner>>);
+
+impl Debug for Events {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Events").finish()
+    }
+}
+
+impl Events {
+    /// Creates a new bus without any subscribers.
+    ///
+    /// Usually, the bus owned by [`Spirit`][crate::Spirit] is used instead.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to events of the given type.
+    ///
+    /// The returned channel receives all the events of that type published from now on. Dropping
+    /// it unsubscribes. If the bus is already closed, the channel is disconnected right away.
+    pub fn subscribe<T: Clone + Send + 'static>(&self) -> Receiver<T> {
+        let (sender, receiver) = mpsc::channel::<T>();
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if !inner.closed {
+            inner
+                .subscribers
+                .entry(TypeId::of::<T>())
+                .or_insert_with(Vec::new)
+                .push(Box::new(sender));
+        }
+        receiver
+    }
+
+    /// Sends a copy of the event to all the subscribers of its type.
+    ///
+    /// This doesn't block. The event is dropped if there are no subscribers.
+    pub fn publish<T: Clone + Send + 'static>(&self, event: T) {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(subscribers) = inner.subscribers.get_mut(&TypeId::of::<T>()) {
+            trace!(
+                "Publishing {} to {} subscribers",
+                std::any::type_name::<T>(),
+                subscribers.len()
+            );
+            // Drop the subscribers that are gone
+            subscribers.retain(|sender| {
+                sender
+                    .downcast_ref::<Sender<T>>()
+                    .expect("Subscriber of wrong type")
+                    .send(event.clone())
+                    .is_ok()
+            });
+        }
+    }
+
+    /// Disconnects all the subscribers and stops accepting new ones.
+    pub(crate) fn close(&self) {
+        let mut inner = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.closed = true;
+        inner.subscribers.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed() {
+        let events = Events::new();
+        let changed_1 = events.subscribe::<ConfigChanged>();
+        let changed_2 = events.subscribe::<ConfigChanged>();
+        let installed = events.subscribe::<ResourceInstalled>();
+        events.publish(ConfigChanged);
+        events.publish(ResourceInstalled { pipeline: "x" });
+        assert_eq!(ConfigChanged, changed_1.try_recv().unwrap());
+        assert_eq!(ConfigChanged, changed_2.try_recv().unwrap());
+        assert!(changed_1.try_recv().is_err());
+        assert_eq!("x", installed.try_recv().unwrap().pipeline);
+    }
+
+    #[test]
+    fn unsubscribe_and_close() {
+        let events = Events::new();
+        let changed = events.subscribe::<ConfigChanged>();
+        drop(events.subscribe::<ConfigChanged>());
+        events.publish(ConfigChanged);
+        assert_eq!(
+            1,
+            events.0.lock().unwrap().subscribers[&TypeId::of::<ConfigChanged>()].len()
+        );
+        events.close();
+        assert_eq!(ConfigChanged, changed.recv().unwrap());
+        assert!(changed.recv().is_err());
+        assert!(events.subscribe::<ConfigChanged>().recv().is_err());
+    }
+}
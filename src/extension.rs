@@ -24,6 +24,7 @@ use std::sync::Arc;
 use log::warn;
 
 use crate::bodies::InnerBody;
+use crate::events::Events;
 use crate::validation::Action;
 use crate::{AnyError, Spirit};
 
@@ -436,6 +437,35 @@ pub trait Extensible: Sized {
     ///
     /// [`run`]: crate::SpiritBuilder::run
     fn autojoin_bg_thread(self, autojoin: Autojoin) -> Self;
+
+    /// Access to the [event bus][crate::events] of the [`Spirit`].
+    ///
+    /// The bus is shared between the [`Builder`] and the [`Spirit`] created from it, so it is
+    /// possible to subscribe to events during the setup. If this is an error (from previous
+    /// failed setup), a detached bus is returned.
+    ///
+    /// [`Spirit`]: crate::Spirit
+    /// [`Builder`]: crate::Builder
+    fn events(&self) -> Events;
+
+    /// Registers a component and its version, to be listed by `--version`.
+    ///
+    /// Extension crates register themselves (usually by their
+    /// [`Installer`][crate::fragment::Installer]), so the output identifies the exact stack the
+    /// application is built from. Once the command line is parsed (eg. on an already built
+    /// [`Spirit`][crate::Spirit]), this has no effect.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    /// use spirit::prelude::*;
+    ///
+    /// Spirit::<Empty, Empty>::new()
+    ///     .component(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+    ///     .run(|_| Ok(()));
+    /// ```
+    fn component(self, name: &str, version: &str) -> Self;
 }
 
 impl<C> Extensible for Result<C, AnyError>
@@ -540,6 +570,14 @@ where
     fn autojoin_bg_thread(self, autojoin: Autojoin) -> Self {
         self.map(|me| me.autojoin_bg_thread(autojoin))
     }
+
+    fn events(&self) -> Events {
+        self.as_ref().map(Extensible::events).unwrap_or_default()
+    }
+
+    fn component(self, name: &str, version: &str) -> Self {
+        self.map(|me| me.component(name, version))
+    }
 }
 
 /// The basic extension trait.
@@ -0,0 +1,310 @@
+//! Feature flags driven by the configuration.
+//!
+//! Sometimes a new code path needs to be switched on (or off again) at runtime, without
+//! deploying a new version. This module reads a table of flags from the configuration (usually
+//! `[features]`) and exposes them through a [`FlagHandle`]. As the flags are part of the
+//! configuration, they are changed by the usual reload.
+//!
+//! Each flag is either a plain boolean or a percentage rollout:
+//!
+//! ```toml
+//! [features]
+//! new-codepath = true
+//! experimental-cache = { rollout = 25 }
+//! ```
+//!
+//! A rollout flag is enabled for the given percentage of... something. With
+//! [`enabled`][FlagHandle::enabled], the decision is made once per process (randomly, but stable
+//! for the lifetime of the process and the flag), so the percentage of instances of a service have
+//! it on. With [`enabled_for`][FlagHandle::enabled_for], the decision is made per key (eg. a user
+//! ID), so the same user gets the same answer each time (and from all the instances built by the
+//! same compiler).
+//!
+//! Flags not present in the configuration are disabled.
+//!
+//! Checking a flag is cheap (there's no locking), so it is fine to do it on each request.
+//!
+//! # Configuration help
+//!
+//! The [`Flags`] don't implement `StructDoc`, as the set of flags is not known up front. Mark the
+//! field as a leaf with `#[structdoc(leaf = "Feature flags")]` if the configuration derives it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::flags::{FlagHandle, Flags};
+//! use spirit::prelude::*;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     features: Flags,
+//! }
+//!
+//! let flags = FlagHandle::new();
+//! flags.on_change("new-codepath", |enabled| println!("New code path: {}", enabled));
+//!
+//! Spirit::<Empty, Cfg>::new()
+//!     .with(flags.extension(|cfg: &Cfg| cfg.features.clone()))
+//!     .run(move |_| {
+//!         if flags.enabled("new-codepath") {
+//!             // The new stuff
+//!         } else {
+//!             // The old stuff
+//!         }
+//!         Ok(())
+//!     });
+//! ```
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+
+use crate::extension::{Extensible, Extension};
+use crate::schema::{Schema, Schematic, Type};
+
+/// A single feature flag.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Flag {
+    /// The flag is either on or off.
+    Enabled(bool),
+    /// The flag is on for the given percentage (0 to 100).
+    Rollout {
+        /// The percentage.
+        rollout: f64,
+    },
+}
+
+impl Flag {
+    /// Decides the flag for the point in the `0 <= point < 100` range.
+    fn active(&self, point: f64) -> bool {
+        match self {
+            Flag::Enabled(enabled) => *enabled,
+            Flag::Rollout { rollout } => point < *rollout,
+        }
+    }
+}
+
+impl Default for Flag {
+    fn default() -> Self {
+        Flag::Enabled(false)
+    }
+}
+
+/// The configuration of all the flags.
+///
+/// This is usually the `[features]` table of the configuration.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct Flags(pub HashMap<String, Flag>);
+
+impl Schematic for Flags {
+    fn schema() -> Schema {
+        let rollout = Schema::object().field_schema(
+            "rollout",
+            Schema::new(Type::Number)
+                .range(Some(0.0), Some(100.0))
+                .description("The percentage of keys the flag is on for."),
+        );
+        Schema::map(Schema::any_of(vec![bool::schema(), rollout]))
+    }
+}
+
+/// Places the key (together with the flag name, so each flag gets different set of keys) into the
+/// `0 <= point < 100` range.
+fn point<K: Hash + ?Sized>(name: &str, key: &K) -> f64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    key.hash(&mut hasher);
+    (hasher.finish() % 10_000) as f64 / 100.0
+}
+
+type ChangeHook = Box<dyn FnMut(bool) + Send>;
+
+struct Inner {
+    flags: ArcSwap<Flags>,
+    /// The key of this process for rollouts without explicit key.
+    seed: u64,
+    hooks: Mutex<HashMap<String, Vec<ChangeHook>>>,
+}
+
+/// Access to the current feature flags.
+///
+/// The handle is cheap to clone and all the clones share the same flags.
+///
+/// See the [module documentation](index.html).
+#[derive(Clone)]
+pub struct FlagHandle {
+    inner: Arc<Inner>,
+}
+
+impl FlagHandle {
+    /// Creates a handle with all the flags disabled.
+    ///
+    /// The flags get set by the [`extension`][FlagHandle::extension] (or manually by
+    /// [`set`][FlagHandle::set]).
+    pub fn new() -> Self {
+        let seed = RandomState::new().build_hasher().finish();
+        Self {
+            inner: Arc::new(Inner {
+                flags: ArcSwap::from_pointee(Flags::default()),
+                seed,
+                hooks: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    /// Is the flag enabled?
+    ///
+    /// Rollouts are decided once per process.
+    pub fn enabled(&self, name: &str) -> bool {
+        Self::check(&self.inner.flags.load(), name, &self.inner.seed)
+    }
+
+    /// Is the flag enabled for the given key?
+    ///
+    /// Rollouts are decided by the key, so the same key gets the same answer each time (unless
+    /// the percentage changes).
+    pub fn enabled_for<K: Hash + ?Sized>(&self, name: &str, key: &K) -> bool {
+        Self::check(&self.inner.flags.load(), name, key)
+    }
+
+    fn check<K: Hash + ?Sized>(flags: &Flags, name: &str, key: &K) -> bool {
+        flags
+            .0
+            .get(name)
+            .map(|flag| flag.active(point(name, key)))
+            .unwrap_or(false)
+    }
+
+    /// Registers a hook called whenever the flag changes.
+    ///
+    /// The hook gets the new value (as returned by [`enabled`][FlagHandle::enabled]). It is
+    /// called only when the value actually changes, including the initial configuration if it
+    /// enables the flag.
+    ///
+    /// The hooks are called with an internal lock held. They must not register more hooks.
+    pub fn on_change<F>(&self, name: &str, hook: F)
+    where
+        F: FnMut(bool) + Send + 'static,
+    {
+        self.inner
+            .hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name.to_owned())
+            .or_insert_with(Vec::new)
+            .push(Box::new(hook));
+    }
+
+    /// Replaces the flags, calling the change hooks.
+    pub fn set(&self, flags: Flags) {
+        let mut hooks = self
+            .inner
+            .hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let old = self.inner.flags.swap(Arc::new(flags));
+        let new = self.inner.flags.load();
+        for (name, hooks) in hooks.iter_mut() {
+            let before = Self::check(&old, name, &self.inner.seed);
+            let after = Self::check(&new, name, &self.inner.seed);
+            if before != after {
+                for hook in hooks {
+                    hook(after);
+                }
+            }
+        }
+    }
+
+    /// An extension that keeps the flags in sync with the configuration.
+    ///
+    /// The extractor picks the flags from the configuration.
+    pub fn extension<E, F>(&self, extractor: F) -> impl Extension<E>
+    where
+        E: Extensible<Ok = E>,
+        F: Fn(&E::Config) -> Flags + Send + 'static,
+    {
+        let handle = self.clone();
+        move |e: E| e.on_config(move |_: &_, cfg: &Arc<_>| handle.set(extractor(cfg)))
+    }
+}
+
+impl Default for FlagHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for FlagHandle {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("FlagHandle")
+            .field("flags", &self.inner.flags.load().0)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn flags(cfg: &str) -> Flags {
+        toml::from_str(cfg).unwrap()
+    }
+
+    #[test]
+    fn toggle() {
+        let handle = FlagHandle::new();
+        assert!(!handle.enabled("a"));
+        handle.set(flags("a = true\nb = false"));
+        assert!(handle.enabled("a"));
+        assert!(!handle.enabled("b"));
+        assert!(!handle.enabled("c"));
+        assert!(handle.enabled_for("a", "key"));
+    }
+
+    #[test]
+    fn rollout() {
+        let handle = FlagHandle::new();
+        handle.set(flags(
+            "none = { rollout = 0 }\nall = { rollout = 100 }\nhalf = { rollout = 50 }",
+        ));
+        assert!(!handle.enabled("none"));
+        assert!(handle.enabled("all"));
+        let enabled = (0..1000).filter(|i| handle.enabled_for("half", i)).count();
+        assert!(enabled > 400 && enabled < 600, "{}", enabled);
+        // Stable for the same key
+        assert_eq!(
+            handle.enabled_for("half", &42),
+            handle.enabled_for("half", &42)
+        );
+    }
+
+    #[test]
+    fn hooks() {
+        let handle = FlagHandle::new();
+        let called = Arc::new(AtomicUsize::new(0));
+        let called_cp = Arc::clone(&called);
+        handle.on_change("a", move |enabled| {
+            assert_eq!(called_cp.fetch_add(1, Ordering::Relaxed) == 0, enabled);
+        });
+        handle.set(flags("b = true"));
+        assert_eq!(0, called.load(Ordering::Relaxed));
+        handle.set(flags("a = true"));
+        assert_eq!(1, called.load(Ordering::Relaxed));
+        handle.set(flags("a = true\nb = false"));
+        assert_eq!(1, called.load(Ordering::Relaxed));
+        handle.set(flags(""));
+        assert_eq!(2, called.load(Ordering::Relaxed));
+    }
+}
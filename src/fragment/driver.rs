@@ -764,6 +764,158 @@ where
     }
 }
 
+/// Returns the name for the sub-fragment under the `key`.
+///
+/// The names need to be `&'static str`, so they are leaked. But only once per key, so it doesn't
+/// grow with the number of reloads.
+fn key_name(
+    names: &mut HashMap<String, &'static str>,
+    name: &'static str,
+    key: &str,
+) -> &'static str {
+    if let Some(name) = names.get(key) {
+        return name;
+    }
+    let sub_name: &'static str = Box::leak(format!("{}.{}", name, key).into_boxed_str());
+    names.insert(key.to_owned(), sub_name);
+    sub_name
+}
+
+/// A plumbing [`Driver`] for maps of fragments.
+///
+/// This is the driver for things like `BTreeMap<String, F>` or `HashMap<String, F>`. Unlike the
+/// [`SeqDriver`], the instances are tracked by their keys, not by their values or positions. A
+/// changed value under the same key is handled by the same slave driver (therefore it can reuse
+/// its caches) and the key is used as part of the name passed down (eg. a map in the `servers`
+/// pipeline with `api` key is named `servers.api`).
+#[derive(Debug)]
+pub struct MapDriver<Item, SlaveDriver> {
+    id_gen: IdGen,
+    sub_drivers: HashMap<String, ItemDriver<SlaveDriver>>,
+    names: HashMap<String, &'static str>,
+    transaction_open: bool,
+    _item: PhantomData<fn(&Item)>,
+}
+
+impl<Item, SlaveDriver> Default for MapDriver<Item, SlaveDriver> {
+    fn default() -> Self {
+        Self {
+            id_gen: IdGen::new(),
+            sub_drivers: HashMap::new(),
+            names: HashMap::new(),
+            transaction_open: false,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<F, I, SlaveDriver> Driver<F> for MapDriver<I, SlaveDriver>
+where
+    F: Fragment,
+    I: Fragment,
+    for<'a> &'a F: IntoIterator<Item = (&'a String, &'a I)>,
+    SlaveDriver: Driver<I> + Default,
+{
+    type SubFragment = SlaveDriver::SubFragment;
+    fn instructions<T, Ins>(
+        &mut self,
+        fragment: &F,
+        transform: &mut T,
+        name: &'static str,
+    ) -> Result<Vec<Instruction<T::OutputResource>>, Vec<AnyError>>
+    where
+        T: Transformation<<Self::SubFragment as Fragment>::Resource, Ins, Self::SubFragment>,
+    {
+        assert!(!self.transaction_open);
+        trace!("Updating map {}", name);
+        self.transaction_open = true;
+        let mut instructions = Vec::new();
+        let mut errors = Vec::new();
+
+        for (key, sub) in fragment {
+            let sub_name = key_name(&mut self.names, name, key);
+            let slot = self.sub_drivers.entry(key.clone()).or_insert_with(|| {
+                trace!("New key in map {}", sub_name);
+                ItemDriver {
+                    new: true,
+                    ..ItemDriver::default()
+                }
+            });
+            slot.used = true;
+            match slot.driver.instructions(sub, transform, sub_name) {
+                Ok(new_instructions) => {
+                    let mapping = if slot.new {
+                        &mut slot.id_mapping
+                    } else {
+                        slot.proposed_mapping = Some(slot.id_mapping.clone());
+                        slot.proposed_mapping.as_mut().unwrap()
+                    };
+                    instructions.extend(mapping.translate(&mut self.id_gen, new_instructions));
+                }
+                Err(errs) => errors.extend(errs),
+            }
+        }
+
+        for (key, slot) in &self.sub_drivers {
+            if !slot.used {
+                trace!("Key {} removed from map {}", key, name);
+                instructions.extend(
+                    slot.id_mapping
+                        .active_target_ids()
+                        .cloned()
+                        .map(Instruction::DropSpecific),
+                );
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(instructions)
+        } else {
+            self.abort(name);
+            Err(errors)
+        }
+    }
+    fn confirm(&mut self, name: &'static str) {
+        trace!("Confirming the whole map {}", name);
+        assert!(self.transaction_open);
+        self.transaction_open = false;
+        self.sub_drivers.retain(|_, s| s.used);
+        for (key, sub) in &mut self.sub_drivers {
+            sub.driver.confirm(key_name(&mut self.names, name, key));
+            if let Some(mapping) = sub.proposed_mapping.take() {
+                sub.id_mapping = mapping;
+            }
+            sub.new = false;
+            sub.used = false;
+        }
+    }
+    fn abort(&mut self, name: &'static str) {
+        trace!("Aborting the whole map {}", name);
+        assert!(self.transaction_open);
+        self.transaction_open = false;
+        self.sub_drivers.retain(|_, s| !s.new);
+        for (key, sub) in &mut self.sub_drivers {
+            if sub.used {
+                sub.driver.abort(key_name(&mut self.names, name, key));
+                sub.proposed_mapping.take();
+                sub.used = false;
+            }
+            assert!(
+                sub.proposed_mapping.is_none(),
+                "Proposed mapping for something not used"
+            );
+        }
+    }
+    fn maybe_cached(&self, fragment: &F, name: &'static str) -> bool {
+        fragment.into_iter().any(|(key, sub)| {
+            self.sub_drivers
+                .get(key)
+                .map(|slave| slave.driver.maybe_cached(sub, name))
+                .unwrap_or(false)
+        })
+    }
+}
+
 /// A [`Driver`] for a single-shot initialization.
 ///
 /// This driver creates the resource only the first time it is called. On an attempt to call it
@@ -876,3 +1028,60 @@ impl<'a, F: Fragment, Inner: Driver<F>> Driver<&'a F> for RefDriver<Inner> {
         self.0.maybe_cached(*fragment, name)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::fragment::pipeline::NopTransformation;
+    use crate::fragment::Stackable;
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Frag(u32);
+
+    impl Stackable for Frag {}
+
+    crate::simple_fragment! {
+        impl Fragment for Frag {
+            type Driver = CacheEq<Frag>;
+            type Resource = u32;
+            type Installer = ();
+            fn create(&self, _: &'static str) -> Result<u32, AnyError> {
+                Ok(self.0)
+            }
+        }
+    }
+
+    /// Runs the driver, returns number of installs and drops.
+    fn run(driver: &mut MapDriver<Frag, CacheEq<Frag>>, map: &[(&str, u32)]) -> (usize, usize) {
+        let map = map
+            .iter()
+            .map(|(k, v)| (k.to_string(), Frag(*v)))
+            .collect::<BTreeMap<_, _>>();
+        let instructions = driver
+            .instructions::<_, ()>(&map, &mut NopTransformation, "servers")
+            .unwrap();
+        driver.confirm("servers");
+        instructions
+            .iter()
+            .fold((0, 0), |(installs, drops), i| match i {
+                Instruction::Install { .. } => (installs + 1, drops),
+                _ => (installs, drops + 1),
+            })
+    }
+
+    #[test]
+    fn map_by_keys() {
+        let mut driver = MapDriver::default();
+        assert_eq!((2, 0), run(&mut driver, &[("api", 1), ("admin", 2)]));
+        // Nothing changed
+        assert_eq!((0, 0), run(&mut driver, &[("api", 1), ("admin", 2)]));
+        // Same value, but under different key is a new instance
+        assert_eq!((1, 1), run(&mut driver, &[("api", 1), ("other", 2)]));
+        // Changed value under the same key
+        assert_eq!((1, 1), run(&mut driver, &[("api", 3), ("other", 2)]));
+        assert_eq!((0, 1), run(&mut driver, &[("api", 3)]));
+        assert_eq!(Some(&"servers.api"), driver.names.get("api"));
+    }
+}
@@ -0,0 +1,246 @@
+//! Per-fragment logging overrides.
+//!
+//! When one of many servers misbehaves, turning up the logging of the whole process is often too
+//! much noise. The [`LogSettings`] can be flattened into a configuration fragment, so each
+//! instance can have its own log target and level:
+//!
+//! ```toml
+//! [[listen]]
+//! port = 1234
+//! log-level = "trace"
+//! ```
+//!
+//! The fragment then logs its messages through a [`FragmentLog`] (usually by the
+//! [`fragment_log`][crate::fragment_log] macro).
+//!
+//! * If the `log-level` is set, messages up to that level are sent to the logger, even if the
+//!   global level is lower. More verbose ones are dropped.
+//! * If the `log-target` is set, it is used as the target of the messages. If only the level is
+//!   set, the target is `<module>::<pipeline name>`, so the override doesn't affect other
+//!   instances of the same fragment.
+//! * Without the settings, the messages are logged as usual.
+//!
+//! The logger itself may still filter the messages by their target. The [`spirit-log`] crate
+//! takes the [`level_overrides`] into account when creating its loggers ‒ note that during a
+//! configuration reload the loggers are usually created before the other fragments, so a changed
+//! level may show there only after the next reload.
+//!
+//! [`spirit-log`]: https://docs.rs/spirit-log
+
+use std::collections::HashMap;
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use log::{Level, LevelFilter, Record, STATIC_MAX_LEVEL};
+use once_cell::sync::Lazy;
+use serde::de::{Deserializer, Error as DeError, Unexpected};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+/// Currently active overrides, target -> (ID of the registering log, level).
+static OVERRIDES: Lazy<Mutex<HashMap<String, (u64, LevelFilter)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+fn deserialize_level<'de, D: Deserializer<'de>>(d: D) -> Result<Option<LevelFilter>, D::Error> {
+    Option::<String>::deserialize(d)?
+        .map(|s| {
+            s.parse()
+                .map_err(|_| DeError::invalid_value(Unexpected::Str(&s), &"log level"))
+        })
+        .transpose()
+}
+
+fn serialize_level<S: Serializer>(level: &Option<LevelFilter>, s: S) -> Result<S::Ok, S::Error> {
+    match level {
+        Some(level) => s.serialize_some(&level.to_string().to_lowercase()),
+        None => s.serialize_none(),
+    }
+}
+
+/// Logging settings of a single fragment.
+///
+/// Meant to be flattened into a configuration fragment with `#[serde(flatten)]`.
+///
+/// # Fields
+///
+/// * `log-target`: The log target used for messages of this fragment.
+/// * `log-level`: The most verbose level of messages logged by this fragment (`off`, `error`,
+///   `warn`, `info`, `debug` or `trace`).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct LogSettings {
+    /// The log target of messages of this fragment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    log_target: Option<String>,
+
+    /// The log level of this fragment, overriding the global one.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_level",
+        serialize_with = "serialize_level",
+        skip_serializing_if = "Option::is_none"
+    )]
+    #[cfg_attr(feature = "cfg-help", structdoc(leaf = "Log level"))]
+    log_level: Option<LevelFilter>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    id: u64,
+    target: String,
+    level: Option<LevelFilter>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if self.level.is_some() {
+            let mut overrides = OVERRIDES.lock().unwrap_or_else(PoisonError::into_inner);
+            if overrides.get(&self.target).map(|(id, _)| *id) == Some(self.id) {
+                overrides.remove(&self.target);
+            }
+        }
+    }
+}
+
+/// A logging handle of one fragment.
+///
+/// Created from the [`LogSettings`] of the fragment (usually when creating its resource) and
+/// passed to wherever the fragment logs from. The override stays active as long as any clone of
+/// the handle is alive.
+///
+/// See the [module documentation](index.html).
+#[derive(Clone, Debug)]
+pub struct FragmentLog {
+    inner: Arc<Inner>,
+}
+
+impl FragmentLog {
+    /// Creates the handle.
+    ///
+    /// The `default_target` is used when the target is not configured (usually `module_path!()`),
+    /// the `name` is the name of the pipeline.
+    pub fn new(default_target: &str, name: &str, settings: &LogSettings) -> Self {
+        let target = match (&settings.log_target, settings.log_level) {
+            (Some(target), _) => target.clone(),
+            (None, Some(_)) => format!("{}::{}", default_target, name),
+            (None, None) => default_target.to_owned(),
+        };
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        if let Some(level) = settings.log_level {
+            OVERRIDES
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(target.clone(), (id, level));
+        }
+        Self {
+            inner: Arc::new(Inner {
+                id,
+                target,
+                level: settings.log_level,
+            }),
+        }
+    }
+
+    /// The log target of the messages.
+    pub fn target(&self) -> &str {
+        &self.inner.target
+    }
+
+    /// Would a message on this level be logged?
+    pub fn enabled(&self, level: Level) -> bool {
+        let max = self.inner.level.unwrap_or_else(log::max_level);
+        level <= max && level <= STATIC_MAX_LEVEL
+    }
+
+    /// Logs a message.
+    ///
+    /// Usually called through the [`fragment_log`][crate::fragment_log] macro.
+    pub fn log(&self, level: Level, args: Arguments) {
+        if self.enabled(level) {
+            log::logger().log(
+                &Record::builder()
+                    .args(args)
+                    .level(level)
+                    .target(&self.inner.target)
+                    .build(),
+            );
+        }
+    }
+}
+
+/// The log targets with their levels currently overridden by some [`FragmentLog`].
+///
+/// This is meant for loggers, to let these messages through.
+pub fn level_overrides() -> Vec<(String, LevelFilter)> {
+    OVERRIDES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .map(|(target, (_, level))| (target.clone(), *level))
+        .collect()
+}
+
+/// Logs a message through a [`FragmentLog`].
+///
+/// Used like the macros from the [`log`] crate, but with the handle in front.
+///
+/// ```rust
+/// use log::Level;
+/// use spirit::fragment::logging::{FragmentLog, LogSettings};
+///
+/// let log = FragmentLog::new(module_path!(), "listener", &LogSettings::default());
+/// spirit::fragment_log!(log, Level::Info, "Accepted connection #{}", 42);
+/// ```
+#[macro_export]
+macro_rules! fragment_log {
+    ($log: expr, $level: expr, $($arg: tt)+) => {
+        $log.log($level, format_args!($($arg)+))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(cfg: &str) -> LogSettings {
+        toml::from_str(cfg).unwrap()
+    }
+
+    #[test]
+    fn targets_and_levels() {
+        let plain = FragmentLog::new("app::net", "plain", &LogSettings::default());
+        assert_eq!("app::net", plain.target());
+
+        let leveled = FragmentLog::new("app::net", "leveled", &settings("log-level = \"TRACE\""));
+        assert_eq!("app::net::leveled", leveled.target());
+        assert!(leveled.enabled(Level::Trace) || STATIC_MAX_LEVEL < LevelFilter::Trace);
+
+        let quiet = FragmentLog::new(
+            "app::net",
+            "quiet",
+            &settings("log-target = \"quiet\"\nlog-level = \"error\""),
+        );
+        assert_eq!("quiet", quiet.target());
+        assert!(!quiet.enabled(Level::Warn));
+        assert!(quiet.enabled(Level::Error));
+
+        let overrides = level_overrides();
+        assert!(overrides.contains(&("quiet".to_owned(), LevelFilter::Error)));
+        drop(quiet);
+        assert!(!level_overrides()
+            .iter()
+            .any(|(target, _)| target == "quiet"));
+    }
+
+    #[test]
+    fn serde_roundtrip() {
+        let cfg = settings("log-target = \"x\"\nlog-level = \"Debug\"");
+        let serialized = toml::to_string(&cfg).unwrap();
+        assert_eq!("log-target = \"x\"\nlog-level = \"debug\"\n", serialized);
+        assert!(toml::from_str::<LogSettings>("log-level = \"loud\"").is_err());
+    }
+}
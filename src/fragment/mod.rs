@@ -60,6 +60,21 @@
 //!
 //! TODO: An example
 //!
+//! # Optional fragments
+//!
+//! A fragment marked as [`Optional`] (which includes all the [`Stackable`] ones) can be used as
+//! `Option<F>`. The pipeline then creates and installs the resource when the configuration section
+//! appears and uninstalls it (drops its [`UninstallHandle`]) when the section goes away.
+//!
+//! Installers of singleton resources often don't do anything on uninstallation, because the next
+//! resource simply replaces the previous one. Such installers don't go well with optional
+//! fragments ‒ the [`Slot`] can be used instead, as it empties itself when the resource is
+//! uninstalled.
+//!
+//! Similarly, maps of fragments (like `BTreeMap<String, F>`) create an instance for each key. The
+//! [`scope`] module builds on that for multi-tenant applications, where each tenant needs its own
+//! set of resources.
+//!
 //! # How to create a fragment
 //!
 //! First, try to do it manually, without fragments or pipeline ‒ eg. write the code that takes the
@@ -93,20 +108,26 @@
 //! [`UninstallHandle`]: crate::fragment::Installer::UninstallHandle
 //! [`Stackable`]: crate::fragment::Stackable
 //! [`Comparable`]: crate::fragment::driver::Comparable
+//! [`Optional`]: crate::fragment::Optional
+//! [`Slot`]: crate::fragment::slot::Slot
 //! [`spirit_tokio`]: https://docs.rs/spirit-tokio
-use std::collections::{BTreeSet, BinaryHeap, HashSet, LinkedList};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, HashSet, LinkedList};
 use std::hash::{BuildHasher, Hash};
 
 use log::trace;
 use serde::de::DeserializeOwned;
 use structopt::StructOpt;
 
-use self::driver::{Driver, RefDriver, SeqDriver};
+use self::driver::{Driver, MapDriver, RefDriver, SeqDriver};
 use crate::extension::Extensible;
 use crate::AnyError;
 
 pub mod driver;
+pub mod logging;
 pub mod pipeline;
+pub mod retry;
+pub mod scope;
+pub mod slot;
 
 /// An entity that is able to install a resource.
 ///
@@ -212,12 +233,21 @@ where
 /// configuration, in addition to implementing the [`Fragment`] trait, mark the fragment by this
 /// trait. Then a default implementation of [`Fragment`] will be provided for the common
 /// collections.
+///
+/// This includes maps with `String` keys (`BTreeMap<String, F>` and `HashMap<String, F>`). The
+/// instances in a map are tracked by their keys and the keys are also part of their names (see
+/// [`MapDriver`]).
 pub trait Stackable {}
 
 /// A trait similar to [`Stackable`], but marking the ability to be optional.
 ///
 /// This allows using the [`Fragment`] as `Option<F>`. This is automatically implemented for all
 /// [`Stackable`] fragments.
+///
+/// Fragments that can have only one instance at a time may implement it manually. In that case,
+/// make sure missing configuration actually removes the resource ‒ dropping the
+/// [`UninstallHandle`][Installer::UninstallHandle] needs to do something, eg. by installing into a
+/// [`Slot`][crate::fragment::slot::Slot].
 pub trait Optional {}
 
 impl<F: Stackable> Optional for F {}
@@ -411,6 +441,46 @@ fragment_for_seq!(Stackable => BinaryHeap<T> where T: Ord);
 fragment_for_seq!(Stackable => HashSet<T, S> where T: Eq + Hash, S: BuildHasher);
 fragment_for_seq!(Optional => Option<T>);
 
+macro_rules! fragment_for_map {
+    ($container: ident<$base: ident $(, $extra: ident)*> $(where $($bounds: tt)+)*) => {
+        impl<$base: Fragment + Stackable + 'static $(, $extra)*> Fragment
+            for $container<String, $base $(, $extra)*>
+        $(
+            where
+            $($bounds)+
+        )*
+        {
+            type Driver = MapDriver<$base, $base::Driver>;
+            type Installer = SeqInstaller<$base::Installer>;
+            type Seed = Vec<$base::Seed>;
+            type Resource = Vec<$base::Resource>;
+            const RUN_BEFORE_CONFIG: bool = $base::RUN_BEFORE_CONFIG;
+            fn make_seed(&self, name: &'static str) -> Result<Self::Seed, AnyError> {
+                self.values().map(|i| i.make_seed(name)).collect()
+            }
+            fn make_resource(&self, seed: &mut Self::Seed, name: &'static str)
+                -> Result<Self::Resource, AnyError>
+            {
+                self.values()
+                    .zip(seed)
+                    .map(|(i, s)| i.make_resource(s, name))
+                    .collect()
+            }
+            fn init<B: Extensible<Ok = B>>(builder: B, name: &'static str) -> Result<B, AnyError>
+            where
+                B::Config: DeserializeOwned + Send + Sync + 'static,
+                B::Opts: StructOpt + Send + Sync + 'static,
+            {
+                $base::init(builder, name)
+            }
+        }
+    }
+}
+
+// Maps keyed by names, like `[servers.api]` and `[servers.admin]` tables in TOML.
+fragment_for_map!(BTreeMap<T>);
+fragment_for_map!(HashMap<T, S> where S: BuildHasher);
+
 /// A helper macro to implement a simple [`Fragment`].
 ///
 /// The full implementation of a [`Fragment`] requires a lot of work that is not usually needed.
@@ -9,20 +9,27 @@
 //! documentation to provide some guidance and clickable links.
 //!
 //! [`Pipeline`]: crate::fragment::pipeline::Pipeline
+use std::any;
 use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::marker::PhantomData;
+use std::mem;
 use std::ops::Deref;
 use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
 
-use log::{debug, trace};
+use err_context::prelude::*;
+use log::{debug, error, trace};
 use serde::de::DeserializeOwned;
 use structopt::StructOpt;
 
 use super::driver::{CacheId, Driver, Instruction};
+use super::retry::{Backoff, Retrier};
 use super::{Extractor, Fragment, Installer, Transformation};
+use crate::events::{Events, ResourceInstalled};
 use crate::extension::{Extensible, Extension};
+use crate::status::ResourceStatus;
 use crate::validation::Action;
 use crate::AnyError;
 
@@ -100,13 +107,23 @@ where
             _type: PhantomData,
         }
     }
-    fn interpret(&mut self, instruction: Instruction<R>, name: &'static str) {
+    /// Interprets the instruction, returning the uninstall handles it removed.
+    ///
+    /// The caller decides when to drop them (and therefore uninstall the resources).
+    fn interpret(
+        &mut self,
+        instruction: Instruction<R>,
+        name: &'static str,
+    ) -> Vec<I::UninstallHandle> {
         match instruction {
-            Instruction::DropAll => self.cache.clear(),
-            Instruction::DropSpecific(id) => assert!(self.cache.remove(&id).is_some()),
+            Instruction::DropAll => self.cache.drain().map(|(_, handle)| handle).collect(),
+            Instruction::DropSpecific(id) => {
+                vec![self.cache.remove(&id).expect("Dropping unknown resource")]
+            }
             Instruction::Install { id, resource } => {
                 let handle = self.installer.install(resource, name);
                 assert!(self.cache.insert(id, handle).is_none());
+                Vec::new()
             }
         }
     }
@@ -129,6 +146,29 @@ where
     }
 }
 
+/// An [`Extractor`] wrapper that records the extracted fragment for the [`ResourceStatus`].
+///
+/// This isn't used by the user directly, it is constructed through the
+/// [`show_config`][Pipeline::show_config] method.
+pub struct ShowConfig<E> {
+    inner: E,
+    shown: Arc<Mutex<Option<String>>>,
+}
+
+impl<'a, O, C, E> Extractor<'a, O, C> for ShowConfig<E>
+where
+    E: Extractor<'a, O, C>,
+    E::Fragment: Debug,
+{
+    type Fragment = E::Fragment;
+    fn extract(&mut self, opts: &'a O, config: &'a C) -> E::Fragment {
+        let fragment = self.inner.extract(opts, config);
+        *self.shown.lock().unwrap_or_else(PoisonError::into_inner) =
+            Some(format!("{:?}", fragment));
+        fragment
+    }
+}
+
 /// A [`Transformation`] that does nothing.
 ///
 /// This is used at the beginning of constructing a [`Pipeline`] to plug the type parameter.
@@ -284,10 +324,22 @@ where
 /// * [`install`][Pipeline::install]: Sets or overrides the [`Installer`] the pipeline uses. This
 ///   is sometimes necessary, but sometimes either the [`Fragment`] or one of the
 ///   [`Transformation`]s provides one.
+/// * [`install_after`][Pipeline::install_after]: Declares the resources of this pipeline need to
+///   be installed after the ones of another pipeline.
+/// * [`transactional`][Pipeline::transactional]: Keeps the old resources around until all the
+///   new ones of the whole reload are installed.
+/// * [`show_config`][Pipeline::show_config]: Records the configuration in the status of the
+///   pipeline.
+/// * [`retry`][Pipeline::retry]: Retries creation of failed resources in the background instead
+///   of refusing the configuration.
 ///
 /// [`Resource`]: Fragment::Resource
 pub struct Pipeline<Fragment, Extractor, Driver, Transformation, SpiritType> {
     name: &'static str,
+    after: Vec<&'static str>,
+    transactional: bool,
+    shown_config: Arc<Mutex<Option<String>>>,
+    retry: Option<Backoff>,
     _fragment: PhantomData<dyn Fn(Fragment)>,
     _spirit: PhantomData<dyn Fn(SpiritType)>,
     extractor: Extractor,
@@ -304,6 +356,10 @@ impl Pipeline<(), (), (), (), ()> {
     pub fn new(name: &'static str) -> Self {
         Self {
             name,
+            after: Vec::new(),
+            transactional: false,
+            shown_config: Arc::new(Mutex::new(None)),
+            retry: None,
             _fragment: PhantomData,
             _spirit: PhantomData,
             extractor: (),
@@ -338,6 +394,10 @@ impl Pipeline<(), (), (), (), ()> {
         trace!("Configured extractor on pipeline {}", self.name);
         Pipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             extractor: e,
@@ -363,6 +423,10 @@ impl Pipeline<(), (), (), (), ()> {
         trace!("Configured extractor on pipeline {}", self.name);
         Pipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             extractor: CfgExtractor(e),
@@ -389,6 +453,10 @@ where
         Pipeline {
             driver,
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             extractor: self.extractor,
@@ -422,6 +490,10 @@ where
         trace!("Adding a transformation to pipeline {}", self.name);
         Pipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             driver: self.driver,
@@ -447,6 +519,10 @@ where
         trace!("Adding a map transformation to pipeline {}", self.name);
         Pipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             driver: self.driver,
@@ -466,6 +542,10 @@ where
         trace!("Setting installer to pipeline {}", self.name);
         Pipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
+            retry: self.retry,
             _fragment: PhantomData,
             _spirit: PhantomData,
             driver: self.driver,
@@ -492,6 +572,86 @@ where
         self
     }
 
+    /// Installs the resources of this pipeline only after the ones of another pipeline.
+    ///
+    /// By default, pipelines install their new resources in the order they were registered. This
+    /// allows to declare explicit dependencies by the names of other pipelines (eg. that a HTTP
+    /// server needs the database pool installed first). When the application terminates, the
+    /// resources are removed in the reverse order.
+    ///
+    /// Can be called multiple times to depend on multiple pipelines.
+    ///
+    /// # Limitations
+    ///
+    /// Only the installation (and the final removal) is ordered. The resources are still
+    /// *created* in the order the pipelines were registered, because that happens during the
+    /// validation of the new configuration, before any of the new resources is installed. The
+    /// creation therefore can't rely on the resources of the other pipeline, only the installed
+    /// resources can.
+    pub fn install_after(mut self, pipeline: &'static str) -> Self {
+        trace!("Pipeline {} is ordered after {}", self.name, pipeline);
+        self.after.push(pipeline);
+        self
+    }
+
+    /// Removes the old resources only after all the new ones are installed.
+    ///
+    /// New resources of all pipelines are created first, during validation of the configuration,
+    /// and if any of them fails, none is installed. But by default, each pipeline removes its old
+    /// resources right before installing the new ones, so for a short while during the reload
+    /// some pipelines already run the new resources and others none at all.
+    ///
+    /// In the transactional mode, the old resources of this pipeline are kept until *all* the
+    /// pipelines (and other validators) have installed their new ones. Only then are they
+    /// removed. This means both the old and new resource may exist at the same time for a short
+    /// while, which some resources may not tolerate (eg. when they lock a file).
+    pub fn transactional(self) -> Self {
+        trace!("Pipeline {} is transactional", self.name);
+        Self {
+            transactional: true,
+            ..self
+        }
+    }
+
+    /// Retries creating the resources in the background if it fails.
+    ///
+    /// By default, failure to create the resources of the pipeline refuses the whole new
+    /// configuration. With this, the failure is only recorded (and the old resources are kept)
+    /// and the creation is retried with the given [`Backoff`] until it succeeds. See the
+    /// [`retry`][crate::fragment::retry] module.
+    pub fn retry(self, backoff: Backoff) -> Self {
+        trace!("Pipeline {} retries with {:?}", self.name, backoff);
+        Self {
+            retry: Some(backoff),
+            ..self
+        }
+    }
+
+    /// Records the current configuration of the fragment in the [`ResourceStatus`].
+    ///
+    /// By default, the status (see [`Spirit::resources`][crate::Spirit::resources]) doesn't
+    /// contain the configuration, because it could contain secrets (passwords to databases,
+    /// private keys...). This turns it on for this pipeline. The configuration is formatted using
+    /// its `Debug` representation.
+    pub fn show_config(self) -> Pipeline<F, ShowConfig<E>, D, T, (O, C)> {
+        trace!("Pipeline {} shows its config", self.name);
+        Pipeline {
+            name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            extractor: ShowConfig {
+                inner: self.extractor,
+                shown: Arc::clone(&self.shown_config),
+            },
+            shown_config: self.shown_config,
+            retry: self.retry,
+            _fragment: PhantomData,
+            _spirit: PhantomData,
+            driver: self.driver,
+            transformation: self.transformation,
+        }
+    }
+
     // TODO: add_installer
 }
 
@@ -504,10 +664,16 @@ where
 /// construct one explicitly and use to run the pipeline in a manual way one day.
 pub struct CompiledPipeline<O, C, T, I, D, E, R, H> {
     name: &'static str,
+    after: Vec<&'static str>,
+    transactional: bool,
+    shown_config: Arc<Mutex<Option<String>>>,
+    status: ResourceStatus,
+    retry: Option<Retrier>,
     transformation: T,
     install_cache: InstallCache<I, O, C, R, H>,
     driver: D,
     extractor: E,
+    events: Events,
 }
 
 impl<O, C, T, I, D, E, R, H> CompiledPipeline<O, C, T, I, D, E, R, H> {
@@ -517,6 +683,15 @@ impl<O, C, T, I, D, E, R, H> CompiledPipeline<O, C, T, I, D, E, R, H> {
     fn explode(&mut self) -> (&'static str, &mut T, &mut D) {
         (self.name, &mut self.transformation, &mut self.driver)
     }
+
+    fn record_error(&mut self, errs: &[AnyError]) {
+        let errs = errs
+            .iter()
+            .map(|e| e.display("; ").to_string())
+            .collect::<Vec<_>>();
+        self.status.last_error = Some(errs.join(", "));
+        self.events.publish(self.status.clone());
+    }
 }
 
 /// Trait alias for one concrete lifetime of a [`Pipeline`].
@@ -554,18 +729,42 @@ where
     fn run(me: &Arc<Mutex<Self>>, opts: &'a O, config: &'a C) -> Result<Action, Vec<AnyError>> {
         let mut me_lock = me.lock().unwrap_or_else(PoisonError::into_inner);
         let fragment = me_lock.extractor.extract(opts, config);
+        let after = me_lock.after.clone();
         let (name, transform, driver) = me_lock.explode();
         debug!("Running pipeline {}", name);
-        let instructions = driver.instructions(&fragment, transform, name)?;
+        let instructions = match driver.instructions(&fragment, transform, name) {
+            Ok(instructions) => instructions,
+            Err(errs) => {
+                me_lock.record_error(&errs);
+                let compiled = &mut *me_lock;
+                if let Some(retry) = compiled.retry.as_mut() {
+                    error!(
+                        "Failed to create resources of {}, keeping the old ones: {}",
+                        name,
+                        compiled.status.last_error.as_ref().unwrap(),
+                    );
+                    retry.schedule(name);
+                    // Let the rest of the configuration through
+                    let action = Action::new().named(name);
+                    return Ok(after.into_iter().fold(action, Action::after));
+                }
+                return Err(errs);
+            }
+        };
         let me_f = Arc::clone(&me);
         let failure = move || {
             debug!("Rolling back pipeline {}", name);
-            me_f.lock()
-                .unwrap_or_else(PoisonError::into_inner)
-                .driver
-                .abort(name);
+            let mut me = me_f.lock().unwrap_or_else(PoisonError::into_inner);
+            me.driver.abort(name);
+            // Something else failed, but we still need another try
+            if let Some(retry) = me.retry.as_mut().filter(|r| r.failing()) {
+                retry.schedule(name);
+            }
         };
         let me_s = Arc::clone(&me);
+        // Old resources of transactional pipelines wait here for the cleanup
+        let retired = Arc::new(Mutex::new(Vec::new()));
+        let retired_s = Arc::clone(&retired);
         let success = move || {
             debug!(
                 "Success for pipeline {}, performing {} install instructions",
@@ -575,11 +774,48 @@ where
             let mut me = me_s.lock().unwrap_or_else(PoisonError::into_inner);
             me.driver.confirm(name);
             let name = me.name;
+            let changed = !instructions.is_empty();
+            let mut retired = Vec::new();
             for ins in instructions {
-                me.install_cache.interpret(ins, name);
+                let removed = me.install_cache.interpret(ins, name);
+                if me.transactional {
+                    retired.extend(removed);
+                }
+            }
+            me.status.instances = me.install_cache.cache.len();
+            me.status.config = me
+                .shown_config
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            me.status.last_error = None;
+            if let Some(retry) = me.retry.as_mut() {
+                retry.succeeded();
+            }
+            if changed {
+                me.status.installed = Some(SystemTime::now());
+            }
+            me.events.publish(me.status.clone());
+            if changed {
+                me.events.publish(ResourceInstalled { pipeline: name });
+            }
+            *retired_s.lock().unwrap_or_else(PoisonError::into_inner) = retired;
+        };
+        let cleanup = move || {
+            let retired = mem::replace(
+                &mut *retired.lock().unwrap_or_else(PoisonError::into_inner),
+                Vec::new(),
+            );
+            if !retired.is_empty() {
+                debug!("Removing {} old resources of {}", retired.len(), name);
             }
         };
-        Ok(Action::new().on_abort(failure).on_success(success))
+        let action = Action::new()
+            .on_abort(failure)
+            .on_success(success)
+            .on_cleanup(cleanup)
+            .named(name);
+        Ok(after.into_iter().fold(action, Action::after))
     }
 }
 
@@ -618,11 +854,36 @@ where
         builder = installer.init(builder, self.name)?;
         let compiled = CompiledPipeline {
             name: self.name,
+            after: self.after,
+            transactional: self.transactional,
+            shown_config: self.shown_config,
             driver: self.driver,
             extractor: self.extractor,
             install_cache: InstallCache::new(installer),
             transformation,
+            status: ResourceStatus::new(self.name, any::type_name::<F>()),
+            retry: self.retry.map(Retrier::new),
+            events: builder.events(),
         };
+        if let Some(retry) = &compiled.retry {
+            let trigger = retry.trigger();
+            builder = builder.run_before(move |spirit| {
+                let spirit = Arc::downgrade(spirit);
+                let reload = move || match spirit.upgrade() {
+                    Some(spirit) if !spirit.is_terminated() => {
+                        // The errors are already logged by the reload itself
+                        if let Err(e) = spirit.config_reload() {
+                            debug!("Retry reload failed: {}", e);
+                        }
+                        true
+                    }
+                    _ => false,
+                };
+                *trigger.lock().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(reload));
+                Ok(())
+            })?;
+        }
+        compiled.events.publish(compiled.status.clone());
         let compiled = Arc::new(Mutex::new(compiled));
         let name = self.name;
         if F::RUN_BEFORE_CONFIG && !B::STARTED {
@@ -635,8 +896,13 @@ where
             builder = builder.before_config(before_config)?;
         }
         let validator = move |_old: &_, cfg: &Arc<B::Config>, opts: &B::Opts| {
-            BoundedCompiledPipeline::run(&compiled, opts, cfg)
-                .map_err(|errs| MultiError::wrap(errs, name))
+            // Not unwrapping the single errors, so the validation report knows the pipeline
+            BoundedCompiledPipeline::run(&compiled, opts, cfg).map_err(|errors| {
+                AnyError::from(MultiError {
+                    errors,
+                    pipeline: name,
+                })
+            })
         };
         builder.config_validator(validator)
     }
@@ -0,0 +1,185 @@
+//! Retrying creation of resources in the background.
+//!
+//! By default, if a [`Pipeline`] fails to create its resources (the port is taken, the upstream
+//! server is down...), the whole new configuration is refused and nothing happens until the
+//! configuration is reloaded the next time. If this happens at startup, the application doesn't
+//! start at all.
+//!
+//! A pipeline can be configured to [`retry`][Pipeline::retry] instead. In such case the failure
+//! doesn't block the configuration. The old resources stay in place (or there are none at
+//! startup), the error is recorded in the [status][crate::status] of the pipeline (therefore
+//! the health checks see it) and the configuration is reloaded again after a [`Backoff`] delay.
+//! This repeats, with growing delays, until the resources are created and installed.
+//!
+//! Note that the retry reloads the whole configuration (as if `SIGHUP` was received), but most
+//! pipelines cache their resources, so only the failed ones are created again.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! use serde::Deserialize;
+//! use spirit::{AnyError, Empty, Pipeline, Spirit};
+//! use spirit::fragment::driver::CacheEq;
+//! use spirit::fragment::retry::Backoff;
+//! use spirit::prelude::*;
+//!
+//! #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+//! struct Upstream {
+//!     address: String,
+//! }
+//!
+//! spirit::simple_fragment! {
+//!     impl Fragment for Upstream {
+//!         type Driver = CacheEq<Upstream>;
+//!         type Resource = String;
+//!         type Installer = ();
+//!         fn create(&self, _: &'static str) -> Result<String, AnyError> {
+//!             // Connect somewhere, possibly failing
+//!             Ok(self.address.clone())
+//!         }
+//!     }
+//! }
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     upstream: Upstream,
+//! }
+//!
+//! Spirit::<Empty, Cfg>::new()
+//!     .with(
+//!         Pipeline::new("upstream")
+//!             .extract_cfg(|cfg: &Cfg| cfg.upstream.clone())
+//!             .retry(Backoff::new(Duration::from_millis(100), Duration::from_secs(30)))
+//!     )
+//!     .run(|_| Ok(()));
+//! ```
+//!
+//! [`Pipeline`]: crate::Pipeline
+//! [Pipeline::retry]: crate::Pipeline::retry
+
+use std::cmp;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use log::{debug, error, info};
+
+/// The delays between retries.
+///
+/// The first retry happens after the `initial` delay, then the delay doubles with each failed
+/// attempt, up to the `max`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    pub initial: Duration,
+    /// The longest delay between retries.
+    pub max: Duration,
+}
+
+impl Backoff {
+    /// Creates the backoff with given initial and maximal delay.
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max }
+    }
+
+    /// The delay before the given (0-based) retry attempt.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .checked_mul(1 << cmp::min(attempt, 31))
+            .map(|delay| cmp::min(delay, self.max))
+            .unwrap_or(self.max)
+    }
+}
+
+impl Default for Backoff {
+    /// Starts at 1 second, up to a minute.
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(60))
+    }
+}
+
+/// Something that triggers the retry, returning `false` if the application is gone.
+pub(crate) type Trigger = Arc<Mutex<Option<Box<dyn Fn() -> bool + Send>>>>;
+
+/// The retry state of a single pipeline.
+pub(crate) struct Retrier {
+    backoff: Backoff,
+    attempt: u32,
+    scheduled: Arc<AtomicBool>,
+    trigger: Trigger,
+}
+
+impl Retrier {
+    pub(crate) fn new(backoff: Backoff) -> Self {
+        Self {
+            backoff,
+            attempt: 0,
+            scheduled: Arc::new(AtomicBool::new(false)),
+            trigger: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// The slot to put the trigger into, once the application is running.
+    pub(crate) fn trigger(&self) -> Trigger {
+        Arc::clone(&self.trigger)
+    }
+
+    /// Is there a failure not yet fixed by a retry?
+    pub(crate) fn failing(&self) -> bool {
+        self.attempt > 0
+    }
+
+    pub(crate) fn succeeded(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Schedules another attempt, unless one is already scheduled.
+    pub(crate) fn schedule(&mut self, name: &'static str) {
+        if self.scheduled.swap(true, Ordering::Relaxed) {
+            debug!("Retry of {} is already scheduled", name);
+            return;
+        }
+        let delay = self.backoff.delay(self.attempt);
+        self.attempt = self.attempt.saturating_add(1);
+        info!("Retrying to create resources of {} in {:?}", name, delay);
+        let scheduled = Arc::clone(&self.scheduled);
+        let trigger = Arc::clone(&self.trigger);
+        let retry = move || loop {
+            thread::sleep(delay);
+            let trigger = trigger.lock().unwrap_or_else(PoisonError::into_inner);
+            // Not available until the application starts running, so wait some more
+            if let Some(trigger) = trigger.as_ref() {
+                scheduled.store(false, Ordering::Relaxed);
+                if !trigger() {
+                    debug!("Not retrying {}, the application is terminating", name);
+                }
+                break;
+            }
+        };
+        let spawned = thread::Builder::new()
+            .name(format!("retry-{}", name))
+            .spawn(retry);
+        if let Err(e) = spawned {
+            error!("Failed to schedule retry of {}: {}", name, e);
+            self.scheduled.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff() {
+        let backoff = Backoff::default();
+        assert_eq!(Duration::from_secs(1), backoff.delay(0));
+        assert_eq!(Duration::from_secs(8), backoff.delay(3));
+        assert_eq!(Duration::from_secs(60), backoff.delay(6));
+        assert_eq!(Duration::from_secs(60), backoff.delay(u32::max_value()));
+    }
+}
@@ -0,0 +1,253 @@
+//! Named scopes with their own set of resources.
+//!
+//! Multi-tenant applications often have a section of configuration per tenant (eg.
+//! `[tenants.acme]` and `[tenants.globex]`) and each tenant needs the same kinds of resources ‒ a
+//! database pool, a listening socket, an HTTP client... Tenants come and go with configuration
+//! reloads.
+//!
+//! [`Scopes`] describe where the scopes are in the configuration. Pipelines created by
+//! [`Scopes::pipeline`] then serve as templates ‒ they extract their fragment from each scope,
+//! create and install one instance of the resource for each of them and drop the instances of the
+//! scopes that disappeared. The fragments and transformations get names made of the pipeline name
+//! and the scope (eg. `db.acme`). Scopes that stayed the same keep their resources, even if other
+//! scopes change.
+//!
+//! If something more needs to happen when a scope appears or goes away, hooks can be registered
+//! through [`Scopes::lifecycle`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::collections::BTreeMap;
+//!
+//! use serde::Deserialize;
+//! use spirit::{AnyError, Empty, Spirit};
+//! use spirit::fragment::{Installer, Stackable};
+//! use spirit::fragment::driver::CacheEq;
+//! use spirit::fragment::scope::Scopes;
+//! use spirit::prelude::*;
+//!
+//! #[derive(Clone, Debug, Deserialize, PartialEq)]
+//! struct Greeting {
+//!     message: String,
+//! }
+//!
+//! impl Stackable for Greeting {}
+//!
+//! #[derive(Default)]
+//! struct Printer;
+//!
+//! struct Printed(String);
+//!
+//! impl Drop for Printed {
+//!     fn drop(&mut self) {
+//!         println!("Removed: {}", self.0);
+//!     }
+//! }
+//!
+//! impl<O, C> Installer<String, O, C> for Printer {
+//!     type UninstallHandle = Printed;
+//!     fn install(&mut self, message: String, _: &'static str) -> Printed {
+//!         println!("Installed: {}", message);
+//!         Printed(message)
+//!     }
+//! }
+//!
+//! spirit::simple_fragment! {
+//!     impl Fragment for Greeting {
+//!         type Driver = CacheEq<Greeting>;
+//!         type Resource = String;
+//!         type Installer = Printer;
+//!         fn create(&self, _: &'static str) -> Result<String, AnyError> {
+//!             Ok(self.message.clone())
+//!         }
+//!     }
+//! }
+//!
+//! #[derive(Clone, Debug, Deserialize)]
+//! struct Tenant {
+//!     greeting: Greeting,
+//! }
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     tenants: BTreeMap<String, Tenant>,
+//! }
+//!
+//! const CFG: &str = r#"
+//! [tenants.acme.greeting]
+//! message = "Hello from Acme"
+//!
+//! [tenants.globex.greeting]
+//! message = "Hello from Globex"
+//! "#;
+//!
+//! fn main() {
+//!     let tenants = Scopes::new("tenants", |cfg: &Cfg| cfg.tenants.clone());
+//!     Spirit::<Empty, Cfg>::new()
+//!         .config_defaults(CFG)
+//!         .with(tenants.pipeline("greeting", |tenant: &Tenant| tenant.greeting.clone()))
+//!         .with(tenants.lifecycle(
+//!             |name: &str, _: &Tenant| println!("Tenant {} added", name),
+//!             |name: &str| println!("Tenant {} removed", name),
+//!         ))
+//!         .run(|_| Ok(()));
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+use log::debug;
+
+use super::driver::MapDriver;
+use super::pipeline::{CfgExtractor, NopTransformation, Pipeline};
+use super::{Fragment, Stackable};
+use crate::extension::{Extensible, Extension};
+
+type Extractor<C, T> = dyn Fn(&C) -> BTreeMap<String, T> + Send + Sync;
+
+/// Description of the scopes in the configuration.
+///
+/// See the [module documentation][crate::fragment::scope].
+pub struct Scopes<C, T> {
+    name: &'static str,
+    extractor: Arc<Extractor<C, T>>,
+}
+
+impl<C, T> Clone for Scopes<C, T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            extractor: Arc::clone(&self.extractor),
+        }
+    }
+}
+
+impl<C, T> Debug for Scopes<C, T> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("Scopes")
+            .field("name", &self.name)
+            .finish()
+    }
+}
+
+impl<C: 'static, T: 'static> Scopes<C, T> {
+    /// Creates the scopes.
+    ///
+    /// The `name` is used in logging. The `extractor` returns the configuration of each scope,
+    /// under its name.
+    pub fn new<E>(name: &'static str, extractor: E) -> Self
+    where
+        E: Fn(&C) -> BTreeMap<String, T> + Send + Sync + 'static,
+    {
+        Self {
+            name,
+            extractor: Arc::new(extractor),
+        }
+    }
+
+    /// Lists the names of the scopes in the configuration.
+    pub fn names(&self, cfg: &C) -> Vec<String> {
+        (self.extractor)(cfg).into_iter().map(|(k, _)| k).collect()
+    }
+
+    /// Creates a pipeline with an instance of the fragment for each scope.
+    ///
+    /// The `extractor` takes the configuration of a single scope. The pipeline can be further
+    /// configured (transformations, installer) as usual. The fragment of each scope is named by
+    /// the pipeline name and the name of the scope (eg. `db.acme`).
+    #[allow(clippy::type_complexity)]
+    pub fn pipeline<O, R, E>(
+        &self,
+        name: &'static str,
+        mut extractor: E,
+    ) -> Pipeline<
+        BTreeMap<String, R>,
+        CfgExtractor<impl FnMut(&C) -> BTreeMap<String, R>>,
+        MapDriver<R, R::Driver>,
+        NopTransformation,
+        (O, C),
+    >
+    where
+        E: FnMut(&T) -> R + Send + 'static,
+        R: Fragment + Stackable + 'static,
+    {
+        let scopes = Arc::clone(&self.extractor);
+        Pipeline::new(name).extract_cfg(move |cfg: &C| {
+            scopes(cfg)
+                .iter()
+                .map(|(scope, sub)| (scope.clone(), extractor(sub)))
+                .collect::<BTreeMap<_, _>>()
+        })
+    }
+
+    /// An extension calling hooks when scopes appear in or disappear from the configuration.
+    ///
+    /// The `added` hook is called with the name and configuration of each new scope, `removed`
+    /// with the name of each scope that went away. They are called after a new configuration is
+    /// accepted (the first configuration counts as adding all its scopes).
+    pub fn lifecycle<E, A, D>(&self, mut added: A, mut removed: D) -> impl Extension<E>
+    where
+        E: Extensible<Config = C, Ok = E>,
+        A: FnMut(&str, &T) + Send + 'static,
+        D: FnMut(&str) + Send + 'static,
+    {
+        let extractor = Arc::clone(&self.extractor);
+        let name = self.name;
+        let mut known = BTreeSet::new();
+        move |e: E| {
+            e.on_config(move |_, cfg: &Arc<C>| {
+                let scopes = extractor(cfg);
+                for gone in known.iter().filter(|k| !scopes.contains_key(*k)) {
+                    debug!("Scope {} removed from {}", gone, name);
+                    removed(gone);
+                }
+                for (scope, sub) in scopes.iter().filter(|(k, _)| !known.contains(*k)) {
+                    debug!("Scope {} added to {}", scope, name);
+                    added(scope, sub);
+                }
+                known = scopes.into_iter().map(|(k, _)| k).collect();
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::testing::TestSpirit;
+    use crate::{Empty, Spirit};
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct Cfg {
+        tenants: BTreeMap<String, u32>,
+    }
+
+    #[test]
+    fn lifecycle() {
+        let scopes = Scopes::new("tenants", |cfg: &Cfg| cfg.tenants.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let added = Arc::clone(&events);
+        let removed = Arc::clone(&events);
+        let builder = Spirit::<Empty, Cfg>::new().with(scopes.lifecycle(
+            move |name: &str, value: &u32| {
+                added.lock().unwrap().push(format!("+{}={}", name, value))
+            },
+            move |name: &str| removed.lock().unwrap().push(format!("-{}", name)),
+        ));
+        let app = TestSpirit::new(builder, "[tenants]\nacme = 1\nglobex = 2").unwrap();
+        assert_eq!(vec!["acme", "globex"], scopes.names(&app.spirit().config()));
+        app.reload("[tenants]\nacme = 3\ninitech = 4").unwrap();
+        assert_eq!(
+            vec!["+acme=1", "+globex=2", "-globex", "+initech=4"],
+            *events.lock().unwrap()
+        );
+    }
+}
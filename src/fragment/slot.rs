@@ -0,0 +1,211 @@
+//! A storage for a single resource that can come and go.
+//!
+//! Many resources are singletons ‒ there's one database pool or one HTTP client in the
+//! application and a new configuration simply replaces it. Their installers usually don't return
+//! any meaningful [`UninstallHandle`], because the new resource overwrites the old one anyway.
+//!
+//! That, however, doesn't work with optional configuration (`Option<F>` fragments). When the
+//! section disappears from the configuration, the pipeline drops the uninstall handle of the
+//! resource and nothing happens ‒ the old resource stays active.
+//!
+//! The [`Slot`] is an [`Installer`] that handles this. It holds the current resource and the
+//! [`SlotGuard`] it returns as the uninstall handle empties the slot again, unless the resource was
+//! already replaced by a newer one.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{AnyError, Empty, Pipeline, Spirit};
+//! use spirit::fragment::Stackable;
+//! use spirit::fragment::driver::CacheEq;
+//! use spirit::fragment::slot::Slot;
+//! use spirit::prelude::*;
+//!
+//! #[derive(Clone, Debug, Deserialize, PartialEq)]
+//! struct Greeting {
+//!     message: String,
+//! }
+//!
+//! impl Stackable for Greeting {}
+//!
+//! spirit::simple_fragment! {
+//!     impl Fragment for Greeting {
+//!         type Driver = CacheEq<Greeting>;
+//!         type Resource = String;
+//!         type Installer = ();
+//!         fn create(&self, _: &'static str) -> Result<String, AnyError> {
+//!             Ok(self.message.clone())
+//!         }
+//!     }
+//! }
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     // The greeting is active only if the section is present in the config
+//!     greeting: Option<Greeting>,
+//! }
+//!
+//! fn main() {
+//!     let greeting = Slot::<String>::new();
+//!     Spirit::<Empty, Cfg>::new()
+//!         .with(
+//!             Pipeline::new("greeting")
+//!                 .extract_cfg(|cfg: &Cfg| cfg.greeting.clone())
+//!                 .install(greeting.clone())
+//!         )
+//!         .run(move |_| {
+//!             if let Some(greeting) = greeting.load() {
+//!                 println!("{}", greeting);
+//!             }
+//!             Ok(())
+//!         });
+//! }
+//! ```
+//!
+//! [`UninstallHandle`]: crate::fragment::Installer::UninstallHandle
+
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use arc_swap::ArcSwapOption;
+use log::debug;
+
+use super::Installer;
+
+struct Inner<R> {
+    value: ArcSwapOption<R>,
+    // Generation of the value, so an old guard doesn't remove a newer value.
+    generation: Mutex<usize>,
+}
+
+/// A storage of a single optional resource.
+///
+/// Cheap to clone, all the clones share the same storage. It can be used as an [`Installer`] in a
+/// [`Pipeline`][crate::Pipeline]. See the [module documentation][crate::fragment::slot].
+pub struct Slot<R>(Arc<Inner<R>>);
+
+impl<R> Slot<R> {
+    /// Creates an empty slot.
+    pub fn new() -> Self {
+        Slot(Arc::new(Inner {
+            value: ArcSwapOption::empty(),
+            generation: Mutex::new(0),
+        }))
+    }
+
+    /// Returns the current resource, if any.
+    pub fn load(&self) -> Option<Arc<R>> {
+        self.0.value.load_full()
+    }
+
+    /// Returns the current resource.
+    ///
+    /// # Panics
+    ///
+    /// If the slot is empty.
+    pub fn get(&self) -> Arc<R> {
+        self.load().expect("Accessing an empty slot")
+    }
+
+    /// Is there a resource?
+    pub fn is_set(&self) -> bool {
+        self.0.value.load().is_some()
+    }
+
+    /// Stores a new resource, replacing the current one.
+    ///
+    /// The resource stays there until the returned guard is dropped or until it is replaced by
+    /// another one.
+    pub fn store(&self, resource: R) -> SlotGuard<R> {
+        let mut generation = self
+            .0
+            .generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        *generation = generation.wrapping_add(1);
+        self.0.value.store(Some(Arc::new(resource)));
+        SlotGuard {
+            slot: Arc::clone(&self.0),
+            generation: *generation,
+        }
+    }
+}
+
+impl<R> Clone for Slot<R> {
+    fn clone(&self) -> Self {
+        Slot(Arc::clone(&self.0))
+    }
+}
+
+impl<R> Default for Slot<R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Debug> Debug for Slot<R> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_tuple("Slot").field(&self.load()).finish()
+    }
+}
+
+/// The uninstall handle of a [`Slot`].
+///
+/// Empties the slot when dropped, unless the resource was replaced in the meantime. The user
+/// should not need to interact with this directly.
+pub struct SlotGuard<R> {
+    slot: Arc<Inner<R>>,
+    generation: usize,
+}
+
+impl<R> Debug for SlotGuard<R> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("SlotGuard")
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+
+impl<R> Drop for SlotGuard<R> {
+    fn drop(&mut self) {
+        let generation = self
+            .slot
+            .generation
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if *generation == self.generation {
+            self.slot.value.store(None);
+        }
+    }
+}
+
+impl<R, O, C> Installer<R, O, C> for Slot<R>
+where
+    R: Send + Sync + 'static,
+{
+    type UninstallHandle = SlotGuard<R>;
+    fn install(&mut self, resource: R, name: &'static str) -> SlotGuard<R> {
+        debug!("Installing resource of {} into a slot", name);
+        self.store(resource)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replace_and_uninstall() {
+        let slot = Slot::new();
+        assert!(!slot.is_set());
+        let first = slot.store(1);
+        assert_eq!(1, *slot.get());
+        let second = slot.store(2);
+        // Replaced already, so the old guard doesn't remove the new value
+        drop(first);
+        assert_eq!(Some(2), slot.load().map(|v| *v));
+        drop(second);
+        assert!(slot.load().is_none());
+    }
+}
@@ -0,0 +1,96 @@
+//! Health probes of the application.
+//!
+//! The failed [resources][crate::status] say something about the health of the application, but
+//! not everything ‒ a client may be cut off from its server by an open circuit breaker, a
+//! certificate may be about to expire. Parts of the application (and the spirit crates) can
+//! register a named probe here to report such problems. The probes are asked whenever someone
+//! checks the health, for example through the `health` command of the `spirit-admin` control
+//! socket.
+//!
+//! A probe returns `None` if everything is fine, or a description of the problem.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::sync::atomic::{AtomicBool, Ordering};
+//! use std::sync::Arc;
+//!
+//! use spirit::health;
+//!
+//! let connected = Arc::new(AtomicBool::new(true));
+//! let probe = Arc::clone(&connected);
+//! health::register("upstream", move || {
+//!     if probe.load(Ordering::Relaxed) {
+//!         None
+//!     } else {
+//!         Some("Lost connection to upstream".to_owned())
+//!     }
+//! });
+//! assert!(health::check().is_empty());
+//!
+//! connected.store(false, Ordering::Relaxed);
+//! let problems = health::check();
+//! assert_eq!("upstream", problems[0].probe);
+//!
+//! health::unregister("upstream");
+//! assert!(health::check().is_empty());
+//! ```
+
+use std::sync::{Arc, Mutex, PoisonError};
+
+use once_cell::sync::Lazy;
+
+type Probe = Arc<dyn Fn() -> Option<String> + Send + Sync>;
+
+static PROBES: Lazy<Mutex<Vec<(String, Probe)>>> = Lazy::new(Default::default);
+
+/// A problem reported by one of the probes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Problem {
+    /// The name the probe was registered under.
+    pub probe: String,
+
+    /// The description of the problem.
+    pub reason: String,
+}
+
+/// Registers a probe under the given name.
+///
+/// A probe already registered under the same name is replaced, so it is fine to register again on
+/// each configuration reload.
+pub fn register<N, F>(name: N, probe: F)
+where
+    N: Into<String>,
+    F: Fn() -> Option<String> + Send + Sync + 'static,
+{
+    let name = name.into();
+    let mut probes = PROBES.lock().unwrap_or_else(PoisonError::into_inner);
+    let probe = Arc::new(probe) as Probe;
+    match probes.iter_mut().find(|(n, _)| *n == name) {
+        Some(existing) => existing.1 = probe,
+        None => probes.push((name, probe)),
+    }
+}
+
+/// Removes the probe with the given name, if there's one.
+pub fn unregister(name: &str) {
+    PROBES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|(n, _)| n != name);
+}
+
+/// Asks all the probes, returning the problems they report.
+///
+/// Empty if all is fine.
+pub fn check() -> Vec<Problem> {
+    // Not holding the lock while running the probes, they may want to register something
+    let probes = PROBES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .clone();
+    probes
+        .into_iter()
+        .filter_map(|(probe, check)| check().map(|reason| Problem { probe, reason }))
+        .collect()
+}
@@ -419,6 +419,11 @@
 //! }
 //! ```
 //!
+//! Extensions that need to know about each other (or about the lifecycle of the application) don't
+//! have to be wired together manually. They can observe the [event bus][crate::events] owned by
+//! the [`Spirit`], which carries events like configuration reloads or pipelines installing new
+//! resources.
+//!
 //! # Features
 //!
 //! There are several features that can tweak functionality. Currently, the `json`, `yaml` and
@@ -432,6 +437,7 @@
 //!   actually available in all the other sub-crates too.
 //! * `color`: support for colored command line help (on by default).
 //! * `suggestions`: support for command line suggestions on errors (on by default).
+//! * `backtrace`: backtraces in [`SerializableError`][crate::error::SerializableError].
 //!
 //! # Other documentation
 //!
@@ -471,15 +477,27 @@
 //! [`err-derive`]: https://crates.io/crates/err-derive
 
 pub mod app;
+pub mod banner;
 mod bodies;
+pub mod cfg;
 pub mod cfg_loader;
+pub mod cgroup;
 mod empty;
 pub mod error;
+pub mod events;
 pub mod extension;
+pub mod flags;
 pub mod fragment;
+pub mod health;
 #[doc(hidden)]
 pub mod macro_support;
+pub mod maintenance;
+pub mod panics;
+pub mod sched;
+pub mod schema;
 mod spirit;
+pub mod status;
+pub mod testing;
 pub mod utils;
 pub mod validation;
 
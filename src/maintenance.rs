@@ -0,0 +1,46 @@
+//! The maintenance mode of the application.
+//!
+//! Before a maintenance (or before taking an instance out of a load balancer), it is useful to
+//! stop taking new work without shutting the application down ‒ the listening sockets stay bound,
+//! but the servers answer that they are unavailable and the clients should come back later (for
+//! HTTP, `503 Service Unavailable` with `Retry-After`). This way the traffic drains away and
+//! nothing gets a connection refused.
+//!
+//! This module holds the process-wide switch. It does nothing on its own, the servers that
+//! support it (eg. the ones of `spirit-hyper`) check it on every request. It can be flipped from
+//! the application or at runtime, for example through the `maintenance` command of the
+//! `spirit-admin` control socket.
+//!
+//! The mode is off when the application starts and configuration reloads don't change it.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use spirit::maintenance;
+//!
+//! maintenance::set(true);
+//! assert!(maintenance::is_active());
+//! maintenance::set(false);
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::info;
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Turns the maintenance mode on or off.
+pub fn set(active: bool) {
+    if ACTIVE.swap(active, Ordering::Relaxed) != active {
+        if active {
+            info!("Entering maintenance mode");
+        } else {
+            info!("Leaving maintenance mode");
+        }
+    }
+}
+
+/// Is the maintenance mode on?
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
@@ -0,0 +1,108 @@
+//! What happens when something panics.
+//!
+//! By default, spirit doesn't touch the panic handling at all ‒ a panic is reported by whatever
+//! panic hook is installed (the one of the standard library printing to stderr, or the logging one
+//! installed by [`spirit-log`](https://docs.rs/spirit-log)) and only the panicking thread dies.
+//!
+//! That is not always the right thing for a daemon. A worker thread silently missing may leave
+//! the application in a half-working state, so it may be better to shut down (and let the service
+//! manager restart it). The [`Builder::panic_policy`][crate::Builder::panic_policy] chooses what
+//! happens and the [`Builder::on_panic`][crate::Builder::on_panic] hooks can report the panic
+//! somewhere (metrics, an error tracking service) first.
+//!
+//! The panic is still reported by the previously installed panic hook, after the
+//! [`on_panic`][crate::Builder::on_panic] hooks and before acting according to the policy.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use spirit::{Empty, Spirit};
+//! use spirit::panics::PanicPolicy;
+//! use spirit::prelude::*;
+//!
+//! Spirit::<Empty, Empty>::new()
+//!     .panic_policy(PanicPolicy::Terminate)
+//!     .on_panic(|info| eprintln!("Reporting panic {} to the mothership", info))
+//!     .run(|_| Ok(()));
+//! ```
+
+use std::panic::{self, PanicInfo};
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+use log::{error, warn};
+
+/// A hook called on panic.
+pub(crate) type PanicHook = Arc<dyn Fn(&PanicInfo) + Send + Sync>;
+
+/// What to do when something panics.
+///
+/// The enum is non-exhaustive ‒ more variants may be added in the future and it won't be
+/// considered an API breaking change.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum PanicPolicy {
+    /// Report the panic and let the rest of the application continue.
+    ///
+    /// Only the panicking thread unwinds. This is the same as the default behaviour, but allows
+    /// using the [`on_panic`][crate::Builder::on_panic] hooks.
+    Continue,
+
+    /// Report the panic and terminate the application gracefully.
+    ///
+    /// This is the same as receiving `SIGTERM` ‒ the termination hooks are run and the
+    /// application shuts down.
+    Terminate,
+
+    /// Report the panic and abort the process immediately.
+    Abort,
+
+    // Prevent users from accidentally matching against this enum without a catch-all branch.
+    #[doc(hidden)]
+    #[allow(non_camel_case_types)]
+    _NON_EXHAUSTIVE,
+}
+
+impl Default for PanicPolicy {
+    fn default() -> Self {
+        PanicPolicy::Continue
+    }
+}
+
+/// Installs the panic hook.
+///
+/// The `terminate` is called (in a separate thread) with the [`PanicPolicy::Terminate`].
+pub(crate) fn install<T>(policy: PanicPolicy, hooks: Vec<PanicHook>, terminate: T)
+where
+    T: Fn() + Send + Sync + 'static,
+{
+    let terminate = Arc::new(terminate);
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        for hook in &hooks {
+            hook(info);
+        }
+        previous(info);
+        match policy {
+            PanicPolicy::Continue => (),
+            PanicPolicy::Terminate => {
+                warn!("Terminating the application because of a panic");
+                let terminate = Arc::clone(&terminate);
+                // Terminating runs the termination hooks. Don't do that inside the panicking
+                // thread, it may be holding some locks.
+                let spawned = thread::Builder::new()
+                    .name("spirit-panic".to_owned())
+                    .spawn(move || terminate());
+                if let Err(e) = spawned {
+                    error!("Failed to start terminating after a panic, aborting: {}", e);
+                    process::abort();
+                }
+            }
+            PanicPolicy::Abort => {
+                error!("Aborting because of a panic");
+                process::abort();
+            }
+            _ => unreachable!("Non-exhaustive sentinel should not be used"),
+        }
+    }));
+}
@@ -0,0 +1,363 @@
+//! CPU affinity and scheduling of threads.
+//!
+//! Latency-sensitive deployments often pin the application to some CPUs and give it a different
+//! scheduling policy or priority. Instead of wrapping the binary in `taskset`, `nice` and `chrt`,
+//! the [`Sched`] configuration fragment allows setting these from the configuration, per group of
+//! threads:
+//!
+//! * The spirit background thread (handling signals and configuration reloading) by the
+//!   [`Sched::background_extension`].
+//! * The worker threads of the tokio runtime, by the configuration of the threadpool in
+//!   [`spirit-tokio`](https://docs.rs/spirit-tokio).
+//! * Any other thread by calling [`Sched::apply`] from inside of it.
+//!
+//! This is currently supported only on Linux. On other systems, applying non-empty settings
+//! results in an error.
+//!
+//! # Fields
+//!
+//! * `cpus`: The CPUs the threads may run on. Either a list of numbers or a string in the format
+//!   accepted by `taskset -c` (eg. `"0-3,8"`).
+//! * `nice`: The nice value (`-20` to `19`, lower is more favourable).
+//! * `policy`: The scheduling policy, one of `other`, `batch`, `idle`, `fifo` and `round-robin`.
+//! * `priority`: The static priority for the `fifo` and `round-robin` policies (`1` to `99`,
+//!   defaults to `1`).
+//!
+//! Raising the priority or using the realtime policies usually needs privileges.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit::sched::Sched;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     #[serde(default)]
+//!     background: Sched,
+//! }
+//!
+//! impl Cfg {
+//!     fn background(&self) -> Sched {
+//!         self.background.clone()
+//!     }
+//! }
+//!
+//! Spirit::<Empty, Cfg>::new()
+//!     .with(Sched::background_extension(Cfg::background))
+//!     .run(|_| Ok(()));
+//! ```
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::str::FromStr;
+use std::sync::{Mutex, PoisonError};
+
+use err_context::prelude::*;
+use log::debug;
+use once_cell::sync::Lazy;
+use serde::de::{Deserializer, Error as DeError};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+
+use crate::error;
+use crate::extension::{Extensible, Extension};
+use crate::schema::{Schema, Schematic, Type};
+use crate::AnyError;
+
+/// Settings waiting to be applied to the background thread.
+static BACKGROUND: Lazy<Mutex<Option<Sched>>> = Lazy::new(|| Mutex::new(None));
+
+/// A set of CPUs.
+///
+/// Deserialized either from a list of CPU numbers or from a string like `"0-3,8"`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct CpuList(pub Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = AnyError;
+    fn from_str(s: &str) -> Result<Self, AnyError> {
+        let mut cpus = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let mut bounds = part.splitn(2, '-');
+            let start: usize = bounds.next().unwrap().trim().parse()?;
+            let end: usize = match bounds.next() {
+                Some(end) => end.trim().parse()?,
+                None => start,
+            };
+            if end < start {
+                return Err(format!("Invalid CPU range {}", part).into());
+            }
+            cpus.extend(start..=end);
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuList(cpus))
+    }
+}
+
+impl Display for CpuList {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let mut first = true;
+        let mut iter = self.0.iter().copied().peekable();
+        while let Some(start) = iter.next() {
+            let mut end = start;
+            while iter.peek() == Some(&(end + 1)) {
+                end = iter.next().unwrap();
+            }
+            if !first {
+                write!(fmt, ",")?;
+            }
+            first = false;
+            if start == end {
+                write!(fmt, "{}", start)?;
+            } else {
+                write!(fmt, "{}-{}", start, end)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for CpuList {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            List(Vec<usize>),
+            Str(String),
+        }
+
+        match Raw::deserialize(d)? {
+            Raw::List(mut cpus) => {
+                cpus.sort_unstable();
+                cpus.dedup();
+                Ok(CpuList(cpus))
+            }
+            Raw::Str(s) => s.parse().map_err(D::Error::custom),
+        }
+    }
+}
+
+impl Serialize for CpuList {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(feature = "cfg-help")]
+impl structdoc::StructDoc for CpuList {
+    fn document() -> structdoc::Documentation {
+        structdoc::Documentation::leaf("list of CPUs (eg. \"0-3,8\")")
+    }
+}
+
+impl Schematic for CpuList {
+    fn schema() -> Schema {
+        Schema::any_of(vec![Vec::<usize>::schema(), Schema::string()])
+            .description("list of CPUs (eg. \"0-3,8\")")
+    }
+}
+
+impl Schematic for Policy {
+    fn schema() -> Schema {
+        Schema::variants(vec!["other", "batch", "idle", "fifo", "round-robin"])
+    }
+}
+
+/// A scheduling policy.
+#[derive(Copy, Clone, Debug, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// The default time-sharing policy.
+    Other,
+    /// For CPU-intensive, non-interactive threads.
+    Batch,
+    /// For very low priority background jobs.
+    Idle,
+    /// Realtime first-in first-out.
+    Fifo,
+    /// Realtime round-robin.
+    RoundRobin,
+}
+
+/// Scheduling settings of a group of threads.
+///
+/// See the [module documentation](index.html).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize)]
+#[cfg_attr(feature = "cfg-help", derive(structdoc::StructDoc))]
+#[serde(rename_all = "kebab-case")]
+pub struct Sched {
+    /// The CPUs the threads may run on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpus: Option<CpuList>,
+
+    /// The nice value (-20 to 19).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nice: Option<i32>,
+
+    /// The scheduling policy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Policy>,
+
+    /// The static priority for the realtime policies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub priority: Option<i32>,
+}
+
+impl Sched {
+    /// Is there anything to set?
+    pub fn is_empty(&self) -> bool {
+        self == &Sched::default()
+    }
+
+    /// Applies the settings to the current thread.
+    pub fn apply(&self) -> Result<(), AnyError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        debug!("Applying scheduling settings {:?}", self);
+        self.apply_inner()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn apply_inner(&self) -> Result<(), AnyError> {
+        use log::warn;
+        use nix::sched::{self, CpuSet};
+        use nix::unistd::Pid;
+
+        if let Some(cpus) = &self.cpus {
+            let mut set = CpuSet::new();
+            for cpu in &cpus.0 {
+                set.set(*cpu)
+                    .with_context(|_| format!("Invalid CPU {}", cpu))?;
+            }
+            // Pid 0 is the current thread
+            sched::sched_setaffinity(Pid::from_raw(0), &set)
+                .with_context(|_| format!("Failed to set CPU affinity to {}", cpus))?;
+        }
+        if let Some(nice) = self.nice {
+            scheduler::set_self_priority(scheduler::Which::Process, nice)
+                .map_err(|()| format!("Failed to set nice value {}", nice))?;
+        }
+        if let Some(policy) = self.policy {
+            let (raw_policy, default_priority) = match policy {
+                Policy::Other => (scheduler::Policy::Other, 0),
+                Policy::Batch => (scheduler::Policy::Batch, 0),
+                Policy::Idle => (scheduler::Policy::Idle, 0),
+                Policy::Fifo => (scheduler::Policy::Fifo, 1),
+                Policy::RoundRobin => (scheduler::Policy::RoundRobin, 1),
+            };
+            let priority = self.priority.unwrap_or(default_priority);
+            scheduler::set_self_policy(raw_policy, priority).map_err(|()| {
+                format!(
+                    "Failed to set scheduling policy {:?} with priority {}",
+                    policy, priority
+                )
+            })?;
+        } else if self.priority.is_some() {
+            warn!("Scheduling priority set without a policy, ignoring");
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_inner(&self) -> Result<(), AnyError> {
+        Err("Scheduling settings are supported only on Linux".into())
+    }
+
+    /// An extension applying the settings to the spirit background thread.
+    ///
+    /// The settings are applied when the background thread starts and after configuration
+    /// reloads. Errors are only logged, as the configuration is already in place by then.
+    pub fn background_extension<E, F>(extractor: F) -> impl Extension<E>
+    where
+        E: Extensible<Ok = E>,
+        F: Fn(&E::Config) -> Self + Send + 'static,
+    {
+        let mut previous = None;
+        move |e: E| {
+            e.on_config(move |_, cfg| {
+                let sched = extractor(cfg);
+                if previous.as_ref() != Some(&sched) {
+                    previous = Some(sched.clone());
+                    *BACKGROUND.lock().unwrap_or_else(PoisonError::into_inner) = Some(sched);
+                }
+            })
+        }
+    }
+}
+
+impl Schematic for Sched {
+    fn schema() -> Schema {
+        Schema::object()
+            .field::<Option<CpuList>>("cpus", "The CPUs the threads may run on.")
+            .field_schema(
+                "nice",
+                Schema::new(Type::Integer)
+                    .range(Some(-20.0), Some(19.0))
+                    .description("The nice value (-20 to 19).")
+                    .optional(),
+            )
+            .field::<Option<Policy>>("policy", "The scheduling policy.")
+            .field_schema(
+                "priority",
+                Schema::new(Type::Integer)
+                    .range(Some(1.0), Some(99.0))
+                    .description("The static priority for the realtime policies.")
+                    .optional(),
+            )
+    }
+}
+
+/// Applies pending settings to the background thread, if there are any.
+///
+/// Called from the background thread.
+pub(crate) fn apply_background() {
+    let pending = BACKGROUND
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take();
+    if let Some(sched) = pending {
+        let _ = error::log_errors(module_path!(), || -> Result<(), AnyError> {
+            sched
+                .apply()
+                .context("Failed to set scheduling of the spirit thread")?;
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_list() {
+        let list: CpuList = "0-3, 8,10-11,2".parse().unwrap();
+        assert_eq!(vec![0, 1, 2, 3, 8, 10, 11], list.0);
+        assert_eq!("0-3,8,10-11", list.to_string());
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("x".parse::<CpuList>().is_err());
+    }
+
+    #[test]
+    fn parse() {
+        let sched: Sched = toml::from_str(
+            r#"
+            cpus = [3, 1, 2]
+            nice = -5
+            policy = "round-robin"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(Some(CpuList(vec![1, 2, 3])), sched.cpus);
+        assert_eq!(Some(-5), sched.nice);
+        assert_eq!(Some(Policy::RoundRobin), sched.policy);
+        assert!(!sched.is_empty());
+        assert!(Sched::default().is_empty());
+        assert!(Sched::default().apply().is_ok());
+    }
+}
@@ -0,0 +1,401 @@
+//! Description of the configuration structure as a JSON Schema.
+//!
+//! Editors can offer autocompletion and validation of configuration files if they know their
+//! schema and external tools (eg. a CI job checking a repository of deployment configurations) can
+//! validate them without running the application. This module allows describing the structure of
+//! the configuration through the [`Schematic`] trait and exporting it as a
+//! [JSON Schema](https://json-schema.org) document with [`to_json_schema`].
+//!
+//! The trait is implemented for the common types (numbers, strings, collections) and the
+//! configuration fragments of spirit itself. The application then implements it for its own
+//! configuration structures, usually with the help of the [`Schema`] builder methods.
+//!
+//! The [`spirit-cfg-helpers`](https://docs.rs/spirit-cfg-helpers) crate provides the
+//! `--config-schema` command line option printing the schema.
+//!
+//! Note that JSON Schema describes the TOML configuration files equally well, as their data model
+//! is the same.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::cfg::ListenAddr;
+//! use spirit::schema::{Schema, Schematic};
+//!
+//! #[derive(Deserialize)]
+//! struct Server {
+//!     listen: ListenAddr,
+//!     #[serde(default)]
+//!     workers: Option<usize>,
+//! }
+//!
+//! impl Schematic for Server {
+//!     fn schema() -> Schema {
+//!         Schema::object()
+//!             .field::<ListenAddr>("listen", "Where to listen for connections")
+//!             .field::<Option<usize>>("workers", "Number of worker threads")
+//!     }
+//! }
+//!
+//! #[derive(Deserialize)]
+//! struct Cfg {
+//!     server: Server,
+//! }
+//!
+//! impl Schematic for Cfg {
+//!     fn schema() -> Schema {
+//!         Schema::object().field::<Server>("server", "The HTTP server")
+//!     }
+//! }
+//!
+//! # #[cfg(feature = "json")]
+//! println!("{:#}", spirit::schema::to_json_schema::<Cfg>());
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+/// The JSON Schema draft the exported documents conform to.
+pub const DRAFT: &str = "http://json-schema.org/draft-07/schema#";
+
+/// The basic type of a value.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Type {
+    /// `true` or `false`.
+    Boolean,
+    /// A whole number.
+    Integer,
+    /// Any number.
+    Number,
+    /// A string.
+    String,
+    /// A list of values.
+    Array,
+    /// A table.
+    Object,
+}
+
+/// What fields of a table are allowed besides the described ones.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+enum Additional {
+    Allowed(bool),
+    Schema(Box<Schema>),
+}
+
+/// A description of a configuration value.
+///
+/// This is a subset of JSON Schema sufficient for describing configuration. It is constructed by
+/// the builder methods, starting either with one of the constructors or with
+/// [`Schematic::schema`] of another type.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Schema {
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    ty: Option<Type>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    properties: BTreeMap<String, Schema>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    required: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    additional_properties: Option<Additional>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items: Option<Box<Schema>>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    unique_items: bool,
+    #[serde(rename = "enum", skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    any_of: Vec<Schema>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    minimum: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    maximum: Option<f64>,
+    #[serde(skip)]
+    optional: bool,
+}
+
+impl Schema {
+    /// A value of the given type.
+    pub fn new(ty: Type) -> Self {
+        Schema {
+            ty: Some(ty),
+            ..Schema::default()
+        }
+    }
+
+    /// A value of any type.
+    pub fn any() -> Self {
+        Schema::default()
+    }
+
+    /// A string.
+    pub fn string() -> Self {
+        Schema::new(Type::String)
+    }
+
+    /// A table, with its fields added by [`field`][Schema::field].
+    ///
+    /// Fields not described are not allowed (which is what an editor usually wants to hear about,
+    /// even if the application would just warn about them).
+    pub fn object() -> Self {
+        Schema {
+            additional_properties: Some(Additional::Allowed(false)),
+            ..Schema::new(Type::Object)
+        }
+    }
+
+    /// A table with arbitrary keys and values of the given kind.
+    pub fn map(values: Schema) -> Self {
+        Schema::new(Type::Object).additional(values)
+    }
+
+    /// A list of values of the given kind.
+    pub fn array(items: Schema) -> Self {
+        Schema {
+            items: Some(Box::new(items)),
+            ..Schema::new(Type::Array)
+        }
+    }
+
+    /// A string with one of the given values.
+    pub fn variants<I, S>(variants: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Schema {
+            variants: variants.into_iter().map(Into::into).collect(),
+            ..Schema::string()
+        }
+    }
+
+    /// A value matching any of the alternatives.
+    pub fn any_of<I: IntoIterator<Item = Schema>>(alternatives: I) -> Self {
+        Schema {
+            any_of: alternatives.into_iter().collect(),
+            ..Schema::default()
+        }
+    }
+
+    /// Sets the human readable description.
+    pub fn description<D: Into<String>>(self, description: D) -> Self {
+        Schema {
+            description: Some(description.into()),
+            ..self
+        }
+    }
+
+    /// Adds a field of a table.
+    ///
+    /// The field is required unless its schema is [`optional`][Schema::optional], which is the
+    /// case of [`Option`]. Fields with defaults can be added through
+    /// [`field_schema`][Schema::field_schema].
+    pub fn field<T: Schematic>(self, name: &str, description: &str) -> Self {
+        self.field_schema(name, T::schema().description(description))
+    }
+
+    /// Adds a field of a table with an explicit schema.
+    pub fn field_schema(mut self, name: &str, schema: Schema) -> Self {
+        if !schema.optional {
+            self.required.push(name.to_owned());
+        }
+        self.properties.insert(name.to_owned(), schema);
+        self
+    }
+
+    /// Marks the value as optional when used as a field.
+    pub fn optional(self) -> Self {
+        Schema {
+            optional: true,
+            ..self
+        }
+    }
+
+    /// Merges all fields of another table into this one.
+    ///
+    /// This is the counterpart of `#[serde(flatten)]`.
+    pub fn flatten(mut self, other: Schema) -> Self {
+        self.required.extend(other.required);
+        self.properties.extend(other.properties);
+        self
+    }
+
+    /// Sets the schema of the fields of a table that are not described.
+    pub fn additional(self, schema: Schema) -> Self {
+        Schema {
+            additional_properties: Some(Additional::Schema(Box::new(schema))),
+            ..self
+        }
+    }
+
+    /// Sets the range of a number.
+    pub fn range(self, minimum: Option<f64>, maximum: Option<f64>) -> Self {
+        Schema {
+            minimum,
+            maximum,
+            ..self
+        }
+    }
+
+    fn unique(self) -> Self {
+        Schema {
+            unique_items: true,
+            ..self
+        }
+    }
+}
+
+/// Types that can describe their configuration structure.
+///
+/// See the [module documentation][crate::schema].
+pub trait Schematic {
+    /// The description of the type.
+    fn schema() -> Schema;
+}
+
+impl Schematic for bool {
+    fn schema() -> Schema {
+        Schema::new(Type::Boolean)
+    }
+}
+
+macro_rules! int {
+    ($($ty: ty),*) => {
+        $(
+            impl Schematic for $ty {
+                fn schema() -> Schema {
+                    Schema::new(Type::Integer)
+                        .range(Some(<$ty>::min_value() as f64), Some(<$ty>::max_value() as f64))
+                }
+            }
+        )*
+    };
+}
+
+int!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl Schematic for f32 {
+    fn schema() -> Schema {
+        Schema::new(Type::Number)
+    }
+}
+
+impl Schematic for f64 {
+    fn schema() -> Schema {
+        Schema::new(Type::Number)
+    }
+}
+
+impl Schematic for String {
+    fn schema() -> Schema {
+        Schema::string()
+    }
+}
+
+impl Schematic for PathBuf {
+    fn schema() -> Schema {
+        Schema::string()
+    }
+}
+
+impl<T: Schematic> Schematic for Option<T> {
+    fn schema() -> Schema {
+        T::schema().optional()
+    }
+}
+
+impl<T: Schematic> Schematic for Box<T> {
+    fn schema() -> Schema {
+        T::schema()
+    }
+}
+
+impl<T: Schematic> Schematic for Vec<T> {
+    fn schema() -> Schema {
+        Schema::array(T::schema())
+    }
+}
+
+impl<T: Schematic, S> Schematic for HashSet<T, S> {
+    fn schema() -> Schema {
+        Schema::array(T::schema()).unique()
+    }
+}
+
+impl<T: Schematic> Schematic for BTreeSet<T> {
+    fn schema() -> Schema {
+        Schema::array(T::schema()).unique()
+    }
+}
+
+impl<T: Schematic, S> Schematic for HashMap<String, T, S> {
+    fn schema() -> Schema {
+        Schema::map(T::schema())
+    }
+}
+
+impl<T: Schematic> Schematic for BTreeMap<String, T> {
+    fn schema() -> Schema {
+        Schema::map(T::schema())
+    }
+}
+
+/// Exports the schema of the configuration as a JSON Schema document.
+#[cfg(feature = "json")]
+pub fn to_json_schema<C: Schematic>() -> serde_json::Value {
+    let mut schema = serde_json::to_value(C::schema()).expect("Schema is always serializable");
+    if let serde_json::Value::Object(ref mut map) = schema {
+        map.insert("$schema".to_owned(), DRAFT.into());
+    }
+    schema
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::cfg::ListenAddr;
+
+    struct Cfg;
+
+    impl Schematic for Cfg {
+        fn schema() -> Schema {
+            Schema::object()
+                .field::<ListenAddr>("listen", "Where to listen")
+                .field::<Option<Vec<String>>>("names", "Some names")
+        }
+    }
+
+    #[test]
+    fn export() {
+        let expected = json!({
+            "$schema": DRAFT,
+            "type": "object",
+            "additionalProperties": false,
+            "required": ["listen"],
+            "properties": {
+                "listen": {
+                    "anyOf": [
+                        { "type": "integer", "minimum": 0.0, "maximum": 65535.0 },
+                        { "type": "string" },
+                    ],
+                    "description": "Where to listen",
+                },
+                "names": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Some names",
+                },
+            },
+        });
+        assert_eq!(expected, to_json_schema::<Cfg>());
+    }
+}
@@ -1,10 +1,11 @@
 use std::any::{Any, TypeId};
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::ffi::OsString;
 use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::marker::PhantomData;
 use std::mem;
-use std::panic::{self, AssertUnwindSafe};
+use std::panic::{self, AssertUnwindSafe, PanicInfo};
 use std::path::{Path, PathBuf};
 use std::process;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
@@ -21,29 +22,52 @@ use structopt::StructOpt;
 
 use crate::app::App;
 use crate::bodies::{InnerBody, SpiritBody, WrapBody, Wrapper};
-use crate::cfg_loader::{Builder as CfgBuilder, ConfigBuilder, Loader as CfgLoader};
+use crate::cfg_loader::{
+    Builder as CfgBuilder, ConfigBuilder, Decryptor, LoadedFile, Loader as CfgLoader,
+};
 use crate::empty::Empty;
 use crate::error;
+use crate::events::{ConfigChanged, Events, Terminating};
 use crate::extension::{Autojoin, Extensible, Extension};
 use crate::fragment::pipeline::MultiError;
-use crate::validation::Action;
+use crate::panics::{self, PanicHook, PanicPolicy};
+use crate::sched;
+use crate::status::{Registry, ResourceStatus};
+use crate::validation::{self, Action, Problem, Report};
 use crate::AnyError;
 
-#[derive(Copy, Clone, Debug)]
-pub struct ValidationError(usize, usize);
+#[derive(Clone, Debug)]
+pub struct ValidationError {
+    errors: usize,
+    validators: usize,
+    pub(crate) problems: Vec<Problem>,
+}
 
 impl Display for ValidationError {
     fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
         write!(
             fmt,
             "Config validation failed with {} errors from {} validators",
-            self.0, self.1
+            self.errors, self.validators
         )
     }
 }
 
 impl Error for ValidationError {}
 
+#[cfg(feature = "json")]
+fn print_report(report: &Report) {
+    match report.to_json() {
+        Ok(json) => println!("{}", json),
+        Err(e) => crate::log_error!(Error, "Failed to format the report" => e),
+    }
+}
+
+#[cfg(not(feature = "json"))]
+fn print_report(_: &Report) {
+    error!("Printing the JSON report needs the json feature");
+}
+
 struct Hooks<O, C> {
     config: Vec<Box<dyn FnMut(&O, &Arc<C>) + Send>>,
     config_loader: CfgLoader,
@@ -52,6 +76,8 @@ struct Hooks<O, C> {
     sigs: HashMap<libc::c_int, Vec<Box<dyn FnMut() + Send>>>,
     singletons: HashSet<TypeId>,
     terminate: Vec<Box<dyn FnMut() + Send>>,
+    // Indices of validators, in the order their actions were last run
+    teardown: Vec<usize>,
     guards: Vec<Box<dyn Any + Send>>,
     // There's terminated inside spirit itself, as atomic variable (for lock-less fast access). But
     // that is prone to races, so we keep a separate one here.
@@ -68,6 +94,7 @@ impl<O, C> Default for Hooks<O, C> {
             sigs: HashMap::new(),
             singletons: HashSet::new(),
             terminate: Vec::new(),
+            teardown: Vec::new(),
             guards: Vec::new(),
             terminated: false,
         }
@@ -128,6 +155,8 @@ pub struct Spirit<O = Empty, C = Empty> {
     autojoin_bg_thread: AtomicUsize,
     signals: Option<Signals>,
     bg_thread: Mutex<Option<JoinHandle<()>>>,
+    events: Events,
+    resources: Mutex<Registry>,
 }
 
 impl<O, C> Spirit<O, C>
@@ -150,6 +179,8 @@ where
 
     /// Similar to [`new`][Spirit::new], but with specific initial config value
     pub fn with_initial_config(config: C) -> Builder<O, C> {
+        let events = Events::new();
+        let resources = Registry::new(&events);
         Builder {
             autojoin_bg_thread: Autojoin::TerminateAndJoin,
             before_bodies: Vec::new(),
@@ -160,7 +191,11 @@ where
             config_hooks: Vec::new(),
             config_mutators: Vec::new(),
             config_validators: Vec::new(),
+            events,
             opts: PhantomData,
+            panic_hooks: Vec::new(),
+            panic_policy: None,
+            resources,
             sig_hooks: HashMap::new(),
             singletons: HashSet::new(),
             terminate_hooks: Vec::new(),
@@ -209,6 +244,43 @@ where
         self.config.load_full()
     }
 
+    /// Access to the [event bus][crate::events].
+    ///
+    /// This is the same bus as returned by [`Extensible::events`], but without the need to clone
+    /// it.
+    pub fn events(&self) -> &Events {
+        &self.events
+    }
+
+    /// The configuration files used by the last configuration load.
+    ///
+    /// See [`Loader::loaded_files`][crate::cfg_loader::Loader::loaded_files].
+    ///
+    /// # Warning
+    ///
+    /// Like [`config_reload`][Spirit::config_reload], this can't be called from the configuration
+    /// callbacks.
+    pub fn loaded_config_files(&self) -> Vec<LoadedFile> {
+        self.hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .config_loader
+            .loaded_files()
+            .to_vec()
+    }
+
+    /// A snapshot of the status of all the resources managed by [`Pipeline`]s.
+    ///
+    /// See the [`status`][crate::status] module.
+    ///
+    /// [`Pipeline`]: crate::Pipeline
+    pub fn resources(&self) -> Vec<ResourceStatus> {
+        self.resources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .snapshot()
+    }
+
     /// Force reload of configuration.
     ///
     /// The configuration gets reloaded either when the process receives `SIGHUP` or when this
@@ -223,6 +295,7 @@ where
     /// * Logging is reopened in the new form.
     /// * The configuration is published into the storage.
     /// * The `on_config` callbacks are called.
+    /// * The [`ConfigChanged`] event is published.
     ///
     /// If any step fails, it is aborted and the old configuration is preserved.
     ///
@@ -233,38 +306,98 @@ where
     /// don't have to by `Sync`). That, however, means that you can't call `config_reload` or
     /// [`terminate`][Spirit::terminate] from any callback as that would lead to a deadlock.
     pub fn config_reload(&self) -> Result<(), AnyError> {
-        let mut new = self.load_config().context("Failed to load configuration")?;
+        let new = self.load_config().context("Failed to load configuration")?;
         // The lock here is across the whole processing, to avoid potential races in logic
         // processing. This makes writing the hooks correctly easier.
         let mut hooks = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        let new = Self::mutate(&mut hooks, new);
+        let actions = self.validate(&mut hooks, &new)?;
+        debug!("Validation successful, switching to new config");
+        let actions = validation::sort(actions);
+        hooks.teardown = actions.iter().map(|(i, _)| *i).collect();
+        let cleanups = actions
+            .into_iter()
+            .filter_map(|(_, a)| a.succeed())
+            .collect::<Vec<_>>();
+        // Only once everything new is in place, clean up after the old things
+        for mut cleanup in cleanups.into_iter().rev() {
+            cleanup();
+        }
+
+        // Once everything is validated, switch to the new config
+        self.config.store(Arc::clone(&new));
+        debug!("Running {} post-configuration hooks", hooks.config.len());
+        for hook in &mut hooks.config {
+            hook(&self.opts, &new);
+        }
+        self.events.publish(ConfigChanged);
+        // Don't let the status updates pile up if nobody asks for them
+        self.resources
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .refresh();
+        debug!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Loads the configuration and validates it, but doesn't use it.
+    ///
+    /// If `resources` is set, the validators run (and therefore the pipelines create their
+    /// resources, possibly binding sockets and such), but all the results are thrown away. If not
+    /// set, only loading of the configuration is checked.
+    ///
+    /// This is what the `--check-config` command line option does.
+    pub(crate) fn config_check(&self, resources: bool) -> Result<(), AnyError> {
+        let new = self.load_config().context("Failed to load configuration")?;
+        let mut hooks = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        let new = Self::mutate(&mut hooks, new);
+        if resources {
+            for (_, a) in self.validate(&mut hooks, &new)? {
+                a.run(false);
+            }
+        }
+        info!("Configuration is valid");
+        Ok(())
+    }
+
+    fn mutate(hooks: &mut Hooks<O, C>, mut new: C) -> Arc<C> {
         debug!("Running {} config mutators", hooks.config_mutators.len());
         for m in &mut hooks.config_mutators {
             m(&mut new);
         }
-        let new = Arc::new(new);
+        Arc::new(new)
+    }
+
+    /// Runs all the validators, either returning all the actions or rolling back.
+    fn validate(
+        &self,
+        hooks: &mut Hooks<O, C>,
+        new: &Arc<C>,
+    ) -> Result<Vec<(usize, Action)>, AnyError> {
         let old = self.config.load();
         debug!(
             "Running {} config validators",
             hooks.config_validators.len()
         );
-        let mut errors = 0;
+        let mut problems = Vec::new();
         let mut failed_validators = 0;
         let mut actions = Vec::with_capacity(hooks.config_validators.len());
-        for v in hooks.config_validators.iter_mut() {
-            match v(&old, &new, &self.opts) {
-                Ok(ac) => actions.push(ac),
+        for (i, v) in hooks.config_validators.iter_mut().enumerate() {
+            match v(&old, new, &self.opts) {
+                Ok(ac) => actions.push((i, ac)),
                 Err(e) => {
                     failed_validators += 1;
                     match e.downcast::<MultiError>() {
                         Ok(e) => {
                             error!("{}", e);
-                            errors += e.errors.len();
-                            for e in e.errors {
+                            let MultiError { errors, pipeline } = *e;
+                            for e in errors {
+                                problems.push(Problem::from_error(&*e).with_fragment(pipeline));
                                 crate::log_error!(multi Error, e);
                             }
                         }
                         Err(e) => {
-                            errors += 1;
+                            problems.push(Problem::from_error(&*e));
                             crate::log_error!(multi Error, e);
                         }
                     }
@@ -272,27 +405,20 @@ where
             }
         }
 
-        if errors == 0 {
-            debug!("Validation successful, switching to new config");
-            for a in actions {
-                a.run(true);
-            }
+        if problems.is_empty() {
+            Ok(actions)
         } else {
             debug!("Rolling back validation attempt");
-            for a in actions {
+            for (_, a) in actions {
                 a.run(false);
             }
-            return Err(ValidationError(errors, failed_validators).into());
-        }
-
-        // Once everything is validated, switch to the new config
-        self.config.store(Arc::clone(&new));
-        debug!("Running {} post-configuration hooks", hooks.config.len());
-        for hook in &mut hooks.config {
-            hook(&self.opts, &new);
+            Err(ValidationError {
+                errors: problems.len(),
+                validators: failed_validators,
+                problems,
+            }
+            .into())
         }
-        debug!("Configuration reloaded");
-        Ok(())
     }
 
     /// Is the application in the shutdown phase?
@@ -334,6 +460,7 @@ where
     ///
     /// The termination does this:
     ///
+    /// * Publishes the [`Terminating`] event and closes the [event bus][crate::events].
     /// * Calls the `on_terminate` callbacks.
     /// * Sets the [`is_terminated`][Spirit::is_terminated] flag is set.
     /// * Drops all callbacks from spirit. This allows destruction/termination of parts of program
@@ -352,6 +479,8 @@ where
             signals.close();
         }
         let mut hooks = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+        self.events.publish(Terminating);
+        self.events.close();
         // Take out the terminate hooks out first, so they are not called multiple times even in
         // case of panic.
         let mut term_hooks = Vec::new();
@@ -360,6 +489,20 @@ where
             hook();
         }
         self.terminate.store(true, Ordering::Relaxed);
+        // Drop the validators (and the resources they hold, in case of pipelines) in the reverse
+        // order they were installed in.
+        let mut validators = mem::replace(&mut hooks.config_validators, Vec::new())
+            .into_iter()
+            .map(Some)
+            .collect::<Vec<_>>();
+        for i in hooks.teardown.iter().rev() {
+            if let Some(validator) = validators.get_mut(*i) {
+                drop(validator.take());
+            }
+        }
+        while let Some(validator) = validators.pop() {
+            drop(validator);
+        }
         // Get rid of all other hooks too. This drops any variables held by the closures,
         // potentially shutting down things than need to be shut down. But we need to keep the
         // guards (until the end of the spirit lifetime) and the singletons (so we don't register
@@ -377,34 +520,50 @@ where
 
     fn background(&self, signals: &Signals) {
         debug!("Starting background processing");
+        sched::apply_background();
         for signal in signals.forever() {
-            debug!("Received signal {}", signal);
-            let term = match signal {
-                libc::SIGHUP => {
-                    let _ = error::log_errors(module_path!(), || self.config_reload());
-                    false
-                }
-                libc::SIGTERM | libc::SIGINT | libc::SIGQUIT => {
-                    self.terminate();
-                    true
-                }
-                // Some other signal, only for the hook benefit
-                _ => false,
-            };
-
-            let mut lock = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+            if self.handle_signal(signal) {
+                break;
+            }
+            sched::apply_background();
+        }
+        debug!("Terminating the background thread");
+    }
 
-            if let Some(hooks) = lock.sigs.get_mut(&signal) {
-                for hook in hooks {
-                    hook();
-                }
+    /// Reacts to a received signal, returning if it was a termination one.
+    pub(crate) fn handle_signal(&self, signal: libc::c_int) -> bool {
+        debug!("Received signal {}", signal);
+        let term = match signal {
+            libc::SIGHUP => {
+                let _ = error::log_errors(module_path!(), || self.config_reload());
+                false
+            }
+            libc::SIGTERM | libc::SIGINT | libc::SIGQUIT => {
+                self.terminate();
+                true
             }
+            // Some other signal, only for the hook benefit
+            _ => false,
+        };
 
-            if term {
-                break;
+        let mut lock = self.hooks.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(hooks) = lock.sigs.get_mut(&signal) {
+            for hook in hooks {
+                hook();
             }
         }
-        debug!("Terminating the background thread");
+
+        term
+    }
+
+    /// Makes the following loads use the given configuration document instead of the files.
+    pub(crate) fn inject_config(&self, config: String) {
+        self.hooks
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .config_loader
+            .inject(config);
     }
 
     fn load_config(&self) -> Result<C, AnyError> {
@@ -481,6 +640,8 @@ where
             let cfg = self.config();
             f(&cfg, &cfg, self.cmd_opts())?.run(true);
             hooks.config_validators.push(Box::new(f));
+            let installed = hooks.config_validators.len() - 1;
+            hooks.teardown.push(installed);
         }
         Ok(self)
     }
@@ -603,6 +764,19 @@ where
             .store(autojoin as usize, Ordering::Relaxed);
         self
     }
+
+    fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    fn component(self, name: &str, version: &str) -> Self {
+        trace!(
+            "Ignoring component {} {}, the command line is already parsed",
+            name,
+            version
+        );
+        self
+    }
 }
 
 /// The builder of [`Spirit`].
@@ -628,7 +802,11 @@ pub struct Builder<O = Empty, C = Empty> {
     config_hooks: Vec<Box<dyn FnMut(&O, &Arc<C>) + Send>>,
     config_mutators: Vec<Box<dyn FnMut(&mut C) + Send>>,
     config_validators: Vec<Box<dyn FnMut(&Arc<C>, &Arc<C>, &O) -> Result<Action, AnyError> + Send>>,
+    events: Events,
     opts: PhantomData<O>,
+    panic_hooks: Vec<PanicHook>,
+    panic_policy: Option<PanicPolicy>,
+    resources: Registry,
     sig_hooks: HashMap<libc::c_int, Vec<Box<dyn FnMut() + Send>>>,
     singletons: HashSet<TypeId>,
     terminate_hooks: Vec<Box<dyn FnMut() + Send>>,
@@ -650,6 +828,201 @@ where
             ..self
         }
     }
+
+    /// Sets the version of the application shown by `--version`.
+    ///
+    /// See [`cfg_loader::Builder::app_version`][crate::cfg_loader::Builder::app_version]. This is
+    /// useful mostly with options not defined by the application itself (like [`Empty`]), which
+    /// would show the version of spirit instead.
+    ///
+    /// [`Empty`]: crate::Empty
+    pub fn app_version<V: Into<String>>(self, version: V) -> Self {
+        Self {
+            config_loader: self.config_loader.app_version(version),
+            ..self
+        }
+    }
+
+    /// Sets what happens when something panics.
+    ///
+    /// See the [`panics`][crate::panics] module. If neither this nor
+    /// [`on_panic`][Builder::on_panic] is used, the panic handling is left alone.
+    pub fn panic_policy(self, policy: PanicPolicy) -> Self {
+        Self {
+            panic_policy: Some(policy),
+            ..self
+        }
+    }
+
+    /// Registers a hook called when something panics.
+    ///
+    /// The hooks are called from the panic hook, in the panicking thread, before the panic is
+    /// reported and the [panic policy][Builder::panic_policy] is applied. They should be quick
+    /// and must not panic.
+    pub fn on_panic<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&PanicInfo) + Send + Sync + 'static,
+    {
+        self.panic_hooks.push(Arc::new(hook));
+        self
+    }
+
+    /// Runs the before-config hooks and turns the builder into a not yet configured spirit.
+    ///
+    /// The bodies and their wrappers are not part of the spirit, so they are returned separately.
+    #[allow(clippy::type_complexity)]
+    fn into_spirit(
+        mut self,
+        opts: O,
+        loader: CfgLoader,
+        signals: bool,
+    ) -> Result<(Spirit<O, C>, Vec<SpiritBody<O, C>>, Vec<Wrapper<O, C>>), AnyError> {
+        for before_config in &mut self.before_config {
+            before_config(&self.config, &opts).context("The before-config phase failed")?;
+        }
+        let signals = if signals {
+            let interesting_signals = self
+                .sig_hooks
+                .keys()
+                .chain(&[libc::SIGHUP, libc::SIGTERM, libc::SIGQUIT, libc::SIGINT])
+                .cloned()
+                .collect::<HashSet<_>>(); // Eliminate duplicates
+            Some(Signals::new(interesting_signals)?)
+        } else {
+            None
+        };
+        let spirit = Spirit {
+            autojoin_bg_thread: AtomicUsize::new(self.autojoin_bg_thread as _),
+            config: ArcSwap::from(Arc::from(self.config)),
+            hooks: Mutex::new(Hooks {
+                config: self.config_hooks,
+                config_loader: loader,
+                config_mutators: self.config_mutators,
+                config_validators: self.config_validators,
+                sigs: self.sig_hooks,
+                singletons: self.singletons,
+                terminate: self.terminate_hooks,
+                teardown: Vec::new(),
+                terminated: false,
+                guards: self.guards,
+            }),
+            opts,
+            terminate: AtomicBool::new(false),
+            signals,
+            bg_thread: Mutex::new(None),
+            events: self.events,
+            resources: Mutex::new(self.resources),
+        };
+        Ok((spirit, self.before_bodies, self.body_wrappers))
+    }
+
+    /// Creates the spirit, loads the initial configuration and prepares the bodies.
+    ///
+    /// The signal handling (and the background thread) is set up only if `background_thread` is
+    /// set. Without it, any registered signal hooks are simply never called.
+    pub(crate) fn assemble(
+        mut self,
+        opts: O,
+        loader: CfgLoader,
+        background_thread: bool,
+    ) -> Result<App<O, C>, AnyError> {
+        let panic_policy = self.panic_policy.take();
+        let panic_hooks = mem::replace(&mut self.panic_hooks, Vec::new());
+        let (spirit, before_bodies, body_wrappers) =
+            self.into_spirit(opts, loader, background_thread)?;
+        spirit
+            .config_reload()
+            .context("Problem loading the initial configuration")?;
+        let signals = spirit.signals.clone();
+        let spirit = Arc::new(spirit);
+        if panic_policy.is_some() || !panic_hooks.is_empty() {
+            let weak = Arc::downgrade(&spirit);
+            let terminate = move || {
+                if let Some(spirit) = weak.upgrade() {
+                    spirit.terminate();
+                }
+            };
+            panics::install(panic_policy.unwrap_or_default(), panic_hooks, terminate);
+        }
+        if background_thread {
+            let spirit_bg = Arc::clone(&spirit);
+            let handle = thread::Builder::new()
+                .name("spirit".to_owned())
+                .spawn(move || {
+                    loop {
+                        // Note: we run a bunch of callbacks inside the service thread. We restart
+                        // the thread if it fails.
+                        let run =
+                            AssertUnwindSafe(|| spirit_bg.background(signals.as_ref().unwrap()));
+                        if panic::catch_unwind(run).is_err() {
+                            // FIXME: Something better than this to prevent looping?
+                            thread::sleep(Duration::from_secs(1));
+                            info!("Restarting the spirit service thread after a panic");
+                        } else {
+                            // Willingly terminated
+                            break;
+                        }
+                    }
+                })
+                .unwrap(); // Could fail only if the name contained \0
+            *spirit
+                .bg_thread
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Some(handle);
+        }
+        debug!(
+            "Building bodies from {} before-bodies and {} wrappers",
+            before_bodies.len(),
+            body_wrappers.len()
+        );
+        let spirit_body = Arc::clone(&spirit);
+        let inner = move || {
+            for body in before_bodies {
+                body(&spirit_body)?;
+            }
+            Ok(())
+        };
+        let inner = Box::new(inner);
+        let spirit_body = Arc::clone(&spirit);
+        let mut wrapped = Box::new(|inner: InnerBody| inner()) as WrapBody;
+        for wrapper in body_wrappers.into_iter().rev() {
+            // TODO: Can we get rid of this clone?
+            let spirit = Arc::clone(&spirit_body);
+            let applied = move |inner: InnerBody| wrapper(&spirit, inner);
+            wrapped = Box::new(applied) as WrapBody;
+        }
+        Ok(App::new(spirit, inner, wrapped))
+    }
+
+    /// The part of building after the command line is parsed.
+    fn build_parsed(
+        self,
+        opts: O,
+        loader: CfgLoader,
+        background_thread: bool,
+    ) -> Result<App<O, C>, AnyError> {
+        if loader.check_only() {
+            let json = loader.check_json();
+            let (spirit, _, _) = self.into_spirit(opts, loader, false)?;
+            let result = spirit.config_check(true);
+            if json {
+                print_report(&Report::from_result(&result));
+            }
+            return Ok(App::checked(Arc::new(spirit), result));
+        }
+        if !background_thread {
+            assert!(
+                self.sig_hooks.is_empty(),
+                "Registered signals; now starting without a signal thread",
+            );
+        }
+        self.assemble(opts, loader, background_thread)
+    }
+
+    /// Takes the config loader builder out, leaving a default one in place.
+    pub(crate) fn take_config_loader(&mut self) -> CfgBuilder {
+        mem::replace(&mut self.config_loader, CfgBuilder::new())
+    }
 }
 
 impl<O, C> ConfigBuilder for Builder<O, C> {
@@ -685,6 +1058,30 @@ impl<O, C> ConfigBuilder for Builder<O, C> {
         }
     }
 
+    fn config_layer<F>(self, layer: F) -> Self
+    where
+        F: FnMut() -> Result<Vec<(String, String)>, AnyError> + Send + 'static,
+    {
+        Self {
+            config_loader: self.config_loader.config_layer(layer),
+            ..self
+        }
+    }
+
+    fn config_decryptor<D: Decryptor + 'static>(self, decryptor: D) -> Self {
+        Self {
+            config_loader: self.config_loader.config_decryptor(decryptor),
+            ..self
+        }
+    }
+
+    fn config_references(self, resolve: bool) -> Self {
+        Self {
+            config_loader: self.config_loader.config_references(resolve),
+            ..self
+        }
+    }
+
     fn warn_on_unused(self, warn: bool) -> Self {
         Self {
             config_loader: self.config_loader.warn_on_unused(warn),
@@ -823,6 +1220,17 @@ impl<O, C> Extensible for Builder<O, C> {
             ..self
         }
     }
+
+    fn events(&self) -> Events {
+        self.events.clone()
+    }
+
+    fn component(self, name: &str, version: &str) -> Self {
+        Self {
+            config_loader: self.config_loader.component(name, version),
+            ..self
+        }
+    }
 }
 
 /// An interface to turn the spirit [`Builder`] into a [`Spirit`] and possibly run it.
@@ -881,6 +1289,89 @@ pub trait SpiritBuilder: ConfigBuilder + Extensible {
     // TODO: The new return value
     fn build(self, background_thread: bool) -> Result<App<Self::Opts, Self::Config>, AnyError>;
 
+    /// Finish building the Spirit, with command line arguments passed explicitly.
+    ///
+    /// This is the same as [`build`][SpiritBuilder::build], but the command line is parsed from
+    /// the provided arguments instead of the ones of the process. Furthermore, invalid arguments
+    /// (and `--help`) are reported as an error instead of exiting the process. This makes it
+    /// better suited for tests or for applications that get their arguments from somewhere else.
+    ///
+    /// Note that the 0th argument is considered to be the name of the application and is not
+    /// parsed as an option.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    /// use spirit::prelude::*;
+    ///
+    /// let app = Spirit::<Empty, Empty>::new()
+    ///     .build_from(&["app", "--config-override", "a=b"], false)
+    ///     .unwrap();
+    /// app.run(|| Ok(())).unwrap();
+    ///
+    /// assert!(Spirit::<Empty, Empty>::new().build_from(&["app", "--invalid"], false).is_err());
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Similar to [`build`][SpiritBuilder::build], this panics if `background_thread` is set to
+    /// `false` and there are registered signals.
+    fn build_from<I>(
+        self,
+        args: I,
+        background_thread: bool,
+    ) -> Result<App<Self::Opts, Self::Config>, AnyError>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone;
+
+    /// Finish building the Spirit, with already parsed command line options.
+    ///
+    /// This is the same as [`build`][SpiritBuilder::build], but doesn't touch the command line at
+    /// all. As the options added by spirit itself are not available, the configuration is loaded
+    /// only from the [default paths][ConfigBuilder::config_default_paths] (and other sources set
+    /// on the builder) and there are no config overrides.
+    ///
+    /// # Panics
+    ///
+    /// Similar to [`build`][SpiritBuilder::build], this panics if `background_thread` is set to
+    /// `false` and there are registered signals.
+    fn build_with_opts(
+        self,
+        opts: Self::Opts,
+        background_thread: bool,
+    ) -> Result<App<Self::Opts, Self::Config>, AnyError>;
+
+    /// Only checks the configuration, without starting the application.
+    ///
+    /// This parses the command line and loads the configuration the same way as
+    /// [`build`][SpiritBuilder::build] does. If `resources` is set, it also runs all the config
+    /// validators, which makes the [`Pipeline`][crate::Pipeline]s create all their resources
+    /// (note that this may involve binding sockets, which can fail if another instance of the
+    /// application is running). The created resources are then thrown away again, without being
+    /// installed and without calling any [`on_config`][Extensible::on_config] callbacks.
+    ///
+    /// This is useful for checking configuration changes (for example in CI) before deploying
+    /// them. The same check can be triggered by the `--check-config` command line option of an
+    /// application calling [`build`][SpiritBuilder::build] or [`run`][SpiritBuilder::run]. In that
+    /// case the [`App`] returned by `build` only carries the outcome (see [`App::check_only`]) and
+    /// the [`run`][SpiritBuilder::run] exits with exit code 0 if the configuration is fine and 1
+    /// otherwise.
+    /// The `--check-config-json` variant also prints a machine-readable
+    /// [`Report`][crate::validation::Report] of the problems to the standard output.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use spirit::{Empty, Spirit};
+    /// use spirit::prelude::*;
+    ///
+    /// let valid = Spirit::<Empty, Empty>::new().check(true).is_ok();
+    /// assert!(valid);
+    /// ```
+    fn check(self, resources: bool) -> Result<(), AnyError>;
+
     /// Build the spirit and run the application, handling all relevant errors.
     ///
     /// In case an error happens (either when creating the Spirit, or returned by the callback),
@@ -921,100 +1412,40 @@ where
 {
     fn build(mut self, background_thread: bool) -> Result<App<O, C>, AnyError> {
         debug!("Building the spirit");
-        let (opts, loader) = self.config_loader.build::<Self::Opts>();
-        for before_config in &mut self.before_config {
-            before_config(&self.config, &opts).context("The before-config phase failed")?;
-        }
-        let interesting_signals = self
-            .sig_hooks
-            .keys()
-            .chain(&[libc::SIGHUP, libc::SIGTERM, libc::SIGQUIT, libc::SIGINT])
-            .cloned()
-            .collect::<HashSet<_>>(); // Eliminate duplicates
-        let config = ArcSwap::from(Arc::from(self.config));
-        let signals = if background_thread {
-            Some(Signals::new(interesting_signals)?)
-        } else {
-            assert!(
-                self.sig_hooks.is_empty(),
-                "Registered signals; now starting without a signal thread",
-            );
-            None
-        };
-        let signals_spirit = signals.clone();
-        let spirit = Spirit {
-            autojoin_bg_thread: AtomicUsize::new(self.autojoin_bg_thread as _),
-            config,
-            hooks: Mutex::new(Hooks {
-                config: self.config_hooks,
-                config_loader: loader,
-                config_mutators: self.config_mutators,
-                config_validators: self.config_validators,
-                sigs: self.sig_hooks,
-                singletons: self.singletons,
-                terminate: self.terminate_hooks,
-                terminated: false,
-                guards: self.guards,
-            }),
-            opts,
-            terminate: AtomicBool::new(false),
-            signals: signals_spirit,
-            bg_thread: Mutex::new(None),
-        };
-        spirit
-            .config_reload()
-            .context("Problem loading the initial configuration")?;
-        let spirit = Arc::new(spirit);
-        if background_thread {
-            let spirit_bg = Arc::clone(&spirit);
-            let handle = thread::Builder::new()
-                .name("spirit".to_owned())
-                .spawn(move || {
-                    loop {
-                        // Note: we run a bunch of callbacks inside the service thread. We restart
-                        // the thread if it fails.
-                        let run =
-                            AssertUnwindSafe(|| spirit_bg.background(signals.as_ref().unwrap()));
-                        if panic::catch_unwind(run).is_err() {
-                            // FIXME: Something better than this to prevent looping?
-                            thread::sleep(Duration::from_secs(1));
-                            info!("Restarting the spirit service thread after a panic");
-                        } else {
-                            // Willingly terminated
-                            break;
-                        }
-                    }
-                })
-                .unwrap(); // Could fail only if the name contained \0
-            *spirit
-                .bg_thread
-                .lock()
-                .unwrap_or_else(PoisonError::into_inner) = Some(handle);
-        }
-        debug!(
-            "Building bodies from {} before-bodies and {} wrappers",
-            self.before_bodies.len(),
-            self.body_wrappers.len()
-        );
-        let spirit_body = Arc::clone(&spirit);
-        let bodies = self.before_bodies;
-        let inner = move || {
-            for body in bodies {
-                body(&spirit_body)?;
-            }
-            Ok(())
-        };
-        let body_wrappers = self.body_wrappers;
-        let inner = Box::new(inner);
-        let spirit_body = Arc::clone(&spirit);
-        let mut wrapped = Box::new(|inner: InnerBody| inner()) as WrapBody;
-        for wrapper in body_wrappers.into_iter().rev() {
-            // TODO: Can we get rid of this clone?
-            let spirit = Arc::clone(&spirit_body);
-            let applied = move |inner: InnerBody| wrapper(&spirit, inner);
-            wrapped = Box::new(applied) as WrapBody;
-        }
-        Ok(App::new(spirit, inner, wrapped))
+        let loader = self.take_config_loader();
+        let (opts, loader) = loader.build::<Self::Opts>();
+        self.build_parsed(opts, loader, background_thread)
+    }
+
+    fn build_from<I>(mut self, args: I, background_thread: bool) -> Result<App<O, C>, AnyError>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        debug!("Building the spirit from explicit arguments");
+        let loader = self.take_config_loader();
+        let (opts, loader) = loader
+            .build_explicit_opts::<Self::Opts, _>(args)
+            .context("Invalid command line arguments")?;
+        self.build_parsed(opts, loader, background_thread)
+    }
+
+    fn build_with_opts(
+        mut self,
+        opts: Self::Opts,
+        background_thread: bool,
+    ) -> Result<App<O, C>, AnyError> {
+        debug!("Building the spirit with pre-parsed options");
+        let loader = self.take_config_loader().build_no_opts();
+        self.build_parsed(opts, loader, background_thread)
+    }
+
+    fn check(mut self, resources: bool) -> Result<(), AnyError> {
+        debug!("Checking the configuration");
+        let loader = self.take_config_loader();
+        let (opts, loader) = loader.build::<Self::Opts>();
+        let (spirit, _, _) = self.into_spirit(opts, loader, false)?;
+        spirit.config_check(resources)
     }
 
     fn run<B: FnOnce(&Arc<Spirit<O, C>>) -> Result<(), AnyError> + Send + 'static>(self, body: B) {
@@ -1030,6 +1461,19 @@ where
     fn build(self, background_thread: bool) -> Result<App<O, C>, AnyError> {
         self.and_then(|b| b.build(background_thread))
     }
+    fn build_from<I>(self, args: I, background_thread: bool) -> Result<App<O, C>, AnyError>
+    where
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        self.and_then(|b| b.build_from(args, background_thread))
+    }
+    fn build_with_opts(self, opts: O, background_thread: bool) -> Result<App<O, C>, AnyError> {
+        self.and_then(|b| b.build_with_opts(opts, background_thread))
+    }
+    fn check(self, resources: bool) -> Result<(), AnyError> {
+        self.and_then(|b| b.check(resources))
+    }
     fn run<B: FnOnce(&Arc<Spirit<O, C>>) -> Result<(), AnyError> + Send + 'static>(self, body: B) {
         let result = error::log_errors("top-level", || {
             let me = self?;
@@ -1055,4 +1499,18 @@ mod tests {
         let spirit = Arc::clone(app.spirit());
         spirit.on_terminate(|| ()).on_config(|_opts, _cfg| ());
     }
+
+    #[test]
+    fn check_config_returns() {
+        let app = Spirit::<Empty, Empty>::new()
+            .build_from(vec!["my-app", "--check-config"], false)
+            .unwrap();
+        assert!(app.check_only());
+        app.run(|| panic!("The body must not run")).unwrap();
+
+        let app = Spirit::<Empty, Empty>::new()
+            .build_from(vec!["my-app"], false)
+            .unwrap();
+        assert!(!app.check_only());
+    }
 }
@@ -0,0 +1,183 @@
+//! Introspection of the resources managed by spirit.
+//!
+//! Every [`Pipeline`] keeps a [`ResourceStatus`] record about itself ‒ what it manages, how many
+//! resources it has installed right now, when it last installed something new and why the last
+//! attempt failed, if it did. A snapshot of all these records can be obtained by
+//! [`Spirit::resources`]. This is meant for admin endpoints and troubleshooting, so one doesn't
+//! have to reconstruct the state of the application by reading the logs.
+//!
+//! Each change of the record is also published on the [event bus][crate::events], so extensions
+//! can watch the changes as they happen.
+//!
+//! Resources can also fail after they were installed (eg. a task serving a listener panics).
+//! Whoever notices can publish a [`ResourceFailed`] event on the bus and it gets recorded as the
+//! last error of the pipeline.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use spirit::{Empty, Spirit};
+//! use spirit::prelude::*;
+//!
+//! Spirit::<Empty, Empty>::new()
+//!     .run(|spirit| {
+//!         for resource in spirit.resources() {
+//!             println!(
+//!                 "{}: {} instances, last error: {:?}",
+//!                 resource.name, resource.instances, resource.last_error,
+//!             );
+//!         }
+//!         Ok(())
+//!     });
+//! ```
+//!
+//! [`Pipeline`]: crate::Pipeline
+//! [`Spirit::resources`]: crate::Spirit::resources
+
+use std::sync::mpsc::Receiver;
+use std::time::SystemTime;
+
+use crate::events::Events;
+
+/// A status of a single [`Pipeline`][crate::Pipeline].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResourceStatus {
+    /// The name of the pipeline.
+    pub name: &'static str,
+
+    /// The (type) name of the fragment the pipeline manages.
+    pub fragment: &'static str,
+
+    /// The current configuration of the fragment.
+    ///
+    /// As the configuration may contain secrets and not all fragments implement `Debug`, this is
+    /// recorded only for pipelines that opt in by
+    /// [`show_config`][crate::fragment::pipeline::Pipeline::show_config].
+    pub config: Option<String>,
+
+    /// How many instances of the resource are installed right now.
+    pub instances: usize,
+
+    /// When the pipeline last installed a new resource.
+    ///
+    /// This is `None` until the first install.
+    pub installed: Option<SystemTime>,
+
+    /// The error of the last attempt to create the resources, if it failed, or of a
+    /// [failure][ResourceFailed] of an installed resource.
+    ///
+    /// Cleared once a new configuration is successfully applied.
+    pub last_error: Option<String>,
+}
+
+impl ResourceStatus {
+    pub(crate) fn new(name: &'static str, fragment: &'static str) -> Self {
+        Self {
+            name,
+            fragment,
+            config: None,
+            instances: 0,
+            installed: None,
+            last_error: None,
+        }
+    }
+}
+
+/// An installed resource failed.
+///
+/// Published by whoever notices a resource of a [`Pipeline`][crate::Pipeline] stopped working after
+/// it was installed. The error is recorded in the [`ResourceStatus`] of the pipeline (until it
+/// installs new resources).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ResourceFailed {
+    /// The name of the pipeline.
+    pub pipeline: &'static str,
+    /// What happened.
+    pub error: String,
+}
+
+/// Collects the status updates published by the pipelines.
+pub(crate) struct Registry {
+    updates: Receiver<ResourceStatus>,
+    failures: Receiver<ResourceFailed>,
+    current: Vec<ResourceStatus>,
+}
+
+impl Registry {
+    pub(crate) fn new(events: &Events) -> Self {
+        Self {
+            updates: events.subscribe(),
+            failures: events.subscribe(),
+            current: Vec::new(),
+        }
+    }
+
+    /// Applies all the pending updates.
+    ///
+    /// This needs to be called from time to time even if nobody is interested in the snapshot, so
+    /// the updates don't pile up in the channel.
+    pub(crate) fn refresh(&mut self) {
+        for update in self.updates.try_iter() {
+            match self.current.iter_mut().find(|s| s.name == update.name) {
+                Some(status) => *status = update,
+                None => self.current.push(update),
+            }
+        }
+        // We don't know the relative order to the updates, so the failures win in case of doubt
+        for failure in self.failures.try_iter() {
+            if let Some(status) = self.current.iter_mut().find(|s| s.name == failure.pipeline) {
+                status.last_error = Some(failure.error);
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&mut self) -> Vec<ResourceStatus> {
+        self.refresh();
+        self.current.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates() {
+        let events = Events::new();
+        let mut registry = Registry::new(&events);
+        assert!(registry.snapshot().is_empty());
+        events.publish(ResourceStatus::new("a", "A"));
+        events.publish(ResourceStatus::new("b", "B"));
+        events.publish(ResourceStatus {
+            instances: 2,
+            ..ResourceStatus::new("a", "A")
+        });
+        let snapshot = registry.snapshot();
+        assert_eq!(2, snapshot.len());
+        assert_eq!("a", snapshot[0].name);
+        assert_eq!(2, snapshot[0].instances);
+        assert_eq!("b", snapshot[1].name);
+    }
+
+    #[test]
+    fn failures() {
+        let events = Events::new();
+        let mut registry = Registry::new(&events);
+        events.publish(ResourceStatus::new("a", "A"));
+        events.publish(ResourceFailed {
+            pipeline: "a",
+            error: "Boom".to_owned(),
+        });
+        // Unknown pipeline is ignored
+        events.publish(ResourceFailed {
+            pipeline: "b",
+            error: "Boom".to_owned(),
+        });
+        let snapshot = registry.snapshot();
+        assert_eq!(1, snapshot.len());
+        assert_eq!(
+            Some("Boom"),
+            snapshot[0].last_error.as_ref().map(String::as_str)
+        );
+    }
+}
@@ -0,0 +1,253 @@
+//! Support for testing spirit applications.
+//!
+//! Running the whole application through [`run`][crate::SpiritBuilder::run] in a test is not
+//! practical. It parses the real command line of the test binary, installs signal handlers,
+//! starts a background thread and exits the process on errors. The [`TestSpirit`] builds the
+//! application from the same [`Builder`] without all that. The configuration is passed in as a
+//! string and can be replaced later on, the reloads, signals and termination are triggered
+//! manually and the [events][crate::events] are recorded so the test can check what happened.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use serde::Deserialize;
+//! use spirit::{AnyError, Empty, Spirit};
+//! use spirit::prelude::*;
+//! use spirit::testing::TestSpirit;
+//!
+//! #[derive(Default, Deserialize)]
+//! struct Cfg {
+//!     msg: String,
+//! }
+//!
+//! # fn main() -> Result<(), AnyError> {
+//! let builder = Spirit::<Empty, Cfg>::new()
+//!     .config_validator(|_, new, _| {
+//!         if new.msg.is_empty() {
+//!             Err("The message must not be empty".into())
+//!         } else {
+//!             Ok(Default::default())
+//!         }
+//!     });
+//! let mut app = TestSpirit::new(builder, r#"msg = "hello""#)?;
+//! assert_eq!("hello", app.spirit().config().msg);
+//! assert_eq!(1, app.config_changes());
+//!
+//! // A broken config is refused and the old one stays
+//! assert!(app.reload(r#"msg = """#).is_err());
+//! assert_eq!("hello", app.spirit().config().msg);
+//! assert_eq!(0, app.config_changes());
+//!
+//! app.reload(r#"msg = "world""#)?;
+//! assert_eq!("world", app.spirit().config().msg);
+//!
+//! app.run(|| Ok(()))?;
+//! app.terminate();
+//! assert!(app.spirit().is_terminated());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::ffi::OsString;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use structopt::StructOpt;
+
+use crate::app::App;
+use crate::events::{ConfigChanged, ResourceInstalled, Terminating};
+use crate::extension::{Extensible, IntoResult};
+use crate::spirit::{Builder, Spirit};
+use crate::AnyError;
+
+/// A spirit application built for tests.
+///
+/// See the [module documentation](index.html).
+pub struct TestSpirit<O, C> {
+    spirit: Arc<Spirit<O, C>>,
+    app: Option<App<O, C>>,
+    config_changed: Receiver<ConfigChanged>,
+    installed: Receiver<ResourceInstalled>,
+    terminating: Receiver<Terminating>,
+}
+
+impl<O, C> TestSpirit<O, C>
+where
+    C: DeserializeOwned + Send + Sync + 'static,
+    O: StructOpt + Sync + Send + 'static,
+{
+    /// Builds the application with the given configuration and no command line arguments.
+    ///
+    /// The configuration is a TOML document, used in place of the configuration files. The
+    /// [defaults][crate::ConfigBuilder::config_defaults] and
+    /// [environment][crate::ConfigBuilder::config_env] still apply.
+    ///
+    /// The initial configuration is loaded, so the pipelines already have their resources
+    /// installed. The [before-bodies][Extensible::run_before] are not run until
+    /// [`run`][TestSpirit::run].
+    pub fn new<B>(builder: B, config: &str) -> Result<Self, AnyError>
+    where
+        B: IntoResult<Builder<O, C>>,
+    {
+        Self::with_args(builder, &["test"], config)
+    }
+
+    /// Builds the application with the given command line arguments.
+    ///
+    /// The 0th argument is the name of the program. Unlike the real command line parsing, an
+    /// invalid command line is reported as an error instead of exiting.
+    pub fn with_args<B, I>(builder: B, args: I, config: &str) -> Result<Self, AnyError>
+    where
+        B: IntoResult<Builder<O, C>>,
+        I: IntoIterator,
+        I::Item: Into<OsString> + Clone,
+    {
+        let mut builder = builder.into_result()?;
+        let events = builder.events();
+        let config_changed = events.subscribe();
+        let installed = events.subscribe();
+        let terminating = events.subscribe();
+        let (opts, mut loader) = builder.take_config_loader().build_explicit_opts(args)?;
+        loader.inject(config.to_owned());
+        let app = builder.assemble(opts, loader, false)?;
+        Ok(Self {
+            spirit: Arc::clone(app.spirit()),
+            app: Some(app),
+            config_changed,
+            installed,
+            terminating,
+        })
+    }
+
+    /// Access to the spirit.
+    pub fn spirit(&self) -> &Arc<Spirit<O, C>> {
+        &self.spirit
+    }
+
+    /// Replaces the configuration and reloads it.
+    ///
+    /// The following [`signal`][TestSpirit::signal] reloads use the new configuration too.
+    pub fn reload(&self, config: &str) -> Result<(), AnyError> {
+        self.spirit.inject_config(config.to_owned());
+        self.spirit.config_reload()
+    }
+
+    /// Simulates receiving a signal.
+    ///
+    /// The signal is handled the same way as by the background thread of a real application ‒
+    /// `SIGHUP` reloads the configuration (logging any errors), the termination signals terminate
+    /// the application and any [signal hooks][Extensible::on_signal] are called.
+    pub fn signal(&self, signal: libc::c_int) {
+        self.spirit.handle_signal(signal);
+    }
+
+    /// Terminates the application.
+    pub fn terminate(&self) {
+        self.spirit.terminate();
+    }
+
+    /// Runs the application body, preceded by the before-bodies and wrapped in the around-bodies.
+    ///
+    /// Depending on the [`autojoin_bg_thread`][Extensible::autojoin_bg_thread] setting, the
+    /// application may be terminated once the body finishes (the default). This can be called
+    /// only once.
+    pub fn run<B>(&mut self, body: B) -> Result<(), AnyError>
+    where
+        B: FnOnce() -> Result<(), AnyError> + Send + 'static,
+    {
+        self.app
+            .take()
+            .ok_or("The test application has already run")?
+            .run(body)
+    }
+
+    /// How many times a new configuration was put in place since the last call.
+    pub fn config_changes(&self) -> usize {
+        self.config_changed.try_iter().count()
+    }
+
+    /// Names of the pipelines that installed new resources since the last call.
+    ///
+    /// If a pipeline installed multiple times, it is listed multiple times.
+    pub fn installed(&self) -> Vec<&'static str> {
+        self.installed.try_iter().map(|i| i.pipeline).collect()
+    }
+
+    /// Did the application start terminating?
+    ///
+    /// Unlike [`Spirit::is_terminated`], this is set already while the termination hooks run.
+    pub fn terminating(&self) -> bool {
+        self.terminating.try_recv().is_ok() || self.spirit.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::fragment::driver::CacheEq;
+    use crate::{Empty, Pipeline};
+
+    #[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+    struct Res {
+        value: u32,
+    }
+
+    crate::simple_fragment! {
+        impl Fragment for Res {
+            type Driver = CacheEq<Res>;
+            type Resource = u32;
+            type Installer = ();
+            fn create(&self, _: &'static str) -> Result<u32, AnyError> {
+                Ok(self.value)
+            }
+        }
+    }
+
+    #[derive(Clone, Debug, Default, Deserialize)]
+    struct Cfg {
+        #[serde(default)]
+        res: Res,
+    }
+
+    #[test]
+    fn reloads_and_pipelines() {
+        let signals = Arc::new(AtomicUsize::new(0));
+        let signals_cp = Arc::clone(&signals);
+        let builder = Spirit::<Empty, Cfg>::new()
+            .with(Pipeline::new("res").extract_cfg(|cfg: &Cfg| cfg.res.clone()))
+            .on_signal(libc::SIGUSR1, move || {
+                signals_cp.fetch_add(1, Ordering::Relaxed);
+            });
+        let mut app = TestSpirit::new(builder, "[res]\nvalue = 1").unwrap();
+        assert_eq!(1, app.config_changes());
+        assert_eq!(vec!["res"], app.installed());
+
+        // The same config doesn't install anything new
+        app.signal(libc::SIGHUP);
+        assert_eq!(1, app.config_changes());
+        assert!(app.installed().is_empty());
+
+        app.reload("[res]\nvalue = 2").unwrap();
+        assert_eq!(2, app.spirit().config().res.value);
+        assert_eq!(vec!["res"], app.installed());
+
+        app.signal(libc::SIGUSR1);
+        assert_eq!(1, signals.load(Ordering::Relaxed));
+
+        assert!(!app.terminating());
+        app.run(|| Ok(())).unwrap();
+        assert!(app.run(|| Ok(())).is_err());
+        assert!(app.terminating());
+    }
+
+    #[test]
+    fn invalid_args() {
+        let builder = Spirit::<Empty, Empty>::new();
+        assert!(TestSpirit::with_args(builder, &["test", "--no-such-option"], "").is_err());
+    }
+}
@@ -17,6 +17,7 @@ use serde::de::{Deserializer, Error as DeError, Unexpected};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
 
+use crate::schema::{Schema, Schematic};
 use crate::AnyError;
 
 /// Tries to read an absolute path from the given OS string.
@@ -177,6 +178,12 @@ impl<T> Serialize for Hidden<T> {
     }
 }
 
+impl<T: Schematic> Schematic for Hidden<T> {
+    fn schema() -> Schema {
+        T::schema()
+    }
+}
+
 /// Serialize a duration.
 ///
 /// This can be used in configuration structures containing durations. The deserialization can be
@@ -253,6 +260,51 @@ pub fn serialize_opt_duration<S: Serializer>(
     }
 }
 
+/// Generates functions returning default values of configuration fields.
+///
+/// Serde can fill in a missing field by calling a function (`#[serde(default = "...")]`), but
+/// writing such one-line function for every field with a non-trivial default is tedious. This
+/// macro generates them from a list of `fn name() -> Type = value;` items (the visibility and
+/// attributes, including doc comments, are optional).
+///
+/// # Examples
+///
+/// ```rust
+/// use std::net::IpAddr;
+/// use std::time::Duration;
+///
+/// use serde::Deserialize;
+///
+/// spirit::cfg_defaults! {
+///     fn default_host() -> IpAddr = "::".parse().unwrap();
+///     fn default_port() -> u16 = 1234;
+///     /// The default timeout is a bit long, to be on the safe side.
+///     pub(crate) fn default_timeout() -> Duration = Duration::from_secs(30);
+/// }
+///
+/// #[derive(Deserialize)]
+/// struct Server {
+///     #[serde(default = "default_host")]
+///     host: IpAddr,
+///     #[serde(default = "default_port")]
+///     port: u16,
+///     #[serde(default = "default_timeout", with = "serde_humantime")]
+///     timeout: Duration,
+/// }
+/// # fn main() {}
+/// ```
+#[macro_export]
+macro_rules! cfg_defaults {
+    ($($(#[$attr: meta])* $vis: vis fn $name: ident() -> $ty: ty = $value: expr;)*) => {
+        $(
+            $(#[$attr])*
+            $vis fn $name() -> $ty {
+                $value
+            }
+        )*
+    };
+}
+
 #[cfg(test)]
 mod tests {
     use std::ffi::OsString;
@@ -307,6 +359,20 @@ mod tests {
             .expect("Different error returned");
     }
 
+    mod defaults {
+        cfg_defaults! {
+            pub(super) fn answer() -> u32 = 42;
+            /// With a doc comment
+            pub(super) fn greeting() -> String = "hello".to_owned();
+        }
+    }
+
+    #[test]
+    fn cfg_defaults() {
+        assert_eq!(42, defaults::answer());
+        assert_eq!("hello", defaults::greeting());
+    }
+
     #[test]
     fn key_val_missing_eq() {
         key_val::<String, String>("no equal sign")
@@ -1,6 +1,24 @@
 //! Helpers for configuration validation.
 //!
 //! See [`config_validator`][crate::Extensible::config_validator].
+//!
+//! # Reports
+//!
+//! Validation errors are usually just logged. For tools processing them (CI checking
+//! configuration changes, configuration UIs), the [`Report`] gathers them in a structured form.
+//! Validators can return the [`Invalid`] error to provide the path of the offending option, an
+//! error code and a suggested fix. The `--check-config-json` command line option checks the
+//! configuration and prints the report as JSON to the standard output (this needs the `json`
+//! feature).
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use log::{error, warn};
+use serde::Serialize;
+
+use crate::AnyError;
 
 /// A validation action.
 ///
@@ -42,10 +60,34 @@
 ///         Ok(Action::new())
 ///     });
 /// ```
+///
+/// # Ordering
+///
+/// By default, the success actions of all the validators run in the order the validators were
+/// registered. An action can be given a [`name`][Action::named] and can declare it needs to run
+/// [`after`][Action::after] actions of other names. [`Pipeline`]s name their actions by their own
+/// names, so this can be used to make sure eg. a database pool is installed before a HTTP server
+/// that uses it (see [`Pipeline::install_after`][crate::Pipeline::install_after]). Only the
+/// success actions are ordered, the validators themselves still run in the order of registration.
+///
+/// When the application terminates, the validators (and the resources they hold) are dropped in
+/// the reverse order.
+///
+/// # Cleanup
+///
+/// An action can also have an [`on_cleanup`][Action::on_cleanup] callback. These run only after
+/// the success actions of *all* the validators ran (in the reverse order). This allows replacing
+/// several things at once, removing the old ones only after all the new ones are in place (see
+/// [`Pipeline::transactional`][crate::Pipeline::transactional]).
+///
+/// [`Pipeline`]: crate::Pipeline
 #[derive(Default)]
 pub struct Action {
     pub(crate) on_abort: Option<Box<dyn FnMut()>>,
     pub(crate) on_success: Option<Box<dyn FnMut()>>,
+    pub(crate) on_cleanup: Option<Box<dyn FnMut()>>,
+    pub(crate) name: Option<&'static str>,
+    pub(crate) after: Vec<&'static str>,
 }
 
 impl Action {
@@ -74,14 +116,381 @@ impl Action {
         }
     }
 
+    /// Attaches (replaces) the cleanup action.
+    ///
+    /// It runs after success, but only once the success actions of all the other validators ran
+    /// too. It is not called if the validation fails.
+    pub fn on_cleanup<F: FnOnce() + 'static>(self, f: F) -> Self {
+        let mut f = Some(f);
+        let wrapper = move || (f.take().unwrap())();
+        Self {
+            on_cleanup: Some(Box::new(wrapper)),
+            ..self
+        }
+    }
+
+    /// Names the action, so other actions can be ordered [`after`][Action::after] it.
+    ///
+    /// Multiple actions may share the same name, the dependent actions then wait for all of them.
+    pub fn named(self, name: &'static str) -> Self {
+        Self {
+            name: Some(name),
+            ..self
+        }
+    }
+
+    /// Runs the success action only after the success actions of the given name.
+    ///
+    /// Dependencies on names that don't exist are ignored (with a warning).
+    pub fn after(mut self, name: &'static str) -> Self {
+        self.after.push(name);
+        self
+    }
+
     pub(crate) fn run(self, success: bool) {
-        let selected = if success {
-            self.on_success
-        } else {
-            self.on_abort
-        };
-        if let Some(mut cback) = selected {
-            cback();
+        if success {
+            if let Some(mut cleanup) = self.succeed() {
+                cleanup();
+            }
+        } else if let Some(mut abort) = self.on_abort {
+            abort();
+        }
+    }
+
+    /// Runs the success action, but leaves the cleanup to the caller.
+    pub(crate) fn succeed(self) -> Option<Box<dyn FnMut()>> {
+        if let Some(mut success) = self.on_success {
+            success();
+        }
+        self.on_cleanup
+    }
+}
+
+/// A validation error with structured details.
+///
+/// Validators may return this (possibly wrapped in more context) instead of an ad-hoc error. The
+/// details then show in the [`Report`].
+///
+/// # Examples
+///
+/// ```rust
+/// use serde::Deserialize;
+/// use spirit::{Empty, Spirit};
+/// use spirit::prelude::*;
+/// use spirit::validation::{Action, Invalid};
+///
+/// #[derive(Default, Deserialize)]
+/// struct Cfg {
+///     port: u16,
+/// }
+///
+/// # let _ =
+/// Spirit::<Empty, Cfg>::new()
+///     .config_validator(|_old, new, _opts| {
+///         if new.port < 1024 {
+///             return Err(Invalid::new(format!("Port {} is privileged", new.port))
+///                 .path("port")
+///                 .code("privileged-port")
+///                 .suggestion("Use a port number of at least 1024")
+///                 .into());
+///         }
+///         Ok(Action::new())
+///     });
+/// ```
+#[derive(Debug)]
+pub struct Invalid {
+    message: String,
+    path: Option<String>,
+    code: Option<String>,
+    suggestion: Option<String>,
+    cause: Option<AnyError>,
+}
+
+impl Invalid {
+    /// Creates the error with the given message.
+    pub fn new<M: Into<String>>(message: M) -> Self {
+        Self {
+            message: message.into(),
+            path: None,
+            code: None,
+            suggestion: None,
+            cause: None,
+        }
+    }
+
+    /// Sets the path of the offending option in the configuration (eg. `http.listen.port`).
+    pub fn path<P: Into<String>>(self, path: P) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Sets a machine-readable code of the problem.
+    pub fn code<C: Into<String>>(self, code: C) -> Self {
+        Self {
+            code: Some(code.into()),
+            ..self
+        }
+    }
+
+    /// Sets a suggestion how to fix the problem.
+    pub fn suggestion<S: Into<String>>(self, suggestion: S) -> Self {
+        Self {
+            suggestion: Some(suggestion.into()),
+            ..self
+        }
+    }
+
+    /// Sets the underlying cause of the problem.
+    pub fn caused_by<E: Into<AnyError>>(self, cause: E) -> Self {
+        Self {
+            cause: Some(cause.into()),
+            ..self
+        }
+    }
+}
+
+impl Display for Invalid {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        write!(fmt, "{}", self.message)
+    }
+}
+
+impl Error for Invalid {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.cause.as_ref().map(|e| &**e as &(dyn Error + 'static))
+    }
+}
+
+/// A single problem found in the configuration.
+///
+/// See [`Report`].
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Problem {
+    /// The [`Pipeline`][crate::Pipeline] (or other fragment) the problem comes from, if known.
+    pub fragment: Option<String>,
+    /// The error message.
+    pub message: String,
+    /// The chain of causes of the error, from the outermost.
+    pub causes: Vec<String>,
+    /// The path of the offending option in the configuration, if known.
+    pub path: Option<String>,
+    /// A machine-readable code of the problem, if provided.
+    pub code: Option<String>,
+    /// A suggestion how to fix the problem, if provided.
+    pub suggestion: Option<String>,
+}
+
+impl Problem {
+    /// Extracts the problem from an error.
+    ///
+    /// The details are taken from the first [`Invalid`] in the chain of causes.
+    pub fn from_error(error: &(dyn Error + 'static)) -> Self {
+        let mut causes = Vec::new();
+        let mut invalid = None;
+        let mut current = Some(error);
+        while let Some(e) = current {
+            if invalid.is_none() {
+                invalid = e.downcast_ref::<Invalid>();
+            }
+            current = e.source();
+            if let Some(cause) = current {
+                causes.push(cause.to_string());
+            }
+        }
+        Problem {
+            fragment: None,
+            message: error.to_string(),
+            causes,
+            path: invalid.and_then(|i| i.path.clone()),
+            code: invalid.and_then(|i| i.code.clone()),
+            suggestion: invalid.and_then(|i| i.suggestion.clone()),
+        }
+    }
+
+    pub(crate) fn with_fragment(self, fragment: &str) -> Self {
+        Self {
+            fragment: Some(fragment.to_owned()),
+            ..self
+        }
+    }
+}
+
+/// A machine-readable report of a configuration check.
+///
+/// This is what the `--check-config-json` command line option prints.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Report {
+    /// Is the configuration valid?
+    pub valid: bool,
+    /// The problems found.
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// Creates the report from a result of a configuration check.
+    ///
+    /// In case of a failed validation, all the errors of all the validators are included.
+    /// Otherwise the error is turned into a single [`Problem`].
+    pub fn from_result(result: &Result<(), AnyError>) -> Self {
+        match result {
+            Ok(()) => Report {
+                valid: true,
+                problems: Vec::new(),
+            },
+            Err(e) => {
+                let problems = match e.downcast_ref::<crate::spirit::ValidationError>() {
+                    Some(validation) => validation.problems.clone(),
+                    None => vec![Problem::from_error(&**e)],
+                };
+                Report {
+                    valid: false,
+                    problems,
+                }
+            }
         }
     }
+
+    /// Formats the report as JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> Result<String, AnyError> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// Orders the actions (tagged by the index of their validator) by their dependencies.
+///
+/// This is a stable topological sort ‒ actions that don't depend on each other stay in the
+/// original order. In case of a cycle, the rest is left in the original order.
+pub(crate) fn sort(actions: Vec<(usize, Action)>) -> Vec<(usize, Action)> {
+    if actions.iter().all(|(_, a)| a.after.is_empty()) {
+        return actions;
+    }
+    let names = actions
+        .iter()
+        .filter_map(|(_, a)| a.name)
+        .collect::<HashSet<_>>();
+    for (_, action) in &actions {
+        for dep in action.after.iter().filter(|dep| !names.contains(*dep)) {
+            warn!(
+                "{} is ordered after {}, which doesn't exist",
+                action.name.unwrap_or("<unnamed>"),
+                dep
+            );
+        }
+    }
+    let mut pending = actions.into_iter().map(Some).collect::<Vec<_>>();
+    let mut sorted = Vec::with_capacity(pending.len());
+    while sorted.len() < pending.len() {
+        // Still waiting for their turn
+        let waiting = pending
+            .iter()
+            .flatten()
+            .filter_map(|(_, a)| a.name)
+            .collect::<HashSet<_>>();
+        let ready = pending.iter().position(|a| match a {
+            Some((_, a)) => a.after.iter().all(|dep| {
+                // Waiting for itself (eg. sharing the name) doesn't count
+                !waiting.contains(dep) || a.name == Some(*dep)
+            }),
+            None => false,
+        });
+        match ready {
+            Some(ready) => sorted.push(pending[ready].take().unwrap()),
+            None => {
+                error!("Dependency cycle between validation actions, ignoring their ordering");
+                sorted.extend(pending.iter_mut().filter_map(Option::take));
+            }
+        }
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(actions: Vec<Action>) -> Vec<usize> {
+        sort(actions.into_iter().enumerate().collect())
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    #[test]
+    fn cleanup_deferred() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let (log_s, log_c) = (Rc::clone(&log), Rc::clone(&log));
+        let action = Action::new()
+            .on_success(move || log_s.borrow_mut().push("success"))
+            .on_cleanup(move || log_c.borrow_mut().push("cleanup"));
+        let mut cleanup = action.succeed().unwrap();
+        assert_eq!(vec!["success"], *log.borrow());
+        cleanup();
+        assert_eq!(vec!["success", "cleanup"], *log.borrow());
+    }
+
+    #[test]
+    fn problem_details() {
+        let invalid = Invalid::new("Port 80 is privileged")
+            .path("listen.port")
+            .code("privileged-port")
+            .caused_by("Permission denied");
+        let error = AnyError::from(crate::fragment::pipeline::MultiError {
+            errors: vec![invalid.into()],
+            pipeline: "listen",
+        });
+        let problem = Problem::from_error(&*error).with_fragment("listen");
+        assert_eq!(
+            Problem {
+                fragment: Some("listen".to_owned()),
+                message: "Pipeline listen failed with 1 errors".to_owned(),
+                causes: vec![
+                    "Port 80 is privileged".to_owned(),
+                    "Permission denied".to_owned(),
+                ],
+                path: Some("listen.port".to_owned()),
+                code: Some("privileged-port".to_owned()),
+                suggestion: None,
+            },
+            problem
+        );
+
+        let report = Report::from_result(&Err(error));
+        assert!(!report.valid);
+        assert_eq!(1, report.problems.len());
+        assert!(Report::from_result(&Ok(())).valid);
+    }
+
+    #[test]
+    fn unordered() {
+        let actions = vec![Action::new(), Action::new().named("a"), Action::new()];
+        assert_eq!(vec![0, 1, 2], order(actions));
+    }
+
+    #[test]
+    fn dependencies() {
+        let actions = vec![
+            Action::new().named("http").after("db"),
+            Action::new().named("log"),
+            Action::new().named("db").after("log").after("missing"),
+        ];
+        assert_eq!(vec![1, 2, 0], order(actions));
+    }
+
+    #[test]
+    fn cycle() {
+        let actions = vec![
+            Action::new().named("a").after("b"),
+            Action::new().named("c"),
+            Action::new().named("b").after("a"),
+        ];
+        assert_eq!(vec![1, 0, 2], order(actions));
+    }
 }